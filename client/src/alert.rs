@@ -0,0 +1,471 @@
+//! Alerts for unattended clients.
+//!
+//! An alert fires once per incident: when connection attempts keep failing,
+//! when the server keeps rejecting the token, or when a tunnel's local
+//! service stays unreachable. A second, resolved notice follows when the
+//! incident clears. Delivery is a JSON webhook and/or an email over SMTP;
+//! a failed delivery is logged and not retried.
+
+use crate::connection::ServerConnection;
+use base64::Engine;
+use nat_traversal_common::{
+    config::{AlertConfig, ClientConfig, EmailConfig, SmtpTls, WebhookConfig},
+    protocol::TunnelProtocol,
+};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_rustls::{rustls, TlsConnector};
+use tracing::{info, warn};
+
+/// How often tunnels' local services are probed
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Limit on connecting to and talking with a webhook or SMTP server
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    ReconnectFailures,
+    AuthFailures,
+    LocalServiceDown,
+}
+
+/// What a webhook receives
+#[derive(Debug, Serialize)]
+struct Alert {
+    client_id: String,
+    kind: AlertKind,
+    resolved: bool,
+    /// Human-readable summary; also what chat webhooks display
+    text: String,
+    timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+/// Counters behind the alerts, each with whether its alert is out
+#[derive(Default)]
+struct AlertState {
+    connect_failures: u32,
+    connect_alerted: bool,
+    auth_failures: u32,
+    auth_alerted: bool,
+    /// Unreachable local services by address: since when, and alerted yet
+    local_down: HashMap<(String, u16), (Instant, bool)>,
+}
+
+pub struct Alerter {
+    config: AlertConfig,
+    client_id: String,
+    state: Mutex<AlertState>,
+}
+
+impl Alerter {
+    /// Alerter for the configuration, `None` when alerts are off or have
+    /// nowhere to go
+    pub fn new(config: &ClientConfig) -> Option<Arc<Self>> {
+        let alerts = config.alerts.as_ref()?;
+        if alerts.webhook.is_none() && alerts.email.is_none() {
+            warn!("Alerts are configured without a webhook or email; not sending any");
+            return None;
+        }
+        Some(Arc::new(Self {
+            config: alerts.clone(),
+            client_id: config.server.client_id.clone(),
+            state: Mutex::new(AlertState::default()),
+        }))
+    }
+
+    /// A connection attempt or session ended in an error
+    pub fn connect_failed(&self, error: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.connect_failures += 1;
+        let threshold = self.config.reconnect_failures;
+        if threshold > 0 && state.connect_failures >= threshold && !state.connect_alerted {
+            state.connect_alerted = true;
+            self.fire(
+                AlertKind::ReconnectFailures,
+                false,
+                format!(
+                    "{} connection attempts in a row failed, last: {}",
+                    state.connect_failures, error
+                ),
+            );
+        }
+    }
+
+    /// The server rejected the client's token
+    pub fn auth_failed(&self, error: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.auth_failures += 1;
+        let threshold = self.config.auth_failures;
+        if threshold > 0 && state.auth_failures >= threshold && !state.auth_alerted {
+            state.auth_alerted = true;
+            self.fire(
+                AlertKind::AuthFailures,
+                false,
+                format!(
+                    "Authentication failed {} time(s) in a row: {}",
+                    state.auth_failures, error
+                ),
+            );
+        }
+    }
+
+    /// The client authenticated, clearing connection and auth incidents
+    pub fn connected(&self) {
+        let mut state = self.state.lock().unwrap();
+        if std::mem::take(&mut state.connect_alerted) {
+            self.fire(
+                AlertKind::ReconnectFailures,
+                true,
+                "Connected to the server again".to_string(),
+            );
+        }
+        if std::mem::take(&mut state.auth_alerted) {
+            self.fire(
+                AlertKind::AuthFailures,
+                true,
+                "Authentication succeeds again".to_string(),
+            );
+        }
+        state.connect_failures = 0;
+        state.auth_failures = 0;
+    }
+
+    /// Probe the local service of every TCP tunnel until the client exits
+    pub async fn watch_local_services(self: Arc<Self>, connection: Arc<ServerConnection>) {
+        if self.config.local_down_minutes == 0 {
+            return;
+        }
+        let limit = Duration::from_secs(self.config.local_down_minutes * 60);
+        let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+
+        loop {
+            ticker.tick().await;
+            let mut targets: Vec<_> = connection
+                .get_tunnels()
+                .await
+                .into_iter()
+                .filter(|t| t.protocol == TunnelProtocol::Tcp)
+                .map(|t| (t.local_host, t.local_port))
+                .collect();
+            targets.sort();
+            targets.dedup();
+            self.state
+                .lock()
+                .unwrap()
+                .local_down
+                .retain(|target, _| targets.contains(target));
+
+            for target in targets {
+                let up = matches!(
+                    tokio::time::timeout(
+                        Duration::from_secs(5),
+                        TcpStream::connect((target.0.as_str(), target.1)),
+                    )
+                    .await,
+                    Ok(Ok(_))
+                );
+                self.local_service_checked(target, up, limit);
+            }
+        }
+    }
+
+    fn local_service_checked(&self, target: (String, u16), up: bool, limit: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let address = format!("{}:{}", target.0, target.1);
+        if up {
+            if let Some((_, true)) = state.local_down.remove(&target) {
+                self.fire(
+                    AlertKind::LocalServiceDown,
+                    true,
+                    format!("Local service {} is reachable again", address),
+                );
+            }
+            return;
+        }
+
+        let (since, alerted) = state
+            .local_down
+            .entry(target)
+            .or_insert((Instant::now(), false));
+        if !*alerted && since.elapsed() >= limit {
+            *alerted = true;
+            self.fire(
+                AlertKind::LocalServiceDown,
+                false,
+                format!(
+                    "Local service {} has been unreachable for {} min",
+                    address,
+                    since.elapsed().as_secs() / 60
+                ),
+            );
+        }
+    }
+
+    fn fire(&self, kind: AlertKind, resolved: bool, text: String) {
+        if resolved {
+            info!("Alert resolved: {}", text);
+        } else {
+            warn!("Alert: {}", text);
+        }
+        let alert = Alert {
+            client_id: self.client_id.clone(),
+            kind,
+            resolved,
+            text,
+            timestamp: chrono::Utc::now(),
+        };
+        let config = self.config.clone();
+        tokio::spawn(async move {
+            if let Some(webhook) = &config.webhook {
+                let result =
+                    tokio::time::timeout(DELIVERY_TIMEOUT, post_webhook(webhook, &alert)).await;
+                if let Err(e) = result.unwrap_or_else(|_| Err(anyhow::anyhow!("timed out"))) {
+                    warn!("Failed to deliver alert to webhook: {}", e);
+                }
+            }
+            if let Some(email) = &config.email {
+                let result =
+                    tokio::time::timeout(DELIVERY_TIMEOUT, send_email(email, &alert)).await;
+                if let Err(e) = result.unwrap_or_else(|_| Err(anyhow::anyhow!("timed out"))) {
+                    warn!("Failed to deliver alert by email: {}", e);
+                }
+            }
+        });
+    }
+}
+
+fn tls_connector() -> TlsConnector {
+    let mut root_cert_store = rustls::RootCertStore::empty();
+    root_cert_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_cert_store)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+async fn connect_tls(
+    host: &str,
+    stream: TcpStream,
+) -> anyhow::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+    let server_name = rustls::ServerName::try_from(host)
+        .map_err(|e| anyhow::anyhow!("Invalid server name {}: {}", host, e))?;
+    Ok(tls_connector().connect(server_name, stream).await?)
+}
+
+/// `http://` or `https://` URL split into what a request needs
+struct WebhookUrl {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl WebhookUrl {
+    fn parse(url: &str) -> anyhow::Result<Self> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(anyhow::anyhow!("Webhook URL must be http:// or https://"));
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        let default_port = if tls { 443 } else { 80 };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse()?),
+            _ => (authority, default_port),
+        };
+        Ok(Self {
+            tls,
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+async fn post_webhook(config: &WebhookConfig, alert: &Alert) -> anyhow::Result<()> {
+    let url = WebhookUrl::parse(&config.url)?;
+    let body = serde_json::to_string(alert)?;
+
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: nat-client\r\n\
+         Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        url.path,
+        url.host,
+        body.len()
+    );
+    for (name, value) in &config.headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    request.push_str(&body);
+
+    let stream = TcpStream::connect((url.host.as_str(), url.port)).await?;
+    let response = if url.tls {
+        http_exchange(connect_tls(&url.host, stream).await?, &request).await?
+    } else {
+        http_exchange(stream, &request).await?
+    };
+
+    let status = response
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| anyhow::anyhow!("malformed HTTP response"))?;
+    if !(200..300).contains(&status) {
+        return Err(anyhow::anyhow!("webhook returned HTTP {}", status));
+    }
+    Ok(())
+}
+
+async fn http_exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    request: &str,
+) -> anyhow::Result<String> {
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+    let mut response = Vec::new();
+    // Servers may close TLS without a close_notify; what was read is enough
+    let _ = stream.read_to_end(&mut response).await;
+    Ok(String::from_utf8_lossy(&response).into_owned())
+}
+
+async fn send_email(config: &EmailConfig, alert: &Alert) -> anyhow::Result<()> {
+    let subject = format!(
+        "[nat-client {}] {}",
+        alert.client_id,
+        if alert.resolved { "Resolved" } else { "Alert" }
+    );
+    let message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@nat-client>\r\n\
+         MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\
+         Content-Transfer-Encoding: 8bit\r\n\r\n{}\r\n",
+        config.from,
+        config.to.join(", "),
+        subject,
+        alert.timestamp.to_rfc2822(),
+        uuid::Uuid::new_v4(),
+        alert.text
+    );
+
+    let stream = TcpStream::connect((config.smtp_host.as_str(), config.smtp_port)).await?;
+    match config.tls {
+        SmtpTls::Implicit => {
+            let stream = connect_tls(&config.smtp_host, stream).await?;
+            smtp_session(BufReader::new(stream), config, &message, false).await
+        }
+        SmtpTls::StartTls => {
+            let mut stream = BufReader::new(stream);
+            smtp_reply(&mut stream, 2).await?;
+            smtp_command(&mut stream, "EHLO localhost", 2).await?;
+            smtp_command(&mut stream, "STARTTLS", 2).await?;
+            let stream = connect_tls(&config.smtp_host, stream.into_inner()).await?;
+            smtp_session(BufReader::new(stream), config, &message, true).await
+        }
+        SmtpTls::None => smtp_session(BufReader::new(stream), config, &message, false).await,
+    }
+}
+
+/// Authenticate and deliver `message`, after the greeting when `greeted`
+async fn smtp_session<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: BufReader<S>,
+    config: &EmailConfig,
+    message: &str,
+    greeted: bool,
+) -> anyhow::Result<()> {
+    if !greeted {
+        smtp_reply(&mut stream, 2).await?;
+    }
+    smtp_command(&mut stream, "EHLO localhost", 2).await?;
+
+    if let Some(username) = &config.username {
+        let password = config.load_password()?.unwrap_or_default();
+        let credentials = base64::engine::general_purpose::STANDARD
+            .encode(format!("\0{}\0{}", username, password));
+        smtp_command(&mut stream, &format!("AUTH PLAIN {}", credentials), 2).await?;
+    }
+
+    smtp_command(&mut stream, &format!("MAIL FROM:<{}>", config.from), 2).await?;
+    for recipient in &config.to {
+        smtp_command(&mut stream, &format!("RCPT TO:<{}>", recipient), 2).await?;
+    }
+    smtp_command(&mut stream, "DATA", 3).await?;
+
+    // Dot-stuff lines that start with a period, then end the data
+    let mut data = String::new();
+    for line in message.split("\r\n") {
+        if line.starts_with('.') {
+            data.push('.');
+        }
+        data.push_str(line);
+        data.push_str("\r\n");
+    }
+    data.push_str(".\r\n");
+    stream.get_mut().write_all(data.as_bytes()).await?;
+    smtp_reply(&mut stream, 2).await?;
+
+    let _ = smtp_command(&mut stream, "QUIT", 2).await;
+    Ok(())
+}
+
+async fn smtp_command<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufReader<S>,
+    command: &str,
+    class: u16,
+) -> anyhow::Result<()> {
+    stream
+        .get_mut()
+        .write_all(format!("{}\r\n", command).as_bytes())
+        .await?;
+    stream.get_mut().flush().await?;
+    smtp_reply(stream, class).await.map_err(|e| {
+        let verb = command.split_whitespace().next().unwrap_or(command);
+        anyhow::anyhow!("{}: {}", verb, e)
+    })
+}
+
+/// Read a possibly multi-line reply and check its code is `class`xx
+async fn smtp_reply<S: AsyncRead + Unpin>(
+    stream: &mut BufReader<S>,
+    class: u16,
+) -> anyhow::Result<()> {
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 {
+            return Err(anyhow::anyhow!("SMTP server closed the connection"));
+        }
+        let code: u16 = line
+            .get(..3)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| anyhow::anyhow!("malformed SMTP reply: {}", line.trim_end()))?;
+        // "250-" continues the reply, "250 " ends it
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+        if code / 100 != class {
+            return Err(anyhow::anyhow!("SMTP server replied {}", line.trim_end()));
+        }
+        return Ok(());
+    }
+}
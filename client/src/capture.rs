@@ -0,0 +1,252 @@
+//! Requests that reached custom-domain tunnels, kept in memory so they can
+//! be sent to the local service again while working on its handler,
+//! without the upstream provider having to send them again.
+
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use nat_traversal_common::config::CaptureConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use uuid::Uuid;
+
+/// Longest a replayed request waits for the complete response
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(30);
+/// Largest response a replay returns
+const MAX_RESPONSE: usize = 512 * 1024;
+
+/// What `nat-client requests` lists
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestSummary {
+    pub id: u64,
+    pub tunnel_id: Uuid,
+    pub tunnel: String,
+    pub received_at: DateTime<Utc>,
+    pub method: String,
+    pub path: String,
+    pub size: usize,
+}
+
+struct CapturedRequest {
+    summary: RequestSummary,
+    /// Where the tunnel forwarded the request
+    target: String,
+    raw: Bytes,
+}
+
+pub struct RequestLog {
+    config: CaptureConfig,
+    requests: Mutex<VecDeque<CapturedRequest>>,
+    next_id: AtomicU64,
+}
+
+impl RequestLog {
+    pub fn new(config: CaptureConfig) -> Self {
+        Self {
+            config,
+            requests: Mutex::new(VecDeque::new()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.max_requests > 0
+    }
+
+    /// Start capturing the requests of one tunneled connection
+    pub fn capturer(&self, tunnel_id: Uuid, tunnel: String, target: String) -> Capturer {
+        Capturer {
+            tunnel_id,
+            tunnel,
+            target,
+            buffer: BytesMut::new(),
+            done: false,
+        }
+    }
+
+    fn record(&self, capturer: &Capturer, method: String, path: String, raw: Bytes) {
+        let request = CapturedRequest {
+            summary: RequestSummary {
+                id: self.next_id.fetch_add(1, Ordering::Relaxed),
+                tunnel_id: capturer.tunnel_id,
+                tunnel: capturer.tunnel.clone(),
+                received_at: Utc::now(),
+                method,
+                path,
+                size: raw.len(),
+            },
+            target: capturer.target.clone(),
+            raw,
+        };
+        let mut requests = self.requests.lock().unwrap();
+        while requests.len() >= self.config.max_requests {
+            requests.pop_front();
+        }
+        requests.push_back(request);
+    }
+
+    /// Captured requests, oldest first
+    pub fn list(&self) -> Vec<RequestSummary> {
+        let requests = self.requests.lock().unwrap();
+        requests.iter().map(|r| r.summary.clone()).collect()
+    }
+
+    /// The raw request and where it went
+    pub fn get(&self, id: u64) -> Option<(String, Bytes)> {
+        let requests = self.requests.lock().unwrap();
+        requests
+            .iter()
+            .find(|r| r.summary.id == id)
+            .map(|r| (r.target.clone(), r.raw.clone()))
+    }
+}
+
+/// Splits the bytes a visitor sends on one connection into requests.
+/// Gives up on the connection at anything it cannot delimit, such as a
+/// chunked body or a request over the size limit.
+pub struct Capturer {
+    tunnel_id: Uuid,
+    tunnel: String,
+    target: String,
+    buffer: BytesMut,
+    done: bool,
+}
+
+impl Capturer {
+    pub fn feed(&mut self, log: &RequestLog, data: &[u8]) {
+        if self.done {
+            return;
+        }
+        self.buffer.extend_from_slice(data);
+        loop {
+            let mut headers = [httparse::EMPTY_HEADER; 64];
+            let mut request = httparse::Request::new(&mut headers);
+            let head_len = match request.parse(&self.buffer) {
+                Ok(httparse::Status::Complete(len)) => len,
+                Ok(httparse::Status::Partial) => {
+                    self.done = self.buffer.len() > log.config.max_request_bytes;
+                    break;
+                }
+                Err(_) => {
+                    self.done = true;
+                    break;
+                }
+            };
+            if header(request.headers, "transfer-encoding").is_some() {
+                self.done = true;
+                break;
+            }
+            let body_len = match header(request.headers, "content-length") {
+                Some(value) => match value.trim().parse::<usize>() {
+                    Ok(len) => len,
+                    Err(_) => {
+                        self.done = true;
+                        break;
+                    }
+                },
+                None => 0,
+            };
+            let total = head_len.saturating_add(body_len);
+            if total > log.config.max_request_bytes {
+                self.done = true;
+                break;
+            }
+            if self.buffer.len() < total {
+                break;
+            }
+
+            let method = request.method.unwrap_or_default().to_string();
+            let path = request.path.unwrap_or_default().to_string();
+            let raw = self.buffer.split_to(total).freeze();
+            log.record(self, method, path, raw);
+        }
+        if self.done {
+            self.buffer = BytesMut::new();
+        }
+    }
+}
+
+fn header<'a>(headers: &'a [httparse::Header<'_>], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case(name))
+        .and_then(|header| std::str::from_utf8(header.value).ok())
+}
+
+/// Turn an edited request back into one the service can parse: the head
+/// gets CRLF line endings again and `Content-Length` matches the body
+pub fn prepare_edited(text: &str) -> Vec<u8> {
+    let (head, body) = match text.find("\r\n\r\n") {
+        Some(at) => (&text[..at], &text[at + 4..]),
+        None => match text.find("\n\n") {
+            Some(at) => (&text[..at], &text[at + 2..]),
+            None => (text.trim_end(), ""),
+        },
+    };
+    let mut request = String::new();
+    for line in head.lines() {
+        match line.split_once(':') {
+            Some((name, _)) if name.trim().eq_ignore_ascii_case("content-length") => {
+                request.push_str(&format!("Content-Length: {}", body.len()));
+            }
+            _ => request.push_str(line),
+        }
+        request.push_str("\r\n");
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    request.into_bytes()
+}
+
+/// Send `raw` to `target` on a new connection and return the response
+pub async fn replay(target: &str, raw: &[u8]) -> anyhow::Result<Bytes> {
+    let mut stream = TcpStream::connect(target)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to connect to {}: {}", target, e))?;
+    stream.write_all(raw).await?;
+
+    let mut response = BytesMut::new();
+    let read = async {
+        loop {
+            if stream.read_buf(&mut response).await? == 0 || response_complete(&response) {
+                return Ok::<_, std::io::Error>(());
+            }
+            if response.len() >= MAX_RESPONSE {
+                response.truncate(MAX_RESPONSE);
+                return Ok(());
+            }
+        }
+    };
+    match tokio::time::timeout(REPLAY_TIMEOUT, read).await {
+        Ok(result) => result?,
+        Err(_) if !response.is_empty() => {}
+        Err(_) => anyhow::bail!("{} did not answer", target),
+    }
+    Ok(response.freeze())
+}
+
+/// Whether `response` holds a whole response, so a kept-alive connection
+/// need not be waited on
+fn response_complete(response: &[u8]) -> bool {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut parsed = httparse::Response::new(&mut headers);
+    let head_len = match parsed.parse(response) {
+        Ok(httparse::Status::Complete(len)) => len,
+        _ => return false,
+    };
+    let body = &response[head_len..];
+    if matches!(parsed.code, Some(204 | 304)) {
+        return true;
+    }
+    if header(parsed.headers, "transfer-encoding").is_some() {
+        return body.ends_with(b"0\r\n\r\n");
+    }
+    match header(parsed.headers, "content-length").and_then(|v| v.trim().parse::<usize>().ok()) {
+        Some(len) => body.len() >= len,
+        None => false,
+    }
+}
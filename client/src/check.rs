@@ -0,0 +1,483 @@
+//! `nat-client check-config`: load the configuration and everything it
+//! refers to the way starting the client would, without connecting or
+//! opening any port, so a change can be checked before restarting.
+
+use crate::config::{load_client_config, Args};
+use crate::connection::ServerConnection;
+use nat_traversal_common::{
+    config::{get_config_dir, ClientConfig, StageConfig, TunnelConfig},
+    noise,
+    pipeline::Pipeline,
+    protocol::TunnelProtocol,
+    wol,
+};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Passed, warned and failed checks, printed as they run
+#[derive(Default)]
+struct Report {
+    passed: usize,
+    warned: usize,
+    failed: usize,
+}
+
+impl Report {
+    fn pass(&mut self, what: &str) {
+        self.passed += 1;
+        println!("  PASS  {}", what);
+    }
+
+    fn warn(&mut self, what: &str) {
+        self.warned += 1;
+        println!("  WARN  {}", what);
+    }
+
+    fn fail(&mut self, what: &str) {
+        self.failed += 1;
+        println!("  FAIL  {}", what);
+    }
+
+    fn check<T, E: std::fmt::Display>(&mut self, what: &str, result: Result<T, E>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                self.pass(what);
+                Some(value)
+            }
+            Err(e) => {
+                self.fail(&format!("{}: {}", what, e));
+                None
+            }
+        }
+    }
+}
+
+/// Validate the configuration and print a report; fails when any check does
+pub async fn run(args: &Args) -> anyhow::Result<()> {
+    let mut report = Report::default();
+    let source = match &args.config {
+        Some(path) => path.clone(),
+        None => get_config_dir()?.join("client.toml"),
+    };
+    println!("Configuration {}", source.display());
+    if !source.exists() {
+        report.warn("The file does not exist; checking the defaults");
+    }
+
+    if let Some(config) = report.check("Parse configuration", load_client_config(args)) {
+        if let Some(profile) = &config.active_profile {
+            println!("        Profile: {}", profile);
+        }
+        if config.ssh.enabled {
+            check_ssh(&config, &mut report);
+        } else {
+            check_server(&config, &mut report).await;
+        }
+        check_tunnels(&config, &mut report);
+        check_e2e(&config, &mut report);
+        check_vpn(&config, &mut report);
+        check_wireguard(&config, &mut report);
+        check_other(&config, &mut report);
+    }
+
+    println!();
+    println!(
+        "{} passed, {} warnings, {} failed",
+        report.passed, report.warned, report.failed
+    );
+    if report.failed > 0 {
+        return Err(anyhow::anyhow!("The configuration has errors"));
+    }
+    Ok(())
+}
+
+async fn check_server(config: &ClientConfig, report: &mut Report) {
+    let server = &config.server;
+    if server.addr.is_empty() {
+        report.fail("server.addr is empty");
+    } else if server.port == 0 {
+        report.fail("server.port must not be 0");
+    } else {
+        report.pass(&format!("Server {}:{}", server.addr, server.port));
+    }
+    if let Some(name) = &server.server_name {
+        report.check(
+            &format!("TLS server name {}", name),
+            tokio_rustls::rustls::ServerName::try_from(name.as_str()),
+        );
+    }
+    if server.client_id.is_empty() {
+        report.fail("server.client_id is empty");
+    }
+
+    if let Some(token) = report.check("Load token", server.load_token()) {
+        if token.is_empty() && server.client_cert.is_none() {
+            report.warn("No token or client certificate; the server will refuse this client");
+        }
+    }
+    if server.client_cert.is_some() != server.client_key.is_some() {
+        report.fail("server.client_cert and server.client_key must be set together");
+    }
+    if server.heartbeat_interval_secs == 0 {
+        report.fail("server.heartbeat_interval_secs must be at least 1");
+    }
+    if !server.tls_verify {
+        report.warn("server.tls_verify is off; any server certificate is accepted");
+    }
+
+    // Builds the TLS settings and tunnel keys without connecting
+    let mut probe = config.clone();
+    probe.alerts = None;
+    report.check(
+        "Load TLS settings and keys",
+        ServerConnection::new(probe).await.map(|_| ()),
+    );
+}
+
+fn check_tunnels(config: &ClientConfig, report: &mut Report) {
+    if config.tunnels.is_empty() {
+        report.warn("No tunnels configured");
+        return;
+    }
+
+    let mut names = HashSet::new();
+    let mut remote: Vec<(&str, u16, u16)> = Vec::new();
+    for tunnel in &config.tunnels {
+        let failed = report.failed;
+        if !names.insert(tunnel.name.as_str()) {
+            report.fail(&format!("Tunnel name '{}' is used twice", tunnel.name));
+        }
+        check_tunnel(tunnel, report);
+
+        if let Some(port) = tunnel.remote_port {
+            let last = port.saturating_add(tunnel.port_count.max(1) - 1);
+            if let Some((other, _, _)) = remote
+                .iter()
+                .find(|(_, start, end)| port <= *end && last >= *start)
+            {
+                report.fail(&format!(
+                    "Tunnels '{}' and '{}' ask for the same remote port",
+                    other, tunnel.name
+                ));
+            }
+            remote.push((&tunnel.name, port, last));
+        }
+
+        if report.failed == failed {
+            report.pass(&format!(
+                "Tunnel '{}': {} -> {}:{}",
+                tunnel.name,
+                tunnel
+                    .remote_port
+                    .map_or_else(|| "any port".to_string(), |port| port.to_string()),
+                tunnel.local_host,
+                tunnel.local_port
+            ));
+        }
+    }
+}
+
+fn check_tunnel(tunnel: &TunnelConfig, report: &mut Report) {
+    let name = &tunnel.name;
+    if tunnel.local_port == 0 {
+        report.fail(&format!("Tunnel '{}': local_port must not be 0", name));
+    }
+    if tunnel.port_count == 0 {
+        report.fail(&format!("Tunnel '{}': port_count must be at least 1", name));
+    } else {
+        let span = tunnel.port_count - 1;
+        if tunnel.local_port.checked_add(span).is_none() {
+            report.fail(&format!(
+                "Tunnel '{}': local port range runs past 65535",
+                name
+            ));
+        }
+        if tunnel
+            .remote_port
+            .is_some_and(|port| port.checked_add(span).is_none())
+        {
+            report.fail(&format!(
+                "Tunnel '{}': remote port range runs past 65535",
+                name
+            ));
+        }
+    }
+    if tunnel.ttl_secs == Some(0) {
+        report.fail(&format!("Tunnel '{}': ttl_secs must be at least 1", name));
+    }
+    match tunnel.load_tls_certificate() {
+        Ok(Some(_)) if tunnel.domain.is_none() => report.warn(&format!(
+            "Tunnel '{}': tls_cert is only used with a domain",
+            name
+        )),
+        Ok(_) => {}
+        Err(e) => report.fail(&format!("Tunnel '{}': {}", name, e)),
+    }
+    if tunnel.tls_passthrough && tunnel.domain.is_none() {
+        report.fail(&format!(
+            "Tunnel '{}': tls_passthrough needs a domain",
+            name
+        ));
+    }
+    if tunnel.tls_passthrough && tunnel.tls_cert.is_some() {
+        report.fail(&format!(
+            "Tunnel '{}': tls_passthrough and tls_cert exclude each other",
+            name
+        ));
+    }
+    if tunnel.http_headers.is_some() && tunnel.domain.is_none() {
+        report.fail(&format!("Tunnel '{}': http_headers needs a domain", name));
+    }
+    if tunnel.tls_passthrough && tunnel.http_headers.is_some() {
+        report.fail(&format!(
+            "Tunnel '{}': tls_passthrough and http_headers exclude each other",
+            name
+        ));
+    }
+    if !tunnel.stages.is_empty() {
+        if !tunnel.e2e_peers.is_empty() {
+            report.fail(&format!(
+                "Tunnel '{}': e2e_peers and stages exclude each other",
+                name
+            ));
+        }
+        if let Err(e) = Pipeline::new(name, &tunnel.stages) {
+            report.fail(&format!("Tunnel '{}': {}", name, e));
+        }
+    }
+    match &tunnel.udp {
+        Some(_) if tunnel.protocol != TunnelProtocol::Udp => report.fail(&format!(
+            "Tunnel '{}': udp settings are only for UDP tunnels",
+            name
+        )),
+        Some(udp)
+            if udp.idle_timeout_secs == 0 || udp.max_sessions == 0 || udp.max_datagram_size == 0 =>
+        {
+            report.fail(&format!(
+                "Tunnel '{}': udp.idle_timeout_secs, udp.max_sessions and udp.max_datagram_size must be at least 1",
+                name
+            ))
+        }
+        _ => {}
+    }
+    if tunnel.protocol == TunnelProtocol::Udp {
+        if !tunnel.e2e_peers.is_empty() {
+            report.fail(&format!(
+                "Tunnel '{}': e2e_peers only work on TCP tunnels",
+                name
+            ));
+        }
+        // Datagram boundaries travel as length prefixes a rewrite would break
+        if tunnel
+            .stages
+            .iter()
+            .any(|stage| matches!(stage, StageConfig::Rewrite { .. }))
+        {
+            report.fail(&format!(
+                "Tunnel '{}': rewrite stages only work on TCP tunnels",
+                name
+            ));
+        }
+    }
+}
+
+fn check_e2e(config: &ClientConfig, report: &mut Report) {
+    let e2e = &config.e2e;
+    if e2e.private_key.is_none() && e2e.private_key_file.is_none() && e2e.visitors.is_empty() {
+        return;
+    }
+    let Some(private) = report
+        .check("Load e2e private key", e2e.load_private_key())
+        .flatten()
+    else {
+        if !e2e.visitors.is_empty() {
+            report.fail("E2E visitors need e2e.private_key or e2e.private_key_file");
+        }
+        return;
+    };
+    if let Err(e) = noise::decode_key(&private) {
+        report.fail(&format!("Invalid e2e private key: {}", e));
+    }
+
+    let mut binds = HashSet::new();
+    for visitor in &e2e.visitors {
+        if let Err(e) = noise::decode_key(&visitor.peer) {
+            report.fail(&format!(
+                "Invalid peer key of visitor {}: {}",
+                visitor.name, e
+            ));
+        }
+        if !binds.insert((visitor.bind_host.as_str(), visitor.bind_port)) {
+            report.fail(&format!(
+                "Visitor {} binds {}:{} like another visitor",
+                visitor.name, visitor.bind_host, visitor.bind_port
+            ));
+        }
+    }
+}
+
+fn check_vpn(config: &ClientConfig, report: &mut Report) {
+    let vpn = &config.vpn;
+    if !vpn.enabled {
+        if !vpn.advertise.is_empty() || !vpn.routes.is_empty() {
+            report.warn("vpn.advertise and vpn.routes have no effect with the VPN disabled");
+        }
+        return;
+    }
+    if cfg!(not(target_os = "linux")) {
+        report.fail("vpn.enabled: the VPN mode is only supported on Linux");
+    }
+    if vpn.mtu < 576 {
+        report.fail("vpn.mtu must be at least 576");
+    }
+    for route in &vpn.routes {
+        if vpn.advertise.iter().any(|subnet| subnet.overlaps(route)) {
+            report.fail(&format!(
+                "vpn.routes {} overlaps a subnet this client advertises",
+                route
+            ));
+        }
+    }
+}
+
+fn check_wireguard(config: &ClientConfig, report: &mut Report) {
+    let wireguard = &config.wireguard;
+    if !wireguard.enabled {
+        if !wireguard.advertise.is_empty() {
+            report.warn("wireguard.advertise has no effect with WireGuard disabled");
+        }
+        return;
+    }
+    if cfg!(not(target_os = "linux")) {
+        report.fail("wireguard.enabled: the WireGuard mesh is only supported on Linux");
+    }
+    if wireguard.listen_port == 0 {
+        report.fail("wireguard.listen_port must be a fixed port, not 0");
+    }
+    if config.vpn.enabled && config.vpn.interface == wireguard.interface {
+        report.fail(&format!(
+            "vpn.interface and wireguard.interface are both {}",
+            wireguard.interface
+        ));
+    }
+    if wireguard.direct && wireguard.persistent_keepalive_secs == 0 {
+        report.warn(
+            "wireguard.persistent_keepalive_secs is 0; direct connections through NAT need keepalives",
+        );
+    }
+    if let Some(path) = &wireguard.private_key_file {
+        match std::fs::read_to_string(path) {
+            Ok(key) => {
+                if let Err(e) = noise::decode_key(&key) {
+                    report.fail(&format!(
+                        "wireguard.private_key_file {}: {}",
+                        path.display(),
+                        e
+                    ));
+                }
+            }
+            Err(_) => report.warn(&format!(
+                "wireguard.private_key_file {} does not exist yet and will be created",
+                path.display()
+            )),
+        }
+    }
+}
+
+/// The SSH server stands in for the NAT traversal server
+fn check_ssh(config: &ClientConfig, report: &mut Report) {
+    let ssh = &config.ssh;
+    if ssh.host.is_empty() {
+        report.fail("ssh.host is empty");
+    } else if ssh.port == 0 {
+        report.fail("ssh.port must not be 0");
+    } else {
+        report.pass(&format!("SSH server {}:{}", ssh.host, ssh.port));
+    }
+    if ssh.user.is_empty() {
+        report.fail("ssh.user is empty");
+    }
+    if let Some(identity) = &ssh.identity_file {
+        if !identity.exists() {
+            report.fail(&format!(
+                "ssh.identity_file {} does not exist",
+                identity.display()
+            ));
+        }
+    }
+    for tunnel in &config.tunnels {
+        if tunnel.protocol != TunnelProtocol::Tcp {
+            report.warn(&format!(
+                "Tunnel {}: SSH forwards only carry TCP; it will not be opened",
+                tunnel.name
+            ));
+        } else if tunnel.port_count > 1 && tunnel.remote_port.is_none() {
+            report.fail(&format!(
+                "Tunnel {}: a port range over SSH needs remote_port",
+                tunnel.name
+            ));
+        }
+    }
+}
+
+fn check_other(config: &ClientConfig, report: &mut Report) {
+    for (name, sockets) in [
+        ("sockets.control", &config.sockets.control),
+        ("sockets.tunnel", &config.sockets.tunnel),
+    ] {
+        let Some(interface) = &sockets.bind_interface else {
+            continue;
+        };
+        if !cfg!(any(target_os = "linux", target_os = "android")) {
+            report.fail(&format!(
+                "{}.bind_interface is only supported on Linux; use bind_addr",
+                name
+            ));
+        } else if !Path::new("/sys/class/net").join(interface).exists() {
+            report.fail(&format!(
+                "{}.bind_interface: there is no interface '{}'",
+                name, interface
+            ));
+        }
+    }
+    for target in &config.wake_on_lan {
+        report.check(
+            &format!("Wake-on-LAN target {}", target.name),
+            wol::parse_mac(&target.mac),
+        );
+    }
+    if let Some(email) = config.alerts.as_ref().and_then(|a| a.email.as_ref()) {
+        report.check("Load alert email password", email.load_password());
+    }
+    if let Err(e) = tracing_subscriber::EnvFilter::try_new(&config.logging.level) {
+        report.fail(&format!("logging.level '{}': {}", config.logging.level, e));
+    }
+    if let Some(path) = &config.logging.file {
+        check_parent(report, "logging.file", path);
+    }
+    if let Some(path) = config
+        .control
+        .path
+        .as_ref()
+        .filter(|_| config.control.enabled)
+    {
+        check_parent(report, "control.path", path);
+    }
+}
+
+fn check_parent(report: &mut Report, name: &str, path: &Path) {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if parent.is_dir() {
+        report.pass(&format!("{} {}", name, path.display()));
+    } else {
+        report.fail(&format!(
+            "{} {}: directory {} does not exist",
+            name,
+            path.display(),
+            parent.display()
+        ));
+    }
+}
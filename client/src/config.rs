@@ -1,7 +1,19 @@
-use clap::Parser;
-use nat_traversal_common::config::{load_config, save_config, ClientConfig};
+use crate::expose::{parse_ttl, ExposeProtocol};
+use crate::remote_log;
+use clap::{Parser, Subcommand};
+use nat_traversal_common::{
+    config::{
+        apply_overrides, get_config_dir, load_config, save_config, ClientConfig, PortRange,
+        TunnelConfig,
+    },
+    logging,
+    protocol::{default_local_host, HttpAuth, TunnelProtocol},
+    telemetry::{self, Telemetry},
+};
+use nat_traversal_platform::service::ServiceAction;
 use std::path::PathBuf;
-use tracing::{error, info};
+use tracing::info;
+use uuid::Uuid;
 
 #[derive(Parser, Debug)]
 #[command(name = "nat-client")]
@@ -11,6 +23,10 @@ pub struct Args {
     #[arg(short, long)]
     pub config: Option<PathBuf>,
 
+    /// Configuration profile to use
+    #[arg(long)]
+    pub profile: Option<String>,
+
     /// Server address
     #[arg(short, long)]
     pub server: Option<String>,
@@ -23,6 +39,56 @@ pub struct Args {
     #[arg(short, long)]
     pub token: Option<String>,
 
+    /// File holding the client token
+    #[arg(long)]
+    pub token_file: Option<PathBuf>,
+
+    /// Client identifier
+    #[arg(long)]
+    pub client_id: Option<String>,
+
+    /// Skip TLS certificate verification (development only)
+    #[arg(long)]
+    pub insecure: bool,
+
+    /// Do not reconnect after the connection drops
+    #[arg(long)]
+    pub no_reconnect: bool,
+
+    /// Seconds to wait between reconnect attempts
+    #[arg(long)]
+    pub reconnect_interval: Option<u64>,
+
+    /// Add a tunnel: [LOCAL_HOST:]LOCAL_PORT[:REMOTE_PORT][/tcp|udp] (repeatable);
+    /// ports may be ranges such as 30000-30010
+    #[arg(long = "tunnel", value_name = "SPEC")]
+    pub tunnels: Vec<String>,
+
+    /// Close the --tunnel tunnels this many seconds after they open
+    #[arg(long, value_name = "SECS", requires = "tunnels")]
+    pub tunnel_ttl: Option<u64>,
+
+    /// Have the server ask for this user name and password before passing
+    /// HTTP requests to the --tunnel tunnels
+    #[arg(long, value_name = "USER:PASSWORD", requires = "tunnels")]
+    pub tunnel_auth: Option<String>,
+
+    /// Log level (trace, debug, info, warn, error)
+    #[arg(long)]
+    pub log_level: Option<String>,
+
+    /// Log file path
+    #[arg(long)]
+    pub log_file: Option<PathBuf>,
+
+    /// Override any configuration value, e.g. --set server.tls_verify=false
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>,
+
+    /// Keep configuration next to the executable instead of the user config directory
+    #[arg(long)]
+    pub portable: bool,
+
     /// Generate default configuration file
     #[arg(long)]
     pub generate_config: bool,
@@ -34,16 +100,175 @@ pub struct Args {
     /// Verbose logging
     #[arg(short, long)]
     pub verbose: bool,
+
+    /// Run under the Windows service control manager (used by `service install`)
+    #[arg(long, hide = true)]
+    pub service: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Manage the nat-client system service
+    Service {
+        /// Service name
+        #[arg(long, default_value = "nat-client")]
+        name: String,
+
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+
+    /// Check connectivity and report how the local NAT behaves
+    Diagnose,
+
+    /// Validate the configuration and the files it refers to without
+    /// connecting
+    CheckConfig,
+
+    /// Measure latency and throughput through a temporary tunnel, in
+    /// both directions
+    Speedtest {
+        /// Megabytes to transfer each way
+        #[arg(long, default_value_t = 10)]
+        size_mb: u64,
+    },
+
+    /// Open a tunnel to a local port until Ctrl+C, ignoring configured
+    /// tunnels
+    Expose {
+        /// Local port to expose
+        port: u16,
+
+        #[arg(long, value_enum, default_value = "tcp")]
+        protocol: ExposeProtocol,
+
+        /// Close the tunnel after this long, e.g. 90s, 15m, 1h or 2d
+        #[arg(long, value_parser = parse_ttl)]
+        ttl: Option<u64>,
+    },
+
+    /// Bridge stdin and stdout to a port on the server, e.g. as an SSH
+    /// ProxyCommand
+    Stdio {
+        /// Public port on the server
+        #[arg(required_unless_present = "visitor")]
+        port: Option<u16>,
+
+        /// Go through this configured e2e visitor's encrypted tunnel instead
+        #[arg(long, conflicts_with = "port")]
+        visitor: Option<String>,
+    },
+
+    /// Show the running client's connection and tunnels
+    Status {
+        /// Print JSON for scripts
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Have the running client fetch and save a replacement token
+    RotateToken,
+
+    /// Open a temporary random public port for a running tunnel
+    Share {
+        /// Tunnel name or ID
+        tunnel: String,
+
+        /// Seconds until the server closes the share
+        #[arg(long, default_value_t = 3600)]
+        ttl: u64,
+
+        /// Require visitors to send this as their first line
+        #[arg(long)]
+        password: Option<String>,
+    },
+
+    /// Close a share before it expires
+    Unshare {
+        /// Share ID, as printed by share or status
+        share_id: Uuid,
+    },
+
+    /// Have another client send a Wake-on-LAN packet for a machine on its
+    /// LAN
+    Wake {
+        /// Client ID of the client on the machine's LAN
+        peer: String,
+
+        /// Name of the machine in that client's wake_on_lan list
+        target: String,
+    },
+
+    /// Compare the running client's tunnels with the ones the server
+    /// holds for it
+    CheckTunnels,
+
+    /// List the requests the running client captured from custom-domain
+    /// tunnels
+    Requests {
+        /// Print JSON for scripts
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Send a captured request to the local service again
+    Replay {
+        /// Request ID, as printed by requests
+        id: u64,
+
+        /// Open the request in $EDITOR before sending it
+        #[arg(long)]
+        edit: bool,
+    },
+
+    /// Print the DNS TXT record that lets this client bind a domain
+    DomainChallenge {
+        /// Domain to serve through the server's HTTP listener
+        domain: String,
+    },
+
+    /// Generate a key pair for end-to-end encrypted tunnels
+    E2eKeygen,
 }
 
 pub fn load_client_config(args: &Args) -> anyhow::Result<ClientConfig> {
-    let mut config: ClientConfig = if let Some(config_path) = &args.config {
+    let file_config: ClientConfig = if let Some(config_path) = &args.config {
         let content = std::fs::read_to_string(config_path)?;
         toml::from_str(&content)?
     } else {
         load_config("client.toml")?
     };
 
+    // Select profile before applying overrides so flags win over profile values
+    let mut config = file_config.resolve_profile(args.profile.as_deref())?;
+
+    // Generic overrides first so dedicated flags take precedence
+    if !args.overrides.is_empty() {
+        let active_profile = config.active_profile.take();
+        config = apply_overrides(&config, &args.overrides)?;
+        config.active_profile = active_profile;
+    }
+
+    // Merge tunnels dropped into the tunnel directory, resolved relative to
+    // the directory holding the configuration file
+    let base_dir = match &args.config {
+        Some(config_path) => config_path
+            .parent()
+            .unwrap_or_else(|| std::path::Path::new("."))
+            .to_path_buf(),
+        None => get_config_dir()?,
+    };
+    let tunnels_dir = base_dir.join(
+        config
+            .tunnels_dir
+            .as_deref()
+            .unwrap_or("tunnels.d".as_ref()),
+    );
+    config.merge_tunnel_dir(&tunnels_dir)?;
+
     // Override with command line arguments
     if let Some(server) = &args.server {
         config.server.addr = server.clone();
@@ -57,6 +282,55 @@ pub fn load_client_config(args: &Args) -> anyhow::Result<ClientConfig> {
         config.server.token = token.clone();
     }
 
+    if let Some(path) = &args.token_file {
+        config.server.token_file = Some(path.clone());
+    }
+
+    if let Some(client_id) = &args.client_id {
+        config.server.client_id = client_id.clone();
+    }
+
+    if args.insecure {
+        config.server.tls_verify = false;
+    }
+
+    if args.no_reconnect {
+        config.server.auto_reconnect = false;
+    }
+
+    if let Some(interval) = args.reconnect_interval {
+        config.server.reconnect_interval_secs = interval;
+    }
+
+    let http_auth = args
+        .tunnel_auth
+        .as_deref()
+        .map(|credentials| match credentials.split_once(':') {
+            Some((username, password)) if !username.is_empty() && !password.is_empty() => {
+                Ok(HttpAuth {
+                    username: Some(username.to_string()),
+                    password: Some(password.to_string()),
+                    bearer_token: None,
+                })
+            }
+            _ => Err(anyhow::anyhow!("--tunnel-auth takes USER:PASSWORD")),
+        })
+        .transpose()?;
+    for spec in &args.tunnels {
+        let mut tunnel = parse_tunnel_spec(spec)?;
+        tunnel.ttl_secs = args.tunnel_ttl;
+        tunnel.http_auth = http_auth.clone();
+        config.tunnels.push(tunnel);
+    }
+
+    if let Some(level) = &args.log_level {
+        config.logging.level = level.clone();
+    }
+
+    if let Some(file) = &args.log_file {
+        config.logging.file = Some(file.clone());
+    }
+
     if args.no_gui {
         config.gui.enabled = false;
     }
@@ -65,9 +339,98 @@ pub fn load_client_config(args: &Args) -> anyhow::Result<ClientConfig> {
         config.logging.level = "debug".to_string();
     }
 
+    config.config_path = Some(match &args.config {
+        Some(config_path) => config_path.clone(),
+        None => get_config_dir()?.join("client.toml"),
+    });
+
     Ok(config)
 }
 
+/// Parse a `[LOCAL_HOST:]LOCAL_PORT[:REMOTE_PORT][/tcp|udp]` tunnel
+/// specification, where both ports may be ranges like `30000-30010`
+fn parse_tunnel_spec(spec: &str) -> anyhow::Result<TunnelConfig> {
+    let (ports, protocol) = match spec.split_once('/') {
+        Some((ports, "tcp")) => (ports, TunnelProtocol::Tcp),
+        Some((ports, "udp")) => (ports, TunnelProtocol::Udp),
+        Some((_, other)) => return Err(anyhow::anyhow!("Unknown tunnel protocol '{}'", other)),
+        None => (spec, TunnelProtocol::Tcp),
+    };
+
+    let range = |ports: &str| ports.parse::<PortRange>().map_err(anyhow::Error::msg);
+    let parts: Vec<&str> = ports.split(':').collect();
+    let (local_host, local, remote) = match parts.as_slice() {
+        [local] => (default_local_host(), range(local)?, None),
+        [first, second] => match range(first) {
+            Ok(local) => (default_local_host(), local, Some(range(second)?)),
+            Err(_) => (first.to_string(), range(second)?, None),
+        },
+        [host, local, remote] => (host.to_string(), range(local)?, Some(range(remote)?)),
+        _ => return Err(anyhow::anyhow!("Invalid tunnel specification '{}'", spec)),
+    };
+    let port_count = local.end - local.start + 1;
+    if let Some(remote) = remote.filter(|remote| remote.start != remote.end) {
+        if remote.end - remote.start + 1 != port_count {
+            return Err(anyhow::anyhow!(
+                "Local and remote port ranges of '{}' differ in length",
+                spec
+            ));
+        }
+    }
+
+    Ok(TunnelConfig {
+        name: format!("cli-{}", spec),
+        local_host,
+        local_port: local.start,
+        remote_port: remote.map(|remote| remote.start),
+        port_count,
+        protocol,
+        auto_start: true,
+        ttl_secs: None,
+        schedule: Vec::new(),
+        domain: None,
+        http_auth: None,
+        tls_cert: None,
+        tls_key: None,
+        tls_passthrough: false,
+        https_redirect: false,
+        http_headers: None,
+        port_mapping: false,
+        e2e_peers: Vec::new(),
+        stages: Vec::new(),
+        dscp: None,
+        udp: None,
+        source: None,
+    })
+}
+
+/// Arguments the installed service runs with, pinning the configuration
+/// file so it is found regardless of the service account's home directory
+pub fn service_arguments(args: &Args) -> anyhow::Result<Vec<String>> {
+    let config_path = match &args.config {
+        Some(path) => std::fs::canonicalize(path)?,
+        None => get_config_dir()?.join("client.toml"),
+    };
+
+    let mut arguments = vec![
+        "--config".to_string(),
+        config_path.to_string_lossy().into_owned(),
+    ];
+
+    if let Some(profile) = &args.profile {
+        arguments.push("--profile".to_string());
+        arguments.push(profile.clone());
+    }
+
+    arguments.push("--no-gui".to_string());
+
+    if cfg!(windows) {
+        arguments.push("--service".to_string());
+    }
+
+    Ok(arguments)
+}
+
 pub fn generate_default_config() -> anyhow::Result<()> {
     let config = ClientConfig::default();
     save_config(&config, "client.toml")?;
@@ -75,11 +438,35 @@ pub fn generate_default_config() -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn setup_logging(config: &ClientConfig) -> anyhow::Result<()> {
-    use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+pub fn setup_logging(config: &ClientConfig) -> anyhow::Result<Telemetry> {
+    use tracing_subscriber::{
+        field::MakeExt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
+    };
 
-    let env_filter =
-        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.logging.level));
+    let (otlp_layer, telemetry) = telemetry::init(config.logging.otlp.as_ref(), "nat-client")?;
+    let mut root_layers: Vec<_> = otlp_layer.into_iter().collect();
+    root_layers.extend(logging::sink_layers(&config.logging, "nat-client")?);
+    let root_layer = logging::combine(root_layers);
+
+    let default_filter = std::env::var(EnvFilter::DEFAULT_ENV)
+        .ok()
+        .filter(|filter| EnvFilter::try_new(filter).is_ok())
+        .unwrap_or_else(|| config.logging.level.clone());
+    let (env_filter, recent) = remote_log::init(default_filter, config.logging.allow_remote);
+    // What the operator may fetch through the server. Span fields get a
+    // formatter of their own, as fmt layers sharing one also share the
+    // console's colored rendering of them.
+    let recent_layer = tracing_subscriber::fmt::layer()
+        .with_ansi(false)
+        .with_target(false)
+        .fmt_fields(
+            tracing_subscriber::fmt::format::debug_fn(|writer, field, value| match field.name() {
+                "message" => write!(writer, "{:?}", value),
+                name => write!(writer, "{}={:?}", name, value),
+            })
+            .delimited(" "),
+        )
+        .with_writer(recent);
 
     let fmt_layer = tracing_subscriber::fmt::layer()
         .with_target(false)
@@ -98,16 +485,20 @@ pub fn setup_logging(config: &ClientConfig) -> anyhow::Result<()> {
         let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
 
         tracing_subscriber::registry()
+            .with(root_layer)
             .with(env_filter)
             .with(fmt_layer)
+            .with(recent_layer)
             .with(tracing_subscriber::fmt::layer().with_writer(non_blocking))
             .init();
     } else {
         tracing_subscriber::registry()
+            .with(root_layer)
             .with(env_filter)
             .with(fmt_layer)
+            .with(recent_layer)
             .init();
     }
 
-    Ok(())
+    Ok(telemetry)
 }
@@ -1,19 +1,58 @@
-use chrono::Utc;
+use crate::alert::Alerter;
+use crate::capture::RequestLog;
+use crate::credentials::{self, TokenStore};
+use crate::e2e;
+use crate::forwarder::{self, LocalForwarder};
+use crate::provision::{self, Provisioned};
+use crate::remote_log;
+use crate::resync::{Difference, Divergence, ServerTunnels, TunnelLists};
+use crate::share::Shares;
+use crate::speedtest::{SpeedTests, TEST_TIMEOUT};
+use crate::transport::{ClientTransport, TlsTransport};
+use crate::vpn::Vpn;
+use crate::wake::Wakes;
+use crate::wireguard::WireGuard;
+use chrono::{Local, Utc};
 use nat_traversal_common::{
-    config::ClientConfig,
+    batch::{self, FrameLimit},
+    config::{ClientConfig, Dscp, FlushPolicy, TunnelConfig},
+    crypto, data_channel,
     error::{NatError, NatResult},
-    protocol::{Message, TunnelInfo, TunnelProtocol, PROTOCOL_VERSION},
+    protocol::{
+        Candidate, HttpAuth, HttpHeaders, Message, RelayInfo, ShareInfo, ShutdownDirection,
+        SpeedTestReport, TlsCertificate, TunnelInfo, TunnelMode, TunnelProtocol, UdpSessions,
+        UsageInfo, Utilization, PROTOCOL_VERSION,
+    },
+    schedule,
+    stun::NatReport,
+    telemetry, tls,
+    transport::{self, Connector, Transport},
 };
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::net::TcpStream;
-use tokio::sync::{mpsc, Mutex, RwLock};
-use tokio_rustls::{rustls, TlsConnector, TlsStream};
-use tracing::{debug, error, info, warn};
+use tokio::sync::{mpsc, Mutex, Notify, RwLock};
+use tokio::task::JoinSet;
+use tokio_rustls::{rustls, TlsConnector};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 use uuid::Uuid;
 
-pub type SecureClientStream = TlsStream<TcpStream>;
+/// The server's answer to `Auth`, or why the token was rejected
+type AuthReply = Result<Accepted, String>;
+
+/// What the server offers an authenticated client
+struct Accepted {
+    /// Key for attaching data channels
+    data_channel: Option<Uuid>,
+    /// The largest frame the server accepts, when it says
+    max_frame_size: Option<u32>,
+}
+
+/// Authentication messages the read task hands to `authenticate`
+enum AuthEvent {
+    Challenge(String),
+    Verdict(AuthReply),
+}
 
 /// Connection state for the client
 #[derive(Debug, Clone, PartialEq)]
@@ -25,6 +64,18 @@ pub enum ConnectionState {
     Error(String),
 }
 
+impl std::fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionState::Disconnected => write!(f, "disconnected"),
+            ConnectionState::Connecting => write!(f, "connecting"),
+            ConnectionState::Connected => write!(f, "connected"),
+            ConnectionState::Authenticated => write!(f, "authenticated"),
+            ConnectionState::Error(e) => write!(f, "error: {}", e),
+        }
+    }
+}
+
 /// Statistics for client connection
 #[derive(Debug, Clone, Default)]
 pub struct ConnectionStats {
@@ -32,7 +83,36 @@ pub struct ConnectionStats {
     pub bytes_received: u64,
     pub reconnect_count: u32,
     pub last_ping_time: Option<chrono::DateTime<Utc>>,
+    /// Heartbeat round trips of the current session
+    pub rtt: RttStats,
     pub uptime: chrono::Duration,
+    /// NAT behavior found by STUN, once detection has finished
+    pub nat: Option<NatReport>,
+    /// This month's traffic as the server counts it against the quota
+    pub usage: Option<UsageInfo>,
+    /// The message the server sent at this session's login
+    pub motd: Option<String>,
+    /// How close the server is to its capacity limits
+    pub utilization: Option<Utilization>,
+}
+
+/// Round trips of heartbeats to the server and back
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RttStats {
+    pub last: Option<std::time::Duration>,
+    pub avg: Option<std::time::Duration>,
+    pub max: Option<std::time::Duration>,
+    samples: u32,
+}
+
+impl RttStats {
+    fn record(&mut self, rtt: std::time::Duration) {
+        let total = self.avg.unwrap_or_default() * self.samples + rtt;
+        self.samples += 1;
+        self.last = Some(rtt);
+        self.avg = Some(total / self.samples);
+        self.max = Some(self.max.map_or(rtt, |max| max.max(rtt)));
+    }
 }
 
 /// Manages the connection to the server
@@ -40,27 +120,106 @@ pub struct ServerConnection {
     config: ClientConfig,
     state: Arc<RwLock<ConnectionState>>,
     tunnels: Arc<RwLock<HashMap<Uuid, TunnelInfo>>>,
+    relays: Arc<RwLock<HashMap<Uuid, RelayInfo>>>,
+    shares: Arc<Shares>,
+    wakes: Arc<Wakes>,
+    speed_tests: Arc<SpeedTests>,
+    tunnel_lists: Arc<TunnelLists>,
+    /// Tunnels the server told this client to open
+    provisioned: Arc<Provisioned>,
+    /// The last request for each named tunnel opened by hand, to reopen
+    /// it the same way
+    requested: RwLock<HashMap<String, Message>>,
+    /// Receives candidate offers and answers from other clients
+    signaling: Arc<RwLock<Option<mpsc::UnboundedSender<Message>>>>,
+    /// Configured tunnels served through a router port mapping instead
+    direct_tunnels: RwLock<HashSet<String>>,
+    /// When configured tunnels with a TTL expire, fixed when they first
+    /// open so reconnecting does not extend them
+    tunnel_deadlines: RwLock<HashMap<String, chrono::DateTime<Utc>>>,
+    /// Whether each scheduled tunnel was in its window when last checked
+    schedule_states: RwLock<HashMap<String, bool>>,
     stats: Arc<RwLock<ConnectionStats>>,
+    /// Counted outside `stats` so reading a frame takes no lock
+    bytes_received: Arc<AtomicU64>,
     message_sender: Arc<Mutex<Option<mpsc::UnboundedSender<Message>>>>,
-    tls_connector: TlsConnector,
+    forwarder: Arc<LocalForwarder>,
+    requests: Arc<RequestLog>,
+    /// Its TLS settings are rebuilt once enrollment has issued a client
+    /// certificate
+    transport: ClientTransport,
+    alerter: Option<Arc<Alerter>>,
+    tokens: Arc<TokenStore>,
+    /// Set by `disconnect` so the session is not reconnected
+    stopping: AtomicBool,
+    /// Ends the current session without waiting for the server
+    hang_up: Notify,
+    /// The layer-3 VPN, when enabled
+    vpn: Option<Arc<Vpn>>,
+    /// The WireGuard mesh, when enabled
+    wireguard: Option<Arc<WireGuard>>,
 }
 
 impl ServerConnection {
     pub async fn new(config: ClientConfig) -> NatResult<Self> {
         let tls_connector = Self::setup_tls(&config).await?;
+        let transport = TlsTransport::new(
+            &config.server,
+            config.sockets.control.clone(),
+            tls_connector,
+        );
+        Self::with_transport(config, ClientTransport::Tls(Box::new(transport)))
+    }
+
+    /// A connection reaching the server over `transport`
+    pub fn with_transport(config: ClientConfig, transport: ClientTransport) -> NatResult<Self> {
+        let e2e = e2e::tunnel_keys(&config).map_err(|e| NatError::config(e.to_string()))?;
+        let requests = Arc::new(RequestLog::new(config.capture.clone()));
+        let pipelines = forwarder::pipelines(&config.tunnels)?;
+        let forwarder = Arc::new(LocalForwarder::new(
+            config.performance,
+            config.sockets.tunnel.clone(),
+            forwarder::dscp_classes(&config.tunnels),
+            e2e,
+            pipelines,
+            requests.clone(),
+            config.server.resume_limits(),
+            config.server.connection_buffer(),
+        ));
+        let wakes = Arc::new(Wakes::new(config.wake_on_lan.clone()));
 
         Ok(Self {
+            alerter: Alerter::new(&config),
+            tokens: Arc::new(TokenStore::new(&config)),
+            stopping: AtomicBool::new(false),
+            hang_up: Notify::new(),
+            provisioned: Arc::new(Provisioned::new(&config.tunnels)),
+            requested: RwLock::new(HashMap::new()),
+            vpn: Vpn::new(&config.vpn),
+            wireguard: WireGuard::new(&config),
             config,
             state: Arc::new(RwLock::new(ConnectionState::Disconnected)),
             tunnels: Arc::new(RwLock::new(HashMap::new())),
+            relays: Arc::new(RwLock::new(HashMap::new())),
+            shares: Arc::new(Shares::default()),
+            wakes,
+            speed_tests: Arc::new(SpeedTests::default()),
+            tunnel_lists: Arc::new(TunnelLists::default()),
+            signaling: Arc::new(RwLock::new(None)),
+            direct_tunnels: RwLock::new(HashSet::new()),
+            tunnel_deadlines: RwLock::new(HashMap::new()),
+            schedule_states: RwLock::new(HashMap::new()),
             stats: Arc::new(RwLock::new(ConnectionStats::default())),
+            bytes_received: Arc::new(AtomicU64::new(0)),
             message_sender: Arc::new(Mutex::new(None)),
-            tls_connector,
+            forwarder,
+            requests,
+            transport,
         })
     }
 
     async fn setup_tls(config: &ClientConfig) -> NatResult<TlsConnector> {
-        let tls_config = if config.server.tls_verify {
+        let builder = if config.server.tls_verify {
             // Use standard certificate verification
             let mut root_cert_store = rustls::RootCertStore::empty();
             root_cert_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
@@ -71,19 +230,20 @@ impl ServerConnection {
                 )
             }));
 
-            rustls::ClientConfig::builder()
-                .with_safe_defaults()
-                .with_root_certificates(root_cert_store)
-                .with_no_client_auth()
+            tls::restrict(rustls::ClientConfig::builder(), &config.server.tls)?
+                .with_custom_certificate_verifier(Arc::new(rustls::client::WebPkiVerifier::new(
+                    root_cert_store,
+                    None,
+                )))
         } else {
             // For development: accept all certificates
             warn!("TLS certificate verification is disabled!");
-            
+
             use rustls::{client::ServerCertVerifier, Certificate, Error, ServerName};
             use std::time::SystemTime;
-            
+
             struct DangerousVerifier;
-            
+
             impl ServerCertVerifier for DangerousVerifier {
                 fn verify_server_cert(
                     &self,
@@ -97,37 +257,106 @@ impl ServerConnection {
                     Ok(rustls::client::ServerCertVerified::assertion())
                 }
             }
-            
-            rustls::ClientConfig::builder()
-                .with_safe_defaults()
+
+            tls::restrict(rustls::ClientConfig::builder(), &config.server.tls)?
                 .with_custom_certificate_verifier(Arc::new(DangerousVerifier))
-                .with_no_client_auth()
         };
 
+        let mut tls_config = match Self::client_certificate(config)? {
+            Some((chain, key)) => builder
+                .with_client_auth_cert(chain, key)
+                .map_err(|e| NatError::config(format!("Unusable client certificate: {}", e)))?,
+            None => builder.with_no_client_auth(),
+        };
+        tls_config.alpn_protocols = tls::alpn_protocols(&config.server.tls);
         Ok(TlsConnector::from(Arc::new(tls_config)))
     }
 
-    pub async fn connect(&self) -> NatResult<()> {
-        self.set_state(ConnectionState::Connecting).await;
+    /// The configured client certificate and key, once they exist
+    fn client_certificate(
+        config: &ClientConfig,
+    ) -> NatResult<Option<(Vec<rustls::Certificate>, rustls::PrivateKey)>> {
+        let (Some(cert_path), Some(key_path)) =
+            (&config.server.client_cert, &config.server.client_key)
+        else {
+            return Ok(None);
+        };
+        if !cert_path.exists() {
+            return Ok(None);
+        }
+        let read = |path: &std::path::Path| {
+            std::fs::read(path)
+                .map_err(|e| NatError::config(format!("Failed to read {}: {}", path.display(), e)))
+        };
+        let chain: Vec<_> = rustls_pemfile::certs(&mut read(cert_path)?.as_slice())
+            .map_err(|e| NatError::config(format!("Failed to parse client certificate: {}", e)))?
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect();
+        let key = rustls_pemfile::pkcs8_private_keys(&mut read(key_path)?.as_slice())
+            .map_err(|e| NatError::config(format!("Failed to parse client key: {}", e)))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| NatError::config("No private key found for the client certificate"))?;
+        if chain.is_empty() {
+            return Err(NatError::config("No client certificate found"));
+        }
+        Ok(Some((chain, rustls::PrivateKey(key))))
+    }
 
-        let server_addr = format!("{}:{}", self.config.server.addr, self.config.server.port);
+    /// Whether connections present a client certificate
+    fn has_certificate(&self) -> bool {
+        self.config
+            .server
+            .client_cert
+            .as_ref()
+            .is_some_and(|path| path.exists())
+            && self.config.server.client_key.is_some()
+    }
 
-        // Connect to server
-        let tcp_stream = TcpStream::connect(&server_addr).await.map_err(|e| {
-            NatError::connection(format!("Failed to connect to {}: {}", server_addr, e))
-        })?;
+    /// Open a connection to the server
+    async fn open_stream(&self) -> NatResult<Box<dyn Transport>> {
+        self.transport.connect().await
+    }
 
-        // Perform TLS handshake
-        let server_name = rustls::ServerName::try_from(self.config.server.addr.as_str())
-            .map_err(|e| NatError::tls(format!("Invalid server name: {}", e)))?;
+    /// Complete a TLS handshake with the server and return the certificate
+    /// chain it presented, without authenticating
+    pub async fn handshake(&self) -> NatResult<Vec<rustls::Certificate>> {
+        let stream = self
+            .transport
+            .tls()
+            .ok_or_else(|| NatError::tls("Not connected to the server over TLS"))?
+            .connect()
+            .await?;
+        Ok(stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .map(<[_]>::to_vec)
+            .unwrap_or_default())
+    }
 
-        let tls_stream = self
-            .tls_connector
-            .connect(server_name, tcp_stream)
-            .await
-            .map_err(|e| NatError::tls(format!("TLS handshake failed: {}", e)))?;
+    pub async fn connect(&self) -> NatResult<()> {
+        let span = info_span!(
+            "server",
+            addr = %self.config.server.addr,
+            port = self.config.server.port
+        );
+        self.run_session().instrument(span).await
+    }
 
-        info!("Connected to server: {}", server_addr);
+    /// Connect and serve one session until the connection is lost
+    async fn run_session(&self) -> NatResult<()> {
+        self.set_state(ConnectionState::Connecting).await;
+        self.stats.write().await.rtt = RttStats::default();
+
+        self.enroll().await?;
+        let tls_stream = self.open_stream().await?;
+
+        info!(
+            "Connected to server: {}:{}",
+            self.config.server.addr, self.config.server.port
+        );
         self.set_state(ConnectionState::Connected).await;
 
         // Setup message handling
@@ -137,112 +366,760 @@ impl ServerConnection {
         // Start message handling tasks
         let (read_half, write_half) = tokio::io::split(tls_stream);
 
-        let write_task = {
+        let performance = self.config.performance;
+        // Set to what the server advertises once it accepts us
+        let frame_limit = FrameLimit::default();
+        let mut write_task = {
             let message_rx = message_rx;
-            tokio::spawn(async move { Self::handle_write(write_half, message_rx).await })
+            let frame_limit = frame_limit.clone();
+            tokio::spawn(async move {
+                Self::handle_write(write_half, message_rx, performance.flush, &frame_limit).await
+            })
         };
 
-        let read_task = {
+        let (auth_events_tx, auth_events) = mpsc::unbounded_channel();
+        let mut read_task = {
             let state = self.state.clone();
             let tunnels = self.tunnels.clone();
+            let relays = self.relays.clone();
+            let shares = self.shares.clone();
+            let wakes = self.wakes.clone();
+            let speed_tests = self.speed_tests.clone();
+            let tunnel_lists = self.tunnel_lists.clone();
+            let provisioned = self.provisioned.clone();
+            let signaling = self.signaling.clone();
             let stats = self.stats.clone();
-            tokio::spawn(async move { Self::handle_read(read_half, state, tunnels, stats).await })
+            let bytes_received = self.bytes_received.clone();
+            let forwarder = self.forwarder.clone();
+            let tokens = self.tokens.clone();
+            let vpn = self.vpn.clone();
+            let wireguard = self.wireguard.clone();
+            let message_tx = message_tx.clone();
+            let heartbeat_timeout = self.config.server.heartbeat().timeout();
+            tokio::spawn(
+                async move {
+                    Self::handle_read(
+                        read_half,
+                        auth_events_tx,
+                        state,
+                        tunnels,
+                        relays,
+                        shares,
+                        wakes,
+                        speed_tests,
+                        tunnel_lists,
+                        provisioned,
+                        signaling,
+                        stats,
+                        bytes_received,
+                        forwarder,
+                        tokens,
+                        vpn,
+                        wireguard,
+                        message_tx,
+                        performance.max_frame_size,
+                        heartbeat_timeout,
+                    )
+                    .await
+                }
+                .in_current_span(),
+            )
         };
 
         // Authenticate
-        self.authenticate().await?;
+        let data_key = match self.authenticate(auth_events).await {
+            Ok(accepted) => {
+                frame_limit.set(accepted.max_frame_size);
+                accepted.data_channel
+            }
+            Err(e) => {
+                read_task.abort();
+                write_task.abort();
+                *self.message_sender.lock().await = None;
+                return Err(e);
+            }
+        };
+
+        // Move tunnel traffic off the control connection when the server
+        // offers a data channel
+        let mut data_tasks = JoinSet::new();
+        // VPN packets take the first data channel when there is one
+        let mut uplink = None;
+        if self.config.server.data_channel {
+            match data_key {
+                Some(key) => {
+                    for _ in 0..self.config.server.data_connections.max(1) {
+                        match self
+                            .attach_data_channel(key, &frame_limit, &mut data_tasks)
+                            .await
+                        {
+                            Ok(sender) => {
+                                uplink.get_or_insert(sender);
+                            }
+                            Err(e) => {
+                                warn!("Failed to attach data channel: {}", e);
+                                break;
+                            }
+                        }
+                    }
+                    if data_tasks.is_empty() {
+                        warn!("No data channel, tunnel data stays on the control connection");
+                    }
+                }
+                None => debug!("Server offered no data channel"),
+            }
+        }
+        // The server spreads connections over every channel, so losing any
+        // one of them ends the session
+        let data_task = async {
+            if data_tasks.is_empty() {
+                std::future::pending::<()>().await;
+            }
+            let _ = data_tasks.join_next().await;
+        };
+
+        // Catch up on what changed on the server while we were away
+        self.reconcile().await;
+        self.forwarder.resume_all(&message_tx);
+
+        // Create configured tunnels that are not already active
+        self.start_auto_tunnels().await;
+        self.publish_services().await;
+        if let Some(vpn) = &self.vpn {
+            vpn.join(&message_tx, uplink.unwrap_or_else(|| message_tx.clone()));
+        }
+        if let Some(wireguard) = &self.wireguard {
+            wireguard.join(&message_tx).await;
+        }
 
         // Start heartbeat
-        let heartbeat_task = {
+        let mut heartbeat_task = {
             let message_tx = message_tx.clone();
-            tokio::spawn(async move { Self::heartbeat_loop(message_tx).await })
+            let every = self.config.server.heartbeat().interval_secs;
+            tokio::spawn(async move { Self::heartbeat_loop(message_tx, every).await })
         };
 
         // Wait for any task to complete (indicating disconnection)
         tokio::select! {
-            _ = write_task => {},
-            _ = read_task => {},
-            _ = heartbeat_task => {},
+            _ = &mut write_task => {},
+            _ = &mut read_task => {},
+            _ = &mut heartbeat_task => {},
+            _ = data_task => {},
+            _ = self.hang_up.notified() => {},
         }
+        // Dropping the stream's halves closes the connection, so the
+        // server notices at once
+        write_task.abort();
+        read_task.abort();
+        heartbeat_task.abort();
 
         self.set_state(ConnectionState::Disconnected).await;
         *self.message_sender.lock().await = None;
+        // Open connections may carry on in the next session
+        if self.stopping.load(Ordering::Relaxed) {
+            self.forwarder.close_all();
+        } else {
+            self.forwarder.hold_all();
+        }
+
+        // The server releases relays and shares when their owner disconnects
+        self.relays.write().await.clear();
+        self.shares.clear().await;
 
         Ok(())
     }
 
-    async fn authenticate(&self) -> NatResult<()> {
-        let auth_message = Message::Auth {
-            version: PROTOCOL_VERSION,
-            token: self.config.server.token.clone(),
+    /// Open another connection that carries tunnel traffic as binary
+    /// frames, served by a task in `tasks` that ends when it closes, and
+    /// return the sender for its frames
+    async fn attach_data_channel(
+        &self,
+        key: Uuid,
+        frame_limit: &FrameLimit,
+        tasks: &mut JoinSet<()>,
+    ) -> NatResult<mpsc::UnboundedSender<Message>> {
+        let mut stream = self.open_stream().await?;
+
+        // Identify the channel with control framing; binary frames follow
+        let attach = Message::AttachDataChannel {
             client_id: self.config.server.client_id.clone(),
+            key,
         };
+        transport::write_frame(&mut stream, &attach).await?;
+        let performance = self.config.performance;
+        match Self::read_reply(&mut stream, performance.max_frame_size).await? {
+            Message::DataChannelAttached => {}
+            other => {
+                return Err(NatError::protocol(format!(
+                    "Unexpected data channel reply: {:?}",
+                    other
+                )))
+            }
+        }
+        info!("Data channel attached");
+
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (data_tx, mut data_rx) = mpsc::unbounded_channel::<Message>();
+
+        let frame_limit = frame_limit.clone();
+        let write_task = tokio::spawn(async move {
+            batch::write_batched(
+                &mut writer,
+                &mut data_rx,
+                performance.flush,
+                &frame_limit,
+                data_channel::encode,
+            )
+            .await
+        });
+
+        let tunnels = self.tunnels.clone();
+        let bytes_received = self.bytes_received.clone();
+        let forwarder = self.forwarder.clone();
+        let vpn = self.vpn.clone();
+        let sender = data_tx.clone();
+        tasks.spawn(
+            async move {
+                let read_task = async {
+                    loop {
+                        match data_channel::read_message(&mut reader, performance.max_frame_size)
+                            .await
+                        {
+                            Ok(Some(Message::VpnPacket { data })) => {
+                                bytes_received.fetch_add(data.len() as u64, Ordering::Relaxed);
+                                if let Some(vpn) = &vpn {
+                                    vpn.deliver(data).await;
+                                }
+                            }
+                            Ok(Some(message)) => {
+                                if let Message::Data { data, .. } = &message {
+                                    bytes_received.fetch_add(data.len() as u64, Ordering::Relaxed);
+                                }
+                                Self::handle_tunnel_message(
+                                    message, &tunnels, &forwarder, &data_tx,
+                                )
+                                .await;
+                            }
+                            Ok(None) => break,
+                            Err(e) => {
+                                error!("Data channel error: {}", e);
+                                break;
+                            }
+                        }
+                    }
+                };
 
-        self.send_message(auth_message).await?;
+                tokio::select! {
+                    _ = write_task => {},
+                    _ = read_task => {},
+                }
+                warn!("Data channel closed");
+            }
+            .in_current_span(),
+        );
+        Ok(sender)
+    }
+
+    /// Obtain a client certificate from the server's CA when one is
+    /// configured but has not been issued yet
+    async fn enroll(&self) -> NatResult<()> {
+        let server = &self.config.server;
+        let (Some(cert_path), Some(key_path)) = (&server.client_cert, &server.client_key) else {
+            return Ok(());
+        };
+        if cert_path.exists() {
+            return Ok(());
+        }
+        info!("Enrolling for a client certificate");
+
+        let failed = |e: rcgen::Error| NatError::tls(format!("Failed to create key: {}", e));
+        let key = rcgen::KeyPair::generate().map_err(failed)?;
+        let mut params = rcgen::CertificateParams::new(Vec::<String>::new()).map_err(failed)?;
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, server.client_id.as_str());
+        let csr = params
+            .serialize_request(&key)
+            .and_then(|request| request.pem())
+            .map_err(failed)?;
+
+        let token = self
+            .tokens
+            .load()
+            .map_err(|e| NatError::config(e.to_string()))?;
+        let max_frame_size = self.config.performance.max_frame_size;
+        let mut stream = self.open_stream().await?;
+        let request = if token.matches('.').count() == 2 {
+            Message::Enroll {
+                client_id: server.client_id.clone(),
+                token,
+                proof: None,
+                csr,
+            }
+        } else {
+            transport::write_frame(&mut stream, &Message::RequestAuthChallenge).await?;
+            let nonce = match Self::read_reply(&mut stream, max_frame_size).await? {
+                Message::AuthChallenge { nonce } => nonce,
+                other => {
+                    return Err(NatError::protocol(format!(
+                        "Unexpected enrollment reply: {:?}",
+                        other
+                    )))
+                }
+            };
+            Message::Enroll {
+                client_id: server.client_id.clone(),
+                token: String::new(),
+                proof: Some(crypto::auth_proof(&token, &nonce, &server.client_id)),
+                csr,
+            }
+        };
+        transport::write_frame(&mut stream, &request).await?;
+        let certificate = match Self::read_reply(&mut stream, max_frame_size).await? {
+            Message::Enrolled { certificate } => certificate,
+            Message::Error { message, .. } => {
+                return Err(NatError::authentication(format!(
+                    "Enrollment refused: {}",
+                    message
+                )))
+            }
+            other => {
+                return Err(NatError::protocol(format!(
+                    "Unexpected enrollment reply: {:?}",
+                    other
+                )))
+            }
+        };
 
-        // Wait for authentication response
-        // Note: In a real implementation, you'd want to wait for the actual response
-        // For now, we'll assume success after sending
-        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-        self.set_state(ConnectionState::Authenticated).await;
+        // The certificate goes last: its presence means enrollment is done
+        let save = |path: &std::path::Path, contents: &str| {
+            credentials::write_atomically(path, contents.as_bytes())
+                .map_err(|e| NatError::config(format!("Failed to save {}: {}", path.display(), e)))
+        };
+        save(key_path, &key.serialize_pem())?;
+        save(cert_path, &certificate)?;
+        info!("Saved client certificate to {}", cert_path.display());
 
-        info!("Authenticated with server");
+        if let Some(tls) = self.transport.tls() {
+            tls.set_tls(Self::setup_tls(&self.config).await?).await;
+        }
         Ok(())
     }
 
-    async fn handle_write(
-        mut writer: tokio::io::WriteHalf<tokio_rustls::client::TlsStream<TcpStream>>,
-        mut message_rx: mpsc::UnboundedReceiver<Message>,
-    ) -> NatResult<()> {
-        use tokio::io::AsyncWriteExt;
+    /// Read the reply to a message sent with control framing on a
+    /// connection of its own
+    async fn read_reply<S: Transport>(stream: &mut S, max_len: usize) -> NatResult<Message> {
+        let reply = transport::read_frame(stream, max_len)
+            .await?
+            .ok_or_else(|| NatError::connection("The server closed the connection"))?;
+        Ok(Message::from_bytes(&reply)?)
+    }
 
-        while let Some(message) = message_rx.recv().await {
-            let data = message.to_bytes()?;
-            let len = data.len() as u32;
+    async fn start_auto_tunnels(&self) {
+        let active: Vec<Option<String>> = self
+            .tunnels
+            .read()
+            .await
+            .values()
+            .map(|t| t.name.clone())
+            .collect();
+        let direct = self.direct_tunnels.read().await;
+
+        let now = Local::now().naive_local();
 
-            // Write length prefix
-            writer.write_all(&len.to_be_bytes()).await?;
-            // Write message data
-            writer.write_all(&data).await?;
-            writer.flush().await?;
+        for tunnel_config in &self.config.tunnels {
+            if !tunnel_config.auto_start
+                || active.contains(&Some(tunnel_config.name.clone()))
+                || direct.contains(&tunnel_config.name)
+            {
+                continue;
+            }
+            if !tunnel_config.schedule.is_empty()
+                && !schedule::is_active(&tunnel_config.schedule, now)
+            {
+                debug!("Tunnel {} is outside its schedule", tunnel_config.name);
+                continue;
+            }
+            self.start_configured_tunnel(tunnel_config).await;
         }
+    }
 
-        Ok(())
+    /// Bring the tunnel list in line with what the server holds for this
+    /// client: adopt the tunnels it kept, reopen the ones it closed while
+    /// the client was away, and take its shares and relays as they are.
+    /// Without an answer the lists stay as they were.
+    async fn reconcile(&self) {
+        let held = match self.list_server_tunnels().await {
+            Ok(held) => held,
+            Err(e) => {
+                warn!("Could not check the tunnels with the server: {}", e);
+                return;
+            }
+        };
+        let local: Vec<_> = self.tunnels.read().await.values().cloned().collect();
+        let tunnels = Difference::between(&local, &held.tunnels, |tunnel| tunnel.id);
+
+        for tunnel in tunnels.missing {
+            info!(
+                "Tunnel {} on port {} is gone from the server, reopening it",
+                tunnel.name.as_deref().unwrap_or(&tunnel.id.to_string()),
+                tunnel.remote_port
+            );
+            self.tunnels.write().await.remove(&tunnel.id);
+            self.forwarder.close_tunnel(tunnel.id);
+            self.reopen(&tunnel).await;
+        }
+
+        for tunnel in tunnels.unknown {
+            // A configured tunnel defined differently since is replaced by
+            // a fresh one
+            let changed = self.config.tunnels.iter().find(|config| {
+                config.auto_start
+                    && tunnel.name.as_deref() == Some(config.name.as_str())
+                    && !provision::matches(config, &tunnel)
+            });
+            if let Some(config) = changed {
+                info!(
+                    "Tunnel {} the server kept was configured differently, replacing it",
+                    config.name
+                );
+                let _ = self.close_tunnel(tunnel.id).await;
+                continue;
+            }
+            info!(
+                "Adopting tunnel {} the server kept on port {}",
+                tunnel.name.as_deref().unwrap_or(&tunnel.id.to_string()),
+                tunnel.remote_port
+            );
+            self.tunnels.write().await.insert(tunnel.id, tunnel);
+        }
+
+        self.shares.adopt(held.shares).await;
+        *self.relays.write().await = held
+            .relays
+            .into_iter()
+            .map(|relay| (relay.id, relay))
+            .collect();
     }
 
-    async fn handle_read(
-        mut reader: tokio::io::ReadHalf<tokio_rustls::client::TlsStream<TcpStream>>,
-        state: Arc<RwLock<ConnectionState>>,
-        tunnels: Arc<RwLock<HashMap<Uuid, TunnelInfo>>>,
-        stats: Arc<RwLock<ConnectionStats>>,
-    ) -> NatResult<()> {
-        use tokio::io::AsyncReadExt;
+    /// Open a tunnel the server closed behind this client's back the way
+    /// it was opened. Unnamed tunnels opened by hand can't be told apart
+    /// and stay closed.
+    async fn reopen(&self, tunnel: &TunnelInfo) {
+        let Some(name) = tunnel.name.as_deref() else {
+            warn!("Not reopening unnamed tunnel {}", tunnel.id);
+            return;
+        };
+        if let Some(config) = self.config.tunnels.iter().find(|t| t.name == name) {
+            // Auto-started tunnels reopen along with the others
+            if !config.auto_start {
+                self.start_configured_tunnel(config).await;
+            }
+            return;
+        }
+        if let Some(config) = self.provisioned.get(name) {
+            if let Some(message_tx) = self.message_sender.lock().await.clone() {
+                self.provisioned
+                    .provision(config, &self.tunnels, &message_tx)
+                    .await;
+            }
+            return;
+        }
+        let request = self.requested.read().await.get(name).cloned();
+        let Some(mut request) = request else {
+            warn!("Not reopening tunnel {}, its request is unknown", name);
+            return;
+        };
+        // Only for the time it had left
+        if let (Message::CreateTunnel { ttl_secs, .. }, Some(expires_at)) =
+            (&mut request, tunnel.expires_at)
+        {
+            let remaining = (expires_at - Utc::now()).num_seconds();
+            if remaining <= 0 {
+                debug!("Not reopening expired tunnel {}", name);
+                return;
+            }
+            *ttl_secs = Some(remaining as u64);
+        }
+        if let Err(e) = self.send_message(request).await {
+            warn!("Failed to reopen tunnel {}: {}", name, e);
+        }
+    }
 
-        loop {
-            // Read message length
-            let mut len_buf = [0u8; 4];
-            if reader.read_exact(&mut len_buf).await.is_err() {
-                break;
+    async fn start_configured_tunnel(&self, tunnel_config: &TunnelConfig) {
+        // Reopen a tunnel with a TTL only for the time it has left
+        let ttl_secs = match tunnel_config.ttl_secs {
+            Some(ttl) => {
+                let now = Utc::now();
+                let deadline = *self
+                    .tunnel_deadlines
+                    .write()
+                    .await
+                    .entry(tunnel_config.name.clone())
+                    .or_insert_with(|| {
+                        now + chrono::Duration::seconds(ttl.min(u32::MAX as u64) as i64)
+                    });
+                let remaining = (deadline - now).num_seconds();
+                if remaining <= 0 {
+                    debug!("Not reopening expired tunnel {}", tunnel_config.name);
+                    return;
+                }
+                Some(remaining as u64)
+            }
+            None => None,
+        };
+        let tls_certificate = match tunnel_config.load_tls_certificate() {
+            Ok(certificate) => certificate,
+            Err(e) => {
+                warn!("Failed to start tunnel {}: {}", tunnel_config.name, e);
+                return;
             }
-            let len = u32::from_be_bytes(len_buf) as usize;
+        };
 
-            if len > 1024 * 1024 {
-                // 1MB limit
-                error!("Message too large: {} bytes", len);
-                break;
+        if let Err(e) = self
+            .create_tunnel(
+                tunnel_config.local_host.clone(),
+                tunnel_config.local_port,
+                tunnel_config.remote_port,
+                tunnel_config.port_count,
+                tunnel_config.protocol,
+                Some(tunnel_config.name.clone()),
+                ttl_secs,
+                tunnel_config.domain.clone(),
+                tunnel_config.http_auth.clone(),
+                tls_certificate,
+                tunnel_config.tls_passthrough,
+                tunnel_config.https_redirect,
+                tunnel_config.http_headers.clone(),
+                tunnel_config.dscp,
+                tunnel_config.udp,
+            )
+            .await
+        {
+            warn!("Failed to start tunnel {}: {}", tunnel_config.name, e);
+        }
+    }
+
+    /// Open scheduled tunnels whose window has started and close those
+    /// whose window has ended. Only changes are acted on, so a scheduled
+    /// tunnel closed by hand stays closed until its next window.
+    pub async fn apply_schedules(&self) {
+        if self.get_state().await != ConnectionState::Authenticated {
+            return;
+        }
+        let now = Local::now().naive_local();
+        for tunnel_config in &self.config.tunnels {
+            if !tunnel_config.auto_start || tunnel_config.schedule.is_empty() {
+                continue;
+            }
+            let active = schedule::is_active(&tunnel_config.schedule, now);
+            let was_active = self
+                .schedule_states
+                .write()
+                .await
+                .insert(tunnel_config.name.clone(), active);
+            if was_active.is_none_or(|was_active| was_active == active) {
+                continue;
+            }
+            let open = self
+                .tunnels
+                .read()
+                .await
+                .values()
+                .find(|t| t.name.as_deref() == Some(tunnel_config.name.as_str()))
+                .map(|t| t.id);
+            match (active, open) {
+                (true, None) => {
+                    info!("Opening tunnel {} for its schedule", tunnel_config.name);
+                    self.start_configured_tunnel(tunnel_config).await;
+                }
+                (false, Some(tunnel_id)) => {
+                    info!("Closing tunnel {} for its schedule", tunnel_config.name);
+                    if let Err(e) = self.close_tunnel(tunnel_id).await {
+                        warn!("Failed to close tunnel {}: {}", tunnel_config.name, e);
+                    }
+                }
+                _ => {}
             }
+        }
+    }
 
-            // Read message data
-            let mut data = vec![0u8; len];
-            if reader.read_exact(&mut data).await.is_err() {
-                break;
+    /// Announce configured direct services; the server forgets them when
+    /// the connection drops
+    async fn publish_services(&self) {
+        for service in &self.config.p2p.services {
+            let message = Message::PublishService {
+                name: service.name.clone(),
+            };
+            if let Err(e) = self.send_message(message).await {
+                warn!("Failed to publish service {}: {}", service.name, e);
             }
+        }
+    }
 
-            // Update stats
-            {
-                let mut stats_guard = stats.write().await;
-                stats_guard.bytes_received += len as u64;
+    /// Leave a configured tunnel to the router port mapping rather than
+    /// creating it on the server
+    pub async fn set_direct(&self, name: &str) {
+        self.direct_tunnels.write().await.insert(name.to_string());
+    }
+
+    /// Authenticate and wait for the verdict, returning what the server
+    /// offers. A client certificate speaks for itself. Static
+    /// tokens are proven against a server nonce rather than sent; signed
+    /// tokens expire on their own and the server needs them whole to check
+    /// the signature.
+    async fn authenticate(
+        &self,
+        mut events: mpsc::UnboundedReceiver<AuthEvent>,
+    ) -> NatResult<Accepted> {
+        let token = self
+            .tokens
+            .load()
+            .map_err(|e| NatError::config(e.to_string()))?;
+        let client_id = self.config.server.client_id.clone();
+        let heartbeat = Some(self.config.server.heartbeat());
+        let max_frame_size = Some(self.config.performance.max_frame_size as u32);
+        let deadline = tokio::time::Instant::now() + tokio::time::Duration::from_secs(10);
+
+        let auth_message = if self.has_certificate() {
+            Message::Auth {
+                version: PROTOCOL_VERSION,
+                token: String::new(),
+                client_id,
+                proof: None,
+                heartbeat,
+                max_frame_size,
+            }
+        } else if token.matches('.').count() == 2 {
+            Message::Auth {
+                version: PROTOCOL_VERSION,
+                token,
+                client_id,
+                proof: None,
+                heartbeat,
+                max_frame_size,
+            }
+        } else {
+            self.send_message(Message::RequestAuthChallenge).await?;
+            let nonce = match Self::next_auth_event(&mut events, deadline).await? {
+                AuthEvent::Challenge(nonce) => nonce,
+                AuthEvent::Verdict(_) => {
+                    return Err(NatError::authentication(
+                        "Server answered without an authentication challenge",
+                    ))
+                }
+            };
+            Message::Auth {
+                version: PROTOCOL_VERSION,
+                token: String::new(),
+                proof: Some(crypto::auth_proof(&token, &nonce, &client_id)),
+                client_id,
+                heartbeat,
+                max_frame_size,
             }
+        };
+
+        self.send_message(auth_message).await?;
+
+        let verdict = match Self::next_auth_event(&mut events, deadline).await? {
+            AuthEvent::Verdict(verdict) => verdict,
+            AuthEvent::Challenge(_) => Err("Unexpected authentication challenge".to_string()),
+        };
+        match verdict {
+            Ok(accepted) => {
+                if let Some(alerter) = &self.alerter {
+                    alerter.connected();
+                }
+                info!("Authenticated with server");
+                Ok(accepted)
+            }
+            Err(error) => {
+                if let Some(alerter) = &self.alerter {
+                    alerter.auth_failed(&error);
+                }
+                Err(NatError::authentication(error))
+            }
+        }
+    }
+
+    async fn next_auth_event(
+        events: &mut mpsc::UnboundedReceiver<AuthEvent>,
+        deadline: tokio::time::Instant,
+    ) -> NatResult<AuthEvent> {
+        tokio::time::timeout_at(deadline, events.recv())
+            .await
+            .map_err(|_| NatError::timeout("No authentication response from server"))?
+            .ok_or_else(|| NatError::connection("Connection closed during authentication"))
+    }
+
+    async fn handle_write<S: Transport>(
+        mut writer: tokio::io::WriteHalf<S>,
+        mut message_rx: mpsc::UnboundedReceiver<Message>,
+        flush: FlushPolicy,
+        frame_limit: &FrameLimit,
+    ) -> NatResult<()> {
+        batch::write_batched(
+            &mut writer,
+            &mut message_rx,
+            flush,
+            frame_limit,
+            Message::encode_frame,
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_read<S: Transport>(
+        mut reader: tokio::io::ReadHalf<S>,
+        auth_events: mpsc::UnboundedSender<AuthEvent>,
+        state: Arc<RwLock<ConnectionState>>,
+        tunnels: Arc<RwLock<HashMap<Uuid, TunnelInfo>>>,
+        relays: Arc<RwLock<HashMap<Uuid, RelayInfo>>>,
+        shares: Arc<Shares>,
+        wakes: Arc<Wakes>,
+        speed_tests: Arc<SpeedTests>,
+        tunnel_lists: Arc<TunnelLists>,
+        provisioned: Arc<Provisioned>,
+        signaling: Arc<RwLock<Option<mpsc::UnboundedSender<Message>>>>,
+        stats: Arc<RwLock<ConnectionStats>>,
+        bytes_received: Arc<AtomicU64>,
+        forwarder: Arc<LocalForwarder>,
+        tokens: Arc<TokenStore>,
+        vpn: Option<Arc<Vpn>>,
+        wireguard: Option<Arc<WireGuard>>,
+        message_tx: mpsc::UnboundedSender<Message>,
+        max_frame_size: usize,
+        heartbeat_timeout: Option<std::time::Duration>,
+    ) -> NatResult<()> {
+        loop {
+            // The server answers every heartbeat, so silence through
+            // several of them means the connection is dead
+            let read = transport::read_frame(&mut reader, max_frame_size);
+            let result = match heartbeat_timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, read).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        warn!(
+                            "No heartbeat answer from the server in {} seconds, reconnecting",
+                            timeout.as_secs()
+                        );
+                        break;
+                    }
+                },
+                None => read.await,
+            };
+            let data = match result {
+                Ok(Some(data)) => data,
+                Ok(None) => break,
+                Err(e @ NatError::Protocol { .. }) => {
+                    error!("{}", e);
+                    break;
+                }
+                Err(_) => break,
+            };
+
+            bytes_received.fetch_add(data.len() as u64, Ordering::Relaxed);
 
             // Parse message
             let message = match Message::from_bytes(&data) {
@@ -252,81 +1129,388 @@ impl ServerConnection {
                     continue;
                 }
             };
+            if let Message::Disconnect { reason } = &message {
+                info!("Server closed the connection: {}", reason);
+                break;
+            }
 
             // Handle message
-            Self::handle_message(message, &state, &tunnels).await;
+            Self::handle_message(
+                message,
+                &auth_events,
+                &state,
+                &tunnels,
+                &relays,
+                &shares,
+                &wakes,
+                &speed_tests,
+                &tunnel_lists,
+                &provisioned,
+                &signaling,
+                &stats,
+                &forwarder,
+                &tokens,
+                vpn.as_ref(),
+                wireguard.as_ref(),
+                &message_tx,
+            )
+            .await;
         }
 
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn handle_message(
         message: Message,
+        auth_events: &mpsc::UnboundedSender<AuthEvent>,
         state: &Arc<RwLock<ConnectionState>>,
         tunnels: &Arc<RwLock<HashMap<Uuid, TunnelInfo>>>,
+        relays: &Arc<RwLock<HashMap<Uuid, RelayInfo>>>,
+        shares: &Shares,
+        wakes: &Arc<Wakes>,
+        speed_tests: &SpeedTests,
+        tunnel_lists: &TunnelLists,
+        provisioned: &Provisioned,
+        signaling: &Arc<RwLock<Option<mpsc::UnboundedSender<Message>>>>,
+        stats: &Arc<RwLock<ConnectionStats>>,
+        forwarder: &Arc<LocalForwarder>,
+        tokens: &TokenStore,
+        vpn: Option<&Arc<Vpn>>,
+        wireguard: Option<&Arc<WireGuard>>,
+        message_tx: &mpsc::UnboundedSender<Message>,
     ) {
         match message {
             Message::AuthResponse {
                 success,
                 error,
                 server_version: _,
+                data_channel,
+                motd,
+                max_frame_size,
             } => {
-                if success {
+                let verdict = if success {
                     *state.write().await = ConnectionState::Authenticated;
                     info!("Authentication successful");
+                    if let Some(motd) = &motd {
+                        info!("Message from the server: {}", motd);
+                    }
+                    stats.write().await.motd = motd;
+                    Ok(Accepted {
+                        data_channel,
+                        max_frame_size,
+                    })
                 } else {
                     let error_msg = error.unwrap_or_else(|| "Unknown error".to_string());
                     *state.write().await = ConnectionState::Error(error_msg.clone());
                     error!("Authentication failed: {}", error_msg);
-                }
+                    Err(error_msg)
+                };
+                let _ = auth_events.send(AuthEvent::Verdict(verdict));
+            }
+
+            Message::AuthChallenge { nonce } => {
+                let _ = auth_events.send(AuthEvent::Challenge(nonce));
             }
 
             Message::TunnelCreated {
                 tunnel_id,
                 remote_port,
+                local_host,
                 local_port,
                 protocol,
                 name,
+                port_count,
+                expires_at,
+                domain,
+                tls_passthrough,
+                udp,
             } => {
-                info!(
-                    "Tunnel created: {} -> {}:{} ({})",
-                    tunnel_id, remote_port, local_port, protocol
-                );
-                
                 // Create tunnel info and add to client's tunnel list
                 let tunnel_info = TunnelInfo {
                     id: tunnel_id,
                     name,
                     protocol,
+                    local_host,
                     local_port,
                     remote_port,
                     created_at: Utc::now(),
                     bytes_sent: 0,
                     bytes_received: 0,
                     active_connections: 0,
+                    mode: TunnelMode::Server,
+                    port_count,
+                    expires_at,
+                    domain,
+                    tls_passthrough,
+                    udp,
                 };
-                
+                info!(
+                    "Tunnel created: {} -> {}:{}:{} ({})",
+                    tunnel_id,
+                    tunnel_info.remote_ports(),
+                    tunnel_info.local_host,
+                    tunnel_info.local_ports(),
+                    protocol
+                );
+
                 let mut tunnels_guard = tunnels.write().await;
                 tunnels_guard.insert(tunnel_id, tunnel_info);
-                // TODO: Start local proxy for this tunnel
             }
 
             Message::TunnelClosed { tunnel_id, reason } => {
                 info!("Tunnel closed: {} - {}", tunnel_id, reason);
                 let mut tunnels_guard = tunnels.write().await;
                 tunnels_guard.remove(&tunnel_id);
+                forwarder.close_tunnel(tunnel_id);
+            }
+
+            Message::ProvisionTunnel { tunnel } => {
+                provisioned.provision(tunnel, tunnels, message_tx).await;
+            }
+
+            Message::UnprovisionTunnel { name } => {
+                provisioned.unprovision(&name, tunnels, message_tx).await;
+            }
+
+            Message::NewConnection { .. }
+            | Message::Data { .. }
+            | Message::ConnectionClosed { .. }
+            | Message::ConnectionShutdown { .. }
+            | Message::ResumeConnection { .. } => {
+                Self::handle_tunnel_message(message, tunnels, forwarder, message_tx).await;
+            }
+
+            Message::RelayAllocated { relay } => {
+                info!(
+                    "Relay allocated: {} ({}) on ports {} <-> {}",
+                    relay.id, relay.protocol, relay.port_a, relay.port_b
+                );
+                relays.write().await.insert(relay.id, relay);
+            }
+
+            Message::RelayClosed {
+                relay_id,
+                bytes_relayed,
+                reason,
+            } => {
+                info!(
+                    "Relay closed: {} - {} ({} bytes relayed)",
+                    relay_id, reason, bytes_relayed
+                );
+                relays.write().await.remove(&relay_id);
             }
 
+            Message::ShareCreated {
+                request_id,
+                share,
+                error,
+            } => {
+                if let Some(share) = &share {
+                    info!(
+                        "Tunnel {} shared on port {} until {}",
+                        share.tunnel_id, share.port, share.expires_at
+                    );
+                }
+                shares.answered(request_id, share, error).await;
+            }
+
+            Message::ShareClosed { share_id, reason } => {
+                info!("Share closed: {} - {}", share_id, reason);
+                shares.closed(&share_id).await;
+            }
+
+            Message::Pong { timestamp } => {
+                // The server echoes our own timestamp, so no clock skew
+                let now = Utc::now();
+                let mut stats = stats.write().await;
+                stats.last_ping_time = Some(now);
+                match (now - timestamp).to_std() {
+                    Ok(rtt) => {
+                        stats.rtt.record(rtt);
+                        telemetry::heartbeat_rtt(rtt);
+                        debug!("Received pong, rtt {:?}", rtt);
+                    }
+                    Err(_) => debug!("Ignoring pong from the future: {}", timestamp),
+                }
+            }
+
+            Message::Status {
+                usage, utilization, ..
+            } => {
+                let mut stats = stats.write().await;
+                stats.usage = usage;
+                stats.utilization = utilization;
+            }
+
+            Message::CandidateOffer { .. } | Message::CandidateAnswer { .. } => {
+                match signaling.read().await.as_ref() {
+                    Some(sender) if sender.send(message.clone()).is_ok() => {}
+                    _ => debug!("Ignoring candidates, no session is waiting: {:?}", message),
+                }
+            }
+
+            Message::WakeOnLan {
+                request_id,
+                peer,
+                target,
+            } => {
+                let wakes = wakes.clone();
+                let message_tx = message_tx.clone();
+                tokio::spawn(
+                    async move {
+                        let result = wakes.wake(&peer, &target).await;
+                        if let Err(e) = &result {
+                            warn!("Refused to wake {} for {}: {}", target, peer, e);
+                        }
+                        let _ = message_tx.send(Message::WakeOnLanResult {
+                            request_id,
+                            peer,
+                            error: result.err(),
+                        });
+                    }
+                    .in_current_span(),
+                );
+            }
+
+            Message::WakeOnLanResult {
+                request_id, error, ..
+            } => {
+                wakes.answered(request_id, error).await;
+            }
+
+            Message::RequestLogs {
+                request_id,
+                level,
+                duration_secs,
+                max_lines,
+            } => {
+                let result = remote_log::request(
+                    level.as_deref(),
+                    std::time::Duration::from_secs(duration_secs),
+                    max_lines as usize,
+                );
+                if let Err(e) = &result {
+                    warn!("Refused the server's log request: {}", e);
+                }
+                let (lines, error) = match result {
+                    Ok(lines) => (lines, None),
+                    Err(e) => (Vec::new(), Some(e)),
+                };
+                let _ = message_tx.send(Message::Logs {
+                    request_id,
+                    lines,
+                    error,
+                });
+            }
+
+            Message::SpeedTestResult {
+                request_id,
+                report,
+                error,
+            } => {
+                speed_tests.answered(request_id, report, error).await;
+            }
+
+            Message::TunnelList {
+                request_id,
+                tunnels,
+                shares,
+                relays,
+            } => {
+                let held = ServerTunnels {
+                    tunnels,
+                    shares,
+                    relays,
+                };
+                tunnel_lists.answered(request_id, held).await;
+            }
+
+            Message::ServicePublished { name } => {
+                info!("Service {} published", name);
+            }
+
+            Message::RotateToken { token, expires_at } => {
+                info!("Server issued a new token valid until {}", expires_at);
+                match tokens.replace(token) {
+                    Ok(path) => info!("Saved the new token to {}", path.display()),
+                    Err(e) => warn!(
+                        "Failed to save the new token, it is used until the client exits: {}",
+                        e
+                    ),
+                }
+            }
+
+            Message::VpnJoined { address } => {
+                if let Some(vpn) = vpn {
+                    vpn.joined(address).await;
+                }
+            }
+
+            Message::VpnPacket { data } => {
+                if let Some(vpn) = vpn {
+                    vpn.deliver(data).await;
+                }
+            }
+
+            Message::WireGuardJoined { address } => {
+                if let Some(wireguard) = wireguard {
+                    wireguard.joined(address);
+                }
+            }
+
+            Message::WireGuardPeers { peers } => {
+                if let Some(wireguard) = wireguard {
+                    wireguard.update(peers).await;
+                }
+            }
+
+            Message::Error { code, message } => {
+                error!("Server error: {:?} - {}", code, message);
+            }
+
+            _ => {
+                warn!("Unhandled message type: {:?}", message);
+            }
+        }
+    }
+
+    /// Handle connection opens, closes, resumptions and data, replying on
+    /// `data_tx`: the data channel when attached, the control connection
+    /// otherwise
+    async fn handle_tunnel_message(
+        message: Message,
+        tunnels: &Arc<RwLock<HashMap<Uuid, TunnelInfo>>>,
+        forwarder: &Arc<LocalForwarder>,
+        data_tx: &mpsc::UnboundedSender<Message>,
+    ) {
+        match message {
             Message::NewConnection {
                 tunnel_id,
                 connection_id,
                 client_addr,
+                port_offset,
             } => {
                 debug!(
                     "New connection {} to tunnel {} from {}",
                     connection_id, tunnel_id, client_addr
                 );
-                // TODO: Handle new connection
+
+                let tunnel = tunnels.read().await.get(&tunnel_id).cloned();
+                match tunnel {
+                    Some(tunnel) => {
+                        forwarder
+                            .open(&tunnel, connection_id, port_offset, data_tx.clone())
+                            .await
+                    }
+                    None => {
+                        warn!("New connection for unknown tunnel {}", tunnel_id);
+                        let _ = data_tx.send(Message::ConnectionClosed {
+                            tunnel_id,
+                            connection_id,
+                        });
+                    }
+                }
             }
 
             Message::Data {
@@ -340,25 +1524,38 @@ impl ServerConnection {
                     tunnel_id,
                     connection_id
                 );
-                // TODO: Forward data to local service
+                forwarder.send(tunnel_id, connection_id, data).await;
             }
 
-            Message::Pong { timestamp: _ } => {
-                debug!("Received pong");
+            Message::ConnectionClosed {
+                tunnel_id,
+                connection_id,
+            } => {
+                forwarder.close(tunnel_id, connection_id);
             }
 
-            Message::Error { code, message } => {
-                error!("Server error: {:?} - {}", code, message);
+            Message::ConnectionShutdown {
+                tunnel_id,
+                connection_id,
+                direction: ShutdownDirection::Inbound,
+            } => {
+                forwarder.shutdown(tunnel_id, connection_id).await;
             }
 
-            _ => {
-                warn!("Unhandled message type: {:?}", message);
+            Message::ResumeConnection {
+                tunnel_id,
+                connection_id,
+                received,
+            } => {
+                forwarder.resumed(tunnel_id, connection_id, received, data_tx);
             }
+
+            _ => {}
         }
     }
 
-    async fn heartbeat_loop(message_tx: mpsc::UnboundedSender<Message>) {
-        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
+    async fn heartbeat_loop(message_tx: mpsc::UnboundedSender<Message>, every_secs: u64) {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(every_secs));
 
         loop {
             interval.tick().await;
@@ -367,7 +1564,8 @@ impl ServerConnection {
                 timestamp: Utc::now(),
             };
 
-            if message_tx.send(ping).is_err() {
+            // Status refreshes the usage the server counts for us
+            if message_tx.send(ping).is_err() || message_tx.send(Message::StatusRequest).is_err() {
                 break;
             }
         }
@@ -384,28 +1582,293 @@ impl ServerConnection {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn create_tunnel(
         &self,
+        local_host: String,
         local_port: u16,
         remote_port: Option<u16>,
+        port_count: u16,
         protocol: TunnelProtocol,
         name: Option<String>,
+        ttl_secs: Option<u64>,
+        domain: Option<String>,
+        http_auth: Option<HttpAuth>,
+        tls_certificate: Option<TlsCertificate>,
+        tls_passthrough: bool,
+        https_redirect: bool,
+        http_headers: Option<HttpHeaders>,
+        dscp: Option<Dscp>,
+        udp: Option<UdpSessions>,
     ) -> NatResult<()> {
         let message = Message::CreateTunnel {
+            local_host,
             local_port,
             remote_port,
             protocol,
             name,
+            port_count,
+            ttl_secs,
+            domain,
+            http_auth,
+            tls_certificate,
+            tls_passthrough,
+            https_redirect,
+            http_headers,
+            dscp,
+            udp,
         };
 
+        if let Message::CreateTunnel {
+            name: Some(name), ..
+        } = &message
+        {
+            self.requested
+                .write()
+                .await
+                .insert(name.clone(), message.clone());
+        }
         self.send_message(message).await
     }
 
     pub async fn close_tunnel(&self, tunnel_id: Uuid) -> NatResult<()> {
+        if let Some(name) = self
+            .tunnels
+            .read()
+            .await
+            .get(&tunnel_id)
+            .and_then(|tunnel| tunnel.name.clone())
+        {
+            self.requested.write().await.remove(&name);
+        }
         let message = Message::CloseTunnel { tunnel_id };
         self.send_message(message).await
     }
 
+    /// Ask the server for a relay port pair; the result arrives as `RelayAllocated`
+    pub async fn allocate_relay(&self, protocol: TunnelProtocol) -> NatResult<()> {
+        self.send_message(Message::AllocateRelay { protocol }).await
+    }
+
+    pub async fn release_relay(&self, relay_id: Uuid) -> NatResult<()> {
+        self.send_message(Message::ReleaseRelay { relay_id }).await
+    }
+
+    /// Send candidates to another client; answers use the offer's session ID
+    pub async fn send_candidates(
+        &self,
+        session_id: Uuid,
+        peer: String,
+        candidates: Vec<Candidate>,
+        public_key: Vec<u8>,
+        answer: bool,
+    ) -> NatResult<()> {
+        let message = if answer {
+            Message::CandidateAnswer {
+                session_id,
+                peer,
+                candidates,
+                public_key,
+            }
+        } else {
+            Message::CandidateOffer {
+                session_id,
+                peer,
+                candidates,
+                service: None,
+                public_key,
+            }
+        };
+        self.send_message(message).await
+    }
+
+    /// Ask to be introduced to the publisher of a service
+    pub async fn subscribe_service(
+        &self,
+        session_id: Uuid,
+        name: String,
+        candidates: Vec<Candidate>,
+        public_key: Vec<u8>,
+    ) -> NatResult<()> {
+        self.send_message(Message::SubscribeService {
+            session_id,
+            name,
+            candidates,
+            public_key,
+        })
+        .await
+    }
+
+    /// Route candidate offers and answers from other clients to the returned
+    /// receiver, replacing any previous subscriber
+    pub async fn subscribe_signaling(&self) -> mpsc::UnboundedReceiver<Message> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        *self.signaling.write().await = Some(tx);
+        rx
+    }
+
+    pub async fn get_relays(&self) -> Vec<RelayInfo> {
+        self.relays.read().await.values().cloned().collect()
+    }
+
+    /// Ask the server for a temporary public port for a tunnel and wait for
+    /// its answer
+    pub async fn share_tunnel(
+        &self,
+        tunnel_id: Uuid,
+        ttl_secs: u64,
+        password: Option<String>,
+    ) -> NatResult<ShareInfo> {
+        let request_id = Uuid::new_v4();
+        let answer = self.shares.expect(request_id).await;
+        let message = Message::CreateShare {
+            request_id,
+            tunnel_id,
+            ttl_secs,
+            password,
+        };
+        if let Err(e) = self.send_message(message).await {
+            self.shares.abandon(&request_id).await;
+            return Err(e);
+        }
+        match tokio::time::timeout(tokio::time::Duration::from_secs(10), answer).await {
+            Ok(Ok(result)) => result.map_err(NatError::tunnel),
+            _ => {
+                self.shares.abandon(&request_id).await;
+                Err(NatError::timeout(
+                    "The server did not answer the share request",
+                ))
+            }
+        }
+    }
+
+    /// Ask another client to wake one of its Wake-on-LAN targets and wait
+    /// for its answer
+    pub async fn wake_peer(&self, peer: String, target: String) -> NatResult<()> {
+        let request_id = Uuid::new_v4();
+        let answer = self.wakes.expect(request_id).await;
+        let message = Message::WakeOnLan {
+            request_id,
+            peer,
+            target,
+        };
+        if let Err(e) = self.send_message(message).await {
+            self.wakes.abandon(&request_id).await;
+            return Err(e);
+        }
+        match tokio::time::timeout(tokio::time::Duration::from_secs(10), answer).await {
+            Ok(Ok(result)) => result.map_err(NatError::tunnel),
+            _ => {
+                self.wakes.abandon(&request_id).await;
+                Err(NatError::timeout(
+                    "The peer did not answer the wake request",
+                ))
+            }
+        }
+    }
+
+    /// Have the server measure one of this client's tunnels and wait for
+    /// its report
+    pub async fn speed_test(&self, tunnel_id: Uuid, bytes: u64) -> NatResult<SpeedTestReport> {
+        let request_id = Uuid::new_v4();
+        let answer = self.speed_tests.expect(request_id).await;
+        let message = Message::SpeedTest {
+            request_id,
+            tunnel_id,
+            bytes,
+        };
+        if let Err(e) = self.send_message(message).await {
+            self.speed_tests.abandon(&request_id).await;
+            return Err(e);
+        }
+        // A little longer than the server allows itself
+        let wait = TEST_TIMEOUT + tokio::time::Duration::from_secs(10);
+        match tokio::time::timeout(wait, answer).await {
+            Ok(Ok(result)) => result.map_err(NatError::tunnel),
+            _ => {
+                self.speed_tests.abandon(&request_id).await;
+                Err(NatError::timeout(
+                    "The server did not finish the speed test",
+                ))
+            }
+        }
+    }
+
+    /// Ask the server for everything it holds for this client
+    pub async fn list_server_tunnels(&self) -> NatResult<ServerTunnels> {
+        let request_id = Uuid::new_v4();
+        let answer = self.tunnel_lists.expect(request_id).await;
+        if let Err(e) = self.send_message(Message::ListTunnels { request_id }).await {
+            self.tunnel_lists.abandon(&request_id).await;
+            return Err(e);
+        }
+        match tokio::time::timeout(tokio::time::Duration::from_secs(10), answer).await {
+            Ok(Ok(held)) => Ok(held),
+            _ => {
+                self.tunnel_lists.abandon(&request_id).await;
+                Err(NatError::timeout("The server did not list the tunnels"))
+            }
+        }
+    }
+
+    /// Compare the tunnels, shares and relays this client has listed with
+    /// the server's
+    pub async fn check_tunnels(&self) -> NatResult<Divergence> {
+        let held = self.list_server_tunnels().await?;
+        let tunnels: Vec<_> = self.tunnels.read().await.values().cloned().collect();
+        let relays: Vec<_> = self.relays.read().await.values().cloned().collect();
+        let divergence = Divergence::between(&tunnels, &self.shares.list().await, &relays, &held);
+        if !divergence.is_empty() {
+            warn!(
+                "The server disagrees about this client's tunnels: {} gone, {} unknown here; \
+                 shares: {} gone, {} unknown; relays: {} gone, {} unknown",
+                divergence.tunnels.missing.len(),
+                divergence.tunnels.unknown.len(),
+                divergence.shares.missing.len(),
+                divergence.shares.unknown.len(),
+                divergence.relays.missing.len(),
+                divergence.relays.unknown.len()
+            );
+        }
+        Ok(divergence)
+    }
+
+    pub async fn close_share(&self, share_id: Uuid) -> NatResult<()> {
+        self.send_message(Message::CloseShare { share_id }).await
+    }
+
+    pub async fn get_shares(&self) -> Vec<ShareInfo> {
+        self.shares.list().await
+    }
+
+    /// Requests captured from custom-domain tunnels
+    pub fn requests(&self) -> &Arc<RequestLog> {
+        &self.requests
+    }
+
+    /// Close the session on purpose and stop reconnecting. The server is
+    /// told why so it drops this client's tunnels at once; it hangs up on
+    /// receipt, and the session is ended here if it does not in time.
+    pub async fn disconnect(&self, reason: &str) {
+        self.stopping.store(true, Ordering::Relaxed);
+        if !matches!(self.get_state().await, ConnectionState::Authenticated) {
+            self.hang_up.notify_waiters();
+            return;
+        }
+        let message = Message::Disconnect {
+            reason: reason.to_string(),
+        };
+        if self.send_message(message).await.is_ok() {
+            let _ = tokio::time::timeout(tokio::time::Duration::from_secs(2), async {
+                while matches!(self.get_state().await, ConnectionState::Authenticated) {
+                    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+                }
+            })
+            .await;
+        }
+        self.hang_up.notify_waiters();
+    }
+
     pub async fn get_state(&self) -> ConnectionState {
         self.state.read().await.clone()
     }
@@ -416,14 +1879,46 @@ impl ServerConnection {
 
     pub async fn get_tunnels(&self) -> Vec<TunnelInfo> {
         let tunnels = self.tunnels.read().await;
-        tunnels.values().cloned().collect()
+        tunnels
+            .values()
+            .map(|tunnel| {
+                let mut tunnel = tunnel.clone();
+                self.forwarder.fill_stats(&mut tunnel);
+                tunnel
+            })
+            .collect()
+    }
+
+    pub async fn set_nat_report(&self, report: NatReport) {
+        self.stats.write().await.nat = Some(report);
+    }
+
+    /// Ask the server for a replacement token and wait until it arrives
+    pub async fn rotate_token(&self) -> NatResult<()> {
+        let replaced = self.tokens.replaced();
+        tokio::pin!(replaced);
+        replaced.as_mut().enable();
+
+        self.send_message(Message::RequestTokenRotation).await?;
+        tokio::time::timeout(tokio::time::Duration::from_secs(10), replaced)
+            .await
+            .map_err(|_| {
+                NatError::timeout("No new token from the server; does it have auth.jwt configured?")
+            })
+    }
+
+    pub fn alerter(&self) -> Option<Arc<Alerter>> {
+        self.alerter.clone()
     }
 
     pub async fn get_stats(&self) -> ConnectionStats {
-        self.stats.read().await.clone()
+        let mut stats = self.stats.read().await.clone();
+        stats.bytes_received = self.bytes_received.load(Ordering::Relaxed);
+        stats
     }
 
     pub async fn run_with_reconnect(&self) -> NatResult<()> {
+        self.stopping.store(false, Ordering::Relaxed);
         loop {
             match self.connect().await {
                 Ok(_) => {
@@ -432,10 +1927,13 @@ impl ServerConnection {
                 Err(e) => {
                     error!("Connection error: {}", e);
                     self.set_state(ConnectionState::Error(e.to_string())).await;
+                    if let Some(alerter) = &self.alerter {
+                        alerter.connect_failed(&e.to_string());
+                    }
                 }
             }
 
-            if !self.config.server.auto_reconnect {
+            if !self.config.server.auto_reconnect || self.stopping.load(Ordering::Relaxed) {
                 break;
             }
 
@@ -447,6 +1945,9 @@ impl ServerConnection {
                 self.config.server.reconnect_interval_secs,
             ))
             .await;
+            if self.stopping.load(Ordering::Relaxed) {
+                break;
+            }
 
             // Update reconnect count
             {
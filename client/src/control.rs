@@ -0,0 +1,295 @@
+//! Requests `nat-client status` sends over the local control socket.
+
+use crate::capture::{self, RequestSummary};
+use crate::core::NatClient;
+use crate::schedule::ScheduleStatus;
+use nat_traversal_common::{
+    config::ClientConfig,
+    control::{self, ControlListener},
+    protocol::{default_port_count, ShareInfo, TunnelMode, TunnelProtocol, UsageInfo, Utilization},
+};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::warn;
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ControlRequest {
+    Status,
+    RotateToken,
+    Share {
+        tunnel: String,
+        ttl_secs: u64,
+        password: Option<String>,
+    },
+    CloseShare {
+        share_id: Uuid,
+    },
+    /// Ask another client to wake a machine on its LAN
+    Wake {
+        peer: String,
+        target: String,
+    },
+    /// Compare the tunnels listed here with the ones the server holds
+    CheckTunnels,
+    /// List the captured requests of custom-domain tunnels
+    Requests,
+    /// A captured request as text, for editing
+    GetRequest {
+        id: u64,
+    },
+    /// Send a captured request to its local service again, replaced by
+    /// `request` when given
+    Replay {
+        id: u64,
+        request: Option<String>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ControlResponse {
+    Status(Box<StatusReport>),
+    Requests(Vec<RequestSummary>),
+    Request(String),
+    Done(String),
+    Error(String),
+}
+
+/// What `nat-client status` shows
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatusReport {
+    pub state: String,
+    pub server: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// Round trip of the last heartbeat
+    pub rtt_ms: Option<f64>,
+    /// Average and largest heartbeat round trip this session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtt_avg_ms: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtt_max_ms: Option<f64>,
+    pub tunnels: Vec<TunnelStatus>,
+    /// Temporary public ports of the tunnels
+    #[serde(default)]
+    pub shares: Vec<ShareInfo>,
+    /// This month's traffic, counted against the token's quota
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<UsageInfo>,
+    /// How close the server is to its capacity limits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_load: Option<Utilization>,
+    /// Configured tunnels that open and close on a schedule
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedules: Vec<ScheduleStatus>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TunnelStatus {
+    pub id: Uuid,
+    pub name: Option<String>,
+    pub protocol: TunnelProtocol,
+    pub mode: TunnelMode,
+    pub remote_port: u16,
+    pub local_host: String,
+    pub local_port: u16,
+    #[serde(default = "default_port_count")]
+    pub port_count: u16,
+    pub uptime_secs: u64,
+    /// Time left before the server closes the tunnel, if it has a TTL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_in_secs: Option<u64>,
+    /// Domain the server's HTTP listener routes to the tunnel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    pub active_connections: u32,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// Where the control socket lives for this configuration
+pub fn socket_path(config: &ClientConfig) -> anyhow::Result<PathBuf> {
+    match &config.control.path {
+        Some(path) => Ok(path.clone()),
+        None => control::default_path("nat-client"),
+    }
+}
+
+/// Answer control requests until the process exits
+pub async fn serve(client: Arc<NatClient>, path: PathBuf) {
+    let result = match ControlListener::bind(&path).await {
+        Ok(listener) => {
+            listener
+                .serve(move |request| {
+                    let client = client.clone();
+                    async move { handle(request, &client).await }
+                })
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        warn!("Control socket {} unavailable: {}", path.display(), e);
+    }
+}
+
+async fn handle(request: ControlRequest, client: &NatClient) -> ControlResponse {
+    match request {
+        ControlRequest::Status => ControlResponse::Status(Box::new(status_report(client).await)),
+        ControlRequest::RotateToken => match client.rotate_token().await {
+            Ok(()) => ControlResponse::Done("Received a new token".to_string()),
+            Err(e) => ControlResponse::Error(e.to_string()),
+        },
+        ControlRequest::Share {
+            tunnel,
+            ttl_secs,
+            password,
+        } => match client.share_tunnel(&tunnel, ttl_secs, password).await {
+            Ok(share) => ControlResponse::Done(format!(
+                "Shared {} on {}:{} until {} (share {})",
+                tunnel,
+                client.get_config().server.addr,
+                share.port,
+                share.expires_at.format("%Y-%m-%d %H:%M:%S UTC"),
+                share.id
+            )),
+            Err(e) => ControlResponse::Error(e.to_string()),
+        },
+        ControlRequest::CloseShare { share_id } => match client.close_share(share_id).await {
+            Ok(()) => ControlResponse::Done(format!("Closed share {}", share_id)),
+            Err(e) => ControlResponse::Error(e.to_string()),
+        },
+        ControlRequest::Wake { peer, target } => match client.wake_peer(&peer, &target).await {
+            Ok(()) => ControlResponse::Done(format!("{} sent a wake packet to {}", peer, target)),
+            Err(e) => ControlResponse::Error(e.to_string()),
+        },
+        ControlRequest::CheckTunnels => match client.check_tunnels().await {
+            Ok(divergence) if divergence.is_empty() => {
+                ControlResponse::Done("The server holds the same tunnels".to_string())
+            }
+            Ok(divergence) => {
+                let mut lines = Vec::new();
+                let tunnels = &divergence.tunnels;
+                for (tunnel, place) in tunnels
+                    .missing
+                    .iter()
+                    .map(|t| (t, "gone from the server"))
+                    .chain(
+                        tunnels
+                            .unknown
+                            .iter()
+                            .map(|t| (t, "held by the server only")),
+                    )
+                {
+                    lines.push(format!(
+                        "Tunnel {} on port {}: {}",
+                        tunnel.name.clone().unwrap_or_else(|| tunnel.id.to_string()),
+                        tunnel.remote_port,
+                        place
+                    ));
+                }
+                let shares = &divergence.shares;
+                for (share, place) in shares
+                    .missing
+                    .iter()
+                    .map(|s| (s, "gone from the server"))
+                    .chain(
+                        shares
+                            .unknown
+                            .iter()
+                            .map(|s| (s, "held by the server only")),
+                    )
+                {
+                    lines.push(format!(
+                        "Share {} on port {}: {}",
+                        share.id, share.port, place
+                    ));
+                }
+                let relays = &divergence.relays;
+                for (relay, place) in relays
+                    .missing
+                    .iter()
+                    .map(|r| (r, "gone from the server"))
+                    .chain(
+                        relays
+                            .unknown
+                            .iter()
+                            .map(|r| (r, "held by the server only")),
+                    )
+                {
+                    lines.push(format!(
+                        "Relay {} on ports {} and {}: {}",
+                        relay.id, relay.port_a, relay.port_b, place
+                    ));
+                }
+                ControlResponse::Done(lines.join("\n"))
+            }
+            Err(e) => ControlResponse::Error(e.to_string()),
+        },
+        ControlRequest::Requests => ControlResponse::Requests(client.requests().list()),
+        ControlRequest::GetRequest { id } => match client.requests().get(id) {
+            Some((_, raw)) => ControlResponse::Request(String::from_utf8_lossy(&raw).into_owned()),
+            None => ControlResponse::Error(format!("No captured request {}", id)),
+        },
+        ControlRequest::Replay { id, request } => {
+            let Some((target, raw)) = client.requests().get(id) else {
+                return ControlResponse::Error(format!("No captured request {}", id));
+            };
+            let raw = match request {
+                Some(text) => capture::prepare_edited(&text).into(),
+                None => raw,
+            };
+            match capture::replay(&target, &raw).await {
+                Ok(response) => {
+                    ControlResponse::Done(String::from_utf8_lossy(&response).into_owned())
+                }
+                Err(e) => ControlResponse::Error(e.to_string()),
+            }
+        }
+    }
+}
+
+async fn status_report(client: &NatClient) -> StatusReport {
+    let config = client.get_config();
+    let now = chrono::Utc::now();
+    let tunnels = client
+        .get_tunnels()
+        .await
+        .into_iter()
+        .map(|tunnel| TunnelStatus {
+            id: tunnel.id,
+            name: tunnel.name,
+            protocol: tunnel.protocol,
+            mode: tunnel.mode,
+            remote_port: tunnel.remote_port,
+            local_host: tunnel.local_host,
+            local_port: tunnel.local_port,
+            port_count: tunnel.port_count,
+            uptime_secs: (now - tunnel.created_at).num_seconds().max(0) as u64,
+            expires_in_secs: tunnel
+                .expires_at
+                .map(|at| (at - now).num_seconds().max(0) as u64),
+            domain: tunnel.domain,
+            active_connections: tunnel.active_connections,
+            bytes_sent: tunnel.bytes_sent,
+            bytes_received: tunnel.bytes_received,
+        })
+        .collect();
+
+    let rtt = client.get_rtt().await;
+    let millis = |rtt: std::time::Duration| rtt.as_micros() as f64 / 1000.0;
+    StatusReport {
+        state: client.get_connection_state().await.to_string(),
+        server: config.server.endpoint(config.server.port),
+        profile: config.active_profile.clone(),
+        rtt_ms: rtt.last.map(millis),
+        rtt_avg_ms: rtt.avg.map(millis),
+        rtt_max_ms: rtt.max.map(millis),
+        tunnels,
+        shares: client.get_shares().await,
+        usage: client.get_usage().await,
+        server_load: client.get_utilization().await,
+        schedules: client.get_schedules(),
+    }
+}
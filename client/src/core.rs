@@ -1,7 +1,17 @@
-use crate::connection::{ConnectionState, ServerConnection};
+use crate::capture::RequestLog;
+use crate::connection::{ConnectionState, RttStats, ServerConnection};
+use crate::e2e;
+use crate::p2p::PeerSessions;
+use crate::portmap::DirectTunnels;
+use crate::resync::Divergence;
+use crate::schedule::{self, ScheduleStatus};
+use crate::ssh::SshTunnels;
 use nat_traversal_common::{
     config::ClientConfig,
-    protocol::{TunnelInfo, TunnelProtocol},
+    protocol::{
+        HttpAuth, RelayInfo, ShareInfo, TunnelInfo, TunnelProtocol, UsageInfo, Utilization,
+    },
+    stun::{self, NatReport, StunReport},
 };
 use std::sync::Arc;
 use tokio::sync::RwLock;
@@ -12,22 +22,70 @@ pub struct NatClient {
     config: ClientConfig,
     connection: Arc<ServerConnection>,
     running: Arc<RwLock<bool>>,
+    stun_report: Arc<RwLock<Option<StunReport>>>,
+    direct_tunnels: Arc<DirectTunnels>,
+    peer_sessions: Arc<PeerSessions>,
+    /// Tunnels on an SSH server, which replaces the NAT traversal server
+    /// when `[ssh]` is enabled
+    ssh_tunnels: Option<Arc<SshTunnels>>,
 }
 
 impl NatClient {
     pub async fn new(config: ClientConfig) -> anyhow::Result<Self> {
-        let connection = Arc::new(ServerConnection::new(config.clone()).await?);
+        let connection = ServerConnection::new(config.clone()).await?;
+        Ok(Self::with_connection(config, connection))
+    }
 
-        Ok(Self {
-            config,
+    /// A client talking to the server through `connection`
+    pub fn with_connection(config: ClientConfig, connection: ServerConnection) -> Self {
+        let connection = Arc::new(connection);
+        let stun_report = Arc::new(RwLock::new(None));
+        let peer_sessions = Arc::new(PeerSessions::new(
+            config.clone(),
+            connection.clone(),
+            stun_report.clone(),
+        ));
+
+        Self {
             connection,
             running: Arc::new(RwLock::new(false)),
-        })
+            stun_report,
+            direct_tunnels: Arc::new(DirectTunnels::new()),
+            peer_sessions,
+            ssh_tunnels: config
+                .ssh
+                .enabled
+                .then(|| Arc::new(SshTunnels::new(&config.ssh))),
+            config,
+        }
     }
 
     pub async fn start(&self) -> anyhow::Result<()> {
         *self.running.write().await = true;
 
+        if let Some(ssh) = &self.ssh_tunnels {
+            for tunnel in self
+                .config
+                .tunnels
+                .iter()
+                .filter(|tunnel| tunnel.auto_start)
+            {
+                if let Err(e) = ssh.open_configured(tunnel).await {
+                    tracing::warn!("Tunnel {} not forwarded over SSH: {}", tunnel.name, e);
+                }
+            }
+            return Ok(());
+        }
+
+        // Settle router mappings first so the connection knows which
+        // tunnels it no longer has to create on the server
+        self.open_direct_tunnels().await;
+
+        if self.peer_sessions.is_configured() {
+            self.peer_sessions.start().await?;
+        }
+        e2e::start_visitors(&self.config).await?;
+
         // Start connection with auto-reconnect
         let connection = self.connection.clone();
         let running = self.running.clone();
@@ -41,45 +99,280 @@ impl NatClient {
             }
         });
 
-        // Start configured tunnels
-        for tunnel_config in &self.config.tunnels {
-            if tunnel_config.auto_start {
-                if let Err(e) = self
-                    .create_tunnel(
-                        tunnel_config.local_port,
-                        tunnel_config.remote_port,
-                        tunnel_config.protocol,
-                        Some(tunnel_config.name.clone()),
-                    )
-                    .await
-                {
-                    tracing::warn!("Failed to start tunnel {}: {}", tunnel_config.name, e);
-                }
+        if self.config.stun.enabled {
+            self.spawn_stun_discovery();
+        }
+
+        if self
+            .config
+            .tunnels
+            .iter()
+            .any(|tunnel| tunnel.auto_start && !tunnel.schedule.is_empty())
+        {
+            tokio::spawn(schedule::run(self.connection.clone()));
+        }
+
+        if let Some(alerter) = self.connection.alerter() {
+            tokio::spawn(alerter.watch_local_services(self.connection.clone()));
+        }
+
+        // Configured tunnels are created by the connection once authenticated
+        Ok(())
+    }
+
+    async fn open_direct_tunnels(&self) {
+        for tunnel in &self.config.tunnels {
+            if !tunnel.auto_start || !tunnel.port_mapping {
+                continue;
+            }
+            // Consumers of an encrypted tunnel expect the handshake, which
+            // only the server path provides
+            if !tunnel.e2e_peers.is_empty() {
+                tracing::warn!(
+                    "Tunnel {} uses e2e_peers; ignoring port_mapping",
+                    tunnel.name
+                );
+                continue;
+            }
+            // Only the server closes tunnels when their TTL runs out, routes
+            // a domain to them or checks their HTTP credentials
+            if tunnel.ttl_secs.is_some() || tunnel.domain.is_some() || tunnel.http_auth.is_some() {
+                tracing::warn!(
+                    "Tunnel {} has a TTL, domain or HTTP auth; ignoring port_mapping",
+                    tunnel.name
+                );
+                continue;
+            }
+            // Schedules open and close server tunnels only
+            if !tunnel.schedule.is_empty() {
+                tracing::warn!(
+                    "Tunnel {} has a schedule; ignoring port_mapping",
+                    tunnel.name
+                );
+                continue;
+            }
+            match self
+                .direct_tunnels
+                .open(tunnel, &self.config.port_mapping)
+                .await
+            {
+                Ok(_) => self.connection.set_direct(&tunnel.name).await,
+                Err(e) => tracing::info!(
+                    "No port mapping for tunnel {}, using the server: {}",
+                    tunnel.name,
+                    e
+                ),
             }
         }
+    }
+
+    /// Learn the public address and NAT behavior in the background
+    fn spawn_stun_discovery(&self) {
+        let servers = self.config.stun.server_list(&self.config.server);
+        let timeout = std::time::Duration::from_millis(self.config.stun.timeout_ms);
+        let stun_report = self.stun_report.clone();
+        let connection = self.connection.clone();
+
+        tokio::spawn(async move {
+            match stun::detect_nat(&servers, timeout).await {
+                Ok(report) => {
+                    tracing::info!(
+                        "Public address {} (local {}), NAT type: {}",
+                        report.stun.public_addr,
+                        report.stun.local_addr,
+                        report.nat_type
+                    );
+                    if !report.nat_type.allows_direct() {
+                        tracing::info!("Direct peer connections may not work from this network");
+                    }
+                    *stun_report.write().await = Some(report.stun.clone());
+                    connection.set_nat_report(report).await;
+                }
+                Err(e) => tracing::warn!("Public address discovery failed: {}", e),
+            }
+        });
+    }
+
+    pub async fn allocate_relay(&self, protocol: TunnelProtocol) -> anyhow::Result<()> {
+        self.connection.allocate_relay(protocol).await?;
+        Ok(())
+    }
+
+    pub async fn release_relay(&self, relay_id: Uuid) -> anyhow::Result<()> {
+        self.connection.release_relay(relay_id).await?;
+        Ok(())
+    }
+
+    pub async fn get_relays(&self) -> Vec<RelayInfo> {
+        self.connection.get_relays().await
+    }
+
+    /// Share a server tunnel, named or given by ID, on a temporary public
+    /// port
+    pub async fn share_tunnel(
+        &self,
+        tunnel: &str,
+        ttl_secs: u64,
+        password: Option<String>,
+    ) -> anyhow::Result<ShareInfo> {
+        let tunnel_id = self
+            .connection
+            .get_tunnels()
+            .await
+            .into_iter()
+            .find(|t| t.name.as_deref() == Some(tunnel) || t.id.to_string() == tunnel)
+            .map(|t| t.id)
+            .ok_or_else(|| anyhow::anyhow!("No server tunnel '{}'", tunnel))?;
+        Ok(self
+            .connection
+            .share_tunnel(tunnel_id, ttl_secs, password)
+            .await?)
+    }
+
+    /// Have another client send a Wake-on-LAN packet for one of its
+    /// configured machines
+    pub async fn wake_peer(&self, peer: &str, target: &str) -> anyhow::Result<()> {
+        Ok(self
+            .connection
+            .wake_peer(peer.to_string(), target.to_string())
+            .await?)
+    }
 
+    pub async fn close_share(&self, share_id: Uuid) -> anyhow::Result<()> {
+        self.connection.close_share(share_id).await?;
         Ok(())
     }
 
+    pub async fn get_shares(&self) -> Vec<ShareInfo> {
+        self.connection.get_shares().await
+    }
+
+    /// Where the tunnels listed here and the ones the server holds differ
+    pub async fn check_tunnels(&self) -> anyhow::Result<Divergence> {
+        Ok(self.connection.check_tunnels().await?)
+    }
+
+    pub fn requests(&self) -> &Arc<RequestLog> {
+        self.connection.requests()
+    }
+
+    pub async fn get_stun_report(&self) -> Option<StunReport> {
+        self.stun_report.read().await.clone()
+    }
+
+    pub async fn get_nat_report(&self) -> Option<NatReport> {
+        self.connection.get_stats().await.nat
+    }
+
+    /// Round trips of heartbeats to the server this session
+    pub async fn get_rtt(&self) -> RttStats {
+        self.connection.get_stats().await.rtt
+    }
+
+    /// Where each scheduled tunnel stands
+    pub fn get_schedules(&self) -> Vec<ScheduleStatus> {
+        schedule::statuses(&self.config)
+    }
+
+    /// This month's traffic as of the last heartbeat
+    pub async fn get_usage(&self) -> Option<UsageInfo> {
+        self.connection.get_stats().await.usage
+    }
+
+    /// The server's capacity in use as of the last heartbeat
+    pub async fn get_utilization(&self) -> Option<Utilization> {
+        self.connection.get_stats().await.utilization
+    }
+
+    /// The message the server sent when this session logged in
+    pub async fn get_motd(&self) -> Option<String> {
+        self.connection.get_stats().await.motd
+    }
+
+    /// Fetch a replacement token from the server and save it
+    pub async fn rotate_token(&self) -> anyhow::Result<()> {
+        Ok(self.connection.rotate_token().await?)
+    }
+
     pub async fn stop(&self) -> anyhow::Result<()> {
         *self.running.write().await = false;
+        if let Some(ssh) = &self.ssh_tunnels {
+            ssh.close_all().await;
+            return Ok(());
+        }
+        self.connection.disconnect("Client shutting down").await;
+        self.direct_tunnels
+            .close_all(self.port_mapping_timeout())
+            .await;
+        self.peer_sessions.stop().await;
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn create_tunnel(
         &self,
+        local_host: String,
         local_port: u16,
         remote_port: Option<u16>,
+        port_count: u16,
         protocol: TunnelProtocol,
         name: Option<String>,
+        ttl_secs: Option<u64>,
+        domain: Option<String>,
+        http_auth: Option<HttpAuth>,
     ) -> anyhow::Result<()> {
+        if let Some(ssh) = &self.ssh_tunnels {
+            if ttl_secs.is_some() || domain.is_some() || http_auth.is_some() {
+                return Err(anyhow::anyhow!(
+                    "SSH forwards cannot expire, serve a domain or check HTTP auth"
+                ));
+            }
+            ssh.open(
+                name,
+                local_host,
+                local_port,
+                remote_port,
+                port_count,
+                protocol,
+            )
+            .await?;
+            return Ok(());
+        }
         self.connection
-            .create_tunnel(local_port, remote_port, protocol, name)
+            .create_tunnel(
+                local_host,
+                local_port,
+                remote_port,
+                port_count,
+                protocol,
+                name,
+                ttl_secs,
+                domain,
+                http_auth,
+                None,
+                false,
+                false,
+                None,
+                None,
+                None,
+            )
             .await?;
         Ok(())
     }
 
     pub async fn close_tunnel(&self, tunnel_id: Uuid) -> anyhow::Result<()> {
+        if let Some(ssh) = &self.ssh_tunnels {
+            if ssh.close(tunnel_id).await {
+                return Ok(());
+            }
+        }
+        if self
+            .direct_tunnels
+            .close(tunnel_id, self.port_mapping_timeout())
+            .await
+        {
+            return Ok(());
+        }
         self.connection.close_tunnel(tunnel_id).await?;
         Ok(())
     }
@@ -89,7 +382,16 @@ impl NatClient {
     }
 
     pub async fn get_tunnels(&self) -> Vec<TunnelInfo> {
-        self.connection.get_tunnels().await
+        let mut tunnels = self.connection.get_tunnels().await;
+        tunnels.extend(self.direct_tunnels.list().await);
+        if let Some(ssh) = &self.ssh_tunnels {
+            tunnels.extend(ssh.list().await);
+        }
+        tunnels
+    }
+
+    fn port_mapping_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.config.port_mapping.timeout_ms)
     }
 
     pub fn get_config(&self) -> &ClientConfig {
@@ -0,0 +1,113 @@
+//! The client's token, including replacements handed out by the server.
+//!
+//! A rotated token takes effect at once for later reconnects and is written
+//! back where the token came from: `token_file` when set, otherwise the
+//! configuration file, under the active profile if there is one. Files are
+//! replaced atomically so a crash never leaves a half-written token.
+
+use nat_traversal_common::config::{ClientConfig, ServerConnectionConfig};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use tokio::sync::{futures::Notified, Notify};
+
+pub struct TokenStore {
+    server: ServerConnectionConfig,
+    config_path: Option<PathBuf>,
+    profile: Option<String>,
+    /// Token received from the server during this run
+    rotated: RwLock<Option<String>>,
+    changed: Notify,
+}
+
+impl TokenStore {
+    pub fn new(config: &ClientConfig) -> Self {
+        Self {
+            server: config.server.clone(),
+            config_path: config.config_path.clone(),
+            profile: config.active_profile.clone(),
+            rotated: RwLock::new(None),
+            changed: Notify::new(),
+        }
+    }
+
+    /// The token to authenticate with
+    pub fn load(&self) -> anyhow::Result<String> {
+        match self.rotated.read().unwrap().as_ref() {
+            Some(token) => Ok(token.clone()),
+            None => self.server.load_token(),
+        }
+    }
+
+    /// Use `token` from now on and persist it, returning where it was saved
+    pub fn replace(&self, token: String) -> anyhow::Result<PathBuf> {
+        *self.rotated.write().unwrap() = Some(token.clone());
+        self.changed.notify_waiters();
+
+        if let Some(path) = &self.server.token_file {
+            write_atomically(path, format!("{}\n", token).as_bytes())?;
+            return Ok(path.clone());
+        }
+        let path = self
+            .config_path
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No configuration file to save it in"))?;
+        let mut document: toml_edit::DocumentMut =
+            std::fs::read_to_string(path).unwrap_or_default().parse()?;
+        let server = match &self.profile {
+            Some(name) => &mut document["profiles"][name.as_str()]["server"],
+            None => &mut document["server"],
+        };
+        match server["token"].as_value_mut() {
+            // Keep the comments around the old value
+            Some(existing) => {
+                let decor = existing.decor().clone();
+                *existing = token.into();
+                *existing.decor_mut() = decor;
+            }
+            None => server["token"] = toml_edit::value(token),
+        }
+        write_atomically(path, document.to_string().as_bytes())?;
+        Ok(path.clone())
+    }
+
+    /// Resolves when the next token replaces the current one
+    pub fn replaced(&self) -> Notified<'_> {
+        self.changed.notified()
+    }
+}
+
+/// Replace `path` with `contents` through a temporary file in the same
+/// directory, keeping the original's permissions
+pub fn write_atomically(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+
+    let permissions = match std::fs::metadata(path) {
+        Ok(metadata) => Some(metadata.permissions()),
+        Err(_) => owner_only(),
+    };
+    // Restrict the file before the token goes into it
+    let result = std::fs::File::create(&temp)
+        .and_then(|_| match permissions {
+            Some(permissions) => std::fs::set_permissions(&temp, permissions),
+            None => Ok(()),
+        })
+        .and_then(|_| std::fs::write(&temp, contents))
+        .and_then(|_| std::fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    Ok(result?)
+}
+
+#[cfg(unix)]
+fn owner_only() -> Option<std::fs::Permissions> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(std::fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(not(unix))]
+fn owner_only() -> Option<std::fs::Permissions> {
+    None
+}
@@ -0,0 +1,391 @@
+//! `nat-client diagnose`: walk through everything a working tunnel needs,
+//! from resolving the server to reaching a tunnel's public port, and say
+//! how to fix the first thing that fails.
+
+use crate::connection::{ConnectionState, ServerConnection};
+use nat_traversal_common::{
+    config::{ClientConfig, TunnelConfig},
+    protocol::{default_local_host, TunnelInfo, TunnelProtocol},
+    stun,
+};
+use rcgen::{CertificateParams, DnType, DnValue, SanType};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use uuid::Uuid;
+
+/// How long each network step may take
+const STEP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sent through the throwaway tunnel to prove it reaches this machine
+const PROBE: &[u8] = b"nat-traversal diagnose\n";
+
+/// Passed and failed checks, printed as they run
+#[derive(Default)]
+struct Report {
+    passed: usize,
+    failed: usize,
+}
+
+impl Report {
+    fn pass(&mut self, what: &str) {
+        self.passed += 1;
+        println!("  PASS  {}", what);
+    }
+
+    /// Something worth knowing that does not stop tunnels from working
+    fn warn(&self, what: &str, hints: &[&str]) {
+        println!("  WARN  {}", what);
+        for hint in hints {
+            println!("        {}", hint);
+        }
+    }
+
+    fn fail(&mut self, what: &str, hints: &[&str]) {
+        self.failed += 1;
+        println!("  FAIL  {}", what);
+        for hint in hints {
+            println!("        {}", hint);
+        }
+    }
+}
+
+/// Run the connectivity checks and print a report
+pub async fn run(config: &ClientConfig) -> anyhow::Result<()> {
+    let mut report = Report::default();
+    println!(
+        "Server {}:{} (client {})",
+        config.server.addr, config.server.port, config.server.client_id
+    );
+    if check_server(config, &mut report).await {
+        check_nat(config, &mut report).await;
+    }
+
+    println!();
+    println!("{} passed, {} failed", report.passed, report.failed);
+    if report.failed > 0 {
+        return Err(anyhow::anyhow!("Some checks failed"));
+    }
+    Ok(())
+}
+
+/// Check the server step by step, stopping at the first failure since
+/// every step needs the previous one. Returns whether all passed.
+async fn check_server(config: &ClientConfig, report: &mut Report) -> bool {
+    // DNS
+    let addrs: Vec<SocketAddr> =
+        match tokio::net::lookup_host((config.server.host(), config.server.port)).await {
+            Ok(addrs) => addrs.collect(),
+            Err(e) => {
+                report.fail(
+                    &format!("Resolve {}: {}", config.server.addr, e),
+                    &[
+                        "Check server.addr for typos, or use the server's IP address",
+                        "Check that this machine's DNS resolver works",
+                    ],
+                );
+                return false;
+            }
+        };
+    let listed: Vec<String> = addrs.iter().map(|addr| addr.ip().to_string()).collect();
+    report.pass(&format!(
+        "Resolve {}: {}",
+        config.server.addr,
+        listed.join(", ")
+    ));
+
+    // TCP
+    match tokio::time::timeout(STEP_TIMEOUT, TcpStream::connect(&addrs[..])).await {
+        Ok(Ok(stream)) => report.pass(&format!(
+            "Reach {} over TCP",
+            stream
+                .peer_addr()
+                .map_or_else(|_| listed[0].clone(), |addr| addr.to_string())
+        )),
+        result => {
+            let error = match result {
+                Ok(Err(e)) => e.to_string(),
+                _ => "timed out".to_string(),
+            };
+            report.fail(
+                &format!("Reach port {} over TCP: {}", config.server.port, error),
+                &[
+                    "Check that nat-server is running and server.port matches its network.port",
+                    "Check firewalls on the server and cloud security groups for that port",
+                ],
+            );
+            return false;
+        }
+    }
+
+    // TLS
+    let connection = match ServerConnection::new(probe_config(config, 0)).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            report.fail(
+                &format!("Load TLS settings: {}", e),
+                &["Check server.tls and the client_cert / client_key files"],
+            );
+            return false;
+        }
+    };
+    match tokio::time::timeout(STEP_TIMEOUT, connection.handshake()).await {
+        Ok(Ok(chain)) => {
+            report.pass("TLS handshake");
+            if let Some(leaf) = chain.first() {
+                print_certificate(&leaf.0);
+            }
+            if !config.server.tls_verify {
+                println!("        Certificate verification is disabled (tls_verify = false)");
+            }
+        }
+        result => {
+            let error = match result {
+                Ok(Err(e)) => e.to_string(),
+                _ => "timed out".to_string(),
+            };
+            report.fail(
+                &format!("TLS handshake: {}", error),
+                &[
+                    "A self-signed server certificate needs tls_verify = false (development only)",
+                    "or a certificate from a public CA issued for server.addr",
+                    "Make sure server.addr is the name the certificate was issued for, or set server.server_name to it when connecting by another address",
+                    "When server.addr is an IP address, the certificate needs it as an IP entry in its subjectAltName",
+                    "Check that server.tls allows the versions and ciphers the server offers",
+                ],
+            );
+            return false;
+        }
+    }
+
+    // Authentication and a throwaway tunnel
+    let local = match TcpListener::bind((default_local_host(), 0)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            report.fail(&format!("Open a local test port: {}", e), &[]);
+            return false;
+        }
+    };
+    let local_port = local.local_addr().map(|addr| addr.port()).unwrap_or(0);
+    let connection = match ServerConnection::new(probe_config(config, local_port)).await {
+        Ok(connection) => Arc::new(connection),
+        Err(e) => {
+            report.fail(&format!("Prepare the test connection: {}", e), &[]);
+            return false;
+        }
+    };
+    let session = {
+        let connection = connection.clone();
+        tokio::spawn(async move { connection.connect().await })
+    };
+    let passed = check_session(config, &connection, &session, local, report).await;
+    session.abort();
+    passed
+}
+
+async fn check_session(
+    config: &ClientConfig,
+    connection: &ServerConnection,
+    session: &tokio::task::JoinHandle<nat_traversal_common::error::NatResult<()>>,
+    local: TcpListener,
+    report: &mut Report,
+) -> bool {
+    let deadline = tokio::time::Instant::now() + STEP_TIMEOUT;
+    loop {
+        match connection.get_state().await {
+            ConnectionState::Authenticated => break,
+            ConnectionState::Error(e) => {
+                report.fail(&format!("Authenticate: {}", e), &auth_hints());
+                return false;
+            }
+            _ if session.is_finished() || tokio::time::Instant::now() > deadline => {
+                report.fail(
+                    "Authenticate: the server closed the connection or did not answer",
+                    &auth_hints(),
+                );
+                return false;
+            }
+            _ => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    }
+    report.pass(&format!("Authenticate as {}", config.server.client_id));
+
+    let tunnel = match wait_for_tunnel(connection).await {
+        Some(tunnel) => tunnel,
+        None => {
+            report.fail(
+                "Create a test tunnel: the server did not open it",
+                &[
+                    "The server may be out of free ports in its tunnel range",
+                    "The token's scope or max_tunnels may not allow another TCP tunnel",
+                    "Check the server's log for why it refused",
+                ],
+            );
+            return false;
+        }
+    };
+    report.pass(&format!(
+        "Create a test tunnel on public port {}",
+        tunnel.remote_port
+    ));
+
+    let public = config.server.endpoint(tunnel.remote_port);
+    let probe = tokio::time::timeout(STEP_TIMEOUT, probe_tunnel(&public, &local)).await;
+    let _ = connection.close_tunnel(tunnel.id).await;
+    match probe {
+        Ok(Ok(())) => {
+            report.pass(&format!("Reach this machine through {}", public));
+            true
+        }
+        result => {
+            let error = match result {
+                Ok(Err(e)) => e.to_string(),
+                _ => "timed out".to_string(),
+            };
+            report.fail(
+                &format!("Reach this machine through {}: {}", public, error),
+                &[
+                    "Open the server's tunnel port range (8000-9000 by default) in its firewall",
+                    "and in any cloud security group",
+                ],
+            );
+            false
+        }
+    }
+}
+
+fn auth_hints() -> [&'static str; 3] {
+    [
+        "Check server.token (or token_file) against the server's auth tokens",
+        "Check server.client_id; scoped and signed tokens are bound to one client ID",
+        "A signed token may have expired; ask for a new one",
+    ]
+}
+
+/// `config` with a single tunnel to `local_port`, and nothing else that
+/// would open ports or start background work
+pub(crate) fn probe_config(config: &ClientConfig, local_port: u16) -> ClientConfig {
+    let mut config = config.clone();
+    config.tunnels = vec![TunnelConfig {
+        name: format!("diagnose-{}", Uuid::new_v4().simple()),
+        local_host: default_local_host(),
+        local_port,
+        remote_port: None,
+        port_count: 1,
+        protocol: TunnelProtocol::Tcp,
+        auto_start: local_port != 0,
+        ttl_secs: Some(STEP_TIMEOUT.as_secs() * 3),
+        schedule: Vec::new(),
+        domain: None,
+        http_auth: None,
+        tls_cert: None,
+        tls_key: None,
+        tls_passthrough: false,
+        https_redirect: false,
+        http_headers: None,
+        port_mapping: false,
+        e2e_peers: Vec::new(),
+        stages: Vec::new(),
+        dscp: None,
+        udp: None,
+        source: None,
+    }];
+    config.alerts = None;
+    config
+}
+
+pub(crate) async fn wait_for_tunnel(connection: &ServerConnection) -> Option<TunnelInfo> {
+    tokio::time::timeout(STEP_TIMEOUT, async {
+        loop {
+            if let Some(tunnel) = connection.get_tunnels().await.into_iter().next() {
+                return tunnel;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .ok()
+}
+
+/// Connect to the tunnel's public port and check the probe arrives on
+/// the local test port
+async fn probe_tunnel(public: &str, local: &TcpListener) -> anyhow::Result<()> {
+    let mut outside = TcpStream::connect(public).await?;
+    outside.write_all(PROBE).await?;
+    let (mut inside, _) = local.accept().await?;
+    let mut received = vec![0u8; PROBE.len()];
+    inside.read_exact(&mut received).await?;
+    if received != PROBE {
+        return Err(anyhow::anyhow!("the data arrived altered"));
+    }
+    Ok(())
+}
+
+/// Who the certificate was issued to and until when
+fn print_certificate(der: &[u8]) {
+    let Ok(params) = CertificateParams::from_ca_cert_der(&der.into()) else {
+        return;
+    };
+    let subject = match params.distinguished_name.get(&DnType::CommonName) {
+        Some(DnValue::Utf8String(name)) => name.clone(),
+        Some(DnValue::PrintableString(name)) => name.as_str().to_string(),
+        _ => "-".to_string(),
+    };
+    let names: Vec<String> = params
+        .subject_alt_names
+        .iter()
+        .map(|name| match name {
+            SanType::DnsName(name) => name.as_str().to_string(),
+            SanType::IpAddress(ip) => ip.to_string(),
+            other => format!("{:?}", other),
+        })
+        .collect();
+    println!("        Certificate subject: {}", subject);
+    if !names.is_empty() {
+        println!("        Certificate names:   {}", names.join(", "));
+    }
+    println!("        Valid until:         {}", params.not_after);
+}
+
+async fn check_nat(config: &ClientConfig, report: &mut Report) {
+    let servers = config.stun.server_list(&config.server);
+    let timeout = Duration::from_millis(config.stun.timeout_ms);
+
+    let nat = match stun::detect_nat(&servers, timeout).await {
+        Ok(nat) => nat,
+        Err(e) => {
+            report.warn(
+                &format!("NAT behavior (STUN: {}): {}", servers.join(", "), e),
+                &["UDP may be blocked; tunnels through the server still work"],
+            );
+            return;
+        }
+    };
+    report.pass(&format!("NAT behavior (STUN: {})", servers.join(", ")));
+
+    println!("        Local address:   {}", nat.stun.local_addr);
+    println!("        Public address:  {}", nat.stun.public_addr);
+    println!("        Mapping:         {:?}", nat.stun.mapping);
+    println!("        Filtering:       {:?}", nat.filtering);
+    match nat.hairpinning {
+        Some(supported) => println!(
+            "        Hairpinning:     {}",
+            if supported { "yes" } else { "no" }
+        ),
+        None => println!("        Hairpinning:     n/a"),
+    }
+    println!("        NAT type:        {}", nat.nat_type);
+
+    if nat.nat_type.allows_direct() {
+        println!("        Direct connections to peers should work");
+    } else if nat.nat_type == stun::NatType::Symmetric {
+        println!("        Direct connections need a reachable peer or a router port mapping;");
+        println!("        otherwise traffic has to go through the server");
+    } else {
+        println!(
+            "        Not enough STUN servers answered to tell whether direct connections work"
+        );
+    }
+}
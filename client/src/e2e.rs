@@ -0,0 +1,177 @@
+//! End-to-end encrypted tunnels. For tunnels with `e2e_peers` this client
+//! answers the Noise handshake before touching the local service; visitors
+//! open the handshake towards another client's tunnel through its public
+//! port. Either way the server relays only ciphertext.
+
+use nat_traversal_common::{
+    config::{ClientConfig, SocketOptions},
+    noise, socket,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::io::DuplexStream;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, info_span, warn, Instrument};
+
+/// Keys a tunnel this client exposes accepts consumers with
+pub struct TunnelKeys {
+    private: Arc<Vec<u8>>,
+    peers: Vec<Vec<u8>>,
+}
+
+impl TunnelKeys {
+    /// Complete the handshake on `tunneled`, then carry its traffic to the
+    /// local service at `target`
+    pub async fn serve(
+        self: Arc<Self>,
+        tunneled: DuplexStream,
+        target: String,
+        sockets: SocketOptions,
+    ) {
+        let session = match noise::accept(tunneled, &self.private, &self.peers).await {
+            Ok(session) => session,
+            Err(e) => {
+                warn!("Refused encrypted connection: {}", e);
+                return;
+            }
+        };
+        let local = match socket::connect(&target, &sockets).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to connect to local service {}: {}", target, e);
+                return;
+            }
+        };
+        if let Err(e) = session.pump(local).await {
+            debug!("Encrypted connection ended: {}", e);
+        }
+    }
+}
+
+/// This client's private key, when one is configured
+fn private_key(config: &ClientConfig) -> anyhow::Result<Option<Arc<Vec<u8>>>> {
+    match config.e2e.load_private_key()? {
+        Some(key) => {
+            Ok(Some(Arc::new(noise::decode_key(&key).map_err(|e| {
+                anyhow::anyhow!("Invalid e2e private key: {}", e)
+            })?)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Keys for each configured tunnel with `e2e_peers`, by tunnel name
+pub fn tunnel_keys(config: &ClientConfig) -> anyhow::Result<HashMap<String, Arc<TunnelKeys>>> {
+    let mut keys = HashMap::new();
+    let encrypted: Vec<_> = config
+        .tunnels
+        .iter()
+        .filter(|tunnel| !tunnel.e2e_peers.is_empty())
+        .collect();
+    if encrypted.is_empty() {
+        return Ok(keys);
+    }
+
+    let private = private_key(config)?.ok_or_else(|| {
+        anyhow::anyhow!("Tunnels with e2e_peers need e2e.private_key or e2e.private_key_file")
+    })?;
+    info!("E2E public key: {}", noise::public_key(&private)?);
+    for tunnel in encrypted {
+        let peers = tunnel
+            .e2e_peers
+            .iter()
+            .map(|peer| noise::decode_key(peer))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_err(|e| anyhow::anyhow!("Invalid e2e peer of tunnel {}: {}", tunnel.name, e))?;
+        keys.insert(
+            tunnel.name.clone(),
+            Arc::new(TunnelKeys {
+                private: private.clone(),
+                peers,
+            }),
+        );
+    }
+    Ok(keys)
+}
+
+/// Bind every configured visitor and serve it in the background
+pub async fn start_visitors(config: &ClientConfig) -> anyhow::Result<()> {
+    if config.e2e.visitors.is_empty() {
+        return Ok(());
+    }
+    let private = private_key(config)?.ok_or_else(|| {
+        anyhow::anyhow!("E2E visitors need e2e.private_key or e2e.private_key_file")
+    })?;
+
+    for visitor in &config.e2e.visitors {
+        let peer = noise::decode_key(&visitor.peer)
+            .map_err(|e| anyhow::anyhow!("Invalid peer key of visitor {}: {}", visitor.name, e))?;
+        let bind = format!("{}:{}", visitor.bind_host, visitor.bind_port);
+        let listener = TcpListener::bind(&bind).await.map_err(|e| {
+            anyhow::anyhow!("Failed to bind visitor {} on {}: {}", visitor.name, bind, e)
+        })?;
+        info!(
+            "Visitor {} on {} reaches server port {}",
+            visitor.name, bind, visitor.server_port
+        );
+
+        let server = config.server.endpoint(visitor.server_port);
+        let span = info_span!("visitor", name = %visitor.name);
+        tokio::spawn(
+            accept_loop(
+                listener,
+                server,
+                private.clone(),
+                Arc::new(peer),
+                config.sockets.tunnel.clone(),
+            )
+            .instrument(span),
+        );
+    }
+    Ok(())
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    server: String,
+    private: Arc<Vec<u8>>,
+    peer: Arc<Vec<u8>>,
+    sockets: SocketOptions,
+) {
+    while let Ok((local, addr)) = listener.accept().await {
+        socket::configure(&local, &sockets);
+        let (server, private, peer, sockets) = (
+            server.clone(),
+            private.clone(),
+            peer.clone(),
+            sockets.clone(),
+        );
+        tokio::spawn(
+            async move {
+                if let Err(e) = visit(local, &server, &private, &peer, &sockets).await {
+                    warn!("Encrypted connection from {} failed: {}", addr, e);
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+async fn visit(
+    local: TcpStream,
+    server: &str,
+    private: &[u8],
+    peer: &[u8],
+    sockets: &SocketOptions,
+) -> anyhow::Result<()> {
+    let remote = socket::connect(server, sockets).await?;
+    let (sent, received) = noise::connect(remote, private, peer)
+        .await?
+        .pump(local)
+        .await?;
+    debug!(
+        "Encrypted connection closed ({} bytes out, {} in)",
+        sent, received
+    );
+    Ok(())
+}
@@ -0,0 +1,156 @@
+//! `nat-client expose`: share one local port right now, without touching
+//! the configuration. The tunnel lives as long as the command does.
+
+use crate::core::NatClient;
+use nat_traversal_common::{
+    config::{ClientConfig, ServerConnectionConfig, TunnelConfig},
+    protocol::{default_local_host, TunnelInfo, TunnelProtocol},
+};
+use std::time::Duration;
+
+/// How long to wait for the server to open the tunnel
+const CREATE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum ExposeProtocol {
+    Tcp,
+    Udp,
+    /// A TCP tunnel printed as an `http://` URL
+    Http,
+}
+
+/// Parse a TTL such as `90`, `30s`, `15m`, `1h` or `2d` into seconds
+pub fn parse_ttl(value: &str) -> Result<u64, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let scale = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(format!("Unknown TTL unit in '{}'; use s, m, h or d", value)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(scale))
+        .filter(|&secs| secs > 0)
+        .ok_or_else(|| format!("Invalid TTL '{}'", value))
+}
+
+/// Connect with only an `expose` tunnel, print its public endpoint and
+/// close it on Ctrl+C or when its TTL runs out
+pub async fn run(
+    mut config: ClientConfig,
+    local_port: u16,
+    protocol: ExposeProtocol,
+    ttl_secs: Option<u64>,
+) -> anyhow::Result<()> {
+    let name = format!("expose-{}", local_port);
+    config.tunnels = vec![TunnelConfig {
+        name: name.clone(),
+        local_host: default_local_host(),
+        local_port,
+        remote_port: None,
+        port_count: 1,
+        protocol: match protocol {
+            ExposeProtocol::Udp => TunnelProtocol::Udp,
+            ExposeProtocol::Tcp | ExposeProtocol::Http => TunnelProtocol::Tcp,
+        },
+        auto_start: true,
+        ttl_secs,
+        schedule: Vec::new(),
+        domain: None,
+        http_auth: None,
+        tls_cert: None,
+        tls_key: None,
+        tls_passthrough: false,
+        https_redirect: false,
+        http_headers: None,
+        port_mapping: false,
+        e2e_peers: Vec::new(),
+        stages: Vec::new(),
+        dscp: None,
+        udp: None,
+        source: None,
+    }];
+    config.gui.enabled = false;
+    let server = config.server.clone();
+
+    let client = NatClient::new(config).await?;
+    client.start().await?;
+
+    let result = tokio::select! {
+        result = serve(&client, &name, &server, protocol) => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+
+    if let Some(tunnel) = find(&client, &name).await {
+        if let Err(e) = client.close_tunnel(tunnel.id).await {
+            tracing::warn!("Failed to close tunnel {}: {}", tunnel.id, e);
+        }
+    }
+    client.stop().await?;
+    result
+}
+
+/// Wait for the tunnel, print where it is reachable and keep going until
+/// the server closes it
+async fn serve(
+    client: &NatClient,
+    name: &str,
+    server: &ServerConnectionConfig,
+    protocol: ExposeProtocol,
+) -> anyhow::Result<()> {
+    let tunnel = tokio::time::timeout(CREATE_TIMEOUT, async {
+        loop {
+            if let Some(tunnel) = find(client, name).await {
+                return tunnel;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .map_err(|_| {
+        anyhow::anyhow!(
+            "The server did not open the tunnel within {}s; check the log for why",
+            CREATE_TIMEOUT.as_secs()
+        )
+    })?;
+
+    let scheme = match protocol {
+        ExposeProtocol::Tcp => "tcp",
+        ExposeProtocol::Udp => "udp",
+        ExposeProtocol::Http => "http",
+    };
+    println!(
+        "Forwarding {}://{} -> {}:{}",
+        scheme,
+        server.endpoint(tunnel.remote_port),
+        tunnel.local_host,
+        tunnel.local_port
+    );
+    if let Some(expires_at) = tunnel.expires_at {
+        println!("Closes at {}", expires_at.format("%Y-%m-%d %H:%M:%S UTC"));
+    }
+    println!("Press Ctrl+C to stop");
+
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        ticker.tick().await;
+        if find(client, name).await.is_none() {
+            println!("The server closed the tunnel");
+            return Ok(());
+        }
+    }
+}
+
+async fn find(client: &NatClient, name: &str) -> Option<TunnelInfo> {
+    client
+        .get_tunnels()
+        .await
+        .into_iter()
+        .find(|tunnel| tunnel.name.as_deref() == Some(name))
+}
@@ -0,0 +1,564 @@
+use crate::capture::RequestLog;
+use crate::e2e::TunnelKeys;
+use bytes::{Bytes, BytesMut};
+use dashmap::DashMap;
+use nat_traversal_common::{
+    config::{Dscp, PerformanceConfig, SocketOptions, TunnelConfig},
+    datagram::DatagramStream,
+    error::{NatError, NatResult},
+    pipeline::Pipeline,
+    protocol::{Message, ShutdownDirection, TunnelInfo, TunnelProtocol, UdpSessions},
+    queue::{self, QueueSender, SendError},
+    resume::{Outbox, ResumeLimits},
+    socket,
+    telemetry::{self, Direction},
+};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+type ConnectionKey = (Uuid, u32);
+
+/// Buffer between the tunnel and an end-to-end encrypted session
+const E2E_BUFFER: usize = 256 * 1024;
+
+/// The local end of a tunneled connection: the service itself, or the
+/// plaintext side of an end-to-end encrypted session
+trait LocalStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> LocalStream for T {}
+
+/// Connect to a tunnel's local service, over UDP for a UDP tunnel,
+/// keeping to the largest datagram the server advertised for it. The
+/// server ends idle UDP sessions, which closes this end as well.
+async fn connect_local(
+    target: &str,
+    sockets: &SocketOptions,
+    udp: Option<UdpSessions>,
+) -> std::io::Result<Box<dyn LocalStream>> {
+    if let Some(udp) = udp {
+        let socket = socket::connect_datagrams(target, sockets).await?;
+        return Ok(Box::new(
+            DatagramStream::connected(socket, None)
+                .with_max_datagram(usize::from(udp.max_datagram_size)),
+        ));
+    }
+    Ok(Box::new(socket::connect(target, sockets).await?))
+}
+
+/// Bytes a tunnel has carried. Each connection holds the counters it adds
+/// to, so counting needs no map lookup per packet.
+#[derive(Default)]
+struct Traffic {
+    /// Local service -> server
+    sent: AtomicU64,
+    /// Server -> local service
+    received: AtomicU64,
+}
+
+/// A tunneled connection to a local service, as the session reaches it
+struct LocalConnection {
+    sender: QueueSender<Bytes>,
+    /// Payload bytes received from the server, reported when resuming
+    received: Arc<AtomicU64>,
+    /// Tells the connection's reader about the session
+    events: mpsc::UnboundedSender<SessionEvent>,
+}
+
+/// What happened to the session a connection is carried on
+enum SessionEvent {
+    /// The local service stopped reading and the data for it reached the
+    /// buffer cap; drop the connection
+    Overflowed,
+    /// The session is gone; hold data for the next one
+    Lost,
+    /// The server picked the connection up on a new session after
+    /// receiving `received` bytes of it, and carries it on `tx`
+    Resumed {
+        tx: mpsc::UnboundedSender<Message>,
+        received: u64,
+    },
+}
+
+/// Build the pipelines of the configured tunnels that list stages, by
+/// tunnel name
+pub fn pipelines(tunnels: &[TunnelConfig]) -> NatResult<HashMap<String, Arc<Pipeline>>> {
+    let mut pipelines = HashMap::new();
+    for tunnel in tunnels.iter().filter(|tunnel| !tunnel.stages.is_empty()) {
+        // The forwarder only sees ciphertext of encrypted tunnels
+        if !tunnel.e2e_peers.is_empty() {
+            return Err(NatError::config(format!(
+                "Tunnel {} uses e2e_peers and cannot have stages",
+                tunnel.name
+            )));
+        }
+        let pipeline = Pipeline::new(&tunnel.name, &tunnel.stages)
+            .map_err(|e| NatError::config(format!("Tunnel {}: {}", tunnel.name, e)))?;
+        pipelines.insert(tunnel.name.clone(), Arc::new(pipeline));
+    }
+    Ok(pipelines)
+}
+
+/// DSCP classes of the configured tunnels that set one, by tunnel name
+pub fn dscp_classes(tunnels: &[TunnelConfig]) -> HashMap<String, Dscp> {
+    tunnels
+        .iter()
+        .filter_map(|tunnel| Some((tunnel.name.clone(), tunnel.dscp?)))
+        .collect()
+}
+
+/// Forwards tunneled connections to the local services they target
+pub struct LocalForwarder {
+    /// Sharded so data for one connection never waits on another
+    connections: Arc<DashMap<ConnectionKey, LocalConnection>>,
+    traffic: DashMap<Uuid, Arc<Traffic>>,
+    performance: PerformanceConfig,
+    /// Options for connections to local services
+    sockets: SocketOptions,
+    /// DSCP classes replacing the one in `sockets`, by tunnel name
+    dscp: HashMap<String, Dscp>,
+    /// Keys of end-to-end encrypted tunnels, by tunnel name
+    e2e: HashMap<String, Arc<TunnelKeys>>,
+    /// Stages of the tunnels that list some, by tunnel name
+    pipelines: HashMap<String, Arc<Pipeline>>,
+    /// Requests to custom-domain tunnels, for replay
+    requests: Arc<RequestLog>,
+    /// How long connections wait for the session to come back
+    resume: ResumeLimits,
+    /// Bytes a connection may queue for its local service
+    buffer: usize,
+    /// Connections left over from the last session, to resume on the next
+    held: Mutex<Vec<ConnectionKey>>,
+}
+
+impl LocalForwarder {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        performance: PerformanceConfig,
+        sockets: SocketOptions,
+        dscp: HashMap<String, Dscp>,
+        e2e: HashMap<String, Arc<TunnelKeys>>,
+        pipelines: HashMap<String, Arc<Pipeline>>,
+        requests: Arc<RequestLog>,
+        resume: ResumeLimits,
+        buffer: usize,
+    ) -> Self {
+        Self {
+            connections: Arc::new(DashMap::new()),
+            traffic: DashMap::new(),
+            performance,
+            sockets,
+            dscp,
+            e2e,
+            pipelines,
+            requests,
+            resume,
+            buffer,
+            held: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Open a connection to the tunnel's local target for a new public connection.
+    /// `port_offset` picks the local port of a port-range tunnel.
+    ///
+    /// The connection is registered immediately so data arriving while the
+    /// local connect is still in progress is queued rather than dropped.
+    pub async fn open(
+        &self,
+        tunnel: &TunnelInfo,
+        connection_id: u32,
+        port_offset: u16,
+        message_tx: mpsc::UnboundedSender<Message>,
+    ) {
+        let key = (tunnel.id, connection_id);
+        let (tx, mut rx) = queue::queue::<Bytes>(self.performance.connection_queue, self.buffer);
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        self.connections.insert(
+            key,
+            LocalConnection {
+                sender: tx,
+                received: Arc::new(AtomicU64::new(0)),
+                events: events_tx,
+            },
+        );
+
+        let connections = self.connections.clone();
+        let traffic = self.traffic.entry(tunnel.id).or_default().clone();
+        let target = tunnel.local_target(port_offset);
+        let tunnel_id = tunnel.id;
+        // Servers from before session settings send none for UDP tunnels
+        let udp = (tunnel.protocol == TunnelProtocol::Udp).then(|| tunnel.udp.unwrap_or_default());
+        let read_buffer_size = self.performance.read_buffer_size;
+        let resume = self.resume;
+        let sockets = SocketOptions {
+            dscp: tunnel
+                .name
+                .as_ref()
+                .and_then(|name| self.dscp.get(name))
+                .copied()
+                .or(self.sockets.dscp),
+            ..self.sockets.clone()
+        };
+        let e2e = tunnel
+            .name
+            .as_ref()
+            .and_then(|name| self.e2e.get(name))
+            .cloned();
+        let pipeline = tunnel
+            .name
+            .as_ref()
+            .and_then(|name| self.pipelines.get(name))
+            .cloned();
+        // Only custom-domain tunnels carry HTTP, and encrypted ones carry
+        // nothing readable
+        let requests = self.requests.clone();
+        let mut capturer = (tunnel.domain.is_some()
+            && !tunnel.tls_passthrough
+            && e2e.is_none()
+            && requests.enabled())
+        .then(|| {
+            let label = tunnel.name.clone().unwrap_or_else(|| tunnel_id.to_string());
+            requests.capturer(tunnel_id, label, target.clone())
+        });
+
+        let span = info_span!("session", %tunnel_id, connection_id, %target);
+        tokio::spawn(
+            async move {
+                // Encrypted tunnels reach the service only after the handshake
+                let stream: Box<dyn LocalStream> = match e2e {
+                    Some(keys) => {
+                        let (near, far) = tokio::io::duplex(E2E_BUFFER);
+                        tokio::spawn(keys.serve(far, target.clone(), sockets).in_current_span());
+                        Box::new(near)
+                    }
+                    None => match connect_local(&target, &sockets, udp).await {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!(
+                                "Failed to connect to local service {} for tunnel {}: {}",
+                                target, tunnel_id, e
+                            );
+                            if connections.remove(&key).is_some() {
+                                let _ = message_tx.send(Message::ConnectionClosed {
+                                    tunnel_id,
+                                    connection_id,
+                                });
+                            }
+                            return;
+                        }
+                    },
+                };
+
+                debug!(
+                    "Connection {} on tunnel {} forwarded to {}",
+                    connection_id, tunnel_id, target
+                );
+
+                telemetry::sessions_changed(1);
+                let (mut reader, mut writer) = tokio::io::split(stream);
+
+                // Server -> local service. An empty chunk is the visitor's
+                // half-close: the service reads end of stream while it may
+                // still answer.
+                let write_traffic = traffic.clone();
+                let write_pipeline = pipeline.clone();
+                let mut write_task = tokio::spawn(
+                    async move {
+                        let mut forwarded = 0u64;
+                        while let Some(mut data) = rx.recv().await {
+                            if data.is_empty() {
+                                break;
+                            }
+                            if let Some(pipeline) = &write_pipeline {
+                                data = pipeline.pass(connection_id, Direction::Inbound, data).await;
+                                if data.is_empty() {
+                                    continue;
+                                }
+                            }
+                            if let Some(capturer) = &mut capturer {
+                                capturer.feed(&requests, &data);
+                            }
+                            if let Err(e) = writer.write_all(&data).await {
+                                error!("Error writing to local service: {}", e);
+                                break;
+                            }
+                            forwarded += data.len() as u64;
+                            write_traffic
+                                .received
+                                .fetch_add(data.len() as u64, Ordering::Relaxed);
+                        }
+                        let _ = writer.shutdown().await;
+                        telemetry::bytes_forwarded(Direction::Inbound, forwarded);
+                    }
+                    .in_current_span(),
+                );
+
+                // Local service -> server, holding the data while the
+                // session is away
+                let mut outbox = Outbox::new(message_tx, resume);
+                let mut buffer = BytesMut::with_capacity(read_buffer_size);
+                let mut forwarded = 0u64;
+                let mut reading = true;
+                let finished = loop {
+                    let expiry = outbox.expiry();
+                    tokio::select! {
+                        read = reader.read_buf(&mut buffer), if reading => match read {
+                            Ok(0) if connections.contains_key(&key) => {
+                                // The service is done sending; the visitor
+                                // may still be sending it a request
+                                reading = false;
+                                let shutdown = Message::ConnectionShutdown {
+                                    tunnel_id,
+                                    connection_id,
+                                    direction: ShutdownDirection::Outbound,
+                                };
+                                if !outbox.send(shutdown) {
+                                    break false;
+                                }
+                            }
+                            Ok(0) => break false,
+                            Ok(_) => {
+                                let mut data = buffer.split().freeze();
+                                if let Some(pipeline) = &pipeline {
+                                    data = pipeline
+                                        .pass(connection_id, Direction::Outbound, data)
+                                        .await;
+                                    // An empty chunk would half-close the
+                                    // visitor's connection
+                                    if data.is_empty() {
+                                        continue;
+                                    }
+                                }
+                                forwarded += data.len() as u64;
+                                traffic.sent.fetch_add(data.len() as u64, Ordering::Relaxed);
+                                let message = Message::Data {
+                                    tunnel_id,
+                                    data,
+                                    connection_id,
+                                };
+                                if !outbox.send(message) {
+                                    break false;
+                                }
+                            }
+                            Err(e) => {
+                                error!("Error reading from local service: {}", e);
+                                break false;
+                            }
+                        },
+                        _ = &mut write_task, if !reading => break true,
+                        event = events.recv() => match event {
+                            // Closed by the server: the connection left
+                            // the map, and its events sender with it
+                            None => break false,
+                            Some(SessionEvent::Overflowed) => {
+                                // Nothing queued for the service is kept
+                                write_task.abort();
+                                outbox.notify(Message::ConnectionClosed {
+                                    tunnel_id,
+                                    connection_id,
+                                });
+                                break false;
+                            }
+                            Some(SessionEvent::Lost) => {
+                                outbox.hold();
+                            }
+                            Some(SessionEvent::Resumed { tx, received }) => {
+                                if !outbox.resume(tx, received) {
+                                    debug!("The data lost with the session is gone, closing");
+                                    break false;
+                                }
+                                // The end of stream may have been lost too
+                                if !reading {
+                                    outbox.send(Message::ConnectionShutdown {
+                                        tunnel_id,
+                                        connection_id,
+                                        direction: ShutdownDirection::Outbound,
+                                    });
+                                }
+                                debug!("Connection resumed");
+                            }
+                        },
+                        _ = expiry => {
+                            debug!("The session did not come back in time, closing");
+                            break false;
+                        }
+                    }
+                };
+                telemetry::bytes_forwarded(Direction::Outbound, forwarded);
+
+                if finished {
+                    connections.remove(&key);
+                } else {
+                    // Tell the server unless it closed the connection first
+                    if connections.remove(&key).is_some() {
+                        outbox.notify(Message::ConnectionClosed {
+                            tunnel_id,
+                            connection_id,
+                        });
+                    }
+                    let _ = write_task.await;
+                }
+                if let Some(pipeline) = &pipeline {
+                    pipeline.close(connection_id);
+                }
+                telemetry::sessions_changed(-1);
+            }
+            .instrument(span),
+        );
+    }
+
+    /// Queue data received from the server for a local connection
+    pub async fn send(&self, tunnel_id: Uuid, connection_id: u32, data: Bytes) {
+        // An empty chunk would half-close the connection
+        if data.is_empty() {
+            return;
+        }
+        // Release the shard before a bounded queue can make us wait
+        let connection = self
+            .connections
+            .get(&(tunnel_id, connection_id))
+            .map(|connection| {
+                (
+                    connection.sender.clone(),
+                    connection.received.clone(),
+                    connection.events.clone(),
+                )
+            });
+        match connection {
+            Some((sender, received, events)) => {
+                let len = data.len() as u64;
+                match sender.send(data).await {
+                    Ok(()) => {
+                        received.fetch_add(len, Ordering::Relaxed);
+                    }
+                    Err(SendError::Full(_)) => {
+                        warn!(
+                            "Connection {} on tunnel {} has {} bytes waiting for the local service, dropping it",
+                            connection_id,
+                            tunnel_id,
+                            sender.buffered()
+                        );
+                        // Frames still on their way find no connection
+                        self.connections.remove(&(tunnel_id, connection_id));
+                        let _ = events.send(SessionEvent::Overflowed);
+                    }
+                    Err(SendError::Closed(_)) => {}
+                }
+            }
+            None => debug!(
+                "Dropping data for unknown connection {} on tunnel {}",
+                connection_id, tunnel_id
+            ),
+        }
+    }
+
+    /// End the local service's input after the visitor stopped sending,
+    /// once the data queued before it is written
+    pub async fn shutdown(&self, tunnel_id: Uuid, connection_id: u32) {
+        let sender = self
+            .connections
+            .get(&(tunnel_id, connection_id))
+            .map(|connection| connection.sender.clone());
+        if let Some(sender) = sender {
+            let _ = sender.send(Bytes::new()).await;
+        }
+    }
+
+    /// Keep the open connections for the next session when resumption is
+    /// on, or close them
+    pub fn hold_all(&self) {
+        if !self.resume.enabled() {
+            return self.close_all();
+        }
+        let mut held = self.held.lock().unwrap();
+        held.clear();
+        for connection in self.connections.iter() {
+            let _ = connection.events.send(SessionEvent::Lost);
+            held.push(*connection.key());
+        }
+    }
+
+    /// Ask the server on `tx` to continue the connections left over from
+    /// the last session
+    pub fn resume_all(&self, tx: &mpsc::UnboundedSender<Message>) {
+        let held = std::mem::take(&mut *self.held.lock().unwrap());
+        let mut resumed = 0;
+        for (tunnel_id, connection_id) in held {
+            let Some(connection) = self.connections.get(&(tunnel_id, connection_id)) else {
+                continue;
+            };
+            let message = Message::ResumeConnection {
+                tunnel_id,
+                connection_id,
+                received: connection.received.load(Ordering::Relaxed),
+            };
+            if tx.send(message).is_ok() {
+                resumed += 1;
+            }
+        }
+        if resumed > 0 {
+            info!("Resuming {} open connections", resumed);
+        }
+    }
+
+    /// Continue a connection on `tx` once the server has picked it up,
+    /// having received `received` bytes of it
+    pub fn resumed(
+        &self,
+        tunnel_id: Uuid,
+        connection_id: u32,
+        received: u64,
+        tx: &mpsc::UnboundedSender<Message>,
+    ) {
+        let event = SessionEvent::Resumed {
+            tx: tx.clone(),
+            received,
+        };
+        let delivered = self
+            .connections
+            .get(&(tunnel_id, connection_id))
+            .is_some_and(|connection| connection.events.send(event).is_ok());
+        if !delivered {
+            let _ = tx.send(Message::ConnectionClosed {
+                tunnel_id,
+                connection_id,
+            });
+        }
+    }
+
+    /// Close a local connection after the public side went away. Its
+    /// reader stops and what was queued for the service is written out.
+    pub fn close(&self, tunnel_id: Uuid, connection_id: u32) {
+        self.connections.remove(&(tunnel_id, connection_id));
+    }
+
+    /// Close every local connection belonging to a tunnel
+    pub fn close_tunnel(&self, tunnel_id: Uuid) {
+        self.connections.retain(|(id, _), _| *id != tunnel_id);
+        self.traffic.remove(&tunnel_id);
+    }
+
+    /// Fill in the tunnel's open connections and byte counters
+    pub fn fill_stats(&self, tunnel: &mut TunnelInfo) {
+        tunnel.active_connections = self
+            .connections
+            .iter()
+            .filter(|entry| entry.key().0 == tunnel.id)
+            .count() as u32;
+        if let Some(traffic) = self.traffic.get(&tunnel.id) {
+            tunnel.bytes_sent = traffic.sent.load(Ordering::Relaxed);
+            tunnel.bytes_received = traffic.received.load(Ordering::Relaxed);
+        }
+    }
+
+    /// Close all local connections, e.g. after losing the server connection
+    pub fn close_all(&self) {
+        self.connections.clear();
+        self.traffic.clear();
+        self.held.lock().unwrap().clear();
+    }
+}
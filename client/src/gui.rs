@@ -1,10 +1,17 @@
-use crate::{connection::ConnectionState, core::NatClient};
+use crate::{
+    connection::{ConnectionState, RttStats},
+    core::NatClient,
+    schedule::ScheduleStatus,
+    status::format_duration,
+};
+use chrono::Utc;
 use eframe::egui;
 use nat_traversal_common::{
-    config::{save_config, ClientConfig},
-    protocol::{TunnelInfo, TunnelProtocol},
+    config::{load_config, save_config, ClientConfig},
+    protocol::{default_local_host, HttpAuth, ShareInfo, TunnelInfo, TunnelMode, TunnelProtocol},
+    stun::{NatReport, StunReport},
 };
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use tokio::sync::mpsc;
 
 /// Main application state for the GUI
@@ -15,30 +22,68 @@ pub struct NatClientApp {
     // UI state
     connection_state: ConnectionState,
     tunnels: Vec<TunnelInfo>,
+    shares: Vec<ShareInfo>,
+    schedules: Vec<ScheduleStatus>,
+    stun_report: Option<StunReport>,
+    nat_report: Option<NatReport>,
+    rtt: RttStats,
+    /// The server's last message of the day, shown once until it changes
+    motd: Option<String>,
 
     // Forms and inputs
     new_tunnel_form: NewTunnelForm,
+    share_form: ShareForm,
     settings_window: bool,
     about_window: bool,
+    motd_window: bool,
 
     // Async state management
     state_receiver: Option<mpsc::UnboundedReceiver<AppState>>,
     state_sender: Option<mpsc::UnboundedSender<AppState>>,
+    state_task: Option<tokio::task::JoinHandle<()>>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 enum AppState {
     ConnectionState(ConnectionState),
     Tunnels(Vec<TunnelInfo>),
+    Shares(Vec<ShareInfo>),
+    Schedules(Vec<ScheduleStatus>),
+    Stun(Option<StunReport>),
+    Nat(Option<NatReport>),
+    Rtt(RttStats),
+    Motd(Option<String>),
+    Client(Arc<NatClient>),
 }
 
 #[derive(Default)]
 struct NewTunnelForm {
     name: String,
+    local_host: String,
     local_port: String,
     remote_port: String,
+    /// Minutes until the server closes the tunnel; empty for no limit
+    ttl_minutes: String,
+    /// Custom domain served through the server's HTTP listener
+    domain: String,
+    /// `user:password` the server asks HTTP visitors for
+    http_auth: String,
     protocol: TunnelProtocol,
-    auto_start: bool,
+}
+
+/// Settings the Share buttons of the tunnel list use
+struct ShareForm {
+    ttl_minutes: String,
+    password: String,
+}
+
+impl Default for ShareForm {
+    fn default() -> Self {
+        Self {
+            ttl_minutes: "60".to_string(),
+            password: String::new(),
+        }
+    }
 }
 
 impl Default for NatClientApp {
@@ -49,11 +94,20 @@ impl Default for NatClientApp {
             config: ClientConfig::default(),
             connection_state: ConnectionState::Disconnected,
             tunnels: Vec::new(),
+            shares: Vec::new(),
+            schedules: Vec::new(),
+            stun_report: None,
+            nat_report: None,
+            rtt: RttStats::default(),
+            motd: None,
             new_tunnel_form: NewTunnelForm::default(),
+            share_form: ShareForm::default(),
             settings_window: false,
             about_window: false,
+            motd_window: false,
             state_receiver: Some(state_receiver),
             state_sender: Some(state_sender),
+            state_task: None,
         }
     }
 }
@@ -71,12 +125,22 @@ impl NatClientApp {
 
     pub async fn initialize(&mut self) -> anyhow::Result<()> {
         let client = Arc::new(NatClient::new(self.config.clone()).await?);
+        self.set_client(client);
+        Ok(())
+    }
+
+    fn set_client(&mut self, client: Arc<NatClient>) {
         self.client = Some(client.clone());
 
+        // Replace the background task that polls the previous client
+        if let Some(task) = self.state_task.take() {
+            task.abort();
+        }
+
         // Start background task to update state
         if let Some(sender) = &self.state_sender {
             let sender = sender.clone();
-            tokio::spawn(async move {
+            self.state_task = Some(tokio::spawn(async move {
                 loop {
                     tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
 
@@ -87,11 +151,55 @@ impl NatClientApp {
                     // Get tunnels
                     let tunnels = client.get_tunnels().await;
                     let _ = sender.send(AppState::Tunnels(tunnels));
+                    let shares = client.get_shares().await;
+                    let _ = sender.send(AppState::Shares(shares));
+                    let _ = sender.send(AppState::Schedules(client.get_schedules()));
+
+                    // Get public address
+                    let report = client.get_stun_report().await;
+                    let _ = sender.send(AppState::Stun(report));
+                    let nat = client.get_nat_report().await;
+                    let _ = sender.send(AppState::Nat(nat));
+                    let _ = sender.send(AppState::Rtt(client.get_rtt().await));
+                    let _ = sender.send(AppState::Motd(client.get_motd().await));
+                }
+            }));
+        }
+    }
+
+    fn switch_profile(&mut self, name: &str) {
+        let config = match self.config.with_profile(name) {
+            Ok(config) => config,
+            Err(e) => {
+                tracing::error!("Failed to switch profile: {}", e);
+                return;
+            }
+        };
+
+        self.stop_client();
+        self.config = config.clone();
+        self.tunnels.clear();
+        self.schedules.clear();
+        self.stun_report = None;
+
+        if let Some(sender) = &self.state_sender {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                match NatClient::new(config).await {
+                    Ok(client) => {
+                        let _ = sender.send(AppState::Client(Arc::new(client)));
+                    }
+                    Err(e) => tracing::error!("Failed to create client for profile: {}", e),
                 }
             });
         }
+    }
 
-        Ok(())
+    fn save_settings(&self) -> anyhow::Result<()> {
+        // Merge into the file on disk so other profiles are preserved
+        let mut on_disk: ClientConfig = load_config("client.toml")?;
+        on_disk.store_active(&self.config);
+        save_config(&on_disk, "client.toml")
     }
 
     fn start_client(&mut self) {
@@ -117,6 +225,8 @@ impl NatClientApp {
     }
 
     fn update_state(&mut self) {
+        let mut pending_client = None;
+
         // Process any pending state updates from background task
         if let Some(receiver) = &mut self.state_receiver {
             while let Ok(state) = receiver.try_recv() {
@@ -127,9 +237,36 @@ impl NatClientApp {
                     AppState::Tunnels(new_tunnels) => {
                         self.tunnels = new_tunnels;
                     }
+                    AppState::Shares(shares) => {
+                        self.shares = shares;
+                    }
+                    AppState::Schedules(schedules) => {
+                        self.schedules = schedules;
+                    }
+                    AppState::Stun(report) => {
+                        self.stun_report = report;
+                    }
+                    AppState::Nat(report) => {
+                        self.nat_report = report;
+                    }
+                    AppState::Rtt(rtt) => {
+                        self.rtt = rtt;
+                    }
+                    AppState::Motd(Some(motd)) if self.motd.as_ref() != Some(&motd) => {
+                        self.motd = Some(motd);
+                        self.motd_window = true;
+                    }
+                    AppState::Motd(_) => {}
+                    AppState::Client(client) => {
+                        pending_client = Some(client);
+                    }
                 }
             }
         }
+
+        if let Some(client) = pending_client {
+            self.set_client(client);
+        }
     }
 }
 
@@ -177,6 +314,30 @@ impl eframe::App for NatClientApp {
                 ui.label(status_text);
                 ui.separator();
                 ui.label(format!("Tunnels: {}", self.tunnels.len()));
+
+                if let Some(report) = &self.stun_report {
+                    ui.separator();
+                    ui.label(format!(
+                        "Public: {} ({:?})",
+                        report.public_addr, report.mapping
+                    ));
+                }
+                if let Some(nat) = &self.nat_report {
+                    ui.separator();
+                    ui.label(format!("NAT: {}", nat.nat_type));
+                }
+                if let (Some(last), Some(avg), Some(max)) =
+                    (self.rtt.last, self.rtt.avg, self.rtt.max)
+                {
+                    let millis = |rtt: std::time::Duration| rtt.as_secs_f64() * 1000.0;
+                    ui.separator();
+                    ui.label(format!(
+                        "RTT: {:.0} ms (avg {:.0}, max {:.0})",
+                        millis(last),
+                        millis(avg),
+                        millis(max)
+                    ));
+                }
             });
         });
 
@@ -211,6 +372,22 @@ impl eframe::App for NatClientApp {
                     "Server: {}:{}",
                     self.config.server.addr, self.config.server.port
                 ));
+
+                let profiles = self.config.profile_names();
+                if !profiles.is_empty() {
+                    let current = self.config.active_profile.clone().unwrap_or_default();
+                    let mut selected = current.clone();
+                    egui::ComboBox::from_label("Profile")
+                        .selected_text(&selected)
+                        .show_ui(ui, |ui| {
+                            for name in &profiles {
+                                ui.selectable_value(&mut selected, name.clone(), name);
+                            }
+                        });
+                    if selected != current {
+                        self.switch_profile(&selected);
+                    }
+                }
             });
 
             ui.separator();
@@ -218,6 +395,28 @@ impl eframe::App for NatClientApp {
             // Tunnels section
             ui.heading("Tunnels");
 
+            ui.horizontal(|ui| {
+                ui.label("Share for (min):");
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.share_form.ttl_minutes)
+                        .desired_width(50.0),
+                );
+                ui.label("Password:");
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.share_form.password)
+                        .password(true)
+                        .hint_text("none"),
+                );
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Basic Auth:");
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.new_tunnel_form.http_auth)
+                        .hint_text("user:password"),
+                );
+            });
+
             // Tunnel list
             egui::ScrollArea::vertical().show(ui, |ui| {
                 if self.tunnels.is_empty() {
@@ -227,12 +426,50 @@ impl eframe::App for NatClientApp {
                         ui.horizontal(|ui| {
                             ui.label(tunnel.name.as_ref().unwrap_or(&tunnel.id.to_string()));
                             ui.label(format!(
-                                "{}:{} -> {}:{}",
-                                tunnel.remote_port,
+                                "{}:{} -> {}:{}:{}",
+                                tunnel.remote_ports(),
                                 tunnel.protocol,
-                                tunnel.local_port,
+                                tunnel.local_host,
+                                tunnel.local_ports(),
                                 tunnel.protocol
                             ));
+                            if let Some(domain) = &tunnel.domain {
+                                ui.label(format!("http://{}", domain));
+                            }
+                            ui.label(format!("via {}", tunnel.mode));
+                            ui.label(format!("{} connections", tunnel.active_connections));
+                            if let Some(expires_at) = tunnel.expires_at {
+                                let left = (expires_at - Utc::now()).num_seconds().max(0);
+                                ui.label(format!("expires in {}", format_duration(left as u64)));
+                            }
+
+                            // Shares go through the server
+                            let ttl_minutes = self.share_form.ttl_minutes.trim().parse::<u64>();
+                            if tunnel.mode == TunnelMode::Server
+                                && tunnel.port_count == 1
+                                && ui
+                                    .add_enabled(ttl_minutes.is_ok(), egui::Button::new("Share"))
+                                    .clicked()
+                            {
+                                if let (Some(client), Ok(minutes)) = (&self.client, ttl_minutes) {
+                                    let client = client.clone();
+                                    let tunnel_id = tunnel.id.to_string();
+                                    let password = Some(self.share_form.password.clone())
+                                        .filter(|password| !password.is_empty());
+                                    tokio::spawn(async move {
+                                        if let Err(e) = client
+                                            .share_tunnel(
+                                                &tunnel_id,
+                                                minutes.saturating_mul(60),
+                                                password,
+                                            )
+                                            .await
+                                        {
+                                            tracing::error!("Failed to share tunnel: {}", e);
+                                        }
+                                    });
+                                }
+                            }
 
                             if ui.button("Close").clicked() {
                                 if let Some(client) = &self.client {
@@ -249,6 +486,43 @@ impl eframe::App for NatClientApp {
                         ui.separator();
                     }
                 }
+
+                for share in &self.shares {
+                    ui.horizontal(|ui| {
+                        let tunnel = self
+                            .tunnels
+                            .iter()
+                            .find(|t| t.id == share.tunnel_id)
+                            .and_then(|t| t.name.clone())
+                            .unwrap_or_else(|| share.tunnel_id.to_string());
+                        ui.label(format!("Shared {} on {}", tunnel, share.port));
+                        if share.password_protected {
+                            ui.label("with password");
+                        }
+                        let left = (share.expires_at - Utc::now()).num_seconds().max(0);
+                        ui.label(format!("expires in {}", format_duration(left as u64)));
+
+                        if ui.button("Stop Sharing").clicked() {
+                            if let Some(client) = &self.client {
+                                let client = client.clone();
+                                let share_id = share.id;
+                                tokio::spawn(async move {
+                                    if let Err(e) = client.close_share(share_id).await {
+                                        tracing::error!("Failed to close share: {}", e);
+                                    }
+                                });
+                            }
+                        }
+                    });
+                }
+
+                for schedule in &self.schedules {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("Scheduled {}", schedule.name));
+                        ui.label(schedule.describe());
+                        ui.label(schedule.windows.join(", "));
+                    });
+                }
             });
 
             ui.separator();
@@ -261,6 +535,14 @@ impl eframe::App for NatClientApp {
                 ui.text_edit_singleline(&mut self.new_tunnel_form.name);
             });
 
+            ui.horizontal(|ui| {
+                ui.label("Local Host:");
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.new_tunnel_form.local_host)
+                        .hint_text("127.0.0.1"),
+                );
+            });
+
             ui.horizontal(|ui| {
                 ui.label("Local Port:");
                 ui.text_edit_singleline(&mut self.new_tunnel_form.local_port);
@@ -271,6 +553,21 @@ impl eframe::App for NatClientApp {
                 ui.text_edit_singleline(&mut self.new_tunnel_form.remote_port);
             });
 
+            ui.horizontal(|ui| {
+                ui.label("Expires After (min):");
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.new_tunnel_form.ttl_minutes)
+                        .hint_text("never"),
+                );
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Domain:");
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.new_tunnel_form.domain).hint_text("none"),
+                );
+            });
+
             ui.horizontal(|ui| {
                 ui.label("Protocol:");
                 ui.radio_value(
@@ -301,12 +598,47 @@ impl eframe::App for NatClientApp {
                         Some(self.new_tunnel_form.name.clone())
                     };
 
+                    let local_host = if self.new_tunnel_form.local_host.trim().is_empty() {
+                        default_local_host()
+                    } else {
+                        self.new_tunnel_form.local_host.trim().to_string()
+                    };
+
+                    let ttl_secs = self
+                        .new_tunnel_form
+                        .ttl_minutes
+                        .trim()
+                        .parse::<u64>()
+                        .ok()
+                        .map(|minutes| minutes.saturating_mul(60));
+
+                    let domain = Some(self.new_tunnel_form.domain.trim().to_string())
+                        .filter(|domain| !domain.is_empty());
+
+                    let http_auth = self.new_tunnel_form.http_auth.trim().split_once(':').map(
+                        |(username, password)| HttpAuth {
+                            username: Some(username.to_string()),
+                            password: Some(password.to_string()),
+                            bearer_token: None,
+                        },
+                    );
+
                     let client = client.clone();
                     let protocol = self.new_tunnel_form.protocol;
 
                     tokio::spawn(async move {
                         if let Err(e) = client
-                            .create_tunnel(local_port, remote_port, protocol, name)
+                            .create_tunnel(
+                                local_host,
+                                local_port,
+                                remote_port,
+                                1,
+                                protocol,
+                                name,
+                                ttl_secs,
+                                domain,
+                                http_auth,
+                            )
                             .await
                         {
                             tracing::error!("Failed to create tunnel: {}", e);
@@ -320,7 +652,7 @@ impl eframe::App for NatClientApp {
         });
 
         // Settings window
-        let mut close_settings = false;
+        let mut save_requested = false;
         if self.settings_window {
             egui::Window::new("Settings")
                 .open(&mut self.settings_window)
@@ -354,19 +686,29 @@ impl eframe::App for NatClientApp {
                     ui.checkbox(&mut self.config.server.tls_verify, "Verify TLS Certificate");
 
                     if ui.button("Save").clicked() {
-                        if let Err(e) = save_config(&self.config, "client.toml") {
-                            tracing::error!("Failed to save config: {}", e);
-                        }
-                        close_settings = true;
+                        save_requested = true;
                     }
                 });
         }
 
-        if close_settings {
+        if save_requested {
+            if let Err(e) = self.save_settings() {
+                tracing::error!("Failed to save config: {}", e);
+            }
             self.settings_window = false;
         }
 
         // About window
+        if self.motd_window {
+            if let Some(motd) = &self.motd {
+                egui::Window::new("Message from the server")
+                    .open(&mut self.motd_window)
+                    .show(ctx, |ui| {
+                        ui.label(motd);
+                    });
+            }
+        }
+
         if self.about_window {
             egui::Window::new("About")
                 .open(&mut self.about_window)
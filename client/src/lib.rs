@@ -0,0 +1,35 @@
+//! The NAT traversal client. `nat-client` is the command line and GUI
+//! around it; the library lets tests and embedders run a client in their
+//! own process.
+
+pub mod alert;
+pub mod capture;
+pub mod check;
+pub mod config;
+pub mod connection;
+pub mod control;
+pub mod core;
+pub mod credentials;
+pub mod diagnose;
+pub mod e2e;
+pub mod expose;
+pub mod forwarder;
+#[cfg(feature = "gui")]
+pub mod gui;
+pub mod p2p;
+pub mod portmap;
+pub mod provision;
+pub mod remote_log;
+pub mod resync;
+pub mod schedule;
+pub mod share;
+pub mod speedtest;
+pub mod ssh;
+pub mod status;
+pub mod stdio;
+#[cfg(feature = "test-util")]
+pub mod testing;
+pub mod transport;
+pub mod vpn;
+pub mod wake;
+pub mod wireguard;
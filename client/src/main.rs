@@ -1,19 +1,172 @@
-mod config;
-mod connection;
-mod core;
-#[cfg(feature = "gui")]
-mod gui;
-
 use clap::Parser;
 use config::*;
 #[cfg(feature = "gui")]
 use gui::NatClientApp;
+#[cfg(feature = "gui")]
+use nat_traversal_client::gui;
+use nat_traversal_client::{
+    check, config, control, core, diagnose, expose, speedtest, status, stdio,
+};
+use nat_traversal_common::config::set_portable;
+use nat_traversal_platform::{
+    notify,
+    service::{run_service_action, ServiceAction, ServiceConfig},
+};
+use std::future::Future;
 use tracing::{error, info};
 
-#[tokio::main]
-async fn main() {
+fn main() {
     let args = Args::parse();
 
+    if args.portable {
+        set_portable(true);
+    }
+
+    if let Some(Command::Service { name, action }) = &args.command {
+        tracing_subscriber::fmt().with_target(false).init();
+        if let Err(e) = run_service_command(*action, name, &args) {
+            eprintln!("Service command failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Diagnose) = &args.command {
+        if let Err(e) = run_diagnose(&args) {
+            eprintln!("Diagnostics failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Speedtest { size_mb }) = &args.command {
+        if let Err(e) = run_speedtest(&args, *size_mb) {
+            eprintln!("Speed test failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::CheckConfig) = &args.command {
+        if let Err(e) = run_check_config(&args) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Expose {
+        port,
+        protocol,
+        ttl,
+    }) = &args.command
+    {
+        if let Err(e) = run_expose(&args, *port, *protocol, *ttl) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Stdio { port, visitor }) = &args.command {
+        if let Err(e) = run_stdio(&args, *port, visitor.as_deref()) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Status { json }) = &args.command {
+        if let Err(e) = run_status(&args, *json) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::RotateToken) = &args.command {
+        if let Err(e) = run_rotate_token(&args) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Share {
+        tunnel,
+        ttl,
+        password,
+    }) = &args.command
+    {
+        if let Err(e) = run_share(&args, tunnel, *ttl, password.clone()) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Unshare { share_id }) = &args.command {
+        if let Err(e) = run_unshare(&args, *share_id) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Wake { peer, target }) = &args.command {
+        if let Err(e) = run_wake(&args, peer, target) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::CheckTunnels) = &args.command {
+        if let Err(e) = run_check_tunnels(&args) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Requests { json }) = &args.command {
+        if let Err(e) = run_requests(&args, *json) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Replay { id, edit }) = &args.command {
+        if let Err(e) = run_replay(&args, *id, *edit) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::DomainChallenge { domain }) = &args.command {
+        if let Err(e) = run_domain_challenge(&args, domain) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::E2eKeygen) = &args.command {
+        match nat_traversal_common::noise::generate_keypair() {
+            Ok((private, public)) => {
+                println!("Private key: {}", private);
+                println!("Public key:  {}", public);
+            }
+            Err(e) => {
+                eprintln!("Failed to generate key pair: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     // Generate config if requested
     if args.generate_config {
         if let Err(e) = generate_default_config() {
@@ -23,6 +176,38 @@ async fn main() {
         return;
     }
 
+    // Hand control to the service control dispatcher when started by the SCM
+    #[cfg(windows)]
+    if args.service {
+        let result =
+            nat_traversal_platform::windows::run_service_dispatcher("nat-client", move |stop| {
+                let runtime = tokio::runtime::Runtime::new()?;
+                runtime.block_on(run(args, async move {
+                    let _ = tokio::task::spawn_blocking(move || stop.recv()).await;
+                }));
+                Ok(())
+            });
+        if let Err(e) = result {
+            eprintln!("Failed to start service dispatcher: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Failed to start runtime: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    runtime.block_on(run(args, async {
+        let _ = tokio::signal::ctrl_c().await;
+    }));
+}
+
+async fn run(args: Args, shutdown: impl Future<Output = ()>) {
     // Load configuration
     let config = match load_client_config(&args) {
         Ok(config) => config,
@@ -32,16 +217,22 @@ async fn main() {
         }
     };
 
-    // Setup logging
-    if let Err(e) = setup_logging(&config) {
-        eprintln!("Failed to setup logging: {}", e);
-        std::process::exit(1);
-    }
+    // Setup logging; the guard keeps telemetry export running
+    let _telemetry = match setup_logging(&config) {
+        Ok(telemetry) => telemetry,
+        Err(e) => {
+            eprintln!("Failed to setup logging: {}", e);
+            std::process::exit(1);
+        }
+    };
 
     info!("Starting NAT Traversal Client");
+    if let Some(profile) = &config.active_profile {
+        info!("Using profile '{}'", profile);
+    }
 
     #[cfg(feature = "gui")]
-    let should_use_gui = config.gui.enabled && !args.no_gui;
+    let should_use_gui = config.gui.enabled && !args.no_gui && !args.service;
     #[cfg(not(feature = "gui"))]
     let should_use_gui = false;
 
@@ -77,8 +268,12 @@ async fn main() {
         // Run CLI application
         info!("Running in CLI mode");
 
+        let control = config
+            .control
+            .enabled
+            .then(|| control::socket_path(&config));
         let client = match core::NatClient::new(config).await {
-            Ok(client) => client,
+            Ok(client) => std::sync::Arc::new(client),
             Err(e) => {
                 error!("Failed to create client: {}", e);
                 std::process::exit(1);
@@ -90,14 +285,132 @@ async fn main() {
             std::process::exit(1);
         }
 
-        // Wait for Ctrl+C
-        tokio::signal::ctrl_c()
-            .await
-            .expect("Failed to listen for ctrl+c");
+        match control {
+            Some(Ok(path)) => {
+                tokio::spawn(control::serve(client.clone(), path));
+            }
+            Some(Err(e)) => error!("No control socket: {}", e),
+            None => {}
+        }
+
+        notify::notify_ready();
+        if let Some(interval) = notify::watchdog_interval() {
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval / 2);
+                loop {
+                    ticker.tick().await;
+                    notify::notify_watchdog();
+                }
+            });
+        }
+
+        // Wait for Ctrl+C or a service stop request
+        shutdown.await;
         info!("Shutting down...");
+        notify::notify_stopping();
 
         if let Err(e) = client.stop().await {
             error!("Error stopping client: {}", e);
         }
     }
 }
+
+fn run_diagnose(args: &Args) -> anyhow::Result<()> {
+    let config = load_client_config(args)?;
+    tokio::runtime::Runtime::new()?.block_on(diagnose::run(&config))
+}
+
+fn run_speedtest(args: &Args, size_mb: u64) -> anyhow::Result<()> {
+    let config = load_client_config(args)?;
+    tokio::runtime::Runtime::new()?.block_on(speedtest::run(&config, size_mb * 1024 * 1024))
+}
+
+fn run_check_config(args: &Args) -> anyhow::Result<()> {
+    tokio::runtime::Runtime::new()?.block_on(check::run(args))
+}
+
+fn run_expose(
+    args: &Args,
+    port: u16,
+    protocol: expose::ExposeProtocol,
+    ttl_secs: Option<u64>,
+) -> anyhow::Result<()> {
+    let config = load_client_config(args)?;
+    let _telemetry = setup_logging(&config)?;
+    tokio::runtime::Runtime::new()?.block_on(expose::run(config, port, protocol, ttl_secs))
+}
+
+fn run_stdio(args: &Args, port: Option<u16>, visitor: Option<&str>) -> anyhow::Result<()> {
+    let config = load_client_config(args)?;
+    tokio::runtime::Runtime::new()?.block_on(stdio::run(&config, port, visitor))
+}
+
+fn run_status(args: &Args, json: bool) -> anyhow::Result<()> {
+    let config = load_client_config(args)?;
+    tokio::runtime::Runtime::new()?.block_on(status::run(&config, json))
+}
+
+fn run_rotate_token(args: &Args) -> anyhow::Result<()> {
+    let config = load_client_config(args)?;
+    tokio::runtime::Runtime::new()?.block_on(status::rotate_token(&config))
+}
+
+fn run_share(
+    args: &Args,
+    tunnel: &str,
+    ttl_secs: u64,
+    password: Option<String>,
+) -> anyhow::Result<()> {
+    let config = load_client_config(args)?;
+    tokio::runtime::Runtime::new()?.block_on(status::share(&config, tunnel, ttl_secs, password))
+}
+
+fn run_unshare(args: &Args, share_id: uuid::Uuid) -> anyhow::Result<()> {
+    let config = load_client_config(args)?;
+    tokio::runtime::Runtime::new()?.block_on(status::unshare(&config, share_id))
+}
+
+fn run_wake(args: &Args, peer: &str, target: &str) -> anyhow::Result<()> {
+    let config = load_client_config(args)?;
+    tokio::runtime::Runtime::new()?.block_on(status::wake(&config, peer, target))
+}
+
+fn run_check_tunnels(args: &Args) -> anyhow::Result<()> {
+    let config = load_client_config(args)?;
+    tokio::runtime::Runtime::new()?.block_on(status::check_tunnels(&config))
+}
+
+fn run_requests(args: &Args, json: bool) -> anyhow::Result<()> {
+    let config = load_client_config(args)?;
+    tokio::runtime::Runtime::new()?.block_on(status::requests(&config, json))
+}
+
+fn run_replay(args: &Args, id: u64, edit: bool) -> anyhow::Result<()> {
+    let config = load_client_config(args)?;
+    tokio::runtime::Runtime::new()?.block_on(status::replay(&config, id, edit))
+}
+
+fn run_domain_challenge(args: &Args, domain: &str) -> anyhow::Result<()> {
+    use nat_traversal_common::domain;
+
+    let config = load_client_config(args)?;
+    let name = domain::normalize(domain)
+        .ok_or_else(|| anyhow::anyhow!("'{}' is not a valid domain", domain))?;
+    println!("Add this TXT record before creating the tunnel:");
+    println!("  Name:  {}", domain::challenge_name(&name));
+    println!(
+        "  Value: {}",
+        domain::challenge_value(&config.server.client_id, &name)
+    );
+    Ok(())
+}
+
+fn run_service_command(action: ServiceAction, name: &str, args: &Args) -> anyhow::Result<()> {
+    let config = ServiceConfig::for_current_exe(
+        name,
+        "NAT Traversal Client",
+        "NAT traversal tunnel client",
+        service_arguments(args)?,
+    )?;
+    run_service_action(action, &config)
+}
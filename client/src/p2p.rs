@@ -0,0 +1,566 @@
+use crate::connection::ServerConnection;
+use crate::portmap::{self, PortMapping};
+use nat_traversal_common::{
+    config::{ClientConfig, PublishedService, ServiceSubscription},
+    ice,
+    protocol::{Candidate, CandidateKind, Message, TunnelProtocol},
+    secure::{self, KeyPair, SecureStream, SessionSecret, STREAM_SALT_LEN},
+    stun::StunReport,
+};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, oneshot, RwLock};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+const HELLO: &[u8] = b"nat-traversal hello";
+const SELECT: &[u8] = b"select";
+/// How long a dialed-in stream may wait for its session to be announced
+/// through the server, which usually lags the direct connection
+const CLAIM_GRACE: Duration = Duration::from_secs(3);
+
+type PeerStream = SecureStream<TcpStream>;
+/// Router mapping of the peer listener and the task keeping it alive
+type ListenerMapping = (Arc<RwLock<PortMapping>>, JoinHandle<()>);
+
+/// Session waiting for direct streams to be established
+struct PendingSession {
+    secret: SessionSecret,
+    offerer: bool,
+    streams: mpsc::UnboundedSender<PeerStream>,
+}
+
+/// Subscription waiting for the publisher's answer
+struct AwaitingAnswer {
+    keys: KeyPair,
+    secret: String,
+    streams: mpsc::UnboundedSender<PeerStream>,
+    answer: oneshot::Sender<Vec<Candidate>>,
+}
+
+/// Direct client-to-client sessions for published and subscribed services.
+///
+/// The server only relays candidates and public keys; payload travels over
+/// a TCP stream one client dials to the other, encrypted with keys only the
+/// two clients can derive.
+pub struct PeerSessions {
+    config: ClientConfig,
+    connection: Arc<ServerConnection>,
+    stun_report: Arc<RwLock<Option<StunReport>>>,
+    candidates: RwLock<Vec<Candidate>>,
+    pending: Arc<RwLock<HashMap<Uuid, PendingSession>>>,
+    awaiting: RwLock<HashMap<Uuid, AwaitingAnswer>>,
+    mapping: RwLock<Option<ListenerMapping>>,
+}
+
+impl PeerSessions {
+    pub fn new(
+        config: ClientConfig,
+        connection: Arc<ServerConnection>,
+        stun_report: Arc<RwLock<Option<StunReport>>>,
+    ) -> Self {
+        Self {
+            config,
+            connection,
+            stun_report,
+            candidates: RwLock::new(Vec::new()),
+            pending: Arc::new(RwLock::new(HashMap::new())),
+            awaiting: RwLock::new(HashMap::new()),
+            mapping: RwLock::new(None),
+        }
+    }
+
+    /// Whether any direct service is configured
+    pub fn is_configured(&self) -> bool {
+        !self.config.p2p.services.is_empty() || !self.config.p2p.subscriptions.is_empty()
+    }
+
+    /// Open the peer listener and the local subscription ports, and start
+    /// answering the server's introductions
+    pub async fn start(self: &Arc<Self>) -> anyhow::Result<()> {
+        let p2p = &self.config.p2p;
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, p2p.listen_port)).await?;
+        let port = listener.local_addr()?.port();
+        self.gather_candidates(port).await;
+        info!("Listening for direct peer sessions on port {}", port);
+
+        let sessions = self.clone();
+        tokio::spawn(async move { sessions.accept_peers(listener).await });
+
+        let signaling = self.connection.subscribe_signaling().await;
+        let sessions = self.clone();
+        tokio::spawn(async move { sessions.handle_signaling(signaling).await });
+
+        for subscription in &p2p.subscriptions {
+            let listener =
+                TcpListener::bind((subscription.bind_host.as_str(), subscription.bind_port))
+                    .await?;
+            info!(
+                "Service {} available on {}",
+                subscription.name,
+                listener.local_addr()?
+            );
+            let sessions = self.clone();
+            let subscription = subscription.clone();
+            tokio::spawn(async move { sessions.accept_local(listener, subscription).await });
+        }
+        Ok(())
+    }
+
+    /// Remove the router mapping of the peer listener, if any
+    pub async fn stop(&self) {
+        if let Some((mapping, renew_task)) = self.mapping.write().await.take() {
+            renew_task.abort();
+            let timeout = Duration::from_millis(self.config.port_mapping.timeout_ms);
+            if let Err(e) = mapping.read().await.delete(timeout).await {
+                warn!("Failed to remove peer listener port mapping: {}", e);
+            }
+        }
+    }
+
+    async fn gather_candidates(&self, port: u16) {
+        let mut candidates = Vec::new();
+
+        match local_ip(&self.config).await {
+            Some(ip) => candidates.push(Candidate::new(
+                CandidateKind::Host,
+                TunnelProtocol::Tcp,
+                SocketAddr::new(ip, port),
+                u16::MAX,
+            )),
+            None => warn!("Could not determine a local address for direct sessions"),
+        }
+
+        if self.config.p2p.port_mapping {
+            match portmap::map_port(&self.config.port_mapping, TunnelProtocol::Tcp, port, port)
+                .await
+            {
+                Ok(mapping) => {
+                    if let Some(ip) = mapping.external_ip {
+                        candidates.push(Candidate::new(
+                            CandidateKind::ServerReflexive,
+                            TunnelProtocol::Tcp,
+                            SocketAddr::new(ip, mapping.external_port),
+                            u16::MAX,
+                        ));
+                    }
+                    info!(
+                        "Peer listener mapped to {} via {}",
+                        mapping.public_addr(),
+                        mapping.method
+                    );
+                    let mapping = Arc::new(RwLock::new(mapping));
+                    let renew_task = tokio::spawn(portmap::renew_loop(
+                        mapping.clone(),
+                        self.config.port_mapping.lease_secs,
+                        Duration::from_millis(self.config.port_mapping.timeout_ms),
+                    ));
+                    *self.mapping.write().await = Some((mapping, renew_task));
+                }
+                Err(e) => info!("No port mapping for the peer listener: {}", e),
+            }
+        }
+
+        *self.candidates.write().await = candidates;
+    }
+
+    /// Our candidates, plus a guess at the public address when STUN found
+    /// one; it only works where the NAT keeps ports or forwards this one
+    async fn local_candidates(&self) -> Vec<Candidate> {
+        let mut candidates = self.candidates.read().await.clone();
+        let port = candidates.first().map(|c| c.addr.port());
+        if let (Some(report), Some(port)) = (self.stun_report.read().await.as_ref(), port) {
+            let guess = SocketAddr::new(report.public_addr.ip(), port);
+            if !candidates.iter().any(|c| c.addr == guess) {
+                candidates.push(Candidate::new(
+                    CandidateKind::ServerReflexive,
+                    TunnelProtocol::Tcp,
+                    guess,
+                    0,
+                ));
+            }
+        }
+        candidates
+    }
+
+    async fn handle_signaling(self: Arc<Self>, mut signaling: mpsc::UnboundedReceiver<Message>) {
+        while let Some(message) = signaling.recv().await {
+            match message {
+                Message::CandidateOffer {
+                    session_id,
+                    peer,
+                    candidates,
+                    service: Some(service),
+                    public_key,
+                } => {
+                    if let Err(e) = self
+                        .answer(session_id, peer.clone(), &service, candidates, &public_key)
+                        .await
+                    {
+                        warn!("Rejected session for {} from {}: {}", service, peer, e);
+                    }
+                }
+                Message::CandidateAnswer {
+                    session_id,
+                    candidates,
+                    public_key,
+                    ..
+                } => {
+                    let Some(awaiting) = self.awaiting.write().await.remove(&session_id) else {
+                        debug!("Answer for unknown session {}", session_id);
+                        continue;
+                    };
+                    match awaiting
+                        .keys
+                        .agree(&public_key, session_id, &awaiting.secret)
+                    {
+                        Ok(secret) => {
+                            self.pending.write().await.insert(
+                                session_id,
+                                PendingSession {
+                                    secret,
+                                    offerer: true,
+                                    streams: awaiting.streams,
+                                },
+                            );
+                            let _ = awaiting.answer.send(candidates);
+                        }
+                        Err(e) => warn!("Session {} failed: {}", session_id, e),
+                    }
+                }
+                other => debug!("Ignoring signaling message {:?}", other),
+            }
+        }
+    }
+
+    /// Accept an introduction to one of our published services
+    async fn answer(
+        self: &Arc<Self>,
+        session_id: Uuid,
+        peer: String,
+        service: &str,
+        remote: Vec<Candidate>,
+        public_key: &[u8],
+    ) -> anyhow::Result<()> {
+        let published = self
+            .config
+            .p2p
+            .services
+            .iter()
+            .find(|s| s.name == service)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("service is not published here"))?;
+
+        let keys = KeyPair::generate()?;
+        let our_public = keys.public.clone();
+        let secret = keys.agree(public_key, session_id, &published.secret)?;
+        let (streams_tx, streams_rx) = mpsc::unbounded_channel();
+        self.pending.write().await.insert(
+            session_id,
+            PendingSession {
+                secret: secret.clone(),
+                offerer: false,
+                streams: streams_tx.clone(),
+            },
+        );
+
+        let local = self.local_candidates().await;
+        self.connection
+            .send_candidates(session_id, peer.clone(), local.clone(), our_public, true)
+            .await?;
+        debug!(
+            "Answered session {} from {} for {}",
+            session_id, peer, service
+        );
+
+        let sessions = self.clone();
+        tokio::spawn(async move {
+            dial_candidates(session_id, &secret, false, &local, &remote, &streams_tx);
+            drop(streams_tx);
+            let result = sessions.serve_published(streams_rx, &published).await;
+            sessions.pending.write().await.remove(&session_id);
+            match result {
+                Ok((sent, received)) => info!(
+                    "Direct session {} for {} closed ({} bytes sent, {} received)",
+                    session_id, published.name, sent, received
+                ),
+                Err(e) => warn!(
+                    "Direct session {} for {} failed: {}",
+                    session_id, published.name, e
+                ),
+            }
+        });
+        Ok(())
+    }
+
+    async fn serve_published(
+        &self,
+        mut streams: mpsc::UnboundedReceiver<PeerStream>,
+        service: &PublishedService,
+    ) -> anyhow::Result<(u64, u64)> {
+        let (selected_tx, mut selected_rx) = mpsc::channel::<PeerStream>(1);
+        let wait = async {
+            loop {
+                tokio::select! {
+                    Some(mut stream) = streams.recv() => {
+                        let selected_tx = selected_tx.clone();
+                        tokio::spawn(async move {
+                            if let Ok(Some(frame)) = stream.read_frame().await {
+                                if frame == SELECT {
+                                    let _ = selected_tx.send(stream).await;
+                                }
+                            }
+                        });
+                    }
+                    Some(stream) = selected_rx.recv() => return Ok(stream),
+                    else => return Err(anyhow::anyhow!("no direct connection could be made")),
+                }
+            }
+        };
+        let stream = tokio::time::timeout(self.connect_timeout(), wait)
+            .await
+            .map_err(|_| anyhow::anyhow!("timed out waiting for a direct connection"))??;
+
+        let target = format!("{}:{}", service.local_host, service.local_port);
+        let local = TcpStream::connect(&target).await?;
+        stream.pump(local).await
+    }
+
+    async fn accept_local(
+        self: Arc<Self>,
+        listener: TcpListener,
+        subscription: ServiceSubscription,
+    ) {
+        loop {
+            let (local, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Accept failed for service {}: {}", subscription.name, e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            debug!("Local connection from {} for {}", addr, subscription.name);
+            let sessions = self.clone();
+            let subscription = subscription.clone();
+            tokio::spawn(async move {
+                match sessions.subscribe(&subscription, local).await {
+                    Ok((sent, received)) => info!(
+                        "Direct session for {} closed ({} bytes sent, {} received)",
+                        subscription.name, sent, received
+                    ),
+                    Err(e) => warn!("Direct session for {} failed: {}", subscription.name, e),
+                }
+            });
+        }
+    }
+
+    /// Set up a direct session to a service's publisher for one local
+    /// connection and forward it
+    async fn subscribe(
+        &self,
+        subscription: &ServiceSubscription,
+        local: TcpStream,
+    ) -> anyhow::Result<(u64, u64)> {
+        let session_id = Uuid::new_v4();
+        let keys = KeyPair::generate()?;
+        let our_public = keys.public.clone();
+        let (streams_tx, mut streams_rx) = mpsc::unbounded_channel();
+        let (answer_tx, answer_rx) = oneshot::channel();
+        self.awaiting.write().await.insert(
+            session_id,
+            AwaitingAnswer {
+                keys,
+                secret: subscription.secret.clone(),
+                streams: streams_tx.clone(),
+                answer: answer_tx,
+            },
+        );
+
+        let local_candidates = self.local_candidates().await;
+        let setup = async {
+            self.connection
+                .subscribe_service(
+                    session_id,
+                    subscription.name.clone(),
+                    local_candidates.clone(),
+                    our_public,
+                )
+                .await?;
+            let remote = answer_rx
+                .await
+                .map_err(|_| anyhow::anyhow!("session was dropped"))?;
+
+            let secret = self
+                .pending
+                .read()
+                .await
+                .get(&session_id)
+                .map(|s| s.secret.clone())
+                .ok_or_else(|| anyhow::anyhow!("session was dropped"))?;
+            dial_candidates(
+                session_id,
+                &secret,
+                true,
+                &local_candidates,
+                &remote,
+                &streams_tx,
+            );
+
+            // Whichever stream authenticates first carries the session
+            let mut stream = streams_rx
+                .recv()
+                .await
+                .ok_or_else(|| anyhow::anyhow!("no direct connection could be made"))?;
+            stream.write_frame(SELECT).await?;
+            Ok::<_, anyhow::Error>(stream)
+        };
+        let result = tokio::time::timeout(self.connect_timeout(), setup).await;
+
+        self.awaiting.write().await.remove(&session_id);
+        self.pending.write().await.remove(&session_id);
+        let stream = result.map_err(|_| {
+            anyhow::anyhow!("timed out waiting for the publisher (is it connected?)")
+        })??;
+        stream.pump(local).await
+    }
+
+    async fn accept_peers(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Peer listener accept failed: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            let pending = self.pending.clone();
+            tokio::spawn(async move {
+                if let Err(e) = claim_stream(stream, &pending).await {
+                    debug!("Dropped peer connection from {}: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    fn connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.config.p2p.connect_timeout_ms)
+    }
+}
+
+/// Dial the peer's candidates in pair priority order, handing each stream
+/// that completes the handshake to the session
+fn dial_candidates(
+    session_id: Uuid,
+    secret: &SessionSecret,
+    offerer: bool,
+    local: &[Candidate],
+    remote: &[Candidate],
+    streams: &mpsc::UnboundedSender<PeerStream>,
+) {
+    let mut targets: Vec<SocketAddr> = Vec::new();
+    for (_, candidate) in ice::candidate_pairs(local, remote, offerer) {
+        if !targets.contains(&candidate.addr) {
+            targets.push(candidate.addr);
+        }
+    }
+
+    for (i, addr) in targets.into_iter().enumerate() {
+        let secret = secret.clone();
+        let streams = streams.clone();
+        tokio::spawn(async move {
+            // Stagger attempts so better paths get a head start
+            tokio::time::sleep(Duration::from_millis(100 * i as u64)).await;
+            match dial(addr, session_id, &secret, offerer).await {
+                Ok(stream) => {
+                    debug!("Direct path to {} established for {}", addr, session_id);
+                    let _ = streams.send(stream);
+                }
+                Err(e) => debug!("Direct path to {} failed: {}", addr, e),
+            }
+        });
+    }
+}
+
+async fn dial(
+    addr: SocketAddr,
+    session_id: Uuid,
+    secret: &SessionSecret,
+    offerer: bool,
+) -> anyhow::Result<PeerStream> {
+    let mut stream = tokio::time::timeout(Duration::from_secs(5), TcpStream::connect(addr))
+        .await
+        .map_err(|_| anyhow::anyhow!("connect timed out"))??;
+    let salt = secure::stream_salt()?;
+    let mut claim = session_id.as_bytes().to_vec();
+    claim.extend_from_slice(&salt);
+    stream.write_all(&claim).await?;
+
+    let mut stream = secret.secure(stream, &salt, offerer)?;
+    handshake(&mut stream).await?;
+    Ok(stream)
+}
+
+/// Match a dialed-in stream to its session by the claim the dialer sends
+async fn claim_stream(
+    mut stream: TcpStream,
+    pending: &RwLock<HashMap<Uuid, PendingSession>>,
+) -> anyhow::Result<()> {
+    let mut claim = [0u8; 16 + STREAM_SALT_LEN];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut claim))
+        .await
+        .map_err(|_| anyhow::anyhow!("no session claim"))??;
+    let session_id = Uuid::from_slice(&claim[..16])?;
+    let mut salt = [0u8; STREAM_SALT_LEN];
+    salt.copy_from_slice(&claim[16..]);
+
+    let deadline = tokio::time::Instant::now() + CLAIM_GRACE;
+    let (secret, offerer, streams) = loop {
+        if let Some(session) = pending.read().await.get(&session_id) {
+            break (
+                session.secret.clone(),
+                session.offerer,
+                session.streams.clone(),
+            );
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Err(anyhow::anyhow!("unknown session {}", session_id));
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+
+    let mut stream = secret.secure(stream, &salt, offerer)?;
+    handshake(&mut stream).await?;
+    let _ = streams.send(stream);
+    Ok(())
+}
+
+/// Both ends prove they derived the same keys before any payload flows
+async fn handshake(stream: &mut PeerStream) -> anyhow::Result<()> {
+    stream.write_frame(HELLO).await?;
+    let hello = tokio::time::timeout(Duration::from_secs(5), stream.read_frame())
+        .await
+        .map_err(|_| anyhow::anyhow!("handshake timed out"))??;
+    match hello {
+        Some(hello) if hello == HELLO => Ok(()),
+        _ => Err(anyhow::anyhow!("handshake failed")),
+    }
+}
+
+/// Address of the interface used to reach the server
+async fn local_ip(config: &ClientConfig) -> Option<IpAddr> {
+    let server = config.server.endpoint(config.server.port);
+    let target = tokio::net::lookup_host(server)
+        .await
+        .ok()?
+        .find(SocketAddr::is_ipv4)?;
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await.ok()?;
+    socket.connect(target).await.ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
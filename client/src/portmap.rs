@@ -0,0 +1,922 @@
+use chrono::Utc;
+use nat_traversal_common::{
+    config::{PortMappingConfig, TunnelConfig},
+    protocol::{TunnelInfo, TunnelMode, TunnelProtocol},
+};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// Port the PCP and NAT-PMP servers listen on
+const PCP_PORT: u16 = 5351;
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+const PCP_VERSION: u8 = 2;
+const PCP_OP_MAP: u8 = 1;
+const NATPMP_VERSION: u8 = 0;
+
+/// A port forwarded by the local router
+#[derive(Debug, Clone)]
+pub struct PortMapping {
+    pub method: TunnelMode,
+    pub protocol: TunnelProtocol,
+    pub internal_port: u16,
+    pub external_port: u16,
+    pub external_ip: Option<IpAddr>,
+    /// Lifetime granted by the router, zero for a permanent UPnP mapping
+    pub lifetime: u32,
+    gateway: Gateway,
+}
+
+#[derive(Debug, Clone)]
+enum Gateway {
+    Pcp {
+        addr: SocketAddr,
+        client_ip: Ipv4Addr,
+        nonce: [u8; 12],
+    },
+    NatPmp {
+        addr: SocketAddr,
+    },
+    Upnp {
+        control: HttpUrl,
+        service_type: String,
+        client_ip: Ipv4Addr,
+    },
+}
+
+/// Ask the router to forward `external_port` (or any free port) to
+/// `internal_port` on this machine, trying PCP, NAT-PMP and UPnP in turn
+pub async fn map_port(
+    config: &PortMappingConfig,
+    protocol: TunnelProtocol,
+    internal_port: u16,
+    external_port: u16,
+) -> anyhow::Result<PortMapping> {
+    let timeout = Duration::from_millis(config.timeout_ms);
+    let gateway = match config.gateway {
+        Some(IpAddr::V4(ip)) => ip,
+        Some(IpAddr::V6(_)) => return Err(anyhow::anyhow!("IPv6 gateways are not supported")),
+        None => default_gateway().await?,
+    };
+    let pcp_addr = SocketAddr::new(IpAddr::V4(gateway), PCP_PORT);
+    let client_ip = local_ip_towards(pcp_addr).await?;
+
+    let mut errors = Vec::new();
+
+    match pcp_map(
+        pcp_addr,
+        client_ip,
+        new_nonce(),
+        protocol,
+        internal_port,
+        external_port,
+        config.lease_secs,
+        timeout,
+    )
+    .await
+    {
+        Ok(mapping) => return Ok(mapping),
+        Err(e) => errors.push(format!("PCP: {}", e)),
+    }
+
+    match natpmp_map(
+        pcp_addr,
+        protocol,
+        internal_port,
+        external_port,
+        config.lease_secs,
+        timeout,
+    )
+    .await
+    {
+        Ok(mapping) => return Ok(mapping),
+        Err(e) => errors.push(format!("NAT-PMP: {}", e)),
+    }
+
+    match upnp_map(
+        client_ip,
+        protocol,
+        internal_port,
+        external_port,
+        config.lease_secs,
+        timeout,
+    )
+    .await
+    {
+        Ok(mapping) => return Ok(mapping),
+        Err(e) => errors.push(format!("UPnP: {}", e)),
+    }
+
+    Err(anyhow::anyhow!(
+        "No port mapping from gateway {} ({})",
+        gateway,
+        errors.join("; ")
+    ))
+}
+
+impl PortMapping {
+    /// Extend the mapping by another lease
+    pub async fn renew(&mut self, lease_secs: u32, timeout: Duration) -> anyhow::Result<()> {
+        let renewed = match &self.gateway {
+            Gateway::Pcp {
+                addr,
+                client_ip,
+                nonce,
+            } => {
+                pcp_map(
+                    *addr,
+                    *client_ip,
+                    *nonce,
+                    self.protocol,
+                    self.internal_port,
+                    self.external_port,
+                    lease_secs,
+                    timeout,
+                )
+                .await?
+            }
+            Gateway::NatPmp { addr } => {
+                natpmp_map(
+                    *addr,
+                    self.protocol,
+                    self.internal_port,
+                    self.external_port,
+                    lease_secs,
+                    timeout,
+                )
+                .await?
+            }
+            Gateway::Upnp {
+                control,
+                service_type,
+                client_ip,
+            } => {
+                upnp_add_mapping(
+                    control,
+                    service_type,
+                    *client_ip,
+                    self.protocol,
+                    self.internal_port,
+                    self.external_port,
+                    lease_secs,
+                    timeout,
+                )
+                .await?;
+                return Ok(());
+            }
+        };
+
+        if renewed.external_port != self.external_port {
+            warn!(
+                "Router moved mapping for port {} from {} to {}",
+                self.internal_port, self.external_port, renewed.external_port
+            );
+        }
+        self.external_port = renewed.external_port;
+        self.external_ip = renewed.external_ip.or(self.external_ip);
+        self.lifetime = renewed.lifetime;
+        Ok(())
+    }
+
+    /// Remove the mapping from the router
+    pub async fn delete(&self, timeout: Duration) -> anyhow::Result<()> {
+        match &self.gateway {
+            Gateway::Pcp {
+                addr,
+                client_ip,
+                nonce,
+            } => {
+                // A MAP request with a zero lifetime deletes the mapping
+                pcp_map(
+                    *addr,
+                    *client_ip,
+                    *nonce,
+                    self.protocol,
+                    self.internal_port,
+                    0,
+                    0,
+                    timeout,
+                )
+                .await?;
+            }
+            Gateway::NatPmp { addr } => {
+                natpmp_map(*addr, self.protocol, self.internal_port, 0, 0, timeout).await?;
+            }
+            Gateway::Upnp {
+                control,
+                service_type,
+                ..
+            } => {
+                let args = format!(
+                    "<NewRemoteHost></NewRemoteHost>\
+                     <NewExternalPort>{}</NewExternalPort>\
+                     <NewProtocol>{}</NewProtocol>",
+                    self.external_port,
+                    protocol_name(self.protocol)
+                );
+                soap_call(control, service_type, "DeletePortMapping", &args, timeout).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Public address of the mapping, when the router reported it
+    pub fn public_addr(&self) -> String {
+        match self.external_ip {
+            Some(ip) => format!("{}:{}", ip, self.external_port),
+            None => format!("*:{}", self.external_port),
+        }
+    }
+}
+
+/// Tunnels served directly through router port mappings
+#[derive(Default)]
+pub struct DirectTunnels {
+    tunnels: Arc<RwLock<HashMap<Uuid, DirectTunnel>>>,
+}
+
+struct DirectTunnel {
+    info: TunnelInfo,
+    mapping: Arc<RwLock<PortMapping>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl DirectTunnels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Listen locally for the tunnel, map a public port to the listener and
+    /// forward accepted connections to the tunnel's target
+    pub async fn open(
+        &self,
+        tunnel: &TunnelConfig,
+        config: &PortMappingConfig,
+    ) -> anyhow::Result<TunnelInfo> {
+        if tunnel.protocol != TunnelProtocol::Tcp {
+            return Err(anyhow::anyhow!(
+                "Port mapping is only supported for TCP tunnels"
+            ));
+        }
+        if tunnel.port_count > 1 {
+            return Err(anyhow::anyhow!(
+                "Port mapping is only supported for single-port tunnels"
+            ));
+        }
+
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        let internal_port = listener.local_addr()?.port();
+        let requested = tunnel.remote_port.unwrap_or(tunnel.local_port);
+        let mapping = map_port(config, tunnel.protocol, internal_port, requested).await?;
+
+        let info = TunnelInfo {
+            id: Uuid::new_v4(),
+            name: Some(tunnel.name.clone()),
+            protocol: tunnel.protocol,
+            local_host: tunnel.local_host.clone(),
+            local_port: tunnel.local_port,
+            remote_port: mapping.external_port,
+            created_at: Utc::now(),
+            bytes_sent: 0,
+            bytes_received: 0,
+            active_connections: 0,
+            mode: mapping.method,
+            port_count: 1,
+            expires_at: None,
+            domain: None,
+            tls_passthrough: false,
+            udp: None,
+        };
+        info!(
+            "Tunnel {} reachable at {} via {} -> {}:{}",
+            tunnel.name,
+            mapping.public_addr(),
+            mapping.method,
+            tunnel.local_host,
+            tunnel.local_port
+        );
+
+        let target = format!("{}:{}", tunnel.local_host, tunnel.local_port);
+        let accept_task = tokio::spawn(accept_loop(listener, target));
+
+        let mapping = Arc::new(RwLock::new(mapping));
+        let renew_task = tokio::spawn(renew_loop(
+            mapping.clone(),
+            config.lease_secs,
+            Duration::from_millis(config.timeout_ms),
+        ));
+
+        self.tunnels.write().await.insert(
+            info.id,
+            DirectTunnel {
+                info: info.clone(),
+                mapping,
+                tasks: vec![accept_task, renew_task],
+            },
+        );
+        Ok(info)
+    }
+
+    /// Stop forwarding and remove the router mapping. Returns false if the
+    /// tunnel is not a direct one.
+    pub async fn close(&self, tunnel_id: Uuid, timeout: Duration) -> bool {
+        let Some(tunnel) = self.tunnels.write().await.remove(&tunnel_id) else {
+            return false;
+        };
+        for task in &tunnel.tasks {
+            task.abort();
+        }
+        let mapping = tunnel.mapping.read().await;
+        if let Err(e) = mapping.delete(timeout).await {
+            warn!(
+                "Failed to remove port mapping {} for tunnel {}: {}",
+                mapping.external_port, tunnel_id, e
+            );
+        }
+        true
+    }
+
+    pub async fn close_all(&self, timeout: Duration) {
+        let ids: Vec<Uuid> = self.tunnels.read().await.keys().copied().collect();
+        for id in ids {
+            self.close(id, timeout).await;
+        }
+    }
+
+    pub async fn list(&self) -> Vec<TunnelInfo> {
+        let tunnels = self.tunnels.read().await;
+        let mut list = Vec::with_capacity(tunnels.len());
+        for tunnel in tunnels.values() {
+            let mut info = tunnel.info.clone();
+            info.remote_port = tunnel.mapping.read().await.external_port;
+            list.push(info);
+        }
+        list
+    }
+}
+
+async fn accept_loop(listener: TcpListener, target: String) {
+    loop {
+        let (mut inbound, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Direct tunnel accept failed: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let target = target.clone();
+        tokio::spawn(async move {
+            match TcpStream::connect(&target).await {
+                Ok(mut outbound) => {
+                    debug!("Direct connection from {} forwarded to {}", peer, target);
+                    let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                }
+                Err(e) => warn!("Failed to connect to local service {}: {}", target, e),
+            }
+        });
+    }
+}
+
+/// Renew a mapping at half its lifetime until the task is aborted
+pub async fn renew_loop(mapping: Arc<RwLock<PortMapping>>, lease_secs: u32, timeout: Duration) {
+    loop {
+        let lifetime = mapping.read().await.lifetime;
+        if lifetime == 0 {
+            // Permanent UPnP mapping, nothing to renew
+            return;
+        }
+        tokio::time::sleep(Duration::from_secs(u64::from(lifetime / 2).max(30))).await;
+
+        let mut mapping = mapping.write().await;
+        if let Err(e) = mapping.renew(lease_secs, timeout).await {
+            warn!(
+                "Failed to renew port mapping {}: {}",
+                mapping.external_port, e
+            );
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn pcp_map(
+    server: SocketAddr,
+    client_ip: Ipv4Addr,
+    nonce: [u8; 12],
+    protocol: TunnelProtocol,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: u32,
+    timeout: Duration,
+) -> anyhow::Result<PortMapping> {
+    let mut request = Vec::with_capacity(60);
+    request.extend_from_slice(&[PCP_VERSION, PCP_OP_MAP, 0, 0]);
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    request.extend_from_slice(&client_ip.to_ipv6_mapped().octets());
+    request.extend_from_slice(&nonce);
+    request.extend_from_slice(&[protocol_number(protocol), 0, 0, 0]);
+    request.extend_from_slice(&internal_port.to_be_bytes());
+    request.extend_from_slice(&external_port.to_be_bytes());
+    request.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+
+    let response = udp_exchange(server, &request, timeout, |r| {
+        r.len() >= 60 && r[1] == PCP_OP_MAP | 0x80 && r[24..36] == nonce
+    })
+    .await?;
+
+    if response[3] != 0 {
+        return Err(anyhow::anyhow!("result code {}", response[3]));
+    }
+    let lifetime = u32::from_be_bytes([response[4], response[5], response[6], response[7]]);
+    let external_port = u16::from_be_bytes([response[42], response[43]]);
+    let mut ip = [0u8; 16];
+    ip.copy_from_slice(&response[44..60]);
+    let external_ip = std::net::Ipv6Addr::from(ip)
+        .to_ipv4_mapped()
+        .map(IpAddr::V4)
+        .unwrap_or(IpAddr::V6(ip.into()));
+
+    Ok(PortMapping {
+        method: TunnelMode::Pcp,
+        protocol,
+        internal_port,
+        external_port,
+        external_ip: Some(external_ip),
+        lifetime,
+        gateway: Gateway::Pcp {
+            addr: server,
+            client_ip,
+            nonce,
+        },
+    })
+}
+
+async fn natpmp_map(
+    server: SocketAddr,
+    protocol: TunnelProtocol,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: u32,
+    timeout: Duration,
+) -> anyhow::Result<PortMapping> {
+    let opcode = match protocol {
+        TunnelProtocol::Udp => 1,
+        TunnelProtocol::Tcp => 2,
+    };
+    let mut request = vec![NATPMP_VERSION, opcode, 0, 0];
+    request.extend_from_slice(&internal_port.to_be_bytes());
+    request.extend_from_slice(&external_port.to_be_bytes());
+    request.extend_from_slice(&lifetime.to_be_bytes());
+
+    let response = udp_exchange(server, &request, timeout, |r| {
+        r.len() >= 16 && r[0] == NATPMP_VERSION && r[1] == opcode | 0x80
+    })
+    .await?;
+
+    let result = u16::from_be_bytes([response[2], response[3]]);
+    if result != 0 {
+        return Err(anyhow::anyhow!("result code {}", result));
+    }
+    let external_port = u16::from_be_bytes([response[10], response[11]]);
+    let lifetime = u32::from_be_bytes([response[12], response[13], response[14], response[15]]);
+
+    // The public address is a separate request; a mapping is usable without it
+    let external_ip = match udp_exchange(server, &[NATPMP_VERSION, 0], timeout, |r| {
+        r.len() >= 12 && r[0] == NATPMP_VERSION && r[1] == 0x80
+    })
+    .await
+    {
+        Ok(r) if r[2..4] == [0, 0] => Some(IpAddr::V4(Ipv4Addr::new(r[8], r[9], r[10], r[11]))),
+        _ => None,
+    };
+
+    Ok(PortMapping {
+        method: TunnelMode::NatPmp,
+        protocol,
+        internal_port,
+        external_port,
+        external_ip,
+        lifetime,
+        gateway: Gateway::NatPmp { addr: server },
+    })
+}
+
+async fn upnp_map(
+    client_ip: Ipv4Addr,
+    protocol: TunnelProtocol,
+    internal_port: u16,
+    external_port: u16,
+    lease_secs: u32,
+    timeout: Duration,
+) -> anyhow::Result<PortMapping> {
+    let location = ssdp_discover(timeout).await?;
+    let (control, service_type) = fetch_control_url(&location, timeout).await?;
+
+    let mut lifetime = lease_secs;
+    if let Err(e) = upnp_add_mapping(
+        &control,
+        &service_type,
+        client_ip,
+        protocol,
+        internal_port,
+        external_port,
+        lifetime,
+        timeout,
+    )
+    .await
+    {
+        // Some IGDv1 routers only accept permanent mappings (error 725)
+        if !e.to_string().contains("725") {
+            return Err(e);
+        }
+        lifetime = 0;
+        upnp_add_mapping(
+            &control,
+            &service_type,
+            client_ip,
+            protocol,
+            internal_port,
+            external_port,
+            0,
+            timeout,
+        )
+        .await?;
+    }
+
+    let external_ip = soap_call(&control, &service_type, "GetExternalIPAddress", "", timeout)
+        .await
+        .ok()
+        .and_then(|body| xml_value(&body, "NewExternalIPAddress"))
+        .and_then(|ip| ip.parse().ok());
+
+    Ok(PortMapping {
+        method: TunnelMode::Upnp,
+        protocol,
+        internal_port,
+        external_port,
+        external_ip,
+        lifetime,
+        gateway: Gateway::Upnp {
+            control,
+            service_type,
+            client_ip,
+        },
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn upnp_add_mapping(
+    control: &HttpUrl,
+    service_type: &str,
+    client_ip: Ipv4Addr,
+    protocol: TunnelProtocol,
+    internal_port: u16,
+    external_port: u16,
+    lease_secs: u32,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let args = format!(
+        "<NewRemoteHost></NewRemoteHost>\
+         <NewExternalPort>{}</NewExternalPort>\
+         <NewProtocol>{}</NewProtocol>\
+         <NewInternalPort>{}</NewInternalPort>\
+         <NewInternalClient>{}</NewInternalClient>\
+         <NewEnabled>1</NewEnabled>\
+         <NewPortMappingDescription>nat-traversal</NewPortMappingDescription>\
+         <NewLeaseDuration>{}</NewLeaseDuration>",
+        external_port,
+        protocol_name(protocol),
+        internal_port,
+        client_ip,
+        lease_secs
+    );
+    soap_call(control, service_type, "AddPortMapping", &args, timeout).await?;
+    Ok(())
+}
+
+/// Find the router's UPnP description URL with an SSDP M-SEARCH
+async fn ssdp_discover(timeout: Duration) -> anyhow::Result<String> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let request = "M-SEARCH * HTTP/1.1\r\n\
+                   HOST: 239.255.255.250:1900\r\n\
+                   ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+                   MAN: \"ssdp:discover\"\r\n\
+                   MX: 2\r\n\r\n";
+    socket.send_to(request.as_bytes(), SSDP_ADDR).await?;
+
+    let mut buffer = [0u8; 2048];
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let (len, _) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer))
+            .await
+            .map_err(|_| anyhow::anyhow!("no Internet Gateway Device answered"))??;
+        let response = String::from_utf8_lossy(&buffer[..len]);
+        if let Some(location) = header_value(&response, "location") {
+            return Ok(location);
+        }
+    }
+}
+
+/// Read the device description and return the WAN connection service's
+/// control URL and type
+async fn fetch_control_url(location: &str, timeout: Duration) -> anyhow::Result<(HttpUrl, String)> {
+    let url = HttpUrl::parse(location)?;
+    let (status, body) = http_request(&url, "GET", &[], "", timeout).await?;
+    if status != 200 {
+        return Err(anyhow::anyhow!(
+            "device description returned HTTP {}",
+            status
+        ));
+    }
+
+    let (control, service_type) = find_wan_service(&body)
+        .ok_or_else(|| anyhow::anyhow!("no WAN connection service in device description"))?;
+    let control = match xml_value(&body, "URLBase") {
+        Some(base) if !control.starts_with("http") => HttpUrl::parse(&base)?.join(&control),
+        _ => url.join(&control),
+    }?;
+    Ok((control, service_type))
+}
+
+fn find_wan_service(description: &str) -> Option<(String, String)> {
+    description.split("<service>").skip(1).find_map(|service| {
+        let service_type = xml_value(service, "serviceType")?;
+        if !service_type.contains("WANIPConnection") && !service_type.contains("WANPPPConnection") {
+            return None;
+        }
+        Some((xml_value(service, "controlURL")?, service_type))
+    })
+}
+
+async fn soap_call(
+    control: &HttpUrl,
+    service_type: &str,
+    action: &str,
+    args: &str,
+    timeout: Duration,
+) -> anyhow::Result<String> {
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{action} xmlns:u=\"{service_type}\">{args}</u:{action}></s:Body>\
+         </s:Envelope>"
+    );
+    let soap_action = format!("\"{}#{}\"", service_type, action);
+    let headers = [
+        ("Content-Type", "text/xml; charset=\"utf-8\""),
+        ("SOAPAction", soap_action.as_str()),
+    ];
+    let (status, response) = http_request(control, "POST", &headers, &body, timeout).await?;
+    if status != 200 {
+        let code = xml_value(&response, "errorCode").unwrap_or_default();
+        let description = xml_value(&response, "errorDescription").unwrap_or_default();
+        return Err(anyhow::anyhow!(
+            "{} failed with HTTP {} (error {} {})",
+            action,
+            status,
+            code,
+            description
+        ));
+    }
+    Ok(response)
+}
+
+/// Plain `http://` URL as used on the LAN by UPnP devices
+#[derive(Debug, Clone, PartialEq)]
+struct HttpUrl {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl HttpUrl {
+    fn parse(url: &str) -> anyhow::Result<Self> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow::anyhow!("unsupported URL '{}'", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse()?),
+            None => (authority, 80),
+        };
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    /// Resolve a possibly relative reference against this URL
+    fn join(&self, reference: &str) -> anyhow::Result<Self> {
+        if reference.starts_with("http://") {
+            return Self::parse(reference);
+        }
+        let path = if reference.starts_with('/') {
+            reference.to_string()
+        } else {
+            let dir = &self.path[..self.path.rfind('/').map_or(0, |i| i + 1)];
+            format!("{}{}", if dir.is_empty() { "/" } else { dir }, reference)
+        };
+        Ok(Self {
+            host: self.host.clone(),
+            port: self.port,
+            path,
+        })
+    }
+}
+
+async fn http_request(
+    url: &HttpUrl,
+    method: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+    timeout: Duration,
+) -> anyhow::Result<(u16, String)> {
+    let exchange = async {
+        let mut stream = TcpStream::connect((url.host.as_str(), url.port)).await?;
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}:{}\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method,
+            url.path,
+            url.host,
+            url.port,
+            body.len()
+        );
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, anyhow::Error>(response)
+    };
+    let response = tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| anyhow::anyhow!("HTTP request to {}:{} timed out", url.host, url.port))??;
+    parse_http_response(&String::from_utf8_lossy(&response))
+}
+
+fn parse_http_response(response: &str) -> anyhow::Result<(u16, String)> {
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| anyhow::anyhow!("malformed HTTP response"))?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("malformed HTTP status line"))?;
+    let chunked = header_value(head, "transfer-encoding")
+        .is_some_and(|value| value.eq_ignore_ascii_case("chunked"));
+    let body = if chunked {
+        decode_chunked(body)
+    } else {
+        body.to_string()
+    };
+    Ok((status, body))
+}
+
+fn decode_chunked(mut body: &str) -> String {
+    let mut decoded = String::new();
+    while let Some((size, rest)) = body.split_once("\r\n") {
+        let size = usize::from_str_radix(size.split(';').next().unwrap_or("").trim(), 16);
+        match size {
+            Ok(size) if size > 0 && rest.len() >= size => {
+                decoded.push_str(&rest[..size]);
+                body = rest[size..].trim_start_matches("\r\n");
+            }
+            _ => break,
+        }
+    }
+    decoded
+}
+
+fn header_value(head: &str, name: &str) -> Option<String> {
+    head.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim().to_string())
+    })
+}
+
+/// Text of the first `<tag>` element, ignoring namespace prefixes
+fn xml_value(xml: &str, tag: &str) -> Option<String> {
+    let mut search = xml;
+    while let Some(start) = search.find('<') {
+        let rest = &search[start + 1..];
+        let end = rest.find('>')?;
+        let name = rest[..end].split_whitespace().next().unwrap_or("");
+        let local = name.rsplit(':').next().unwrap_or(name);
+        if local == tag && !name.starts_with('/') {
+            let content = &rest[end + 1..];
+            let close = content.find("</")?;
+            return Some(content[..close].trim().to_string());
+        }
+        search = &rest[end + 1..];
+    }
+    None
+}
+
+async fn udp_exchange(
+    server: SocketAddr,
+    request: &[u8],
+    timeout: Duration,
+    accept: impl Fn(&[u8]) -> bool,
+) -> anyhow::Result<Vec<u8>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(server).await?;
+
+    // Retransmit with a doubling interval as RFC 6886 and RFC 6887 suggest
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut interval = Duration::from_millis(250);
+    let mut buffer = [0u8; 1100];
+    loop {
+        socket.send(request).await?;
+        let retry_at = (tokio::time::Instant::now() + interval).min(deadline);
+        loop {
+            match tokio::time::timeout_at(retry_at, socket.recv(&mut buffer)).await {
+                Ok(Ok(len)) if accept(&buffer[..len]) => return Ok(buffer[..len].to_vec()),
+                Ok(Ok(_)) => continue,
+                // ICMP port unreachable: nothing listens on the gateway
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => break,
+            }
+        }
+        if retry_at >= deadline {
+            return Err(anyhow::anyhow!("no response from {}", server));
+        }
+        interval *= 2;
+    }
+}
+
+/// Default IPv4 gateway, read from the routing table where available and
+/// otherwise guessed as the `.1` address of the local subnet
+async fn default_gateway() -> anyhow::Result<Ipv4Addr> {
+    #[cfg(target_os = "linux")]
+    if let Ok(routes) = std::fs::read_to_string("/proc/net/route") {
+        if let Some(gateway) = parse_proc_route(&routes) {
+            return Ok(gateway);
+        }
+    }
+
+    let local = local_ip_towards(SocketAddr::from(([192, 0, 2, 1], 9))).await?;
+    let [a, b, c, _] = local.octets();
+    Ok(Ipv4Addr::new(a, b, c, 1))
+}
+
+#[cfg(target_os = "linux")]
+fn parse_proc_route(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[1] != "00000000" {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+        // The kernel prints the address in host byte order
+        Some(Ipv4Addr::from(gateway.to_le_bytes()))
+    })
+}
+
+/// Local address the system would use to reach `target`
+async fn local_ip_towards(target: SocketAddr) -> anyhow::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(target).await?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(ip) => Err(anyhow::anyhow!("unexpected local address {}", ip)),
+    }
+}
+
+fn new_nonce() -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&Uuid::new_v4().as_bytes()[..12]);
+    nonce
+}
+
+fn protocol_number(protocol: TunnelProtocol) -> u8 {
+    match protocol {
+        TunnelProtocol::Tcp => 6,
+        TunnelProtocol::Udp => 17,
+    }
+}
+
+fn protocol_name(protocol: TunnelProtocol) -> &'static str {
+    match protocol {
+        TunnelProtocol::Tcp => "TCP",
+        TunnelProtocol::Udp => "UDP",
+    }
+}
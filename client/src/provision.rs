@@ -0,0 +1,125 @@
+//! Tunnels the server's operator defined for this client. The server sends
+//! them at every login; tunnels in the client's own configuration take
+//! precedence over provisioned ones of the same name.
+
+use nat_traversal_common::{
+    config::TunnelConfig,
+    protocol::{Message, TunnelInfo},
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, RwLock};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+type Tunnels = Arc<RwLock<HashMap<Uuid, TunnelInfo>>>;
+
+pub struct Provisioned {
+    /// Names of the configured tunnels
+    local: HashSet<String>,
+    /// Provisioned tunnels by name
+    tunnels: Mutex<HashMap<String, TunnelConfig>>,
+}
+
+impl Provisioned {
+    pub fn new(configured: &[TunnelConfig]) -> Self {
+        Self {
+            local: configured.iter().map(|t| t.name.clone()).collect(),
+            tunnels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Open a provisioned tunnel unless it is already open as defined,
+    /// reopening it when its definition changed
+    pub async fn provision(
+        &self,
+        tunnel: TunnelConfig,
+        open: &Tunnels,
+        message_tx: &mpsc::UnboundedSender<Message>,
+    ) {
+        if self.local.contains(&tunnel.name) {
+            warn!(
+                "Ignoring provisioned tunnel {}, one by that name is configured",
+                tunnel.name
+            );
+            return;
+        }
+        self.tunnels
+            .lock()
+            .unwrap()
+            .insert(tunnel.name.clone(), tunnel.clone());
+
+        if let Some(info) = find(open, &tunnel.name).await {
+            if matches(&tunnel, &info) {
+                return;
+            }
+            info!("Provisioned tunnel {} changed, reopening it", tunnel.name);
+            let _ = message_tx.send(Message::CloseTunnel { tunnel_id: info.id });
+        }
+        let tls_certificate = match tunnel.load_tls_certificate() {
+            Ok(certificate) => certificate,
+            Err(e) => {
+                warn!("Failed to start provisioned tunnel {}: {}", tunnel.name, e);
+                return;
+            }
+        };
+        info!("Opening provisioned tunnel {}", tunnel.name);
+        let _ = message_tx.send(Message::CreateTunnel {
+            local_host: tunnel.local_host,
+            local_port: tunnel.local_port,
+            remote_port: tunnel.remote_port,
+            protocol: tunnel.protocol,
+            name: Some(tunnel.name),
+            port_count: tunnel.port_count,
+            ttl_secs: tunnel.ttl_secs,
+            domain: tunnel.domain,
+            http_auth: tunnel.http_auth,
+            tls_certificate,
+            tls_passthrough: tunnel.tls_passthrough,
+            https_redirect: tunnel.https_redirect,
+            http_headers: tunnel.http_headers,
+            dscp: tunnel.dscp,
+            udp: tunnel.udp,
+        });
+    }
+
+    /// The provisioned tunnel by this name
+    pub fn get(&self, name: &str) -> Option<TunnelConfig> {
+        self.tunnels.lock().unwrap().get(name).cloned()
+    }
+
+    /// Close a tunnel that is no longer provisioned
+    pub async fn unprovision(
+        &self,
+        name: &str,
+        open: &Tunnels,
+        message_tx: &mpsc::UnboundedSender<Message>,
+    ) {
+        if self.tunnels.lock().unwrap().remove(name).is_none() {
+            return;
+        }
+        if let Some(info) = find(open, name).await {
+            info!("Closing tunnel {}, it is no longer provisioned", name);
+            let _ = message_tx.send(Message::CloseTunnel { tunnel_id: info.id });
+        }
+    }
+}
+
+async fn find(open: &Tunnels, name: &str) -> Option<TunnelInfo> {
+    open.read()
+        .await
+        .values()
+        .find(|t| t.name.as_deref() == Some(name))
+        .cloned()
+}
+
+/// Whether the open tunnel still serves what `tunnel` defines
+pub fn matches(tunnel: &TunnelConfig, info: &TunnelInfo) -> bool {
+    info.local_host == tunnel.local_host
+        && info.local_port == tunnel.local_port
+        && info.protocol == tunnel.protocol
+        && info.port_count == tunnel.port_count
+        && tunnel
+            .remote_port
+            .is_none_or(|port| port == info.remote_port)
+}
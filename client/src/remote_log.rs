@@ -0,0 +1,126 @@
+//! The log level and recent log lines, within reach of the server's
+//! operator so supporting a remote user takes no config edits or restarts.
+//! A raised level goes back to the configured one when its time is up.
+
+use nat_traversal_common::telemetry::TelemetryLayer;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tracing::info;
+use tracing_subscriber::{fmt::MakeWriter, layer::Layered, reload, EnvFilter, Registry};
+
+/// Lines kept for the operator to fetch
+const RECENT_LINES: usize = 2000;
+/// Longest the level stays raised
+const MAX_RAISE: Duration = Duration::from_secs(24 * 3600);
+
+/// What the filter sits on in `setup_logging`
+type Base = Layered<Option<TelemetryLayer>, Registry>;
+
+static CONTROL: OnceLock<LogControl> = OnceLock::new();
+
+struct LogControl {
+    filter: reload::Handle<EnvFilter, Base>,
+    /// The filter logging started with
+    default: String,
+    allowed: bool,
+    /// Bumped by each raise, so only the latest one restores the default
+    raises: AtomicU64,
+    recent: Recent,
+}
+
+/// The last lines logged, oldest first
+#[derive(Clone, Default)]
+pub struct Recent(Arc<Mutex<VecDeque<String>>>);
+
+impl Recent {
+    fn last(&self, max_lines: usize) -> Vec<String> {
+        let lines = self.0.lock().unwrap();
+        let skip = lines.len().saturating_sub(max_lines);
+        lines.iter().skip(skip).cloned().collect()
+    }
+}
+
+impl Write for Recent {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut lines = self.0.lock().unwrap();
+        for line in String::from_utf8_lossy(buf).lines() {
+            if lines.len() == RECENT_LINES {
+                lines.pop_front();
+            }
+            lines.push_back(line.to_string());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Recent {
+    type Writer = Recent;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+/// Make `default` the reloadable filter, returned along with where to
+/// write the lines kept for the operator
+pub fn init(default: String, allowed: bool) -> (reload::Layer<EnvFilter, Base>, Recent) {
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(&default));
+    let recent = Recent::default();
+    let _ = CONTROL.set(LogControl {
+        filter: handle,
+        default,
+        allowed,
+        raises: AtomicU64::new(0),
+        recent: recent.clone(),
+    });
+    (filter, recent)
+}
+
+/// Answer the server's `RequestLogs`: raise the level to `level` for
+/// `duration` when given, then return up to `max_lines` recent lines
+pub fn request(
+    level: Option<&str>,
+    duration: Duration,
+    max_lines: usize,
+) -> Result<Vec<String>, String> {
+    let control = CONTROL
+        .get()
+        .ok_or_else(|| "Logging is not set up".to_string())?;
+    if !control.allowed {
+        return Err("Remote access to the log is disabled on this client".to_string());
+    }
+    if let Some(level) = level {
+        control.raise(level, duration.clamp(Duration::from_secs(1), MAX_RAISE))?;
+    }
+    Ok(control.recent.last(max_lines))
+}
+
+impl LogControl {
+    fn raise(&'static self, level: &str, duration: Duration) -> Result<(), String> {
+        let filter = EnvFilter::try_new(level)
+            .map_err(|e| format!("Invalid log level '{}': {}", level, e))?;
+        self.filter.reload(filter).map_err(|e| e.to_string())?;
+        let raise = self.raises.fetch_add(1, Ordering::Relaxed) + 1;
+        info!(
+            "Logging at {} for {} seconds at the server's request",
+            level,
+            duration.as_secs()
+        );
+        tokio::spawn(async move {
+            tokio::time::sleep(duration).await;
+            if self.raises.load(Ordering::Relaxed) == raise
+                && self.filter.reload(EnvFilter::new(&self.default)).is_ok()
+            {
+                info!("Log level back to {}", self.default);
+            }
+        });
+        Ok(())
+    }
+}
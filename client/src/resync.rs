@@ -0,0 +1,100 @@
+//! Checking the tunnels this client believes it has open against the ones
+//! the server holds for it. The two drift apart when a `TunnelCreated` or
+//! `TunnelClosed` is lost around a network blip.
+
+use nat_traversal_common::protocol::{RelayInfo, ShareInfo, TunnelInfo};
+use std::collections::HashMap;
+use tokio::sync::{oneshot, Mutex};
+use uuid::Uuid;
+
+/// What the server holds for this client, as of a `ListTunnels`
+#[derive(Debug, Clone, Default)]
+pub struct ServerTunnels {
+    pub tunnels: Vec<TunnelInfo>,
+    pub shares: Vec<ShareInfo>,
+    pub relays: Vec<RelayInfo>,
+}
+
+#[derive(Default)]
+pub struct TunnelLists {
+    /// Requests still waiting for `TunnelList`
+    pending: Mutex<HashMap<Uuid, oneshot::Sender<ServerTunnels>>>,
+}
+
+impl TunnelLists {
+    /// Register a list request; the receiver gets the server's answer
+    pub async fn expect(&self, request_id: Uuid) -> oneshot::Receiver<ServerTunnels> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().await.insert(request_id, tx);
+        rx
+    }
+
+    /// Forget a request that will not be waited for any more
+    pub async fn abandon(&self, request_id: &Uuid) {
+        self.pending.lock().await.remove(request_id);
+    }
+
+    /// Record the server's answer to a list request
+    pub async fn answered(&self, request_id: Uuid, tunnels: ServerTunnels) {
+        if let Some(tx) = self.pending.lock().await.remove(&request_id) {
+            let _ = tx.send(tunnels);
+        }
+    }
+}
+
+/// Where the client's list of one kind of thing and the server's disagree
+#[derive(Debug, Clone)]
+pub struct Difference<T> {
+    /// Listed by the client, gone from the server
+    pub missing: Vec<T>,
+    /// Held by the server, unknown to the client
+    pub unknown: Vec<T>,
+}
+
+impl<T: Clone> Difference<T> {
+    pub fn between(local: &[T], server: &[T], id: impl Fn(&T) -> Uuid) -> Self {
+        let missing = local
+            .iter()
+            .filter(|item| !server.iter().any(|held| id(held) == id(item)))
+            .cloned()
+            .collect();
+        let unknown = server
+            .iter()
+            .filter(|held| !local.iter().any(|item| id(item) == id(held)))
+            .cloned()
+            .collect();
+        Self { missing, unknown }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.unknown.is_empty()
+    }
+}
+
+/// Where what the client has listed and what the server holds for it
+/// disagree
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    pub tunnels: Difference<TunnelInfo>,
+    pub shares: Difference<ShareInfo>,
+    pub relays: Difference<RelayInfo>,
+}
+
+impl Divergence {
+    pub fn between(
+        tunnels: &[TunnelInfo],
+        shares: &[ShareInfo],
+        relays: &[RelayInfo],
+        server: &ServerTunnels,
+    ) -> Self {
+        Self {
+            tunnels: Difference::between(tunnels, &server.tunnels, |tunnel| tunnel.id),
+            shares: Difference::between(shares, &server.shares, |share| share.id),
+            relays: Difference::between(relays, &server.relays, |relay| relay.id),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tunnels.is_empty() && self.shares.is_empty() && self.relays.is_empty()
+    }
+}
@@ -0,0 +1,60 @@
+//! Opening and closing configured tunnels on their schedules.
+
+use crate::connection::ServerConnection;
+use chrono::{DateTime, Local};
+use nat_traversal_common::{config::ClientConfig, schedule};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often schedules are checked; windows are set in whole minutes
+const CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Where a scheduled tunnel stands, for `nat-client status` and the GUI
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleStatus {
+    pub name: String,
+    /// The windows as configured, e.g. `mon-fri 09:00-18:00`
+    pub windows: Vec<String>,
+    /// Whether the tunnel is in one of its windows now
+    pub active: bool,
+    /// When it next opens or closes
+    pub next_change: Option<DateTime<Local>>,
+}
+
+impl ScheduleStatus {
+    /// `open until Fri 18:00` or `closed until Mon 09:00`
+    pub fn describe(&self) -> String {
+        let state = if self.active { "open" } else { "closed" };
+        match self.next_change {
+            Some(at) => format!("{} until {}", state, at.format("%a %H:%M")),
+            None => state.to_string(),
+        }
+    }
+}
+
+/// The schedule state of every configured tunnel that has one
+pub fn statuses(config: &ClientConfig) -> Vec<ScheduleStatus> {
+    let now = Local::now();
+    config
+        .tunnels
+        .iter()
+        .filter(|tunnel| tunnel.auto_start && !tunnel.schedule.is_empty())
+        .map(|tunnel| ScheduleStatus {
+            name: tunnel.name.clone(),
+            windows: tunnel.schedule.iter().map(ToString::to_string).collect(),
+            active: schedule::is_active(&tunnel.schedule, now.naive_local()),
+            next_change: schedule::next_change(&tunnel.schedule, now.naive_local())
+                .and_then(|at| at.and_local_timezone(Local).earliest()),
+        })
+        .collect()
+}
+
+/// Apply the schedules of configured tunnels until the process exits
+pub async fn run(connection: Arc<ServerConnection>) {
+    let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        connection.apply_schedules().await;
+    }
+}
@@ -0,0 +1,69 @@
+//! Temporary shares of this client's tunnels. The server opens an extra
+//! random public port per share and closes it when the share expires.
+
+use nat_traversal_common::protocol::ShareInfo;
+use std::collections::HashMap;
+use tokio::sync::{oneshot, Mutex, RwLock};
+use uuid::Uuid;
+
+type ShareResult = Result<ShareInfo, String>;
+
+#[derive(Default)]
+pub struct Shares {
+    active: RwLock<HashMap<Uuid, ShareInfo>>,
+    /// Requests still waiting for `ShareCreated`
+    pending: Mutex<HashMap<Uuid, oneshot::Sender<ShareResult>>>,
+}
+
+impl Shares {
+    /// Register a share request; the receiver gets the server's answer
+    pub async fn expect(&self, request_id: Uuid) -> oneshot::Receiver<ShareResult> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().await.insert(request_id, tx);
+        rx
+    }
+
+    /// Forget a request that will not be waited for any more
+    pub async fn abandon(&self, request_id: &Uuid) {
+        self.pending.lock().await.remove(request_id);
+    }
+
+    /// Record the server's answer to a share request
+    pub async fn answered(
+        &self,
+        request_id: Uuid,
+        share: Option<ShareInfo>,
+        error: Option<String>,
+    ) {
+        let result = match share {
+            Some(share) => {
+                self.active.write().await.insert(share.id, share.clone());
+                Ok(share)
+            }
+            None => Err(error.unwrap_or_else(|| "Share refused".to_string())),
+        };
+        if let Some(tx) = self.pending.lock().await.remove(&request_id) {
+            let _ = tx.send(result);
+        }
+    }
+
+    pub async fn closed(&self, share_id: &Uuid) {
+        self.active.write().await.remove(share_id);
+    }
+
+    /// The server closes every share when the connection drops
+    pub async fn clear(&self) {
+        self.active.write().await.clear();
+    }
+
+    /// Take the server's list of shares as the current one
+    pub async fn adopt(&self, shares: Vec<ShareInfo>) {
+        *self.active.write().await = shares.into_iter().map(|share| (share.id, share)).collect();
+    }
+
+    pub async fn list(&self) -> Vec<ShareInfo> {
+        let mut shares: Vec<_> = self.active.read().await.values().cloned().collect();
+        shares.sort_by_key(|share| share.expires_at);
+        shares
+    }
+}
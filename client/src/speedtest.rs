@@ -0,0 +1,224 @@
+//! `nat-client speedtest`: open a throwaway tunnel to a test service on
+//! this machine and have the server push data through it both ways, so
+//! slowness can be pinned on the tunnel path or on the application. The
+//! server side and the wire format of the test connections are in the
+//! server's `speedtest` module.
+
+use crate::connection::{ConnectionState, ServerConnection};
+use crate::diagnose::{probe_config, wait_for_tunnel};
+use nat_traversal_common::{
+    config::ClientConfig,
+    protocol::{default_local_host, SpeedTestReport},
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{oneshot, Mutex};
+use tracing::debug;
+use uuid::Uuid;
+
+type SpeedTestResult = Result<SpeedTestReport, String>;
+
+/// How long the server may take to measure, matching its own limit
+pub const TEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long connecting and opening the tunnel may take
+const SETUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most bytes the server moves each way in one test
+const MAX_BYTES: u64 = 1 << 30;
+
+const CHUNK: usize = 64 * 1024;
+
+#[derive(Default)]
+pub struct SpeedTests {
+    /// Requests still waiting for `SpeedTestResult`
+    pending: Mutex<HashMap<Uuid, oneshot::Sender<SpeedTestResult>>>,
+}
+
+impl SpeedTests {
+    /// Register a speed test; the receiver gets the server's measurements
+    pub async fn expect(&self, request_id: Uuid) -> oneshot::Receiver<SpeedTestResult> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().await.insert(request_id, tx);
+        rx
+    }
+
+    /// Forget a request that will not be waited for any more
+    pub async fn abandon(&self, request_id: &Uuid) {
+        self.pending.lock().await.remove(request_id);
+    }
+
+    /// Record the server's answer to a speed test
+    pub async fn answered(
+        &self,
+        request_id: Uuid,
+        report: Option<SpeedTestReport>,
+        error: Option<String>,
+    ) {
+        if let Some(tx) = self.pending.lock().await.remove(&request_id) {
+            let result = match report {
+                Some(report) => Ok(report),
+                None => Err(error.unwrap_or_else(|| "The speed test failed".to_string())),
+            };
+            let _ = tx.send(result);
+        }
+    }
+}
+
+/// Measure a throwaway tunnel moving `bytes` each way and print the results
+pub async fn run(config: &ClientConfig, bytes: u64) -> anyhow::Result<()> {
+    if bytes == 0 || bytes > MAX_BYTES {
+        return Err(anyhow::anyhow!(
+            "The size must be 1 to {} MB",
+            MAX_BYTES / (1024 * 1024)
+        ));
+    }
+    let local = TcpListener::bind((default_local_host(), 0)).await?;
+    let local_port = local.local_addr()?.port();
+    let service = tokio::spawn(serve(local));
+
+    let mut probe = probe_config(config, local_port);
+    for tunnel in &mut probe.tunnels {
+        tunnel.name = format!("speedtest-{}", Uuid::new_v4().simple());
+        tunnel.ttl_secs = Some((SETUP_TIMEOUT + TEST_TIMEOUT).as_secs() * 2);
+    }
+    let connection = Arc::new(ServerConnection::new(probe).await?);
+    let session = {
+        let connection = connection.clone();
+        tokio::spawn(async move { connection.connect().await })
+    };
+    let result = measure(config, &connection, &session, bytes).await;
+    session.abort();
+    service.abort();
+    result
+}
+
+async fn measure(
+    config: &ClientConfig,
+    connection: &ServerConnection,
+    session: &tokio::task::JoinHandle<nat_traversal_common::error::NatResult<()>>,
+    bytes: u64,
+) -> anyhow::Result<()> {
+    let deadline = tokio::time::Instant::now() + SETUP_TIMEOUT;
+    loop {
+        match connection.get_state().await {
+            ConnectionState::Authenticated => break,
+            ConnectionState::Error(e) => {
+                return Err(anyhow::anyhow!("Authentication failed: {}", e))
+            }
+            _ if session.is_finished() || tokio::time::Instant::now() > deadline => {
+                return Err(anyhow::anyhow!(
+                    "Could not connect to {}; `nat-client diagnose` tells why",
+                    config.server.endpoint(config.server.port)
+                ));
+            }
+            _ => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    }
+    let tunnel = wait_for_tunnel(connection)
+        .await
+        .ok_or_else(|| anyhow::anyhow!("The server did not open the test tunnel"))?;
+
+    println!(
+        "Speed test through {}, {} each way",
+        config.server.endpoint(tunnel.remote_port),
+        format_size(bytes)
+    );
+    let result = connection.speed_test(tunnel.id, bytes).await;
+    let _ = connection.close_tunnel(tunnel.id).await;
+    let report = result?;
+
+    let trips = &report.round_trips_ms;
+    if !trips.is_empty() {
+        let min = trips.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = trips.iter().copied().fold(0.0, f64::max);
+        let avg = trips.iter().sum::<f64>() / trips.len() as f64;
+        println!(
+            "  Latency   min {:.1} ms, avg {:.1} ms, max {:.1} ms ({} round trips)",
+            min,
+            avg,
+            max,
+            trips.len()
+        );
+    }
+    println!(
+        "  Download  {} (server to this machine)",
+        throughput(report.download_bytes, report.download_ms)
+    );
+    println!(
+        "  Upload    {} (this machine to the server)",
+        throughput(report.upload_bytes, report.upload_ms)
+    );
+    Ok(())
+}
+
+fn throughput(bytes: u64, ms: f64) -> String {
+    let mbits = bytes as f64 * 8.0 / 1_000_000.0 / (ms.max(0.001) / 1000.0);
+    format!(
+        "{:.1} Mbit/s, {} in {:.0} ms",
+        mbits,
+        format_size(bytes),
+        ms
+    )
+}
+
+fn format_size(bytes: u64) -> String {
+    if bytes >= 1024 * 1024 {
+        format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+    } else {
+        format!("{:.1} KiB", bytes as f64 / 1024.0)
+    }
+}
+
+/// The test service the server's connections reach through the tunnel
+async fn serve(listener: TcpListener) {
+    while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(stream).await {
+                debug!("Speed test connection failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn serve_connection(mut stream: TcpStream) -> std::io::Result<()> {
+    let _ = stream.set_nodelay(true);
+    match stream.read_u8().await? {
+        b'p' => {
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await?;
+        }
+        b'd' => {
+            let mut left = stream.read_u64().await?;
+            let mut buffer = vec![0u8; CHUNK];
+            while left > 0 {
+                let n = left.min(CHUNK as u64) as usize;
+                let read = stream.read(&mut buffer[..n]).await?;
+                if read == 0 {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                left -= read as u64;
+            }
+            stream.write_u8(b'k').await?;
+        }
+        b'u' => {
+            let mut left = stream.read_u64().await?;
+            let data: Vec<u8> = (0..CHUNK).map(|i| (i % 251) as u8).collect();
+            while left > 0 {
+                let n = left.min(CHUNK as u64) as usize;
+                stream.write_all(&data[..n]).await?;
+                left -= n as u64;
+            }
+        }
+        mode => {
+            return Err(std::io::Error::other(format!(
+                "Unknown speed test mode {}",
+                mode
+            )))
+        }
+    }
+    stream.shutdown().await
+}
@@ -0,0 +1,272 @@
+//! Tunnels as remote forwards on a plain SSH server.
+//!
+//! With `[ssh]` enabled the client does not talk to a NAT traversal server
+//! at all: each TCP tunnel runs the OpenSSH client (`ssh -N -R`), which
+//! asks the SSH server to listen on the tunnel's remote ports and forwards
+//! what arrives there to the local service. A forward that ends, because
+//! the connection dropped or the server refused the port, is started again
+//! after `reconnect_interval_secs`. When a tunnel has no remote port the
+//! SSH server picks one, read back from ssh's output.
+
+use chrono::Utc;
+use nat_traversal_common::{
+    config::{ClientSshConfig, TunnelConfig},
+    protocol::{TunnelInfo, TunnelMode, TunnelProtocol},
+};
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+pub struct SshTunnels {
+    config: ClientSshConfig,
+    tunnels: tokio::sync::RwLock<HashMap<Uuid, SshTunnel>>,
+}
+
+struct SshTunnel {
+    /// Updated with the port the SSH server picked
+    info: Arc<RwLock<TunnelInfo>>,
+    task: JoinHandle<()>,
+}
+
+impl SshTunnels {
+    pub fn new(config: &ClientSshConfig) -> Self {
+        Self {
+            config: config.clone(),
+            tunnels: tokio::sync::RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Open a configured tunnel, leaving out what only the NAT traversal
+    /// server provides
+    pub async fn open_configured(&self, tunnel: &TunnelConfig) -> anyhow::Result<TunnelInfo> {
+        if tunnel.ttl_secs.is_some()
+            || tunnel.domain.is_some()
+            || tunnel.http_auth.is_some()
+            || !tunnel.e2e_peers.is_empty()
+            || !tunnel.stages.is_empty()
+        {
+            warn!(
+                "Tunnel {} uses server features SSH forwards lack (TTL, domain, HTTP auth, \
+                 e2e_peers or stages); forwarding it without them",
+                tunnel.name
+            );
+        }
+        self.open(
+            Some(tunnel.name.clone()),
+            tunnel.local_host.clone(),
+            tunnel.local_port,
+            tunnel.remote_port,
+            tunnel.port_count,
+            tunnel.protocol,
+        )
+        .await
+    }
+
+    /// Start forwarding `remote_port` (and the `port_count - 1` after it)
+    /// on the SSH server to `local_host:local_port`
+    pub async fn open(
+        &self,
+        name: Option<String>,
+        local_host: String,
+        local_port: u16,
+        remote_port: Option<u16>,
+        port_count: u16,
+        protocol: TunnelProtocol,
+    ) -> anyhow::Result<TunnelInfo> {
+        if protocol != TunnelProtocol::Tcp {
+            return Err(anyhow::anyhow!("SSH forwards only carry TCP tunnels"));
+        }
+        let port_count = port_count.max(1);
+        if port_count > 1 && remote_port.is_none() {
+            return Err(anyhow::anyhow!(
+                "A port range over SSH needs a remote_port to start from"
+            ));
+        }
+        let first = remote_port.unwrap_or(0);
+        if u32::from(first) + u32::from(port_count) - 1 > u32::from(u16::MAX)
+            || u32::from(local_port) + u32::from(port_count) - 1 > u32::from(u16::MAX)
+        {
+            return Err(anyhow::anyhow!("Port range runs past 65535"));
+        }
+
+        let forwards = (0..port_count)
+            .map(|offset| {
+                forward(
+                    &self.config.bind_addr,
+                    if first == 0 { 0 } else { first + offset },
+                    &local_host,
+                    local_port + offset,
+                )
+            })
+            .collect();
+        let info = TunnelInfo {
+            id: Uuid::new_v4(),
+            name: name.clone(),
+            protocol,
+            local_host,
+            local_port,
+            remote_port: first,
+            created_at: Utc::now(),
+            bytes_sent: 0,
+            bytes_received: 0,
+            active_connections: 0,
+            mode: TunnelMode::Ssh,
+            port_count,
+            expires_at: None,
+            domain: None,
+            tls_passthrough: false,
+            udp: None,
+        };
+        let shared = Arc::new(RwLock::new(info.clone()));
+        let label = name.unwrap_or_else(|| info.id.to_string());
+        let task = tokio::spawn(run(self.config.clone(), forwards, shared.clone(), label));
+        self.tunnels
+            .write()
+            .await
+            .insert(info.id, SshTunnel { info: shared, task });
+        Ok(info)
+    }
+
+    /// Stop a forward. Returns false if the tunnel is not an SSH one.
+    pub async fn close(&self, tunnel_id: Uuid) -> bool {
+        match self.tunnels.write().await.remove(&tunnel_id) {
+            Some(tunnel) => {
+                tunnel.task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub async fn close_all(&self) {
+        for (_, tunnel) in self.tunnels.write().await.drain() {
+            tunnel.task.abort();
+        }
+    }
+
+    pub async fn list(&self) -> Vec<TunnelInfo> {
+        self.tunnels
+            .read()
+            .await
+            .values()
+            .map(|tunnel| tunnel.info.read().unwrap().clone())
+            .collect()
+    }
+}
+
+/// An `-R` argument, `[bind:]remote:host:local`
+fn forward(bind_addr: &str, remote_port: u16, local_host: &str, local_port: u16) -> String {
+    let bracket = |host: &str| {
+        if host.contains(':') && !host.starts_with('[') {
+            format!("[{}]", host)
+        } else {
+            host.to_string()
+        }
+    };
+    let target = format!("{}:{}", bracket(local_host), local_port);
+    if bind_addr.is_empty() {
+        format!("{}:{}", remote_port, target)
+    } else {
+        format!("{}:{}:{}", bracket(bind_addr), remote_port, target)
+    }
+}
+
+/// The port in ssh's `Allocated port 43567 for remote forward to ...`
+fn allocated_port(line: &str) -> Option<u16> {
+    line.strip_prefix("Allocated port ")?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+fn command(config: &ClientSshConfig, forwards: &[String]) -> Command {
+    let mut command = Command::new(&config.program);
+    command.args(["-N", "-T", "-p", &config.port.to_string()]);
+    // ssh keeps the first value given for an option, so these go first
+    // to override the defaults below
+    for option in &config.options {
+        command.arg("-o").arg(option);
+    }
+    command
+        .args(["-o", "BatchMode=yes", "-o", "ExitOnForwardFailure=yes"])
+        .arg("-o")
+        .arg(format!("ServerAliveInterval={}", config.keepalive_secs))
+        .args(["-o", "ServerAliveCountMax=3"]);
+    if let Some(identity) = &config.identity_file {
+        command.arg("-i").arg(identity);
+    }
+    for forward in forwards {
+        command.arg("-R").arg(forward);
+    }
+    command
+        .arg(format!("{}@{}", config.user, config.host))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    command
+}
+
+/// Keep one tunnel's forwards running until the task is aborted
+async fn run(
+    config: ClientSshConfig,
+    forwards: Vec<String>,
+    info: Arc<RwLock<TunnelInfo>>,
+    name: String,
+) {
+    let retry = Duration::from_secs(config.reconnect_interval_secs.max(1));
+    loop {
+        let mut child = match command(&config, &forwards).spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Failed to run {}: {}", config.program.display(), e);
+                tokio::time::sleep(retry).await;
+                continue;
+            }
+        };
+        info!(
+            "Tunnel {} forwarding over SSH to {}@{}",
+            name, config.user, config.host
+        );
+
+        let mut last_error = String::new();
+        if let Some(stderr) = child.stderr.take() {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                match allocated_port(&line) {
+                    Some(port) => {
+                        info.write().unwrap().remote_port = port;
+                        info!(
+                            "Tunnel {} reachable at {}:{} -> {}",
+                            name, config.host, port, forwards[0]
+                        );
+                    }
+                    None => {
+                        debug!("ssh: {}", line);
+                        last_error = line;
+                    }
+                }
+            }
+        }
+        let status = child.wait().await;
+        warn!(
+            "SSH forward for tunnel {} ended ({}){}; retrying in {}s",
+            name,
+            status.map_or_else(|e| e.to_string(), |status| status.to_string()),
+            if last_error.is_empty() {
+                String::new()
+            } else {
+                format!(": {}", last_error)
+            },
+            retry.as_secs()
+        );
+        tokio::time::sleep(retry).await;
+    }
+}
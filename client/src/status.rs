@@ -0,0 +1,334 @@
+use crate::control::{self, ControlRequest, ControlResponse, StatusReport};
+use nat_traversal_common::config::ClientConfig;
+use nat_traversal_common::control::request;
+use nat_traversal_common::protocol::{port_range, Utilization};
+use uuid::Uuid;
+
+/// Ask the running client for its status and print it
+pub async fn run(config: &ClientConfig, json: bool) -> anyhow::Result<()> {
+    let report = match send(config, &ControlRequest::Status).await? {
+        ControlResponse::Status(report) => report,
+        ControlResponse::Error(message) => return Err(anyhow::anyhow!(message)),
+        _ => return Err(anyhow::anyhow!("Unexpected response")),
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_report(&report);
+    }
+    Ok(())
+}
+
+/// Have the running client fetch a replacement token from the server
+pub async fn rotate_token(config: &ClientConfig) -> anyhow::Result<()> {
+    command(config, &ControlRequest::RotateToken).await
+}
+
+/// Have the running client share a tunnel on a temporary public port
+pub async fn share(
+    config: &ClientConfig,
+    tunnel: &str,
+    ttl_secs: u64,
+    password: Option<String>,
+) -> anyhow::Result<()> {
+    let request = ControlRequest::Share {
+        tunnel: tunnel.to_string(),
+        ttl_secs,
+        password,
+    };
+    command(config, &request).await
+}
+
+/// Have the running client close one of its shares
+pub async fn unshare(config: &ClientConfig, share_id: Uuid) -> anyhow::Result<()> {
+    command(config, &ControlRequest::CloseShare { share_id }).await
+}
+
+/// Have the running client ask `peer` to wake one of its machines
+pub async fn wake(config: &ClientConfig, peer: &str, target: &str) -> anyhow::Result<()> {
+    let request = ControlRequest::Wake {
+        peer: peer.to_string(),
+        target: target.to_string(),
+    };
+    command(config, &request).await
+}
+
+/// Have the running client compare its tunnels with the server's
+pub async fn check_tunnels(config: &ClientConfig) -> anyhow::Result<()> {
+    command(config, &ControlRequest::CheckTunnels).await
+}
+
+/// List the requests captured from custom-domain tunnels
+pub async fn requests(config: &ClientConfig, json: bool) -> anyhow::Result<()> {
+    let requests = match send(config, &ControlRequest::Requests).await? {
+        ControlResponse::Requests(requests) => requests,
+        ControlResponse::Error(message) => return Err(anyhow::anyhow!(message)),
+        _ => return Err(anyhow::anyhow!("Unexpected response")),
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&requests)?);
+        return Ok(());
+    }
+    if requests.is_empty() {
+        println!("No captured requests");
+        return Ok(());
+    }
+    println!(
+        "{:<6}  {:<8}  {:<16}  {:<7}  {:>9}  PATH",
+        "ID", "TIME", "TUNNEL", "METHOD", "SIZE"
+    );
+    for request in requests {
+        println!(
+            "{:<6}  {:<8}  {:<16}  {:<7}  {:>9}  {}",
+            request.id,
+            request
+                .received_at
+                .with_timezone(&chrono::Local)
+                .format("%H:%M:%S"),
+            request.tunnel,
+            request.method,
+            format_bytes(request.size as u64),
+            request.path
+        );
+    }
+    Ok(())
+}
+
+/// Have the running client send a captured request to its local service
+/// again, after opening it in an editor when `edit` is set
+pub async fn replay(config: &ClientConfig, id: u64, edit: bool) -> anyhow::Result<()> {
+    let request = if edit {
+        let text = match send(config, &ControlRequest::GetRequest { id }).await? {
+            ControlResponse::Request(text) => text,
+            ControlResponse::Error(message) => return Err(anyhow::anyhow!(message)),
+            _ => return Err(anyhow::anyhow!("Unexpected response")),
+        };
+        Some(edit_in_editor(id, &text)?)
+    } else {
+        None
+    };
+    command(config, &ControlRequest::Replay { id, request }).await
+}
+
+/// Let the user change `text` in `$VISUAL` or `$EDITOR`
+fn edit_in_editor(id: u64, text: &str) -> anyhow::Result<String> {
+    let path = std::env::temp_dir().join(format!(
+        "nat-client-request-{}-{}.http",
+        std::process::id(),
+        id
+    ));
+    std::fs::write(&path, text)?;
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| {
+            if cfg!(windows) {
+                "notepad".to_string()
+            } else {
+                "vi".to_string()
+            }
+        });
+    let status = std::process::Command::new(&editor).arg(&path).status();
+    let edited = std::fs::read_to_string(&path);
+    let _ = std::fs::remove_file(&path);
+    match status {
+        Ok(status) if status.success() => Ok(edited?),
+        Ok(status) => Err(anyhow::anyhow!("{} exited with {}", editor, status)),
+        Err(e) => Err(anyhow::anyhow!("Failed to run {}: {}", editor, e)),
+    }
+}
+
+/// Send a request that is answered with a message to print
+async fn command(config: &ClientConfig, control_request: &ControlRequest) -> anyhow::Result<()> {
+    match send(config, control_request).await? {
+        ControlResponse::Done(message) => {
+            println!("{}", message);
+            Ok(())
+        }
+        ControlResponse::Error(message) => Err(anyhow::anyhow!(message)),
+        _ => Err(anyhow::anyhow!("Unexpected response")),
+    }
+}
+
+async fn send(
+    config: &ClientConfig,
+    control_request: &ControlRequest,
+) -> anyhow::Result<ControlResponse> {
+    let path = control::socket_path(config)?;
+    request(&path, control_request)
+        .await
+        .map_err(|e| anyhow::anyhow!("No running client at {}: {}", path.display(), e))
+}
+
+fn print_report(report: &StatusReport) {
+    println!("State:    {}", report.state);
+    println!("Server:   {}", report.server);
+    if let Some(profile) = &report.profile {
+        println!("Profile:  {}", profile);
+    }
+    match (report.rtt_ms, report.rtt_avg_ms, report.rtt_max_ms) {
+        (Some(rtt), Some(avg), Some(max)) => println!(
+            "RTT:      {:.1} ms (avg {:.1} ms, max {:.1} ms)",
+            rtt, avg, max
+        ),
+        (Some(rtt), _, _) => println!("RTT:      {:.1} ms", rtt),
+        _ => println!("RTT:      -"),
+    }
+    if let Some(usage) = &report.usage {
+        match usage.quota_bytes {
+            Some(quota) => println!(
+                "Usage:    {} of {} ({})",
+                format_bytes(usage.bytes),
+                format_bytes(quota),
+                usage.month
+            ),
+            None => println!("Usage:    {} ({})", format_bytes(usage.bytes), usage.month),
+        }
+    }
+    if let Some(load) = report.server_load.as_ref().and_then(format_load) {
+        println!("Load:     {}", load);
+    }
+    println!();
+
+    if report.tunnels.is_empty() {
+        println!("No active tunnels");
+        print_schedules(report);
+        return;
+    }
+
+    let mut rows = vec![[
+        "NAME".to_string(),
+        "REMOTE".to_string(),
+        "LOCAL".to_string(),
+        "PROTO".to_string(),
+        "VIA".to_string(),
+        "UPTIME".to_string(),
+        "EXPIRES".to_string(),
+        "CONNS".to_string(),
+        "SENT".to_string(),
+        "RECEIVED".to_string(),
+    ]];
+    for tunnel in &report.tunnels {
+        rows.push([
+            tunnel.name.clone().unwrap_or_else(|| tunnel.id.to_string()),
+            match &tunnel.domain {
+                Some(domain) => format!("{} ({})", tunnel.remote_port, domain),
+                None => port_range(tunnel.remote_port, tunnel.port_count).to_string(),
+            },
+            format!(
+                "{}:{}",
+                tunnel.local_host,
+                port_range(tunnel.local_port, tunnel.port_count)
+            ),
+            tunnel.protocol.to_string(),
+            tunnel.mode.to_string(),
+            format_duration(tunnel.uptime_secs),
+            tunnel
+                .expires_in_secs
+                .map_or_else(|| "-".to_string(), format_duration),
+            tunnel.active_connections.to_string(),
+            format_bytes(tunnel.bytes_sent),
+            format_bytes(tunnel.bytes_received),
+        ]);
+    }
+
+    let mut widths = [0usize; 10];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in &rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+
+    if !report.shares.is_empty() {
+        println!();
+        println!("Shares:");
+        let now = chrono::Utc::now();
+        for share in &report.shares {
+            let tunnel = report
+                .tunnels
+                .iter()
+                .find(|t| t.id == share.tunnel_id)
+                .and_then(|t| t.name.clone())
+                .unwrap_or_else(|| share.tunnel_id.to_string());
+            println!(
+                "  {}  {}  port {}  expires in {}{}",
+                share.id,
+                tunnel,
+                share.port,
+                format_duration((share.expires_at - now).num_seconds().max(0) as u64),
+                if share.password_protected {
+                    "  (password)"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+    print_schedules(report);
+}
+
+fn print_schedules(report: &StatusReport) {
+    if report.schedules.is_empty() {
+        return;
+    }
+    println!();
+    println!("Schedules:");
+    for schedule in &report.schedules {
+        println!(
+            "  {}  {}  ({})",
+            schedule.name,
+            schedule.describe(),
+            schedule.windows.join(", ")
+        );
+    }
+}
+
+/// The server's capped resources as shares in use, e.g.
+/// `tunnels 40%, ports 12%`; none when nothing is capped
+fn format_load(load: &Utilization) -> Option<String> {
+    let parts: Vec<String> = [
+        ("tunnels", load.tunnels),
+        ("connections", load.connections),
+        ("ports", load.ports),
+    ]
+    .into_iter()
+    .filter_map(|(name, gauge)| Some(format!("{} {:.0}%", name, gauge.percent()?)))
+    .collect();
+    (!parts.is_empty()).then(|| parts.join(", "))
+}
+
+/// Compact rendering such as `3h05m`, `12m30s` or `2d04h`
+pub fn format_duration(secs: u64) -> String {
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    if days > 0 {
+        format!("{}d{:02}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h{:02}m", hours, minutes)
+    } else {
+        format!("{}m{:02}s", minutes, secs % 60)
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
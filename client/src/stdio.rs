@@ -0,0 +1,57 @@
+//! `nat-client stdio`: carry stdin and stdout to a port on the server, the
+//! way `ssh -W` does, for use as an SSH ProxyCommand or in pipelines.
+//! Nothing else may write to stdout while the bridge runs.
+
+use nat_traversal_common::{config::ClientConfig, noise, socket};
+
+/// Connect to `port` on the server, or through the encrypted tunnel of the
+/// configured visitor named `visitor`, and bridge it until both sides close
+pub async fn run(
+    config: &ClientConfig,
+    port: Option<u16>,
+    visitor: Option<&str>,
+) -> anyhow::Result<()> {
+    let mut local = tokio::io::join(tokio::io::stdin(), tokio::io::stdout());
+
+    let visitor = visitor
+        .map(|name| {
+            config
+                .e2e
+                .visitors
+                .iter()
+                .find(|visitor| visitor.name == name)
+                .ok_or_else(|| anyhow::anyhow!("No e2e visitor '{}'", name))
+        })
+        .transpose()?;
+    let port = match (visitor, port) {
+        (Some(visitor), _) => visitor.server_port,
+        (None, Some(port)) => port,
+        (None, None) => return Err(anyhow::anyhow!("Give a server port or --visitor")),
+    };
+
+    let server = config.server.endpoint(port);
+    let mut remote = socket::connect(&server, &config.sockets.tunnel)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to connect to {}: {}", server, e))?;
+
+    match visitor {
+        Some(visitor) => {
+            let private = config.e2e.load_private_key()?.ok_or_else(|| {
+                anyhow::anyhow!("E2E visitors need e2e.private_key or e2e.private_key_file")
+            })?;
+            let private = noise::decode_key(&private)
+                .map_err(|e| anyhow::anyhow!("Invalid e2e private key: {}", e))?;
+            let peer = noise::decode_key(&visitor.peer).map_err(|e| {
+                anyhow::anyhow!("Invalid peer key of visitor {}: {}", visitor.name, e)
+            })?;
+            noise::connect(remote, &private, &peer)
+                .await?
+                .pump(local)
+                .await?;
+        }
+        None => {
+            tokio::io::copy_bidirectional(&mut local, &mut remote).await?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,50 @@
+//! Clients running inside a test process, reaching a server in the same
+//! process over the in-memory transport.
+
+use crate::connection::ServerConnection;
+use crate::core::NatClient;
+use crate::transport::ClientTransport;
+use nat_traversal_common::{
+    config::ClientConfig, protocol::TunnelInfo, transport::MemoryConnector,
+};
+use std::time::Duration;
+
+/// Settings for a test client named `client_id` authenticating with
+/// `token`. Nothing is looked up on the network besides the server.
+pub fn config(client_id: &str, token: &str) -> ClientConfig {
+    let mut config = ClientConfig::default();
+    config.server.addr = "memory".to_string();
+    config.server.client_id = client_id.to_string();
+    config.server.token = token.to_string();
+    config.server.reconnect_interval_secs = 1;
+    config.gui.enabled = false;
+    config.stun.enabled = false;
+    config.control.enabled = false;
+    config
+}
+
+/// A client that connects to the server through `connector` once started
+pub fn client(config: ClientConfig, connector: MemoryConnector) -> anyhow::Result<NatClient> {
+    let connection =
+        ServerConnection::with_transport(config.clone(), ClientTransport::Memory(connector))?;
+    Ok(NatClient::with_connection(config, connection))
+}
+
+/// Wait until the client has at least `count` tunnels open on the server
+pub async fn wait_for_tunnels(
+    client: &NatClient,
+    count: usize,
+    timeout: Duration,
+) -> anyhow::Result<Vec<TunnelInfo>> {
+    tokio::time::timeout(timeout, async {
+        loop {
+            let tunnels = client.get_tunnels().await;
+            if tunnels.len() >= count {
+                return tunnels;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("Fewer than {} tunnels open after {:?}", count, timeout))
+}
@@ -0,0 +1,133 @@
+//! How the client reaches the server: TLS over TCP, or in tests a
+//! transport within the process
+
+use nat_traversal_common::{
+    config::{ServerConnectionConfig, SocketOptions},
+    dial::Dialer,
+    error::{NatError, NatResult},
+    transport::{Connector, Transport},
+};
+use std::net::IpAddr;
+use tokio::net::TcpStream;
+use tokio::sync::RwLock;
+use tokio_rustls::{rustls, TlsConnector};
+
+pub type SecureClientStream = tokio_rustls::client::TlsStream<TcpStream>;
+
+pub enum ClientTransport {
+    Tls(Box<TlsTransport>),
+    #[cfg(feature = "test-util")]
+    Memory(nat_traversal_common::transport::MemoryConnector),
+}
+
+impl ClientTransport {
+    /// The TLS transport, unless the client runs over another
+    pub fn tls(&self) -> Option<&TlsTransport> {
+        match self {
+            Self::Tls(tls) => Some(tls),
+            #[cfg(feature = "test-util")]
+            Self::Memory(_) => None,
+        }
+    }
+}
+
+impl Connector for ClientTransport {
+    type Stream = Box<dyn Transport>;
+
+    async fn connect(&self) -> NatResult<Box<dyn Transport>> {
+        Ok(match self {
+            Self::Tls(tls) => Box::new(tls.connect().await?),
+            #[cfg(feature = "test-util")]
+            Self::Memory(memory) => Box::new(memory.connect().await?),
+        })
+    }
+}
+
+pub struct TlsTransport {
+    host: String,
+    port: u16,
+    /// `host:port` of the server, for messages
+    endpoint: String,
+    /// Resolves `host` again for each connection and opens it with the
+    /// control socket options
+    dialer: Dialer,
+    /// Name or IP address the certificate is verified against, which may
+    /// differ from the address connected to
+    server_name: String,
+    /// Rebuilt once enrollment has issued a client certificate
+    tls: RwLock<TlsConnector>,
+}
+
+impl TlsTransport {
+    pub fn new(server: &ServerConnectionConfig, sockets: SocketOptions, tls: TlsConnector) -> Self {
+        Self {
+            host: server.host().to_string(),
+            port: server.port,
+            endpoint: server.endpoint(server.port),
+            dialer: Dialer::new(sockets),
+            server_name: server.tls_name().to_string(),
+            tls: RwLock::new(tls),
+        }
+    }
+
+    /// Use `tls` for the connections opened from now on
+    pub async fn set_tls(&self, tls: TlsConnector) {
+        *self.tls.write().await = tls;
+    }
+}
+
+impl Connector for TlsTransport {
+    type Stream = SecureClientStream;
+
+    async fn connect(&self) -> NatResult<SecureClientStream> {
+        // Connect to server, following its DNS record should it change
+        let tcp_stream = self
+            .dialer
+            .connect(&self.host, self.port)
+            .await
+            .map_err(|e| {
+                NatError::connection(format!("Failed to connect to {}: {}", self.endpoint, e))
+            })?;
+
+        // Perform TLS handshake; an IP address is checked against the
+        // certificate's IP entries rather than its DNS names
+        let server_name = match self.server_name.parse::<IpAddr>() {
+            Ok(ip) => rustls::ServerName::IpAddress(ip),
+            Err(_) => rustls::ServerName::try_from(self.server_name.as_str())
+                .map_err(|e| NatError::tls(format!("Invalid server name: {}", e)))?,
+        };
+
+        let tls_connector = self.tls.read().await.clone();
+        tls_connector
+            .connect(server_name, tcp_stream)
+            .await
+            .map_err(|e| NatError::tls(handshake_error(&self.server_name, &e)))
+    }
+}
+
+/// Why a handshake failed, spelling out a certificate that does not name
+/// the server
+fn handshake_error(server_name: &str, error: &std::io::Error) -> String {
+    let wrong_name = error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+        .is_some_and(|inner| {
+            matches!(
+                inner,
+                rustls::Error::InvalidCertificate(rustls::CertificateError::NotValidForName)
+            )
+        });
+    match (wrong_name, server_name.parse::<IpAddr>()) {
+        (true, Ok(_)) => format!(
+            "TLS handshake failed: the server's certificate has no IP address entry for {}; \
+             add IP:{} to its subjectAltName, or set server_name to a DNS name it holds",
+            server_name, server_name
+        ),
+        (true, Err(_)) => format!(
+            "TLS handshake failed: the server's certificate is not issued for {}; \
+             set server_name to a name it holds",
+            server_name
+        ),
+        (false, _) => format!("TLS handshake failed: {}", error),
+    }
+}
@@ -0,0 +1,108 @@
+//! The client end of the layer-3 VPN: join the server's VPN on every
+//! session, open a TUN device with the address the server hands out and
+//! carry packets between it and the server.
+
+use bytes::Bytes;
+use nat_traversal_common::{config::ClientVpnConfig, protocol::Message, vpn::Subnet};
+use nat_traversal_platform::tun::Tun;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, OnceCell};
+use tracing::{debug, error, info, warn};
+
+pub struct Vpn {
+    config: ClientVpnConfig,
+    /// Opened on the first join and kept across sessions, so routes and
+    /// connections through it survive reconnecting
+    device: OnceCell<(Arc<Tun>, Subnet)>,
+    /// Where packets for the server go in the current session
+    uplink: Mutex<Option<mpsc::UnboundedSender<Message>>>,
+}
+
+impl Vpn {
+    /// The VPN end for `config`, when it is enabled
+    pub fn new(config: &ClientVpnConfig) -> Option<Arc<Self>> {
+        config.enabled.then(|| {
+            Arc::new(Self {
+                config: config.clone(),
+                device: OnceCell::new(),
+                uplink: Mutex::new(None),
+            })
+        })
+    }
+
+    /// Ask the server to let this client join, sending packets on `uplink`
+    /// from now on
+    pub fn join(
+        &self,
+        message_tx: &mpsc::UnboundedSender<Message>,
+        uplink: mpsc::UnboundedSender<Message>,
+    ) {
+        *self.uplink.lock().unwrap() = Some(uplink);
+        let _ = message_tx.send(Message::JoinVpn {
+            advertise: self.config.advertise.clone(),
+        });
+    }
+
+    /// The server accepted the join with `address`: open the device the
+    /// first time and start carrying its packets
+    pub async fn joined(self: &Arc<Self>, address: Subnet) {
+        let result = self
+            .device
+            .get_or_try_init(|| async {
+                // `ip` runs as a child process, which must not stall the runtime
+                let config = self.config.clone();
+                let device = tokio::task::spawn_blocking(move || {
+                    let device =
+                        Tun::create(&config.interface, address.addr, address.prefix, config.mtu)?;
+                    for route in &config.routes {
+                        if let Err(e) = device.add_route(route.network(), route.prefix) {
+                            warn!("Failed to route {} through the VPN: {}", route, e);
+                        }
+                    }
+                    anyhow::Ok(Arc::new(device))
+                })
+                .await??;
+                tokio::spawn(self.clone().pump(device.clone()));
+                info!("Joined the VPN as {} on {}", address, device.name());
+                anyhow::Ok((device, address))
+            })
+            .await;
+        match result {
+            Ok((_, current)) if *current != address => warn!(
+                "The server now gives this client {} on the VPN; restart the client to use it",
+                address
+            ),
+            Ok(_) => debug!("Rejoined the VPN as {}", address),
+            Err(e) => error!("Failed to join the VPN: {}", e),
+        }
+    }
+
+    /// Hand a packet from the server to the device
+    pub async fn deliver(&self, data: Bytes) {
+        let Some((device, _)) = self.device.get() else {
+            return;
+        };
+        if let Err(e) = device.send(&data).await {
+            debug!("Failed to write VPN packet: {}", e);
+        }
+    }
+
+    /// Send the packets the kernel routes into the device to the server.
+    /// Packets sent while disconnected are dropped, as by any link that
+    /// went down.
+    async fn pump(self: Arc<Self>, device: Arc<Tun>) {
+        let mut buffer = vec![0; usize::from(self.config.mtu).max(576)];
+        loop {
+            let len = match device.recv(&mut buffer).await {
+                Ok(len) => len,
+                Err(e) => return error!("VPN device {} failed: {}", device.name(), e),
+            };
+            let packet = Message::VpnPacket {
+                data: Bytes::copy_from_slice(&buffer[..len]),
+            };
+            if let Some(uplink) = &*self.uplink.lock().unwrap() {
+                let _ = uplink.send(packet);
+            }
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! Wake-on-LAN through the server: other clients ask this one to wake a
+//! machine on its LAN, and this one asks others to wake theirs.
+
+use nat_traversal_common::{config::WakeTarget, wol};
+use std::collections::HashMap;
+use tokio::sync::{oneshot, Mutex};
+use tracing::info;
+use uuid::Uuid;
+
+type WakeResult = Result<(), String>;
+
+pub struct Wakes {
+    targets: Vec<WakeTarget>,
+    /// Requests still waiting for `WakeOnLanResult`
+    pending: Mutex<HashMap<Uuid, oneshot::Sender<WakeResult>>>,
+}
+
+impl Wakes {
+    pub fn new(targets: Vec<WakeTarget>) -> Self {
+        Self {
+            targets,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Send the magic packet for `target` if `peer` may wake it
+    pub async fn wake(&self, peer: &str, target: &str) -> WakeResult {
+        let machine = self
+            .targets
+            .iter()
+            .find(|machine| machine.name == target)
+            .ok_or_else(|| format!("No Wake-on-LAN target '{}'", target))?;
+        if !machine.allowed_peers.is_empty() && !machine.allowed_peers.iter().any(|p| p == peer) {
+            return Err(format!("Client '{}' may not wake '{}'", peer, target));
+        }
+        let mac = wol::parse_mac(&machine.mac).map_err(|e| e.to_string())?;
+        wol::send(&mac, machine.broadcast)
+            .await
+            .map_err(|e| format!("Failed to send the magic packet: {}", e))?;
+        info!(
+            "Sent Wake-on-LAN packet for {} ({}) at {}'s request",
+            target, machine.mac, peer
+        );
+        Ok(())
+    }
+
+    /// Register a wake request; the receiver gets the peer's answer
+    pub async fn expect(&self, request_id: Uuid) -> oneshot::Receiver<WakeResult> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().await.insert(request_id, tx);
+        rx
+    }
+
+    /// Forget a request that will not be waited for any more
+    pub async fn abandon(&self, request_id: &Uuid) {
+        self.pending.lock().await.remove(request_id);
+    }
+
+    /// Record a peer's answer to a wake request
+    pub async fn answered(&self, request_id: Uuid, error: Option<String>) {
+        if let Some(tx) = self.pending.lock().await.remove(&request_id) {
+            let _ = tx.send(error.map_or(Ok(()), Err));
+        }
+    }
+}
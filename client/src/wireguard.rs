@@ -0,0 +1,290 @@
+//! The client end of the WireGuard mesh: a kernel WireGuard device whose
+//! peers are the other clients the server announces.
+//!
+//! Before the device takes its port, STUN learns the port's public
+//! address, which the server passes on to the other members. Each peer is
+//! first tried at its public address when it has one; a peer that has not
+//! completed a handshake there within `DIRECT_TIMEOUT` is moved to the
+//! server's relay for the rest of the run.
+
+use crate::credentials::write_atomically;
+use nat_traversal_common::{
+    config::{get_config_dir, ClientConfig, ClientWireGuardConfig},
+    noise,
+    protocol::{Message, WireGuardPeer},
+    stun::{self, MappingBehavior},
+    vpn::Subnet,
+};
+use nat_traversal_platform::wireguard::WireGuardDevice;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Mutex, OnceCell};
+use tracing::{debug, error, info, warn};
+
+/// How long a peer has to complete a handshake at its public address
+const DIRECT_TIMEOUT: Duration = Duration::from_secs(15);
+/// How often peers' handshakes are checked
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+struct Device {
+    device: WireGuardDevice,
+    public_key: String,
+    /// Public address of the device's port, when STUN found it
+    endpoint: Option<SocketAddr>,
+}
+
+struct Peer {
+    info: WireGuardPeer,
+    relayed: bool,
+    configured_at: Instant,
+}
+
+pub struct WireGuard {
+    config: ClientWireGuardConfig,
+    server_addr: String,
+    stun_servers: Vec<String>,
+    stun_timeout: Duration,
+    /// Created on the first join and kept across sessions
+    device: OnceCell<Device>,
+    /// Configured peers, by public key
+    peers: Mutex<HashMap<String, Peer>>,
+}
+
+impl WireGuard {
+    /// The mesh end for `config`, when it is enabled
+    pub fn new(config: &ClientConfig) -> Option<Arc<Self>> {
+        config.wireguard.enabled.then(|| {
+            Arc::new(Self {
+                config: config.wireguard.clone(),
+                server_addr: config.server.addr.clone(),
+                stun_servers: config.stun.server_list(&config.server),
+                stun_timeout: Duration::from_millis(config.stun.timeout_ms),
+                device: OnceCell::new(),
+                peers: Mutex::new(HashMap::new()),
+            })
+        })
+    }
+
+    /// Set up the device the first time and ask the server to let this
+    /// client join the mesh
+    pub async fn join(self: &Arc<Self>, message_tx: &mpsc::UnboundedSender<Message>) {
+        let device = match self.device.get_or_try_init(|| self.open()).await {
+            Ok(device) => device,
+            Err(e) => return error!("Failed to set up WireGuard: {}", e),
+        };
+        let _ = message_tx.send(Message::JoinWireGuard {
+            public_key: device.public_key.clone(),
+            endpoint: device.endpoint,
+            advertise: self.config.advertise.clone(),
+        });
+    }
+
+    /// The server accepted the join with `address`
+    pub fn joined(&self, address: Subnet) {
+        let Some(device) = self.device.get() else {
+            return;
+        };
+        match device.device.set_address(address.addr, address.prefix) {
+            Ok(()) => info!(
+                "Joined the WireGuard mesh as {} on {}",
+                address,
+                device.device.name()
+            ),
+            Err(e) => error!("Failed to set the WireGuard address: {}", e),
+        }
+    }
+
+    /// Bring the device's peers in line with the server's list
+    pub async fn update(&self, peers: Vec<WireGuardPeer>) {
+        let Some(device) = self.device.get() else {
+            return;
+        };
+        let mut current = self.peers.lock().await;
+
+        let gone: Vec<String> = current
+            .keys()
+            .filter(|key| !peers.iter().any(|peer| &peer.public_key == *key))
+            .cloned()
+            .collect();
+        for key in gone {
+            if let Some(peer) = current.remove(&key) {
+                if let Err(e) = device.device.remove_peer(&key) {
+                    debug!("Failed to remove WireGuard peer: {}", e);
+                }
+                unroute(device, &peer.info);
+                info!("WireGuard peer {} left", peer.info.client_id);
+            }
+        }
+
+        for info in peers {
+            if current
+                .get(&info.public_key)
+                .is_some_and(|peer| peer.info == info)
+            {
+                continue;
+            }
+            // A peer whose addresses changed starts over at its public address
+            if let Some(previous) = current.remove(&info.public_key) {
+                unroute(device, &previous.info);
+            }
+            let relayed = !self.config.direct || info.endpoint.is_none();
+            self.configure(device, &info, relayed).await;
+            for subnet in &info.allowed_ips {
+                if let Err(e) = device.device.add_route(subnet.network(), subnet.prefix) {
+                    warn!("Failed to route {} to WireGuard: {}", subnet, e);
+                }
+            }
+            info!(
+                "WireGuard peer {} at {}",
+                info.client_id,
+                if relayed {
+                    "the relay"
+                } else {
+                    "its public address"
+                }
+            );
+            current.insert(
+                info.public_key.clone(),
+                Peer {
+                    info,
+                    relayed,
+                    configured_at: Instant::now(),
+                },
+            );
+        }
+    }
+
+    async fn open(self: &Arc<Self>) -> anyhow::Result<Device> {
+        let private_key = self.private_key()?;
+        let public_key = noise::public_key(&noise::decode_key(&private_key)?)?;
+        let endpoint = self.discover_endpoint().await;
+        let device = WireGuardDevice::create(
+            &self.config.interface,
+            &private_key,
+            self.config.listen_port,
+        )?;
+        tokio::spawn(self.clone().watch());
+        Ok(Device {
+            device,
+            public_key,
+            endpoint,
+        })
+    }
+
+    /// The configured private key, created on first use
+    fn private_key(&self) -> anyhow::Result<String> {
+        let path = match &self.config.private_key_file {
+            Some(path) => path.clone(),
+            None => get_config_dir()?.join("wireguard.key"),
+        };
+        if path.exists() {
+            return Ok(std::fs::read_to_string(&path)?.trim().to_string());
+        }
+        let (private_key, _) = noise::generate_keypair()?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        write_atomically(&path, format!("{}\n", private_key).as_bytes())?;
+        info!("Created WireGuard key {}", path.display());
+        Ok(private_key)
+    }
+
+    /// Learn the public address of the WireGuard port while it is free
+    async fn discover_endpoint(&self) -> Option<SocketAddr> {
+        let port = self.config.listen_port;
+        let socket = match UdpSocket::bind(("0.0.0.0", port)).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("Failed to bind WireGuard port {}: {}", port, e);
+                return None;
+            }
+        };
+        match stun::discover_with(&socket, &self.stun_servers, self.stun_timeout).await {
+            Ok(report) => {
+                if report.mapping == MappingBehavior::AddressDependent {
+                    warn!(
+                        "The NAT maps port {} per destination; peers may not reach it, \
+                         even through the relay",
+                        port
+                    );
+                }
+                Some(report.public_addr)
+            }
+            Err(e) => {
+                warn!("No public address for WireGuard port {}: {}", port, e);
+                None
+            }
+        }
+    }
+
+    /// Point `peer` at its public address, or at the server's relay
+    async fn configure(&self, device: &Device, peer: &WireGuardPeer, relayed: bool) {
+        let endpoint = if relayed {
+            tokio::net::lookup_host(format!("{}:{}", self.server_addr, peer.relay_port))
+                .await
+                .ok()
+                .and_then(|mut addrs| addrs.find(SocketAddr::is_ipv4))
+        } else {
+            peer.endpoint
+        };
+        let allowed_ips: Vec<String> = peer.allowed_ips.iter().map(Subnet::to_string).collect();
+        if let Err(e) = device.device.set_peer(
+            &peer.public_key,
+            endpoint,
+            &allowed_ips,
+            self.config.persistent_keepalive_secs,
+        ) {
+            warn!(
+                "Failed to configure WireGuard peer {}: {}",
+                peer.client_id, e
+            );
+        }
+    }
+
+    /// Move peers that never completed a handshake at their public address
+    /// to the relay
+    async fn watch(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let Some(device) = self.device.get() else {
+                continue;
+            };
+            let handshakes = match device.device.latest_handshakes() {
+                Ok(handshakes) => handshakes,
+                Err(e) => {
+                    debug!("Failed to read WireGuard handshakes: {}", e);
+                    continue;
+                }
+            };
+            let mut peers = self.peers.lock().await;
+            for peer in peers.values_mut() {
+                if peer.relayed
+                    || peer.configured_at.elapsed() < DIRECT_TIMEOUT
+                    || handshakes
+                        .get(&peer.info.public_key)
+                        .is_some_and(|time| *time > 0)
+                {
+                    continue;
+                }
+                info!(
+                    "No WireGuard handshake with {} at its public address, using the relay",
+                    peer.info.client_id
+                );
+                peer.relayed = true;
+                self.configure(device, &peer.info, true).await;
+            }
+        }
+    }
+}
+
+fn unroute(device: &Device, peer: &WireGuardPeer) {
+    for subnet in &peer.allowed_ips {
+        if let Err(e) = device.device.remove_route(subnet.network(), subnet.prefix) {
+            debug!("Failed to remove WireGuard route {}: {}", subnet, e);
+        }
+    }
+}
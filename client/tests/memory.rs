@@ -0,0 +1,244 @@
+//! A server and a client in one process, joined by the in-memory
+//! transport, carrying a TCP tunnel end to end.
+
+use nat_traversal_client::{core::NatClient, testing};
+use nat_traversal_common::{
+    config::TunnelConfig,
+    crypto, data_channel,
+    protocol::{Message, ShutdownDirection, TunnelInfo, PROTOCOL_VERSION},
+    transport::{self, Connector},
+};
+use nat_traversal_server::testing::{self as server, TestServer};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpStream};
+
+const TOKEN: &str = "memory-test-token";
+
+/// Port of a local service that echoes what it reads
+async fn echo_service() -> u16 {
+    let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_port = echo.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = echo.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    local_port
+}
+
+/// A started client with one tunnel to an echo service
+async fn echo_client(server: &TestServer) -> (NatClient, TunnelInfo) {
+    let local_port = echo_service().await;
+    let mut config = testing::config("memory-test", TOKEN);
+    config.tunnels.push(
+        toml::from_str::<TunnelConfig>(&format!(
+            "name = \"echo\"\nlocal_port = {}\nprotocol = \"Tcp\"",
+            local_port
+        ))
+        .unwrap(),
+    );
+    let client = testing::client(config, server.connector()).unwrap();
+    client.start().await.unwrap();
+
+    let mut tunnels = testing::wait_for_tunnels(&client, 1, Duration::from_secs(10))
+        .await
+        .unwrap();
+    (client, tunnels.remove(0))
+}
+
+/// Send `payload` through the tunnel and expect exactly it back
+async fn assert_echoes(visitor: &mut TcpStream, payload: &[u8]) {
+    visitor.write_all(payload).await.unwrap();
+    let mut echoed = vec![0u8; payload.len()];
+    tokio::time::timeout(Duration::from_secs(10), visitor.read_exact(&mut echoed))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(echoed, payload);
+}
+
+async fn read_reply(stream: &mut DuplexStream) -> Message {
+    let frame = transport::read_frame(stream, 1024 * 1024)
+        .await
+        .unwrap()
+        .expect("the server closed the stream");
+    Message::from_bytes(&frame).unwrap()
+}
+
+/// A second client holding a valid token but owning no tunnels, speaking
+/// the protocol by hand over a control stream and a data channel
+struct Intruder {
+    control: DuplexStream,
+    data: DuplexStream,
+}
+
+impl Intruder {
+    const CLIENT_ID: &'static str = "intruder";
+
+    async fn connect(server: &TestServer) -> Self {
+        let connector = server.connector();
+        let mut control = connector.connect().await.unwrap();
+        transport::write_frame(&mut control, &Message::RequestAuthChallenge)
+            .await
+            .unwrap();
+        let nonce = match read_reply(&mut control).await {
+            Message::AuthChallenge { nonce } => nonce,
+            other => panic!("Unexpected reply: {:?}", other),
+        };
+        let auth = Message::Auth {
+            version: PROTOCOL_VERSION,
+            token: String::new(),
+            client_id: Self::CLIENT_ID.to_string(),
+            proof: Some(crypto::auth_proof(TOKEN, &nonce, Self::CLIENT_ID)),
+            heartbeat: None,
+            max_frame_size: None,
+        };
+        transport::write_frame(&mut control, &auth).await.unwrap();
+        let key = match read_reply(&mut control).await {
+            Message::AuthResponse {
+                success: true,
+                data_channel: Some(key),
+                ..
+            } => key,
+            other => panic!("Unexpected reply: {:?}", other),
+        };
+
+        let mut data = connector.connect().await.unwrap();
+        let attach = Message::AttachDataChannel {
+            client_id: Self::CLIENT_ID.to_string(),
+            key,
+        };
+        transport::write_frame(&mut data, &attach).await.unwrap();
+        match read_reply(&mut data).await {
+            Message::DataChannelAttached => {}
+            other => panic!("Unexpected reply: {:?}", other),
+        }
+        Self { control, data }
+    }
+
+    /// Send `message` over both channels and let the server act on it
+    async fn send(&mut self, message: Message) {
+        self.send_data(message.clone()).await;
+        self.send_control(message).await;
+    }
+
+    /// Send `message` over the data channel only
+    async fn send_data(&mut self, message: Message) {
+        data_channel::write_message(&mut self.data, &message)
+            .await
+            .unwrap();
+        // The data channel answers nothing, so it gets a moment instead
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+
+    /// Send `message` over the control stream only
+    async fn send_control(&mut self, message: Message) {
+        transport::write_frame(&mut self.control, &message)
+            .await
+            .unwrap();
+
+        // The server handles a stream's messages in order, so the pong
+        // means the message was dealt with
+        let ping = Message::Ping {
+            timestamp: chrono::Utc::now(),
+        };
+        transport::write_frame(&mut self.control, &ping)
+            .await
+            .unwrap();
+        while !matches!(read_reply(&mut self.control).await, Message::Pong { .. }) {}
+    }
+}
+
+#[tokio::test]
+async fn test_tunnel_over_memory_transport() {
+    let server = TestServer::start(server::config(TOKEN)).await.unwrap();
+    let (client, tunnel) = echo_client(&server).await;
+
+    let mut visitor = TcpStream::connect(("127.0.0.1", tunnel.remote_port))
+        .await
+        .unwrap();
+    assert_echoes(&mut visitor, b"over the memory transport").await;
+
+    client.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_ignores_shutdown_from_other_clients() {
+    let server = TestServer::start(server::config(TOKEN)).await.unwrap();
+    let (client, tunnel) = echo_client(&server).await;
+    let mut visitor = TcpStream::connect(("127.0.0.1", tunnel.remote_port))
+        .await
+        .unwrap();
+    assert_echoes(&mut visitor, b"before").await;
+
+    // Connection IDs count from 1 on each tunnel
+    let mut intruder = Intruder::connect(&server).await;
+    intruder
+        .send(Message::ConnectionShutdown {
+            tunnel_id: tunnel.id,
+            connection_id: 1,
+            direction: ShutdownDirection::Outbound,
+        })
+        .await;
+
+    // A half-close would have ended the visitor's side
+    assert_echoes(&mut visitor, b"after").await;
+
+    client.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_ignores_close_from_other_clients() {
+    let server = TestServer::start(server::config(TOKEN)).await.unwrap();
+    let (client, tunnel) = echo_client(&server).await;
+    let mut visitor = TcpStream::connect(("127.0.0.1", tunnel.remote_port))
+        .await
+        .unwrap();
+    assert_echoes(&mut visitor, b"before").await;
+
+    let mut intruder = Intruder::connect(&server).await;
+    intruder
+        .send_control(Message::ConnectionClosed {
+            tunnel_id: tunnel.id,
+            connection_id: 1,
+        })
+        .await;
+
+    assert_echoes(&mut visitor, b"after").await;
+
+    client.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_ignores_data_channel_from_other_clients() {
+    let server = TestServer::start(server::config(TOKEN)).await.unwrap();
+    let (client, tunnel) = echo_client(&server).await;
+    let mut visitor = TcpStream::connect(("127.0.0.1", tunnel.remote_port))
+        .await
+        .unwrap();
+    assert_echoes(&mut visitor, b"before").await;
+
+    let mut intruder = Intruder::connect(&server).await;
+    intruder
+        .send_data(Message::Data {
+            tunnel_id: tunnel.id,
+            connection_id: 1,
+            data: "injected".into(),
+        })
+        .await;
+    intruder
+        .send_data(Message::ConnectionClosed {
+            tunnel_id: tunnel.id,
+            connection_id: 1,
+        })
+        .await;
+
+    // Injected bytes would arrive ahead of the echo
+    assert_echoes(&mut visitor, b"after").await;
+
+    client.stop().await.unwrap();
+}
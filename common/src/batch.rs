@@ -0,0 +1,257 @@
+//! Coalescing writer for the control and data connections.
+//!
+//! Every message already queued when the writer wakes up is encoded into one
+//! buffer and handed to the stream in a single write, so a burst of small
+//! frames becomes one TLS record and one flush instead of two writes and a
+//! flush apiece.
+//!
+//! No frame goes out larger than the peer accepts: tunnel data is split
+//! into several frames, and any other message too large is dropped with a
+//! warning rather than making the peer close the connection.
+
+use crate::config::FlushPolicy;
+use crate::protocol::{Message, DEFAULT_MAX_FRAME_SIZE, MIN_MAX_FRAME_SIZE};
+use bytes::BytesMut;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// Stop adding queued messages to a batch once it holds this many bytes
+pub const MAX_BATCH_BYTES: usize = 64 * 1024;
+
+/// The largest frame the peer accepts, learned when it authenticates and
+/// shared with the writers of all its connections
+#[derive(Debug, Clone)]
+pub struct FrameLimit(Arc<AtomicUsize>);
+
+impl Default for FrameLimit {
+    fn default() -> Self {
+        Self(Arc::new(AtomicUsize::new(DEFAULT_MAX_FRAME_SIZE)))
+    }
+}
+
+impl FrameLimit {
+    /// Take the limit the peer advertised, if it did
+    pub fn set(&self, advertised: Option<u32>) {
+        let limit = advertised.map_or(DEFAULT_MAX_FRAME_SIZE, |limit| {
+            (limit as usize).max(MIN_MAX_FRAME_SIZE)
+        });
+        self.0.store(limit, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Write messages from `rx` until it closes, encoding each with `encode`
+/// into frames of at most `limit` bytes
+pub async fn write_batched<W, F>(
+    writer: &mut W,
+    rx: &mut mpsc::UnboundedReceiver<Message>,
+    flush: FlushPolicy,
+    limit: &FrameLimit,
+    encode: F,
+) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    F: Fn(&Message, &mut BytesMut) -> anyhow::Result<()>,
+{
+    let mut buf = BytesMut::with_capacity(MAX_BATCH_BYTES);
+    while let Some(message) = rx.recv().await {
+        encode_within(&message, &mut buf, limit.get(), &encode)?;
+        while buf.len() < MAX_BATCH_BYTES {
+            match rx.try_recv() {
+                Ok(message) => encode_within(&message, &mut buf, limit.get(), &encode)?,
+                Err(_) => break,
+            }
+        }
+
+        writer.write_all(&buf).await?;
+        buf.clear();
+        if flush.should_flush(!rx.is_empty()) {
+            writer.flush().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Append `message` as frames of at most `limit` bytes after their length
+/// prefix, splitting tunnel data over as many as it takes
+fn encode_within<F>(
+    message: &Message,
+    buf: &mut BytesMut,
+    limit: usize,
+    encode: &F,
+) -> anyhow::Result<()>
+where
+    F: Fn(&Message, &mut BytesMut) -> anyhow::Result<()>,
+{
+    let start = buf.len();
+    encode(message, buf)?;
+    let len = buf.len() - start - 4;
+    if len <= limit {
+        return Ok(());
+    }
+    buf.truncate(start);
+    match message {
+        Message::Data {
+            tunnel_id,
+            data,
+            connection_id,
+        } if data.len() > 1 => {
+            let parts = (len / limit + 1).min(data.len());
+            let size = data.len().div_ceil(parts);
+            for offset in (0..data.len()).step_by(size) {
+                let part = Message::Data {
+                    tunnel_id: *tunnel_id,
+                    data: data.slice(offset..(offset + size).min(data.len())),
+                    connection_id: *connection_id,
+                };
+                encode_within(&part, buf, limit, encode)?;
+            }
+        }
+        _ => warn!(
+            "Dropping a {} byte message the peer would not accept (limit {})",
+            len, limit
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_channel;
+    use bytes::Bytes;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_batched_frames_decode_in_order() {
+        let tunnel_id = Uuid::new_v4();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        for connection_id in 0..100 {
+            tx.send(Message::Data {
+                tunnel_id,
+                data: Bytes::from(vec![connection_id as u8; 1000]),
+                connection_id,
+            })
+            .unwrap();
+        }
+        drop(tx);
+
+        let (mut a, mut b) = tokio::io::duplex(256 * 1024);
+        write_batched(
+            &mut a,
+            &mut rx,
+            FlushPolicy::Batched,
+            &FrameLimit::default(),
+            data_channel::encode,
+        )
+        .await
+        .unwrap();
+        drop(a);
+
+        for expected in 0..100 {
+            match data_channel::read_message(&mut b, 4096).await.unwrap() {
+                Some(Message::Data {
+                    data,
+                    connection_id,
+                    ..
+                }) => {
+                    assert_eq!(connection_id, expected);
+                    assert_eq!(data.len(), 1000);
+                }
+                other => panic!("unexpected frame: {:?}", other),
+            }
+        }
+        assert!(data_channel::read_message(&mut b, 4096)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_data_is_split_to_fit() {
+        let tunnel_id = Uuid::new_v4();
+        let payload: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        tx.send(Message::Data {
+            tunnel_id,
+            data: Bytes::from(payload.clone()),
+            connection_id: 7,
+        })
+        .unwrap();
+        drop(tx);
+
+        let limit = FrameLimit::default();
+        limit.set(Some(1));
+        assert_eq!(limit.get(), MIN_MAX_FRAME_SIZE);
+        let (mut a, mut b) = tokio::io::duplex(1024 * 1024);
+        write_batched(
+            &mut a,
+            &mut rx,
+            FlushPolicy::Batched,
+            &limit,
+            Message::encode_frame,
+        )
+        .await
+        .unwrap();
+        drop(a);
+
+        let mut received = Vec::new();
+        let mut frames = 0;
+        while let Some(frame) = crate::transport::read_frame(&mut b, MIN_MAX_FRAME_SIZE)
+            .await
+            .unwrap()
+        {
+            match Message::from_bytes(&frame).unwrap() {
+                Message::Data {
+                    data,
+                    connection_id: 7,
+                    ..
+                } => received.extend_from_slice(&data),
+                other => panic!("unexpected frame: {:?}", other),
+            }
+            frames += 1;
+        }
+        assert!(frames > 1);
+        assert_eq!(received, payload);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_control_message_is_dropped() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        tx.send(Message::Disconnect {
+            reason: "x".repeat(2 * MIN_MAX_FRAME_SIZE),
+        })
+        .unwrap();
+        tx.send(Message::StatusRequest).unwrap();
+        drop(tx);
+
+        let limit = FrameLimit::default();
+        limit.set(Some(MIN_MAX_FRAME_SIZE as u32));
+        let (mut a, mut b) = tokio::io::duplex(1024 * 1024);
+        write_batched(
+            &mut a,
+            &mut rx,
+            FlushPolicy::Batched,
+            &limit,
+            Message::encode_frame,
+        )
+        .await
+        .unwrap();
+        drop(a);
+
+        let frame = crate::transport::read_frame(&mut b, MIN_MAX_FRAME_SIZE)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            Message::from_bytes(&frame).unwrap(),
+            Message::StatusRequest
+        ));
+    }
+}
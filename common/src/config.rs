@@ -1,6 +1,9 @@
+use crate::vpn::Subnet;
 use serde::{Deserialize, Serialize};
-use std::net::{IpAddr, Ipv4Addr};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 /// Server configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -10,15 +13,115 @@ pub struct ServerConfig {
     pub auth: AuthConfig,
     pub limits: LimitsConfig,
     pub logging: LoggingConfig,
+    #[serde(default)]
+    pub performance: PerformanceConfig,
+    #[serde(default)]
+    pub sockets: SocketConfig,
+    #[serde(default)]
+    pub control: ControlConfig,
+    /// Certificate authority issuing client certificates for mTLS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca: Option<CaConfig>,
+    /// Route HTTP requests to tunnels by their `Host` header
+    #[serde(default)]
+    pub http: HttpConfig,
+    /// Record clients, tunnels and sessions in a database
+    #[serde(default)]
+    pub storage: StorageConfig,
+    /// Where monthly traffic per client is kept
+    #[serde(default)]
+    pub usage: UsageConfig,
+    /// Public ports and domains held for particular clients, by client ID
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub reservations: BTreeMap<String, Reservation>,
+    /// Tunnels particular clients are told to open whenever they log in,
+    /// by client ID
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub provisioned: BTreeMap<String, Vec<TunnelConfig>>,
+    /// Scripts run on new public connections and, optionally, tunnel data
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub plugins: Vec<PluginConfig>,
+    /// Layer-3 VPN clients may join
+    #[serde(default)]
+    pub vpn: ServerVpnConfig,
+    /// WireGuard mesh the server brokers between clients
+    #[serde(default)]
+    pub wireguard: ServerWireGuardConfig,
+    /// Administration API over gRPC
+    #[serde(default)]
+    pub grpc: GrpcConfig,
+    /// Live events over a WebSocket
+    #[serde(default)]
+    pub events: EventsConfig,
+    /// Listener accepting frp clients
+    #[serde(default)]
+    pub frp: ServerFrpConfig,
 }
 
 /// Client configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClientConfig {
+    /// Profile used when none is given on the command line
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_profile: Option<String>,
     pub server: ServerConnectionConfig,
     pub tunnels: Vec<TunnelConfig>,
     pub gui: GuiConfig,
     pub logging: LoggingConfig,
+    #[serde(default)]
+    pub stun: StunConfig,
+    #[serde(default)]
+    pub port_mapping: PortMappingConfig,
+    #[serde(default)]
+    pub p2p: P2pConfig,
+    /// Keys for end-to-end encrypted tunnels
+    #[serde(default)]
+    pub e2e: E2eConfig,
+    #[serde(default)]
+    pub performance: PerformanceConfig,
+    #[serde(default)]
+    pub sockets: SocketConfig,
+    #[serde(default)]
+    pub control: ControlConfig,
+    #[serde(default)]
+    pub capture: CaptureConfig,
+    /// Notify someone when the client keeps failing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alerts: Option<AlertConfig>,
+    /// Machines on this client's LAN other clients may wake
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub wake_on_lan: Vec<WakeTarget>,
+    /// Join the server's layer-3 VPN
+    #[serde(default)]
+    pub vpn: ClientVpnConfig,
+    /// Join the server's WireGuard mesh
+    #[serde(default)]
+    pub wireguard: ClientWireGuardConfig,
+    /// Run tunnels as remote forwards on an SSH server instead
+    #[serde(default)]
+    pub ssh: ClientSshConfig,
+    /// Directory of additional tunnel definition files, defaults to
+    /// `tunnels.d` next to the configuration file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tunnels_dir: Option<PathBuf>,
+    /// Named profiles, each with its own server settings and tunnels
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, ProfileConfig>,
+    /// Name of the profile currently applied to `server` and `tunnels`
+    #[serde(skip)]
+    pub active_profile: Option<String>,
+    /// File this configuration was loaded from, where a rotated token is
+    /// written back
+    #[serde(skip)]
+    pub config_path: Option<PathBuf>,
+}
+
+/// Named client profile
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfileConfig {
+    pub server: ServerConnectionConfig,
+    #[serde(default)]
+    pub tunnels: Vec<TunnelConfig>,
 }
 
 /// Network configuration
@@ -27,6 +130,15 @@ pub struct NetworkConfig {
     pub bind_addr: IpAddr,
     pub port: u16,
     pub max_connections: u32,
+    /// Answer STUN binding requests on the control port over UDP
+    #[serde(default = "default_true")]
+    pub stun_enabled: bool,
+    /// Unprivileged user to switch to once the listener is bound (Unix)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_as_user: Option<String>,
+    /// Group to switch to, defaults to the user's primary group
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_as_group: Option<String>,
 }
 
 /// TLS configuration
@@ -36,14 +148,365 @@ pub struct TlsConfig {
     pub key_path: PathBuf,
     pub ca_path: Option<PathBuf>,
     pub verify_client: bool,
+    #[serde(flatten)]
+    pub policy: TlsPolicy,
+}
+
+/// The server's own certificate authority. Clients holding a bootstrap
+/// token enroll once by sending a certificate signing request and use the
+/// signed certificate to authenticate from then on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaConfig {
+    /// CA certificate; generated together with the key when both are missing
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// Validity of issued client certificates
+    #[serde(default = "default_cert_ttl_days")]
+    pub cert_ttl_days: u32,
+    /// Tokens that may only be used to enroll, not to connect
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bootstrap_tokens: Vec<String>,
+}
+
+impl Default for CaConfig {
+    fn default() -> Self {
+        Self {
+            cert_path: "ca.crt".into(),
+            key_path: "ca.key".into(),
+            cert_ttl_days: default_cert_ttl_days(),
+            bootstrap_tokens: Vec::new(),
+        }
+    }
+}
+
+fn default_cert_ttl_days() -> u32 {
+    365
+}
+
+/// Restrictions on the TLS handshake, shared by the server's `[tls]` and
+/// the client's `[server.tls]`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsPolicy {
+    /// Oldest protocol version accepted
+    #[serde(default)]
+    pub min_version: TlsVersion,
+    /// Cipher suites allowed, by rustls name (e.g. `TLS13_AES_256_GCM_SHA384`);
+    /// empty allows every safe default
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cipher_suites: Vec<String>,
+    /// ALPN protocols offered by the client and required by the server
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alpn: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
 }
 
 /// Authentication configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthConfig {
     pub tokens: Vec<String>,
+    /// File with additional tokens, one per line (`#` starts a comment)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens_file: Option<PathBuf>,
     pub require_auth: bool,
     pub max_clients_per_token: Option<u32>,
+    /// Accept static tokens sent in the clear by clients that predate
+    /// challenge-response authentication. Off unless a fleet still has
+    /// such clients, as anyone who sees one of those logins has the token.
+    #[serde(default)]
+    pub allow_plain_tokens: bool,
+    /// Also accept signed, expiring tokens (JWT)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwt: Option<JwtConfig>,
+    /// What clients authenticating with a given static token may do;
+    /// tokens without an entry are unrestricted
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub scopes: BTreeMap<String, TokenScope>,
+    /// What happens when a client logs in with the ID of one that is
+    /// still connected
+    #[serde(default)]
+    pub duplicate_client_id: DuplicateClientId,
+    /// Message sent to clients when they log in, such as terms of use or
+    /// whom to contact
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motd: Option<String>,
+}
+
+/// Which session keeps a client ID that two clients log in with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DuplicateClientId {
+    /// The newer login; the older session is dropped and its open
+    /// connections held for the newer one to resume, as a client coming
+    /// back from a network change does before its old session times out
+    #[default]
+    TakeOver,
+    /// The older session; the newer login is refused
+    Reject,
+}
+
+/// This client's static key for end-to-end encrypted tunnels, and local
+/// ports that reach such tunnels exposed by other clients
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct E2eConfig {
+    /// Base64 private key from `nat-client e2e-keygen`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<String>,
+    /// File holding the private key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key_file: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub visitors: Vec<E2eVisitor>,
+}
+
+/// A local port forwarding to another client's encrypted tunnel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct E2eVisitor {
+    pub name: String,
+    /// The tunnel's public port on the server
+    pub server_port: u16,
+    #[serde(default = "crate::protocol::default_local_host")]
+    pub bind_host: String,
+    pub bind_port: u16,
+    /// Public key of the client exposing the tunnel
+    pub peer: String,
+}
+
+/// Restrictions on a token's tunnels. Empty lists allow everything.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenScope {
+    /// Tunnel protocols the token may open
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub protocols: Vec<crate::protocol::TunnelProtocol>,
+    /// Public ports the token may listen on, e.g. `["8000-8099", 9443]`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<PortRange>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tunnels: Option<u32>,
+    /// Combined rate of all the client's tunnels, both directions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bandwidth_mbps: Option<u32>,
+    /// Megabytes (10^6 bytes) the client may carry per calendar month (UTC),
+    /// both directions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monthly_quota_mb: Option<u64>,
+    /// Once the quota is used up, slow the client to this rate instead of
+    /// refusing its new connections and tunnels
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub over_quota_mbps: Option<u32>,
+}
+
+impl TokenScope {
+    pub fn allows_protocol(&self, protocol: crate::protocol::TunnelProtocol) -> bool {
+        self.protocols.is_empty() || self.protocols.contains(&protocol)
+    }
+
+    pub fn allows_port(&self, port: u16) -> bool {
+        self.ports.is_empty() || self.ports.iter().any(|range| range.contains(port))
+    }
+}
+
+/// Inclusive port range, written `"8000-8099"`, or a single port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+impl std::str::FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("Invalid port range '{}'", s))
+        };
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            None => (parse(s)?, parse(s)?),
+        };
+        if start > end {
+            return Err(format!("Invalid port range '{}'", s));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl std::fmt::Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Port(u16),
+            Range(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Port(port) => Ok(Self {
+                start: port,
+                end: port,
+            }),
+            Raw::Range(range) => range.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// DSCP class to mark packets with, written as its name (`"EF"`,
+/// `"AF41"`, `"CS1"`, `"LE"`) or its value from 0 to 63
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dscp(u8);
+
+impl Dscp {
+    pub fn new(value: u8) -> Result<Self, String> {
+        if value > 63 {
+            return Err(format!("DSCP {} is out of range 0-63", value));
+        }
+        Ok(Self(value))
+    }
+
+    pub fn value(self) -> u8 {
+        self.0
+    }
+
+    /// The IP TOS / traffic class byte carrying the class
+    pub fn tos(self) -> u32 {
+        u32::from(self.0) << 2
+    }
+}
+
+impl std::str::FromStr for Dscp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim().to_ascii_uppercase();
+        let value = match name.as_str() {
+            "DEFAULT" | "BE" => 0,
+            "LE" => 1,
+            "EF" => 46,
+            "VA" => 44,
+            _ => {
+                let number = |prefix| {
+                    name.strip_prefix(prefix)
+                        .and_then(|rest| rest.parse::<u8>().ok())
+                };
+                match (number("CS"), number("AF")) {
+                    (Some(class @ 0..=7), _) => class * 8,
+                    // AFxy: class x from 1 to 4, drop precedence y from 1 to 3
+                    (_, Some(af @ 11..=43)) if (1..=3).contains(&(af % 10)) => {
+                        af / 10 * 8 + af % 10 * 2
+                    }
+                    _ => name
+                        .parse::<u8>()
+                        .map_err(|_| format!("Unknown DSCP class '{}'", s))?,
+                }
+            }
+        };
+        Self::new(value)
+    }
+}
+
+impl std::fmt::Display for Dscp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Serialize for Dscp {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Dscp {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Value(u8),
+            Name(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Value(value) => Self::new(value),
+            Raw::Name(name) => name.parse(),
+        }
+        .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Keys and claims for signed client tokens. Tokens must carry `exp`; a
+/// `sub` claim binds the token to one client ID, and the `TokenScope` fields
+/// as claims restrict it like a static token's scope.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JwtConfig {
+    /// Shared secret for HS256 tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    /// File holding the HS256 secret
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_file: Option<PathBuf>,
+    /// Ed25519 public key (PEM) for EdDSA tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<PathBuf>,
+    /// Required `iss` claim
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    /// Required `aud` claim
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+    /// Clock skew allowed when checking `exp` and `nbf`
+    #[serde(default = "default_jwt_leeway_secs")]
+    pub leeway_secs: u64,
+    /// Lifetime of replacement tokens handed to clients by token rotation
+    #[serde(default = "default_rotation_ttl_hours")]
+    pub rotation_ttl_hours: u64,
+    /// Refuse tokens without a `sub` claim, which any client ID could use
+    #[serde(default = "default_true")]
+    pub require_subject: bool,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            secret_file: None,
+            public_key: None,
+            issuer: None,
+            audience: None,
+            leeway_secs: default_jwt_leeway_secs(),
+            rotation_ttl_hours: default_rotation_ttl_hours(),
+            require_subject: true,
+        }
+    }
 }
 
 /// Rate limiting and resource limits
@@ -53,6 +516,47 @@ pub struct LimitsConfig {
     pub max_bandwidth_mbps: Option<u32>,
     pub max_connections_per_tunnel: u32,
     pub connection_timeout_secs: u64,
+    #[serde(default = "default_max_relays_per_client")]
+    pub max_relays_per_client: u32,
+    /// Relays with no traffic for this long are released
+    #[serde(default = "default_relay_idle_timeout_secs")]
+    pub relay_idle_timeout_secs: u64,
+    /// Parallel data connections a client may attach
+    #[serde(default = "default_max_data_connections")]
+    pub max_data_connections: u32,
+    /// Largest port range a single tunnel may expose
+    #[serde(default = "default_max_ports_per_tunnel")]
+    pub max_ports_per_tunnel: u16,
+    /// Longest a temporary share of a tunnel may last
+    #[serde(default = "default_max_share_ttl_secs")]
+    pub max_share_ttl_secs: u64,
+    /// Drop a client that sends nothing, not even a heartbeat, for this
+    /// long; 0 never drops a silent client. Clients that advertise their
+    /// heartbeat interval and allowed misses get that window instead.
+    #[serde(default = "default_heartbeat_timeout_secs")]
+    pub heartbeat_timeout_secs: u64,
+    /// Keep a dropped client's open connections this long for it to
+    /// reconnect and resume them; 0 closes them with the session
+    #[serde(default = "default_resume_window_secs")]
+    pub resume_window_secs: u64,
+    /// Recently sent data each connection keeps for replay, and what a held
+    /// connection may buffer meanwhile before it is closed
+    #[serde(default = "default_resume_buffer_kb")]
+    pub resume_buffer_kb: usize,
+    /// Data a tunneled connection may have queued for writing
+    /// before it is closed, so one stalled reader can't pile up memory;
+    /// 0 leaves it uncapped
+    #[serde(default = "default_max_connection_buffer_kb")]
+    pub max_connection_buffer_kb: usize,
+    /// Most tunnels open at once across all clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_total_tunnels: Option<u32>,
+    /// Most public connections open at once across all tunnels
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_total_connections: Option<u32>,
+    /// Most public ports tunnels, shares and relays hold at once
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_ports_in_use: Option<u32>,
 }
 
 /// Logging configuration
@@ -62,28 +566,941 @@ pub struct LoggingConfig {
     pub file: Option<PathBuf>,
     pub max_size_mb: u32,
     pub max_files: u32,
+    /// Export spans and metrics to an OpenTelemetry collector; needs a
+    /// build with the `otlp` feature
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub otlp: Option<OtlpConfig>,
+    /// Also log to the systemd journal (Linux)
+    #[serde(default)]
+    pub journald: bool,
+    /// Also log to syslog, locally or on a remote collector
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub syslog: Option<SyslogConfig>,
+    /// Let the server's operator raise the client's log level for a while
+    /// and fetch its recent log lines (clients only)
+    #[serde(default = "default_true")]
+    pub allow_remote: bool,
+}
+
+/// Syslog output, RFC 5424 formatted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SyslogConfig {
+    /// `host:port` of a remote collector; the local `/dev/log` when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    /// Transport to a remote collector
+    pub protocol: SyslogProtocol,
+    /// Facility name, e.g. `daemon` or `local0`
+    pub facility: String,
+    /// Reported APP-NAME, defaults to the binary name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_name: Option<String>,
+}
+
+impl Default for SyslogConfig {
+    fn default() -> Self {
+        Self {
+            address: None,
+            protocol: SyslogProtocol::Udp,
+            facility: "daemon".to_string(),
+            app_name: None,
+        }
+    }
+}
+
+/// Transport to a remote syslog collector
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum SyslogProtocol {
+    Udp,
+    /// Octet-counted framing (RFC 6587)
+    Tcp,
+}
+
+/// OpenTelemetry export over OTLP/HTTP
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OtlpConfig {
+    /// Collector base URL; `/v1/traces` and `/v1/metrics` are appended
+    pub endpoint: String,
+    /// Reported `service.name`, defaults to the binary name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_name: Option<String>,
+    pub traces: bool,
+    pub metrics: bool,
+    pub metrics_interval_secs: u64,
+    /// Extra request headers, e.g. collector credentials
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Server connection configuration for client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerConnectionConfig {
+    pub addr: String,
+    pub port: u16,
+    /// Name the server's certificate is checked against and sent as SNI,
+    /// when `addr` is not it: an IP address, an internal load balancer or
+    /// a jump host forwarding to the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+    pub token: String,
+    /// File holding the token; takes precedence over `token` when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_file: Option<PathBuf>,
+    pub client_id: String,
+    pub auto_reconnect: bool,
+    pub reconnect_interval_secs: u64,
+    pub tls_verify: bool,
+    /// Carry tunnel payloads on a second connection as binary frames
+    #[serde(default = "default_true")]
+    pub data_channel: bool,
+    /// Number of parallel data connections; more than one helps on links
+    /// where a single TLS stream cannot fill the bandwidth-delay product
+    #[serde(default = "default_data_connections")]
+    pub data_connections: usize,
+    #[serde(default)]
+    pub tls: TlsPolicy,
+    /// Client certificate for mutual TLS. When both paths are set but the
+    /// files do not exist yet, the client enrolls with the server's CA
+    /// using its token and saves the issued certificate here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<PathBuf>,
+    /// Seconds between heartbeats; shorter keeps NAT mappings on mobile
+    /// and carrier networks from expiring
+    #[serde(default = "default_heartbeat_interval_secs")]
+    pub heartbeat_interval_secs: u64,
+    /// Reconnect after this many heartbeats in a row go unanswered; 0
+    /// waits for the connection to fail instead. Sent to the server,
+    /// which drops the client after the same silence.
+    #[serde(default = "default_heartbeat_max_missed")]
+    pub heartbeat_max_missed: u32,
+    /// Keep open tunneled connections this long after losing the server
+    /// and resume them on reconnecting; 0 closes them at once. Needs to be
+    /// longer than `reconnect_interval_secs` to help.
+    #[serde(default = "default_resume_window_secs")]
+    pub resume_window_secs: u64,
+    /// Recently sent data each connection keeps for replay, and what a held
+    /// connection may buffer meanwhile before it is closed
+    #[serde(default = "default_resume_buffer_kb")]
+    pub resume_buffer_kb: usize,
+    /// Data a tunneled connection may have queued for writing
+    /// before it is closed, so one stalled reader can't pile up memory;
+    /// 0 leaves it uncapped
+    #[serde(default = "default_max_connection_buffer_kb")]
+    pub max_connection_buffer_kb: usize,
+}
+
+/// Tunnel configuration for client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelConfig {
+    pub name: String,
+    /// Host on the client's network that receives the tunnel traffic
+    #[serde(default = "crate::protocol::default_local_host")]
+    pub local_host: String,
+    pub local_port: u16,
+    pub remote_port: Option<u16>,
+    /// Expose this many consecutive ports from `local_port`, on as many
+    /// consecutive public ports from `remote_port`
+    #[serde(
+        default = "crate::protocol::default_port_count",
+        skip_serializing_if = "crate::protocol::is_single_port"
+    )]
+    pub port_count: u16,
+    pub protocol: crate::protocol::TunnelProtocol,
+    #[serde(default = "default_true")]
+    pub auto_start: bool,
+    /// Close the tunnel this many seconds after it is first opened, for
+    /// demo or support access that should not outlive its purpose
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_secs: Option<u64>,
+    /// Local times the tunnel is open, e.g. `["mon-fri 09:00-18:00"]`; the
+    /// client opens and closes it as each window starts and ends. Empty
+    /// keeps it open whenever the client runs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedule: Vec<crate::schedule::ScheduleWindow>,
+    /// Also route HTTP requests for this domain to the tunnel through the
+    /// server's HTTP listener. The domain needs a `_nat-traversal` TXT record
+    /// for this client (see `nat-client domain-challenge`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    /// Have the server require these credentials on HTTP requests before
+    /// passing them to the tunnel, on its own port and on the domain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_auth: Option<crate::protocol::HttpAuth>,
+    /// Certificate chain and key (PEM) the server's HTTPS listener presents
+    /// for `domain`; the local service then receives plain HTTP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_cert: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_key: Option<PathBuf>,
+    /// Route HTTPS connections for `domain` by their SNI without decrypting
+    /// them, so the local service presents its own certificate and the
+    /// server never sees the plaintext
+    #[serde(default)]
+    pub tls_passthrough: bool,
+    /// Have the server answer plain-HTTP requests for `domain` with a
+    /// permanent redirect to the same URL on its HTTPS listener
+    #[serde(default)]
+    pub https_redirect: bool,
+    /// Headers the server sets on HTTP requests for `domain`, such as
+    /// `X-Forwarded-For`, before they reach the local service
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_headers: Option<crate::protocol::HttpHeaders>,
+    /// Ask the local router to forward a public port straight to this
+    /// machine, falling back to the server when no mapping can be made
+    #[serde(default)]
+    pub port_mapping: bool,
+    /// Public keys of the consumers allowed through this tunnel. When set,
+    /// connections must complete a Noise handshake with this client's
+    /// `e2e` key, so the server only carries ciphertext.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub e2e_peers: Vec<String>,
+    /// What the tunnel's traffic passes through on this client, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stages: Vec<StageConfig>,
+    /// DSCP class for the tunnel's public connections on the server and
+    /// its local service connections, in place of `sockets.tunnel.dscp`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dscp: Option<Dscp>,
+    /// Idle timeout and session cap of a UDP tunnel on the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub udp: Option<crate::protocol::UdpSessions>,
+    /// File the tunnel was loaded from when it came from a tunnel directory
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
+}
+
+/// A step of a tunnel's pipeline
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StageConfig {
+    /// Hold the tunnel's traffic, both ways and all connections together,
+    /// to this many kilobits per second
+    RateLimit { kbps: u32 },
+    /// Log the bytes each connection carried when it closes
+    Count,
+    /// Log every chunk, with up to `preview` bytes of it
+    Log {
+        #[serde(default)]
+        preview: usize,
+    },
+    /// Replace `find` with `replace` in the traffic going `direction`, or
+    /// both ways when unset
+    Rewrite {
+        find: String,
+        replace: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        direction: Option<crate::telemetry::Direction>,
+    },
+}
+
+/// Contents of a file in the tunnel definition directory
+#[derive(Debug, Default, Deserialize)]
+struct TunnelFile {
+    #[serde(default)]
+    tunnels: Vec<TunnelConfig>,
+}
+
+/// Public address discovery settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StunConfig {
+    pub enabled: bool,
+    /// Query the NAT traversal server before the public servers
+    pub use_server: bool,
+    pub servers: Vec<String>,
+    pub timeout_ms: u64,
+}
+
+impl StunConfig {
+    /// Servers to query, with the NAT traversal server first when enabled
+    pub fn server_list(&self, server: &ServerConnectionConfig) -> Vec<String> {
+        let mut servers = Vec::new();
+        if self.use_server {
+            servers.push(format!("{}:{}", server.addr, server.port));
+        }
+        servers.extend(self.servers.iter().cloned());
+        servers
+    }
+}
+
+/// Router port mapping (PCP, NAT-PMP, UPnP IGD) settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PortMappingConfig {
+    /// Gateway to ask, detected from the routing table when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gateway: Option<IpAddr>,
+    /// Requested mapping lifetime; mappings are renewed at half of it
+    pub lease_secs: u32,
+    pub timeout_ms: u64,
+}
+
+/// Buffer, frame and queue sizes for tunnel traffic
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PerformanceConfig {
+    /// Bytes read from a tunneled socket at a time
+    pub read_buffer_size: usize,
+    /// Largest control message or data frame accepted from the peer,
+    /// advertised when authenticating so the peer splits its data to fit
+    pub max_frame_size: usize,
+    /// Payloads queued for each tunneled connection before the sender
+    /// waits; 0 leaves the queue unbounded
+    pub connection_queue: usize,
+    pub flush: FlushPolicy,
+}
+
+/// TCP options for the server connection and for tunneled connections:
+/// public connections accepted by the server, local service connections
+/// opened by the client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SocketConfig {
+    pub control: SocketOptions,
+    pub tunnel: SocketOptions,
+    /// Accept loops the server runs on each tunnel port, sharing it through
+    /// SO_REUSEPORT; Linux only, elsewhere every port gets one
+    pub accept_workers: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SocketOptions {
+    /// Disable Nagle's algorithm so small interactive writes go out at once
+    pub nodelay: bool,
+    /// Idle time before TCP keepalive probes start, 0 disables them
+    pub keepalive_secs: u64,
+    /// SO_RCVBUF, left to the OS when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recv_buffer: Option<usize>,
+    /// SO_SNDBUF, left to the OS when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub send_buffer: Option<usize>,
+    /// Network interface the connections this side opens go out through,
+    /// with SO_BINDTODEVICE; Linux only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bind_interface: Option<String>,
+    /// Source address the connections this side opens are bound to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bind_addr: Option<IpAddr>,
+    /// DSCP class the packets are marked with, for routers to prioritize
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dscp: Option<Dscp>,
+}
+
+/// Shared HTTP listener that hands each request's connection to the tunnel
+/// bound to its `Host`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub enabled: bool,
+    pub bind_addr: String,
+    pub port: u16,
+    /// Require the `_nat-traversal` TXT record before a client may bind a
+    /// domain
+    pub verify_domains: bool,
+    /// Name servers for the TXT lookups; the system's when empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dns_servers: Vec<SocketAddr>,
+    /// Also accept HTTPS on this port, terminating TLS with the
+    /// certificate for the requested name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub https_port: Option<u16>,
+    /// Certificates for the HTTPS listener, in addition to those clients
+    /// upload with their tunnels
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub certificates: Vec<DomainCertificate>,
+    /// HTML answered with a 503 while a domain's client is disconnected;
+    /// `{{tunnel}}` and `{{retry_after}}` are filled in. A built-in page
+    /// is used when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offline_page: Option<PathBuf>,
+    /// Seconds visitors of the offline page are told to wait before
+    /// retrying
+    pub offline_retry_secs: u64,
+    /// Compress text responses with brotli or gzip for visitors that
+    /// accept it, unless the service already did
+    pub compress: bool,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind_addr: "0.0.0.0".to_string(),
+            port: 80,
+            verify_domains: true,
+            dns_servers: Vec::new(),
+            https_port: None,
+            certificates: Vec::new(),
+            offline_page: None,
+            offline_retry_secs: 30,
+            compress: false,
+        }
+    }
+}
+
+/// A certificate the HTTPS listener presents for `domain`, which may be a
+/// wildcard such as `*.example.com`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DomainCertificate {
+    pub domain: String,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// Local control socket used by `nat-client status` and `nat-server inspect`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ControlConfig {
+    pub enabled: bool,
+    /// Unix socket path or Windows pipe name; defaults to `nat-client.sock`
+    /// or `nat-server.sock` in the configuration directory, or
+    /// `\\.\pipe\nat-client` and `\\.\pipe\nat-server`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// Tokens that may view clients, tunnels and statistics through the
+    /// observer socket but change nothing (servers only)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub observer_tokens: Vec<String>,
+    /// Socket every local user may connect to with an observer token;
+    /// defaults to `nat-server-observer.sock` in the configuration
+    /// directory, or `\\.\pipe\nat-server-observer`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub observer_path: Option<PathBuf>,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: None,
+            observer_tokens: Vec::new(),
+            observer_path: None,
+        }
+    }
+}
+
+/// SQLite database of the clients that connected, the tunnels they opened
+/// and their sessions, kept across restarts for `nat-server inspect history`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// Database file; nothing is recorded when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database: Option<PathBuf>,
+    /// Days closed sessions and tunnels are kept, 0 keeps them forever
+    pub retention_days: u32,
+    /// How often the traffic of open sessions and tunnels is written, so
+    /// reports and restarts see it before they close
+    pub checkpoint_secs: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            database: None,
+            retention_days: 90,
+            checkpoint_secs: 300,
+        }
+    }
+}
+
+/// A Lua script the server runs for tunnels' public connections
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginConfig {
+    /// Script defining `on_connect`, `on_data` or both
+    pub script: PathBuf,
+    /// Names of the tunnels it runs for; every tunnel when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tunnels: Vec<String>,
+    /// Pass the tunnels' data through `on_data`, not only new connections
+    #[serde(default)]
+    pub data: bool,
+    /// Longest one call may run before it is stopped
+    #[serde(default = "default_plugin_time_limit_ms")]
+    pub time_limit_ms: u64,
+    /// Most memory the script may hold
+    #[serde(default = "default_plugin_memory_limit_kb")]
+    pub memory_limit_kb: usize,
+    /// What happens to a connection when a call fails or runs out of time
+    #[serde(default)]
+    pub on_error: PluginFailMode,
+}
+
+/// How a connection fares when a plugin call for it fails
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PluginFailMode {
+    /// The connection is refused, or closed when the call was for its data
+    #[default]
+    Closed,
+    /// The connection and its data go through unchanged
+    Open,
+}
+
+/// Public endpoints only one client may use, whether or not it is connected
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Reservation {
+    /// Ports or ranges, e.g. `[8443, "8600-8609"]`; tunnels of the client
+    /// that ask for no particular port get the first free one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<PortRange>,
+    /// Domains the client may serve without a TXT record
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub domains: Vec<String>,
+}
+
+/// Monthly traffic per client, counted against token quotas and saved
+/// periodically so restarts do not reset it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UsageConfig {
+    /// JSON file the counts are saved to; `usage.json` in the configuration
+    /// directory when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    pub save_interval_secs: u64,
+}
+
+impl Default for UsageConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            save_interval_secs: 60,
+        }
+    }
+}
+
+/// Requests to custom-domain tunnels kept in memory so `nat-client replay`
+/// can send them to the local service again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CaptureConfig {
+    /// Requests kept, dropping the oldest first; 0 to disable
+    pub max_requests: usize,
+    /// Requests larger than this, body included, are not kept
+    pub max_request_bytes: usize,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            max_requests: 100,
+            max_request_bytes: 64 * 1024,
+        }
+    }
+}
+
+/// The server's layer-3 VPN: clients that join exchange IP packets with
+/// it through a TUN device, reaching the server, each other and the
+/// subnets they advertise
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerVpnConfig {
+    /// Any authenticated client may join when enabled
+    pub enabled: bool,
+    /// Name of the server's TUN device
+    pub interface: String,
+    /// Overlay subnet; the server takes its first address and gives each
+    /// client one of the rest
+    pub subnet: Subnet,
+    pub mtu: u16,
+    /// Subnets each client ID may advertise, the subnets or any inside
+    /// them; they are routed through the device at startup and a client
+    /// advertising anything else is refused
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub advertise: BTreeMap<String, Vec<Subnet>>,
+}
+
+impl Default for ServerVpnConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interface: "natvpn0".to_string(),
+            subnet: Subnet {
+                addr: Ipv4Addr::new(10, 89, 0, 0),
+                prefix: 24,
+            },
+            mtu: default_vpn_mtu(),
+            advertise: BTreeMap::new(),
+        }
+    }
+}
+
+/// Joining the server's layer-3 VPN through a TUN device on the client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientVpnConfig {
+    pub enabled: bool,
+    /// Name of the client's TUN device
+    pub interface: String,
+    pub mtu: u16,
+    /// Subnets behind this client, such as its LAN, that the rest of the
+    /// VPN reaches through it
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub advertise: Vec<Subnet>,
+    /// Subnets this client sends into the VPN besides the overlay, such as
+    /// another member's LAN
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<Subnet>,
+}
+
+impl Default for ClientVpnConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interface: "natvpn0".to_string(),
+            mtu: default_vpn_mtu(),
+            advertise: Vec::new(),
+            routes: Vec::new(),
+        }
+    }
+}
+
+/// Leaves room for the TLS and TCP headers of the connection carrying
+/// the packets on a 1500-byte path
+fn default_vpn_mtu() -> u16 {
+    1400
+}
+
+/// The server as a WireGuard broker: it hands out overlay addresses,
+/// tells every client on the mesh about the others and relays their UDP
+/// when they cannot reach each other directly. Packets never pass
+/// through the server's own network stack.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerWireGuardConfig {
+    /// Any authenticated client may join when enabled
+    pub enabled: bool,
+    /// Overlay subnet clients get their addresses from
+    pub subnet: Subnet,
+    /// UDP ports relaying WireGuard traffic, one per client on the mesh
+    pub relay_ports: PortRange,
+}
+
+impl Default for ServerWireGuardConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            subnet: Subnet {
+                addr: Ipv4Addr::new(10, 90, 0, 0),
+                prefix: 24,
+            },
+            relay_ports: PortRange {
+                start: 51900,
+                end: 51999,
+            },
+        }
+    }
+}
+
+/// The administration API of `proto/admin.proto` served over gRPC, for
+/// orchestration systems with typed clients in other languages. It speaks
+/// plain HTTP/2 without TLS, bearer tokens included, so it should only
+/// listen on a loopback address.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GrpcConfig {
+    pub enabled: bool,
+    pub listen: SocketAddr,
+    /// Bearer tokens that may call every method
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tokens: Vec<String>,
+    /// Bearer tokens that may only list, read statistics and watch events
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub observer_tokens: Vec<String>,
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 7001)),
+            tokens: Vec::new(),
+            observer_tokens: Vec::new(),
+        }
+    }
+}
+
+/// The server's events pushed as JSON over a WebSocket at `/events`, for
+/// dashboards that would otherwise poll. Like the gRPC API it is plain
+/// HTTP, so it should listen on a trusted network.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EventsConfig {
+    pub enabled: bool,
+    pub listen: SocketAddr,
+    /// Tokens a watcher presents as a bearer token or as `?token=`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tokens: Vec<String>,
+    /// How often each watcher gets the traffic of every tunnel; 0 sends
+    /// none
+    pub sample_interval_secs: u64,
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 7002)),
+            tokens: Vec::new(),
+            sample_interval_secs: 5,
+        }
+    }
+}
+
+/// A listener speaking enough of frp's protocol for frpc to open TCP
+/// proxies, which become tunnels like any other client's, to ease
+/// migrating from frp
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerFrpConfig {
+    pub enabled: bool,
+    pub bind_addr: IpAddr,
+    pub port: u16,
+    /// frpc's `auth.token`; it has to be one of the server's tokens, whose
+    /// scope then applies, and also keys the encryption of frpc's control
+    /// connection
+    pub token: String,
+    /// Whether frpc multiplexes its connections over one, as frpc's
+    /// `transport.tcpMux` (on unless turned off there)
+    pub tcp_mux: bool,
+    /// Whether heartbeats and work connections must carry the token too,
+    /// as frpc sends them with `auth.additionalScopes = ["HeartBeats",
+    /// "NewWorkConns"]`
+    pub require_additional_scopes: bool,
+}
+
+impl Default for ServerFrpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 7010,
+            token: String::new(),
+            tcp_mux: true,
+            require_additional_scopes: true,
+        }
+    }
+}
+
+/// Joining the server's WireGuard mesh with a kernel WireGuard device
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientWireGuardConfig {
+    pub enabled: bool,
+    /// Name of the WireGuard device
+    pub interface: String,
+    /// UDP port the device listens on; its public address is learned by
+    /// STUN before the device takes it
+    pub listen_port: u16,
+    /// Private key, created on first use; defaults to `wireguard.key` in
+    /// the configuration directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub private_key_file: Option<PathBuf>,
+    /// Subnets behind this client that the other members route to it
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub advertise: Vec<Subnet>,
+    /// Try each peer's public address before the server's relay
+    pub direct: bool,
+    pub persistent_keepalive_secs: u16,
+}
+
+impl Default for ClientWireGuardConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interface: "natwg0".to_string(),
+            listen_port: 51820,
+            private_key_file: None,
+            advertise: Vec::new(),
+            direct: true,
+            persistent_keepalive_secs: 25,
+        }
+    }
+}
+
+/// Tunnels as remote forwards (`ssh -R`) on a plain SSH server, for hosts
+/// where only sshd is available. The client then never contacts a NAT
+/// traversal server; only TCP tunnels are carried and server-side
+/// features such as domains, TTLs and HTTP auth do not apply.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientSshConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    /// Private key to log in with, instead of ssh's defaults and agent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity_file: Option<PathBuf>,
+    /// Address the remote ports listen on at the SSH server; anything but
+    /// loopback needs `GatewayPorts clientspecified` there. Empty leaves
+    /// it to the server.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub bind_addr: String,
+    /// Extra `-o` options for ssh, e.g. `"StrictHostKeyChecking=accept-new"`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+    pub keepalive_secs: u64,
+    pub reconnect_interval_secs: u64,
+    /// The OpenSSH client to run
+    pub program: PathBuf,
+}
+
+impl Default for ClientSshConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: String::new(),
+            port: 22,
+            user: String::new(),
+            identity_file: None,
+            bind_addr: String::new(),
+            options: Vec::new(),
+            keepalive_secs: 30,
+            reconnect_interval_secs: 5,
+            program: "ssh".into(),
+        }
+    }
+}
+
+/// Alerts for unattended clients, sent by webhook and/or email
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AlertConfig {
+    /// Alert after this many connection attempts fail in a row, 0 to disable
+    pub reconnect_failures: u32,
+    /// Alert once a tunnel's local service has been unreachable this long,
+    /// 0 to disable
+    pub local_down_minutes: u64,
+    /// Alert after this many authentication failures in a row, 0 to disable
+    pub auth_failures: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<WebhookConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<EmailConfig>,
+}
+
+/// A machine this client sends Wake-on-LAN packets for when another
+/// client asks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WakeTarget {
+    pub name: String,
+    /// MAC address, e.g. `00:1a:2b:3c:4d:5e`
+    pub mac: String,
+    /// Where the magic packet is sent
+    #[serde(default = "default_wake_broadcast")]
+    pub broadcast: SocketAddr,
+    /// Client IDs allowed to wake the machine; empty allows any client of
+    /// the server
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_peers: Vec<String>,
+}
+
+/// JSON POST to an HTTP(S) endpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Extra request headers, e.g. credentials
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Email through an SMTP server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailConfig {
+    pub smtp_host: String,
+    #[serde(default = "default_smtp_port")]
+    pub smtp_port: u16,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// File holding the password, read instead of `password`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<PathBuf>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+/// How the SMTP connection is secured
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum SmtpTls {
+    /// Upgrade with STARTTLS, usually on port 587
+    #[default]
+    StartTls,
+    /// TLS from the start, usually on port 465
+    Implicit,
+    /// Plain text, only for a relay on a trusted network
+    None,
+}
+
+/// When writers flush the control and data connections
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    /// After every write, for the lowest latency. Messages queued together
+    /// still share one write.
+    #[default]
+    Always,
+    /// Once no more messages are queued, for fewer and larger TLS records
+    Batched,
 }
 
-/// Server connection configuration for client
+impl FlushPolicy {
+    /// Whether to flush after a write, given whether more messages are waiting
+    pub fn should_flush(self, more_queued: bool) -> bool {
+        match self {
+            FlushPolicy::Always => true,
+            FlushPolicy::Batched => !more_queued,
+        }
+    }
+}
+
+/// Direct client-to-client services brokered by the server
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ServerConnectionConfig {
-    pub addr: String,
-    pub port: u16,
-    pub token: String,
-    pub client_id: String,
-    pub auto_reconnect: bool,
-    pub reconnect_interval_secs: u64,
-    pub tls_verify: bool,
+#[serde(default)]
+pub struct P2pConfig {
+    /// Port other clients dial for direct sessions, 0 picks one
+    pub listen_port: u16,
+    /// Also map the listen port on the local router
+    pub port_mapping: bool,
+    /// How long to wait for a direct connection before giving up
+    pub connect_timeout_ms: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub services: Vec<PublishedService>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub subscriptions: Vec<ServiceSubscription>,
 }
 
-/// Tunnel configuration for client
+/// Local service offered to other clients
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct TunnelConfig {
+pub struct PublishedService {
     pub name: String,
+    #[serde(default = "crate::protocol::default_local_host")]
+    pub local_host: String,
     pub local_port: u16,
-    pub remote_port: Option<u16>,
-    pub protocol: crate::protocol::TunnelProtocol,
-    pub auto_start: bool,
+    /// Shared with subscribers and never sent to the server
+    pub secret: String,
+}
+
+/// Remote service made available on a local port
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceSubscription {
+    pub name: String,
+    #[serde(default = "crate::protocol::default_local_host")]
+    pub bind_host: String,
+    pub bind_port: u16,
+    pub secret: String,
 }
 
 /// GUI configuration
@@ -102,30 +1519,71 @@ impl Default for ServerConfig {
                 bind_addr: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
                 port: 7000,
                 max_connections: 1000,
+                stun_enabled: true,
+                run_as_user: None,
+                run_as_group: None,
             },
             tls: TlsConfig {
                 cert_path: "server.crt".into(),
                 key_path: "server.key".into(),
                 ca_path: None,
                 verify_client: false,
+                policy: TlsPolicy::default(),
             },
             auth: AuthConfig {
                 tokens: vec!["default-token".to_string()],
+                tokens_file: None,
                 require_auth: true,
                 max_clients_per_token: Some(10),
+                allow_plain_tokens: false,
+                jwt: None,
+                scopes: BTreeMap::new(),
+                duplicate_client_id: DuplicateClientId::TakeOver,
+                motd: None,
             },
             limits: LimitsConfig {
                 max_tunnels_per_client: 10,
                 max_bandwidth_mbps: None,
                 max_connections_per_tunnel: 100,
                 connection_timeout_secs: 300,
+                max_relays_per_client: default_max_relays_per_client(),
+                relay_idle_timeout_secs: default_relay_idle_timeout_secs(),
+                max_data_connections: default_max_data_connections(),
+                max_ports_per_tunnel: default_max_ports_per_tunnel(),
+                max_share_ttl_secs: default_max_share_ttl_secs(),
+                heartbeat_timeout_secs: default_heartbeat_timeout_secs(),
+                resume_window_secs: default_resume_window_secs(),
+                resume_buffer_kb: default_resume_buffer_kb(),
+                max_connection_buffer_kb: default_max_connection_buffer_kb(),
+                max_total_tunnels: None,
+                max_total_connections: None,
+                max_ports_in_use: None,
             },
             logging: LoggingConfig {
                 level: "info".to_string(),
                 file: None,
                 max_size_mb: 100,
                 max_files: 5,
+                otlp: None,
+                journald: false,
+                syslog: None,
+                allow_remote: true,
             },
+            performance: PerformanceConfig::default(),
+            sockets: SocketConfig::default(),
+            control: ControlConfig::default(),
+            ca: None,
+            http: HttpConfig::default(),
+            storage: StorageConfig::default(),
+            usage: UsageConfig::default(),
+            reservations: BTreeMap::new(),
+            provisioned: BTreeMap::new(),
+            plugins: Vec::new(),
+            vpn: ServerVpnConfig::default(),
+            wireguard: ServerWireGuardConfig::default(),
+            grpc: GrpcConfig::default(),
+            events: EventsConfig::default(),
+            frp: ServerFrpConfig::default(),
         }
     }
 }
@@ -133,14 +1591,28 @@ impl Default for ServerConfig {
 impl Default for ClientConfig {
     fn default() -> Self {
         Self {
+            default_profile: None,
+            tunnels_dir: None,
             server: ServerConnectionConfig {
                 addr: "localhost".to_string(),
                 port: 7000,
+                server_name: None,
                 token: "default-token".to_string(),
+                token_file: None,
                 client_id: "default-client".to_string(),
                 auto_reconnect: true,
                 reconnect_interval_secs: 30,
                 tls_verify: true,
+                data_channel: true,
+                data_connections: default_data_connections(),
+                tls: TlsPolicy::default(),
+                client_cert: None,
+                client_key: None,
+                heartbeat_interval_secs: default_heartbeat_interval_secs(),
+                heartbeat_max_missed: default_heartbeat_max_missed(),
+                resume_window_secs: default_resume_window_secs(),
+                resume_buffer_kb: default_resume_buffer_kb(),
+                max_connection_buffer_kb: default_max_connection_buffer_kb(),
             },
             tunnels: vec![],
             gui: GuiConfig {
@@ -154,13 +1626,498 @@ impl Default for ClientConfig {
                 file: None,
                 max_size_mb: 50,
                 max_files: 3,
+                otlp: None,
+                journald: false,
+                syslog: None,
+                allow_remote: true,
             },
+            stun: StunConfig::default(),
+            port_mapping: PortMappingConfig::default(),
+            p2p: P2pConfig::default(),
+            e2e: E2eConfig::default(),
+            performance: PerformanceConfig::default(),
+            sockets: SocketConfig::default(),
+            control: ControlConfig::default(),
+            capture: CaptureConfig::default(),
+            alerts: None,
+            wake_on_lan: Vec::new(),
+            vpn: ClientVpnConfig::default(),
+            wireguard: ClientWireGuardConfig::default(),
+            ssh: ClientSshConfig::default(),
+            profiles: BTreeMap::new(),
+            active_profile: None,
+            config_path: None,
+        }
+    }
+}
+
+impl Default for StunConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            use_server: true,
+            servers: vec![
+                "stun.l.google.com:19302".to_string(),
+                "stun.cloudflare.com:3478".to_string(),
+            ],
+            timeout_ms: 1500,
+        }
+    }
+}
+
+impl Default for PortMappingConfig {
+    fn default() -> Self {
+        Self {
+            gateway: None,
+            lease_secs: 3600,
+            timeout_ms: 2000,
+        }
+    }
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: "http://localhost:4318".to_string(),
+            service_name: None,
+            traces: true,
+            metrics: true,
+            metrics_interval_secs: 30,
+            headers: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self {
+            reconnect_failures: 5,
+            local_down_minutes: 5,
+            auth_failures: 1,
+            webhook: None,
+            email: None,
+        }
+    }
+}
+
+impl Default for PerformanceConfig {
+    fn default() -> Self {
+        Self {
+            read_buffer_size: 8192,
+            max_frame_size: crate::protocol::DEFAULT_MAX_FRAME_SIZE,
+            connection_queue: 0,
+            flush: FlushPolicy::Always,
+        }
+    }
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        Self {
+            control: SocketOptions::default(),
+            tunnel: SocketOptions::default(),
+            accept_workers: 1,
+        }
+    }
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive_secs: 60,
+            recv_buffer: None,
+            send_buffer: None,
+            bind_interface: None,
+            bind_addr: None,
+            dscp: None,
+        }
+    }
+}
+
+impl Default for P2pConfig {
+    fn default() -> Self {
+        Self {
+            listen_port: 0,
+            port_mapping: false,
+            connect_timeout_ms: 10_000,
+            services: Vec::new(),
+            subscriptions: Vec::new(),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Return a copy of this configuration with the named profile's server
+    /// settings and tunnels in place of the top-level ones
+    pub fn with_profile(&self, name: &str) -> anyhow::Result<ClientConfig> {
+        let profile = self.profiles.get(name).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Unknown profile '{}' (available: {})",
+                name,
+                self.profile_names().join(", ")
+            )
+        })?;
+
+        let mut config = self.clone();
+        config.server = profile.server.clone();
+        config.tunnels = profile.tunnels.clone();
+        config.active_profile = Some(name.to_string());
+        Ok(config)
+    }
+
+    /// Apply the requested profile, falling back to `default_profile`
+    pub fn resolve_profile(&self, requested: Option<&str>) -> anyhow::Result<ClientConfig> {
+        match requested.or(self.default_profile.as_deref()) {
+            Some(name) => self.with_profile(name),
+            None => Ok(self.clone()),
+        }
+    }
+
+    /// Write the active server settings and tunnels back to where they came
+    /// from: the active profile if one is selected, the top level otherwise
+    pub fn store_active(&mut self, active: &ClientConfig) {
+        match active
+            .active_profile
+            .as_ref()
+            .and_then(|name| self.profiles.get_mut(name))
+        {
+            Some(profile) => {
+                profile.server = active.server.clone();
+                profile.tunnels = active.own_tunnels();
+            }
+            None => {
+                self.server = active.server.clone();
+                self.tunnels = active.own_tunnels();
+            }
+        }
+        self.gui = active.gui.clone();
+        self.logging = active.logging.clone();
+    }
+
+    pub fn profile_names(&self) -> Vec<String> {
+        self.profiles.keys().cloned().collect()
+    }
+
+    /// Append the tunnels defined in every `*.toml` file of `dir`, in file
+    /// name order. A missing directory is not an error.
+    pub fn merge_tunnel_dir(&mut self, dir: &Path) -> anyhow::Result<()> {
+        for tunnel in load_tunnel_dir(dir)? {
+            if let Some(existing) = self.tunnels.iter().find(|t| t.name == tunnel.name) {
+                let defined_in = existing
+                    .source
+                    .as_ref()
+                    .map(|p| p.display().to_string())
+                    .unwrap_or_else(|| "the main configuration".to_string());
+                return Err(anyhow::anyhow!(
+                    "Tunnel '{}' in {} is already defined in {}",
+                    tunnel.name,
+                    tunnel.source.as_deref().unwrap_or(dir).display(),
+                    defined_in
+                ));
+            }
+            self.tunnels.push(tunnel);
+        }
+        Ok(())
+    }
+
+    /// Tunnels that belong in the main configuration file
+    fn own_tunnels(&self) -> Vec<TunnelConfig> {
+        self.tunnels
+            .iter()
+            .filter(|t| t.source.is_none())
+            .cloned()
+            .collect()
+    }
+}
+
+/// Load tunnel definitions from a conf.d-style directory
+pub fn load_tunnel_dir(dir: &Path) -> anyhow::Result<Vec<TunnelConfig>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "toml"))
+        .collect();
+    files.sort();
+
+    let mut tunnels = Vec::new();
+    for path in files {
+        let content = std::fs::read_to_string(&path)?;
+        let file: TunnelFile = toml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", path.display(), e))?;
+        for mut tunnel in file.tunnels {
+            tunnel.source = Some(path.clone());
+            tunnels.push(tunnel);
+        }
+    }
+
+    Ok(tunnels)
+}
+
+impl AuthConfig {
+    /// Inline tokens plus those listed in `tokens_file`
+    pub fn load_tokens(&self) -> anyhow::Result<Vec<String>> {
+        let mut tokens = self.tokens.clone();
+        if let Some(path) = &self.tokens_file {
+            let content = read_secret_file(path)?;
+            tokens.extend(
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::to_string),
+            );
+        }
+        Ok(tokens)
+    }
+}
+
+impl LimitsConfig {
+    /// How long and how much to hold a dropped client's connections
+    pub fn resume_limits(&self) -> crate::resume::ResumeLimits {
+        crate::resume::ResumeLimits::new(self.resume_window_secs, self.resume_buffer_kb)
+    }
+
+    /// Bytes each tunneled connection may queue, 0 for no cap
+    pub fn connection_buffer(&self) -> usize {
+        self.max_connection_buffer_kb.saturating_mul(1024)
+    }
+}
+
+impl JwtConfig {
+    /// HS256 secret, read from `secret_file` when configured
+    pub fn load_secret(&self) -> anyhow::Result<Option<String>> {
+        match &self.secret_file {
+            Some(path) => {
+                let secret = read_secret_file(path)?.trim().to_string();
+                if secret.is_empty() {
+                    return Err(anyhow::anyhow!("Secret file {} is empty", path.display()));
+                }
+                Ok(Some(secret))
+            }
+            None => Ok(self.secret.clone()),
+        }
+    }
+}
+
+impl E2eConfig {
+    /// Private key, read from `private_key_file` when configured
+    pub fn load_private_key(&self) -> anyhow::Result<Option<String>> {
+        match &self.private_key_file {
+            Some(path) => Ok(Some(read_secret_file(path)?.trim().to_string())),
+            None => Ok(self.private_key.clone()),
+        }
+    }
+}
+
+impl ServerConnectionConfig {
+    /// `addr` without the brackets an IPv6 address may be written in
+    pub fn host(&self) -> &str {
+        self.addr
+            .strip_prefix('[')
+            .and_then(|addr| addr.strip_suffix(']'))
+            .unwrap_or(&self.addr)
+    }
+
+    /// `host:port` to dial `port` on the server, with an IPv6 address
+    /// bracketed
+    pub fn endpoint(&self, port: u16) -> String {
+        match self.host() {
+            host if host.parse::<Ipv6Addr>().is_ok() => format!("[{}]:{}", host, port),
+            host => format!("{}:{}", host, port),
+        }
+    }
+
+    /// The name TLS connections to the server verify and ask for; an IP
+    /// address needs to be in the certificate's subjectAltName
+    pub fn tls_name(&self) -> &str {
+        self.server_name.as_deref().unwrap_or(self.host())
+    }
+
+    /// The heartbeat schedule to follow and advertise to the server
+    pub fn heartbeat(&self) -> crate::protocol::HeartbeatSettings {
+        crate::protocol::HeartbeatSettings {
+            interval_secs: self.heartbeat_interval_secs.max(1),
+            max_missed: self.heartbeat_max_missed,
+        }
+    }
+
+    /// How long and how much to hold connections for a reconnect
+    pub fn resume_limits(&self) -> crate::resume::ResumeLimits {
+        crate::resume::ResumeLimits::new(self.resume_window_secs, self.resume_buffer_kb)
+    }
+
+    /// Bytes each tunneled connection may queue, 0 for no cap
+    pub fn connection_buffer(&self) -> usize {
+        self.max_connection_buffer_kb.saturating_mul(1024)
+    }
+
+    /// Token to authenticate with, read from `token_file` when configured
+    pub fn load_token(&self) -> anyhow::Result<String> {
+        match &self.token_file {
+            Some(path) => {
+                let token = read_secret_file(path)?.trim().to_string();
+                if token.is_empty() {
+                    return Err(anyhow::anyhow!("Token file {} is empty", path.display()));
+                }
+                Ok(token)
+            }
+            None => Ok(self.token.clone()),
+        }
+    }
+}
+
+impl TunnelConfig {
+    /// The certificate to upload for `domain`, read from `tls_cert` and
+    /// `tls_key`
+    pub fn load_tls_certificate(&self) -> anyhow::Result<Option<crate::protocol::TlsCertificate>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some(crate::protocol::TlsCertificate {
+                cert_pem: std::fs::read_to_string(cert)
+                    .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", cert.display(), e))?,
+                key_pem: read_secret_file(key)?,
+            })),
+            (None, None) => Ok(None),
+            _ => Err(anyhow::anyhow!("tls_cert and tls_key must be set together")),
+        }
+    }
+}
+
+impl EmailConfig {
+    /// SMTP password, read from `password_file` when configured
+    pub fn load_password(&self) -> anyhow::Result<Option<String>> {
+        match &self.password_file {
+            Some(path) => Ok(Some(read_secret_file(path)?.trim().to_string())),
+            None => Ok(self.password.clone()),
+        }
+    }
+}
+
+/// Read a file holding secrets, warning when other users can read it
+fn read_secret_file(path: &Path) -> anyhow::Result<String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Ok(metadata) = std::fs::metadata(path) {
+            if metadata.permissions().mode() & 0o077 != 0 {
+                tracing::warn!(
+                    "Secret file {} is accessible by other users; consider chmod 600",
+                    path.display()
+                );
+            }
         }
     }
+
+    Ok(content)
+}
+
+static PORTABLE: AtomicBool = AtomicBool::new(false);
+
+/// Marker file that enables portable mode when placed beside the executable
+pub const PORTABLE_MARKER: &str = "portable.toml";
+
+/// Force portable mode, keeping configuration next to the executable
+pub fn set_portable(portable: bool) {
+    PORTABLE.store(portable, Ordering::Relaxed);
+}
+
+/// Directory holding the executable when running in portable mode
+pub fn portable_dir() -> Option<PathBuf> {
+    let exe_dir = std::env::current_exe().ok()?.parent()?.to_path_buf();
+    if PORTABLE.load(Ordering::Relaxed) || exe_dir.join(PORTABLE_MARKER).exists() {
+        Some(exe_dir)
+    } else {
+        None
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_relays_per_client() -> u32 {
+    4
+}
+
+fn default_relay_idle_timeout_secs() -> u64 {
+    300
+}
+
+fn default_max_data_connections() -> u32 {
+    8
+}
+
+fn default_max_ports_per_tunnel() -> u16 {
+    100
+}
+
+fn default_wake_broadcast() -> SocketAddr {
+    SocketAddr::from(([255, 255, 255, 255], 9))
+}
+
+fn default_max_share_ttl_secs() -> u64 {
+    86400
+}
+
+/// Three missed heartbeats at the client's default interval, for clients
+/// that do not say how often they send them
+fn default_heartbeat_timeout_secs() -> u64 {
+    default_heartbeat_interval_secs() * default_heartbeat_max_missed() as u64
+}
+
+fn default_plugin_time_limit_ms() -> u64 {
+    10
+}
+
+fn default_plugin_memory_limit_kb() -> usize {
+    4096
+}
+
+fn default_heartbeat_interval_secs() -> u64 {
+    30
+}
+
+fn default_heartbeat_max_missed() -> u32 {
+    3
+}
+
+/// Outlasts the client's default reconnect interval
+fn default_resume_window_secs() -> u64 {
+    60
+}
+
+fn default_resume_buffer_kb() -> usize {
+    256
+}
+
+fn default_max_connection_buffer_kb() -> usize {
+    8192
+}
+
+fn default_jwt_leeway_secs() -> u64 {
+    60
+}
+
+fn default_rotation_ttl_hours() -> u64 {
+    720
+}
+
+fn default_data_connections() -> usize {
+    1
 }
 
 /// Cross-platform configuration paths
 pub fn get_config_dir() -> anyhow::Result<PathBuf> {
+    if let Some(dir) = portable_dir() {
+        return Ok(dir);
+    }
+
     let dirs = directories::ProjectDirs::from("com", "nat-traversal", "nat-traversal")
         .ok_or_else(|| anyhow::anyhow!("Failed to get project directories"))?;
 
@@ -185,6 +2142,79 @@ where
     }
 }
 
+/// Apply `section.key=value` overrides (e.g. from `--set`) to a configuration.
+///
+/// Keys are dotted paths into the TOML representation, with numeric segments
+/// indexing arrays (`tunnels.0.local_port=8080`). Values are parsed as TOML
+/// and fall back to plain strings. Fields that are not serialized are reset
+/// to their defaults, so callers must restore them if needed.
+pub fn apply_overrides<T>(config: &T, overrides: &[String]) -> anyhow::Result<T>
+where
+    T: Serialize + for<'de> Deserialize<'de>,
+{
+    let mut root = toml::Value::try_from(config)?;
+
+    for assignment in overrides {
+        let (key, raw) = assignment.split_once('=').ok_or_else(|| {
+            anyhow::anyhow!("Invalid override '{}', expected key=value", assignment)
+        })?;
+        let value = parse_override_value(raw.trim());
+        set_value(&mut root, key.trim(), value)
+            .map_err(|e| anyhow::anyhow!("Invalid override '{}': {}", assignment, e))?;
+    }
+
+    Ok(root.try_into()?)
+}
+
+fn parse_override_value(raw: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(raw.to_string()))
+}
+
+fn set_value(root: &mut toml::Value, key: &str, value: toml::Value) -> anyhow::Result<()> {
+    let segments: Vec<&str> = key.split('.').collect();
+    if segments.iter().any(|s| s.is_empty()) {
+        return Err(anyhow::anyhow!("empty key segment"));
+    }
+
+    let (last, parents) = segments
+        .split_last()
+        .expect("split always yields a segment");
+    let mut current = root;
+    for segment in parents {
+        current = match current {
+            toml::Value::Table(table) => table
+                .entry(segment.to_string())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new())),
+            toml::Value::Array(array) => {
+                let index: usize = segment.parse()?;
+                array
+                    .get_mut(index)
+                    .ok_or_else(|| anyhow::anyhow!("index {} out of range", index))?
+            }
+            _ => return Err(anyhow::anyhow!("'{}' is not a table", segment)),
+        };
+    }
+
+    match current {
+        toml::Value::Table(table) => {
+            table.insert(last.to_string(), value);
+        }
+        toml::Value::Array(array) => {
+            let index: usize = last.parse()?;
+            let slot = array
+                .get_mut(index)
+                .ok_or_else(|| anyhow::anyhow!("index {} out of range", index))?;
+            *slot = value;
+        }
+        _ => return Err(anyhow::anyhow!("cannot set '{}' on a plain value", last)),
+    }
+
+    Ok(())
+}
+
 /// Save configuration to file
 pub fn save_config<T>(config: &T, file_name: &str) -> anyhow::Result<()>
 where
@@ -197,3 +2227,255 @@ where
     std::fs::write(&config_path, content)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_with_profiles() -> ClientConfig {
+        let mut config = ClientConfig::default();
+        let mut office = config.server.clone();
+        office.addr = "office.example.com".to_string();
+        config.profiles.insert(
+            "office".to_string(),
+            ProfileConfig {
+                server: office,
+                tunnels: vec![TunnelConfig {
+                    name: "ssh".to_string(),
+                    local_host: "127.0.0.1".to_string(),
+                    local_port: 22,
+                    remote_port: None,
+                    port_count: 1,
+                    protocol: crate::protocol::TunnelProtocol::Tcp,
+                    auto_start: true,
+                    ttl_secs: None,
+                    schedule: Vec::new(),
+                    domain: None,
+                    http_auth: None,
+                    tls_cert: None,
+                    tls_key: None,
+                    tls_passthrough: false,
+                    https_redirect: false,
+                    http_headers: None,
+                    port_mapping: false,
+                    e2e_peers: Vec::new(),
+                    stages: Vec::new(),
+                    dscp: None,
+                    udp: None,
+                    source: None,
+                }],
+            },
+        );
+        config
+    }
+
+    #[test]
+    fn test_dscp_names() {
+        let parse = |s: &str| s.parse::<Dscp>().map(Dscp::value);
+        assert_eq!(parse("EF"), Ok(46));
+        assert_eq!(parse("af41"), Ok(34));
+        assert_eq!(parse("AF11"), Ok(10));
+        assert_eq!(parse("CS1"), Ok(8));
+        assert_eq!(parse("LE"), Ok(1));
+        assert_eq!(parse("26"), Ok(26));
+        assert!(parse("AF44").is_err());
+        assert!(parse("CS8").is_err());
+        assert!(parse("64").is_err());
+        assert_eq!(Dscp::new(46).unwrap().tos(), 0xb8);
+
+        let options: SocketOptions = toml::from_str("dscp = \"EF\"").unwrap();
+        assert_eq!(options.dscp.map(Dscp::value), Some(46));
+        let options: SocketOptions = toml::from_str("dscp = 8").unwrap();
+        assert_eq!(options.dscp.map(Dscp::value), Some(8));
+        assert!(toml::from_str::<SocketOptions>("dscp = 64").is_err());
+    }
+
+    #[test]
+    fn test_server_endpoint() {
+        let mut server = ClientConfig::default().server;
+        server.addr = "nat.example.com".to_string();
+        assert_eq!(server.endpoint(7000), "nat.example.com:7000");
+        assert_eq!(server.tls_name(), "nat.example.com");
+
+        server.addr = "203.0.113.5".to_string();
+        assert_eq!(server.endpoint(7000), "203.0.113.5:7000");
+        assert_eq!(server.tls_name(), "203.0.113.5");
+
+        for addr in ["2001:db8::1", "[2001:db8::1]"] {
+            server.addr = addr.to_string();
+            assert_eq!(server.host(), "2001:db8::1");
+            assert_eq!(server.endpoint(7000), "[2001:db8::1]:7000");
+            assert_eq!(server.tls_name(), "2001:db8::1");
+        }
+
+        server.server_name = Some("nat.example.com".to_string());
+        assert_eq!(server.tls_name(), "nat.example.com");
+    }
+
+    #[test]
+    fn test_profile_selection() {
+        let config = config_with_profiles();
+
+        let office = config.resolve_profile(Some("office")).unwrap();
+        assert_eq!(office.server.addr, "office.example.com");
+        assert_eq!(office.tunnels.len(), 1);
+        assert_eq!(office.active_profile.as_deref(), Some("office"));
+
+        let base = config.resolve_profile(None).unwrap();
+        assert_eq!(base.server.addr, "localhost");
+        assert!(base.active_profile.is_none());
+
+        assert!(config.resolve_profile(Some("missing")).is_err());
+    }
+
+    #[test]
+    fn test_profile_round_trip() {
+        let mut config = config_with_profiles();
+        config.default_profile = Some("office".to_string());
+
+        let content = toml::to_string_pretty(&config).unwrap();
+        let parsed: ClientConfig = toml::from_str(&content).unwrap();
+        assert_eq!(parsed.profile_names(), vec!["office".to_string()]);
+
+        let mut active = parsed.resolve_profile(None).unwrap();
+        active.server.port = 7443;
+
+        let mut on_disk = parsed.clone();
+        on_disk.store_active(&active);
+        assert_eq!(on_disk.server.port, 7000);
+        assert_eq!(on_disk.profiles["office"].server.port, 7443);
+    }
+
+    #[test]
+    fn test_apply_overrides() {
+        let config = ServerConfig::default();
+        let overrides = vec![
+            "network.port=7443".to_string(),
+            "network.bind_addr=127.0.0.1".to_string(),
+            "auth.tokens=[\"a\", \"b\"]".to_string(),
+            "logging.file=/var/log/nat-server.log".to_string(),
+            "limits.max_bandwidth_mbps = 100".to_string(),
+        ];
+
+        let config = apply_overrides(&config, &overrides).unwrap();
+        assert_eq!(config.network.port, 7443);
+        assert_eq!(config.network.bind_addr.to_string(), "127.0.0.1");
+        assert_eq!(config.auth.tokens, vec!["a", "b"]);
+        assert_eq!(
+            config.logging.file,
+            Some(PathBuf::from("/var/log/nat-server.log"))
+        );
+        assert_eq!(config.limits.max_bandwidth_mbps, Some(100));
+
+        assert!(apply_overrides(&config, &["network.port=abc".to_string()]).is_err());
+        assert!(apply_overrides(&config, &["network.port".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_token_scope() {
+        let auth: AuthConfig = toml::from_str(
+            r#"
+            tokens = ["kiosk"]
+            require_auth = true
+
+            [scopes.kiosk]
+            protocols = ["Tcp"]
+            ports = ["8000-8099", 9443]
+            max_tunnels = 2
+            "#,
+        )
+        .unwrap();
+        let scope = &auth.scopes["kiosk"];
+        assert!(scope.allows_protocol(crate::protocol::TunnelProtocol::Tcp));
+        assert!(!scope.allows_protocol(crate::protocol::TunnelProtocol::Udp));
+        assert!(scope.allows_port(8050));
+        assert!(scope.allows_port(9443));
+        assert!(!scope.allows_port(8100));
+        assert!(TokenScope::default().allows_port(1));
+        assert_eq!(scope.ports[0].to_string(), "8000-8099");
+        assert_eq!(scope.ports[1].to_string(), "9443");
+
+        assert!("9000-8000".parse::<PortRange>().is_err());
+        assert!("80-x".parse::<PortRange>().is_err());
+    }
+
+    #[test]
+    fn test_plain_tokens_off_by_default() {
+        assert!(!ServerConfig::default().auth.allow_plain_tokens);
+        let auth: AuthConfig =
+            toml::from_str("tokens = [\"t\"]\nrequire_auth = true\nmax_clients_per_token = 1")
+                .unwrap();
+        assert!(!auth.allow_plain_tokens);
+        let auth: AuthConfig = toml::from_str(
+            "tokens = []\nrequire_auth = true\nmax_clients_per_token = 1\nallow_plain_tokens = true",
+        )
+        .unwrap();
+        assert!(auth.allow_plain_tokens);
+    }
+
+    #[test]
+    fn test_token_files() {
+        let dir = std::env::temp_dir().join(format!("nat-tokens-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let tokens_file = dir.join("tokens");
+        std::fs::write(&tokens_file, "# fleet tokens\nalpha\n\n  beta  \n").unwrap();
+        let mut auth = ServerConfig::default().auth;
+        auth.tokens_file = Some(tokens_file);
+        assert_eq!(
+            auth.load_tokens().unwrap(),
+            vec!["default-token", "alpha", "beta"]
+        );
+
+        let token_file = dir.join("token");
+        std::fs::write(&token_file, "secret\n").unwrap();
+        let mut server = ClientConfig::default().server;
+        assert_eq!(server.load_token().unwrap(), "default-token");
+        server.token_file = Some(token_file);
+        assert_eq!(server.load_token().unwrap(), "secret");
+
+        let secret_file = dir.join("jwt.secret");
+        std::fs::write(&secret_file, "signing-key\n").unwrap();
+        let mut jwt: JwtConfig = toml::from_str("secret = \"inline\"").unwrap();
+        assert_eq!(jwt.leeway_secs, 60);
+        assert_eq!(jwt.load_secret().unwrap().as_deref(), Some("inline"));
+        jwt.secret_file = Some(secret_file);
+        assert_eq!(jwt.load_secret().unwrap().as_deref(), Some("signing-key"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tunnel_dir_merge() {
+        let dir = std::env::temp_dir().join(format!("nat-tunnels-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("20-web.toml"),
+            "[[tunnels]]\nname = \"web\"\nlocal_port = 8080\nprotocol = \"Tcp\"\nauto_start = true\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("10-db.toml"),
+            "[[tunnels]]\nname = \"db\"\nlocal_port = 5432\nprotocol = \"Tcp\"\nauto_start = false\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("README"), "ignored").unwrap();
+
+        let mut config = config_with_profiles()
+            .resolve_profile(Some("office"))
+            .unwrap();
+        config.merge_tunnel_dir(&dir).unwrap();
+        let names: Vec<_> = config.tunnels.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["ssh", "db", "web"]);
+
+        // Directory tunnels are never written back into the main file
+        let mut on_disk = config_with_profiles();
+        on_disk.store_active(&config);
+        assert_eq!(on_disk.profiles["office"].tunnels.len(), 1);
+
+        // Duplicate names are rejected
+        assert!(config.merge_tunnel_dir(&dir).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+//! Local control socket for querying and managing a running process.
+//!
+//! `nat-client status` and `nat-server inspect` talk to the running process
+//! through a Unix domain socket in the configuration directory, or a named
+//! pipe on Windows. Requests and responses are JSON with the same 4-byte
+//! length prefix the server protocol uses. Access is limited to the socket's
+//! owner, so nothing here is authenticated; a shared socket leaves checking
+//! credentials to the requests it serves.
+
+use crate::config::get_config_dir;
+use serde::{de::DeserializeOwned, Serialize};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, info};
+
+/// Largest request or response accepted on the control socket
+const MAX_FRAME: usize = 1024 * 1024;
+
+/// Default socket for the program called `name`: `<name>.sock` in the
+/// configuration directory, or `\\.\pipe\<name>` on Windows
+pub fn default_path(name: &str) -> anyhow::Result<PathBuf> {
+    if cfg!(windows) {
+        Ok(PathBuf::from(format!(r"\\.\pipe\{}", name)))
+    } else {
+        Ok(get_config_dir()?.join(format!("{}.sock", name)))
+    }
+}
+
+/// A bound control socket, ready to serve
+pub struct ControlListener {
+    #[cfg(unix)]
+    listener: tokio::net::UnixListener,
+    #[cfg(windows)]
+    server: tokio::net::windows::named_pipe::NamedPipeServer,
+    /// Pipe name, for creating the instance that takes the next client
+    #[cfg(windows)]
+    path: PathBuf,
+}
+
+impl ControlListener {
+    /// Bind `path`, replacing a socket left behind by a process that did not
+    /// exit cleanly but never one another process is still serving
+    #[cfg(unix)]
+    pub async fn bind(path: &Path) -> anyhow::Result<Self> {
+        Self::bind_mode(path, 0o600).await
+    }
+
+    /// Bind `path` so every local user can connect, for requests that
+    /// carry their own credentials
+    #[cfg(unix)]
+    pub async fn bind_shared(path: &Path) -> anyhow::Result<Self> {
+        Self::bind_mode(path, 0o666).await
+    }
+
+    #[cfg(unix)]
+    async fn bind_mode(path: &Path, mode: u32) -> anyhow::Result<Self> {
+        use std::os::unix::fs::PermissionsExt;
+
+        if path.exists() {
+            if tokio::net::UnixStream::connect(path).await.is_ok() {
+                return Err(anyhow::anyhow!("another process is already serving it"));
+            }
+            std::fs::remove_file(path)?;
+        }
+
+        let listener = tokio::net::UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        info!("Control socket listening on {}", path.display());
+        Ok(Self { listener })
+    }
+
+    /// Create the first instance of pipe `path`, failing if another process
+    /// already owns it
+    #[cfg(windows)]
+    pub async fn bind(path: &Path) -> anyhow::Result<Self> {
+        use tokio::net::windows::named_pipe::ServerOptions;
+
+        let server = ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(path)?;
+        info!("Control pipe listening on {}", path.display());
+        Ok(Self {
+            path: path.to_path_buf(),
+            server,
+        })
+    }
+
+    /// Named pipes take the default security, as with `bind`
+    #[cfg(windows)]
+    pub async fn bind_shared(path: &Path) -> anyhow::Result<Self> {
+        Self::bind(path).await
+    }
+
+    /// Answer each request with `handler` until the socket fails
+    pub async fn serve<Req, Resp, H, Fut>(self, handler: H) -> anyhow::Result<()>
+    where
+        Req: DeserializeOwned + Send + 'static,
+        Resp: Serialize + Send + 'static,
+        H: Fn(Req) -> Fut + Clone + Send + Sync + 'static,
+        Fut: Future<Output = Resp> + Send + 'static,
+    {
+        #[cfg(unix)]
+        loop {
+            let (stream, _) = self.listener.accept().await?;
+            tokio::spawn(handle(stream, handler.clone()));
+        }
+
+        #[cfg(windows)]
+        {
+            use tokio::net::windows::named_pipe::ServerOptions;
+
+            let mut server = self.server;
+            loop {
+                server.connect().await?;
+                let stream = server;
+                server = ServerOptions::new().create(&self.path)?;
+                tokio::spawn(handle(stream, handler.clone()));
+            }
+        }
+    }
+}
+
+async fn handle<S, Req, Resp, H, Fut>(mut stream: S, handler: H)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+    H: Fn(Req) -> Fut,
+    Fut: Future<Output = Resp>,
+{
+    let result: anyhow::Result<()> = async {
+        while let Some(request) = read_frame(&mut stream).await? {
+            let response = encode_frame(&handler(request).await)?;
+            write_frame(&mut stream, &response).await?;
+        }
+        Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        debug!("Control connection ended: {}", e);
+    }
+}
+
+/// Send one request to the process serving `path`
+pub async fn request<Req, Resp>(path: &Path, request: &Req) -> anyhow::Result<Resp>
+where
+    Req: Serialize,
+    Resp: DeserializeOwned,
+{
+    #[cfg(unix)]
+    let mut stream = tokio::net::UnixStream::connect(path).await?;
+    #[cfg(windows)]
+    let mut stream = tokio::net::windows::named_pipe::ClientOptions::new().open(path)?;
+
+    write_frame(&mut stream, &encode_frame(request)?).await?;
+    read_frame(&mut stream)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("control connection closed without a response"))
+}
+
+fn encode_frame<T: Serialize>(value: &T) -> anyhow::Result<Vec<u8>> {
+    let data = serde_json::to_vec(value)?;
+    let mut frame = Vec::with_capacity(4 + data.len());
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&data);
+    Ok(frame)
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &[u8]) -> anyhow::Result<()> {
+    writer.write_all(frame).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn read_frame<R, T>(reader: &mut R) -> anyhow::Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let mut len_buf = [0u8; 4];
+    match reader.read_exact(&mut len_buf).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > MAX_FRAME {
+        return Err(anyhow::anyhow!("Control frame too large: {} bytes", len));
+    }
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data).await?;
+    Ok(Some(serde_json::from_slice(&data)?))
+}
@@ -1,4 +1,5 @@
 use rand::Rng;
+use ring::hmac;
 use sha2::{Digest, Sha256};
 
 /// Generate a secure random token
@@ -20,6 +21,31 @@ pub fn verify_token(token: &str, hash: &str) -> bool {
     hash_token(token) == hash
 }
 
+/// Random nonce for a challenge-response authentication
+pub fn generate_nonce() -> String {
+    generate_token()
+}
+
+/// Proof that the holder of `token` answered `nonce` as `client_id`
+pub fn auth_proof(token: &str, nonce: &str, client_id: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, token.as_bytes());
+    hex::encode(hmac::sign(&key, &proof_message(nonce, client_id)))
+}
+
+/// Check an `auth_proof` in constant time
+pub fn verify_auth_proof(token: &str, nonce: &str, client_id: &str, proof: &str) -> bool {
+    let Ok(proof) = hex::decode(proof) else {
+        return false;
+    };
+    let key = hmac::Key::new(hmac::HMAC_SHA256, token.as_bytes());
+    hmac::verify(&key, &proof_message(nonce, client_id), &proof).is_ok()
+}
+
+/// Binds the proof to the client ID so it cannot be replayed for another
+fn proof_message(nonce: &str, client_id: &str) -> Vec<u8> {
+    format!("nat-traversal auth\n{}\n{}", nonce, client_id).into_bytes()
+}
+
 /// Generate a client ID
 pub fn generate_client_id() -> String {
     uuid::Uuid::new_v4().to_string()
@@ -49,6 +75,28 @@ mod tests {
         assert!(!verify_token("wrong-token", &hash1));
     }
 
+    #[test]
+    fn test_auth_proof() {
+        let nonce = generate_nonce();
+        let proof = auth_proof("test-token", &nonce, "client-1");
+
+        assert!(verify_auth_proof("test-token", &nonce, "client-1", &proof));
+        assert!(!verify_auth_proof(
+            "wrong-token",
+            &nonce,
+            "client-1",
+            &proof
+        ));
+        assert!(!verify_auth_proof("test-token", &nonce, "client-2", &proof));
+        assert!(!verify_auth_proof(
+            "test-token",
+            &generate_nonce(),
+            "client-1",
+            &proof
+        ));
+        assert!(!proof.contains("test-token"));
+    }
+
     #[test]
     fn test_client_id_generation() {
         let id1 = generate_client_id();
@@ -0,0 +1,273 @@
+//! Binary framing for the per-client data channel.
+//!
+//! Tunnel payloads travel on a second TLS connection so bulk traffic never
+//! goes through JSON encoding or queues behind control messages. The client
+//! attaches it with `AttachDataChannel` and the key from `AuthResponse`;
+//! afterwards every frame is
+//!
+//! ```text
+//! length: u32 | kind: u8 | tunnel_id: [u8; 16] | connection_id: u32 | payload
+//! ```
+//!
+//! where `length` counts everything after itself. Connection opens and
+//! closes use the same channel as the data so they stay in order with it.
+//! An open carries the peer address as its payload, preceded by a `u16`
+//! port offset for connections to a port-range tunnel's later ports. A
+//! half-close carries one byte, 0 for inbound and 1 for outbound, and a
+//! resumption the bytes received as a `u64`. VPN packets belong to no
+//! tunnel and travel with a nil tunnel ID and connection 0.
+
+use crate::protocol::{Message, ShutdownDirection};
+use bytes::{BufMut, BytesMut};
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use uuid::Uuid;
+
+const KIND_DATA: u8 = 0;
+const KIND_OPEN: u8 = 1;
+const KIND_CLOSE: u8 = 2;
+const KIND_OPEN_AT: u8 = 3;
+const KIND_SHUTDOWN: u8 = 4;
+const KIND_RESUME: u8 = 5;
+const KIND_PACKET: u8 = 6;
+
+const HEADER_LEN: usize = 1 + 16 + 4;
+
+/// Append the message to `buf` as a data-channel frame
+pub fn encode(message: &Message, buf: &mut BytesMut) -> anyhow::Result<()> {
+    let (kind, tunnel_id, connection_id, payload): (_, _, _, &[u8]) = match message {
+        Message::Data {
+            tunnel_id,
+            data,
+            connection_id,
+        } => (KIND_DATA, tunnel_id, *connection_id, data),
+        Message::NewConnection {
+            tunnel_id,
+            connection_id,
+            client_addr,
+            port_offset: 0,
+        } => {
+            let addr = client_addr.to_string();
+            return encode_parts(buf, KIND_OPEN, tunnel_id, *connection_id, addr.as_bytes());
+        }
+        Message::NewConnection {
+            tunnel_id,
+            connection_id,
+            client_addr,
+            port_offset,
+        } => {
+            let mut payload = port_offset.to_be_bytes().to_vec();
+            payload.extend_from_slice(client_addr.to_string().as_bytes());
+            return encode_parts(buf, KIND_OPEN_AT, tunnel_id, *connection_id, &payload);
+        }
+        Message::ConnectionClosed {
+            tunnel_id,
+            connection_id,
+        } => (KIND_CLOSE, tunnel_id, *connection_id, &[]),
+        Message::ConnectionShutdown {
+            tunnel_id,
+            connection_id,
+            direction,
+        } => {
+            let direction = match direction {
+                ShutdownDirection::Inbound => 0,
+                ShutdownDirection::Outbound => 1,
+            };
+            return encode_parts(buf, KIND_SHUTDOWN, tunnel_id, *connection_id, &[direction]);
+        }
+        Message::ResumeConnection {
+            tunnel_id,
+            connection_id,
+            received,
+        } => {
+            let payload = received.to_be_bytes();
+            return encode_parts(buf, KIND_RESUME, tunnel_id, *connection_id, &payload);
+        }
+        Message::VpnPacket { data } => (KIND_PACKET, &Uuid::nil(), 0, data),
+        other => {
+            return Err(anyhow::anyhow!(
+                "Message does not belong on the data channel: {:?}",
+                other
+            ))
+        }
+    };
+    encode_parts(buf, kind, tunnel_id, connection_id, payload)
+}
+
+fn encode_parts(
+    buf: &mut BytesMut,
+    kind: u8,
+    tunnel_id: &Uuid,
+    connection_id: u32,
+    payload: &[u8],
+) -> anyhow::Result<()> {
+    buf.reserve(4 + HEADER_LEN + payload.len());
+    buf.put_u32((HEADER_LEN + payload.len()) as u32);
+    buf.put_u8(kind);
+    buf.put_slice(tunnel_id.as_bytes());
+    buf.put_u32(connection_id);
+    buf.put_slice(payload);
+    Ok(())
+}
+
+/// Decode a frame body (everything after the length prefix)
+pub fn decode(mut body: BytesMut) -> anyhow::Result<Message> {
+    if body.len() < HEADER_LEN {
+        return Err(anyhow::anyhow!(
+            "Data frame too short: {} bytes",
+            body.len()
+        ));
+    }
+    let payload = body.split_off(HEADER_LEN).freeze();
+    let kind = body[0];
+    let tunnel_id = Uuid::from_slice(&body[1..17])?;
+    let connection_id = u32::from_be_bytes([body[17], body[18], body[19], body[20]]);
+
+    match kind {
+        KIND_DATA => Ok(Message::Data {
+            tunnel_id,
+            data: payload,
+            connection_id,
+        }),
+        KIND_OPEN => {
+            let client_addr: SocketAddr = std::str::from_utf8(&payload)?.parse()?;
+            Ok(Message::NewConnection {
+                tunnel_id,
+                connection_id,
+                client_addr,
+                port_offset: 0,
+            })
+        }
+        KIND_OPEN_AT if payload.len() >= 2 => {
+            let client_addr: SocketAddr = std::str::from_utf8(&payload[2..])?.parse()?;
+            Ok(Message::NewConnection {
+                tunnel_id,
+                connection_id,
+                client_addr,
+                port_offset: u16::from_be_bytes([payload[0], payload[1]]),
+            })
+        }
+        KIND_CLOSE => Ok(Message::ConnectionClosed {
+            tunnel_id,
+            connection_id,
+        }),
+        KIND_SHUTDOWN if payload.len() == 1 => Ok(Message::ConnectionShutdown {
+            tunnel_id,
+            connection_id,
+            direction: match payload[0] {
+                0 => ShutdownDirection::Inbound,
+                1 => ShutdownDirection::Outbound,
+                other => return Err(anyhow::anyhow!("Unknown shutdown direction {}", other)),
+            },
+        }),
+        KIND_RESUME if payload.len() == 8 => Ok(Message::ResumeConnection {
+            tunnel_id,
+            connection_id,
+            received: u64::from_be_bytes(payload[..].try_into()?),
+        }),
+        KIND_PACKET => Ok(Message::VpnPacket { data: payload }),
+        other => Err(anyhow::anyhow!("Unknown data frame kind {}", other)),
+    }
+}
+
+/// Write one message as a frame
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &Message,
+) -> anyhow::Result<()> {
+    let mut buf = BytesMut::new();
+    encode(message, &mut buf)?;
+    writer.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Read the next frame, `None` at end of stream. Frames over `max_len`
+/// bytes are an error.
+pub async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+) -> anyhow::Result<Option<Message>> {
+    let mut len_buf = [0u8; 4];
+    match reader.read_exact(&mut len_buf).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > max_len {
+        return Err(anyhow::anyhow!("Data frame too large: {} bytes", len));
+    }
+
+    let mut body = BytesMut::zeroed(len);
+    reader.read_exact(&mut body).await?;
+    decode(body).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn test_frames_round_trip() {
+        let tunnel_id = Uuid::new_v4();
+        let messages = [
+            Message::NewConnection {
+                tunnel_id,
+                connection_id: 7,
+                client_addr: "203.0.113.9:51000".parse().unwrap(),
+                port_offset: 0,
+            },
+            Message::NewConnection {
+                tunnel_id,
+                connection_id: 8,
+                client_addr: "[2001:db8::1]:443".parse().unwrap(),
+                port_offset: 4,
+            },
+            Message::Data {
+                tunnel_id,
+                data: Bytes::from_static(b"hello"),
+                connection_id: 7,
+            },
+            Message::ConnectionShutdown {
+                tunnel_id,
+                connection_id: 7,
+                direction: ShutdownDirection::Inbound,
+            },
+            Message::ConnectionShutdown {
+                tunnel_id,
+                connection_id: 8,
+                direction: ShutdownDirection::Outbound,
+            },
+            Message::ResumeConnection {
+                tunnel_id,
+                connection_id: 8,
+                received: 1 << 40,
+            },
+            Message::ConnectionClosed {
+                tunnel_id,
+                connection_id: 7,
+            },
+            Message::VpnPacket {
+                data: Bytes::from_static(&[0x45, 0, 0, 20]),
+            },
+        ];
+
+        let (mut a, mut b) = tokio::io::duplex(4096);
+        for message in &messages {
+            write_message(&mut a, message).await.unwrap();
+        }
+        drop(a);
+
+        for expected in &messages {
+            let message = read_message(&mut b, 1024).await.unwrap().unwrap();
+            assert_eq!(
+                serde_json::to_string(&message).unwrap(),
+                serde_json::to_string(expected).unwrap()
+            );
+        }
+        assert!(read_message(&mut b, 1024).await.unwrap().is_none());
+
+        assert!(encode(&Message::StatusRequest, &mut BytesMut::new()).is_err());
+    }
+}
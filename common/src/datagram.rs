@@ -0,0 +1,322 @@
+//! UDP sessions carried over the byte streams tunnels are made of. Each
+//! datagram travels as a two-byte big-endian length and its payload, so
+//! the far end sends the same datagrams on however the stream was cut
+//! into data frames, held back or replayed on the way: one larger than a
+//! data frame is split across several and put back together from its
+//! length prefix.
+//!
+//! A tunnel advertises the largest datagram it carries. Either end drops
+//! the datagrams over it, and those its socket refuses, such as ones too
+//! big for the path, instead of ending the session over them.
+//!
+//! Datagrams have no half-close: once either side is done the session is
+//! over, so shutting the stream down for writing also ends its reading.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::{Instant, Sleep};
+
+/// Largest datagram a length prefix can describe
+pub const MAX_DATAGRAM: usize = u16::MAX as usize;
+
+/// Most a UDP datagram over IPv4 can hold
+pub const MAX_UDP_PAYLOAD: u16 = 65507;
+
+/// Bytes in front of each datagram
+const PREFIX: usize = 2;
+
+/// Where a session's datagrams come from
+enum Source {
+    /// A socket connected to the session's one peer
+    Connected(Box<[u8]>),
+    /// A socket shared by many peers, whose receive loop hands over the
+    /// datagrams from `peer`
+    Demuxed {
+        peer: SocketAddr,
+        incoming: mpsc::Receiver<Bytes>,
+    },
+}
+
+/// One UDP session as a byte stream of length-prefixed datagrams
+pub struct DatagramStream {
+    socket: Arc<UdpSocket>,
+    source: Source,
+    /// Received datagrams, encoded, not yet read
+    readable: BytesMut,
+    /// Written bytes not yet making up a whole datagram
+    writable: BytesMut,
+    /// Longer datagrams are dropped either way
+    max_datagram: usize,
+    /// Ends the session once no datagram has passed for this long
+    idle_timeout: Option<Duration>,
+    idle: Option<Pin<Box<Sleep>>>,
+    /// The session is over; reads end and writes are dropped
+    closed: bool,
+    /// The read waiting for a datagram, woken when the session closes
+    reader: Option<Waker>,
+}
+
+impl DatagramStream {
+    /// A session over `socket`, already connected to its peer
+    pub fn connected(socket: UdpSocket, idle_timeout: Option<Duration>) -> Self {
+        let buffer = vec![0; MAX_DATAGRAM].into_boxed_slice();
+        Self::new(Arc::new(socket), Source::Connected(buffer), idle_timeout)
+    }
+
+    /// A session with `peer` on a socket shared with other peers, reading
+    /// the datagrams from `peer` off `incoming`
+    pub fn demuxed(
+        socket: Arc<UdpSocket>,
+        peer: SocketAddr,
+        incoming: mpsc::Receiver<Bytes>,
+        idle_timeout: Option<Duration>,
+    ) -> Self {
+        Self::new(socket, Source::Demuxed { peer, incoming }, idle_timeout)
+    }
+
+    fn new(socket: Arc<UdpSocket>, source: Source, idle_timeout: Option<Duration>) -> Self {
+        Self {
+            socket,
+            source,
+            readable: BytesMut::new(),
+            writable: BytesMut::new(),
+            max_datagram: MAX_DATAGRAM,
+            idle_timeout,
+            idle: idle_timeout.map(|timeout| Box::pin(tokio::time::sleep(timeout))),
+            closed: false,
+            reader: None,
+        }
+    }
+
+    /// Drop datagrams longer than `max` bytes in either direction
+    pub fn with_max_datagram(mut self, max: usize) -> Self {
+        self.max_datagram = max.min(MAX_DATAGRAM);
+        self
+    }
+
+    /// Put off the idle timeout, a datagram having passed
+    fn touch(&mut self) {
+        if let (Some(timeout), Some(idle)) = (self.idle_timeout, &mut self.idle) {
+            idle.as_mut().reset(Instant::now() + timeout);
+        }
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        // Later datagrams from the peer start a new session
+        if let Source::Demuxed { incoming, .. } = &mut self.source {
+            incoming.close();
+        }
+        if let Some(reader) = self.reader.take() {
+            reader.wake();
+        }
+    }
+
+    /// The next datagram from the peer, or `None` once the session is over
+    fn poll_datagram(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<Option<Bytes>>> {
+        let received = match &mut self.source {
+            Source::Connected(buffer) => {
+                let mut buffer = ReadBuf::new(buffer);
+                match self.socket.poll_recv(cx, &mut buffer) {
+                    Poll::Ready(Ok(())) => {
+                        Poll::Ready(Some(Bytes::copy_from_slice(buffer.filled())))
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+            Source::Demuxed { incoming, .. } => incoming.poll_recv(cx),
+        };
+        match received {
+            Poll::Ready(datagram) => Poll::Ready(Ok(datagram)),
+            Poll::Pending => match self.idle.as_mut().map(|idle| idle.as_mut().poll(cx)) {
+                Some(Poll::Ready(())) => Poll::Ready(Ok(None)),
+                _ => Poll::Pending,
+            },
+        }
+    }
+
+    /// Send every whole datagram written so far. One the socket cannot
+    /// take right away is dropped, as a full send buffer drops any UDP
+    /// datagram, rather than held for a write that may never come.
+    fn send(&mut self, cx: &mut Context<'_>) {
+        while self.writable.len() >= PREFIX {
+            let len = usize::from(u16::from_be_bytes([self.writable[0], self.writable[1]]));
+            if self.writable.len() < PREFIX + len {
+                break;
+            }
+            if len > self.max_datagram {
+                tracing::debug!(
+                    "Dropped a {} byte datagram over the tunnel's {} byte limit",
+                    len,
+                    self.max_datagram
+                );
+                self.writable.advance(PREFIX + len);
+                continue;
+            }
+            let datagram = &self.writable[PREFIX..PREFIX + len];
+            let sent = match &self.source {
+                Source::Connected(_) => self.socket.poll_send(cx, datagram),
+                Source::Demuxed { peer, .. } => self.socket.poll_send_to(cx, datagram, *peer),
+            };
+            self.writable.advance(PREFIX + len);
+            match sent {
+                Poll::Ready(Ok(_)) => self.touch(),
+                // Most often longer than the path to the peer carries
+                Poll::Ready(Err(e)) => tracing::debug!("Dropped a {} byte datagram: {}", len, e),
+                Poll::Pending => tracing::debug!("Send buffer full, dropped a datagram"),
+            }
+        }
+    }
+}
+
+impl AsyncRead for DatagramStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        this.reader = Some(cx.waker().clone());
+        while this.readable.is_empty() && !this.closed {
+            match ready!(this.poll_datagram(cx)) {
+                Ok(Some(datagram)) if datagram.len() > this.max_datagram => tracing::debug!(
+                    "Dropped a {} byte datagram over the tunnel's {} byte limit",
+                    datagram.len(),
+                    this.max_datagram
+                ),
+                Ok(Some(datagram)) => {
+                    this.touch();
+                    this.readable.put_u16(datagram.len() as u16);
+                    this.readable.extend_from_slice(&datagram);
+                }
+                Ok(None) => this.close(),
+                Err(e) => {
+                    // A connected socket hears of the peer refusing a
+                    // datagram on its next receive
+                    this.close();
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+        let len = this.readable.len().min(buf.remaining());
+        buf.put_slice(&this.readable[..len]);
+        this.readable.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for DatagramStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        if this.closed {
+            return Poll::Ready(Ok(buf.len()));
+        }
+        ready!(this.socket.poll_send_ready(cx))?;
+        this.writable.extend_from_slice(buf);
+        this.send(cx);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        // Only a datagram not yet written in full is ever held back
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn pair() -> (UdpSocket, UdpSocket) {
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        a.connect(b.local_addr().unwrap()).await.unwrap();
+        b.connect(a.local_addr().unwrap()).await.unwrap();
+        (a, b)
+    }
+
+    #[tokio::test]
+    async fn test_datagrams_survive_chunking() {
+        let (a, b) = pair().await;
+        let mut stream = DatagramStream::connected(a, None);
+
+        // Two datagrams written across three uneven chunks
+        let mut encoded = BytesMut::new();
+        for datagram in [&b"first"[..], &[7u8; 3000][..]] {
+            encoded.put_u16(datagram.len() as u16);
+            encoded.extend_from_slice(datagram);
+        }
+        for chunk in [&encoded[..3], &encoded[3..1000], &encoded[1000..]] {
+            stream.write_all(chunk).await.unwrap();
+        }
+        let mut received = vec![0; MAX_DATAGRAM];
+        let len = b.recv(&mut received).await.unwrap();
+        assert_eq!(&received[..len], b"first");
+        let len = b.recv(&mut received).await.unwrap();
+        assert_eq!(len, 3000);
+
+        b.send(b"reply").await.unwrap();
+        let mut read = [0; 3];
+        stream.read_exact(&mut read).await.unwrap();
+        assert_eq!(read, [0, 5, b'r']);
+        let mut rest = [0; 4];
+        stream.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"eply");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_datagrams_dropped() {
+        let (a, b) = pair().await;
+        let mut stream = DatagramStream::connected(a, None).with_max_datagram(4);
+
+        let mut encoded = BytesMut::new();
+        for datagram in [&b"too long"[..], &b"ok"[..]] {
+            encoded.put_u16(datagram.len() as u16);
+            encoded.extend_from_slice(datagram);
+        }
+        stream.write_all(&encoded).await.unwrap();
+        let mut received = vec![0; MAX_DATAGRAM];
+        let len = b.recv(&mut received).await.unwrap();
+        assert_eq!(&received[..len], b"ok");
+
+        b.send(b"too long").await.unwrap();
+        b.send(b"fine").await.unwrap();
+        let mut read = [0; 6];
+        stream.read_exact(&mut read).await.unwrap();
+        assert_eq!(&read, b"\0\x04fine");
+    }
+
+    #[tokio::test]
+    async fn test_idle_session_ends() {
+        let (a, _b) = pair().await;
+        let peer = a.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel(4);
+        let mut stream =
+            DatagramStream::demuxed(Arc::new(a), peer, rx, Some(Duration::from_millis(50)));
+        tx.send(Bytes::from_static(b"hi")).await.unwrap();
+        let mut read = Vec::new();
+        stream.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, [0, 2, b'h', b'i']);
+        // The receive loop sees the session is gone
+        assert!(tx.send(Bytes::new()).await.is_err());
+    }
+}
@@ -0,0 +1,185 @@
+//! Connect to a host by name, resolving it afresh for every connection so
+//! a changed DNS record is followed, and trying the addresses that worked
+//! recently before the others.
+//!
+//! Addresses are tried Happy Eyeballs style (RFC 8305): IPv6 and IPv4
+//! alternate, and each attempt gets a short head start before the next
+//! begins alongside it, so a broken route to one family costs a moment
+//! rather than a TCP timeout.
+
+use crate::config::SocketOptions;
+use crate::socket;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+
+/// Addresses remembered as having worked
+const RECENT: usize = 4;
+
+/// Head start each attempt gets before the next address is tried too
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Dials one host, remembering which of its addresses answered
+pub struct Dialer {
+    /// Options for the connections, including where they go out from
+    sockets: SocketOptions,
+    /// Addresses that connected, the latest first
+    recent: Mutex<Vec<SocketAddr>>,
+}
+
+impl Dialer {
+    pub fn new(sockets: SocketOptions) -> Self {
+        Self {
+            sockets,
+            recent: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Resolve `host` and connect to the first of its addresses that
+    /// answers on `port`, starting on the next one whenever an attempt
+    /// fails or has had its head start. The attempts still running once
+    /// one succeeds are dropped.
+    pub async fn connect(&self, host: &str, port: u16) -> std::io::Result<TcpStream> {
+        let mut addrs = self.resolve(host, port).await?.into_iter();
+        let mut attempts = JoinSet::new();
+        let mut failure = None;
+        if let Some(addr) = addrs.next() {
+            attempts.spawn(attempt(addr, self.sockets.clone()));
+        }
+        loop {
+            let head_start = tokio::time::sleep(ATTEMPT_DELAY);
+            tokio::select! {
+                finished = attempts.join_next() => match finished {
+                    Some(Ok((addr, Ok(stream)))) => {
+                        self.succeeded(addr);
+                        return Ok(stream);
+                    }
+                    Some(Ok((addr, Err(e)))) => {
+                        tracing::debug!("Failed to connect to {}: {}", addr, e);
+                        failure = Some(e);
+                        if let Some(addr) = addrs.next() {
+                            attempts.spawn(attempt(addr, self.sockets.clone()));
+                        }
+                    }
+                    Some(Err(e)) => failure = Some(std::io::Error::other(e)),
+                    None => break,
+                },
+                _ = head_start, if addrs.len() > 0 => {
+                    if let Some(addr) = addrs.next() {
+                        attempts.spawn(attempt(addr, self.sockets.clone()));
+                    }
+                }
+            }
+        }
+        Err(failure.unwrap_or_else(|| std::io::ErrorKind::NotFound.into()))
+    }
+
+    /// The addresses `host` resolves to now, in the order to try them
+    pub async fn resolve(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+        let mut addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+        if addrs.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} has no addresses", host),
+            ));
+        }
+        prefer(&mut addrs, &self.recent.lock().unwrap());
+        let addrs = interleave(addrs);
+        tracing::debug!("Resolved {} to {:?}", host, addrs);
+        Ok(addrs)
+    }
+
+    /// Remember that `addr` answered
+    pub fn succeeded(&self, addr: SocketAddr) {
+        let mut recent = self.recent.lock().unwrap();
+        recent.retain(|known| *known != addr);
+        recent.insert(0, addr);
+        recent.truncate(RECENT);
+    }
+}
+
+async fn attempt(
+    addr: SocketAddr,
+    sockets: SocketOptions,
+) -> (SocketAddr, std::io::Result<TcpStream>) {
+    (addr, socket::connect(addr, &sockets).await)
+}
+
+/// Alternate between the address families, starting with the family of
+/// the first address and otherwise keeping the order
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let first_family = first.is_ipv6();
+    let mut interleaved = Vec::with_capacity(addrs.len());
+    let (leading, trailing): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_family);
+    let (mut leading, mut trailing) = (leading.into_iter(), trailing.into_iter());
+    loop {
+        match (leading.next(), trailing.next()) {
+            (None, None) => break,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+    interleaved
+}
+
+/// Move the addresses in `recent` to the front of `addrs`, the latest
+/// first; ones the name no longer resolves to are not added back
+fn prefer(addrs: &mut [SocketAddr], recent: &[SocketAddr]) {
+    addrs.sort_by_key(|addr| {
+        recent
+            .iter()
+            .position(|known| known == addr)
+            .unwrap_or(recent.len())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(last: u8) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, last], 7000))
+    }
+
+    #[test]
+    fn test_prefer_recent() {
+        let mut addrs = vec![addr(1), addr(2), addr(3), addr(4)];
+        prefer(&mut addrs, &[addr(3), addr(9), addr(2)]);
+        assert_eq!(addrs, vec![addr(3), addr(2), addr(1), addr(4)]);
+
+        let mut addrs = vec![addr(1), addr(2)];
+        prefer(&mut addrs, &[addr(9)]);
+        assert_eq!(addrs, vec![addr(1), addr(2)]);
+    }
+
+    #[test]
+    fn test_interleave_families() {
+        let v6 = |last: u16| SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, last], 7000));
+        let addrs = vec![v6(1), v6(2), v6(3), addr(1), addr(2)];
+        assert_eq!(
+            interleave(addrs),
+            vec![v6(1), addr(1), v6(2), addr(2), v6(3)]
+        );
+        let addrs = vec![addr(1), addr(2), v6(1)];
+        assert_eq!(interleave(addrs), vec![addr(1), v6(1), addr(2)]);
+        assert!(interleave(Vec::new()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dialer_remembers() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let dialer = Dialer::new(SocketOptions::default());
+        dialer.connect("127.0.0.1", port).await.unwrap();
+        assert_eq!(
+            *dialer.recent.lock().unwrap(),
+            vec![listener.local_addr().unwrap()]
+        );
+    }
+}
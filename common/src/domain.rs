@@ -0,0 +1,85 @@
+//! Custom domains for HTTP tunnels.
+//!
+//! A client proves it may route a domain to its tunnels with a DNS TXT
+//! record at `_nat-traversal.<domain>` whose value is derived from its
+//! client ID, so the record can be published before the tunnel exists and
+//! cannot be reused by another client.
+
+use sha2::{Digest, Sha256};
+
+/// Longest domain name DNS allows
+const MAX_DOMAIN_LEN: usize = 253;
+
+/// Lowercase `domain` and drop a trailing dot, or `None` if it is not a
+/// plausible host name with at least two labels
+pub fn normalize(domain: &str) -> Option<String> {
+    let domain = domain.trim().trim_end_matches('.').to_ascii_lowercase();
+    let valid = domain.len() <= MAX_DOMAIN_LEN
+        && domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        });
+    valid.then_some(domain)
+}
+
+/// The host a `Host` header names, without its port
+pub fn host_of(header: &str) -> Option<String> {
+    let header = header.trim();
+    let host = match header.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => header,
+    };
+    normalize(host)
+}
+
+/// Name of the TXT record proving ownership of `domain`
+pub fn challenge_name(domain: &str) -> String {
+    format!("_nat-traversal.{}", domain)
+}
+
+/// Value the TXT record must hold for `client_id` to use `domain`
+pub fn challenge_value(client_id: &str, domain: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(format!("nat-traversal domain\n{}\n{}", client_id, domain).as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_domain_names() {
+        assert_eq!(
+            normalize("App.Example.COM.").as_deref(),
+            Some("app.example.com")
+        );
+        assert_eq!(normalize("localhost"), None);
+        assert_eq!(normalize("-bad.example.com"), None);
+        assert_eq!(normalize("under_score.example.com"), None);
+
+        assert_eq!(
+            host_of("app.example.com:8080").as_deref(),
+            Some("app.example.com")
+        );
+        assert_eq!(
+            host_of("app.example.com").as_deref(),
+            Some("app.example.com")
+        );
+        assert_eq!(host_of("[::1]:80"), None);
+
+        let value = challenge_value("laptop", "app.example.com");
+        assert_eq!(value.len(), 64);
+        assert_ne!(value, challenge_value("desktop", "app.example.com"));
+        assert_eq!(
+            challenge_name("app.example.com"),
+            "_nat-traversal.app.example.com"
+        );
+    }
+}
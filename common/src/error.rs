@@ -15,6 +15,12 @@ pub enum NatError {
     #[error("Authentication failed: {message}")]
     Authentication { message: String },
 
+    #[error("Permission denied: {message}")]
+    PermissionDenied { message: String },
+
+    #[error("Limit reached: {message}")]
+    LimitReached { message: String },
+
     #[error("Tunnel error: {message}")]
     Tunnel { message: String },
 
@@ -41,6 +47,18 @@ impl NatError {
         }
     }
 
+    pub fn permission_denied(message: impl Into<String>) -> Self {
+        Self::PermissionDenied {
+            message: message.into(),
+        }
+    }
+
+    pub fn limit_reached(message: impl Into<String>) -> Self {
+        Self::LimitReached {
+            message: message.into(),
+        }
+    }
+
     pub fn tunnel(message: impl Into<String>) -> Self {
         Self::Tunnel {
             message: message.into(),
@@ -72,10 +90,7 @@ impl NatError {
     }
 
     pub fn network(message: impl Into<String>) -> Self {
-        Self::Network(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            message.into(),
-        ))
+        Self::Network(std::io::Error::other(message.into()))
     }
 
     pub fn tls(message: impl Into<String>) -> Self {
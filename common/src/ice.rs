@@ -0,0 +1,181 @@
+//! ICE-style candidate gathering and pairing (RFC 8445, simplified).
+//!
+//! Candidates are exchanged through the server with `CandidateOffer` and
+//! `CandidateAnswer`; each side then checks the resulting pairs in order.
+
+use crate::protocol::{Candidate, CandidateKind, TunnelProtocol};
+use crate::stun;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+impl CandidateKind {
+    /// Type preference from RFC 8445 section 5.1.2.2
+    pub fn type_preference(self) -> u32 {
+        match self {
+            CandidateKind::Host => 126,
+            CandidateKind::ServerReflexive => 100,
+            CandidateKind::Relay => 0,
+        }
+    }
+}
+
+impl Candidate {
+    /// Build a candidate with its priority computed from the kind and a
+    /// local preference (higher wins among candidates of the same kind)
+    pub fn new(
+        kind: CandidateKind,
+        protocol: TunnelProtocol,
+        addr: SocketAddr,
+        local_preference: u16,
+    ) -> Self {
+        // Single component, so the component ID term is always 255
+        let priority = (kind.type_preference() << 24) + (u32::from(local_preference) << 8) + 255;
+        Self {
+            kind,
+            protocol,
+            addr,
+            priority,
+        }
+    }
+}
+
+/// Candidate pair priority from RFC 8445 section 6.1.2.3
+pub fn pair_priority(controlling: u32, controlled: u32) -> u64 {
+    let (g, d) = (u64::from(controlling), u64::from(controlled));
+    (g.min(d) << 32) + 2 * g.max(d) + u64::from(g > d)
+}
+
+/// Pair local and remote candidates of the same protocol and address
+/// family, best first. `controlling` is true on the side that sent the offer.
+pub fn candidate_pairs(
+    local: &[Candidate],
+    remote: &[Candidate],
+    controlling: bool,
+) -> Vec<(Candidate, Candidate)> {
+    let mut pairs: Vec<(u64, Candidate, Candidate)> = local
+        .iter()
+        .flat_map(|l| remote.iter().map(move |r| (l, r)))
+        .filter(|(l, r)| l.protocol == r.protocol && l.addr.is_ipv4() == r.addr.is_ipv4())
+        .map(|(l, r)| {
+            let priority = if controlling {
+                pair_priority(l.priority, r.priority)
+            } else {
+                pair_priority(r.priority, l.priority)
+            };
+            (priority, *l, *r)
+        })
+        .collect();
+    pairs.sort_by_key(|(priority, _, _)| std::cmp::Reverse(*priority));
+    pairs.into_iter().map(|(_, l, r)| (l, r)).collect()
+}
+
+/// Gather UDP candidates for `socket`: its host address, the public address
+/// STUN reports for it, and the relay port when one has been allocated
+pub async fn gather(
+    socket: &UdpSocket,
+    stun_servers: &[String],
+    timeout: Duration,
+    relay: Option<SocketAddr>,
+) -> Vec<Candidate> {
+    let mut candidates = Vec::new();
+
+    match stun::discover_with(socket, stun_servers, timeout).await {
+        Ok(report) => {
+            candidates.push(Candidate::new(
+                CandidateKind::Host,
+                TunnelProtocol::Udp,
+                report.local_addr,
+                u16::MAX,
+            ));
+            if report.public_addr != report.local_addr {
+                candidates.push(Candidate::new(
+                    CandidateKind::ServerReflexive,
+                    TunnelProtocol::Udp,
+                    report.public_addr,
+                    u16::MAX,
+                ));
+            }
+        }
+        Err(e) => {
+            tracing::debug!("No server-reflexive candidate: {}", e);
+            if let Ok(addr) = socket.local_addr() {
+                if !addr.ip().is_unspecified() {
+                    candidates.push(Candidate::new(
+                        CandidateKind::Host,
+                        TunnelProtocol::Udp,
+                        addr,
+                        u16::MAX,
+                    ));
+                }
+            }
+        }
+    }
+
+    if let Some(addr) = relay {
+        candidates.push(Candidate::new(
+            CandidateKind::Relay,
+            TunnelProtocol::Udp,
+            addr,
+            u16::MAX,
+        ));
+    }
+
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidate_pair_order() {
+        let udp = TunnelProtocol::Udp;
+        let local = [
+            Candidate::new(
+                CandidateKind::Relay,
+                udp,
+                "203.0.113.1:40000".parse().unwrap(),
+                65535,
+            ),
+            Candidate::new(
+                CandidateKind::Host,
+                udp,
+                "192.168.1.2:5000".parse().unwrap(),
+                65535,
+            ),
+            Candidate::new(
+                CandidateKind::Host,
+                udp,
+                "[fe80::1]:5000".parse().unwrap(),
+                65535,
+            ),
+        ];
+        let remote = [
+            Candidate::new(
+                CandidateKind::ServerReflexive,
+                udp,
+                "198.51.100.7:6000".parse().unwrap(),
+                65535,
+            ),
+            Candidate::new(
+                CandidateKind::Host,
+                udp,
+                "10.0.0.5:6000".parse().unwrap(),
+                65535,
+            ),
+        ];
+
+        let pairs = candidate_pairs(&local, &remote, true);
+        // The IPv6 host candidate has no IPv4 counterpart to pair with
+        assert_eq!(pairs.len(), 4);
+        assert_eq!(pairs[0].0.kind, CandidateKind::Host);
+        assert_eq!(pairs[0].1.kind, CandidateKind::Host);
+        assert_eq!(pairs[3].0.kind, CandidateKind::Relay);
+
+        // Both sides must agree on the order
+        let reverse = candidate_pairs(&remote, &local, false);
+        let flipped: Vec<_> = reverse.into_iter().map(|(l, r)| (r, l)).collect();
+        assert_eq!(pairs, flipped);
+    }
+}
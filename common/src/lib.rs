@@ -1,4 +1,26 @@
+pub mod batch;
 pub mod config;
+pub mod control;
 pub mod crypto;
+pub mod data_channel;
+pub mod datagram;
+pub mod dial;
+pub mod domain;
 pub mod error;
+pub mod ice;
+pub mod logging;
+pub mod noise;
+pub mod pipeline;
 pub mod protocol;
+pub mod queue;
+pub mod resume;
+pub mod schedule;
+pub mod secure;
+pub mod socket;
+pub mod stun;
+pub mod telemetry;
+pub mod throttle;
+pub mod tls;
+pub mod transport;
+pub mod vpn;
+pub mod wol;
@@ -0,0 +1,336 @@
+//! Log sinks besides the console and the log file.
+//!
+//! The systemd journal goes through `tracing-journald`. Syslog messages are
+//! RFC 5424 formatted and sent to the local `/dev/log`, or to a remote
+//! collector over UDP or octet-counted TCP. A message that cannot be
+//! delivered is dropped rather than holding up the caller.
+
+use crate::config::{LoggingConfig, SyslogConfig, SyslogProtocol};
+use crate::telemetry::TelemetryLayer;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{Level, Metadata};
+use tracing_subscriber::{fmt::MakeWriter, Layer};
+
+/// How long to wait for a remote TCP collector to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Layers for the journald and syslog outputs the configuration enables
+pub fn sink_layers(config: &LoggingConfig, app_name: &str) -> anyhow::Result<Vec<TelemetryLayer>> {
+    let mut layers = Vec::new();
+    if config.journald {
+        layers.extend(journald_layer(app_name)?);
+    }
+    if let Some(syslog) = &config.syslog {
+        layers.push(syslog_layer(syslog, app_name)?);
+    }
+    Ok(layers)
+}
+
+/// Stack `layers` into one. An empty list gives `None` rather than an empty
+/// `Vec` layer, which would mark every callsite uninteresting and silence all
+/// output.
+pub fn combine(layers: Vec<TelemetryLayer>) -> Option<TelemetryLayer> {
+    layers
+        .into_iter()
+        .reduce(|stacked, layer| Box::new(stacked.and_then(layer)))
+}
+
+#[cfg(target_os = "linux")]
+fn journald_layer(app_name: &str) -> anyhow::Result<Option<TelemetryLayer>> {
+    let layer = tracing_journald::layer()
+        .map_err(|e| anyhow::anyhow!("systemd journal unavailable: {}", e))?
+        .with_syslog_identifier(app_name.to_string());
+    Ok(Some(Box::new(layer)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn journald_layer(_app_name: &str) -> anyhow::Result<Option<TelemetryLayer>> {
+    // Logging is not up yet, so this goes straight to stderr
+    eprintln!("logging.journald is only supported on Linux; ignoring");
+    Ok(None)
+}
+
+fn syslog_layer(config: &SyslogConfig, app_name: &str) -> anyhow::Result<TelemetryLayer> {
+    let sink = SyslogSink {
+        facility: facility_code(&config.facility)?,
+        header: format!(
+            "{} {} {} - -",
+            hostname(),
+            config.app_name.as_deref().unwrap_or(app_name),
+            std::process::id()
+        ),
+        transport: Mutex::new(Transport::open(config)?),
+    };
+    Ok(Box::new(
+        tracing_subscriber::fmt::layer()
+            .with_writer(SyslogWriter(Arc::new(sink)))
+            .with_ansi(false)
+            .without_time()
+            .with_level(false)
+            .with_target(false),
+    ))
+}
+
+/// Numeric code of a syslog facility name
+fn facility_code(name: &str) -> anyhow::Result<u8> {
+    const FACILITIES: [&str; 12] = [
+        "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron",
+        "authpriv", "ftp",
+    ];
+    if let Some(code) = FACILITIES.iter().position(|f| *f == name) {
+        return Ok(code as u8);
+    }
+    match name
+        .strip_prefix("local")
+        .and_then(|n| n.parse::<u8>().ok())
+    {
+        Some(n) if n < 8 => Ok(16 + n),
+        _ => Err(anyhow::anyhow!("Unknown syslog facility '{}'", name)),
+    }
+}
+
+fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        _ => 7,
+    }
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+enum Transport {
+    #[cfg(unix)]
+    Local(std::os::unix::net::UnixDatagram),
+    Udp(std::net::UdpSocket),
+    /// Reconnected on the next message after a failed write
+    Tcp {
+        address: String,
+        stream: Option<std::net::TcpStream>,
+    },
+}
+
+impl Transport {
+    fn open(config: &SyslogConfig) -> anyhow::Result<Self> {
+        let address = match &config.address {
+            Some(address) => address.clone(),
+            None => return Self::local(),
+        };
+        match config.protocol {
+            SyslogProtocol::Udp => {
+                let socket = std::net::UdpSocket::bind(if address.starts_with('[') {
+                    "[::]:0"
+                } else {
+                    "0.0.0.0:0"
+                })?;
+                socket
+                    .connect(&address)
+                    .map_err(|e| anyhow::anyhow!("Invalid syslog address {}: {}", address, e))?;
+                Ok(Transport::Udp(socket))
+            }
+            SyslogProtocol::Tcp => Ok(Transport::Tcp {
+                stream: Self::connect_tcp(&address).ok(),
+                address,
+            }),
+        }
+    }
+
+    #[cfg(unix)]
+    fn local() -> anyhow::Result<Self> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket
+            .connect("/dev/log")
+            .map_err(|e| anyhow::anyhow!("Local syslog /dev/log unavailable: {}", e))?;
+        Ok(Transport::Local(socket))
+    }
+
+    #[cfg(not(unix))]
+    fn local() -> anyhow::Result<Self> {
+        Err(anyhow::anyhow!(
+            "No local syslog on this platform; set logging.syslog.address"
+        ))
+    }
+
+    fn connect_tcp(address: &str) -> std::io::Result<std::net::TcpStream> {
+        use std::net::ToSocketAddrs;
+
+        let mut last_error = None;
+        for addr in address.to_socket_addrs()? {
+            match std::net::TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| std::io::ErrorKind::NotFound.into()))
+    }
+
+    fn send(&mut self, message: &[u8]) -> std::io::Result<()> {
+        match self {
+            #[cfg(unix)]
+            Transport::Local(socket) => socket.send(message).map(|_| ()),
+            Transport::Udp(socket) => socket.send(message).map(|_| ()),
+            Transport::Tcp { address, stream } => {
+                if stream.is_none() {
+                    *stream = Some(Self::connect_tcp(address)?);
+                }
+                let connection = stream.as_mut().expect("connected above");
+                let mut frame = format!("{} ", message.len()).into_bytes();
+                frame.extend_from_slice(message);
+                let result = connection.write_all(&frame);
+                if result.is_err() {
+                    *stream = None;
+                }
+                result
+            }
+        }
+    }
+}
+
+struct SyslogSink {
+    facility: u8,
+    /// HOSTNAME, APP-NAME, PROCID, MSGID and STRUCTURED-DATA
+    header: String,
+    transport: Mutex<Transport>,
+}
+
+impl SyslogSink {
+    fn send(&self, severity: u8, text: &[u8]) {
+        let text = String::from_utf8_lossy(text);
+        let message = format!(
+            "<{}>1 {} {} {}",
+            self.facility * 8 + severity,
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+            self.header,
+            text.trim_end()
+        );
+        if let Ok(mut transport) = self.transport.lock() {
+            let _ = transport.send(message.as_bytes());
+        }
+    }
+}
+
+/// Hands each formatted event to the syslog sink with its severity
+struct SyslogWriter(Arc<SyslogSink>);
+
+impl<'a> MakeWriter<'a> for SyslogWriter {
+    type Writer = SyslogLine;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.line(6)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        self.line(severity(meta.level()))
+    }
+}
+
+impl SyslogWriter {
+    fn line(&self, severity: u8) -> SyslogLine {
+        SyslogLine {
+            sink: self.0.clone(),
+            severity,
+            buf: Vec::new(),
+        }
+    }
+}
+
+/// One event's text, sent as a single syslog message when dropped
+struct SyslogLine {
+    sink: Arc<SyslogSink>,
+    severity: u8,
+    buf: Vec<u8>,
+}
+
+impl Write for SyslogLine {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SyslogLine {
+    fn drop(&mut self) {
+        if !self.buf.is_empty() {
+            self.sink.send(self.severity, &self.buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_no_sinks_keeps_other_output() {
+        let count = Arc::new(Mutex::new(0));
+        let counted = count.clone();
+        let counter = tracing_subscriber::fmt::layer().with_writer(move || {
+            *counted.lock().unwrap() += 1;
+            std::io::sink()
+        });
+
+        let subscriber = tracing_subscriber::registry()
+            .with(combine(Vec::new()))
+            .with(counter);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("still logged");
+        });
+        assert_eq!(*count.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_facility_codes() {
+        assert_eq!(facility_code("kern").unwrap(), 0);
+        assert_eq!(facility_code("daemon").unwrap(), 3);
+        assert_eq!(facility_code("local7").unwrap(), 23);
+        assert!(facility_code("local8").is_err());
+        assert!(facility_code("nope").is_err());
+    }
+
+    #[test]
+    fn test_syslog_over_udp() {
+        let collector = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let config = SyslogConfig {
+            address: Some(collector.local_addr().unwrap().to_string()),
+            facility: "local0".to_string(),
+            ..Default::default()
+        };
+
+        let layer = syslog_layer(&config, "nat-test").unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!("disk almost full");
+        });
+
+        let mut buf = [0u8; 2048];
+        let len = collector.recv(&mut buf).unwrap();
+        let message = std::str::from_utf8(&buf[..len]).unwrap();
+        // local0 (16) * 8 + warning (4)
+        assert!(message.starts_with("<132>1 "), "{}", message);
+        assert!(
+            message.contains(&format!(" nat-test {} - - ", std::process::id())),
+            "{}",
+            message
+        );
+        assert!(message.ends_with("disk almost full"), "{}", message);
+    }
+}
@@ -0,0 +1,260 @@
+//! End-to-end encryption for tunnel connections.
+//!
+//! A consumer and the exposing client run a Noise IK handshake over the
+//! tunneled byte stream, so the server only ever relays ciphertext. The
+//! consumer must know the exposing client's static public key, and the
+//! exposing client only accepts consumers whose static keys it lists. Each
+//! Noise message travels with a 2-byte length prefix.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::{params::DHChoice, StatelessTransportState};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const PATTERN: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
+const MAX_MESSAGE: usize = 65535;
+const TAG_LEN: usize = 16;
+/// Largest plaintext carried in one message
+const MAX_PAYLOAD: usize = MAX_MESSAGE - TAG_LEN;
+const KEY_LEN: usize = 32;
+
+/// A new static key pair as base64 `(private, public)`
+pub fn generate_keypair() -> anyhow::Result<(String, String)> {
+    let keypair = snow::Builder::new(PATTERN.parse()?).generate_keypair()?;
+    Ok((
+        STANDARD.encode(&keypair.private),
+        STANDARD.encode(&keypair.public),
+    ))
+}
+
+/// Decode a base64 key
+pub fn decode_key(key: &str) -> anyhow::Result<Vec<u8>> {
+    let key = STANDARD
+        .decode(key.trim())
+        .map_err(|_| anyhow::anyhow!("Key is not valid base64"))?;
+    if key.len() != KEY_LEN {
+        return Err(anyhow::anyhow!("Key must be {} bytes", KEY_LEN));
+    }
+    Ok(key)
+}
+
+/// Base64 public key belonging to a private key
+pub fn public_key(private: &[u8]) -> anyhow::Result<String> {
+    let mut dh = DefaultResolver
+        .resolve_dh(&DHChoice::Curve25519)
+        .ok_or_else(|| anyhow::anyhow!("X25519 unavailable"))?;
+    dh.set(private);
+    Ok(STANDARD.encode(dh.pubkey()))
+}
+
+/// Open a session as the consumer of a tunnel exposed by `peer_public`
+pub async fn connect<S>(
+    mut stream: S,
+    private: &[u8],
+    peer_public: &[u8],
+) -> anyhow::Result<NoiseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut handshake = snow::Builder::new(PATTERN.parse()?)
+        .local_private_key(private)
+        .remote_public_key(peer_public)
+        .build_initiator()?;
+    let mut buffer = vec![0u8; MAX_MESSAGE];
+
+    let len = handshake.write_message(&[], &mut buffer)?;
+    write_message(&mut stream, &buffer[..len]).await?;
+    let reply = read_message(&mut stream)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Peer closed the connection during the handshake"))?;
+    handshake
+        .read_message(&reply, &mut buffer)
+        .map_err(|_| anyhow::anyhow!("Peer failed the handshake"))?;
+
+    Ok(NoiseStream {
+        inner: stream,
+        transport: handshake.into_stateless_transport_mode()?,
+    })
+}
+
+/// Answer a consumer's session, accepting only static keys in `peers`
+pub async fn accept<S>(
+    mut stream: S,
+    private: &[u8],
+    peers: &[Vec<u8>],
+) -> anyhow::Result<NoiseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut handshake = snow::Builder::new(PATTERN.parse()?)
+        .local_private_key(private)
+        .build_responder()?;
+    let mut buffer = vec![0u8; MAX_MESSAGE];
+
+    let hello = read_message(&mut stream)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Peer closed the connection during the handshake"))?;
+    handshake
+        .read_message(&hello, &mut buffer)
+        .map_err(|_| anyhow::anyhow!("Peer failed the handshake"))?;
+    let peer = handshake.get_remote_static().unwrap_or_default();
+    if !peers.iter().any(|allowed| allowed.as_slice() == peer) {
+        return Err(anyhow::anyhow!(
+            "Peer key {} is not allowed",
+            STANDARD.encode(peer)
+        ));
+    }
+
+    let len = handshake.write_message(&[], &mut buffer)?;
+    write_message(&mut stream, &buffer[..len]).await?;
+
+    Ok(NoiseStream {
+        inner: stream,
+        transport: handshake.into_stateless_transport_mode()?,
+    })
+}
+
+/// A stream after a completed handshake
+pub struct NoiseStream<S> {
+    inner: S,
+    transport: StatelessTransportState,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> NoiseStream<S> {
+    /// Forward traffic between this session and a plain local stream until
+    /// both directions close. Returns the bytes sent and received.
+    pub async fn pump<L>(self, local: L) -> anyhow::Result<(u64, u64)>
+    where
+        L: AsyncRead + AsyncWrite + Unpin,
+    {
+        let transport = &self.transport;
+        let (mut peer_read, mut peer_write) = tokio::io::split(self.inner);
+        let (mut local_read, mut local_write) = tokio::io::split(local);
+
+        let outbound = async {
+            let mut plain = vec![0u8; MAX_PAYLOAD];
+            let mut cipher = vec![0u8; MAX_MESSAGE];
+            let (mut nonce, mut sent) = (0u64, 0u64);
+            loop {
+                let n = local_read.read(&mut plain).await?;
+                if n == 0 {
+                    peer_write.shutdown().await?;
+                    return Ok::<_, anyhow::Error>(sent);
+                }
+                let len = transport.write_message(nonce, &plain[..n], &mut cipher)?;
+                write_message(&mut peer_write, &cipher[..len]).await?;
+                nonce += 1;
+                sent += n as u64;
+            }
+        };
+
+        let inbound = async {
+            let mut plain = vec![0u8; MAX_MESSAGE];
+            let (mut nonce, mut received) = (0u64, 0u64);
+            while let Some(message) = read_message(&mut peer_read).await? {
+                let n = transport
+                    .read_message(nonce, &message, &mut plain)
+                    .map_err(|_| anyhow::anyhow!("Message failed authentication"))?;
+                local_write.write_all(&plain[..n]).await?;
+                nonce += 1;
+                received += n as u64;
+            }
+            local_write.shutdown().await?;
+            Ok::<_, anyhow::Error>(received)
+        };
+
+        tokio::try_join!(outbound, inbound)
+    }
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &[u8],
+) -> anyhow::Result<()> {
+    let mut frame = Vec::with_capacity(2 + message.len());
+    frame.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    frame.extend_from_slice(message);
+    writer.write_all(&frame).await?;
+    Ok(())
+}
+
+/// Next message, `None` at a clean end of stream
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 2];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut message = vec![0u8; u16::from_be_bytes(len) as usize];
+    reader.read_exact(&mut message).await?;
+    Ok(Some(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_noise_session() {
+        let (exposer_private, exposer_public) = generate_keypair().unwrap();
+        let (consumer_private, consumer_public) = generate_keypair().unwrap();
+        let exposer_private = decode_key(&exposer_private).unwrap();
+        let consumer_private = decode_key(&consumer_private).unwrap();
+        assert_eq!(public_key(&exposer_private).unwrap(), exposer_public);
+
+        // The consumer's local app talks plaintext through a Noise session
+        // to the exposing side's local service
+        let (relay_a, relay_b) = tokio::io::duplex(4096);
+        let (app, app_side) = tokio::io::duplex(4096);
+        let (service_side, service) = tokio::io::duplex(4096);
+        let peers = vec![decode_key(&consumer_public).unwrap()];
+        let exposer_key = decode_key(&exposer_public).unwrap();
+
+        let exposer = tokio::spawn(async move {
+            accept(relay_b, &exposer_private, &peers)
+                .await
+                .unwrap()
+                .pump(service_side)
+                .await
+        });
+        let consumer = tokio::spawn(async move {
+            connect(relay_a, &consumer_private, &exposer_key)
+                .await
+                .unwrap()
+                .pump(app_side)
+                .await
+        });
+
+        let (mut app, mut service) = (app, service);
+        app.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        service.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        service.write_all(b"pong").await.unwrap();
+        app.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+
+        drop(app);
+        drop(service);
+        assert_eq!(consumer.await.unwrap().unwrap(), (4, 4));
+        assert_eq!(exposer.await.unwrap().unwrap(), (4, 4));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_consumer_rejected() {
+        let (exposer_private, exposer_public) = generate_keypair().unwrap();
+        let (consumer_private, _) = generate_keypair().unwrap();
+        let (_, other_public) = generate_keypair().unwrap();
+        let exposer_private = decode_key(&exposer_private).unwrap();
+        let consumer_private = decode_key(&consumer_private).unwrap();
+        let exposer_public = decode_key(&exposer_public).unwrap();
+        let peers = vec![decode_key(&other_public).unwrap()];
+
+        let (a, b) = tokio::io::duplex(4096);
+        let exposer = tokio::spawn(async move { accept(b, &exposer_private, &peers).await });
+        let consumer = connect(a, &consumer_private, &exposer_public).await;
+        assert!(exposer.await.unwrap().is_err());
+        assert!(consumer.is_err());
+    }
+}
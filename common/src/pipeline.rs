@@ -0,0 +1,302 @@
+//! Stages a tunnel's traffic passes through on its way between the public
+//! visitor and the local service.
+//!
+//! A tunnel lists its stages in its configuration and every chunk read
+//! from either end runs through them in order, so rate limiting, counting,
+//! logging and rewriting compose per tunnel instead of each being wired
+//! into the forwarder. Stages see chunks as they were read: a pattern split
+//! across two reads is not rewritten.
+
+use crate::config::StageConfig;
+use crate::telemetry::Direction;
+use crate::throttle::Throttle;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::info;
+
+/// The connection and direction a chunk travels
+#[derive(Debug, Clone, Copy)]
+pub struct Flow<'a> {
+    /// Tunnel name
+    pub tunnel: &'a str,
+    pub connection_id: u32,
+    pub direction: Direction,
+}
+
+/// A chunk on its way through the stages
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    pub data: Bytes,
+    /// How long to hold the chunk before passing it on
+    pub delay: Duration,
+}
+
+/// One step of a pipeline
+pub trait Stage: Send + Sync {
+    /// Handle a chunk, returning what goes on to the next stage
+    fn process(&self, flow: &Flow, chunk: Chunk) -> Chunk;
+
+    /// The connection closed
+    fn close(&self, _tunnel: &str, _connection_id: u32) {}
+}
+
+/// The stages of one tunnel, shared by its connections
+pub struct Pipeline {
+    tunnel: String,
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Pipeline {
+    /// Build the stages configured for `tunnel`
+    pub fn new(tunnel: &str, stages: &[StageConfig]) -> Result<Self, String> {
+        let mut pipeline = Self {
+            tunnel: tunnel.to_string(),
+            stages: Vec::new(),
+        };
+        for stage in stages {
+            pipeline.push(build(stage)?);
+        }
+        Ok(pipeline)
+    }
+
+    /// Add a stage after the ones already there
+    pub fn push(&mut self, stage: Box<dyn Stage>) {
+        self.stages.push(stage);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Run `data` through every stage
+    pub fn process(&self, connection_id: u32, direction: Direction, data: Bytes) -> Chunk {
+        let flow = Flow {
+            tunnel: &self.tunnel,
+            connection_id,
+            direction,
+        };
+        self.stages.iter().fold(
+            Chunk {
+                data,
+                delay: Duration::ZERO,
+            },
+            |chunk, stage| stage.process(&flow, chunk),
+        )
+    }
+
+    /// Run `data` through every stage and wait out any delay they asked for
+    pub async fn pass(&self, connection_id: u32, direction: Direction, data: Bytes) -> Bytes {
+        let chunk = self.process(connection_id, direction, data);
+        if !chunk.delay.is_zero() {
+            tokio::time::sleep(chunk.delay).await;
+        }
+        chunk.data
+    }
+
+    /// Tell every stage the connection closed
+    pub fn close(&self, connection_id: u32) {
+        for stage in &self.stages {
+            stage.close(&self.tunnel, connection_id);
+        }
+    }
+}
+
+fn build(stage: &StageConfig) -> Result<Box<dyn Stage>, String> {
+    Ok(match stage {
+        StageConfig::RateLimit { kbps } => {
+            if *kbps == 0 {
+                return Err("RateLimit needs kbps above 0".to_string());
+            }
+            Box::new(RateLimit(Throttle::per_second(*kbps as f64 * 1000.0 / 8.0)))
+        }
+        StageConfig::Count => Box::new(Count::default()),
+        StageConfig::Log { preview } => Box::new(Log { preview: *preview }),
+        StageConfig::Rewrite {
+            find,
+            replace,
+            direction,
+        } => {
+            if find.is_empty() {
+                return Err("Rewrite needs something to find".to_string());
+            }
+            Box::new(Rewrite {
+                find: find.as_bytes().to_vec(),
+                replace: Bytes::copy_from_slice(replace.as_bytes()),
+                direction: *direction,
+            })
+        }
+    })
+}
+
+/// Holds the tunnel's traffic, both ways together, to a rate
+struct RateLimit(Throttle);
+
+impl Stage for RateLimit {
+    fn process(&self, _flow: &Flow, mut chunk: Chunk) -> Chunk {
+        chunk.delay += self.0.reserve(chunk.data.len());
+        chunk
+    }
+}
+
+/// Bytes each open connection carried, logged when it closes
+#[derive(Default)]
+struct Count {
+    /// Inbound and outbound bytes by connection
+    connections: Mutex<HashMap<u32, (u64, u64)>>,
+}
+
+impl Stage for Count {
+    fn process(&self, flow: &Flow, chunk: Chunk) -> Chunk {
+        let mut connections = self.connections.lock().unwrap();
+        let (inbound, outbound) = connections.entry(flow.connection_id).or_default();
+        match flow.direction {
+            Direction::Inbound => *inbound += chunk.data.len() as u64,
+            Direction::Outbound => *outbound += chunk.data.len() as u64,
+        }
+        chunk
+    }
+
+    fn close(&self, tunnel: &str, connection_id: u32) {
+        let counted = self.connections.lock().unwrap().remove(&connection_id);
+        if let Some((inbound, outbound)) = counted {
+            info!(
+                "Connection {} on tunnel {} carried {} bytes in and {} bytes out",
+                connection_id, tunnel, inbound, outbound
+            );
+        }
+    }
+}
+
+/// Logs every chunk, with up to `preview` bytes of it
+struct Log {
+    preview: usize,
+}
+
+impl Stage for Log {
+    fn process(&self, flow: &Flow, chunk: Chunk) -> Chunk {
+        let shown = &chunk.data[..chunk.data.len().min(self.preview)];
+        if shown.is_empty() {
+            info!(
+                "{:?} {} bytes on connection {} of tunnel {}",
+                flow.direction,
+                chunk.data.len(),
+                flow.connection_id,
+                flow.tunnel
+            );
+        } else {
+            info!(
+                "{:?} {} bytes on connection {} of tunnel {}: {}",
+                flow.direction,
+                chunk.data.len(),
+                flow.connection_id,
+                flow.tunnel,
+                shown.escape_ascii()
+            );
+        }
+        chunk
+    }
+}
+
+/// Replaces every `find` in the traffic going `direction`, or both ways
+struct Rewrite {
+    find: Vec<u8>,
+    replace: Bytes,
+    direction: Option<Direction>,
+}
+
+impl Stage for Rewrite {
+    fn process(&self, flow: &Flow, mut chunk: Chunk) -> Chunk {
+        if self
+            .direction
+            .is_some_and(|direction| direction != flow.direction)
+        {
+            return chunk;
+        }
+        let data = &chunk.data;
+        let Some(first) = find(data, &self.find, 0) else {
+            return chunk;
+        };
+        let mut rewritten = Vec::with_capacity(data.len());
+        let mut start = 0;
+        let mut next = Some(first);
+        while let Some(at) = next {
+            rewritten.extend_from_slice(&data[start..at]);
+            rewritten.extend_from_slice(&self.replace);
+            start = at + self.find.len();
+            next = find(data, &self.find, start);
+        }
+        rewritten.extend_from_slice(&data[start..]);
+        chunk.data = rewritten.into();
+        chunk
+    }
+}
+
+/// Where `needle` next occurs in `haystack` at or after `from`
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|at| at + from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pipeline_runs_stages_in_order() {
+        let pipeline = Pipeline::new(
+            "web",
+            &[
+                StageConfig::Rewrite {
+                    find: "nginx".to_string(),
+                    replace: "web".to_string(),
+                    direction: Some(Direction::Outbound),
+                },
+                StageConfig::Rewrite {
+                    find: "web".to_string(),
+                    replace: "site".to_string(),
+                    direction: None,
+                },
+                StageConfig::Count,
+            ],
+        )
+        .unwrap();
+
+        let chunk = pipeline.process(
+            1,
+            Direction::Outbound,
+            Bytes::from_static(b"Server: nginx, nginx"),
+        );
+        assert_eq!(&chunk.data[..], b"Server: site, site");
+        assert!(chunk.delay.is_zero());
+
+        let chunk = pipeline.process(1, Direction::Inbound, Bytes::from_static(b"nginx web"));
+        assert_eq!(&chunk.data[..], b"nginx site");
+
+        assert!(Pipeline::new(
+            "web",
+            &[StageConfig::Rewrite {
+                find: String::new(),
+                replace: "x".to_string(),
+                direction: None,
+            }]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_rate_limit_delays_past_one_second_of_traffic() {
+        let pipeline = Pipeline::new("web", &[StageConfig::RateLimit { kbps: 8 }]).unwrap();
+        let data = Bytes::from(vec![0u8; 1000]);
+
+        // A second's worth passes at once; what follows waits for it
+        let chunk = pipeline.process(1, Direction::Inbound, data.clone());
+        assert!(chunk.delay.is_zero());
+        let chunk = pipeline.process(2, Direction::Outbound, data);
+        assert!(chunk.delay > Duration::from_millis(900));
+    }
+}
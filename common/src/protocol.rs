@@ -1,19 +1,51 @@
+use crate::config::{Dscp, PortRange};
+use crate::vpn::Subnet;
+use bytes::{BufMut, Bytes, BytesMut};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
+use std::collections::BTreeMap;
+use std::net::{Ipv6Addr, SocketAddr};
 use uuid::Uuid;
 
 /// Protocol version for compatibility checking
 pub const PROTOCOL_VERSION: u32 = 1;
 
+/// The largest frame a peer that does not advertise its limit accepts
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// The smallest frame limit a peer is held to, whatever it advertises, so
+/// control messages always fit
+pub const MIN_MAX_FRAME_SIZE: usize = 64 * 1024;
+
 /// Message types exchanged between client and server
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Message {
+    /// Ask for a nonce to prove the token against, so the token itself
+    /// never has to be sent
+    RequestAuthChallenge,
+
+    /// Nonce for the client's next `Auth::proof`
+    AuthChallenge { nonce: String },
+
     /// Authentication request from client
     Auth {
         version: u32,
+        /// The token in the clear; only sent for signed tokens and by
+        /// clients that predate challenge-response
+        #[serde(default, skip_serializing_if = "String::is_empty")]
         token: String,
         client_id: String,
+        /// HMAC of the server's challenge keyed with the token
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        proof: Option<String>,
+        /// How often the client sends heartbeats, so the server can tell
+        /// when it went silent
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        heartbeat: Option<HeartbeatSettings>,
+        /// The largest frame the client accepts, which the server splits
+        /// tunnel data to fit
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_frame_size: Option<u32>,
     },
 
     /// Authentication response from server
@@ -21,23 +53,117 @@ pub enum Message {
         success: bool,
         error: Option<String>,
         server_version: u32,
+        /// Key for attaching a data channel, absent from servers that
+        /// carry tunnel data on the control connection only
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        data_channel: Option<Uuid>,
+        /// The server's message of the day, for the user to read
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        motd: Option<String>,
+        /// The largest frame the server accepts, which the client splits
+        /// tunnel data to fit
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_frame_size: Option<u32>,
     },
 
+    /// Ask the server's CA to sign a client certificate, authenticating
+    /// like `Auth`. Sent on a connection of its own that the client closes
+    /// once answered.
+    Enroll {
+        client_id: String,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        token: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        proof: Option<String>,
+        /// PEM certificate signing request
+        csr: String,
+    },
+
+    /// The signed client certificate in PEM
+    Enrolled { certificate: String },
+
+    /// Ask the server for a replacement token
+    RequestTokenRotation,
+
+    /// A replacement token the client should use from now on, pushed by the
+    /// server or answering `RequestTokenRotation`
+    RotateToken {
+        token: String,
+        expires_at: DateTime<Utc>,
+    },
+
+    /// First message on a second connection that should carry this
+    /// client's tunnel data as binary frames
+    AttachDataChannel { client_id: String, key: Uuid },
+
+    /// Data channel accepted; binary frames follow on that connection
+    DataChannelAttached,
+
     /// Create a new tunnel
     CreateTunnel {
+        #[serde(default = "default_local_host")]
+        local_host: String,
         local_port: u16,
         remote_port: Option<u16>, // None for auto-assign
         protocol: TunnelProtocol,
         name: Option<String>,
+        /// Consecutive ports from `local_port` (and `remote_port`) the
+        /// tunnel covers
+        #[serde(default = "default_port_count", skip_serializing_if = "is_single_port")]
+        port_count: u16,
+        /// Close the tunnel automatically this many seconds after creation
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl_secs: Option<u64>,
+        /// Route HTTP requests for this domain to the tunnel as well
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        domain: Option<String>,
+        /// Credentials the server requires on HTTP requests to the tunnel
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        http_auth: Option<HttpAuth>,
+        /// Certificate the server presents for `domain` on HTTPS
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tls_certificate: Option<TlsCertificate>,
+        /// Hand HTTPS connections for `domain` to the tunnel still
+        /// encrypted, for the local service to terminate
+        #[serde(default)]
+        tls_passthrough: bool,
+        /// Answer plain-HTTP requests for `domain` with a redirect to HTTPS
+        #[serde(default)]
+        https_redirect: bool,
+        /// Headers the server sets on HTTP requests for `domain`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        http_headers: Option<HttpHeaders>,
+        /// DSCP class for the public connections of the tunnel
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dscp: Option<Dscp>,
+        /// How long a UDP tunnel keeps each visitor's session, and how
+        /// many it keeps at once
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        udp: Option<UdpSessions>,
     },
 
     /// Tunnel creation response
     TunnelCreated {
         tunnel_id: Uuid,
         remote_port: u16,
+        #[serde(default = "default_local_host")]
+        local_host: String,
         local_port: u16,
         protocol: TunnelProtocol,
         name: Option<String>,
+        #[serde(default = "default_port_count", skip_serializing_if = "is_single_port")]
+        port_count: u16,
+        /// When the server will close the tunnel, for tunnels with a TTL
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<DateTime<Utc>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        domain: Option<String>,
+        #[serde(default)]
+        tls_passthrough: bool,
+        /// Session settings the server holds a UDP tunnel to, including
+        /// the largest datagram it carries
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        udp: Option<UdpSessions>,
     },
 
     /// Close an existing tunnel
@@ -46,10 +172,42 @@ pub enum Message {
     /// Tunnel closed notification
     TunnelClosed { tunnel_id: Uuid, reason: String },
 
+    /// A tunnel the operator defined for this client on the server. The
+    /// client opens it unless its own configuration has one by that name.
+    ProvisionTunnel { tunnel: crate::config::TunnelConfig },
+
+    /// The operator no longer provisions the named tunnel; the client
+    /// closes it
+    UnprovisionTunnel { name: String },
+
+    /// Ask for a temporary extra public port for one of this client's
+    /// tunnels, separate from its stable one
+    CreateShare {
+        request_id: Uuid,
+        tunnel_id: Uuid,
+        ttl_secs: u64,
+        /// Visitors must send this as their first line
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+    },
+
+    /// Answer to `CreateShare`; `share` is absent when refused
+    ShareCreated {
+        request_id: Uuid,
+        share: Option<ShareInfo>,
+        error: Option<String>,
+    },
+
+    /// Close a share before it expires
+    CloseShare { share_id: Uuid },
+
+    /// Share closed, expired or gone with its tunnel
+    ShareClosed { share_id: Uuid, reason: String },
+
     /// Data transfer through tunnel
     Data {
         tunnel_id: Uuid,
-        data: Vec<u8>,
+        data: Bytes,
         connection_id: u32,
     },
 
@@ -58,17 +216,43 @@ pub enum Message {
         tunnel_id: Uuid,
         connection_id: u32,
         client_addr: SocketAddr,
+        /// Which of a port-range tunnel's ports the connection arrived on,
+        /// counted from its first port
+        #[serde(default, skip_serializing_if = "is_zero")]
+        port_offset: u16,
     },
 
     /// Connection closed
     ConnectionClosed { tunnel_id: Uuid, connection_id: u32 },
 
+    /// One direction of a connection reached end of stream; the other
+    /// keeps flowing until it ends too
+    ConnectionShutdown {
+        tunnel_id: Uuid,
+        connection_id: u32,
+        direction: ShutdownDirection,
+    },
+
+    /// A connection carried over from the client's previous session, with
+    /// the payload bytes the sender received for it in total. The other
+    /// side answers in kind and continues it, or closes it when some data
+    /// went missing in flight.
+    ResumeConnection {
+        tunnel_id: Uuid,
+        connection_id: u32,
+        received: u64,
+    },
+
     /// Heartbeat ping
     Ping { timestamp: DateTime<Utc> },
 
     /// Heartbeat pong response
     Pong { timestamp: DateTime<Utc> },
 
+    /// The sender is closing the connection on purpose, so the peer can
+    /// clean up at once instead of waiting for the stream to die
+    Disconnect { reason: String },
+
     /// Status request
     StatusRequest,
 
@@ -77,12 +261,239 @@ pub enum Message {
         tunnels: Vec<TunnelInfo>,
         connections: u32,
         uptime: u64, // seconds
+        #[serde(default)]
+        relays: Vec<RelayInfo>,
+        /// Traffic counted against the client's monthly quota
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        usage: Option<UsageInfo>,
+        /// How close the server is to its capacity limits
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        utilization: Option<Utilization>,
+    },
+
+    /// Ask for everything the server holds for this client, to compare
+    /// with what the client believes it has open
+    ListTunnels { request_id: Uuid },
+
+    /// Answer to `ListTunnels`: the client's tunnels, shares and relays
+    /// as the server has them
+    TunnelList {
+        request_id: Uuid,
+        tunnels: Vec<TunnelInfo>,
+        #[serde(default)]
+        shares: Vec<ShareInfo>,
+        #[serde(default)]
+        relays: Vec<RelayInfo>,
+    },
+
+    /// Request a relay port pair for a peer session
+    AllocateRelay { protocol: TunnelProtocol },
+
+    /// Relay allocation response
+    RelayAllocated { relay: RelayInfo },
+
+    /// Release a relay allocation
+    ReleaseRelay { relay_id: Uuid },
+
+    /// Relay released or expired
+    RelayClosed {
+        relay_id: Uuid,
+        bytes_relayed: u64,
+        reason: String,
+    },
+
+    /// Direct-path candidates offered to another client of the same
+    /// server. `peer` names the recipient when sent and is replaced with the
+    /// sender's client ID when the server delivers it.
+    CandidateOffer {
+        session_id: Uuid,
+        peer: String,
+        candidates: Vec<Candidate>,
+        /// Published service the offer is for, set by the server when it
+        /// introduces a subscriber
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        service: Option<String>,
+        /// Ephemeral X25519 key for an encrypted direct session
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        public_key: Vec<u8>,
+    },
+
+    /// Candidates sent back in reply to an offer, routed like the offer
+    CandidateAnswer {
+        session_id: Uuid,
+        peer: String,
+        candidates: Vec<Candidate>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        public_key: Vec<u8>,
+    },
+
+    /// Offer a service to other clients by name. Traffic flows directly
+    /// between the clients; the server only introduces them.
+    PublishService { name: String },
+
+    /// Service publication accepted
+    ServicePublished { name: String },
+
+    /// Withdraw a published service
+    UnpublishService { name: String },
+
+    /// Ask the server to introduce this client to a service's publisher,
+    /// which receives the candidates as a `CandidateOffer`
+    SubscribeService {
+        session_id: Uuid,
+        name: String,
+        candidates: Vec<Candidate>,
+        public_key: Vec<u8>,
+    },
+
+    /// Ask another client to send a Wake-on-LAN packet for one of its
+    /// configured machines. `peer` is routed like `CandidateOffer`.
+    WakeOnLan {
+        request_id: Uuid,
+        peer: String,
+        target: String,
+    },
+
+    /// Outcome of a `WakeOnLan`, routed back to the client that asked
+    WakeOnLanResult {
+        request_id: Uuid,
+        peer: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+
+    /// Ask the server to measure one of this client's tunnels. The server
+    /// connects through it like a visitor, to a test service the client
+    /// runs behind it, and times round trips and transfers both ways.
+    SpeedTest {
+        request_id: Uuid,
+        tunnel_id: Uuid,
+        /// Bytes to transfer in each direction
+        bytes: u64,
+    },
+
+    /// Measurements of a `SpeedTest`, or why it failed
+    SpeedTestResult {
+        request_id: Uuid,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        report: Option<SpeedTestReport>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
     },
 
+    /// Ask a client for its most recent log lines, first raising its log
+    /// level to `level` for `duration_secs` when given, so an operator can
+    /// look into a problem without the user editing anything
+    RequestLogs {
+        request_id: Uuid,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        level: Option<String>,
+        #[serde(default)]
+        duration_secs: u64,
+        max_lines: u32,
+    },
+
+    /// The lines a `RequestLogs` asked for, oldest first, or why the client
+    /// refused
+    Logs {
+        request_id: Uuid,
+        #[serde(default)]
+        lines: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+
+    /// Join the server's layer-3 VPN, routing packets for `advertise`, the
+    /// subnets behind this client, to it
+    JoinVpn {
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        advertise: Vec<Subnet>,
+    },
+
+    /// The client's address on the VPN, with the overlay's prefix
+    VpnJoined { address: Subnet },
+
+    /// An IPv4 packet travelling through the VPN
+    VpnPacket { data: Bytes },
+
+    /// Join the server's WireGuard mesh with `public_key`. `endpoint` is
+    /// the public address of the client's WireGuard port, when STUN found
+    /// it; `advertise` lists the subnets behind the client.
+    JoinWireGuard {
+        public_key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        endpoint: Option<SocketAddr>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        advertise: Vec<Subnet>,
+    },
+
+    /// The client's address on the mesh, with the overlay's prefix
+    WireGuardJoined { address: Subnet },
+
+    /// Every other member of the mesh, sent again whenever one joins or
+    /// leaves
+    WireGuardPeers { peers: Vec<WireGuardPeer> },
+
     /// Error message
     Error { code: ErrorCode, message: String },
 }
 
+/// Which half of a tunneled connection is finished, seen from the local
+/// service
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShutdownDirection {
+    /// The visitor stopped sending; the service reads end of stream
+    Inbound,
+    /// The service stopped sending; the visitor reads end of stream
+    Outbound,
+}
+
+/// A client's heartbeat schedule, advertised when it authenticates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeartbeatSettings {
+    pub interval_secs: u64,
+    /// Heartbeats that may go unanswered before the connection counts as
+    /// dead; 0 never gives up on it
+    pub max_missed: u32,
+}
+
+impl HeartbeatSettings {
+    /// How long a connection may stay silent, `None` when it may forever
+    pub fn timeout(&self) -> Option<std::time::Duration> {
+        (self.interval_secs > 0 && self.max_missed > 0)
+            .then(|| std::time::Duration::from_secs(self.interval_secs * self.max_missed as u64))
+    }
+}
+
+/// What a speed test through a tunnel measured. Downloads go from the
+/// server to the client, uploads the other way.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpeedTestReport {
+    /// Round trips through the tunnel to the test service, in milliseconds
+    pub round_trips_ms: Vec<f64>,
+    pub download_bytes: u64,
+    pub download_ms: f64,
+    pub upload_bytes: u64,
+    pub upload_ms: f64,
+}
+
+/// Default target host for tunneled connections
+pub fn default_local_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+pub fn default_port_count() -> u16 {
+    1
+}
+
+pub fn is_single_port(count: &u16) -> bool {
+    *count == 1
+}
+
+fn is_zero(offset: &u16) -> bool {
+    *offset == 0
+}
+
 /// Supported tunnel protocols
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
 pub enum TunnelProtocol {
@@ -91,18 +502,270 @@ pub enum TunnelProtocol {
     Udp,
 }
 
+/// Credentials the server asks for before passing HTTP requests to a
+/// tunnel: a user name and password for Basic auth, a bearer token, or
+/// both, in which case either is accepted
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HttpAuth {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bearer_token: Option<String>,
+}
+
+/// Headers the server's HTTP listener sets on requests before passing
+/// them to a tunnel, so the local service sees who is asking
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HttpHeaders {
+    /// Set `X-Forwarded-For`, `X-Forwarded-Proto`, `X-Forwarded-Host` and
+    /// `X-Real-IP`, replacing whatever the visitor sent
+    #[serde(default)]
+    pub forwarded: bool,
+    /// Replace the `Host` header, for services that only answer to their
+    /// own name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Further headers to set, replacing any of the same name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub set: BTreeMap<String, String>,
+}
+
+/// How a UDP tunnel keeps its sessions. Each visitor address gets a
+/// session of its own, carried to the client like a TCP connection, until
+/// no datagram has passed either way for the idle timeout: a few seconds
+/// suit DNS, games want minutes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UdpSessions {
+    /// Seconds a quiet session keeps its visitor's address mapped
+    #[serde(default = "default_udp_idle_timeout")]
+    pub idle_timeout_secs: u64,
+    /// Sessions the tunnel holds at once; datagrams from further visitors
+    /// are dropped until one ends
+    #[serde(default = "default_udp_max_sessions")]
+    pub max_sessions: u32,
+    /// Largest datagram the tunnel carries, cut into data frames on the
+    /// way and put back together at the far end; longer ones are dropped
+    #[serde(default = "default_udp_max_datagram")]
+    pub max_datagram_size: u16,
+}
+
+fn default_udp_idle_timeout() -> u64 {
+    60
+}
+
+fn default_udp_max_sessions() -> u32 {
+    256
+}
+
+fn default_udp_max_datagram() -> u16 {
+    crate::datagram::MAX_UDP_PAYLOAD
+}
+
+impl Default for UdpSessions {
+    fn default() -> Self {
+        Self {
+            idle_timeout_secs: default_udp_idle_timeout(),
+            max_sessions: default_udp_max_sessions(),
+            max_datagram_size: default_udp_max_datagram(),
+        }
+    }
+}
+
+/// A certificate chain and its private key, both PEM
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TlsCertificate {
+    pub cert_pem: String,
+    pub key_pem: String,
+}
+
+impl std::fmt::Debug for TlsCertificate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsCertificate").finish_non_exhaustive()
+    }
+}
+
+/// A client's traffic in the current calendar month (UTC)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageInfo {
+    /// As `YYYY-MM`
+    pub month: String,
+    pub bytes: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota_bytes: Option<u64>,
+}
+
+/// How much of the server's tunnels, public connections and ports is in use
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Utilization {
+    pub tunnels: Gauge,
+    pub connections: Gauge,
+    pub ports: Gauge,
+}
+
+/// One resource in use, against its cap when there is one
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Gauge {
+    pub used: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+}
+
+impl Gauge {
+    /// Share of the cap in use, when capped
+    pub fn percent(&self) -> Option<f64> {
+        self.limit
+            .map(|limit| self.used as f64 * 100.0 / limit.max(1) as f64)
+    }
+}
+
 /// Tunnel information for status reporting
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TunnelInfo {
     pub id: Uuid,
     pub name: Option<String>,
     pub protocol: TunnelProtocol,
+    /// Host the client forwards tunnel traffic to
+    #[serde(default = "default_local_host")]
+    pub local_host: String,
     pub local_port: u16,
     pub remote_port: u16,
     pub created_at: DateTime<Utc>,
     pub bytes_sent: u64,
     pub bytes_received: u64,
     pub active_connections: u32,
+    /// How public traffic reaches the client
+    #[serde(default)]
+    pub mode: TunnelMode,
+    /// Consecutive ports covered, starting at `remote_port` and
+    /// `local_port`
+    #[serde(default = "default_port_count", skip_serializing_if = "is_single_port")]
+    pub port_count: u16,
+    /// When the server closes the tunnel, if it was created with a TTL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Domain whose HTTP requests the server routes to the tunnel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    /// HTTPS connections for `domain` reach the local service encrypted
+    #[serde(default)]
+    pub tls_passthrough: bool,
+    /// Session settings of a UDP tunnel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub udp: Option<UdpSessions>,
+}
+
+impl TunnelInfo {
+    /// Public ports of the tunnel
+    pub fn remote_ports(&self) -> PortRange {
+        port_range(self.remote_port, self.port_count)
+    }
+
+    /// Ports on the local host the public ports map to
+    pub fn local_ports(&self) -> PortRange {
+        port_range(self.local_port, self.port_count)
+    }
+
+    /// `host:port` to connect to for public port `port_offset` of the
+    /// tunnel, with an IPv6 address bracketed
+    pub fn local_target(&self, port_offset: u16) -> String {
+        let host = self
+            .local_host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(&self.local_host);
+        let port = self.local_port.saturating_add(port_offset);
+        match host.parse::<Ipv6Addr>() {
+            Ok(_) => format!("[{}]:{}", host, port),
+            Err(_) => format!("{}:{}", host, port),
+        }
+    }
+}
+
+/// `count` ports from `start`
+pub fn port_range(start: u16, count: u16) -> PortRange {
+    PortRange {
+        start,
+        end: start.saturating_add(count.max(1) - 1),
+    }
+}
+
+/// Path public traffic takes to a tunnel
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum TunnelMode {
+    /// Through a port opened on the server
+    #[default]
+    Server,
+    /// Directly, through a port mapped on the client's router
+    Upnp,
+    NatPmp,
+    Pcp,
+    /// Through a remote forward on an SSH server
+    Ssh,
+}
+
+/// Relay allocation for a peer session.
+///
+/// Each peer claims one port by sending the 16 relay ID bytes first (as the
+/// first bytes of a TCP stream, or as a UDP datagram); afterwards traffic
+/// arriving on one port is forwarded to the peer on the other.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayInfo {
+    pub id: Uuid,
+    pub protocol: TunnelProtocol,
+    pub port_a: u16,
+    pub port_b: u16,
+    pub created_at: DateTime<Utc>,
+    pub bytes_relayed: u64,
+}
+
+/// Another client on the WireGuard mesh
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WireGuardPeer {
+    pub client_id: String,
+    pub public_key: String,
+    /// The peer's overlay address and the subnets it advertises
+    pub allowed_ips: Vec<Subnet>,
+    /// Public address of the peer's WireGuard port, when known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<SocketAddr>,
+    /// Server UDP port relaying traffic to the peer
+    pub relay_port: u16,
+}
+
+/// A temporary public endpoint for a tunnel. Connections to `port` reach
+/// the tunnel like those to its own port until `expires_at`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareInfo {
+    pub id: Uuid,
+    pub tunnel_id: Uuid,
+    pub port: u16,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// Visitors have to send the password line first
+    pub password_protected: bool,
+}
+
+/// Kind of address a candidate was gathered from
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum CandidateKind {
+    /// Address of a local interface
+    Host,
+    /// Public address learned through STUN
+    ServerReflexive,
+    /// Port allocated on the server's relay
+    Relay,
+}
+
+/// Transport address a peer may be reachable on
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Candidate {
+    pub kind: CandidateKind,
+    pub protocol: TunnelProtocol,
+    pub addr: SocketAddr,
+    /// Higher is preferred, computed as in RFC 8445 section 5.1.2
+    pub priority: u32,
 }
 
 /// Error codes for protocol errors
@@ -116,6 +779,8 @@ pub enum ErrorCode {
     RateLimitExceeded,
     InternalError,
     ProtocolVersionMismatch,
+    /// The client a message was addressed to is not connected
+    PeerNotFound,
 }
 
 impl Message {
@@ -128,6 +793,28 @@ impl Message {
     pub fn from_bytes(data: &[u8]) -> anyhow::Result<Self> {
         Ok(serde_json::from_slice(data)?)
     }
+
+    /// Append the message to `buf` as a length-prefixed control frame
+    pub fn encode_frame(&self, buf: &mut BytesMut) -> anyhow::Result<()> {
+        let start = buf.len();
+        buf.put_u32(0);
+        serde_json::to_writer(buf.writer(), self)?;
+        let len = (buf.len() - start - 4) as u32;
+        buf[start..start + 4].copy_from_slice(&len.to_be_bytes());
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for TunnelMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TunnelMode::Server => write!(f, "server"),
+            TunnelMode::Upnp => write!(f, "direct (UPnP)"),
+            TunnelMode::NatPmp => write!(f, "direct (NAT-PMP)"),
+            TunnelMode::Pcp => write!(f, "direct (PCP)"),
+            TunnelMode::Ssh => write!(f, "ssh"),
+        }
+    }
 }
 
 impl std::fmt::Display for TunnelProtocol {
@@ -150,6 +837,7 @@ impl std::fmt::Display for ErrorCode {
             ErrorCode::RateLimitExceeded => write!(f, "Rate limit exceeded"),
             ErrorCode::InternalError => write!(f, "Internal server error"),
             ErrorCode::ProtocolVersionMismatch => write!(f, "Protocol version mismatch"),
+            ErrorCode::PeerNotFound => write!(f, "Peer not found"),
         }
     }
 }
@@ -0,0 +1,204 @@
+//! Payload queues for tunneled connections.
+//!
+//! A capacity of zero keeps the queue unbounded; otherwise senders wait for
+//! room, which pushes back on whichever socket is producing the data.
+//!
+//! Independently of the item count, a queue may cap the bytes it holds.
+//! Going over refuses the item instead of waiting, so a consumer that
+//! stopped reading gets its connection dropped rather than stalling the
+//! session that feeds every other connection.
+
+use bytes::Bytes;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::sync::mpsc;
+
+/// Items whose size counts against a queue's byte cap
+pub trait Weigh {
+    fn weight(&self) -> usize;
+}
+
+impl Weigh for Bytes {
+    fn weight(&self) -> usize {
+        self.len()
+    }
+}
+
+/// Why an item was not queued; either way the caller gets it back
+#[derive(Debug, PartialEq, Eq)]
+pub enum SendError<T> {
+    /// The receiver is gone
+    Closed(T),
+    /// The queue already holds as many bytes as it may
+    Full(T),
+}
+
+enum Sender<T> {
+    Bounded(mpsc::Sender<T>),
+    Unbounded(mpsc::UnboundedSender<T>),
+}
+
+enum Receiver<T> {
+    Bounded(mpsc::Receiver<T>),
+    Unbounded(mpsc::UnboundedReceiver<T>),
+}
+
+/// Bytes queued but not yet received, shared by both ends
+struct Buffered {
+    bytes: AtomicUsize,
+    /// Zero leaves the bytes uncapped
+    limit: usize,
+}
+
+pub struct QueueSender<T> {
+    tx: Sender<T>,
+    buffered: Arc<Buffered>,
+}
+
+pub struct QueueReceiver<T> {
+    rx: Receiver<T>,
+    buffered: Arc<Buffered>,
+}
+
+/// Create a queue holding at most `capacity` items, or any number when 0,
+/// and at most `max_bytes` of them, or any amount when 0. An item larger
+/// than `max_bytes` still fits into an empty queue.
+pub fn queue<T: Weigh>(capacity: usize, max_bytes: usize) -> (QueueSender<T>, QueueReceiver<T>) {
+    let buffered = Arc::new(Buffered {
+        bytes: AtomicUsize::new(0),
+        limit: max_bytes,
+    });
+    let (tx, rx) = if capacity == 0 {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Sender::Unbounded(tx), Receiver::Unbounded(rx))
+    } else {
+        let (tx, rx) = mpsc::channel(capacity);
+        (Sender::Bounded(tx), Receiver::Bounded(rx))
+    };
+    (
+        QueueSender {
+            tx,
+            buffered: buffered.clone(),
+        },
+        QueueReceiver { rx, buffered },
+    )
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        let tx = match &self.tx {
+            Sender::Bounded(tx) => Sender::Bounded(tx.clone()),
+            Sender::Unbounded(tx) => Sender::Unbounded(tx.clone()),
+        };
+        Self {
+            tx,
+            buffered: self.buffered.clone(),
+        }
+    }
+}
+
+impl<T: Weigh> QueueSender<T> {
+    /// Queue an item, waiting for room in a bounded queue. Fails with the
+    /// item when the receiver is gone or the byte cap is reached.
+    pub async fn send(&self, item: T) -> Result<(), SendError<T>> {
+        let weight = item.weight();
+        let limit = self.buffered.limit;
+        let reserved =
+            self.buffered
+                .bytes
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |bytes| {
+                    (limit == 0 || bytes == 0 || bytes + weight <= limit).then_some(bytes + weight)
+                });
+        if reserved.is_err() {
+            return Err(SendError::Full(item));
+        }
+        let sent = match &self.tx {
+            Sender::Bounded(tx) => tx.send(item).await.map_err(|e| e.0),
+            Sender::Unbounded(tx) => tx.send(item).map_err(|e| e.0),
+        };
+        sent.map_err(|item| {
+            self.buffered.bytes.fetch_sub(weight, Ordering::AcqRel);
+            SendError::Closed(item)
+        })
+    }
+
+    /// Bytes queued and not yet received
+    pub fn buffered(&self) -> usize {
+        self.buffered.bytes.load(Ordering::Acquire)
+    }
+}
+
+impl<T: Weigh> QueueReceiver<T> {
+    pub async fn recv(&mut self) -> Option<T> {
+        let item = match &mut self.rx {
+            Receiver::Bounded(rx) => rx.recv().await,
+            Receiver::Unbounded(rx) => rx.recv().await,
+        }?;
+        self.buffered
+            .bytes
+            .fetch_sub(item.weight(), Ordering::AcqRel);
+        Some(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Weigh for i32 {
+        fn weight(&self) -> usize {
+            1
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bounded_queue_waits_for_room() {
+        let (tx, mut rx) = queue(1, 0);
+        tx.send(1).await.unwrap();
+        let blocked = tokio::time::timeout(std::time::Duration::from_millis(50), tx.send(2)).await;
+        assert!(blocked.is_err());
+        assert_eq!(rx.recv().await, Some(1));
+        tx.send(3).await.unwrap();
+        assert_eq!(rx.recv().await, Some(3));
+
+        let (tx, mut rx) = queue(0, 0);
+        for i in 0..100 {
+            tx.send(i).await.unwrap();
+        }
+        drop(tx);
+        let mut count = 0;
+        while rx.recv().await.is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 100);
+    }
+
+    #[tokio::test]
+    async fn test_byte_cap_refuses_instead_of_waiting() {
+        let (tx, mut rx) = queue::<Bytes>(0, 8);
+        // Anything fits into an empty queue
+        tx.send(Bytes::from_static(b"0123456789")).await.unwrap();
+        assert_eq!(tx.buffered(), 10);
+        assert_eq!(
+            tx.send(Bytes::from_static(b"x")).await,
+            Err(SendError::Full(Bytes::from_static(b"x")))
+        );
+
+        rx.recv().await.unwrap();
+        assert_eq!(tx.buffered(), 0);
+        tx.send(Bytes::from_static(b"0123")).await.unwrap();
+        tx.send(Bytes::from_static(b"4567")).await.unwrap();
+        assert!(matches!(
+            tx.send(Bytes::from_static(b"8")).await,
+            Err(SendError::Full(_))
+        ));
+
+        drop(rx);
+        assert!(matches!(
+            tx.send(Bytes::new()).await,
+            Err(SendError::Closed(_))
+        ));
+    }
+}
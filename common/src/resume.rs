@@ -0,0 +1,356 @@
+//! Carrying tunneled connections across a brief loss of the session.
+//!
+//! Each side numbers the payload bytes of every connection by their offset
+//! in the stream, counting what it hands to the session and what it
+//! receives from it, and keeps the most recently sent bytes around. When
+//! the session drops, a connection's messages wait in a small buffer
+//! instead of failing. Once the client is back, each side sends
+//! `ResumeConnection` with the offset it received up to; the other side
+//! replays whatever it sent past that offset, since it was lost in flight,
+//! and the held messages follow. When the lost bytes are no longer kept,
+//! the buffer fills up or the window passes first, the connection closes.
+
+use crate::protocol::Message;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// How long and how much of a connection is held for its session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResumeLimits {
+    /// Zero closes connections along with their session
+    pub window: Duration,
+    /// Payload bytes held per connection, and sent bytes kept for replay
+    pub buffer: usize,
+}
+
+impl ResumeLimits {
+    pub fn new(window_secs: u64, buffer_kb: usize) -> Self {
+        Self {
+            window: Duration::from_secs(window_secs),
+            buffer: buffer_kb.saturating_mul(1024),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.window.is_zero()
+    }
+}
+
+/// The way one connection's messages reach the session: straight through
+/// while it is up, into a capped buffer while it is away
+pub struct Outbox {
+    tx: mpsc::UnboundedSender<Message>,
+    limits: ResumeLimits,
+    held: VecDeque<Message>,
+    held_bytes: usize,
+    /// When the connection stops waiting for its session, set while holding
+    deadline: Option<Instant>,
+    /// Payload bytes handed to the session
+    sent: u64,
+    /// The last data messages handed to the session, for replay
+    replay: VecDeque<Message>,
+    replay_bytes: usize,
+}
+
+impl Outbox {
+    pub fn new(tx: mpsc::UnboundedSender<Message>, limits: ResumeLimits) -> Self {
+        Self {
+            tx,
+            limits,
+            held: VecDeque::new(),
+            held_bytes: 0,
+            deadline: None,
+            sent: 0,
+            replay: VecDeque::new(),
+            replay_bytes: 0,
+        }
+    }
+
+    /// Payload bytes handed to the session so far
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// Whether the channel messages go out on has closed
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// Carry on over `tx` after the channel in use closed while the
+    /// session stayed up, as a data channel of the session does. Nothing
+    /// changes while holding for the session; it resumes on its own.
+    pub fn reroute(&mut self, tx: mpsc::UnboundedSender<Message>) {
+        if self.deadline.is_none() {
+            self.tx = tx;
+        }
+    }
+
+    /// Send `message`, or hold it while the session is away. False when
+    /// the connection has to close instead: the session is gone and
+    /// resumption is off, or the buffer is full.
+    pub fn send(&mut self, message: Message) -> bool {
+        let message = if self.deadline.is_none() {
+            let len = payload_len(&message);
+            let copy = (len > 0 && self.limits.enabled()).then(|| message.clone());
+            match self.tx.send(message) {
+                Ok(()) => {
+                    self.sent += len as u64;
+                    if let Some(copy) = copy {
+                        self.keep(copy, len);
+                    }
+                    return true;
+                }
+                Err(e) if self.hold() => e.0,
+                Err(_) => return false,
+            }
+        } else {
+            message
+        };
+        self.held_bytes += payload_len(&message);
+        self.held.push_back(message);
+        self.held_bytes <= self.limits.buffer
+    }
+
+    /// Keep a sent message for replay, forgetting the oldest ones beyond
+    /// the last `buffer` bytes
+    fn keep(&mut self, message: Message, len: usize) {
+        self.replay.push_back(message);
+        self.replay_bytes += len;
+        while let Some(front) = self.replay.front() {
+            let front_len = payload_len(front);
+            if self.replay_bytes - front_len < self.limits.buffer {
+                break;
+            }
+            self.replay_bytes -= front_len;
+            self.replay.pop_front();
+        }
+    }
+
+    /// Hold messages until the session comes back; false when resumption
+    /// is off
+    pub fn hold(&mut self) -> bool {
+        if !self.limits.enabled() {
+            return false;
+        }
+        if self.deadline.is_none() {
+            self.deadline = Some(Instant::now() + self.limits.window);
+        }
+        true
+    }
+
+    /// Resolves once the session has been away for the whole window, never
+    /// while it is up
+    pub fn expiry(&self) -> impl Future<Output = ()> + 'static {
+        let deadline = self.deadline;
+        async move {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    /// Continue on `tx` after the peer reported receiving up to offset
+    /// `received`, replaying what was sent past it and then sending what
+    /// was held. False when the lost bytes are no longer kept, so the
+    /// connection has to close.
+    pub fn resume(&mut self, tx: mpsc::UnboundedSender<Message>, received: u64) -> bool {
+        self.tx = tx;
+        self.deadline = None;
+        let held = std::mem::take(&mut self.held);
+        self.held_bytes = 0;
+        if !self.replay_from(received) {
+            return false;
+        }
+        held.into_iter().all(|message| self.send(message))
+    }
+
+    /// Send again the bytes from offset `received` up to what was sent
+    fn replay_from(&mut self, received: u64) -> bool {
+        let kept_from = self.sent - self.replay_bytes as u64;
+        if received < kept_from || received > self.sent {
+            return false;
+        }
+        let mut skip = (received - kept_from) as usize;
+        for message in &self.replay {
+            let len = payload_len(message);
+            if skip >= len {
+                skip -= len;
+                continue;
+            }
+            let message = match message {
+                Message::Data {
+                    tunnel_id,
+                    data,
+                    connection_id,
+                } => Message::Data {
+                    tunnel_id: *tunnel_id,
+                    data: data.slice(skip..),
+                    connection_id: *connection_id,
+                },
+                other => other.clone(),
+            };
+            skip = 0;
+            if self.tx.send(message).is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Send a message that only matters to a live session, such as a close
+    pub fn notify(&self, message: Message) {
+        if self.deadline.is_none() {
+            let _ = self.tx.send(message);
+        }
+    }
+}
+
+fn payload_len(message: &Message) -> usize {
+    match message {
+        Message::Data { data, .. } => data.len(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use uuid::Uuid;
+
+    fn data(tunnel_id: Uuid, bytes: &'static [u8]) -> Message {
+        Message::Data {
+            tunnel_id,
+            data: Bytes::from_static(bytes),
+            connection_id: 1,
+        }
+    }
+
+    fn payload(message: Message) -> Vec<u8> {
+        match message {
+            Message::Data { data, .. } => data.to_vec(),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outbox_holds_and_resumes() {
+        let tunnel_id = Uuid::new_v4();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut outbox = Outbox::new(tx, ResumeLimits::new(60, 1));
+        assert!(outbox.send(data(tunnel_id, b"abc")));
+        assert_eq!(outbox.sent(), 3);
+
+        // The session goes away and the next messages wait for it
+        drop(rx);
+        assert!(outbox.send(data(tunnel_id, b"de")));
+        assert!(outbox.send(data(tunnel_id, b"f")));
+        assert_eq!(outbox.sent(), 3);
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        assert!(outbox.resume(tx, 3));
+        assert_eq!(payload(rx.recv().await.unwrap()), b"de");
+        assert_eq!(payload(rx.recv().await.unwrap()), b"f");
+        assert_eq!(outbox.sent(), 6);
+
+        // Bytes the peer never got are sent again from its offset
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        assert!(outbox.hold());
+        assert!(outbox.resume(tx, 4));
+        assert_eq!(payload(rx.recv().await.unwrap()), b"e");
+        assert_eq!(payload(rx.recv().await.unwrap()), b"f");
+        assert_eq!(outbox.sent(), 6);
+
+        // The peer cannot have received more than was sent
+        let (tx, _rx) = mpsc::unbounded_channel();
+        assert!(outbox.hold());
+        assert!(!outbox.resume(tx, 7));
+    }
+
+    #[tokio::test]
+    async fn test_outbox_replays_recent_bytes_only() {
+        let tunnel_id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut outbox = Outbox::new(tx, ResumeLimits::new(60, 1));
+        assert!(outbox.send(data(tunnel_id, &[1; 1000])));
+        assert!(outbox.send(data(tunnel_id, &[2; 1000])));
+        assert!(outbox.send(data(tunnel_id, &[3; 100])));
+
+        // The first message fell out of the last 1 KiB
+        let (tx, _rx) = mpsc::unbounded_channel();
+        assert!(outbox.hold());
+        assert!(!outbox.resume(tx, 500));
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        assert!(outbox.hold());
+        assert!(outbox.resume(tx, 1900));
+        assert_eq!(payload(rx.recv().await.unwrap()), [2; 100]);
+        assert_eq!(payload(rx.recv().await.unwrap()), [3; 100]);
+    }
+
+    #[tokio::test]
+    async fn test_outbox_reroutes() {
+        let tunnel_id = Uuid::new_v4();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut outbox = Outbox::new(tx, ResumeLimits::new(0, 1));
+        assert!(outbox.send(data(tunnel_id, b"ab")));
+        drop(rx);
+        assert!(outbox.is_closed());
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        outbox.reroute(tx);
+        assert!(!outbox.is_closed());
+        assert!(outbox.send(data(tunnel_id, b"c")));
+        assert_eq!(payload(rx.recv().await.unwrap()), b"c");
+        assert_eq!(outbox.sent(), 3);
+
+        // A held connection waits for its session instead
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut outbox = Outbox::new(tx, ResumeLimits::new(60, 1));
+        drop(rx);
+        assert!(outbox.hold());
+        let (tx, _rx) = mpsc::unbounded_channel();
+        outbox.reroute(tx);
+        assert!(outbox.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_outbox_gives_up() {
+        let tunnel_id = Uuid::new_v4();
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut outbox = Outbox::new(tx, ResumeLimits::new(60, 1));
+        drop(rx);
+        assert!(outbox.send(data(tunnel_id, &[0; 1024])));
+        assert!(!outbox.send(data(tunnel_id, b"x")));
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut outbox = Outbox::new(tx, ResumeLimits::new(0, 1));
+        drop(rx);
+        assert!(!outbox.hold());
+        assert!(!outbox.send(data(tunnel_id, b"x")));
+    }
+
+    #[tokio::test]
+    async fn test_outbox_expires() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut outbox = Outbox::new(
+            tx,
+            ResumeLimits {
+                window: Duration::from_millis(50),
+                buffer: 1024,
+            },
+        );
+        let idle = tokio::time::timeout(Duration::from_millis(200), outbox.expiry()).await;
+        assert!(idle.is_err());
+
+        assert!(outbox.hold());
+        let started = Instant::now();
+        outbox.expiry().await;
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,218 @@
+//! Weekly time windows a configured tunnel is open in, written like
+//! `"mon-fri 09:00-18:00"`. Times are the client's local time.
+
+use chrono::{Datelike, Duration, NaiveDateTime, NaiveTime, Timelike};
+use serde::{Deserialize, Serialize};
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Every day of the week, one bit per day from Monday
+const EVERY_DAY: u8 = 0x7f;
+
+/// Days of the week and a daily span of time. A span ending at or before
+/// its start runs past midnight into the next day; `00:00-24:00` is the
+/// whole day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduleWindow {
+    /// One bit per day the window starts on, Monday first
+    days: u8,
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl ScheduleWindow {
+    pub fn contains(&self, at: NaiveDateTime) -> bool {
+        let day = at.weekday().num_days_from_monday();
+        let time = at.time();
+        if self.start < self.end {
+            self.starts_on(day) && time >= self.start && time < self.end
+        } else {
+            (self.starts_on(day) && time >= self.start)
+                || (self.starts_on((day + 6) % 7) && time < self.end)
+        }
+    }
+
+    fn starts_on(&self, day: u32) -> bool {
+        self.days & (1 << day) != 0
+    }
+}
+
+/// Whether any of `windows` contains `at`
+pub fn is_active(windows: &[ScheduleWindow], at: NaiveDateTime) -> bool {
+    windows.iter().any(|window| window.contains(at))
+}
+
+/// The next minute after `at` when `windows` open or close, `None` when
+/// they never change
+pub fn next_change(windows: &[ScheduleWindow], at: NaiveDateTime) -> Option<NaiveDateTime> {
+    let active = is_active(windows, at);
+    let mut minute = at.with_second(0)?.with_nanosecond(0)?;
+    // A week and a minute covers every window's edges
+    for _ in 0..=7 * 24 * 60 {
+        minute += Duration::minutes(1);
+        if is_active(windows, minute) != active {
+            return Some(minute);
+        }
+    }
+    None
+}
+
+impl std::str::FromStr for ScheduleWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid schedule '{}', expected e.g. 'mon-fri 09:00-18:00'",
+                s
+            )
+        };
+        let (days, span) = match s.trim().rsplit_once(char::is_whitespace) {
+            Some((days, span)) => (parse_days(days.trim()).ok_or_else(invalid)?, span),
+            None => (EVERY_DAY, s.trim()),
+        };
+        let (start, end) = span.split_once('-').ok_or_else(invalid)?;
+        let start = parse_time(start).ok_or_else(invalid)?;
+        let end = parse_time(end).ok_or_else(invalid)?;
+        Ok(Self { days, start, end })
+    }
+}
+
+fn parse_days(s: &str) -> Option<u8> {
+    let day = |name: &str| {
+        DAYS.iter()
+            .position(|day| name.eq_ignore_ascii_case(day))
+            .map(|day| day as u32)
+    };
+    match s.to_ascii_lowercase().as_str() {
+        "daily" => return Some(EVERY_DAY),
+        "weekdays" => return Some(0x1f),
+        "weekends" => return Some(0x60),
+        _ => {}
+    }
+    let mut days = 0;
+    for part in s.split(',') {
+        let part = part.trim();
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (day(first.trim())?, day(last.trim())?);
+                // Ranges may wrap around the week, e.g. `sat-mon`
+                let mut day = first;
+                loop {
+                    days |= 1 << day;
+                    if day == last {
+                        break;
+                    }
+                    day = (day + 1) % 7;
+                }
+            }
+            None => days |= 1 << day(part)?,
+        }
+    }
+    Some(days)
+}
+
+/// `HH:MM`, with `24:00` for the end of the day
+fn parse_time(s: &str) -> Option<NaiveTime> {
+    if s.trim() == "24:00" {
+        return Some(NaiveTime::MIN);
+    }
+    NaiveTime::parse_from_str(s.trim(), "%H:%M").ok()
+}
+
+impl std::fmt::Display for ScheduleWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.days != EVERY_DAY {
+            // Runs of three or more days are written as ranges
+            let mut parts = Vec::new();
+            let mut day = 0;
+            while day < 7 {
+                if !self.starts_on(day) {
+                    day += 1;
+                    continue;
+                }
+                let first_day = day;
+                while day + 1 < 7 && self.starts_on(day + 1) {
+                    day += 1;
+                }
+                let (first, last) = (DAYS[first_day as usize], DAYS[day as usize]);
+                match day - first_day {
+                    0 => parts.push(first.to_string()),
+                    1 => parts.extend([first, last].map(String::from)),
+                    _ => parts.push(format!("{}-{}", first, last)),
+                }
+                day += 1;
+            }
+            write!(f, "{} ", parts.join(","))?;
+        }
+        let end = if self.end == NaiveTime::MIN {
+            "24:00".to_string()
+        } else {
+            self.end.format("%H:%M").to_string()
+        };
+        write!(f, "{}-{}", self.start.format("%H:%M"), end)
+    }
+}
+
+impl Serialize for ScheduleWindow {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ScheduleWindow {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(day: u32, time: &str) -> NaiveDateTime {
+        // 2026-10-12 is a Monday
+        NaiveDate::from_ymd_opt(2026, 10, 12 + day)
+            .unwrap()
+            .and_time(NaiveTime::parse_from_str(time, "%H:%M").unwrap())
+    }
+
+    #[test]
+    fn test_schedule_windows() {
+        let office: ScheduleWindow = "mon-fri 09:00-18:00".parse().unwrap();
+        assert_eq!(office.to_string(), "mon-fri 09:00-18:00");
+        assert!(office.contains(at(0, "09:00")));
+        assert!(!office.contains(at(0, "18:00")));
+        assert!(!office.contains(at(5, "12:00")));
+
+        let night: ScheduleWindow = "fri,sat 22:00-06:00".parse().unwrap();
+        assert!(night.contains(at(4, "23:00")));
+        assert!(night.contains(at(6, "05:59")));
+        assert!(!night.contains(at(0, "05:00")));
+
+        let whole: ScheduleWindow = "sat-mon 00:00-24:00".parse().unwrap();
+        assert_eq!(whole.to_string(), "mon,sat,sun 00:00-24:00");
+        assert!(whole.contains(at(0, "23:59")));
+        assert!(!whole.contains(at(1, "00:00")));
+
+        assert_eq!(
+            "12:00-13:00".parse::<ScheduleWindow>().unwrap().to_string(),
+            "12:00-13:00"
+        );
+        assert!("mon-xyz 09:00-18:00".parse::<ScheduleWindow>().is_err());
+        assert!("mon 9-18".parse::<ScheduleWindow>().is_err());
+    }
+
+    #[test]
+    fn test_next_change() {
+        let windows = ["mon-fri 09:00-18:00".parse().unwrap()];
+        assert_eq!(next_change(&windows, at(0, "08:30")), Some(at(0, "09:00")));
+        assert_eq!(next_change(&windows, at(4, "18:30")), Some(at(7, "09:00")));
+        assert_eq!(next_change(&[], at(0, "08:30")), None);
+        let always = ["00:00-24:00".parse().unwrap()];
+        assert_eq!(next_change(&always, at(0, "08:30")), None);
+    }
+}
@@ -0,0 +1,257 @@
+//! Encrypted framing for direct client-to-client sessions.
+//!
+//! Peers exchange ephemeral X25519 keys through the server and mix the
+//! shared secret with a service secret the server never sees, so a broker
+//! that swaps keys still cannot read or inject traffic. Each TCP stream
+//! gets its own keys from a random salt sent in the clear by the dialer.
+
+use ring::{aead, agreement, hkdf, rand::SecureRandom};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use uuid::Uuid;
+
+/// Largest plaintext carried in one frame
+pub const MAX_FRAME: usize = 16 * 1024;
+pub const STREAM_SALT_LEN: usize = 16;
+
+/// Ephemeral key pair for one session
+pub struct KeyPair {
+    private: agreement::EphemeralPrivateKey,
+    pub public: Vec<u8>,
+}
+
+impl KeyPair {
+    pub fn generate() -> anyhow::Result<Self> {
+        let rng = ring::rand::SystemRandom::new();
+        let private = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng)
+            .map_err(|_| anyhow::anyhow!("Failed to generate session key"))?;
+        let public = private
+            .compute_public_key()
+            .map_err(|_| anyhow::anyhow!("Failed to compute session public key"))?
+            .as_ref()
+            .to_vec();
+        Ok(Self { private, public })
+    }
+
+    /// Combine with the peer's public key and the shared service secret
+    pub fn agree(
+        self,
+        peer_public: &[u8],
+        session_id: Uuid,
+        secret: &str,
+    ) -> anyhow::Result<SessionSecret> {
+        let peer = agreement::UnparsedPublicKey::new(&agreement::X25519, peer_public);
+        let prk = agreement::agree_ephemeral(self.private, &peer, |shared| {
+            let mut ikm = shared.to_vec();
+            ikm.extend_from_slice(secret.as_bytes());
+            hkdf::Salt::new(hkdf::HKDF_SHA256, session_id.as_bytes()).extract(&ikm)
+        })
+        .map_err(|_| anyhow::anyhow!("Invalid peer session key"))?;
+        Ok(SessionSecret { prk })
+    }
+}
+
+/// Key material shared by both ends of a session
+#[derive(Clone)]
+pub struct SessionSecret {
+    prk: hkdf::Prk,
+}
+
+impl SessionSecret {
+    /// Wrap a connected stream. `offerer` is true on the side that sent the
+    /// candidate offer; both ends must use the same `salt`.
+    pub fn secure<S>(
+        &self,
+        stream: S,
+        salt: &[u8; STREAM_SALT_LEN],
+        offerer: bool,
+    ) -> anyhow::Result<SecureStream<S>> {
+        let to_answerer = self.key(salt, b"offerer to answerer")?;
+        let to_offerer = self.key(salt, b"answerer to offerer")?;
+        let (seal, open) = if offerer {
+            (to_answerer, to_offerer)
+        } else {
+            (to_offerer, to_answerer)
+        };
+        Ok(SecureStream {
+            inner: stream,
+            seal: Cipher::new(seal),
+            open: Cipher::new(open),
+        })
+    }
+
+    fn key(&self, salt: &[u8], direction: &[u8]) -> anyhow::Result<aead::LessSafeKey> {
+        let info = [salt, direction];
+        let okm = self
+            .prk
+            .expand(&info, &aead::CHACHA20_POLY1305)
+            .map_err(|_| anyhow::anyhow!("Failed to derive stream key"))?;
+        Ok(aead::LessSafeKey::new(aead::UnboundKey::from(okm)))
+    }
+}
+
+/// Fresh random salt for a stream the caller is about to dial
+pub fn stream_salt() -> anyhow::Result<[u8; STREAM_SALT_LEN]> {
+    let mut salt = [0u8; STREAM_SALT_LEN];
+    ring::rand::SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| anyhow::anyhow!("Failed to generate stream salt"))?;
+    Ok(salt)
+}
+
+struct Cipher {
+    key: aead::LessSafeKey,
+    counter: u64,
+}
+
+impl Cipher {
+    fn new(key: aead::LessSafeKey) -> Self {
+        Self { key, counter: 0 }
+    }
+
+    fn next_nonce(&mut self) -> aead::Nonce {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        aead::Nonce::assume_unique_for_key(nonce)
+    }
+}
+
+/// Stream of length-prefixed ChaCha20-Poly1305 frames
+pub struct SecureStream<S> {
+    inner: S,
+    seal: Cipher,
+    open: Cipher,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> SecureStream<S> {
+    pub async fn write_frame(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        write_frame(&mut self.inner, &mut self.seal, data).await
+    }
+
+    /// Read and decrypt the next frame, `None` at a clean end of stream
+    pub async fn read_frame(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        read_frame(&mut self.inner, &mut self.open).await
+    }
+
+    /// Forward traffic between this session and a plain local stream until
+    /// both directions close. Returns the bytes sent and received.
+    pub async fn pump<L>(self, local: L) -> anyhow::Result<(u64, u64)>
+    where
+        L: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (mut peer_read, mut peer_write) = tokio::io::split(self.inner);
+        let (mut local_read, mut local_write) = tokio::io::split(local);
+        let (mut seal, mut open) = (self.seal, self.open);
+
+        let outbound = async {
+            let mut buffer = vec![0u8; MAX_FRAME];
+            let mut sent = 0u64;
+            loop {
+                let n = local_read.read(&mut buffer).await?;
+                if n == 0 {
+                    peer_write.shutdown().await?;
+                    return Ok::<_, anyhow::Error>(sent);
+                }
+                write_frame(&mut peer_write, &mut seal, &buffer[..n]).await?;
+                sent += n as u64;
+            }
+        };
+
+        let inbound = async {
+            let mut received = 0u64;
+            while let Some(data) = read_frame(&mut peer_read, &mut open).await? {
+                local_write.write_all(&data).await?;
+                received += data.len() as u64;
+            }
+            local_write.shutdown().await?;
+            Ok::<_, anyhow::Error>(received)
+        };
+
+        tokio::try_join!(outbound, inbound)
+    }
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    cipher: &mut Cipher,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    let mut payload = data.to_vec();
+    let nonce = cipher.next_nonce();
+    cipher
+        .key
+        .seal_in_place_append_tag(nonce, aead::Aad::empty(), &mut payload)
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt frame"))?;
+
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    writer.write_all(&frame).await?;
+    Ok(())
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    cipher: &mut Cipher,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut len_buf = [0u8; 4];
+    match reader.read_exact(&mut len_buf).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > MAX_FRAME + aead::MAX_TAG_LEN {
+        return Err(anyhow::anyhow!("Frame too large: {} bytes", len));
+    }
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    let nonce = cipher.next_nonce();
+    let plain_len = cipher
+        .key
+        .open_in_place(nonce, aead::Aad::empty(), &mut payload)
+        .map_err(|_| anyhow::anyhow!("Frame failed authentication"))?
+        .len();
+    payload.truncate(plain_len);
+    Ok(Some(payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_secure_stream_round_trip() {
+        let session_id = Uuid::new_v4();
+        let (offerer, answerer) = (KeyPair::generate().unwrap(), KeyPair::generate().unwrap());
+        let (offerer_public, answerer_public) = (offerer.public.clone(), answerer.public.clone());
+        let offerer = offerer
+            .agree(&answerer_public, session_id, "s3cret")
+            .unwrap();
+        let answerer = answerer
+            .agree(&offerer_public, session_id, "s3cret")
+            .unwrap();
+        let salt = stream_salt().unwrap();
+
+        let (a, b) = tokio::io::duplex(1024);
+        let mut a = offerer.secure(a, &salt, true).unwrap();
+        let mut b = answerer.secure(b, &salt, false).unwrap();
+
+        a.write_frame(b"hello").await.unwrap();
+        assert_eq!(b.read_frame().await.unwrap().unwrap(), b"hello");
+        b.write_frame(b"world").await.unwrap();
+        assert_eq!(a.read_frame().await.unwrap().unwrap(), b"world");
+
+        // A different service secret yields keys that fail authentication
+        let (c, d) = (KeyPair::generate().unwrap(), KeyPair::generate().unwrap());
+        let (c_public, d_public) = (c.public.clone(), d.public.clone());
+        let c = c.agree(&d_public, session_id, "s3cret").unwrap();
+        let d = d.agree(&c_public, session_id, "wrong").unwrap();
+        let (x, y) = tokio::io::duplex(1024);
+        let mut x = c.secure(x, &salt, true).unwrap();
+        let mut y = d.secure(y, &salt, false).unwrap();
+        x.write_frame(b"hello").await.unwrap();
+        assert!(y.read_frame().await.is_err());
+    }
+}
@@ -0,0 +1,252 @@
+//! Apply configured TCP options to connected sockets, open connections
+//! from a chosen interface or source address, and bind listeners several
+//! accept loops can share, as well as the UDP sockets of UDP tunnels
+
+use crate::config::{Dscp, SocketOptions};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpSocket, TcpStream, ToSocketAddrs, UdpSocket};
+
+/// Pending connections each listener queues
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// Set the options on `stream`, logging any the platform rejects
+pub fn configure(stream: &TcpStream, options: &SocketOptions) {
+    if let Err(e) = try_configure(stream, options) {
+        tracing::warn!("Failed to apply socket options: {}", e);
+    }
+}
+
+/// Connect to `target` through the configured interface or from the
+/// configured source address, then set the options on the connection.
+/// Each address `target` resolves to is tried in turn.
+pub async fn connect(
+    target: impl ToSocketAddrs,
+    options: &SocketOptions,
+) -> std::io::Result<TcpStream> {
+    let stream = if options.bind_interface.is_none() && options.bind_addr.is_none() {
+        TcpStream::connect(target).await?
+    } else {
+        connect_bound_any(target, options).await?
+    };
+    configure(&stream, options);
+    Ok(stream)
+}
+
+async fn connect_bound_any(
+    target: impl ToSocketAddrs,
+    options: &SocketOptions,
+) -> std::io::Result<TcpStream> {
+    let mut failure = None;
+    for addr in tokio::net::lookup_host(target).await? {
+        match connect_bound(addr, options).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => failure = Some(e),
+        }
+    }
+    Err(failure.unwrap_or_else(|| std::io::ErrorKind::NotFound.into()))
+}
+
+async fn connect_bound(addr: SocketAddr, options: &SocketOptions) -> std::io::Result<TcpStream> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Some(interface) = &options.bind_interface {
+        bind_device(&SockRef::from(&socket), interface)?;
+    }
+    if let Some(local) = source_addr(addr, options)? {
+        socket.bind(local)?;
+    }
+    socket.connect(addr).await
+}
+
+/// A UDP socket connected to the first address of `target`, sending from
+/// the configured interface or source address and marked with the
+/// configured DSCP class
+pub async fn connect_datagrams(
+    target: impl ToSocketAddrs,
+    options: &SocketOptions,
+) -> std::io::Result<UdpSocket> {
+    let addr = tokio::net::lookup_host(target)
+        .await?
+        .next()
+        .ok_or(std::io::ErrorKind::NotFound)?;
+    let local = source_addr(addr, options)?.unwrap_or(match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    });
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if let Some(interface) = &options.bind_interface {
+        bind_device(&SockRef::from(&socket), interface)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&local.into())?;
+    let socket = UdpSocket::from_std(socket.into())?;
+    configure_datagrams(&socket, options);
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
+/// Bind the UDP socket of a tunnel port, its replies marked with the
+/// configured DSCP class
+pub fn bind_datagrams(addr: SocketAddr, options: &SocketOptions) -> std::io::Result<UdpSocket> {
+    let socket = std::net::UdpSocket::bind(addr)?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket)?;
+    configure_datagrams(&socket, options);
+    Ok(socket)
+}
+
+/// Set the buffer sizes and DSCP class on a UDP socket, logging any the
+/// platform rejects
+fn configure_datagrams(socket: &UdpSocket, options: &SocketOptions) {
+    if let Err(e) = try_configure_datagrams(socket, options) {
+        tracing::warn!("Failed to apply socket options: {}", e);
+    }
+}
+
+fn try_configure_datagrams(socket: &UdpSocket, options: &SocketOptions) -> std::io::Result<()> {
+    let sock = SockRef::from(socket);
+    if let Some(size) = options.recv_buffer {
+        sock.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = options.send_buffer {
+        sock.set_send_buffer_size(size)?;
+    }
+    if let Some(dscp) = options.dscp {
+        mark(&sock, socket.local_addr()?.is_ipv6(), dscp)?;
+    }
+    Ok(())
+}
+
+/// The configured source address for reaching `addr`, if any
+fn source_addr(addr: SocketAddr, options: &SocketOptions) -> std::io::Result<Option<SocketAddr>> {
+    match options.bind_addr {
+        Some(ip) if ip.is_ipv4() != addr.is_ipv4() => Err(std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            format!("bind_addr {} cannot reach {}", ip, addr),
+        )),
+        Some(ip) => Ok(Some(SocketAddr::new(ip, 0))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_device(socket: &SockRef, interface: &str) -> std::io::Result<()> {
+    socket
+        .bind_device(Some(interface.as_bytes()))
+        .map_err(|e| std::io::Error::new(e.kind(), format!("bind_interface {}: {}", interface, e)))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_device(_socket: &SockRef, _interface: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "bind_interface is only supported on Linux; use bind_addr with the interface's address",
+    ))
+}
+
+/// Bind `workers` listeners to `addr` with SO_REUSEPORT, so the kernel
+/// spreads incoming connections across their accept loops. Only Linux
+/// balances them, so elsewhere a single listener is bound.
+pub fn bind_listeners(addr: SocketAddr, workers: usize) -> std::io::Result<Vec<TcpListener>> {
+    let workers = if cfg!(target_os = "linux") {
+        workers.max(1)
+    } else {
+        1
+    };
+    (0..workers)
+        .map(|_| bind_listener(addr, workers > 1))
+        .collect()
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+fn bind_listener(addr: SocketAddr, reuse_port: bool) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // As `TcpListener::bind` does, so ports in TIME_WAIT can be bound again
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    #[cfg(target_os = "linux")]
+    socket.set_reuse_port(reuse_port)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+fn try_configure(stream: &TcpStream, options: &SocketOptions) -> std::io::Result<()> {
+    stream.set_nodelay(options.nodelay)?;
+
+    let socket = SockRef::from(stream);
+    if options.keepalive_secs > 0 {
+        let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(options.keepalive_secs));
+        socket.set_tcp_keepalive(&keepalive)?;
+    }
+    if let Some(size) = options.recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = options.send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(dscp) = options.dscp {
+        mark(&socket, stream.local_addr()?.is_ipv6(), dscp)?;
+    }
+    Ok(())
+}
+
+/// Mark the packets `socket` sends with `dscp`
+fn mark(socket: &SockRef, ipv6: bool, dscp: Dscp) -> std::io::Result<()> {
+    if !ipv6 {
+        return socket.set_tos_v4(dscp.tos());
+    }
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "freebsd"
+    ))]
+    socket.set_tclass_v6(dscp.tos())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_listeners_share_port() {
+        let probe = bind_listeners(SocketAddr::from(([127, 0, 0, 1], 0)), 1).unwrap();
+        let addr = probe[0].local_addr().unwrap();
+        drop(probe);
+
+        let listeners = bind_listeners(addr, 3).unwrap();
+        let expected = if cfg!(target_os = "linux") { 3 } else { 1 };
+        assert_eq!(listeners.len(), expected);
+        for listener in &listeners {
+            assert_eq!(listener.local_addr().unwrap(), addr);
+        }
+        TcpStream::connect(addr).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_from_bind_addr() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let options = SocketOptions {
+            bind_addr: Some("127.0.0.2".parse().unwrap()),
+            ..SocketOptions::default()
+        };
+        let stream = connect(addr, &options).await.unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), peer.ip());
+        assert_eq!(peer.ip().to_string(), "127.0.0.2");
+
+        let options = SocketOptions {
+            bind_addr: Some("::1".parse().unwrap()),
+            ..SocketOptions::default()
+        };
+        assert!(connect(addr, &options).await.is_err());
+    }
+}
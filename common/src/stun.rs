@@ -0,0 +1,676 @@
+//! Minimal STUN (RFC 5389) binding client and responder.
+//!
+//! Only the Binding method is implemented: enough to learn the public
+//! address a NAT maps a UDP socket to and how that mapping behaves.
+
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+pub const MAGIC_COOKIE: u32 = 0x2112_A442;
+
+const HEADER_LEN: usize = 20;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_RESPONSE: u16 = 0x0101;
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_CHANGE_REQUEST: u16 = 0x0003;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTR_OTHER_ADDRESS: u16 = 0x802C;
+const CHANGE_IP: u32 = 0x04;
+const CHANGE_PORT: u32 = 0x02;
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+
+/// Number of times a binding request is sent before giving up
+const ATTEMPTS: u32 = 3;
+
+pub type TransactionId = [u8; 12];
+
+/// How the NAT maps a local socket to public addresses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MappingBehavior {
+    /// No translation: the public address is the local address
+    NoNat,
+    /// Same public address regardless of destination (cone NAT)
+    EndpointIndependent,
+    /// Public address changes per destination (symmetric NAT)
+    AddressDependent,
+    /// Not enough servers answered to tell
+    Unknown,
+}
+
+/// Result of querying STUN servers from one socket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StunReport {
+    pub local_addr: SocketAddr,
+    pub public_addr: SocketAddr,
+    pub mapping: MappingBehavior,
+    /// Mapped address reported by each server that answered
+    pub observations: Vec<(SocketAddr, SocketAddr)>,
+}
+
+/// Which inbound packets the NAT lets through to a mapping (RFC 4787)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilteringBehavior {
+    /// Any source may reach the mapping (full cone)
+    EndpointIndependent,
+    /// Only addresses the client has sent to (restricted cone). Also
+    /// reported for endpoint-independent filtering when the server cannot
+    /// answer from a second IP address to tell the two apart.
+    AddressDependent,
+    /// Only the exact address and port the client has sent to
+    AddressAndPortDependent,
+    /// The server does not support change requests
+    Unknown,
+}
+
+/// Classic NAT classification derived from mapping and filtering
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NatType {
+    /// Public address, no translation
+    Open,
+    FullCone,
+    RestrictedCone,
+    PortRestrictedCone,
+    Symmetric,
+    Unknown,
+}
+
+impl NatType {
+    /// Whether a direct connection to this host can work without the peer
+    /// being directly reachable
+    pub fn allows_direct(self) -> bool {
+        !matches!(self, NatType::Symmetric | NatType::Unknown)
+    }
+}
+
+impl std::fmt::Display for NatType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NatType::Open => write!(f, "open (no NAT)"),
+            NatType::FullCone => write!(f, "full cone"),
+            NatType::RestrictedCone => write!(f, "restricted cone"),
+            NatType::PortRestrictedCone => write!(f, "port-restricted cone"),
+            NatType::Symmetric => write!(f, "symmetric"),
+            NatType::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Full NAT behavior report
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NatReport {
+    pub stun: StunReport,
+    pub filtering: FilteringBehavior,
+    /// Whether packets sent to our own public address loop back to us;
+    /// `None` when there is no NAT to test
+    pub hairpinning: Option<bool>,
+    pub nat_type: NatType,
+}
+
+/// Build a Binding request
+pub fn binding_request(transaction_id: &TransactionId) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(HEADER_LEN);
+    write_header(&mut packet, BINDING_REQUEST, 0, transaction_id);
+    packet
+}
+
+/// Build a Binding request asking the server to answer from its other
+/// address and/or port (RFC 5780 CHANGE-REQUEST)
+pub fn change_request(
+    transaction_id: &TransactionId,
+    change_ip: bool,
+    change_port: bool,
+) -> Vec<u8> {
+    let mut flags = 0;
+    if change_ip {
+        flags |= CHANGE_IP;
+    }
+    if change_port {
+        flags |= CHANGE_PORT;
+    }
+    let mut packet = Vec::with_capacity(HEADER_LEN + 8);
+    write_header(&mut packet, BINDING_REQUEST, 8, transaction_id);
+    packet.extend_from_slice(&ATTR_CHANGE_REQUEST.to_be_bytes());
+    packet.extend_from_slice(&4u16.to_be_bytes());
+    packet.extend_from_slice(&flags.to_be_bytes());
+    packet
+}
+
+/// Build a Binding success response carrying `mapped` as XOR-MAPPED-ADDRESS
+/// and, when the server has one, its alternate address as OTHER-ADDRESS
+pub fn binding_response(
+    transaction_id: &TransactionId,
+    mapped: SocketAddr,
+    other: Option<SocketAddr>,
+) -> Vec<u8> {
+    let mut attributes = Vec::new();
+    push_attribute(
+        &mut attributes,
+        ATTR_XOR_MAPPED_ADDRESS,
+        &encode_xor_address(mapped, transaction_id),
+    );
+    if let Some(other) = other {
+        push_attribute(&mut attributes, ATTR_OTHER_ADDRESS, &encode_address(other));
+    }
+
+    let mut packet = Vec::with_capacity(HEADER_LEN + attributes.len());
+    write_header(
+        &mut packet,
+        BINDING_RESPONSE,
+        attributes.len() as u16,
+        transaction_id,
+    );
+    packet.extend_from_slice(&attributes);
+    packet
+}
+
+/// Transaction ID of a Binding request, if `data` is one
+pub fn parse_binding_request(data: &[u8]) -> Option<TransactionId> {
+    let (message_type, transaction_id) = parse_header(data)?;
+    (message_type == BINDING_REQUEST).then_some(transaction_id)
+}
+
+/// CHANGE-REQUEST flags of a Binding request as (change IP, change port)
+pub fn parse_change_request(data: &[u8]) -> (bool, bool) {
+    let flags = attributes(data)
+        .into_iter()
+        .find(|(attr_type, value)| *attr_type == ATTR_CHANGE_REQUEST && value.len() == 4)
+        .map(|(_, value)| u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+        .unwrap_or(0);
+    (flags & CHANGE_IP != 0, flags & CHANGE_PORT != 0)
+}
+
+/// Mapped address from a Binding response matching `transaction_id`
+pub fn parse_binding_response(data: &[u8], transaction_id: &TransactionId) -> Option<SocketAddr> {
+    let (message_type, id) = parse_header(data)?;
+    if message_type != BINDING_RESPONSE || &id != transaction_id {
+        return None;
+    }
+
+    let mut mapped = None;
+    for (attr_type, value) in attributes(data) {
+        match attr_type {
+            // XOR-MAPPED-ADDRESS wins over the legacy attribute
+            ATTR_XOR_MAPPED_ADDRESS => return decode_address(value, Some(transaction_id)),
+            ATTR_MAPPED_ADDRESS => mapped = decode_address(value, None),
+            _ => {}
+        }
+    }
+
+    mapped
+}
+
+/// OTHER-ADDRESS of a Binding response, present when the server can answer
+/// change requests
+pub fn parse_other_address(data: &[u8]) -> Option<SocketAddr> {
+    attributes(data)
+        .into_iter()
+        .find(|(attr_type, _)| *attr_type == ATTR_OTHER_ADDRESS)
+        .and_then(|(_, value)| decode_address(value, None))
+}
+
+/// Ask a STUN server for the public address of `socket`
+pub async fn binding(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    timeout: Duration,
+) -> anyhow::Result<SocketAddr> {
+    let transaction_id: TransactionId = rand::random();
+    let request = binding_request(&transaction_id);
+    let (mapped, _) = transact(socket, server, &request, &transaction_id, timeout, |from| {
+        from == server
+    })
+    .await?;
+    Ok(mapped)
+}
+
+/// Send `request` until a matching response arrives from an accepted
+/// source, returning the mapped address and the whole response
+async fn transact(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    request: &[u8],
+    transaction_id: &TransactionId,
+    timeout: Duration,
+    accept_from: impl Fn(SocketAddr) -> bool,
+) -> anyhow::Result<(SocketAddr, Vec<u8>)> {
+    let mut buffer = [0u8; 512];
+
+    for _ in 0..ATTEMPTS {
+        socket.send_to(request, server).await?;
+
+        let deadline = tokio::time::Instant::now() + timeout / ATTEMPTS;
+        while let Ok(received) =
+            tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await
+        {
+            let (len, from) = received?;
+            if !accept_from(from) {
+                continue;
+            }
+            if let Some(mapped) = parse_binding_response(&buffer[..len], transaction_id) {
+                return Ok((mapped, buffer[..len].to_vec()));
+            }
+        }
+    }
+
+    Err(anyhow::anyhow!("No response from STUN server {}", server))
+}
+
+/// Query several STUN servers from one socket and classify the mapping
+pub async fn discover(servers: &[String], timeout: Duration) -> anyhow::Result<StunReport> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    discover_with(&socket, servers, timeout).await
+}
+
+/// Like [`discover`] but using an existing socket, e.g. one later used for hole punching
+pub async fn discover_with(
+    socket: &UdpSocket,
+    servers: &[String],
+    timeout: Duration,
+) -> anyhow::Result<StunReport> {
+    let mut observations = Vec::new();
+
+    for server in servers {
+        let addr = match tokio::net::lookup_host(server.as_str())
+            .await
+            .ok()
+            .and_then(|mut addrs| addrs.find(SocketAddr::is_ipv4))
+        {
+            Some(addr) => addr,
+            None => {
+                tracing::debug!("Failed to resolve STUN server {}", server);
+                continue;
+            }
+        };
+
+        // Mapping behavior is only meaningful across distinct server IPs
+        if observations
+            .iter()
+            .any(|(seen, _): &(SocketAddr, SocketAddr)| seen.ip() == addr.ip())
+        {
+            continue;
+        }
+
+        match binding(socket, addr, timeout).await {
+            Ok(mapped) => observations.push((addr, mapped)),
+            Err(e) => tracing::debug!("STUN query to {} failed: {}", server, e),
+        }
+    }
+
+    let public_addr = observations
+        .first()
+        .map(|(_, mapped)| *mapped)
+        .ok_or_else(|| anyhow::anyhow!("No STUN server answered"))?;
+
+    let mut local_addr = socket.local_addr()?;
+    if local_addr.ip().is_unspecified() {
+        if let Some(ip) = local_ip_towards(observations[0].0).await {
+            local_addr.set_ip(ip);
+        }
+    }
+
+    let mapping = if observations.iter().all(|(_, mapped)| *mapped == local_addr) {
+        MappingBehavior::NoNat
+    } else if observations.len() < 2 {
+        MappingBehavior::Unknown
+    } else if observations
+        .iter()
+        .all(|(_, mapped)| *mapped == public_addr)
+    {
+        MappingBehavior::EndpointIndependent
+    } else {
+        MappingBehavior::AddressDependent
+    };
+
+    Ok(StunReport {
+        local_addr,
+        public_addr,
+        mapping,
+        observations,
+    })
+}
+
+/// Classify the NAT in front of this host: mapping across the given
+/// servers, filtering through change requests to the first server that
+/// supports them, and hairpinning through our own public address
+pub async fn detect_nat(servers: &[String], timeout: Duration) -> anyhow::Result<NatReport> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let stun = discover_with(&socket, servers, timeout).await?;
+
+    let (filtering, hairpinning) = if stun.mapping == MappingBehavior::NoNat {
+        (FilteringBehavior::EndpointIndependent, None)
+    } else {
+        let filtering = probe_filtering(&socket, &stun, timeout).await;
+        let hairpinning = probe_hairpinning(&socket, stun.public_addr, timeout).await;
+        (filtering, Some(hairpinning))
+    };
+
+    let nat_type = match (stun.mapping, filtering) {
+        (MappingBehavior::NoNat, _) => NatType::Open,
+        (MappingBehavior::AddressDependent, _) => NatType::Symmetric,
+        (MappingBehavior::Unknown, _) | (_, FilteringBehavior::Unknown) => NatType::Unknown,
+        (_, FilteringBehavior::EndpointIndependent) => NatType::FullCone,
+        (_, FilteringBehavior::AddressDependent) => NatType::RestrictedCone,
+        (_, FilteringBehavior::AddressAndPortDependent) => NatType::PortRestrictedCone,
+    };
+
+    Ok(NatReport {
+        stun,
+        filtering,
+        hairpinning,
+        nat_type,
+    })
+}
+
+async fn probe_filtering(
+    socket: &UdpSocket,
+    stun: &StunReport,
+    timeout: Duration,
+) -> FilteringBehavior {
+    for (server, _) in &stun.observations {
+        // Only servers that advertise an alternate address answer change requests
+        let transaction_id: TransactionId = rand::random();
+        let request = binding_request(&transaction_id);
+        let other = match transact(
+            socket,
+            *server,
+            &request,
+            &transaction_id,
+            timeout,
+            |from| from == *server,
+        )
+        .await
+        {
+            Ok((_, response)) => parse_other_address(&response),
+            Err(_) => None,
+        };
+        let Some(other) = other else {
+            continue;
+        };
+
+        let other_ip = !other.ip().is_unspecified() && other.ip() != server.ip();
+        if other_ip && change_answered(socket, *server, true, timeout).await {
+            return FilteringBehavior::EndpointIndependent;
+        }
+        if other.port() != server.port() {
+            return if change_answered(socket, *server, false, timeout).await {
+                FilteringBehavior::AddressDependent
+            } else {
+                FilteringBehavior::AddressAndPortDependent
+            };
+        }
+    }
+    FilteringBehavior::Unknown
+}
+
+/// Whether a response to a change request gets through the NAT
+async fn change_answered(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    change_ip: bool,
+    timeout: Duration,
+) -> bool {
+    let transaction_id: TransactionId = rand::random();
+    let request = change_request(&transaction_id, change_ip, true);
+    transact(socket, server, &request, &transaction_id, timeout, |from| {
+        from != server
+    })
+    .await
+    .is_ok()
+}
+
+/// Send a Binding request from a second socket to our own public address
+/// and see whether it arrives on the first
+async fn probe_hairpinning(socket: &UdpSocket, public_addr: SocketAddr, timeout: Duration) -> bool {
+    let Ok(probe) = UdpSocket::bind("0.0.0.0:0").await else {
+        return false;
+    };
+    let transaction_id: TransactionId = rand::random();
+    let request = binding_request(&transaction_id);
+    let mut buffer = [0u8; 512];
+
+    for _ in 0..ATTEMPTS {
+        if probe.send_to(&request, public_addr).await.is_err() {
+            return false;
+        }
+        let deadline = tokio::time::Instant::now() + timeout / ATTEMPTS;
+        while let Ok(Ok((len, _))) =
+            tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await
+        {
+            if parse_binding_request(&buffer[..len]) == Some(transaction_id) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Answer Binding requests on `socket` until it fails
+///
+/// Requests asking for a change of port are answered from `alternate`,
+/// which lets clients probe how their NAT filters inbound traffic. Changing
+/// the IP address is not supported, so such requests go unanswered.
+pub async fn serve(socket: UdpSocket, alternate: Option<UdpSocket>) -> anyhow::Result<()> {
+    let other = match &alternate {
+        Some(alternate) => Some(alternate.local_addr()?),
+        None => None,
+    };
+    let mut buffer = [0u8; 512];
+    loop {
+        let (len, from) = socket.recv_from(&mut buffer).await?;
+        let Some(transaction_id) = parse_binding_request(&buffer[..len]) else {
+            continue;
+        };
+
+        let response = binding_response(&transaction_id, from, other);
+        let sender = match (parse_change_request(&buffer[..len]), &alternate) {
+            ((false, false), _) => &socket,
+            ((false, true), Some(alternate)) => alternate,
+            _ => continue,
+        };
+        if let Err(e) = sender.send_to(&response, from).await {
+            tracing::debug!("Failed to answer STUN request from {}: {}", from, e);
+        }
+    }
+}
+
+/// Local interface address used to reach `target`
+async fn local_ip_towards(target: SocketAddr) -> Option<IpAddr> {
+    let probe = UdpSocket::bind("0.0.0.0:0").await.ok()?;
+    probe.connect(target).await.ok()?;
+    Some(probe.local_addr().ok()?.ip())
+}
+
+fn write_header(
+    packet: &mut Vec<u8>,
+    message_type: u16,
+    length: u16,
+    transaction_id: &TransactionId,
+) {
+    packet.extend_from_slice(&message_type.to_be_bytes());
+    packet.extend_from_slice(&length.to_be_bytes());
+    packet.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    packet.extend_from_slice(transaction_id);
+}
+
+fn push_attribute(packet: &mut Vec<u8>, attr_type: u16, value: &[u8]) {
+    packet.extend_from_slice(&attr_type.to_be_bytes());
+    packet.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    packet.extend_from_slice(value);
+    packet.resize(packet.len() + (4 - value.len() % 4) % 4, 0);
+}
+
+/// Attributes of a STUN message as (type, value) pairs
+fn attributes(data: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut list = Vec::new();
+    if data.len() < HEADER_LEN {
+        return list;
+    }
+    let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+    let Some(body) = data.get(HEADER_LEN..HEADER_LEN + length) else {
+        return list;
+    };
+
+    let mut offset = 0;
+    while offset + 4 <= body.len() {
+        let attr_type = u16::from_be_bytes([body[offset], body[offset + 1]]);
+        let attr_len = u16::from_be_bytes([body[offset + 2], body[offset + 3]]) as usize;
+        let Some(value) = body.get(offset + 4..offset + 4 + attr_len) else {
+            break;
+        };
+        list.push((attr_type, value));
+
+        // Attributes are padded to a multiple of four bytes
+        offset += 4 + attr_len.div_ceil(4) * 4;
+    }
+    list
+}
+
+fn parse_header(data: &[u8]) -> Option<(u16, TransactionId)> {
+    if data.len() < HEADER_LEN || data[0] & 0xC0 != 0 {
+        return None;
+    }
+    if u32::from_be_bytes([data[4], data[5], data[6], data[7]]) != MAGIC_COOKIE {
+        return None;
+    }
+
+    let message_type = u16::from_be_bytes([data[0], data[1]]);
+    let mut transaction_id = [0u8; 12];
+    transaction_id.copy_from_slice(&data[8..HEADER_LEN]);
+    Some((message_type, transaction_id))
+}
+
+/// XOR key for addresses: the magic cookie followed by the transaction ID
+fn xor_key(transaction_id: &TransactionId) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    key[4..].copy_from_slice(transaction_id);
+    key
+}
+
+fn encode_xor_address(addr: SocketAddr, transaction_id: &TransactionId) -> Vec<u8> {
+    let key = xor_key(transaction_id);
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+
+    let (family, ip): (u8, Vec<u8>) = match addr.ip() {
+        IpAddr::V4(ip) => (FAMILY_IPV4, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (FAMILY_IPV6, ip.octets().to_vec()),
+    };
+
+    let mut value = vec![0, family];
+    value.extend_from_slice(&port.to_be_bytes());
+    value.extend(ip.iter().zip(key.iter()).map(|(b, k)| b ^ k));
+    value
+}
+
+fn encode_address(addr: SocketAddr) -> Vec<u8> {
+    let mut value = vec![0];
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            value.push(FAMILY_IPV4);
+            value.extend_from_slice(&addr.port().to_be_bytes());
+            value.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            value.push(FAMILY_IPV6);
+            value.extend_from_slice(&addr.port().to_be_bytes());
+            value.extend_from_slice(&ip.octets());
+        }
+    }
+    value
+}
+
+fn decode_address(value: &[u8], transaction_id: Option<&TransactionId>) -> Option<SocketAddr> {
+    if value.len() < 4 {
+        return None;
+    }
+
+    let key = transaction_id.map(xor_key).unwrap_or([0u8; 16]);
+    let mut port = u16::from_be_bytes([value[2], value[3]]);
+    if transaction_id.is_some() {
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+    }
+
+    let ip = match value[1] {
+        FAMILY_IPV4 => {
+            let raw = value.get(4..8)?;
+            let mut octets = [0u8; 4];
+            for (i, octet) in octets.iter_mut().enumerate() {
+                *octet = raw[i] ^ key[i];
+            }
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        FAMILY_IPV6 => {
+            let raw = value.get(4..20)?;
+            let mut octets = [0u8; 16];
+            for (i, octet) in octets.iter_mut().enumerate() {
+                *octet = raw[i] ^ key[i];
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binding_round_trip() {
+        let transaction_id: TransactionId = rand::random();
+        let request = binding_request(&transaction_id);
+        assert_eq!(parse_binding_request(&request), Some(transaction_id));
+
+        for mapped in ["203.0.113.7:40000", "[2001:db8::1]:5555"] {
+            let mapped: SocketAddr = mapped.parse().unwrap();
+            let response = binding_response(&transaction_id, mapped, None);
+            assert_eq!(
+                parse_binding_response(&response, &transaction_id),
+                Some(mapped)
+            );
+            assert_eq!(parse_binding_response(&response, &[0u8; 12]), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_discover_against_local_responder() {
+        let responder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = responder.local_addr().unwrap().to_string();
+        tokio::spawn(serve(responder, None));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let report = discover_with(&socket, &[server], Duration::from_secs(2))
+            .await
+            .unwrap();
+        assert_eq!(report.public_addr, socket.local_addr().unwrap());
+        assert_eq!(report.mapping, MappingBehavior::NoNat);
+    }
+
+    #[tokio::test]
+    async fn test_change_request_answered_from_alternate_port() {
+        let responder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let alternate = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = responder.local_addr().unwrap();
+        let alternate_addr = alternate.local_addr().unwrap();
+        tokio::spawn(serve(responder, Some(alternate)));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let transaction_id: TransactionId = rand::random();
+        let request = binding_request(&transaction_id);
+        let timeout = Duration::from_secs(2);
+        let (_, response) = transact(&socket, server, &request, &transaction_id, timeout, |_| {
+            true
+        })
+        .await
+        .unwrap();
+        assert_eq!(parse_other_address(&response), Some(alternate_addr));
+
+        assert!(change_answered(&socket, server, false, timeout).await);
+        assert!(!change_answered(&socket, server, true, Duration::from_millis(300)).await);
+    }
+}
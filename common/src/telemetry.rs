@@ -0,0 +1,259 @@
+//! OpenTelemetry export of spans and metrics.
+//!
+//! Spans come from the regular `tracing` instrumentation: the `connection`,
+//! `tunnel` and `session` spans on the server and the `server` and `session`
+//! spans on the client. Metrics are recorded through the functions below
+//! when a session, tunnel or client comes and goes, never per packet.
+//!
+//! Export needs the `otlp` feature; without it `init` only warns when the
+//! configuration asks for it and recording is a no-op.
+
+use crate::config::OtlpConfig;
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::{Layer, Registry};
+
+/// Layer added at the root of the subscriber
+pub type TelemetryLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Keeps the exporters running; dropping it flushes what is still buffered
+#[derive(Default)]
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    tracer_provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+    #[cfg(feature = "otlp")]
+    meter_provider: Option<opentelemetry_sdk::metrics::SdkMeterProvider>,
+}
+
+/// Start exporting as configured, returning the span layer when traces are
+/// enabled. `service_name` is used unless the configuration overrides it.
+#[cfg(feature = "otlp")]
+pub fn init(
+    config: Option<&OtlpConfig>,
+    service_name: &str,
+) -> anyhow::Result<(Option<TelemetryLayer>, Telemetry)> {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::{WithExportConfig, WithHttpConfig};
+    use opentelemetry_sdk::{metrics, trace, Resource};
+
+    let mut telemetry = Telemetry::default();
+    let config = match config {
+        Some(config) => config,
+        None => return Ok((None, telemetry)),
+    };
+
+    let resource = Resource::builder()
+        .with_service_name(
+            config
+                .service_name
+                .clone()
+                .unwrap_or_else(|| service_name.to_string()),
+        )
+        .build();
+    let endpoint = config.endpoint.trim_end_matches('/');
+    let headers: std::collections::HashMap<_, _> = config
+        .headers
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+
+    let mut layer: Option<TelemetryLayer> = None;
+    if config.traces {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", endpoint))
+            .with_headers(headers.clone())
+            .build()?;
+        let provider = trace::SdkTracerProvider::builder()
+            .with_resource(resource.clone())
+            .with_batch_exporter(exporter)
+            .build();
+        layer = Some(Box::new(
+            tracing_opentelemetry::layer().with_tracer(provider.tracer("nat-traversal")),
+        ));
+        telemetry.tracer_provider = Some(provider);
+    }
+
+    if config.metrics {
+        let exporter = opentelemetry_otlp::MetricExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/metrics", endpoint))
+            .with_headers(headers)
+            .build()?;
+        let reader = metrics::PeriodicReader::builder(exporter)
+            .with_interval(std::time::Duration::from_secs(
+                config.metrics_interval_secs.max(1),
+            ))
+            .build();
+        let provider = metrics::SdkMeterProvider::builder()
+            .with_resource(resource)
+            .with_reader(reader)
+            .build();
+        opentelemetry::global::set_meter_provider(provider.clone());
+        telemetry.meter_provider = Some(provider);
+    }
+
+    Ok((layer, telemetry))
+}
+
+/// Start exporting as configured; this build has no exporter
+#[cfg(not(feature = "otlp"))]
+pub fn init(
+    config: Option<&OtlpConfig>,
+    _service_name: &str,
+) -> anyhow::Result<(Option<TelemetryLayer>, Telemetry)> {
+    if config.is_some() {
+        // Logging is not up yet, so this goes straight to stderr
+        eprintln!("logging.otlp is set but this build has no `otlp` feature; not exporting");
+    }
+    Ok((None, Telemetry::default()))
+}
+
+#[cfg(feature = "otlp")]
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.tracer_provider.take() {
+            let _ = provider.shutdown();
+        }
+        if let Some(provider) = self.meter_provider.take() {
+            let _ = provider.shutdown();
+        }
+    }
+}
+
+#[cfg(feature = "otlp")]
+struct Instruments {
+    clients: opentelemetry::metrics::UpDownCounter<i64>,
+    tunnels: opentelemetry::metrics::UpDownCounter<i64>,
+    sessions: opentelemetry::metrics::UpDownCounter<i64>,
+    bytes: opentelemetry::metrics::Counter<u64>,
+    heartbeat_rtt: opentelemetry::metrics::Histogram<f64>,
+    utilization: opentelemetry::metrics::Gauge<f64>,
+    plugin: opentelemetry::metrics::Counter<f64>,
+}
+
+#[cfg(feature = "otlp")]
+fn instruments() -> &'static Instruments {
+    static INSTRUMENTS: std::sync::OnceLock<Instruments> = std::sync::OnceLock::new();
+    INSTRUMENTS.get_or_init(|| {
+        let meter = opentelemetry::global::meter("nat-traversal");
+        Instruments {
+            clients: meter
+                .i64_up_down_counter("nat.clients")
+                .with_description("Authenticated clients connected")
+                .build(),
+            tunnels: meter
+                .i64_up_down_counter("nat.tunnels")
+                .with_description("Open tunnels")
+                .build(),
+            sessions: meter
+                .i64_up_down_counter("nat.sessions")
+                .with_description("Tunneled connections in progress")
+                .build(),
+            bytes: meter
+                .u64_counter("nat.forwarded.bytes")
+                .with_description("Payload bytes forwarded through tunnels")
+                .with_unit("By")
+                .build(),
+            heartbeat_rtt: meter
+                .f64_histogram("nat.heartbeat.rtt")
+                .with_description("Round trip of heartbeats to the server")
+                .with_unit("ms")
+                .build(),
+            utilization: meter
+                .f64_gauge("nat.capacity.utilization")
+                .with_description("Share of a capped server resource in use")
+                .with_unit("%")
+                .build(),
+            plugin: meter
+                .f64_counter("nat.plugin.metric")
+                .with_description("Values server plugins report")
+                .build(),
+        }
+    })
+}
+
+/// Direction of forwarded bytes, as seen from the tunneled service
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    /// Towards the local service
+    Inbound,
+    /// Back to the public peer
+    Outbound,
+}
+
+impl Direction {
+    #[cfg_attr(not(feature = "otlp"), allow(dead_code))]
+    fn as_str(self) -> &'static str {
+        match self {
+            Direction::Inbound => "inbound",
+            Direction::Outbound => "outbound",
+        }
+    }
+}
+
+/// Count an authenticated client connecting (+1) or leaving (-1)
+pub fn clients_changed(_delta: i64) {
+    #[cfg(feature = "otlp")]
+    instruments().clients.add(_delta, &[]);
+}
+
+/// Count a tunnel opening (+1) or closing (-1)
+pub fn tunnels_changed(_delta: i64) {
+    #[cfg(feature = "otlp")]
+    instruments().tunnels.add(_delta, &[]);
+}
+
+/// Count a tunneled connection starting (+1) or ending (-1)
+pub fn sessions_changed(_delta: i64) {
+    #[cfg(feature = "otlp")]
+    instruments().sessions.add(_delta, &[]);
+}
+
+/// Add the bytes one side of a finished session forwarded
+pub fn bytes_forwarded(_direction: Direction, _bytes: u64) {
+    #[cfg(feature = "otlp")]
+    instruments().bytes.add(
+        _bytes,
+        &[opentelemetry::KeyValue::new(
+            "direction",
+            _direction.as_str(),
+        )],
+    );
+}
+
+/// Record the round trip of a heartbeat to the server
+pub fn heartbeat_rtt(_rtt: std::time::Duration) {
+    #[cfg(feature = "otlp")]
+    instruments()
+        .heartbeat_rtt
+        .record(_rtt.as_secs_f64() * 1000.0, &[]);
+}
+
+/// Record how much of each capped server resource is in use
+pub fn utilization(_utilization: &crate::protocol::Utilization) {
+    #[cfg(feature = "otlp")]
+    for (resource, gauge) in [
+        ("tunnels", _utilization.tunnels),
+        ("connections", _utilization.connections),
+        ("ports", _utilization.ports),
+    ] {
+        if let Some(percent) = gauge.percent() {
+            instruments().utilization.record(
+                percent,
+                &[opentelemetry::KeyValue::new("resource", resource)],
+            );
+        }
+    }
+}
+
+/// Add `value` to the metric a server plugin reports as `name`
+pub fn plugin_metric(_plugin: &str, _name: &str, _value: f64) {
+    #[cfg(feature = "otlp")]
+    instruments().plugin.add(
+        _value,
+        &[
+            opentelemetry::KeyValue::new("plugin", _plugin.to_string()),
+            opentelemetry::KeyValue::new("metric", _name.to_string()),
+        ],
+    );
+}
@@ -0,0 +1,50 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Token bucket shared by every connection of one client. Callers take what
+/// they send up front and sleep off any debt, so a burst of one second's
+/// worth passes at once and the long-run rate stays at the limit.
+pub struct Throttle {
+    bytes_per_sec: f64,
+    /// Bytes available now (negative while in debt) and when last refilled
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl Throttle {
+    pub fn new(mbps: u32) -> Self {
+        Self::per_second(mbps as f64 * 1_000_000.0 / 8.0)
+    }
+
+    pub fn per_second(bytes_per_sec: f64) -> Self {
+        Self {
+            bytes_per_sec,
+            bucket: Mutex::new((bytes_per_sec, Instant::now())),
+        }
+    }
+
+    /// Take `bytes` from the bucket, returning how long to wait before
+    /// sending them
+    pub fn reserve(&self, bytes: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let (available, refilled) = &mut *bucket;
+        let now = Instant::now();
+        *available = (*available
+            + now.duration_since(*refilled).as_secs_f64() * self.bytes_per_sec)
+            .min(self.bytes_per_sec);
+        *refilled = now;
+        *available -= bytes as f64;
+        if *available < 0.0 {
+            Duration::from_secs_f64(-*available / self.bytes_per_sec)
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    /// Wait until `bytes` fit within the rate
+    pub async fn consume(&self, bytes: usize) {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Apply the configured TLS policy to rustls configurations
+
+use crate::config::{TlsPolicy, TlsVersion};
+use crate::error::{NatError, NatResult};
+use rustls::{
+    ConfigBuilder, ConfigSide, SupportedCipherSuite, SupportedProtocolVersion, WantsCipherSuites,
+    WantsVerifier, ALL_CIPHER_SUITES, DEFAULT_CIPHER_SUITES,
+};
+
+/// Restrict `builder` to the policy's protocol versions and cipher suites
+pub fn restrict<S: ConfigSide>(
+    builder: ConfigBuilder<S, WantsCipherSuites>,
+    policy: &TlsPolicy,
+) -> NatResult<ConfigBuilder<S, WantsVerifier>> {
+    builder
+        .with_cipher_suites(&cipher_suites(policy)?)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions(policy.min_version))
+        .map_err(|e| NatError::config(format!("Unusable TLS policy: {}", e)))
+}
+
+/// ALPN protocol ids in wire form
+pub fn alpn_protocols(policy: &TlsPolicy) -> Vec<Vec<u8>> {
+    policy
+        .alpn
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect()
+}
+
+const TLS13_ONLY: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13];
+
+fn versions(min_version: TlsVersion) -> &'static [&'static SupportedProtocolVersion] {
+    match min_version {
+        TlsVersion::Tls12 => rustls::DEFAULT_VERSIONS,
+        TlsVersion::Tls13 => TLS13_ONLY,
+    }
+}
+
+fn cipher_suites(policy: &TlsPolicy) -> NatResult<Vec<SupportedCipherSuite>> {
+    if policy.cipher_suites.is_empty() {
+        return Ok(DEFAULT_CIPHER_SUITES.to_vec());
+    }
+    policy
+        .cipher_suites
+        .iter()
+        .map(|name| {
+            ALL_CIPHER_SUITES
+                .iter()
+                .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+                .copied()
+                .ok_or_else(|| NatError::config(format!("Unknown TLS cipher suite '{}'", name)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tls_policy() {
+        let policy = TlsPolicy {
+            min_version: TlsVersion::Tls13,
+            cipher_suites: vec!["tls13_aes_256_gcm_sha384".to_string()],
+            alpn: vec!["nat/1".to_string()],
+        };
+        assert_eq!(cipher_suites(&policy).unwrap().len(), 1);
+        assert!(restrict(rustls::ClientConfig::builder(), &policy).is_ok());
+        assert_eq!(alpn_protocols(&policy), vec![b"nat/1".to_vec()]);
+
+        // Only TLS 1.2 suites leave nothing for a TLS 1.3-only handshake
+        let unusable = TlsPolicy {
+            cipher_suites: vec!["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".to_string()],
+            ..policy.clone()
+        };
+        assert!(restrict(rustls::ServerConfig::builder(), &unusable).is_err());
+
+        let unknown = TlsPolicy {
+            cipher_suites: vec!["TLS_RSA_WITH_RC4_128_MD5".to_string()],
+            ..policy
+        };
+        assert!(cipher_suites(&unknown).is_err());
+    }
+}
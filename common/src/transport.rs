@@ -0,0 +1,187 @@
+//! Transports the control protocol runs over.
+//!
+//! Both ends only see a `Transport`: a byte stream carrying length-prefixed
+//! frames. The server takes them from a `Listener` and the client opens
+//! them with a `Connector`, so TLS over TCP is one implementation among
+//! possible others, and `memory` connects the two ends in-process.
+
+use crate::error::{NatError, NatResult};
+use crate::protocol::Message;
+#[cfg(any(test, feature = "test-util"))]
+pub use memory::{memory, MemoryConnector, MemoryListener};
+use std::future::Future;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// A connected byte stream between a client and the server
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Transport for T {}
+
+/// A connection the server accepted and finished setting up
+pub struct Established<S> {
+    pub stream: S,
+    /// Who the peer's verified client certificate names, for transports
+    /// that have them
+    pub identity: Option<String>,
+}
+
+/// Where the server takes client connections from
+pub trait Listener: Send + Sync + 'static {
+    /// A connection accepted but not set up yet
+    type Incoming: Send + 'static;
+    type Stream: Transport;
+
+    /// Wait for the next connection. Handshakes belong in `establish`, so
+    /// one slow peer never holds up the others.
+    fn accept(&self) -> impl Future<Output = NatResult<(Self::Incoming, SocketAddr)>> + Send;
+
+    /// Finish setting up a connection, on a task of its own
+    fn establish(
+        &self,
+        incoming: Self::Incoming,
+    ) -> impl Future<Output = NatResult<Established<Self::Stream>>> + Send;
+}
+
+/// How a client reaches the server
+pub trait Connector: Send + Sync {
+    type Stream: Transport;
+
+    fn connect(&self) -> impl Future<Output = NatResult<Self::Stream>> + Send;
+}
+
+/// Send one message with control framing and flush it
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &Message,
+) -> NatResult<()> {
+    let data = message.to_bytes()?;
+    writer.write_all(&(data.len() as u32).to_be_bytes()).await?;
+    writer.write_all(&data).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read the body of the next control frame, `None` at end of stream.
+/// Frames over `max_len` bytes are an error.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+) -> NatResult<Option<Vec<u8>>> {
+    let mut len_buf = [0u8; 4];
+    match reader.read_exact(&mut len_buf).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > max_len {
+        return Err(NatError::protocol(format!(
+            "Message too large: {} bytes",
+            len
+        )));
+    }
+
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data).await?;
+    Ok(Some(data))
+}
+
+/// Transports within one process, for tests
+#[cfg(any(test, feature = "test-util"))]
+mod memory {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use tokio::io::DuplexStream;
+    use tokio::sync::{mpsc, Mutex};
+
+    /// Connect clients to a server in the same process, each connection
+    /// buffering up to `buffer` bytes each way
+    pub fn memory(buffer: usize) -> (MemoryConnector, MemoryListener) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (
+            MemoryConnector { tx, buffer },
+            MemoryListener {
+                incoming: Mutex::new(rx),
+            },
+        )
+    }
+
+    #[derive(Clone)]
+    pub struct MemoryConnector {
+        tx: mpsc::UnboundedSender<DuplexStream>,
+        buffer: usize,
+    }
+
+    pub struct MemoryListener {
+        incoming: Mutex<mpsc::UnboundedReceiver<DuplexStream>>,
+    }
+
+    impl Connector for MemoryConnector {
+        type Stream = DuplexStream;
+
+        async fn connect(&self) -> NatResult<DuplexStream> {
+            let (near, far) = tokio::io::duplex(self.buffer);
+            self.tx
+                .send(far)
+                .map_err(|_| NatError::connection("The server is gone"))?;
+            Ok(near)
+        }
+    }
+
+    impl Listener for MemoryListener {
+        type Incoming = DuplexStream;
+        type Stream = DuplexStream;
+
+        async fn accept(&self) -> NatResult<(DuplexStream, SocketAddr)> {
+            let stream = self
+                .incoming
+                .lock()
+                .await
+                .recv()
+                .await
+                .ok_or_else(|| NatError::connection("Every connector is gone"))?;
+            Ok((stream, (Ipv4Addr::LOCALHOST, 0).into()))
+        }
+
+        async fn establish(&self, incoming: DuplexStream) -> NatResult<Established<DuplexStream>> {
+            Ok(Established {
+                stream: incoming,
+                identity: None,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_transport_carries_frames() {
+        let (connector, listener) = memory(4096);
+        let mut client = connector.connect().await.unwrap();
+        let (incoming, _) = listener.accept().await.unwrap();
+        let mut server = listener.establish(incoming).await.unwrap().stream;
+
+        write_frame(&mut client, &Message::StatusRequest)
+            .await
+            .unwrap();
+        let frame = read_frame(&mut server, 1024).await.unwrap().unwrap();
+        assert!(matches!(
+            Message::from_bytes(&frame).unwrap(),
+            Message::StatusRequest
+        ));
+
+        let reason = "x".repeat(2048);
+        write_frame(&mut server, &Message::Disconnect { reason })
+            .await
+            .unwrap();
+        assert!(read_frame(&mut client, 1024).await.is_err());
+
+        drop(server);
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(read_frame(&mut client, 1024).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,161 @@
+//! Pieces of the layer-3 VPN mode both ends share.
+//!
+//! Clients that join the VPN get an address on the server's overlay
+//! subnet and exchange raw IPv4 packets with it as `VpnPacket` messages.
+//! The server routes each packet by its destination: to the client whose
+//! overlay address or advertised subnet holds it, otherwise into its own
+//! TUN device.
+
+use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
+
+/// An IPv4 network, written `"192.168.1.0/24"`. As an interface address
+/// the host bits are kept, as in `"10.89.0.2/24"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Subnet {
+    pub addr: Ipv4Addr,
+    pub prefix: u8,
+}
+
+impl Subnet {
+    fn mask(&self) -> u32 {
+        u32::MAX
+            .checked_shl(32 - u32::from(self.prefix))
+            .unwrap_or(0)
+    }
+
+    /// The subnet's first address, the host bits cleared
+    pub fn network(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.addr) & self.mask())
+    }
+
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        u32::from(addr) & self.mask() == u32::from(self.network())
+    }
+
+    /// Whether the two subnets share any address
+    pub fn overlaps(&self, other: &Subnet) -> bool {
+        self.contains(other.network()) || other.contains(self.network())
+    }
+
+    /// Whether every address of `other` is in this subnet
+    pub fn covers(&self, other: &Subnet) -> bool {
+        self.prefix <= other.prefix && self.contains(other.network())
+    }
+
+    /// The `index`th address of the subnet, if it holds that many and the
+    /// address is not its broadcast address
+    pub fn host(&self, index: u32) -> Option<Ipv4Addr> {
+        let size = 1u64 << (32 - u32::from(self.prefix));
+        if index == 0 || u64::from(index) >= size - 1 {
+            return None;
+        }
+        Some(Ipv4Addr::from(u32::from(self.network()) + index))
+    }
+
+    /// `addr` on this subnet, as an interface address
+    pub fn with_host(&self, addr: Ipv4Addr) -> Subnet {
+        Subnet {
+            addr,
+            prefix: self.prefix,
+        }
+    }
+}
+
+impl std::str::FromStr for Subnet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid subnet '{}'", s);
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, prefix.parse::<u8>().map_err(|_| invalid())?),
+            None => (s, 32),
+        };
+        if prefix > 32 {
+            return Err(invalid());
+        }
+        Ok(Self {
+            addr: addr.trim().parse().map_err(|_| invalid())?,
+            prefix,
+        })
+    }
+}
+
+impl std::fmt::Display for Subnet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Serialize for Subnet {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Subnet {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Source and destination of an IPv4 packet, `None` for anything else
+pub fn addresses(packet: &[u8]) -> Option<(Ipv4Addr, Ipv4Addr)> {
+    if packet.len() < 20 || packet[0] >> 4 != 4 {
+        return None;
+    }
+    let source = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+    let destination = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+    Some((source, destination))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subnet_parse_and_contains() {
+        let lan: Subnet = "192.168.1.0/24".parse().unwrap();
+        assert!(lan.contains(Ipv4Addr::new(192, 168, 1, 77)));
+        assert!(!lan.contains(Ipv4Addr::new(192, 168, 2, 1)));
+        assert_eq!(lan.to_string(), "192.168.1.0/24");
+
+        let address: Subnet = "10.89.0.2/24".parse().unwrap();
+        assert_eq!(address.network(), Ipv4Addr::new(10, 89, 0, 0));
+        assert!(address.overlaps(&"10.0.0.0/8".parse().unwrap()));
+        assert!(!address.overlaps(&lan));
+        assert!(lan.covers(&"192.168.1.128/25".parse().unwrap()));
+        assert!(lan.covers(&lan));
+        assert!(!lan.covers(&"192.168.0.0/16".parse().unwrap()));
+
+        let host: Subnet = "10.1.2.3".parse().unwrap();
+        assert_eq!(host.prefix, 32);
+        assert!(host.contains(Ipv4Addr::new(10, 1, 2, 3)));
+        assert!("10.0.0.0/33".parse::<Subnet>().is_err());
+    }
+
+    #[test]
+    fn test_subnet_hosts() {
+        let overlay: Subnet = "10.89.0.0/30".parse().unwrap();
+        assert_eq!(overlay.host(0), None);
+        assert_eq!(overlay.host(1), Some(Ipv4Addr::new(10, 89, 0, 1)));
+        assert_eq!(overlay.host(2), Some(Ipv4Addr::new(10, 89, 0, 2)));
+        assert_eq!(overlay.host(3), None);
+    }
+
+    #[test]
+    fn test_packet_addresses() {
+        let mut packet = [0u8; 20];
+        packet[0] = 0x45;
+        packet[12..16].copy_from_slice(&[10, 89, 0, 2]);
+        packet[16..20].copy_from_slice(&[192, 168, 1, 10]);
+        assert_eq!(
+            addresses(&packet),
+            Some((Ipv4Addr::new(10, 89, 0, 2), Ipv4Addr::new(192, 168, 1, 10)))
+        );
+        packet[0] = 0x60;
+        assert_eq!(addresses(&packet), None);
+    }
+}
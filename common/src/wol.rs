@@ -0,0 +1,71 @@
+//! Wake-on-LAN magic packets
+
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+/// Parse a MAC address written as six hex pairs separated by `:` or `-`
+pub fn parse_mac(mac: &str) -> anyhow::Result<[u8; 6]> {
+    let parts: Vec<&str> = mac.split([':', '-']).collect();
+    if parts.len() != 6 {
+        return Err(anyhow::anyhow!("Invalid MAC address '{}'", mac));
+    }
+    let mut bytes = [0u8; 6];
+    for (byte, part) in bytes.iter_mut().zip(parts) {
+        if part.len() != 2 {
+            return Err(anyhow::anyhow!("Invalid MAC address '{}'", mac));
+        }
+        *byte = u8::from_str_radix(part, 16)
+            .map_err(|_| anyhow::anyhow!("Invalid MAC address '{}'", mac))?;
+    }
+    Ok(bytes)
+}
+
+/// Six 0xFF bytes followed by the MAC address sixteen times
+pub fn magic_packet(mac: &[u8; 6]) -> Vec<u8> {
+    let mut packet = vec![0xFF; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(mac);
+    }
+    packet
+}
+
+/// Broadcast the magic packet for `mac` to `addr`
+pub async fn send(mac: &[u8; 6], addr: SocketAddr) -> anyhow::Result<()> {
+    let bind: SocketAddr = if addr.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.set_broadcast(true)?;
+    socket.send_to(&magic_packet(mac), addr).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mac() {
+        let mac = [0x00, 0x1a, 0x2b, 0x3c, 0x4d, 0x5e];
+        assert_eq!(parse_mac("00:1A:2b:3c:4d:5e").unwrap(), mac);
+        assert_eq!(parse_mac("00-1a-2b-3c-4d-5e").unwrap(), mac);
+        assert!(parse_mac("00:1a:2b:3c:4d").is_err());
+        assert!(parse_mac("00:1a:2b:3c:4d:5g").is_err());
+        assert!(parse_mac("001:a:2b:3c:4d:5e").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_magic_packet_delivered() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mac = [1, 2, 3, 4, 5, 6];
+        send(&mac, receiver.local_addr().unwrap()).await.unwrap();
+
+        let mut buf = [0u8; 256];
+        let n = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(n, 102);
+        assert_eq!(&buf[..6], &[0xFF; 6]);
+        assert!(buf[6..n].chunks(6).all(|chunk| chunk == mac));
+    }
+}
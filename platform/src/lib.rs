@@ -1,4 +1,8 @@
+pub mod notify;
+pub mod privileges;
 pub mod service;
+pub mod tun;
+pub mod wireguard;
 
 #[cfg(windows)]
 pub mod windows;
@@ -9,13 +9,262 @@ use std::path::Path;
 #[cfg(unix)]
 use std::process::Command;
 
+/// Init system used to manage services
 #[cfg(unix)]
-pub struct LinuxServiceManager;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitSystem {
+    Systemd,
+    OpenRc,
+    SysV,
+}
+
+#[cfg(unix)]
+impl InitSystem {
+    /// Detect the init system the host was booted with
+    pub fn detect() -> Self {
+        if Path::new("/run/systemd/system").exists() {
+            InitSystem::Systemd
+        } else if Path::new("/run/openrc").exists() || Path::new("/sbin/openrc-run").exists() {
+            InitSystem::OpenRc
+        } else {
+            InitSystem::SysV
+        }
+    }
+}
+
+#[cfg(unix)]
+pub struct LinuxServiceManager {
+    init_system: InitSystem,
+}
 
 #[cfg(unix)]
 impl LinuxServiceManager {
     pub fn new() -> Self {
-        Self
+        Self::with_init_system(InitSystem::detect())
+    }
+
+    pub fn with_init_system(init_system: InitSystem) -> Self {
+        Self { init_system }
+    }
+
+    pub fn init_system(&self) -> InitSystem {
+        self.init_system
+    }
+
+    fn init_script_path(service_name: &str) -> String {
+        format!("/etc/init.d/{}", service_name)
+    }
+
+    fn command_line(config: &ServiceConfig) -> String {
+        std::iter::once(config.executable_path.to_string_lossy().into_owned())
+            .chain(config.arguments.iter().cloned())
+            .map(|arg| shell_quote(&arg))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn create_openrc_script(config: &ServiceConfig) -> String {
+        let working_dir = config
+            .working_directory
+            .as_ref()
+            .map(|p| p.to_string_lossy())
+            .unwrap_or_else(|| "/".into());
+
+        // OpenRC evals command_args, so the quoted list is quoted once more
+        let args = config
+            .arguments
+            .iter()
+            .map(|arg| shell_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        format!(
+            r#"#!/sbin/openrc-run
+
+name={}
+description={}
+command={}
+command_args={}
+command_user={}
+command_background=true
+pidfile="/run/${{RC_SVCNAME}}.pid"
+directory={}
+
+depend() {{
+    need net
+    after firewall
+}}
+"#,
+            shell_quote(&config.display_name),
+            shell_quote(&config.description),
+            shell_quote(&config.executable_path.to_string_lossy()),
+            shell_quote(&args),
+            shell_quote(config.user.as_deref().unwrap_or("root")),
+            shell_quote(&working_dir),
+        )
+    }
+
+    fn create_sysv_script(config: &ServiceConfig) -> String {
+        let working_dir = config
+            .working_directory
+            .as_ref()
+            .map(|p| p.to_string_lossy())
+            .unwrap_or_else(|| "/".into());
+
+        // Run as the configured user through su, otherwise directly as root
+        let command = Self::command_line(config);
+        let launch = match config.user.as_deref() {
+            Some(user) if user != "root" => format!(
+                "su -s /bin/sh -c {} {}",
+                shell_quote(&format!("exec {} >/dev/null 2>&1 & echo $!", command)),
+                shell_quote(user)
+            ),
+            _ => format!(
+                "sh -c {}",
+                shell_quote(&format!("exec {} >/dev/null 2>&1 & echo $!", command))
+            ),
+        };
+
+        format!(
+            r#"#!/bin/sh
+### BEGIN INIT INFO
+# Provides:          {name}
+# Required-Start:    $network $remote_fs
+# Required-Stop:     $network $remote_fs
+# Default-Start:     2 3 4 5
+# Default-Stop:      0 1 6
+# Short-Description: {description}
+### END INIT INFO
+
+PIDFILE=/var/run/{name}.pid
+
+is_running() {{
+    [ -f "$PIDFILE" ] && kill -0 "$(cat "$PIDFILE")" 2>/dev/null
+}}
+
+case "$1" in
+    start)
+        if is_running; then
+            echo "{name} is already running"
+            exit 0
+        fi
+        cd {working_dir} || exit 1
+        {launch} > "$PIDFILE"
+        ;;
+    stop)
+        if is_running; then
+            kill "$(cat "$PIDFILE")"
+        fi
+        rm -f "$PIDFILE"
+        ;;
+    restart)
+        "$0" stop
+        sleep 1
+        "$0" start
+        ;;
+    status)
+        if is_running; then
+            echo "{name} is running"
+            exit 0
+        fi
+        echo "{name} is stopped"
+        exit 3
+        ;;
+    *)
+        echo "Usage: $0 {{start|stop|restart|status}}"
+        exit 1
+        ;;
+esac
+"#,
+            name = config.name,
+            description = config.description,
+            working_dir = shell_quote(&working_dir),
+            launch = launch,
+        )
+    }
+
+    fn install_init_script(&self, config: &ServiceConfig) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let script_path = Self::init_script_path(&config.name);
+        let script = match self.init_system {
+            InitSystem::OpenRc => Self::create_openrc_script(config),
+            _ => Self::create_sysv_script(config),
+        };
+
+        fs::write(&script_path, script)?;
+        fs::set_permissions(&script_path, fs::Permissions::from_mode(0o755))?;
+
+        // Register the script with the runlevels
+        match self.init_system {
+            InitSystem::OpenRc => run_command(
+                "rc-update",
+                &["add", &config.name, "default"],
+                "enable service",
+            )?,
+            _ => {
+                if command_exists("update-rc.d") {
+                    run_command("update-rc.d", &[&config.name, "defaults"], "enable service")?;
+                } else if command_exists("chkconfig") {
+                    run_command("chkconfig", &["--add", &config.name], "enable service")?;
+                } else {
+                    tracing::warn!(
+                        "No runlevel tool found; '{}' will not start at boot",
+                        config.name
+                    );
+                }
+            }
+        }
+
+        tracing::info!("Service '{}' installed successfully", config.name);
+        Ok(())
+    }
+
+    fn uninstall_init_script(&self, service_name: &str) -> Result<()> {
+        let _ = self.stop(service_name);
+
+        let result = match self.init_system {
+            InitSystem::OpenRc => run_command(
+                "rc-update",
+                &["del", service_name, "default"],
+                "disable service",
+            ),
+            _ if command_exists("update-rc.d") => run_command(
+                "update-rc.d",
+                &["-f", service_name, "remove"],
+                "disable service",
+            ),
+            _ if command_exists("chkconfig") => {
+                run_command("chkconfig", &["--del", service_name], "disable service")
+            }
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            tracing::warn!("{}", e);
+        }
+
+        let script_path = Self::init_script_path(service_name);
+        if Path::new(&script_path).exists() {
+            fs::remove_file(&script_path)?;
+        }
+
+        tracing::info!("Service '{}' uninstalled successfully", service_name);
+        Ok(())
+    }
+
+    fn control_init_script(&self, service_name: &str, action: &str) -> Result<()> {
+        match self.init_system {
+            InitSystem::OpenRc => run_command(
+                "rc-service",
+                &[service_name, action],
+                &format!("{} service", action),
+            ),
+            _ => run_command(
+                &Self::init_script_path(service_name),
+                &[action],
+                &format!("{} service", action),
+            ),
+        }
     }
 
     fn systemd_service_path(service_name: &str) -> String {
@@ -41,12 +290,13 @@ Description={}
 After=network.target
 
 [Service]
-Type=simple
+Type=notify
 User={}
 WorkingDirectory={}
 ExecStart={}{}
 Restart=always
 RestartSec=5
+WatchdogSec=30
 
 [Install]
 WantedBy=multi-user.target
@@ -60,9 +310,20 @@ WantedBy=multi-user.target
     }
 }
 
+#[cfg(unix)]
+impl Default for LinuxServiceManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(unix)]
 impl ServiceManager for LinuxServiceManager {
     fn install(&self, config: &ServiceConfig) -> Result<()> {
+        if self.init_system != InitSystem::Systemd {
+            return self.install_init_script(config);
+        }
+
         let service_path = Self::systemd_service_path(&config.name);
         let service_content = Self::create_systemd_service_file(config);
 
@@ -70,9 +331,7 @@ impl ServiceManager for LinuxServiceManager {
         fs::write(&service_path, service_content)?;
 
         // Reload systemd
-        let output = Command::new("systemctl")
-            .args(&["daemon-reload"])
-            .output()?;
+        let output = Command::new("systemctl").args(["daemon-reload"]).output()?;
 
         if !output.status.success() {
             return Err(anyhow!(
@@ -83,7 +342,7 @@ impl ServiceManager for LinuxServiceManager {
 
         // Enable service
         let output = Command::new("systemctl")
-            .args(&["enable", &config.name])
+            .args(["enable", &config.name])
             .output()?;
 
         if !output.status.success() {
@@ -98,12 +357,16 @@ impl ServiceManager for LinuxServiceManager {
     }
 
     fn uninstall(&self, service_name: &str) -> Result<()> {
+        if self.init_system != InitSystem::Systemd {
+            return self.uninstall_init_script(service_name);
+        }
+
         // Stop service first
         let _ = self.stop(service_name);
 
         // Disable service
         let output = Command::new("systemctl")
-            .args(&["disable", service_name])
+            .args(["disable", service_name])
             .output()?;
 
         if !output.status.success() {
@@ -120,9 +383,7 @@ impl ServiceManager for LinuxServiceManager {
         }
 
         // Reload systemd
-        let output = Command::new("systemctl")
-            .args(&["daemon-reload"])
-            .output()?;
+        let output = Command::new("systemctl").args(["daemon-reload"]).output()?;
 
         if !output.status.success() {
             return Err(anyhow!(
@@ -136,8 +397,14 @@ impl ServiceManager for LinuxServiceManager {
     }
 
     fn start(&self, service_name: &str) -> Result<()> {
+        if self.init_system != InitSystem::Systemd {
+            self.control_init_script(service_name, "start")?;
+            tracing::info!("Service '{}' started successfully", service_name);
+            return Ok(());
+        }
+
         let output = Command::new("systemctl")
-            .args(&["start", service_name])
+            .args(["start", service_name])
             .output()?;
 
         if !output.status.success() {
@@ -152,8 +419,14 @@ impl ServiceManager for LinuxServiceManager {
     }
 
     fn stop(&self, service_name: &str) -> Result<()> {
+        if self.init_system != InitSystem::Systemd {
+            self.control_init_script(service_name, "stop")?;
+            tracing::info!("Service '{}' stopped successfully", service_name);
+            return Ok(());
+        }
+
         let output = Command::new("systemctl")
-            .args(&["stop", service_name])
+            .args(["stop", service_name])
             .output()?;
 
         if !output.status.success() {
@@ -168,10 +441,44 @@ impl ServiceManager for LinuxServiceManager {
     }
 
     fn is_running(&self, service_name: &str) -> Result<bool> {
+        if self.init_system != InitSystem::Systemd {
+            return Ok(self.control_init_script(service_name, "status").is_ok());
+        }
+
         let output = Command::new("systemctl")
-            .args(&["is-active", "--quiet", service_name])
+            .args(["is-active", "--quiet", service_name])
             .output()?;
 
         Ok(output.status.success())
     }
 }
+
+/// Quote a value for a POSIX shell
+#[cfg(unix)]
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[cfg(unix)]
+fn command_exists(program: &str) -> bool {
+    Command::new("sh")
+        .args(["-c", &format!("command -v {} >/dev/null 2>&1", program)])
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+#[cfg(unix)]
+fn run_command(program: &str, args: &[&str], action: &str) -> Result<()> {
+    let output = Command::new(program).args(args).output()?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Failed to {}: {}",
+            action,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,69 @@
+//! Service manager readiness and watchdog notifications.
+//!
+//! Implements the systemd `sd_notify` protocol. Every function is a no-op
+//! when the process was not started by systemd or on other platforms.
+
+use std::time::Duration;
+
+/// Tell the service manager that startup has finished
+pub fn notify_ready() {
+    notify("READY=1");
+}
+
+/// Tell the service manager that the process is shutting down
+pub fn notify_stopping() {
+    notify("STOPPING=1");
+}
+
+/// Reset the service manager's watchdog timer
+pub fn notify_watchdog() {
+    notify("WATCHDOG=1");
+}
+
+/// Interval at which `notify_watchdog` must be called, if a watchdog is enabled
+pub fn watchdog_interval() -> Option<Duration> {
+    // WATCHDOG_PID, when present, names the process the watchdog applies to
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+#[cfg(unix)]
+fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    let socket = match UnixDatagram::unbound() {
+        Ok(socket) => socket,
+        Err(e) => {
+            tracing::debug!("Failed to create notify socket: {}", e);
+            return;
+        }
+    };
+
+    let path = path.to_string_lossy();
+    let result = match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())
+                .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr))
+        }
+        _ => socket.send_to(state.as_bytes(), path.as_ref()),
+    };
+
+    if let Err(e) = result {
+        tracing::debug!("Failed to notify service manager: {}", e);
+    }
+}
+
+#[cfg(not(unix))]
+fn notify(_state: &str) {}
@@ -0,0 +1,51 @@
+use anyhow::{anyhow, Result};
+
+/// Switch the process to an unprivileged account.
+///
+/// Meant to be called once privileged resources (listening sockets, key
+/// files) have been acquired. `group` defaults to the user's primary group.
+#[cfg(unix)]
+pub fn drop_privileges(user: &str, group: Option<&str>) -> Result<()> {
+    use nix::unistd::{getuid, initgroups, setgid, setuid, Group, User};
+    use std::ffi::CString;
+
+    let target = User::from_name(user)?.ok_or_else(|| anyhow!("Unknown user '{}'", user))?;
+    let gid = match group {
+        Some(name) => {
+            Group::from_name(name)?
+                .ok_or_else(|| anyhow!("Unknown group '{}'", name))?
+                .gid
+        }
+        None => target.gid,
+    };
+
+    if getuid() == target.uid {
+        return Ok(());
+    }
+
+    if !getuid().is_root() {
+        return Err(anyhow!(
+            "Cannot switch to user '{}' without root privileges",
+            user
+        ));
+    }
+
+    // Supplementary groups and gid must change while we are still root
+    let name = CString::new(user)?;
+    initgroups(&name, gid)?;
+    setgid(gid)?;
+    setuid(target.uid)?;
+
+    // Make sure root cannot be regained
+    if setuid(nix::unistd::Uid::from_raw(0)).is_ok() {
+        return Err(anyhow!("Privileges could not be dropped permanently"));
+    }
+
+    tracing::info!("Dropped privileges to user '{}' (uid {})", user, target.uid);
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn drop_privileges(_user: &str, _group: Option<&str>) -> Result<()> {
+    Err(anyhow!("Dropping privileges is not supported on Windows"))
+}
@@ -1,4 +1,5 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use clap::Subcommand;
 use std::path::PathBuf;
 
 /// Cross-platform service management
@@ -31,6 +32,81 @@ pub struct ServiceConfig {
     pub user: Option<String>,
 }
 
+impl ServiceConfig {
+    /// Service running the current executable with the given arguments
+    pub fn for_current_exe(
+        name: &str,
+        display_name: &str,
+        description: &str,
+        arguments: Vec<String>,
+    ) -> Result<Self> {
+        let executable_path = std::env::current_exe()?;
+        let working_directory = executable_path.parent().map(|p| p.to_path_buf());
+
+        Ok(Self {
+            name: name.to_string(),
+            display_name: display_name.to_string(),
+            description: description.to_string(),
+            executable_path,
+            arguments,
+            working_directory,
+            user: None,
+        })
+    }
+}
+
+/// Service management commands shared by the client and server binaries
+#[derive(Subcommand, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceAction {
+    /// Install and enable the service
+    Install,
+    /// Stop and remove the service
+    Uninstall,
+    /// Start the installed service
+    Start,
+    /// Stop the running service
+    Stop,
+    /// Show whether the service is running
+    Status,
+}
+
+impl ServiceAction {
+    /// Whether the action modifies system state and needs elevated privileges
+    pub fn requires_privileges(&self) -> bool {
+        !matches!(self, ServiceAction::Status)
+    }
+}
+
+/// Run a service command against the platform service manager
+pub fn run_service_action(action: ServiceAction, config: &ServiceConfig) -> Result<()> {
+    if action.requires_privileges() && !has_service_privileges() {
+        #[cfg(windows)]
+        return Err(anyhow!(
+            "Administrator privileges are required to manage services"
+        ));
+
+        #[cfg(unix)]
+        return Err(anyhow!("Root privileges are required to manage services"));
+    }
+
+    let manager = get_service_manager();
+    match action {
+        ServiceAction::Install => manager.install(config),
+        ServiceAction::Uninstall => manager.uninstall(&config.name),
+        ServiceAction::Start => manager.start(&config.name),
+        ServiceAction::Stop => manager.stop(&config.name),
+        ServiceAction::Status => {
+            let state = if manager.is_running(&config.name)? {
+                "running"
+            } else {
+                "stopped"
+            };
+            println!("Service '{}' is {}", config.name, state);
+            Ok(())
+        }
+    }
+}
+
 /// Get the appropriate service manager for the current platform
 pub fn get_service_manager() -> Box<dyn ServiceManager> {
     #[cfg(windows)]
@@ -0,0 +1,186 @@
+//! TUN devices for the VPN mode.
+//!
+//! A TUN device is a virtual network interface whose IP packets the
+//! process reads and writes instead of a network card. Creating one and
+//! changing its address or routes takes root or `CAP_NET_ADMIN`; the
+//! address and routes are set with `ip` from iproute2. Only Linux is
+//! supported, elsewhere `Tun::create` fails.
+
+use anyhow::Result;
+use std::net::Ipv4Addr;
+
+/// An open TUN device carrying raw IPv4 packets
+pub struct Tun {
+    name: String,
+    #[cfg(target_os = "linux")]
+    fd: tokio::io::unix::AsyncFd<std::fs::File>,
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::os::raw::{c_char, c_short};
+
+    /// `_IOW('T', 202, int)`
+    pub const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+    pub const IFF_TUN: c_short = 0x0001;
+    /// Packets come without the 4-byte protocol header
+    pub const IFF_NO_PI: c_short = 0x1000;
+
+    /// The part of `struct ifreq` `TUNSETIFF` reads, padded to its size
+    #[repr(C)]
+    pub struct IfReq {
+        pub name: [c_char; libc::IFNAMSIZ],
+        pub flags: c_short,
+        pub _pad: [u8; 22],
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Tun {
+    /// Create the device `name` (the kernel picks one for a name ending in
+    /// `%d`), give it `address/prefix` and `mtu`, and bring it up
+    pub fn create(name: &str, address: Ipv4Addr, prefix: u8, mtu: u16) -> Result<Self> {
+        use std::os::fd::AsRawFd;
+
+        if name.is_empty() || name.len() >= libc::IFNAMSIZ {
+            return Err(anyhow::anyhow!("Invalid interface name '{}'", name));
+        }
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")
+            .map_err(|e| anyhow::anyhow!("Failed to open /dev/net/tun: {}", e))?;
+
+        let mut request = sys::IfReq {
+            name: [0; libc::IFNAMSIZ],
+            flags: sys::IFF_TUN | sys::IFF_NO_PI,
+            _pad: [0; 22],
+        };
+        for (slot, byte) in request.name.iter_mut().zip(name.bytes()) {
+            *slot = byte as _;
+        }
+        // SAFETY: the descriptor is open and `request` outlives the call
+        if unsafe { libc::ioctl(file.as_raw_fd(), sys::TUNSETIFF as _, &mut request) } < 0 {
+            return Err(anyhow::anyhow!(
+                "Failed to create TUN device '{}': {}",
+                name,
+                std::io::Error::last_os_error()
+            ));
+        }
+        let name = request
+            .name
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as u8 as char)
+            .collect::<String>();
+
+        // SAFETY: as above; O_NONBLOCK lets tokio drive the descriptor
+        unsafe {
+            let flags = libc::fcntl(file.as_raw_fd(), libc::F_GETFL);
+            libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK);
+        }
+        let tun = Self {
+            fd: tokio::io::unix::AsyncFd::new(file)?,
+            name,
+        };
+
+        let address = format!("{}/{}", address, prefix);
+        let mtu = mtu.to_string();
+        ip(&["address", "replace", &address, "dev", &tun.name])?;
+        ip(&["link", "set", "dev", &tun.name, "mtu", &mtu, "up"])?;
+        tracing::info!("TUN device {} up with address {}", tun.name, address);
+        Ok(tun)
+    }
+
+    /// Route `network/prefix` through the device
+    pub fn add_route(&self, network: Ipv4Addr, prefix: u8) -> Result<()> {
+        let route = format!("{}/{}", network, prefix);
+        ip(&["route", "add", &route, "dev", &self.name])
+    }
+
+    /// Stop routing `network/prefix` through the device
+    pub fn remove_route(&self, network: Ipv4Addr, prefix: u8) -> Result<()> {
+        let route = format!("{}/{}", network, prefix);
+        ip(&["route", "del", &route, "dev", &self.name])
+    }
+
+    /// Read the next packet the kernel sends through the device
+    pub async fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        use std::io::Read;
+
+        loop {
+            let mut guard = self.fd.readable().await?;
+            match guard.try_io(|fd| fd.get_ref().read(buf)) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Hand a packet to the kernel as if it arrived on the device
+    pub async fn send(&self, packet: &[u8]) -> std::io::Result<()> {
+        use std::io::Write;
+
+        loop {
+            let mut guard = self.fd.writable().await?;
+            match guard.try_io(|fd| fd.get_ref().write(packet)) {
+                Ok(result) => return result.map(|_| ()),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn ip(args: &[&str]) -> Result<()> {
+    let output = std::process::Command::new("ip")
+        .args(args)
+        .output()
+        .map_err(|e| anyhow::anyhow!("Failed to run ip: {}", e))?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "ip {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Tun {
+    pub fn create(_name: &str, _address: Ipv4Addr, _prefix: u8, _mtu: u16) -> Result<Self> {
+        Err(anyhow::anyhow!("The VPN mode is only supported on Linux"))
+    }
+
+    pub fn add_route(&self, _network: Ipv4Addr, _prefix: u8) -> Result<()> {
+        Err(unsupported().into())
+    }
+
+    pub fn remove_route(&self, _network: Ipv4Addr, _prefix: u8) -> Result<()> {
+        Err(unsupported().into())
+    }
+
+    pub async fn recv(&self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Err(unsupported())
+    }
+
+    pub async fn send(&self, _packet: &[u8]) -> std::io::Result<()> {
+        Err(unsupported())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "TUN devices are only supported on Linux",
+    )
+}
+
+impl Tun {
+    /// Name of the interface
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
@@ -20,6 +20,13 @@ impl WindowsServiceManager {
     }
 }
 
+#[cfg(windows)]
+impl Default for WindowsServiceManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(windows)]
 impl ServiceManager for WindowsServiceManager {
     fn install(&self, config: &ServiceConfig) -> Result<()> {
@@ -35,9 +42,9 @@ impl ServiceManager for WindowsServiceManager {
             start_type: ServiceStartType::AutoStart,
             error_control: ServiceErrorControl::Normal,
             executable_path: config.executable_path.clone(),
-            launch_arguments: config.arguments.iter().map(|s| OsString::from(s)).collect(),
+            launch_arguments: config.arguments.iter().map(OsString::from).collect(),
             dependencies: vec![],
-            account_name: config.user.as_ref().map(|u| OsString::from(u)),
+            account_name: config.user.as_ref().map(OsString::from),
             account_password: None,
         };
 
@@ -91,6 +98,88 @@ impl ServiceManager for WindowsServiceManager {
     }
 }
 
+#[cfg(windows)]
+type ServiceBody = Box<dyn FnOnce(std::sync::mpsc::Receiver<()>) -> Result<()> + Send>;
+
+#[cfg(windows)]
+static SERVICE: std::sync::Mutex<Option<(String, ServiceBody)>> = std::sync::Mutex::new(None);
+
+#[cfg(windows)]
+windows_service::define_windows_service!(ffi_service_main, service_main);
+
+/// Run the process under the service control dispatcher.
+///
+/// Blocks until the service stops. `body` runs on the service thread and
+/// receives a channel that fires when the SCM asks the service to stop or
+/// the system shuts down; it should return once it has shut down.
+#[cfg(windows)]
+pub fn run_service_dispatcher<F>(service_name: &str, body: F) -> Result<()>
+where
+    F: FnOnce(std::sync::mpsc::Receiver<()>) -> Result<()> + Send + 'static,
+{
+    *SERVICE.lock().unwrap() = Some((service_name.to_string(), Box::new(body)));
+    windows_service::service_dispatcher::start(service_name, ffi_service_main)?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn service_main(_arguments: Vec<OsString>) {
+    let Some((service_name, body)) = SERVICE.lock().unwrap().take() else {
+        return;
+    };
+
+    if let Err(e) = run_service_body(&service_name, body) {
+        tracing::error!("Service '{}' failed: {}", service_name, e);
+    }
+}
+
+#[cfg(windows)]
+fn run_service_body(service_name: &str, body: ServiceBody) -> Result<()> {
+    use std::time::Duration;
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+
+    let (stop_tx, stop_rx) = std::sync::mpsc::channel();
+    let status_handle =
+        service_control_handler::register(service_name, move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                let _ = stop_tx.send(());
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?;
+
+    let status = |state, controls_accepted, exit_code| ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(exit_code),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    };
+
+    status_handle.set_service_status(status(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        0,
+    ))?;
+
+    let result = body(stop_rx);
+    let exit_code = if result.is_ok() { 0 } else { 1 };
+
+    status_handle.set_service_status(status(
+        ServiceState::Stopped,
+        ServiceControlAccept::empty(),
+        exit_code,
+    ))?;
+
+    result
+}
+
 #[cfg(windows)]
 pub fn is_elevated() -> bool {
     use winapi::um::processthreadsapi::GetCurrentProcess;
@@ -0,0 +1,201 @@
+//! Kernel WireGuard devices for the WireGuard mesh.
+//!
+//! The device is created with `ip` from iproute2 and configured with `wg`
+//! from wireguard-tools; the kernel does the encryption and carries the
+//! packets. Creating and configuring one takes root or `CAP_NET_ADMIN`.
+//! Only Linux is supported, elsewhere `WireGuardDevice::create` fails.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+
+/// A WireGuard interface, deleted when dropped
+pub struct WireGuardDevice {
+    name: String,
+}
+
+#[cfg(target_os = "linux")]
+impl WireGuardDevice {
+    /// Create the device `name` with `private_key` (base64) listening on
+    /// `listen_port`, replacing one left behind by an earlier run, and
+    /// bring it up
+    pub fn create(name: &str, private_key: &str, listen_port: u16) -> Result<Self> {
+        use crate::tun::ip;
+
+        let _ = ip(&["link", "del", "dev", name]);
+        ip(&["link", "add", "dev", name, "type", "wireguard"])?;
+        let device = Self {
+            name: name.to_string(),
+        };
+        wg(
+            &[
+                "set",
+                name,
+                "private-key",
+                "/dev/stdin",
+                "listen-port",
+                &listen_port.to_string(),
+            ],
+            Some(private_key),
+        )?;
+        ip(&["link", "set", "dev", name, "up"])?;
+        tracing::info!("WireGuard device {} up on port {}", name, listen_port);
+        Ok(device)
+    }
+
+    /// Give the device `address/prefix`, replacing any earlier address
+    pub fn set_address(&self, address: Ipv4Addr, prefix: u8) -> Result<()> {
+        use crate::tun::ip;
+
+        ip(&["address", "flush", "dev", &self.name])?;
+        ip(&[
+            "address",
+            "add",
+            &format!("{}/{}", address, prefix),
+            "dev",
+            &self.name,
+        ])
+    }
+
+    /// Add or update the peer `public_key`, sending to `endpoint` and
+    /// accepting packets from `allowed_ips`
+    pub fn set_peer(
+        &self,
+        public_key: &str,
+        endpoint: Option<SocketAddr>,
+        allowed_ips: &[String],
+        keepalive_secs: u16,
+    ) -> Result<()> {
+        let allowed_ips = allowed_ips.join(",");
+        let keepalive = keepalive_secs.to_string();
+        let mut args = vec![
+            "set",
+            &self.name,
+            "peer",
+            public_key,
+            "allowed-ips",
+            &allowed_ips,
+            "persistent-keepalive",
+            &keepalive,
+        ];
+        let endpoint = endpoint.map(|endpoint| endpoint.to_string());
+        if let Some(endpoint) = &endpoint {
+            args.extend(["endpoint", endpoint]);
+        }
+        wg(&args, None).map(|_| ())
+    }
+
+    pub fn remove_peer(&self, public_key: &str) -> Result<()> {
+        wg(&["set", &self.name, "peer", public_key, "remove"], None).map(|_| ())
+    }
+
+    /// Seconds since the epoch of each peer's latest handshake, 0 for
+    /// peers that never completed one
+    pub fn latest_handshakes(&self) -> Result<HashMap<String, u64>> {
+        let output = wg(&["show", &self.name, "latest-handshakes"], None)?;
+        Ok(output
+            .lines()
+            .filter_map(|line| {
+                let (key, time) = line.split_once('\t')?;
+                Some((key.to_string(), time.trim().parse().ok()?))
+            })
+            .collect())
+    }
+
+    /// Route `network/prefix` through the device
+    pub fn add_route(&self, network: Ipv4Addr, prefix: u8) -> Result<()> {
+        let route = format!("{}/{}", network, prefix);
+        crate::tun::ip(&["route", "replace", &route, "dev", &self.name])
+    }
+
+    /// Stop routing `network/prefix` through the device
+    pub fn remove_route(&self, network: Ipv4Addr, prefix: u8) -> Result<()> {
+        let route = format!("{}/{}", network, prefix);
+        crate::tun::ip(&["route", "del", &route, "dev", &self.name])
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for WireGuardDevice {
+    fn drop(&mut self) {
+        let _ = crate::tun::ip(&["link", "del", "dev", &self.name]);
+    }
+}
+
+/// Run `wg`, feeding it `input` on stdin, and return its output
+#[cfg(target_os = "linux")]
+fn wg(args: &[&str], input: Option<&str>) -> Result<String> {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let mut child = Command::new("wg")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("Failed to run wg: {}", e))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin.write_all(input.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "wg {} failed: {}",
+            args.first().copied().unwrap_or_default(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(not(target_os = "linux"))]
+impl WireGuardDevice {
+    pub fn create(_name: &str, _private_key: &str, _listen_port: u16) -> Result<Self> {
+        Err(anyhow::anyhow!(
+            "The WireGuard mesh is only supported on Linux"
+        ))
+    }
+
+    pub fn set_address(&self, _address: Ipv4Addr, _prefix: u8) -> Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn set_peer(
+        &self,
+        _public_key: &str,
+        _endpoint: Option<SocketAddr>,
+        _allowed_ips: &[String],
+        _keepalive_secs: u16,
+    ) -> Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn remove_peer(&self, _public_key: &str) -> Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn latest_handshakes(&self) -> Result<HashMap<String, u64>> {
+        Err(unsupported())
+    }
+
+    pub fn add_route(&self, _network: Ipv4Addr, _prefix: u8) -> Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn remove_route(&self, _network: Ipv4Addr, _prefix: u8) -> Result<()> {
+        Err(unsupported())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> anyhow::Error {
+    anyhow::anyhow!("WireGuard devices are only supported on Linux")
+}
+
+impl WireGuardDevice {
+    /// Name of the interface
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
@@ -0,0 +1,279 @@
+//! The server's certificate authority. It signs the certificate signing
+//! requests of enrolling clients, and those certificates then identify the
+//! clients over mutual TLS.
+
+use nat_traversal_common::{
+    config::CaConfig,
+    error::{NatError, NatResult},
+};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, CertificateSigningRequestParams, DnType,
+    DnValue, ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose, SerialNumber,
+};
+use std::path::Path;
+use tracing::info;
+use uuid::Uuid;
+
+pub struct CertificateAuthority {
+    /// The CA certificate as stored
+    cert_der: Vec<u8>,
+    /// The CA certificate rebuilt from its parameters, as the issuer
+    issuer: Certificate,
+    key: KeyPair,
+    cert_ttl_days: u32,
+    pub bootstrap_tokens: Vec<String>,
+}
+
+impl CertificateAuthority {
+    /// Load the CA, creating a self-signed one when neither file exists
+    pub fn load_or_create(config: &CaConfig) -> NatResult<Self> {
+        if !config.cert_path.exists() && !config.key_path.exists() {
+            Self::create(&config.cert_path, &config.key_path)?;
+        }
+        let cert_pem = std::fs::read_to_string(&config.cert_path).map_err(|e| {
+            NatError::config(format!(
+                "Failed to read CA certificate {}: {}",
+                config.cert_path.display(),
+                e
+            ))
+        })?;
+        let key_pem = std::fs::read_to_string(&config.key_path).map_err(|e| {
+            NatError::config(format!(
+                "Failed to read CA key {}: {}",
+                config.key_path.display(),
+                e
+            ))
+        })?;
+
+        let cert_der = rustls_pemfile::certs(&mut cert_pem.as_bytes())
+            .ok()
+            .and_then(|certs| certs.into_iter().next())
+            .ok_or_else(|| NatError::config("No CA certificate found"))?;
+        let key = KeyPair::from_pem(&key_pem)
+            .map_err(|e| NatError::config(format!("Invalid CA key: {}", e)))?;
+        let issuer = CertificateParams::from_ca_cert_pem(&cert_pem)
+            .and_then(|params| params.self_signed(&key))
+            .map_err(|e| NatError::config(format!("Invalid CA certificate: {}", e)))?;
+
+        Ok(Self {
+            cert_der,
+            issuer,
+            key,
+            cert_ttl_days: config.cert_ttl_days,
+            bootstrap_tokens: config.bootstrap_tokens.clone(),
+        })
+    }
+
+    fn create(cert_path: &Path, key_path: &Path) -> NatResult<()> {
+        let failed = |e: rcgen::Error| NatError::config(format!("Failed to create CA: {}", e));
+        let key = KeyPair::generate().map_err(failed)?;
+        let mut params = CertificateParams::new(Vec::<String>::new()).map_err(failed)?;
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "NAT Traversal Client CA");
+        params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        let now = time::OffsetDateTime::now_utc();
+        params.not_before = now;
+        params.not_after = now + time::Duration::days(3650);
+        let cert = params.self_signed(&key).map_err(failed)?;
+
+        write_private(key_path, &key.serialize_pem())?;
+        std::fs::write(cert_path, cert.pem()).map_err(|e| {
+            NatError::config(format!(
+                "Failed to write CA certificate {}: {}",
+                cert_path.display(),
+                e
+            ))
+        })?;
+        info!("Created client CA {}", cert_path.display());
+        Ok(())
+    }
+
+    /// The CA certificate in DER, for verifying client certificates
+    pub fn cert_der(&self) -> &[u8] {
+        &self.cert_der
+    }
+
+    /// Sign `csr` as a client certificate for `client_id`. Only the CSR's
+    /// public key is used; the subject is always the client ID.
+    pub fn issue(&self, csr: &str, client_id: &str) -> NatResult<String> {
+        let mut request = CertificateSigningRequestParams::from_pem(csr)
+            .map_err(|e| NatError::protocol(format!("Invalid certificate request: {}", e)))?;
+
+        let mut params = CertificateParams::default();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, client_id);
+        params.is_ca = IsCa::ExplicitNoCa;
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        params.use_authority_key_identifier_extension = true;
+        let mut serial = Uuid::new_v4().into_bytes();
+        serial[0] &= 0x7f;
+        params.serial_number = Some(SerialNumber::from_slice(&serial));
+        let now = time::OffsetDateTime::now_utc();
+        params.not_before = now - time::Duration::minutes(5);
+        params.not_after = now + time::Duration::days(self.cert_ttl_days as i64);
+        request.params = params;
+
+        let cert = request
+            .signed_by(&self.issuer, &self.key)
+            .map_err(|e| NatError::protocol(format!("Failed to sign certificate: {}", e)))?;
+        info!(
+            "Issued a certificate to client {} valid for {} days",
+            client_id, self.cert_ttl_days
+        );
+        Ok(cert.pem())
+    }
+}
+
+/// The client ID a verified client certificate was issued to: its common
+/// name
+pub fn identity(cert: &[u8]) -> Option<String> {
+    let params = CertificateParams::from_ca_cert_der(&cert.into()).ok()?;
+    match params.distinguished_name.get(&DnType::CommonName)? {
+        DnValue::Utf8String(name) => Some(name.clone()),
+        DnValue::PrintableString(name) => Some(name.as_str().to_string()),
+        DnValue::Ia5String(name) => Some(name.as_str().to_string()),
+        _ => None,
+    }
+}
+
+/// Write a private key readable only by its owner
+fn write_private(path: &Path, contents: &str) -> NatResult<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|e| NatError::config(format!("Failed to write CA key {}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::server::{AllowAnyAuthenticatedClient, ClientCertVerifier};
+    use std::time::{Duration, SystemTime};
+
+    /// A CA created in a fresh directory, removed when it is dropped
+    struct TestCa {
+        dir: std::path::PathBuf,
+        ca: CertificateAuthority,
+    }
+
+    impl TestCa {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("nat-ca-{}", Uuid::new_v4()));
+            std::fs::create_dir(&dir).unwrap();
+            let ca = CertificateAuthority::load_or_create(&Self::config(&dir)).unwrap();
+            Self { dir, ca }
+        }
+
+        fn config(dir: &Path) -> CaConfig {
+            CaConfig {
+                cert_path: dir.join("ca.crt"),
+                key_path: dir.join("ca.key"),
+                cert_ttl_days: 30,
+                ..Default::default()
+            }
+        }
+
+        /// Whether the CA accepts `cert` from a client at `now`
+        fn verifies(&self, cert: &[u8], now: SystemTime) -> bool {
+            let mut roots = rustls::RootCertStore::empty();
+            roots
+                .add(&rustls::Certificate(self.ca.cert_der().to_vec()))
+                .unwrap();
+            AllowAnyAuthenticatedClient::new(roots)
+                .verify_client_cert(&rustls::Certificate(cert.to_vec()), &[], now)
+                .is_ok()
+        }
+    }
+
+    impl Drop for TestCa {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// A certificate request naming itself `subject`
+    fn csr(subject: &str) -> String {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::default();
+        params.distinguished_name.push(DnType::CommonName, subject);
+        params.serialize_request(&key).unwrap().pem().unwrap()
+    }
+
+    fn der(pem: &str) -> Vec<u8> {
+        rustls_pemfile::certs(&mut pem.as_bytes())
+            .unwrap()
+            .remove(0)
+    }
+
+    #[test]
+    fn test_issued_certificates_verify() {
+        let test = TestCa::new();
+        // The subject the client asked for is ignored
+        let cert = der(&test.ca.issue(&csr("admin"), "laptop").unwrap());
+        assert!(test.verifies(&cert, SystemTime::now()));
+        assert_eq!(identity(&cert).as_deref(), Some("laptop"));
+
+        // A CA loaded again from its files still vouches for it
+        let reloaded = CertificateAuthority::load_or_create(&TestCa::config(&test.dir)).unwrap();
+        assert_eq!(reloaded.cert_der(), test.ca.cert_der());
+        let cert = der(&reloaded.issue(&csr("laptop"), "desktop").unwrap());
+        assert!(test.verifies(&cert, SystemTime::now()));
+        assert_eq!(identity(&cert).as_deref(), Some("desktop"));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(test.dir.join("ca.key"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_rejects_expired_certificates() {
+        let test = TestCa::new();
+        let cert = der(&test.ca.issue(&csr("laptop"), "laptop").unwrap());
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert!(test.verifies(&cert, SystemTime::now() + 29 * day));
+        assert!(!test.verifies(&cert, SystemTime::now() + 31 * day));
+        assert!(!test.verifies(&cert, SystemTime::now() - day));
+    }
+
+    #[test]
+    fn test_rejects_foreign_certificates() {
+        let test = TestCa::new();
+        let other = TestCa::new();
+        let foreign = der(&other.ca.issue(&csr("laptop"), "laptop").unwrap());
+        assert!(other.verifies(&foreign, SystemTime::now()));
+        assert!(!test.verifies(&foreign, SystemTime::now()));
+
+        // Nor does a certificate the client signed itself pass
+        let key = KeyPair::generate().unwrap();
+        let params = CertificateParams::new(vec!["laptop".to_string()]).unwrap();
+        let own = params.self_signed(&key).unwrap();
+        assert!(!test.verifies(own.der(), SystemTime::now()));
+    }
+
+    #[test]
+    fn test_rejects_invalid_requests() {
+        let test = TestCa::new();
+        assert!(test.ca.issue("not a request", "laptop").is_err());
+        let pem = csr("laptop").replace("REQUEST-----\n", "REQUEST-----\nAAAA");
+        assert!(test.ca.issue(&pem, "laptop").is_err());
+    }
+}
@@ -0,0 +1,289 @@
+//! Traffic capture for debugging: payloads forwarded through one tunnel,
+//! or one connection of it, written to a hex log or a pcap file. Started
+//! and stopped at runtime with `nat-server inspect capture`.
+
+use chrono::{DateTime, Utc};
+use nat_traversal_common::telemetry::Direction;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::info;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, clap::ValueEnum)]
+pub enum CaptureFormat {
+    /// Readable hex dump with a header line per payload
+    Hex,
+    /// Packets with synthesized IP and TCP headers, for Wireshark
+    Pcap,
+}
+
+/// pcap link type for packets starting with an IPv4 or IPv6 header
+const LINKTYPE_RAW: u32 = 101;
+
+/// Largest payload put into one synthesized packet
+const MAX_SEGMENT: usize = 60_000;
+
+/// A running capture of one tunnel
+pub struct Capture {
+    pub path: PathBuf,
+    /// Only this connection when set
+    pub connection_id: Option<u32>,
+    /// Public port of the tunnel, used as the server side of packets
+    port: u16,
+    format: CaptureFormat,
+    /// Stop once the file would grow past this
+    max_bytes: u64,
+    /// Record sizes only, never payload bytes
+    redact: bool,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// `None` once the size limit was reached
+    out: Option<BufWriter<File>>,
+    written: u64,
+    /// Next TCP sequence number per connection, inbound and outbound
+    sequence: HashMap<u32, [u32; 2]>,
+}
+
+impl Capture {
+    /// Create the capture file, replacing an existing one
+    pub fn create(
+        path: &Path,
+        connection_id: Option<u32>,
+        port: u16,
+        format: CaptureFormat,
+        max_bytes: u64,
+        redact: bool,
+    ) -> std::io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let mut written = 0;
+        if let CaptureFormat::Pcap = format {
+            let mut header = Vec::with_capacity(24);
+            header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+            header.extend_from_slice(&2u16.to_le_bytes());
+            header.extend_from_slice(&4u16.to_le_bytes());
+            header.extend_from_slice(&0i32.to_le_bytes());
+            header.extend_from_slice(&0u32.to_le_bytes());
+            header.extend_from_slice(&65535u32.to_le_bytes());
+            header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+            out.write_all(&header)?;
+            out.flush()?;
+            written = header.len() as u64;
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            connection_id,
+            port,
+            format,
+            max_bytes,
+            redact,
+            state: Mutex::new(State {
+                out: Some(out),
+                written,
+                sequence: HashMap::new(),
+            }),
+        })
+    }
+
+    /// Bytes written to the file so far
+    pub fn written(&self) -> u64 {
+        self.state.lock().unwrap().written
+    }
+
+    /// Record `data` forwarded on a connection of the tunnel. `port_offset`
+    /// is the tunnel port the connection arrived on.
+    pub fn record(
+        &self,
+        direction: Direction,
+        connection_id: u32,
+        peer: SocketAddr,
+        port_offset: u16,
+        data: &[u8],
+    ) {
+        if self.connection_id.is_some_and(|id| id != connection_id) {
+            return;
+        }
+        let server = SocketAddr::new(
+            unspecified(peer.ip()),
+            self.port.saturating_add(port_offset),
+        );
+
+        let mut state = self.state.lock().unwrap();
+        let record = match self.format {
+            CaptureFormat::Hex => self.hex_record(direction, connection_id, peer, data),
+            CaptureFormat::Pcap => {
+                let sequence = state.sequence.entry(connection_id).or_insert([1, 1]);
+                self.pcap_records(direction, sequence, peer, server, data)
+            }
+        };
+
+        let State { out, written, .. } = &mut *state;
+        let Some(file) = out else { return };
+        if *written + record.len() as u64 > self.max_bytes {
+            let _ = file.flush();
+            *out = None;
+            info!(
+                "Capture {} reached its limit of {} bytes and stopped",
+                self.path.display(),
+                self.max_bytes
+            );
+            return;
+        }
+        // Flushed per record so the file can be followed while it grows
+        match file.write_all(&record).and_then(|_| file.flush()) {
+            Ok(()) => *written += record.len() as u64,
+            Err(e) => {
+                info!("Capture {} stopped: {}", self.path.display(), e);
+                *out = None;
+            }
+        }
+    }
+
+    fn hex_record(
+        &self,
+        direction: Direction,
+        connection_id: u32,
+        peer: SocketAddr,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let arrow = match direction {
+            Direction::Inbound => "->",
+            Direction::Outbound => "<-",
+        };
+        let mut text = format!(
+            "{} #{} {} {} tunnel, {} bytes\n",
+            Utc::now().format("%Y-%m-%d %H:%M:%S%.6f"),
+            connection_id,
+            peer,
+            arrow,
+            data.len()
+        );
+        if self.redact {
+            text.push_str("  (redacted)\n");
+        } else {
+            for (line, chunk) in data.chunks(16).enumerate() {
+                let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+                let ascii: String = chunk
+                    .iter()
+                    .map(|&b| {
+                        if b.is_ascii_graphic() || b == b' ' {
+                            b as char
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect();
+                text.push_str(&format!(
+                    "  {:08x}  {:<47}  |{}|\n",
+                    line * 16,
+                    hex.join(" "),
+                    ascii
+                ));
+            }
+        }
+        text.push('\n');
+        text.into_bytes()
+    }
+
+    /// Packets carrying `data`, split to fit IP's length field. Checksums
+    /// are left zero; Wireshark does not check them by default.
+    fn pcap_records(
+        &self,
+        direction: Direction,
+        sequence: &mut [u32; 2],
+        peer: SocketAddr,
+        server: SocketAddr,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let (src, dst, sent, acked) = match direction {
+            Direction::Inbound => (peer, server, 0, 1),
+            Direction::Outbound => (server, peer, 1, 0),
+        };
+        let now: DateTime<Utc> = Utc::now();
+        let mut records = Vec::new();
+        for chunk in data.chunks(MAX_SEGMENT) {
+            let packet = tcp_packet(
+                src,
+                dst,
+                sequence[sent],
+                sequence[acked],
+                chunk,
+                self.redact,
+            );
+            sequence[sent] = sequence[sent].wrapping_add(chunk.len() as u32);
+
+            records.extend_from_slice(&(now.timestamp() as u32).to_le_bytes());
+            records.extend_from_slice(&now.timestamp_subsec_micros().to_le_bytes());
+            records.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            records.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            records.extend_from_slice(&packet);
+        }
+        records
+    }
+}
+
+fn unspecified(like: IpAddr) -> IpAddr {
+    match like {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    }
+}
+
+/// An IP packet with a TCP segment carrying `payload`, zeroed when redacted
+fn tcp_packet(
+    src: SocketAddr,
+    dst: SocketAddr,
+    sequence: u32,
+    ack: u32,
+    payload: &[u8],
+    redact: bool,
+) -> Vec<u8> {
+    let mut tcp = Vec::with_capacity(20 + payload.len());
+    tcp.extend_from_slice(&src.port().to_be_bytes());
+    tcp.extend_from_slice(&dst.port().to_be_bytes());
+    tcp.extend_from_slice(&sequence.to_be_bytes());
+    tcp.extend_from_slice(&ack.to_be_bytes());
+    // Header length 5 words, PSH and ACK
+    tcp.extend_from_slice(&[0x50, 0x18]);
+    tcp.extend_from_slice(&65535u16.to_be_bytes());
+    tcp.extend_from_slice(&[0, 0, 0, 0]);
+    if redact {
+        tcp.resize(20 + payload.len(), 0);
+    } else {
+        tcp.extend_from_slice(payload);
+    }
+
+    let mut packet = Vec::with_capacity(40 + tcp.len());
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&((20 + tcp.len()) as u16).to_be_bytes());
+            // ID, don't fragment, TTL 64, TCP, checksum
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+        }
+        (src, dst) => {
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+            // Next header TCP, hop limit 64
+            packet.extend_from_slice(&[6, 64]);
+            packet.extend_from_slice(&to_v6(src).octets());
+            packet.extend_from_slice(&to_v6(dst).octets());
+        }
+    }
+    packet.extend_from_slice(&tcp);
+    packet
+}
+
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
@@ -0,0 +1,525 @@
+//! `nat-server check-config`: load the configuration and everything it
+//! refers to the way starting the server would, without binding any port,
+//! so a change can be checked before restarting the service.
+
+use crate::ca::CertificateAuthority;
+use crate::config::{load_server_config, Args};
+use crate::plugin::Plugins;
+use crate::provision::Provisioning;
+use crate::reservation::Reservations;
+use crate::server::NatServer;
+use crate::token::JwtVerifier;
+use crate::tunnel::TUNNEL_PORTS;
+use crate::usage::UsageLedger;
+use crate::vhost::{CertStore, DomainVerifier, OfflinePage};
+use nat_traversal_common::config::{get_config_dir, PortRange, ServerConfig};
+use nat_traversal_common::protocol::MIN_MAX_FRAME_SIZE;
+use rcgen::CertificateParams;
+use rustls_pemfile::certs;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Certificates expiring sooner than this get a warning
+const EXPIRY_WARNING_DAYS: i64 = 30;
+
+/// Passed, warned and failed checks, printed as they run
+#[derive(Default)]
+struct Report {
+    passed: usize,
+    warned: usize,
+    failed: usize,
+}
+
+impl Report {
+    fn pass(&mut self, what: &str) {
+        self.passed += 1;
+        println!("  PASS  {}", what);
+    }
+
+    fn warn(&mut self, what: &str) {
+        self.warned += 1;
+        println!("  WARN  {}", what);
+    }
+
+    fn fail(&mut self, what: &str) {
+        self.failed += 1;
+        println!("  FAIL  {}", what);
+    }
+
+    fn check<T, E: std::fmt::Display>(&mut self, what: &str, result: Result<T, E>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                self.pass(what);
+                Some(value)
+            }
+            Err(e) => {
+                self.fail(&format!("{}: {}", what, e));
+                None
+            }
+        }
+    }
+}
+
+/// Validate the configuration and print a report; fails when any check does
+pub async fn run(args: &Args) -> anyhow::Result<()> {
+    let mut report = Report::default();
+    let source = match &args.config {
+        Some(path) => path.clone(),
+        None => get_config_dir()?.join("server.toml"),
+    };
+    println!("Configuration {}", source.display());
+    if !source.exists() {
+        report.warn("The file does not exist; checking the defaults");
+    }
+
+    if let Some(config) = report.check("Parse configuration", load_server_config(args)) {
+        check_network(&config, &mut report);
+        check_tls(&config, &mut report).await;
+        check_auth(&config, &mut report);
+        check_http(&config, &mut report);
+        check_plugins(&config, &mut report);
+        check_vpn(&config, &mut report);
+        check_wireguard(&config, &mut report);
+        check_grpc(&config, &mut report);
+        check_events(&config, &mut report);
+        check_frp(&config, &mut report);
+        check_files(&config, &mut report);
+    }
+
+    println!();
+    println!(
+        "{} passed, {} warnings, {} failed",
+        report.passed, report.warned, report.failed
+    );
+    if report.failed > 0 {
+        return Err(anyhow::anyhow!("The configuration has errors"));
+    }
+    Ok(())
+}
+
+fn check_network(config: &ServerConfig, report: &mut Report) {
+    let network = &config.network;
+    if network.port == 0 {
+        report.fail("network.port must not be 0");
+    } else {
+        report.pass(&format!("Listen on {}:{}", network.bind_addr, network.port));
+    }
+    if network.max_connections == 0 {
+        report.fail("network.max_connections must be at least 1");
+    }
+    // Peers split their data to no less than this, whatever we advertise
+    if config.performance.max_frame_size < MIN_MAX_FRAME_SIZE {
+        report.fail(&format!(
+            "performance.max_frame_size {} is below the minimum of {} bytes",
+            config.performance.max_frame_size, MIN_MAX_FRAME_SIZE
+        ));
+    }
+
+    let (first, last) = TUNNEL_PORTS;
+    let mut ports = vec![("network.port", network.port)];
+    if config.http.enabled {
+        match config.http.bind_addr.parse::<IpAddr>() {
+            Ok(addr) => report.pass(&format!("Listen for HTTP on {}:{}", addr, config.http.port)),
+            Err(e) => report.fail(&format!(
+                "http.bind_addr '{}': {}",
+                config.http.bind_addr, e
+            )),
+        }
+        ports.push(("http.port", config.http.port));
+        if let Some(port) = config.http.https_port {
+            ports.push(("http.https_port", port));
+        }
+    }
+    for (i, (name, port)) in ports.iter().enumerate() {
+        if (first..=last).contains(port) {
+            report.fail(&format!(
+                "{} {} is inside the tunnel port range {}-{}",
+                name, port, first, last
+            ));
+        }
+        if let Some((other, _)) = ports[..i].iter().find(|(_, p)| p == port) {
+            report.fail(&format!("{} and {} are both {}", other, name, port));
+        }
+    }
+
+    if config.limits.max_ports_per_tunnel == 0 {
+        report.fail("limits.max_ports_per_tunnel must be at least 1");
+    }
+    if config.limits.max_ports_per_tunnel > last - first + 1 {
+        report.warn(&format!(
+            "limits.max_ports_per_tunnel {} exceeds the {} ports of the tunnel range",
+            config.limits.max_ports_per_tunnel,
+            last - first + 1
+        ));
+    }
+    match Reservations::new(&config.reservations) {
+        Ok(_) => {
+            for (client_id, reservation) in &config.reservations {
+                warn_outside_range(
+                    report,
+                    &format!("Reservation of {}", client_id),
+                    &reservation.ports,
+                );
+            }
+        }
+        Err(e) => report.fail(&format!("reservations: {}", e)),
+    }
+    if let Err(e) = Provisioning::new(&config.provisioned) {
+        report.fail(&format!("provisioned: {}", e));
+    }
+}
+
+async fn check_tls(config: &ServerConfig, report: &mut Report) {
+    let ca = match &config.ca {
+        Some(ca) if !ca.cert_path.exists() && !ca.key_path.exists() => {
+            report.warn(&format!(
+                "CA {} does not exist yet and will be created on start",
+                ca.cert_path.display()
+            ));
+            None
+        }
+        Some(ca) => {
+            match report.check(
+                &format!("Load CA {}", ca.cert_path.display()),
+                CertificateAuthority::load_or_create(ca),
+            ) {
+                Some(ca) => Some(ca),
+                None => return,
+            }
+        }
+        None => None,
+    };
+
+    let loaded = report.check(
+        &format!(
+            "Load TLS certificate {} and key {}",
+            config.tls.cert_path.display(),
+            config.tls.key_path.display()
+        ),
+        NatServer::setup_tls(config, ca.as_ref()).await,
+    );
+    if loaded.is_some() {
+        check_expiry(report, &config.tls.cert_path);
+    }
+}
+
+/// Warn about a certificate that expired or is about to
+fn check_expiry(report: &mut Report, path: &Path) {
+    let Some(params) = std::fs::read(path)
+        .ok()
+        .and_then(|pem| certs(&mut pem.as_slice()).ok())
+        .and_then(|certs| certs.into_iter().next())
+        .and_then(|der| CertificateParams::from_ca_cert_der(&der.into()).ok())
+    else {
+        return;
+    };
+    let left = params.not_after - time::OffsetDateTime::now_utc();
+    if left.is_negative() {
+        report.fail(&format!(
+            "Certificate {} expired on {}",
+            path.display(),
+            params.not_after.date()
+        ));
+    } else if left.whole_days() < EXPIRY_WARNING_DAYS {
+        report.warn(&format!(
+            "Certificate {} expires in {} days",
+            path.display(),
+            left.whole_days()
+        ));
+    } else {
+        report.pass(&format!(
+            "Certificate valid until {}",
+            params.not_after.date()
+        ));
+    }
+}
+
+fn check_auth(config: &ServerConfig, report: &mut Report) {
+    let auth = &config.auth;
+    let Some(tokens) = report.check("Load tokens", auth.load_tokens()) else {
+        return;
+    };
+    if let Some(jwt) = &auth.jwt {
+        report.check("Load auth.jwt keys", JwtVerifier::new(jwt));
+    }
+    let bootstrap = config
+        .ca
+        .as_ref()
+        .is_some_and(|ca| !ca.bootstrap_tokens.is_empty());
+    if tokens.is_empty() && auth.jwt.is_none() && !bootstrap && !config.tls.verify_client {
+        report.fail("No tokens, auth.jwt or client certificates; no client can authenticate");
+    } else {
+        report.pass(&format!("{} token(s) configured", tokens.len()));
+    }
+    if auth.allow_plain_tokens {
+        report.warn("auth.allow_plain_tokens accepts static tokens sent in the clear");
+    }
+    for (client_id, scope) in &auth.scopes {
+        warn_outside_range(report, &format!("Scope of {}", client_id), &scope.ports);
+    }
+    let observers = &config.control.observer_tokens;
+    if observers.iter().any(|token| tokens.contains(token)) {
+        report.warn("An observer token is also a client token");
+    }
+    if !observers.is_empty() && !config.control.enabled {
+        report.warn("control.observer_tokens has no effect with the control socket disabled");
+    }
+}
+
+fn check_http(config: &ServerConfig, report: &mut Report) {
+    if !config.http.enabled {
+        return;
+    }
+    report.check(
+        "Set up domain verification",
+        DomainVerifier::new(&config.http),
+    );
+    if config.http.https_port.is_some() {
+        report.check(
+            &format!(
+                "Load {} HTTPS certificate(s)",
+                config.http.certificates.len()
+            ),
+            CertStore::new(&config.http.certificates),
+        );
+    }
+    if config.http.offline_page.is_some() {
+        report.check("Load offline page", OfflinePage::new(&config.http));
+    }
+}
+
+fn check_plugins(config: &ServerConfig, report: &mut Report) {
+    if !config.plugins.is_empty() {
+        report.check(
+            &format!("Load {} plugin(s)", config.plugins.len()),
+            Plugins::load(&config.plugins),
+        );
+    }
+}
+
+fn check_vpn(config: &ServerConfig, report: &mut Report) {
+    let vpn = &config.vpn;
+    if !vpn.enabled {
+        return;
+    }
+    if cfg!(not(target_os = "linux")) {
+        report.fail("vpn.enabled: the VPN mode is only supported on Linux");
+    }
+    if vpn.subnet.prefix > 30 {
+        report.fail(&format!(
+            "vpn.subnet {} leaves no addresses for clients",
+            vpn.subnet
+        ));
+    }
+    if vpn.mtu < 576 {
+        report.fail("vpn.mtu must be at least 576");
+    }
+    for (client_id, subnets) in &vpn.advertise {
+        for subnet in subnets.iter().filter(|subnet| subnet.overlaps(&vpn.subnet)) {
+            report.fail(&format!(
+                "vpn.advertise.{}: {} overlaps vpn.subnet {}",
+                client_id, subnet, vpn.subnet
+            ));
+        }
+    }
+}
+
+fn check_wireguard(config: &ServerConfig, report: &mut Report) {
+    let wireguard = &config.wireguard;
+    if !wireguard.enabled {
+        return;
+    }
+    if wireguard.subnet.prefix > 30 {
+        report.fail(&format!(
+            "wireguard.subnet {} leaves no addresses for clients",
+            wireguard.subnet
+        ));
+    }
+    if config.vpn.enabled && wireguard.subnet.overlaps(&config.vpn.subnet) {
+        report.fail("wireguard.subnet and vpn.subnet overlap");
+    }
+    let relay_ports = wireguard.relay_ports;
+    let (first, last) = TUNNEL_PORTS;
+    if relay_ports.start <= last && first <= relay_ports.end {
+        report.fail(&format!(
+            "wireguard.relay_ports {} overlaps the tunnel port range {}-{}",
+            relay_ports, first, last
+        ));
+    }
+    if relay_ports.contains(config.network.port) {
+        report.fail(&format!(
+            "wireguard.relay_ports {} includes network.port",
+            relay_ports
+        ));
+    }
+}
+
+fn check_grpc(config: &ServerConfig, report: &mut Report) {
+    let grpc = &config.grpc;
+    if !grpc.enabled {
+        return;
+    }
+    if grpc.tokens.is_empty() && grpc.observer_tokens.is_empty() {
+        report.fail("grpc is enabled but grpc.tokens and grpc.observer_tokens are empty");
+    } else {
+        report.pass(&format!("Serve the gRPC API on {}", grpc.listen));
+    }
+    if !grpc.listen.ip().is_loopback() {
+        report.warn(&format!(
+            "grpc.listen {} is not a loopback address; the API is not encrypted",
+            grpc.listen
+        ));
+    }
+    let (first, last) = TUNNEL_PORTS;
+    if (first..=last).contains(&grpc.listen.port()) {
+        report.fail(&format!(
+            "grpc.listen port {} is inside the tunnel port range {}-{}",
+            grpc.listen.port(),
+            first,
+            last
+        ));
+    }
+    if grpc.listen.port() == config.network.port {
+        report.fail(&format!(
+            "grpc.listen uses network.port {}",
+            config.network.port
+        ));
+    }
+}
+
+fn check_events(config: &ServerConfig, report: &mut Report) {
+    let events = &config.events;
+    if !events.enabled {
+        return;
+    }
+    if events.tokens.is_empty() {
+        report.fail("events is enabled but events.tokens is empty");
+    } else {
+        report.pass(&format!("Stream events on ws://{}/events", events.listen));
+    }
+    if !events.listen.ip().is_loopback() {
+        report.warn(&format!(
+            "events.listen {} is not a loopback address; the stream is not encrypted",
+            events.listen
+        ));
+    }
+    let (first, last) = TUNNEL_PORTS;
+    if (first..=last).contains(&events.listen.port()) {
+        report.fail(&format!(
+            "events.listen port {} is inside the tunnel port range {}-{}",
+            events.listen.port(),
+            first,
+            last
+        ));
+    }
+    if events.listen.port() == config.network.port {
+        report.fail(&format!(
+            "events.listen uses network.port {}",
+            config.network.port
+        ));
+    }
+    if config.grpc.enabled && events.listen == config.grpc.listen {
+        report.fail(&format!(
+            "events.listen {} is also grpc.listen",
+            events.listen
+        ));
+    }
+}
+
+fn check_frp(config: &ServerConfig, report: &mut Report) {
+    let frp = &config.frp;
+    if !frp.enabled {
+        return;
+    }
+    if frp.token.is_empty() {
+        report.fail("frp is enabled but frp.token is empty; any frpc could log in");
+    } else if !config
+        .auth
+        .load_tokens()
+        .is_ok_and(|tokens| tokens.contains(&frp.token))
+    {
+        report.fail("frp.token is not one of the server's tokens; no frpc can log in");
+    } else {
+        report.pass(&format!(
+            "Accept frpc on {}:{} (tcpMux {})",
+            frp.bind_addr,
+            frp.port,
+            if frp.tcp_mux { "on" } else { "off" }
+        ));
+    }
+    if !frp.require_additional_scopes {
+        report.warn(
+            "frp.require_additional_scopes is off; heartbeats and work connections go unauthenticated",
+        );
+    }
+    let (first, last) = TUNNEL_PORTS;
+    if (first..=last).contains(&frp.port) {
+        report.fail(&format!(
+            "frp.port {} is inside the tunnel port range {}-{}",
+            frp.port, first, last
+        ));
+    }
+    if frp.port == config.network.port {
+        report.fail(&format!("frp.port uses network.port {}", frp.port));
+    }
+    if config.grpc.enabled && frp.port == config.grpc.listen.port() {
+        report.fail(&format!("frp.port {} is also grpc.listen's", frp.port));
+    }
+}
+
+/// Files the server writes need a directory to go in
+fn check_files(config: &ServerConfig, report: &mut Report) {
+    if let Some(path) = &config.storage.database {
+        check_parent(report, "storage.database", path);
+    }
+    if let Some(path) = &config.usage.file {
+        check_parent(report, "usage.file", path);
+        report.check(
+            &format!("Read usage from {}", path.display()),
+            UsageLedger::open(Some(path.clone())),
+        );
+    }
+    if let Some(path) = &config.logging.file {
+        check_parent(report, "logging.file", path);
+    }
+    if let Some(path) = config
+        .control
+        .path
+        .as_ref()
+        .filter(|_| config.control.enabled)
+    {
+        check_parent(report, "control.path", path);
+    }
+    if let Err(e) = tracing_subscriber::EnvFilter::try_new(&config.logging.level) {
+        report.fail(&format!("logging.level '{}': {}", config.logging.level, e));
+    }
+}
+
+fn check_parent(report: &mut Report, name: &str, path: &Path) {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if parent.is_dir() {
+        report.pass(&format!("{} {}", name, path.display()));
+    } else {
+        report.fail(&format!(
+            "{} {}: directory {} does not exist",
+            name,
+            path.display(),
+            parent.display()
+        ));
+    }
+}
+
+fn warn_outside_range(report: &mut Report, what: &str, ports: &[PortRange]) {
+    let (first, last) = TUNNEL_PORTS;
+    for range in ports {
+        if range.start < first || range.end > last {
+            report.warn(&format!(
+                "{} allows ports {}, outside the tunnel port range {}-{}",
+                what, range, first, last
+            ));
+        }
+    }
+}
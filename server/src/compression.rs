@@ -0,0 +1,477 @@
+//! Compression of HTTP responses at the edge, for visitors that accept it
+//! and services that did not compress themselves.
+//!
+//! Compression is decided once per connection, from its first request, so
+//! a request that asks for it is forwarded with `Connection: close` and its
+//! visitor stream wrapped in `Compressing`. That writer holds back the
+//! response head, decides from it whether to compress, and then encodes
+//! the body on its way to the visitor, ending it when the service closes.
+
+use bytes::{Buf, BytesMut};
+use flate2::write::GzEncoder;
+use std::io::Write;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Largest response head held back before giving up on compressing
+const MAX_HEAD: usize = 16 * 1024;
+
+/// Bodies announced shorter than this are not worth compressing
+const MIN_LENGTH: u64 = 1024;
+
+/// Content types worth compressing; event streams are left alone as
+/// their events would sit in the encoder
+const COMPRESSIBLE: [&str; 6] = [
+    "text/",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "image/svg+xml",
+    "+json",
+];
+
+/// Brotli quality and window, traded for speed on the relay
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+        }
+    }
+}
+
+/// The encoding to answer a request with, from its `Accept-Encoding`
+/// header; brotli when the visitor takes both. HEAD requests get none,
+/// having no body to compress.
+pub fn negotiate(request: &httparse::Request) -> Option<Encoding> {
+    if request.method == Some("HEAD") {
+        return None;
+    }
+    let accepted = request
+        .headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("accept-encoding"))
+        .filter_map(|header| std::str::from_utf8(header.value).ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let coding = parts.next()?.trim().to_ascii_lowercase();
+            let refused = parts.any(|parameter| {
+                parameter
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .is_some_and(|q| q <= 0.0)
+            });
+            (!refused).then_some(coding)
+        })
+        .collect::<Vec<_>>();
+    if accepted.iter().any(|coding| coding == "br") {
+        Some(Encoding::Brotli)
+    } else if accepted.iter().any(|coding| coding == "gzip") {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Gzip => Self::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::fast())),
+            Encoding::Brotli => Self::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                4096,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+        }
+    }
+
+    /// Encode `data`, returning what the encoder has produced so far
+    fn encode(&mut self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+            Self::Brotli(encoder) => {
+                encoder.write_all(data)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+        }
+    }
+
+    /// End the stream, returning the rest of it
+    fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip(encoder) => encoder.finish(),
+            Self::Brotli(encoder) => Ok(encoder.into_inner()),
+        }
+    }
+}
+
+enum State {
+    /// Collecting the response head
+    Head(BytesMut),
+    /// Passing the response on as it is
+    Pass,
+    /// Encoding the body
+    Encode(Encoder),
+    /// The compressed body has ended
+    Done,
+}
+
+/// A visitor stream whose response is compressed with `encoding` when it
+/// is worth it. Reads pass straight through.
+pub struct Compressing<S> {
+    inner: S,
+    encoding: Encoding,
+    state: State,
+    /// Bytes accepted but not yet written to `inner`
+    pending: BytesMut,
+}
+
+impl<S> Compressing<S> {
+    pub fn new(inner: S, encoding: Encoding) -> Self {
+        Self {
+            inner,
+            encoding,
+            state: State::Head(BytesMut::new()),
+            pending: BytesMut::new(),
+        }
+    }
+
+    /// Decide on the collected response head, if it is complete
+    fn decide(&mut self, head: &[u8]) -> std::io::Result<()> {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut response = httparse::Response::new(&mut headers);
+        let head_len = match response.parse(head) {
+            Ok(httparse::Status::Complete(len)) => len,
+            Ok(httparse::Status::Partial) if head.len() < MAX_HEAD => return Ok(()),
+            _ => {
+                self.pending.extend_from_slice(head);
+                self.state = State::Pass;
+                return Ok(());
+            }
+        };
+        if !compressible(&response) {
+            self.pending.extend_from_slice(head);
+            self.state = State::Pass;
+            return Ok(());
+        }
+
+        let status_line_end = head
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .unwrap_or(0);
+        self.pending.extend_from_slice(&head[..status_line_end + 2]);
+        let mut vary = Vec::new();
+        for header in response.headers.iter() {
+            let name = header.name.to_ascii_lowercase();
+            if name == "vary" {
+                vary.push(String::from_utf8_lossy(header.value).into_owned());
+                continue;
+            }
+            if ["content-length", "connection", "keep-alive"].contains(&name.as_str()) {
+                continue;
+            }
+            self.pending.extend_from_slice(header.name.as_bytes());
+            self.pending.extend_from_slice(b": ");
+            self.pending.extend_from_slice(header.value);
+            self.pending.extend_from_slice(b"\r\n");
+        }
+        vary.push("Accept-Encoding".to_string());
+        self.pending.extend_from_slice(
+            format!(
+                "Content-Encoding: {}\r\nVary: {}\r\nConnection: close\r\n\r\n",
+                self.encoding.name(),
+                vary.join(", ")
+            )
+            .as_bytes(),
+        );
+
+        let mut encoder = Encoder::new(self.encoding);
+        let encoded = encoder.encode(&head[head_len..])?;
+        self.pending.extend_from_slice(&encoded);
+        self.state = State::Encode(encoder);
+        Ok(())
+    }
+}
+
+/// Whether a response is worth compressing: a successful answer with a
+/// textual body of some size that the service did not encode itself and
+/// that ends with the connection rather than in chunks
+fn compressible(response: &httparse::Response) -> bool {
+    if response.code != Some(200) {
+        return false;
+    }
+    let header = |name: &str| {
+        response
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| String::from_utf8_lossy(header.value).to_ascii_lowercase())
+    };
+    if header("content-encoding").is_some() || header("transfer-encoding").is_some() {
+        return false;
+    }
+    if header("content-length")
+        .and_then(|length| length.trim().parse::<u64>().ok())
+        .is_some_and(|length| length < MIN_LENGTH)
+    {
+        return false;
+    }
+    header("content-type").is_some_and(|content_type| {
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        media_type != "text/event-stream"
+            && COMPRESSIBLE
+                .iter()
+                .any(|compressible| media_type.contains(compressible))
+    })
+}
+
+impl<S: AsyncWrite + Unpin> Compressing<S> {
+    /// Write out everything accepted so far
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while !self.pending.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if written == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Compressing<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Compressing<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_pending(cx))?;
+        match &mut this.state {
+            State::Head(head) => {
+                let mut head = std::mem::take(head);
+                head.extend_from_slice(buf);
+                this.decide(&head)?;
+                if matches!(this.state, State::Head(_)) {
+                    this.state = State::Head(head);
+                }
+                Poll::Ready(Ok(buf.len()))
+            }
+            State::Pass => Pin::new(&mut this.inner).poll_write(cx, buf),
+            State::Encode(encoder) => {
+                let encoded = encoder.encode(buf)?;
+                this.pending.extend_from_slice(&encoded);
+                Poll::Ready(Ok(buf.len()))
+            }
+            State::Done => Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        match std::mem::replace(&mut this.state, State::Done) {
+            // A response cut short goes out as far as it came
+            State::Head(head) => {
+                this.pending.extend_from_slice(&head);
+                this.state = State::Pass;
+            }
+            State::Encode(encoder) => {
+                let rest = encoder.finish()?;
+                this.pending.extend_from_slice(&rest);
+            }
+            state => this.state = state,
+        }
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const BODY: &str = "<p>Hello, compressed world!</p>\n";
+
+    fn response(headers: &str, body: &[u8]) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 200 OK\r\n{}\r\n", headers).into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    /// What the visitor reads when the service writes `response` in
+    /// pieces of `piece` bytes and closes
+    async fn relay(encoding: Encoding, response: &[u8], piece: usize) -> Vec<u8> {
+        let (mut visitor, service) = tokio::io::duplex(1024 * 1024);
+        let mut stream = Compressing::new(service, encoding);
+        for chunk in response.chunks(piece) {
+            stream.write_all(chunk).await.unwrap();
+        }
+        stream.shutdown().await.unwrap();
+        drop(stream);
+        let mut read = Vec::new();
+        visitor.read_to_end(&mut read).await.unwrap();
+        read
+    }
+
+    fn split(response: &[u8]) -> (String, &[u8]) {
+        let end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap()
+            + 4;
+        (
+            String::from_utf8(response[..end].to_vec()).unwrap(),
+            &response[end..],
+        )
+    }
+
+    fn request(head: &str) -> Option<Encoding> {
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let mut request = httparse::Request::new(&mut headers);
+        request.parse(head.as_bytes()).unwrap();
+        negotiate(&request)
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let get = |encodings: &str| {
+            request(&format!(
+                "GET / HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n",
+                encodings
+            ))
+        };
+        assert_eq!(get("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(get("GZIP"), Some(Encoding::Gzip));
+        assert_eq!(get("br;q=0, gzip;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(get("br; q=0.0, gzip;q=0"), None);
+        assert_eq!(get("identity"), None);
+        assert_eq!(get(",;,"), None);
+        assert_eq!(request("GET / HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(
+            request("HEAD / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let body = BODY.repeat(200);
+        let original = response(
+            &format!(
+                "Content-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nVary: Cookie\r\nConnection: keep-alive\r\n",
+                body.len()
+            ),
+            body.as_bytes(),
+        );
+        for encoding in [Encoding::Gzip, Encoding::Brotli] {
+            for piece in [7, 4096] {
+                let read = relay(encoding, &original, piece).await;
+                let (head, compressed) = split(&read);
+                assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+                assert!(head.contains(&format!("Content-Encoding: {}\r\n", encoding.name())));
+                assert!(head.contains("Vary: Cookie, Accept-Encoding\r\n"));
+                assert!(head.contains("Connection: close\r\n"));
+                assert!(!head.contains("Content-Length"));
+                assert!(!head.contains("keep-alive"));
+                assert!(compressed.len() < body.len());
+
+                let mut decoded = Vec::new();
+                match encoding {
+                    Encoding::Gzip => flate2::read::GzDecoder::new(compressed)
+                        .read_to_end(&mut decoded)
+                        .unwrap(),
+                    Encoding::Brotli => brotli::Decompressor::new(compressed, 4096)
+                        .read_to_end(&mut decoded)
+                        .unwrap(),
+                };
+                assert_eq!(decoded, body.as_bytes());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_passes_incompressible_responses() {
+        let body = BODY.repeat(200);
+        let passed = [
+            // Too short to bother
+            response(
+                "Content-Type: text/plain\r\nContent-Length: 5\r\n",
+                b"short",
+            ),
+            // Already encoded, chunked, an event stream or not text
+            response(
+                "Content-Type: text/html\r\nContent-Encoding: gzip\r\n",
+                body.as_bytes(),
+            ),
+            response(
+                "Content-Type: text/html\r\nTransfer-Encoding: chunked\r\n",
+                b"5\r\nhello\r\n0\r\n\r\n",
+            ),
+            response("Content-Type: text/event-stream\r\n", body.as_bytes()),
+            response("Content-Type: image/png\r\n", body.as_bytes()),
+            response("", body.as_bytes()),
+            format!(
+                "HTTP/1.1 404 Not Found\r\nContent-Type: text/html\r\n\r\n{}",
+                body
+            )
+            .into_bytes(),
+        ];
+        for original in passed {
+            assert_eq!(relay(Encoding::Gzip, &original, 100).await, original);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_passes_malformed_responses() {
+        let garbage = b"NOT HTTP AT ALL\r\n\r\nbody".to_vec();
+        assert_eq!(relay(Encoding::Brotli, &garbage, 3).await, garbage);
+        // A head that never ends is given up on once it is too long
+        let endless = [
+            b"HTTP/1.1 200 OK\r\nX-Filler: ".as_slice(),
+            &[b'a'; MAX_HEAD],
+        ]
+        .concat();
+        assert_eq!(relay(Encoding::Gzip, &endless, 1000).await, endless);
+        // A response cut short in its head goes out as far as it came
+        let cut = b"HTTP/1.1 200 OK\r\nContent-Type: text/ht".to_vec();
+        assert_eq!(relay(Encoding::Gzip, &cut, 5).await, cut);
+    }
+}
@@ -1,7 +1,18 @@
-use clap::Parser;
-use nat_traversal_common::config::{load_config, save_config, ServerConfig};
+use crate::capture::CaptureFormat;
+use crate::report::{parse_time, ReportBy, ReportFormat};
+use crate::storage::HistoryKind;
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use nat_traversal_common::{
+    config::{apply_overrides, get_config_dir, load_config, save_config, PortRange, ServerConfig},
+    logging,
+    protocol::TunnelProtocol,
+    telemetry::{self, Telemetry},
+};
+use nat_traversal_platform::service::ServiceAction;
 use std::path::PathBuf;
-use tracing::{error, info};
+use tracing::info;
+use uuid::Uuid;
 
 #[derive(Parser, Debug)]
 #[command(name = "nat-server")]
@@ -19,13 +30,349 @@ pub struct Args {
     #[arg(short, long)]
     pub port: Option<u16>,
 
+    /// Maximum number of concurrent client connections
+    #[arg(long)]
+    pub max_connections: Option<u32>,
+
+    /// Switch to this user after binding the listener (Unix)
+    #[arg(long)]
+    pub run_as_user: Option<String>,
+
+    /// Switch to this group after binding the listener (Unix)
+    #[arg(long)]
+    pub run_as_group: Option<String>,
+
+    /// TLS certificate path
+    #[arg(long)]
+    pub cert: Option<PathBuf>,
+
+    /// TLS private key path
+    #[arg(long)]
+    pub key: Option<PathBuf>,
+
+    /// TLS CA certificate path
+    #[arg(long)]
+    pub ca: Option<PathBuf>,
+
+    /// Authentication token (repeat for several); replaces configured tokens
+    #[arg(long = "token", value_name = "TOKEN")]
+    pub tokens: Vec<String>,
+
+    /// File with additional authentication tokens, one per line
+    #[arg(long)]
+    pub tokens_file: Option<PathBuf>,
+
+    /// Accept clients without authentication
+    #[arg(long)]
+    pub no_auth: bool,
+
+    /// Maximum clients sharing one token
+    #[arg(long)]
+    pub max_clients_per_token: Option<u32>,
+
+    /// Maximum tunnels per client
+    #[arg(long)]
+    pub max_tunnels_per_client: Option<u32>,
+
+    /// Maximum bandwidth in Mbps
+    #[arg(long)]
+    pub max_bandwidth_mbps: Option<u32>,
+
+    /// Maximum connections per tunnel
+    #[arg(long)]
+    pub max_connections_per_tunnel: Option<u32>,
+
+    /// Connection timeout in seconds
+    #[arg(long)]
+    pub connection_timeout: Option<u64>,
+
+    /// Log level (trace, debug, info, warn, error)
+    #[arg(long)]
+    pub log_level: Option<String>,
+
+    /// Log file path
+    #[arg(long)]
+    pub log_file: Option<PathBuf>,
+
+    /// Override any configuration value, e.g. --set limits.max_tunnels_per_client=20
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>,
+
+    /// Keep configuration next to the executable instead of the user config directory
+    #[arg(long)]
+    pub portable: bool,
+
     /// Generate default configuration file
     #[arg(long)]
     pub generate_config: bool,
 
+    /// Start the server, check a tunnel through it end to end with an
+    /// in-process client, and exit
+    #[arg(long)]
+    pub self_test: bool,
+
     /// Verbose logging
     #[arg(short, long)]
     pub verbose: bool,
+
+    /// Run under the Windows service control manager (used by `service install`)
+    #[arg(long, hide = true)]
+    pub service: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Manage the nat-server system service
+    Service {
+        /// Service name
+        #[arg(long, default_value = "nat-server")]
+        name: String,
+
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+
+    /// Inspect and manage the running server through its control socket
+    Inspect {
+        /// Print JSON for scripts
+        #[arg(long, global = true)]
+        json: bool,
+
+        /// Go through the observer socket with this token, which allows
+        /// viewing only
+        #[arg(long, global = true)]
+        observer_token: Option<String>,
+
+        #[command(subcommand)]
+        action: InspectAction,
+    },
+
+    /// Export traffic per client or tunnel from the history database
+    /// (needs storage.database)
+    Report {
+        /// Start of the period, as YYYY-MM-DD or RFC 3339 (default: start of this month)
+        #[arg(long, value_parser = parse_time)]
+        from: Option<DateTime<Utc>>,
+
+        /// End of the period, exclusive (default: now)
+        #[arg(long, value_parser = parse_time)]
+        to: Option<DateTime<Utc>>,
+
+        /// One row per client or per tunnel
+        #[arg(long, value_enum, default_value = "client")]
+        by: ReportBy,
+
+        #[arg(long, value_enum, default_value = "csv")]
+        format: ReportFormat,
+    },
+
+    /// Sign an expiring HS256 token with the configured auth.jwt secret
+    IssueToken {
+        /// Client ID the token is bound to
+        #[arg(long)]
+        client_id: String,
+
+        /// Hours until the token expires
+        #[arg(long, default_value_t = 720)]
+        ttl_hours: u64,
+
+        /// Tunnel protocol the client may use (repeat for several; default all)
+        #[arg(long = "protocol", value_name = "tcp|udp", value_parser = parse_protocol)]
+        protocols: Vec<TunnelProtocol>,
+
+        /// Public port or range the client may use, e.g. 8000-8099 (repeat for several)
+        #[arg(long = "port", value_name = "PORTS")]
+        ports: Vec<PortRange>,
+
+        /// Maximum tunnels the client may open
+        #[arg(long)]
+        max_tunnels: Option<u32>,
+
+        /// Maximum bandwidth in Mbps across the client's tunnels
+        #[arg(long)]
+        max_bandwidth_mbps: Option<u32>,
+
+        /// Megabytes the client may transfer per calendar month
+        #[arg(long)]
+        monthly_quota_mb: Option<u64>,
+
+        /// Throttle to this many Mbps once the quota is used up, instead of
+        /// refusing new connections
+        #[arg(long, requires = "monthly_quota_mb")]
+        over_quota_mbps: Option<u32>,
+    },
+
+    /// Validate the configuration and the files it refers to without
+    /// starting the server
+    CheckConfig,
+
+    /// Live view of clients, tunnels, connection rate and bandwidth of the
+    /// running server
+    Top {
+        /// Seconds between refreshes
+        #[arg(long, default_value_t = 2)]
+        interval: u64,
+
+        /// Most tunnels to list, busiest first
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+
+        /// Go through the observer socket with this token
+        #[arg(long)]
+        observer_token: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum InspectAction {
+    /// List connected clients
+    Clients,
+    /// List tunnels
+    Tunnels {
+        /// Only tunnels of this client
+        #[arg(long)]
+        client: Option<String>,
+    },
+    /// List open public connections
+    Connections {
+        /// Only connections through this tunnel
+        #[arg(long)]
+        tunnel: Option<Uuid>,
+    },
+    /// Close a tunnel and tell its client
+    CloseTunnel {
+        /// Tunnel ID
+        tunnel_id: Uuid,
+    },
+    /// List temporary shares of tunnels
+    Shares,
+    /// Close a share before it expires and tell its client
+    CloseShare {
+        /// Share ID
+        share_id: Uuid,
+    },
+    /// Close a client's tunnels and disconnect it
+    Kick {
+        /// Client ID
+        client_id: String,
+    },
+    /// Push a freshly signed token to a connected client
+    RotateToken {
+        /// Client ID
+        client_id: String,
+    },
+    /// List recorded clients, sessions or tunnels, including past ones
+    /// (needs storage.database)
+    History {
+        #[arg(value_enum, default_value = "sessions")]
+        kind: HistoryKind,
+        /// Only records of this client
+        #[arg(long)]
+        client: Option<String>,
+        /// Most records to list
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// Show traffic per client this month and last
+    Usage,
+    /// Write payloads forwarded through a tunnel to a file until stopped
+    Capture {
+        /// Tunnel ID
+        tunnel_id: Uuid,
+        /// File to write, relative to the current directory
+        path: PathBuf,
+        /// Only this connection of the tunnel
+        #[arg(long)]
+        connection: Option<u32>,
+        #[arg(long, value_enum, default_value = "hex")]
+        format: CaptureFormat,
+        /// Stop once the file reaches this many MiB
+        #[arg(long, default_value_t = 10)]
+        max_mb: u64,
+        /// Record payload sizes and timing but not their bytes
+        #[arg(long)]
+        redact: bool,
+    },
+    /// Stop capturing a tunnel
+    CaptureStop {
+        /// Tunnel ID
+        tunnel_id: Uuid,
+    },
+    /// List tunnels clients are told to open when they log in
+    Provisioned {
+        /// Only tunnels of this client
+        #[arg(long)]
+        client: Option<String>,
+    },
+    /// Have a client open a tunnel now and at every login, until the server
+    /// restarts (add it to `provisioned` in the configuration to keep it)
+    Provision {
+        /// Client ID
+        client_id: String,
+        /// Tunnel name; replaces the client's provisioned tunnel of that name
+        name: String,
+        /// Port of the service on the client's side
+        local_port: u16,
+        /// Host on the client's network that receives the traffic
+        #[arg(long, default_value = "127.0.0.1")]
+        local_host: String,
+        /// Public port; any free one when unset
+        #[arg(long)]
+        remote_port: Option<u16>,
+        #[arg(long, value_name = "tcp|udp", value_parser = parse_protocol, default_value = "tcp")]
+        protocol: TunnelProtocol,
+    },
+    /// Stop provisioning a tunnel and have the client close it
+    Unprovision {
+        /// Client ID
+        client_id: String,
+        /// Tunnel name
+        name: String,
+    },
+    /// Print a connected client's recent log lines
+    ClientLogs {
+        /// Client ID
+        client_id: String,
+        /// First have the client log at this level, e.g. `debug` or
+        /// `nat_client=trace`, so what it logs from now on can be fetched
+        #[arg(long)]
+        level: Option<String>,
+        /// Seconds until the client goes back to its configured level
+        #[arg(long, default_value_t = 600, requires = "level")]
+        secs: u64,
+        /// Most lines to fetch
+        #[arg(long, default_value_t = 200)]
+        lines: u32,
+    },
+    /// Show tunnels, public connections and ports in use against the
+    /// server-wide limits
+    Capacity,
+    /// Show maintenance mode, or enter or leave it: no new clients or
+    /// tunnels are accepted while the open ones keep running
+    Maintenance {
+        #[arg(value_enum)]
+        mode: Option<MaintenanceMode>,
+        /// Disconnect the clients still connected after this many seconds
+        #[arg(long, requires = "mode")]
+        drain_secs: Option<u64>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum MaintenanceMode {
+    On,
+    Off,
+}
+
+fn parse_protocol(value: &str) -> Result<TunnelProtocol, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "tcp" => Ok(TunnelProtocol::Tcp),
+        "udp" => Ok(TunnelProtocol::Udp),
+        _ => Err(format!("Unknown protocol '{}'", value)),
+    }
 }
 
 pub fn load_server_config(args: &Args) -> anyhow::Result<ServerConfig> {
@@ -36,6 +383,11 @@ pub fn load_server_config(args: &Args) -> anyhow::Result<ServerConfig> {
         load_config("server.toml")?
     };
 
+    // Generic overrides first so dedicated flags take precedence
+    if !args.overrides.is_empty() {
+        config = apply_overrides(&config, &args.overrides)?;
+    }
+
     // Override with command line arguments
     if let Some(bind) = &args.bind {
         config.network.bind_addr = bind.parse()?;
@@ -45,6 +397,70 @@ pub fn load_server_config(args: &Args) -> anyhow::Result<ServerConfig> {
         config.network.port = port;
     }
 
+    if let Some(max_connections) = args.max_connections {
+        config.network.max_connections = max_connections;
+    }
+
+    if let Some(user) = &args.run_as_user {
+        config.network.run_as_user = Some(user.clone());
+    }
+
+    if let Some(group) = &args.run_as_group {
+        config.network.run_as_group = Some(group.clone());
+    }
+
+    if let Some(cert) = &args.cert {
+        config.tls.cert_path = cert.clone();
+    }
+
+    if let Some(key) = &args.key {
+        config.tls.key_path = key.clone();
+    }
+
+    if let Some(ca) = &args.ca {
+        config.tls.ca_path = Some(ca.clone());
+    }
+
+    if !args.tokens.is_empty() {
+        config.auth.tokens = args.tokens.clone();
+    }
+
+    if let Some(path) = &args.tokens_file {
+        config.auth.tokens_file = Some(path.clone());
+    }
+
+    if args.no_auth {
+        config.auth.require_auth = false;
+    }
+
+    if let Some(max) = args.max_clients_per_token {
+        config.auth.max_clients_per_token = Some(max);
+    }
+
+    if let Some(max) = args.max_tunnels_per_client {
+        config.limits.max_tunnels_per_client = max;
+    }
+
+    if let Some(mbps) = args.max_bandwidth_mbps {
+        config.limits.max_bandwidth_mbps = Some(mbps);
+    }
+
+    if let Some(max) = args.max_connections_per_tunnel {
+        config.limits.max_connections_per_tunnel = max;
+    }
+
+    if let Some(timeout) = args.connection_timeout {
+        config.limits.connection_timeout_secs = timeout;
+    }
+
+    if let Some(level) = &args.log_level {
+        config.logging.level = level.clone();
+    }
+
+    if let Some(file) = &args.log_file {
+        config.logging.file = Some(file.clone());
+    }
+
     if args.verbose {
         config.logging.level = "debug".to_string();
     }
@@ -52,6 +468,26 @@ pub fn load_server_config(args: &Args) -> anyhow::Result<ServerConfig> {
     Ok(config)
 }
 
+/// Arguments the installed service runs with, pinning the configuration
+/// file so it is found regardless of the service account's home directory
+pub fn service_arguments(args: &Args) -> anyhow::Result<Vec<String>> {
+    let config_path = match &args.config {
+        Some(path) => std::fs::canonicalize(path)?,
+        None => get_config_dir()?.join("server.toml"),
+    };
+
+    let mut arguments = vec![
+        "--config".to_string(),
+        config_path.to_string_lossy().into_owned(),
+    ];
+
+    if cfg!(windows) {
+        arguments.push("--service".to_string());
+    }
+
+    Ok(arguments)
+}
+
 pub fn generate_default_config() -> anyhow::Result<()> {
     let config = ServerConfig::default();
     save_config(&config, "server.toml")?;
@@ -59,9 +495,14 @@ pub fn generate_default_config() -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn setup_logging(config: &ServerConfig) -> anyhow::Result<()> {
+pub fn setup_logging(config: &ServerConfig) -> anyhow::Result<Telemetry> {
     use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
+    let (otlp_layer, telemetry) = telemetry::init(config.logging.otlp.as_ref(), "nat-server")?;
+    let mut root_layers: Vec<_> = otlp_layer.into_iter().collect();
+    root_layers.extend(logging::sink_layers(&config.logging, "nat-server")?);
+    let root_layer = logging::combine(root_layers);
+
     let env_filter =
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.logging.level));
 
@@ -82,16 +523,18 @@ pub fn setup_logging(config: &ServerConfig) -> anyhow::Result<()> {
         let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
 
         tracing_subscriber::registry()
+            .with(root_layer)
             .with(env_filter)
             .with(fmt_layer)
             .with(tracing_subscriber::fmt::layer().with_writer(non_blocking))
             .init();
     } else {
         tracing_subscriber::registry()
+            .with(root_layer)
             .with(env_filter)
             .with(fmt_layer)
             .init();
     }
 
-    Ok(())
+    Ok(telemetry)
 }
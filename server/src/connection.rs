@@ -1,18 +1,39 @@
-use chrono::Utc;
+use crate::ca::CertificateAuthority;
+use crate::events::{Events, ServerEvent};
+use crate::frp;
+use crate::provision::Provisioning;
+use crate::storage::Storage;
+use crate::token::{JwtVerifier, TokenGrant};
+use crate::usage::{UsageAccount, UsageLedger};
+use crate::vpn::VpnHub;
+use crate::wireguard::WireGuardBroker;
+use chrono::{DateTime, Utc};
 use nat_traversal_common::{
+    batch::FrameLimit,
+    config::{DuplicateClientId, TokenScope},
+    crypto,
     error::{NatError, NatResult},
-    protocol::{ErrorCode, Message, TunnelInfo, TunnelProtocol},
+    protocol::{ErrorCode, HeartbeatSettings, Message, TunnelInfo},
+    telemetry,
+    throttle::Throttle,
 };
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
 use tokio::net::TcpStream;
-use tokio::sync::{mpsc, RwLock};
-use tokio_rustls::TlsStream;
-use tracing::{debug, error, info, warn};
+use tokio::sync::{mpsc, oneshot, Notify, RwLock};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
-pub type SecureStream = TlsStream<TcpStream>;
+/// How long `request_logs` waits for the client
+const LOG_REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// A client's recent log lines, or why it refused them
+type LogResult = Result<Vec<String>, String>;
+
+pub type SecureStream = tokio_rustls::server::TlsStream<TcpStream>;
 
 /// Represents a client connection to the server
 pub struct ClientConnection {
@@ -21,9 +42,36 @@ pub struct ClientConnection {
     pub authenticated: bool,
     pub tunnels: Arc<RwLock<HashMap<Uuid, TunnelInfo>>>,
     pub sender: mpsc::UnboundedSender<Message>,
-    pub bytes_sent: Arc<RwLock<u64>>,
-    pub bytes_received: Arc<RwLock<u64>>,
+    /// Key the client presents to attach its data channel
+    pub data_key: Uuid,
+    /// Binary data channels the client has attached
+    data_senders: RwLock<Vec<mpsc::UnboundedSender<Message>>>,
+    /// Tunnel data sent to the client this session
+    pub bytes_sent: AtomicU64,
+    /// Tunnel data received from the client this session
+    pub bytes_received: AtomicU64,
     pub connected_at: chrono::DateTime<Utc>,
+    /// What the client's token allows
+    pub scope: TokenScope,
+    /// Enforces the scope's bandwidth cap across all the client's tunnels
+    pub throttle: Option<Arc<Throttle>>,
+    /// Takes over from `throttle` once the monthly quota is used up
+    over_quota_throttle: Option<Arc<Throttle>>,
+    /// This month's traffic, counted against the scope's quota
+    usage: Option<Arc<UsageAccount>>,
+    /// Signalled when an operator disconnects the client
+    kicked: Notify,
+    /// Set when a newer session with the same ID took over
+    replaced: AtomicBool,
+    /// When the client's token expires; static tokens never do
+    expires_at: std::sync::Mutex<Option<DateTime<Utc>>>,
+    /// The heartbeat schedule the client advertised
+    pub heartbeat: Option<HeartbeatSettings>,
+    /// The largest frame the client accepts, for the writers of its data
+    /// channels
+    pub frame_limit: FrameLimit,
+    /// Log requests still waiting for the client's `Logs`
+    log_requests: std::sync::Mutex<HashMap<Uuid, oneshot::Sender<LogResult>>>,
 }
 
 impl ClientConnection {
@@ -34,9 +82,169 @@ impl ClientConnection {
             authenticated: false,
             tunnels: Arc::new(RwLock::new(HashMap::new())),
             sender,
-            bytes_sent: Arc::new(RwLock::new(0)),
-            bytes_received: Arc::new(RwLock::new(0)),
+            data_key: Uuid::new_v4(),
+            data_senders: RwLock::new(Vec::new()),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
             connected_at: Utc::now(),
+            scope: TokenScope::default(),
+            throttle: None,
+            over_quota_throttle: None,
+            usage: None,
+            kicked: Notify::new(),
+            replaced: AtomicBool::new(false),
+            expires_at: std::sync::Mutex::new(None),
+            heartbeat: None,
+            frame_limit: FrameLimit::default(),
+            log_requests: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Restrict the client to `scope`
+    pub fn set_scope(&mut self, scope: TokenScope) {
+        self.throttle = scope
+            .max_bandwidth_mbps
+            .map(|mbps| Arc::new(Throttle::new(mbps)));
+        self.over_quota_throttle = scope
+            .over_quota_mbps
+            .map(|mbps| Arc::new(Throttle::new(mbps)));
+        self.scope = scope;
+    }
+
+    /// Count the client's traffic on `account`
+    pub fn set_usage(&mut self, account: Arc<UsageAccount>) {
+        self.usage = Some(account);
+    }
+
+    /// Bytes carried this month and the quota they count against
+    pub fn usage(&self) -> Option<(u64, Option<u64>)> {
+        self.usage
+            .as_ref()
+            .map(|account| (account.bytes(), self.quota_bytes()))
+    }
+
+    fn quota_bytes(&self) -> Option<u64> {
+        self.scope
+            .monthly_quota_mb
+            .map(|mb| mb.saturating_mul(1_000_000))
+    }
+
+    /// Whether the client has used up its monthly quota
+    pub fn over_quota(&self) -> bool {
+        matches!(self.usage(), Some((bytes, Some(quota))) if bytes >= quota)
+    }
+
+    /// Whether new connections and tunnels are refused for the quota; an
+    /// over-quota rate lets them through slowly instead
+    pub fn quota_exhausted(&self) -> bool {
+        self.over_quota_throttle.is_none() && self.over_quota()
+    }
+
+    /// The throttle traffic passes through right now
+    pub fn current_throttle(&self) -> Option<&Arc<Throttle>> {
+        match &self.over_quota_throttle {
+            Some(throttle) if self.over_quota() => Some(throttle),
+            _ => self.throttle.as_ref(),
+        }
+    }
+
+    /// Fetch the client's recent log lines, first raising its log level to
+    /// `level` for `duration_secs` when given
+    pub async fn request_logs(
+        &self,
+        level: Option<String>,
+        duration_secs: u64,
+        max_lines: u32,
+    ) -> LogResult {
+        let request_id = Uuid::new_v4();
+        let (tx, rx) = oneshot::channel();
+        self.log_requests.lock().unwrap().insert(request_id, tx);
+        let request = Message::RequestLogs {
+            request_id,
+            level,
+            duration_secs,
+            max_lines,
+        };
+        let answer = match self.sender.send(request) {
+            Ok(()) => tokio::time::timeout(LOG_REQUEST_TIMEOUT, rx).await.ok(),
+            Err(_) => None,
+        };
+        self.log_requests.lock().unwrap().remove(&request_id);
+        match answer {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(format!("Client {} disconnected", self.id)),
+            None => Err(format!("Client {} did not answer", self.id)),
+        }
+    }
+
+    /// Hand the client's `Logs` to the request waiting for it
+    pub fn logs_received(&self, request_id: Uuid, result: LogResult) {
+        if let Some(tx) = self.log_requests.lock().unwrap().remove(&request_id) {
+            let _ = tx.send(result);
+        }
+    }
+
+    /// Ask the connection's reader to drop the client
+    pub fn kick(&self) {
+        self.kicked.notify_one();
+    }
+
+    /// Resolves once `kick` has been called
+    pub async fn kicked(&self) {
+        self.kicked.notified().await;
+    }
+
+    /// Drop the client for a newer session with the same ID
+    pub fn replace(&self) {
+        self.replaced.store(true, Ordering::Relaxed);
+        self.kick();
+    }
+
+    /// Whether the client was dropped for a newer session
+    pub fn replaced(&self) -> bool {
+        self.replaced.load(Ordering::Relaxed)
+    }
+
+    /// Disconnect the client at `expires_at`, replacing the expiry of the
+    /// token it held before
+    pub fn expire_at(self: &Arc<Self>, expires_at: DateTime<Utc>) {
+        let previous = self.expires_at.lock().unwrap().replace(expires_at);
+        if previous.is_none() {
+            tokio::spawn(Self::watch_expiry(Arc::downgrade(self)));
+        }
+    }
+
+    /// Kick the client once its token expires, unless it has left or been
+    /// given a newer token by then
+    async fn watch_expiry(client: Weak<Self>) {
+        loop {
+            let Some(expires_at) = client.upgrade().and_then(|c| *c.expires_at.lock().unwrap())
+            else {
+                return;
+            };
+            let remaining = (expires_at - Utc::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(remaining).await;
+
+            let Some(client) = client.upgrade() else {
+                return;
+            };
+            if client
+                .expires_at
+                .lock()
+                .unwrap()
+                .is_some_and(|at| at > Utc::now())
+            {
+                continue;
+            }
+            info!("Token of client {} expired; disconnecting", client.id);
+            let _ = client
+                .send_message(Message::Error {
+                    code: ErrorCode::AuthenticationFailed,
+                    message: "Token expired".to_string(),
+                })
+                .await;
+            client.kick();
+            return;
         }
     }
 
@@ -47,6 +255,51 @@ impl ClientConnection {
         Ok(())
     }
 
+    /// Channel for one tunneled connection's opens, closes and data: a data
+    /// channel when any are attached, otherwise the control connection.
+    /// Callers keep the sender for the connection's lifetime so its
+    /// messages stay in order and the data path takes no lock per packet,
+    /// asking again only once it closes with the data channel detached.
+    pub async fn tunnel_sender(
+        &self,
+        tunnel_id: &Uuid,
+        connection_id: u32,
+    ) -> mpsc::UnboundedSender<Message> {
+        let data_senders = self.data_senders.read().await;
+        if data_senders.is_empty() {
+            return self.sender.clone();
+        }
+        let key = tunnel_id.as_u128() as usize ^ connection_id as usize;
+        data_senders[key % data_senders.len()].clone()
+    }
+
+    /// Add a data channel, refused once `limit` are attached
+    pub async fn attach_data_channel(
+        &self,
+        sender: mpsc::UnboundedSender<Message>,
+        limit: usize,
+    ) -> bool {
+        let mut data_senders = self.data_senders.write().await;
+        if data_senders.len() >= limit {
+            return false;
+        }
+        data_senders.push(sender);
+        true
+    }
+
+    /// Remove a data channel; with none left, traffic falls back to the
+    /// control connection
+    pub async fn detach_data_channel(&self, sender: &mpsc::UnboundedSender<Message>) {
+        self.data_senders
+            .write()
+            .await
+            .retain(|current| !current.same_channel(sender));
+    }
+
+    pub async fn data_channel_count(&self) -> usize {
+        self.data_senders.read().await.len()
+    }
+
     pub async fn add_tunnel(&self, tunnel: TunnelInfo) {
         let mut tunnels = self.tunnels.write().await;
         tunnels.insert(tunnel.id, tunnel);
@@ -67,45 +320,305 @@ impl ClientConnection {
         tunnels.values().cloned().collect()
     }
 
-    pub async fn update_bytes_sent(&self, bytes: u64) {
-        let mut sent = self.bytes_sent.write().await;
-        *sent += bytes;
+    pub fn update_bytes_sent(&self, bytes: u64) {
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(usage) = &self.usage {
+            usage.add(bytes);
+        }
     }
 
-    pub async fn update_bytes_received(&self, bytes: u64) {
-        let mut received = self.bytes_received.write().await;
-        *received += bytes;
+    pub fn update_bytes_received(&self, bytes: u64) {
+        self.bytes_received.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(usage) = &self.usage {
+            usage.add(bytes);
+        }
     }
 
-    pub async fn get_stats(&self) -> (u64, u64) {
-        let sent = *self.bytes_sent.read().await;
-        let received = *self.bytes_received.read().await;
-        (sent, received)
+    /// Bytes sent to and received from the client this session
+    pub fn get_stats(&self) -> (u64, u64) {
+        (
+            self.bytes_sent.load(Ordering::Relaxed),
+            self.bytes_received.load(Ordering::Relaxed),
+        )
     }
 }
 
+/// How a client proves who it is
+#[derive(Clone, Copy)]
+pub enum Credential<'a> {
+    /// The token itself
+    Token(&'a str),
+    /// An HMAC of the nonce this connection was challenged with
+    Proof { nonce: &'a str, proof: &'a str },
+    /// A certificate from the client's TLS handshake, by the name it was
+    /// issued to
+    Certificate(&'a str),
+    /// frpc's MD5 of a token and the login's timestamp
+    Frp { key: &'a str, timestamp: i64 },
+}
+
+/// Maintenance mode, during which the server takes no new clients or
+/// tunnels while the open ones keep running
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Maintenance {
+    pub since: DateTime<Utc>,
+    /// When the clients still connected are disconnected, if ever
+    pub drain_at: Option<DateTime<Utc>>,
+}
+
 /// Connection manager handles all client connections
 pub struct ConnectionManager {
     clients: Arc<RwLock<HashMap<String, Arc<ClientConnection>>>>,
+    /// Published service name to the ID of the client offering it
+    services: Arc<RwLock<HashMap<String, String>>>,
     auth_tokens: Vec<String>,
+    allow_plain_tokens: bool,
+    /// Scopes of static tokens, by token
+    scopes: BTreeMap<String, TokenScope>,
+    jwt: Option<JwtVerifier>,
+    /// Signs the certificates of enrolling clients
+    ca: Option<CertificateAuthority>,
+    /// Only accept clients authenticating with a certificate
+    require_certificate: bool,
+    /// Data channels each client may attach
+    pub max_data_connections: usize,
+    /// Where sessions and tunnels are recorded, when configured
+    storage: Option<Arc<Storage>>,
+    /// Monthly traffic of every client
+    usage: Arc<UsageLedger>,
+    /// Which session keeps a client ID two clients log in with
+    duplicate_client_id: DuplicateClientId,
+    /// Tunnels clients are told to open when they log in
+    provisioning: Provisioning,
+    /// Message sent to clients that log in
+    motd: Option<String>,
+    maintenance: std::sync::Mutex<Option<Maintenance>>,
+    /// The layer-3 VPN clients may join, when enabled
+    vpn: Option<Arc<VpnHub>>,
+    /// The WireGuard mesh clients may join, when enabled
+    wireguard: Option<Arc<WireGuardBroker>>,
+    events: Events,
 }
 
 impl ConnectionManager {
-    pub fn new(auth_tokens: Vec<String>) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        auth_tokens: Vec<String>,
+        allow_plain_tokens: bool,
+        scopes: BTreeMap<String, TokenScope>,
+        jwt: Option<JwtVerifier>,
+        ca: Option<CertificateAuthority>,
+        require_certificate: bool,
+        max_data_connections: usize,
+        storage: Option<Arc<Storage>>,
+        usage: Arc<UsageLedger>,
+        duplicate_client_id: DuplicateClientId,
+        provisioning: Provisioning,
+        motd: Option<String>,
+        vpn: Option<Arc<VpnHub>>,
+        wireguard: Option<Arc<WireGuardBroker>>,
+    ) -> Self {
         Self {
             clients: Arc::new(RwLock::new(HashMap::new())),
+            services: Arc::new(RwLock::new(HashMap::new())),
             auth_tokens,
+            allow_plain_tokens,
+            scopes,
+            jwt,
+            ca,
+            require_certificate,
+            max_data_connections,
+            storage,
+            usage,
+            duplicate_client_id,
+            provisioning,
+            motd,
+            maintenance: std::sync::Mutex::new(None),
+            vpn,
+            wireguard,
+            events: Events::default(),
         }
     }
 
-    pub async fn add_client(&self, client: Arc<ClientConnection>) {
-        let mut clients = self.clients.write().await;
-        clients.insert(client.id.clone(), client);
+    /// Changes to clients and tunnels, for watchers
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    /// The history database, when configured
+    pub fn storage(&self) -> Option<&Arc<Storage>> {
+        self.storage.as_ref()
     }
 
-    pub async fn remove_client(&self, client_id: &str) -> Option<Arc<ClientConnection>> {
+    /// Monthly traffic of every client
+    pub fn usage(&self) -> &Arc<UsageLedger> {
+        &self.usage
+    }
+
+    /// Tunnels clients are told to open when they log in
+    pub fn provisioning(&self) -> &Provisioning {
+        &self.provisioning
+    }
+
+    /// The layer-3 VPN, when enabled
+    pub fn vpn(&self) -> Option<&Arc<VpnHub>> {
+        self.vpn.as_ref()
+    }
+
+    /// The WireGuard mesh, when enabled
+    pub fn wireguard(&self) -> Option<&Arc<WireGuardBroker>> {
+        self.wireguard.as_ref()
+    }
+
+    /// Message sent to clients that log in
+    pub fn motd(&self) -> Option<&str> {
+        self.motd.as_deref()
+    }
+
+    /// Maintenance mode, while the server is in it
+    pub fn maintenance(&self) -> Option<Maintenance> {
+        self.maintenance.lock().unwrap().clone()
+    }
+
+    pub fn set_maintenance(&self, maintenance: Option<Maintenance>) {
+        *self.maintenance.lock().unwrap() = maintenance;
+    }
+
+    /// Tell every connected client the server is going away and give them
+    /// a moment to hang up
+    pub async fn disconnect_all(&self, reason: &str) {
+        let clients = self.get_all_clients().await;
+        if clients.is_empty() {
+            return;
+        }
+        for client in &clients {
+            let _ = client
+                .send_message(Message::Disconnect {
+                    reason: reason.to_string(),
+                })
+                .await;
+        }
+        let _ = tokio::time::timeout(std::time::Duration::from_secs(2), async {
+            while self.get_client_count().await > 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+    }
+
+    /// Write the traffic of every connected client's session to the
+    /// history database
+    pub async fn checkpoint(&self) {
+        let Some(storage) = &self.storage else {
+            return;
+        };
+        for client in self.clients.read().await.values() {
+            storage.session_progress(&client.id, client.connected_at, client.get_stats());
+        }
+    }
+
+    /// Register an authenticated client, settling a clash with a session
+    /// already holding its ID by the configured policy. Returns why the
+    /// client was refused.
+    pub async fn add_client(&self, client: Arc<ClientConnection>) -> Result<(), String> {
         let mut clients = self.clients.write().await;
-        clients.remove(client_id)
+        if let Some(existing) = clients.get(&client.id) {
+            match self.duplicate_client_id {
+                DuplicateClientId::Reject => {
+                    warn!(
+                        "Refusing client {} from {}: already connected from {}",
+                        client.id, client.addr, existing.addr
+                    );
+                    return Err(format!("Client ID '{}' is already connected", client.id));
+                }
+                DuplicateClientId::TakeOver => {
+                    warn!(
+                        "Client {} from {} takes over the session from {}",
+                        client.id, client.addr, existing.addr
+                    );
+                    existing.replace();
+                }
+            }
+        }
+        if let Some(storage) = &self.storage {
+            storage.client_connected(&client.id, client.addr, client.connected_at);
+        }
+        self.events.publish(ServerEvent::ClientConnected {
+            client_id: client.id.clone(),
+            addr: client.addr,
+            at: client.connected_at,
+        });
+        if clients.insert(client.id.clone(), client).is_none() {
+            telemetry::clients_changed(1);
+        }
+        Ok(())
+    }
+
+    /// End `client`'s session. False when a newer session has taken over
+    /// its ID, which then keeps the ID's services.
+    pub async fn remove_client(&self, client: &Arc<ClientConnection>) -> bool {
+        if let Some(storage) = &self.storage {
+            storage.client_disconnected(&client.id, client.connected_at, client.get_stats());
+        }
+        {
+            let mut clients = self.clients.write().await;
+            if !clients
+                .get(&client.id)
+                .is_some_and(|current| Arc::ptr_eq(current, client))
+            {
+                return false;
+            }
+            clients.remove(&client.id);
+        }
+        telemetry::clients_changed(-1);
+        let (bytes_sent, bytes_received) = client.get_stats();
+        self.events.publish(ServerEvent::ClientDisconnected {
+            client_id: client.id.clone(),
+            at: Utc::now(),
+            bytes_sent,
+            bytes_received,
+        });
+        if let Some(vpn) = &self.vpn {
+            vpn.leave(client).await;
+        }
+        if let Some(wireguard) = &self.wireguard {
+            wireguard.leave(client).await;
+        }
+        self.services
+            .write()
+            .await
+            .retain(|_, publisher| publisher != &client.id);
+        true
+    }
+
+    /// Register `client_id` as the publisher of a service name
+    pub async fn publish_service(&self, client_id: &str, name: &str) -> NatResult<()> {
+        let mut services = self.services.write().await;
+        match services.get(name) {
+            Some(publisher) if publisher != client_id => Err(NatError::protocol(format!(
+                "Service '{}' is already published by another client",
+                name
+            ))),
+            _ => {
+                info!("Client {} published service {}", client_id, name);
+                services.insert(name.to_string(), client_id.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn unpublish_service(&self, client_id: &str, name: &str) {
+        let mut services = self.services.write().await;
+        if services
+            .get(name)
+            .is_some_and(|publisher| publisher == client_id)
+        {
+            services.remove(name);
+        }
+    }
+
+    pub async fn service_publisher(&self, name: &str) -> Option<String> {
+        self.services.read().await.get(name).cloned()
     }
 
     pub async fn get_client(&self, client_id: &str) -> Option<Arc<ClientConnection>> {
@@ -113,17 +626,112 @@ impl ConnectionManager {
         clients.get(client_id).cloned()
     }
 
-    pub async fn authenticate(&self, token: &str, client_id: &str) -> bool {
-        if !self.auth_tokens.contains(&token.to_string()) {
-            warn!(
-                "Authentication failed for client {}: invalid token",
-                client_id
-            );
-            return false;
+    /// Check a client's credential, returning what it grants or why it was
+    /// refused
+    pub async fn authenticate(
+        &self,
+        credential: Credential<'_>,
+        client_id: &str,
+    ) -> Result<TokenGrant, String> {
+        let result = match credential {
+            Credential::Token(_) | Credential::Proof { .. } | Credential::Frp { .. }
+                if self.require_certificate =>
+            {
+                Err("This server requires a client certificate".to_string())
+            }
+            _ => self.verify(credential, client_id, &[]),
+        };
+
+        match &result {
+            Ok(_) => info!("Client {} authenticated successfully", client_id),
+            Err(reason) => warn!("Authentication failed for client {}: {}", client_id, reason),
         }
+        result
+    }
 
-        info!("Client {} authenticated successfully", client_id);
-        true
+    /// Sign a client certificate for `client_id`. Bootstrap tokens count
+    /// here besides the usual credentials, and a certificate holder may
+    /// renew its own.
+    pub async fn enroll(
+        &self,
+        credential: Credential<'_>,
+        client_id: &str,
+        csr: &str,
+    ) -> Result<String, String> {
+        let ca = self
+            .ca
+            .as_ref()
+            .ok_or_else(|| "This server does not issue certificates".to_string())?;
+        let result = self
+            .verify(credential, client_id, &ca.bootstrap_tokens)
+            .and_then(|_| ca.issue(csr, client_id).map_err(|e| e.to_string()));
+        if let Err(reason) = &result {
+            warn!("Enrollment failed for client {}: {}", client_id, reason);
+        }
+        result
+    }
+
+    /// Check `credential` against the static tokens, `extra_tokens`, the
+    /// JWT settings or the certificate's name
+    fn verify(
+        &self,
+        credential: Credential<'_>,
+        client_id: &str,
+        extra_tokens: &[String],
+    ) -> Result<TokenGrant, String> {
+        let mut tokens = self.auth_tokens.iter().chain(extra_tokens);
+        match credential {
+            Credential::Certificate(name) if name == client_id => Ok(TokenGrant::default()),
+            Credential::Certificate(name) => {
+                Err(format!("Certificate was issued to client {}", name))
+            }
+            Credential::Proof { nonce, proof } => tokens
+                .find(|token| crypto::verify_auth_proof(token, nonce, client_id, proof))
+                .map(|token| self.static_grant(token))
+                .ok_or_else(|| "Invalid token".to_string()),
+            Credential::Frp { key, timestamp } => tokens
+                .find(|token| frp::privilege_key(token, timestamp) == key)
+                .map(|token| self.static_grant(token))
+                .ok_or_else(|| "Invalid token".to_string()),
+            Credential::Token(token) if tokens.any(|t| t == token) => {
+                if self.allow_plain_tokens {
+                    Ok(self.static_grant(token))
+                } else {
+                    Err("Tokens sent in the clear are not accepted; upgrade the client".to_string())
+                }
+            }
+            Credential::Token(token) => match &self.jwt {
+                Some(jwt) if token.matches('.').count() == 2 => jwt.verify(token, client_id),
+                _ => Err("Invalid token".to_string()),
+            },
+        }
+    }
+
+    /// Push `client` a freshly signed token carrying its current scope
+    pub async fn rotate_token(&self, client: &Arc<ClientConnection>) -> NatResult<DateTime<Utc>> {
+        let jwt = self
+            .jwt
+            .as_ref()
+            .ok_or_else(|| NatError::config("Token rotation needs auth.jwt with a secret"))?;
+        let (token, expires_at) = jwt
+            .renew(&client.id, &client.scope)
+            .map_err(|e| NatError::config(format!("Cannot issue a new token: {}", e)))?;
+        client
+            .send_message(Message::RotateToken { token, expires_at })
+            .await?;
+        client.expire_at(expires_at);
+        info!(
+            "Sent client {} a new token valid until {}",
+            client.id, expires_at
+        );
+        Ok(expires_at)
+    }
+
+    fn static_grant(&self, token: &str) -> TokenGrant {
+        TokenGrant {
+            scope: self.scopes.get(token).cloned().unwrap_or_default(),
+            expires_at: None,
+        }
     }
 
     pub async fn broadcast_message(&self, message: Message) {
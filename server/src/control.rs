@@ -0,0 +1,794 @@
+//! Requests `nat-server inspect` sends over the local control socket.
+
+use crate::{
+    capture::{Capture, CaptureFormat},
+    connection::{ConnectionManager, Maintenance},
+    relay::RelayManager,
+    storage::{ClientRecord, HistoryKind, SessionRecord, TunnelRecord},
+    tunnel::{PublicConnection, TunnelManager},
+    usage::UsageSummary,
+};
+use chrono::{DateTime, Utc};
+use nat_traversal_common::{
+    config::{ServerConfig, TunnelConfig},
+    control::{self, ControlListener},
+    protocol::{ErrorCode, Message, ShareInfo, TunnelInfo, Utilization},
+};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum InspectRequest {
+    Clients,
+    Tunnels {
+        client_id: Option<String>,
+    },
+    Connections {
+        tunnel_id: Option<Uuid>,
+    },
+    CloseTunnel {
+        tunnel_id: Uuid,
+    },
+    Shares,
+    CloseShare {
+        share_id: Uuid,
+    },
+    Kick {
+        client_id: String,
+    },
+    RotateToken {
+        client_id: String,
+    },
+    History {
+        kind: HistoryKind,
+        client_id: Option<String>,
+        limit: usize,
+    },
+    Usage,
+    Capture {
+        tunnel_id: Uuid,
+        connection_id: Option<u32>,
+        path: PathBuf,
+        format: CaptureFormat,
+        max_bytes: u64,
+        redact: bool,
+    },
+    StopCapture {
+        tunnel_id: Uuid,
+    },
+    /// Counters for `nat-server top`
+    Top,
+    Provisioned {
+        client_id: Option<String>,
+    },
+    Provision {
+        client_id: String,
+        tunnel: Box<TunnelConfig>,
+    },
+    Unprovision {
+        client_id: String,
+        name: String,
+    },
+    ClientLogs {
+        client_id: String,
+        level: Option<String>,
+        duration_secs: u64,
+        max_lines: u32,
+    },
+    /// Tunnels, public connections and ports in use against their caps
+    Capacity,
+    /// Enter or leave maintenance mode, or with `enable` unset just report
+    /// it; a drain disconnects the clients left after that many seconds
+    Maintenance {
+        enable: Option<bool>,
+        drain_secs: Option<u64>,
+    },
+}
+
+impl InspectRequest {
+    /// Whether the request only looks at the server, so observers may send it
+    pub fn read_only(&self) -> bool {
+        match self {
+            InspectRequest::Clients
+            | InspectRequest::Tunnels { .. }
+            | InspectRequest::Connections { .. }
+            | InspectRequest::Shares
+            | InspectRequest::History { .. }
+            | InspectRequest::Usage
+            | InspectRequest::Top
+            | InspectRequest::Capacity
+            | InspectRequest::Provisioned { .. } => true,
+            InspectRequest::ClientLogs { level, .. } => level.is_none(),
+            InspectRequest::Maintenance { enable, .. } => enable.is_none(),
+            InspectRequest::CloseTunnel { .. }
+            | InspectRequest::CloseShare { .. }
+            | InspectRequest::Kick { .. }
+            | InspectRequest::RotateToken { .. }
+            | InspectRequest::Capture { .. }
+            | InspectRequest::StopCapture { .. }
+            | InspectRequest::Provision { .. }
+            | InspectRequest::Unprovision { .. } => false,
+        }
+    }
+}
+
+/// A request on the observer socket, with the observer token it comes with
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ObserverRequest {
+    pub token: String,
+    pub request: InspectRequest,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum InspectResponse {
+    Clients(Vec<ClientSummary>),
+    Tunnels(Vec<TunnelSummary>),
+    Connections(Vec<PublicConnection>),
+    Shares(Vec<ShareSummary>),
+    ClientHistory(Vec<ClientRecord>),
+    SessionHistory(Vec<SessionRecord>),
+    TunnelHistory(Vec<TunnelRecord>),
+    Usage(Vec<UsageSummary>),
+    Top(TopSnapshot),
+    Provisioned(Vec<ProvisionedSummary>),
+    /// A client's recent log lines, oldest first
+    Logs(Vec<String>),
+    Capacity(Utilization),
+    /// Maintenance mode, unset when the server is not in it
+    Maintenance(Option<Maintenance>),
+    Done(String),
+    Error(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientSummary {
+    pub id: String,
+    pub addr: SocketAddr,
+    pub connected_at: DateTime<Utc>,
+    pub tunnels: usize,
+    pub data_channels: usize,
+    pub relays: usize,
+}
+
+/// Traffic counters at one moment; `nat-server top` turns the difference
+/// between two into rates
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TopSnapshot {
+    pub taken_at: DateTime<Utc>,
+    pub clients: Vec<ClientCounters>,
+    pub tunnels: Vec<TunnelCounters>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientCounters {
+    pub id: String,
+    pub addr: SocketAddr,
+    pub connected_at: DateTime<Utc>,
+    pub tunnels: usize,
+    /// Tunnel data sent to the client this session
+    pub bytes_sent: u64,
+    /// Tunnel data received from the client this session
+    pub bytes_received: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TunnelCounters {
+    pub client_id: String,
+    /// Connections carried since the tunnel opened
+    pub connections: u64,
+    #[serde(flatten)]
+    pub tunnel: TunnelInfo,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TunnelSummary {
+    pub client_id: String,
+    #[serde(flatten)]
+    pub tunnel: TunnelInfo,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProvisionedSummary {
+    pub client_id: String,
+    /// Whether the client has the tunnel open right now
+    pub open: bool,
+    #[serde(flatten)]
+    pub tunnel: TunnelConfig,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShareSummary {
+    pub client_id: String,
+    #[serde(flatten)]
+    pub share: ShareInfo,
+}
+
+/// Where the control socket lives for this configuration
+pub fn socket_path(config: &ServerConfig) -> anyhow::Result<PathBuf> {
+    match &config.control.path {
+        Some(path) => Ok(path.clone()),
+        None => control::default_path("nat-server"),
+    }
+}
+
+/// Where the observer socket lives for this configuration
+pub fn observer_socket_path(config: &ServerConfig) -> anyhow::Result<PathBuf> {
+    match &config.control.observer_path {
+        Some(path) => Ok(path.clone()),
+        None => control::default_path("nat-server-observer"),
+    }
+}
+
+/// Send `request` to the running server, through the observer socket when
+/// an observer token is given
+pub async fn send(
+    config: &ServerConfig,
+    observer_token: Option<&str>,
+    request: InspectRequest,
+) -> anyhow::Result<InspectResponse> {
+    let (path, response) = match observer_token {
+        Some(token) => {
+            let path = observer_socket_path(config)?;
+            let request = ObserverRequest {
+                token: token.to_string(),
+                request,
+            };
+            let response = control::request(&path, &request).await;
+            (path, response)
+        }
+        None => {
+            let path = socket_path(config)?;
+            let response = control::request(&path, &request).await;
+            (path, response)
+        }
+    };
+    response.map_err(|e| anyhow::anyhow!("No running server at {}: {}", path.display(), e))
+}
+
+/// Answers inspect requests against the server's live state
+#[derive(Clone)]
+pub struct Inspector {
+    pub connection_manager: Arc<ConnectionManager>,
+    pub tunnel_manager: Arc<TunnelManager>,
+    pub relay_manager: Arc<RelayManager>,
+}
+
+impl Inspector {
+    /// Answer requests on `listener` until the process exits
+    pub async fn serve(self, listener: ControlListener) {
+        let result = listener
+            .serve(move |request| {
+                let inspector = self.clone();
+                async move { inspector.handle(request).await }
+            })
+            .await;
+        if let Err(e) = result {
+            warn!("Control socket stopped: {}", e);
+        }
+    }
+
+    /// Answer the read-only requests of observers on `listener`, refusing
+    /// any without one of `tokens`
+    pub async fn serve_observers(self, listener: ControlListener, tokens: Vec<String>) {
+        let tokens = Arc::new(tokens);
+        let result = listener
+            .serve(move |ObserverRequest { token, request }| {
+                let inspector = self.clone();
+                let tokens = tokens.clone();
+                async move {
+                    if !tokens.contains(&token) {
+                        warn!("Refused an observer request with an unknown token");
+                        InspectResponse::Error("Unknown observer token".to_string())
+                    } else if !request.read_only() {
+                        InspectResponse::Error("Observers may only view the server".to_string())
+                    } else {
+                        inspector.handle(request).await
+                    }
+                }
+            })
+            .await;
+        if let Err(e) = result {
+            warn!("Observer socket stopped: {}", e);
+        }
+    }
+
+    pub(crate) async fn handle(&self, request: InspectRequest) -> InspectResponse {
+        match request {
+            InspectRequest::Clients => InspectResponse::Clients(self.clients().await),
+            InspectRequest::Tunnels { client_id } => {
+                let mut tunnels: Vec<_> = self
+                    .tunnel_manager
+                    .list_tunnels_with_owner()
+                    .await
+                    .into_iter()
+                    .filter(|(owner, _)| client_id.as_ref().is_none_or(|id| id == owner))
+                    .map(|(client_id, tunnel)| TunnelSummary { client_id, tunnel })
+                    .collect();
+                tunnels.sort_by_key(|t| t.tunnel.remote_port);
+                InspectResponse::Tunnels(tunnels)
+            }
+            InspectRequest::Connections { tunnel_id } => InspectResponse::Connections(
+                self.tunnel_manager.list_connections(tunnel_id.as_ref()),
+            ),
+            InspectRequest::CloseTunnel { tunnel_id } => match self.close_tunnel(tunnel_id).await {
+                Ok(()) => InspectResponse::Done(format!("Closed tunnel {}", tunnel_id)),
+                Err(e) => InspectResponse::Error(e),
+            },
+            InspectRequest::Shares => {
+                let mut shares: Vec<_> = self
+                    .tunnel_manager
+                    .list_shares()
+                    .await
+                    .into_iter()
+                    .map(|(client_id, share)| ShareSummary { client_id, share })
+                    .collect();
+                shares.sort_by_key(|s| s.share.expires_at);
+                InspectResponse::Shares(shares)
+            }
+            InspectRequest::CloseShare { share_id } => {
+                if self
+                    .tunnel_manager
+                    .close_share(&share_id, "Closed by operator")
+                    .await
+                {
+                    InspectResponse::Done(format!("Closed share {}", share_id))
+                } else {
+                    InspectResponse::Error(format!("No share {}", share_id))
+                }
+            }
+            InspectRequest::Kick { client_id } => match self.kick(&client_id).await {
+                Ok(closed) => InspectResponse::Done(format!(
+                    "Disconnected client {} and closed {} tunnel(s)",
+                    client_id, closed
+                )),
+                Err(e) => InspectResponse::Error(e),
+            },
+            InspectRequest::RotateToken { client_id } => {
+                match self.rotate_token(&client_id).await {
+                    Ok(expires_at) => InspectResponse::Done(format!(
+                        "Sent client {} a new token valid until {}",
+                        client_id,
+                        expires_at.format("%Y-%m-%d %H:%M:%S UTC")
+                    )),
+                    Err(e) => InspectResponse::Error(e),
+                }
+            }
+            InspectRequest::History {
+                kind,
+                client_id,
+                limit,
+            } => match self.history(kind, client_id, limit).await {
+                Ok(response) => response,
+                Err(e) => InspectResponse::Error(e.to_string()),
+            },
+            InspectRequest::Usage => InspectResponse::Usage(self.usage().await),
+            InspectRequest::Capture {
+                tunnel_id,
+                connection_id,
+                path,
+                format,
+                max_bytes,
+                redact,
+            } => {
+                let Some(tunnel) = self.tunnel_manager.get_tunnel(&tunnel_id).await else {
+                    return InspectResponse::Error(format!("No tunnel {}", tunnel_id));
+                };
+                let capture = match Capture::create(
+                    &path,
+                    connection_id,
+                    tunnel.remote_port,
+                    format,
+                    max_bytes,
+                    redact,
+                ) {
+                    Ok(capture) => Arc::new(capture),
+                    Err(e) => {
+                        return InspectResponse::Error(format!(
+                            "Failed to create {}: {}",
+                            path.display(),
+                            e
+                        ))
+                    }
+                };
+                match self.tunnel_manager.start_capture(&tunnel_id, capture).await {
+                    Ok(()) => {
+                        info!("Capturing tunnel {} to {}", tunnel_id, path.display());
+                        InspectResponse::Done(format!(
+                            "Capturing tunnel {} to {}",
+                            tunnel_id,
+                            path.display()
+                        ))
+                    }
+                    Err(e) => InspectResponse::Error(e.to_string()),
+                }
+            }
+            InspectRequest::Top => InspectResponse::Top(self.top().await),
+            InspectRequest::Capacity => {
+                InspectResponse::Capacity(self.tunnel_manager.utilization().await)
+            }
+            InspectRequest::ClientLogs {
+                client_id,
+                level,
+                duration_secs,
+                max_lines,
+            } => match self
+                .client_logs(&client_id, level, duration_secs, max_lines)
+                .await
+            {
+                Ok(lines) => InspectResponse::Logs(lines),
+                Err(e) => InspectResponse::Error(e),
+            },
+            InspectRequest::Maintenance { enable, drain_secs } => {
+                if let Some(enable) = enable {
+                    self.set_maintenance(enable, drain_secs);
+                }
+                InspectResponse::Maintenance(self.connection_manager.maintenance())
+            }
+            InspectRequest::Provisioned { client_id } => {
+                InspectResponse::Provisioned(self.provisioned(client_id.as_deref()).await)
+            }
+            InspectRequest::Provision { client_id, tunnel } => {
+                let name = tunnel.name.clone();
+                let sent = self.provision(&client_id, *tunnel).await;
+                InspectResponse::Done(format!(
+                    "Provisioned tunnel {} for client {}{}",
+                    name,
+                    client_id,
+                    if sent {
+                        ""
+                    } else {
+                        "; it opens at its next login"
+                    }
+                ))
+            }
+            InspectRequest::Unprovision { client_id, name } => {
+                match self.unprovision(&client_id, &name).await {
+                    Ok(()) => InspectResponse::Done(format!(
+                        "Stopped provisioning tunnel {} for client {}",
+                        name, client_id
+                    )),
+                    Err(e) => InspectResponse::Error(e),
+                }
+            }
+            InspectRequest::StopCapture { tunnel_id } => {
+                match self.tunnel_manager.stop_capture(&tunnel_id).await {
+                    Some(capture) => {
+                        info!("Stopped capturing tunnel {}", tunnel_id);
+                        InspectResponse::Done(format!(
+                            "Stopped capturing tunnel {}; wrote {} bytes to {}",
+                            tunnel_id,
+                            capture.written(),
+                            capture.path.display()
+                        ))
+                    }
+                    None => InspectResponse::Error(format!("Tunnel {} is not captured", tunnel_id)),
+                }
+            }
+        }
+    }
+
+    async fn top(&self) -> TopSnapshot {
+        let tunnels: Vec<_> = self
+            .tunnel_manager
+            .list_tunnel_counters()
+            .await
+            .into_iter()
+            .map(|(client_id, tunnel, connections)| TunnelCounters {
+                client_id,
+                connections,
+                tunnel,
+            })
+            .collect();
+        let clients = self
+            .connection_manager
+            .get_all_clients()
+            .await
+            .into_iter()
+            .map(|client| ClientCounters {
+                tunnels: tunnels.iter().filter(|t| t.client_id == client.id).count(),
+                id: client.id.clone(),
+                addr: client.addr,
+                connected_at: client.connected_at,
+                bytes_sent: client.bytes_sent.load(Ordering::Relaxed),
+                bytes_received: client.bytes_received.load(Ordering::Relaxed),
+            })
+            .collect();
+        TopSnapshot {
+            taken_at: Utc::now(),
+            clients,
+            tunnels,
+        }
+    }
+
+    /// Traffic per client this month and last, with the quotas of
+    /// connected clients
+    async fn usage(&self) -> Vec<UsageSummary> {
+        let ledger = self.connection_manager.usage();
+        let month = ledger.month();
+        let mut usage = ledger.summaries();
+        for summary in usage.iter_mut().filter(|summary| summary.month == month) {
+            if let Some(client) = self.connection_manager.get_client(&summary.client_id).await {
+                summary.quota_bytes = client.usage().and_then(|(_, quota)| quota);
+            }
+        }
+        usage
+    }
+
+    async fn history(
+        &self,
+        kind: HistoryKind,
+        client_id: Option<String>,
+        limit: usize,
+    ) -> anyhow::Result<InspectResponse> {
+        let storage = self
+            .connection_manager
+            .storage()
+            .ok_or_else(|| anyhow::anyhow!("No history is recorded; set storage.database"))?;
+        Ok(match kind {
+            HistoryKind::Clients => {
+                let mut clients = storage.clients().await?;
+                if let Some(client_id) = &client_id {
+                    clients.retain(|c| &c.client_id == client_id);
+                }
+                clients.truncate(limit);
+                InspectResponse::ClientHistory(clients)
+            }
+            HistoryKind::Sessions => {
+                InspectResponse::SessionHistory(storage.sessions(client_id, limit).await?)
+            }
+            HistoryKind::Tunnels => {
+                let mut tunnels = storage.tunnels(client_id, limit).await?;
+                // Traffic is stored when a tunnel closes; open ones are live
+                for record in tunnels.iter_mut().filter(|t| t.closed_at.is_none()) {
+                    let Ok(id) = record.id.parse() else { continue };
+                    if let Some(live) = self.tunnel_manager.get_tunnel(&id).await {
+                        record.bytes_sent = live.bytes_sent;
+                        record.bytes_received = live.bytes_received;
+                    }
+                }
+                InspectResponse::TunnelHistory(tunnels)
+            }
+        })
+    }
+
+    async fn clients(&self) -> Vec<ClientSummary> {
+        let mut clients = Vec::new();
+        for client in self.connection_manager.get_all_clients().await {
+            clients.push(ClientSummary {
+                id: client.id.clone(),
+                addr: client.addr,
+                connected_at: client.connected_at,
+                tunnels: self
+                    .tunnel_manager
+                    .list_client_tunnels(&client.id)
+                    .await
+                    .len(),
+                data_channels: client.data_channel_count().await,
+                relays: self.relay_manager.list_relays(&client.id).await.len(),
+            });
+        }
+        clients.sort_by_key(|c| c.connected_at);
+        clients
+    }
+
+    /// Close a tunnel and tell its client, if still connected, why
+    async fn close_tunnel(&self, tunnel_id: Uuid) -> Result<(), String> {
+        let owner = self
+            .tunnel_manager
+            .tunnel_owner(&tunnel_id)
+            .await
+            .ok_or_else(|| format!("No tunnel {}", tunnel_id))?;
+        self.tunnel_manager
+            .close_tunnel(&tunnel_id)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if let Some(client) = self.connection_manager.get_client(&owner).await {
+            client.remove_tunnel(&tunnel_id).await;
+            let _ = client
+                .send_message(Message::TunnelClosed {
+                    tunnel_id,
+                    reason: "Closed by operator".to_string(),
+                })
+                .await;
+        }
+        info!("Operator closed tunnel {} of client {}", tunnel_id, owner);
+        Ok(())
+    }
+
+    /// Enter maintenance mode, keeping the time it began when already in
+    /// it, or leave it
+    fn set_maintenance(&self, enable: bool, drain_secs: Option<u64>) {
+        if !enable {
+            if self.connection_manager.maintenance().is_some() {
+                info!("Operator ended maintenance");
+            }
+            self.connection_manager.set_maintenance(None);
+            return;
+        }
+        let now = Utc::now();
+        let maintenance = Maintenance {
+            since: self
+                .connection_manager
+                .maintenance()
+                .map_or(now, |maintenance| maintenance.since),
+            drain_at: drain_secs.map(|secs| now + chrono::Duration::seconds(secs as i64)),
+        };
+        self.connection_manager
+            .set_maintenance(Some(maintenance.clone()));
+        match drain_secs {
+            Some(secs) => info!(
+                "Operator started maintenance; clients are disconnected in {} seconds",
+                secs
+            ),
+            None => info!("Operator started maintenance"),
+        }
+
+        let Some(secs) = drain_secs else { return };
+        let inspector = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_secs(secs)).await;
+            // Maintenance may have ended or been timed anew meanwhile
+            if inspector.connection_manager.maintenance() == Some(maintenance) {
+                inspector.drain().await;
+            }
+        });
+    }
+
+    /// Close the tunnels of the clients still connected and tell them the
+    /// server is going down
+    async fn drain(&self) {
+        let clients = self.connection_manager.get_all_clients().await;
+        info!(
+            "Maintenance drain is due, disconnecting {} client(s)",
+            clients.len()
+        );
+        for client in clients {
+            for tunnel in self.tunnel_manager.list_client_tunnels(&client.id).await {
+                let _ = self.tunnel_manager.close_tunnel(&tunnel.id).await;
+            }
+            let _ = client
+                .send_message(Message::Disconnect {
+                    reason: "The server is shutting down for maintenance".to_string(),
+                })
+                .await;
+            client.kick();
+        }
+    }
+
+    /// A client's recent log lines, after raising its log level when asked
+    async fn client_logs(
+        &self,
+        client_id: &str,
+        level: Option<String>,
+        duration_secs: u64,
+        max_lines: u32,
+    ) -> Result<Vec<String>, String> {
+        if let Some(level) = &level {
+            tracing_subscriber::EnvFilter::try_new(level)
+                .map_err(|e| format!("Invalid log level '{}': {}", level, e))?;
+        }
+        let client = self
+            .connection_manager
+            .get_client(client_id)
+            .await
+            .ok_or_else(|| format!("Client {} is not connected", client_id))?;
+        if let Some(level) = &level {
+            info!(
+                "Operator set the log level of client {} to {} for {} seconds",
+                client_id, level, duration_secs
+            );
+        }
+        client.request_logs(level, duration_secs, max_lines).await
+    }
+
+    /// Provisioned tunnels, of one client or all
+    async fn provisioned(&self, client_id: Option<&str>) -> Vec<ProvisionedSummary> {
+        let open = self.tunnel_manager.list_tunnels_with_owner().await;
+        self.connection_manager
+            .provisioning()
+            .list()
+            .into_iter()
+            .filter(|(owner, _)| client_id.is_none_or(|id| id == owner))
+            .flat_map(|(client_id, tunnels)| {
+                tunnels
+                    .into_iter()
+                    .map(move |tunnel| (client_id.clone(), tunnel))
+            })
+            .map(|(client_id, tunnel)| ProvisionedSummary {
+                open: open.iter().any(|(owner, info)| {
+                    *owner == client_id && info.name.as_deref() == Some(tunnel.name.as_str())
+                }),
+                client_id,
+                tunnel,
+            })
+            .collect()
+    }
+
+    /// Provision `tunnel` for a client and send it along if the client is
+    /// connected, returning whether it was
+    async fn provision(&self, client_id: &str, tunnel: TunnelConfig) -> bool {
+        self.connection_manager
+            .provisioning()
+            .set(client_id, tunnel.clone());
+        info!(
+            "Operator provisioned tunnel {} for client {}",
+            tunnel.name, client_id
+        );
+        match self.connection_manager.get_client(client_id).await {
+            Some(client) => client
+                .send_message(Message::ProvisionTunnel { tunnel })
+                .await
+                .is_ok(),
+            None => false,
+        }
+    }
+
+    /// Stop provisioning a tunnel and have the client close it
+    async fn unprovision(&self, client_id: &str, name: &str) -> Result<(), String> {
+        if !self
+            .connection_manager
+            .provisioning()
+            .remove(client_id, name)
+        {
+            return Err(format!(
+                "No tunnel {} is provisioned for client {}",
+                name, client_id
+            ));
+        }
+        if let Some(client) = self.connection_manager.get_client(client_id).await {
+            let _ = client
+                .send_message(Message::UnprovisionTunnel {
+                    name: name.to_string(),
+                })
+                .await;
+        }
+        info!(
+            "Operator unprovisioned tunnel {} of client {}",
+            name, client_id
+        );
+        Ok(())
+    }
+
+    /// Close a client's tunnels and drop its connection, returning how many
+    /// tunnels were closed
+    async fn rotate_token(&self, client_id: &str) -> Result<DateTime<Utc>, String> {
+        let client = self
+            .connection_manager
+            .get_client(client_id)
+            .await
+            .ok_or_else(|| format!("Client {} is not connected", client_id))?;
+        self.connection_manager
+            .rotate_token(&client)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn kick(&self, client_id: &str) -> Result<usize, String> {
+        let client = self
+            .connection_manager
+            .get_client(client_id)
+            .await
+            .ok_or_else(|| format!("Client {} is not connected", client_id))?;
+
+        let tunnels = self.tunnel_manager.list_client_tunnels(client_id).await;
+        for tunnel in &tunnels {
+            let _ = self.tunnel_manager.close_tunnel(&tunnel.id).await;
+        }
+
+        let _ = client
+            .send_message(Message::Error {
+                code: ErrorCode::PermissionDenied,
+                message: "Disconnected by operator".to_string(),
+            })
+            .await;
+        client.kick();
+        info!("Operator disconnected client {}", client_id);
+        Ok(tunnels.len())
+    }
+}
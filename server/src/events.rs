@@ -0,0 +1,63 @@
+//! Clients connecting and leaving and tunnels opening and closing, as they
+//! happen, for whoever watches the server through its APIs. Events nobody
+//! is watching are dropped.
+
+use chrono::{DateTime, Utc};
+use nat_traversal_common::protocol::TunnelInfo;
+use serde::Serialize;
+use std::net::SocketAddr;
+use tokio::sync::broadcast;
+
+/// Events a watcher may fall behind by before it misses some
+const CAPACITY: usize = 1024;
+
+/// Serialized as JSON objects with a `type` such as `client_connected`
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    ClientConnected {
+        client_id: String,
+        addr: SocketAddr,
+        at: DateTime<Utc>,
+    },
+    ClientDisconnected {
+        client_id: String,
+        at: DateTime<Utc>,
+        bytes_sent: u64,
+        bytes_received: u64,
+    },
+    TunnelOpened {
+        client_id: String,
+        tunnel: TunnelInfo,
+    },
+    TunnelClosed {
+        client_id: String,
+        /// The tunnel's traffic at the end
+        tunnel: TunnelInfo,
+        connections: u64,
+        at: DateTime<Utc>,
+    },
+}
+
+pub struct Events {
+    sender: broadcast::Sender<ServerEvent>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+        }
+    }
+}
+
+impl Events {
+    pub fn publish(&self, event: ServerEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    /// Events from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.sender.subscribe()
+    }
+}
@@ -0,0 +1,1019 @@
+//! A listener speaking enough of frp's protocol for frpc to use the server.
+//!
+//! frpc logs in with the token, asks for TCP proxies and opens a work
+//! connection each time it is asked for one. The server opens each proxy
+//! as a tunnel of a client named `frp-<id>`, so the proxies share ports,
+//! limits, the admin API and usage accounting with native tunnels. A
+//! visitor arriving on a proxy's port makes the server ask frpc for a work
+//! connection, which then carries that visitor's traffic.
+//!
+//! frpc logs in with one of the server's tokens, so the token's scope,
+//! the duplicate client ID policy and maintenance apply as for native
+//! clients. Its login, and by default its heartbeats and work connections,
+//! carry an MD5 of the token and a timestamp, which has to be within
+//! minutes of the server's clock.
+//!
+//! Connections may start with TLS, with or without frp's 0x17 first byte,
+//! and may multiplex several frp connections over yamux. Past login the
+//! control connection is encrypted with AES-128-CFB keyed by the token.
+//! Work connections are plain, so proxies asking for encryption or
+//! compression are refused, as are proxy types other than `tcp`.
+
+use crate::connection::{ClientConnection, ConnectionManager, Credential};
+use crate::server::NatServer;
+use crate::token::TokenGrant;
+use crate::tunnel::TunnelManager;
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+use aes::Aes128;
+use bytes::Bytes;
+use md5::{Digest, Md5};
+use nat_traversal_common::{
+    config::ServerFrpConfig,
+    error::{NatError, NatResult},
+    protocol::{Message, TunnelProtocol},
+    transport::Transport,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha1::Sha1;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, RwLock};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// The frps version the server reports to frpc
+const VERSION: &str = "0.61.0";
+/// Largest message body frp sends
+const MAX_MESSAGE: u64 = 10240;
+/// How long a new connection has to send its first message
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// What frpc sends before a TLS handshake unless told not to
+const TLS_FIRST_BYTE: u8 = 0x17;
+/// Salt frp derives its control connection key with
+const SALT: &[u8] = b"frp";
+/// Furthest a privilege key's timestamp may be from the server's clock
+const TIMESTAMP_WINDOW: i64 = 15 * 60;
+
+const TYPE_LOGIN: u8 = b'o';
+const TYPE_LOGIN_RESP: u8 = b'1';
+const TYPE_NEW_PROXY: u8 = b'p';
+const TYPE_NEW_PROXY_RESP: u8 = b'2';
+const TYPE_CLOSE_PROXY: u8 = b'c';
+const TYPE_NEW_WORK_CONN: u8 = b'w';
+const TYPE_REQ_WORK_CONN: u8 = b'r';
+const TYPE_START_WORK_CONN: u8 = b's';
+const TYPE_PING: u8 = b'h';
+const TYPE_PONG: u8 = b'4';
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Login {
+    version: String,
+    hostname: String,
+    privilege_key: String,
+    timestamp: i64,
+    run_id: String,
+    client_id: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct LoginResp {
+    version: String,
+    run_id: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    error: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct NewProxy {
+    proxy_name: String,
+    proxy_type: String,
+    use_encryption: bool,
+    use_compression: bool,
+    remote_port: u16,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct NewProxyResp {
+    proxy_name: String,
+    remote_addr: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    error: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct CloseProxy {
+    proxy_name: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct NewWorkConn {
+    run_id: String,
+    privilege_key: String,
+    timestamp: i64,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct ReqWorkConn {}
+
+#[derive(Debug, Default, Serialize)]
+struct StartWorkConn {
+    proxy_name: String,
+    src_addr: String,
+    dst_addr: String,
+    src_port: u16,
+    dst_port: u16,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Ping {
+    privilege_key: String,
+    timestamp: i64,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Pong {}
+
+/// What frpc proves it holds the token with: the MD5 of the token followed
+/// by the timestamp, in hex
+pub(crate) fn privilege_key(token: &str, timestamp: i64) -> String {
+    Md5::digest(format!("{}{}", token, timestamp))
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Whether `timestamp` is within `TIMESTAMP_WINDOW` of the server's clock,
+/// so a captured key soon stops working
+fn recent(timestamp: i64) -> bool {
+    (chrono::Utc::now().timestamp() - timestamp).abs() <= TIMESTAMP_WINDOW
+}
+
+/// AES-128 in CFB mode over whole blocks, as Go's `cipher.NewCFBEncrypter`
+struct Cfb {
+    aes: Aes128,
+    /// The last ciphertext block, the IV at first
+    register: [u8; 16],
+    keystream: [u8; 16],
+    position: usize,
+}
+
+impl Cfb {
+    fn new(token: &str, iv: [u8; 16]) -> Self {
+        let mut key = [0u8; 16];
+        pbkdf2::pbkdf2_hmac::<Sha1>(token.as_bytes(), SALT, 64, &mut key);
+        Self {
+            aes: Aes128::new(GenericArray::from_slice(&key)),
+            register: iv,
+            keystream: [0; 16],
+            position: 16,
+        }
+    }
+
+    fn next_keystream(&mut self) -> u8 {
+        if self.position == 16 {
+            let mut block = GenericArray::from(self.register);
+            self.aes.encrypt_block(&mut block);
+            self.keystream = block.into();
+            self.position = 0;
+        }
+        self.keystream[self.position]
+    }
+
+    fn encrypt(&mut self, data: &mut [u8]) {
+        for byte in data {
+            *byte ^= self.next_keystream();
+            self.register[self.position] = *byte;
+            self.position += 1;
+        }
+    }
+
+    fn decrypt(&mut self, data: &mut [u8]) {
+        for byte in data {
+            let cipher = *byte;
+            *byte ^= self.next_keystream();
+            self.register[self.position] = cipher;
+            self.position += 1;
+        }
+    }
+}
+
+/// Read one message: its type byte, a big-endian length and a JSON body
+async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    mut cipher: Option<&mut Cfb>,
+) -> NatResult<(u8, Vec<u8>)> {
+    let mut head = [0u8; 9];
+    reader.read_exact(&mut head).await?;
+    if let Some(cipher) = cipher.as_deref_mut() {
+        cipher.decrypt(&mut head);
+    }
+    let length = i64::from_be_bytes(head[1..].try_into().unwrap());
+    if !(0..=MAX_MESSAGE as i64).contains(&length) {
+        return Err(NatError::protocol(format!(
+            "frp message of {} bytes",
+            length
+        )));
+    }
+    let mut body = vec![0u8; length as usize];
+    reader.read_exact(&mut body).await?;
+    if let Some(cipher) = cipher {
+        cipher.decrypt(&mut body);
+    }
+    Ok((head[0], body))
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    kind: u8,
+    body: &impl Serialize,
+    cipher: Option<&mut Cfb>,
+) -> NatResult<()> {
+    let body = serde_json::to_vec(body)?;
+    let mut message = Vec::with_capacity(9 + body.len());
+    message.push(kind);
+    message.extend_from_slice(&(body.len() as i64).to_be_bytes());
+    message.extend_from_slice(&body);
+    if let Some(cipher) = cipher {
+        cipher.encrypt(&mut message);
+    }
+    writer.write_all(&message).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+fn parse<T: DeserializeOwned>(body: &[u8]) -> NatResult<T> {
+    serde_json::from_slice(body)
+        .map_err(|e| NatError::protocol(format!("Malformed frp message: {}", e)))
+}
+
+/// A visitor waiting for frpc's next work connection
+struct Visitor {
+    tunnel_id: Uuid,
+    connection_id: u32,
+    proxy_name: String,
+    addr: SocketAddr,
+    port: u16,
+    /// What the visitor sent so far; empty once it stopped sending
+    data: mpsc::UnboundedReceiver<Bytes>,
+}
+
+/// A logged-in frpc, found by its run ID when its work connections arrive
+struct Session {
+    client: Arc<ClientConnection>,
+    pending: Mutex<VecDeque<Visitor>>,
+    /// Where each visitor's data goes, until its connection closes
+    links: Mutex<HashMap<(Uuid, u32), mpsc::UnboundedSender<Bytes>>>,
+}
+
+/// A proxy frpc opened, as the tunnel behind it
+struct Proxy {
+    name: String,
+    remote_port: u16,
+}
+
+pub struct FrpServer {
+    token: String,
+    tcp_mux: bool,
+    require_additional_scopes: bool,
+    tls_acceptor: Option<TlsAcceptor>,
+    connection_manager: Arc<ConnectionManager>,
+    tunnel_manager: Arc<TunnelManager>,
+    sessions: RwLock<HashMap<String, Arc<Session>>>,
+}
+
+impl FrpServer {
+    pub fn new(
+        config: &ServerFrpConfig,
+        tls_acceptor: Option<TlsAcceptor>,
+        connection_manager: Arc<ConnectionManager>,
+        tunnel_manager: Arc<TunnelManager>,
+    ) -> Self {
+        Self {
+            token: config.token.clone(),
+            tcp_mux: config.tcp_mux,
+            require_additional_scopes: config.require_additional_scopes,
+            tls_acceptor,
+            connection_manager,
+            tunnel_manager,
+            sessions: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("frp listener stopped: {}", e);
+                    return;
+                }
+            };
+            tokio::spawn(self.clone().accept(stream, addr));
+        }
+    }
+
+    async fn accept(self: Arc<Self>, stream: TcpStream, addr: SocketAddr) {
+        let stream = match self.unwrap_tls(stream).await {
+            Ok(stream) => stream,
+            Err(e) => return debug!("frp connection from {} failed: {}", addr, e),
+        };
+        if !self.tcp_mux {
+            return self.handle(stream, addr).await;
+        }
+        let mut connection = yamux::Connection::new(
+            stream.compat(),
+            yamux::Config::default(),
+            yamux::Mode::Server,
+        );
+        loop {
+            match std::future::poll_fn(|cx| connection.poll_next_inbound(cx)).await {
+                Some(Ok(stream)) => {
+                    tokio::spawn(self.clone().handle(Box::new(stream.compat()), addr));
+                }
+                Some(Err(e)) => return debug!("frp session from {} ended: {}", addr, e),
+                None => return,
+            }
+        }
+    }
+
+    /// The connection past TLS when it starts with a handshake
+    async fn unwrap_tls(&self, mut stream: TcpStream) -> NatResult<Box<dyn Transport>> {
+        let Some(acceptor) = &self.tls_acceptor else {
+            return Ok(Box::new(stream));
+        };
+        let mut first = [0u8; 1];
+        stream.peek(&mut first).await?;
+        match first[0] {
+            TLS_FIRST_BYTE => {
+                stream.read_exact(&mut first).await?;
+            }
+            // A TLS handshake record
+            0x16 => {}
+            _ => return Ok(Box::new(stream)),
+        }
+        Ok(Box::new(acceptor.accept(stream).await?))
+    }
+
+    /// One frp connection: frpc's control connection or a work connection
+    async fn handle(self: Arc<Self>, mut stream: Box<dyn Transport>, addr: SocketAddr) {
+        let first =
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, read_message(&mut stream, None)).await {
+                Ok(Ok(first)) => first,
+                Ok(Err(e)) => return debug!("frp connection from {} failed: {}", addr, e),
+                Err(_) => return debug!("frp connection from {} sent nothing", addr),
+            };
+        let result = match first {
+            (TYPE_LOGIN, body) => match parse::<Login>(&body) {
+                Ok(login) => self.control(stream, addr, login).await,
+                Err(e) => Err(e),
+            },
+            (TYPE_NEW_WORK_CONN, body) => match parse::<NewWorkConn>(&body) {
+                Ok(work) => self.work(stream, work).await,
+                Err(e) => Err(e),
+            },
+            (kind, _) => Err(NatError::protocol(format!(
+                "Unexpected frp message type {:?}",
+                kind as char
+            ))),
+        };
+        if let Err(e) = result {
+            debug!("frp connection from {} failed: {}", addr, e);
+        }
+    }
+
+    /// Whether `key` proves the token for a recent `timestamp`
+    fn authentic(&self, key: &str, timestamp: i64) -> bool {
+        recent(timestamp) && key == privilege_key(&self.token, timestamp)
+    }
+
+    /// Whether a heartbeat or work connection with `key` may go on
+    fn authentic_scope(&self, key: &str, timestamp: i64) -> bool {
+        (key.is_empty() && !self.require_additional_scopes) || self.authentic(key, timestamp)
+    }
+
+    /// What the client logging in may do, or why it may not log in
+    async fn admit(&self, login: &Login, client_id: &str) -> Result<TokenGrant, String> {
+        if !recent(login.timestamp) {
+            warn!("frpc {} logged in with a stale timestamp", client_id);
+            return Err("Authentication failed".to_string());
+        }
+        let credential = Credential::Frp {
+            key: &login.privilege_key,
+            timestamp: login.timestamp,
+        };
+        let grant = self
+            .connection_manager
+            .authenticate(credential, client_id)
+            .await
+            .map_err(|_| "Authentication failed".to_string())?;
+        let refused = self.connection_manager.maintenance().is_some()
+            && self
+                .tunnel_manager
+                .list_client_tunnels(client_id)
+                .await
+                .is_empty();
+        if refused {
+            info!("Refusing new client {} during maintenance", client_id);
+            return Err("The server is under maintenance; try again later".to_string());
+        }
+        Ok(grant)
+    }
+
+    async fn control(
+        &self,
+        stream: Box<dyn Transport>,
+        addr: SocketAddr,
+        login: Login,
+    ) -> NatResult<()> {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let run_id = if login.run_id.is_empty() {
+            format!("{:016x}", rand::random::<u64>())
+        } else {
+            login.run_id.clone()
+        };
+        let client_id = format!(
+            "frp-{}",
+            if login.client_id.is_empty() {
+                &run_id
+            } else {
+                &login.client_id
+            }
+        );
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let admitted = match self.admit(&login, &client_id).await {
+            Ok(grant) => {
+                let mut client = ClientConnection::new(client_id.clone(), addr, tx);
+                client.set_scope(grant.scope);
+                client.set_usage(self.connection_manager.usage().account(&client_id));
+                let client = Arc::new(client);
+                match self.connection_manager.add_client(client.clone()).await {
+                    Ok(()) => {
+                        if let Some(expires_at) = grant.expires_at {
+                            client.expire_at(expires_at);
+                        }
+                        Ok(client)
+                    }
+                    Err(reason) => Err(reason),
+                }
+            }
+            Err(reason) => Err(reason),
+        };
+        let response = LoginResp {
+            version: VERSION.to_string(),
+            run_id: run_id.clone(),
+            error: admitted.as_ref().err().cloned().unwrap_or_default(),
+        };
+        write_message(&mut writer, TYPE_LOGIN_RESP, &response, None).await?;
+        let client = match admitted {
+            Ok(client) => client,
+            Err(reason) => {
+                info!("Refused frpc {} from {}: {}", client_id, addr, reason);
+                return Ok(());
+            }
+        };
+        info!(
+            "frpc {} {} logged in from {} as {}",
+            login.hostname, login.version, addr, client_id
+        );
+
+        let session = Arc::new(Session {
+            client: client.clone(),
+            pending: Mutex::new(VecDeque::new()),
+            links: Mutex::new(HashMap::new()),
+        });
+        self.sessions
+            .write()
+            .await
+            .insert(run_id.clone(), session.clone());
+
+        // Each side starts its encrypted stream with an IV of its own
+        let token = self.token.clone();
+        let (frames_tx, mut frames) = mpsc::unbounded_channel();
+        let read_task = tokio::spawn(async move {
+            let mut iv = [0u8; 16];
+            if reader.read_exact(&mut iv).await.is_err() {
+                return;
+            }
+            let mut cipher = Cfb::new(&token, iv);
+            while let Ok(frame) = read_message(&mut reader, Some(&mut cipher)).await {
+                if frames_tx.send(frame).is_err() {
+                    return;
+                }
+            }
+        });
+        let iv: [u8; 16] = rand::random();
+        let mut cipher = Cfb::new(&self.token, iv);
+
+        let mut proxies: HashMap<Uuid, Proxy> = HashMap::new();
+        let result = async {
+            writer.write_all(&iv).await?;
+            loop {
+                tokio::select! {
+                    frame = frames.recv() => {
+                        let Some((kind, body)) = frame else { break };
+                        self.on_frame(&client, kind, &body, &mut proxies, &mut writer, &mut cipher)
+                            .await?;
+                    }
+                    message = rx.recv() => {
+                        let Some(message) = message else { break };
+                        if !self
+                            .on_message(&session, message, &mut proxies, &mut writer, &mut cipher)
+                            .await?
+                        {
+                            break;
+                        }
+                    }
+                    _ = client.kicked() => break,
+                }
+            }
+            Ok::<(), NatError>(())
+        }
+        .await;
+        read_task.abort();
+
+        for tunnel_id in proxies.keys() {
+            let _ = self.tunnel_manager.close_tunnel(tunnel_id).await;
+            client.remove_tunnel(tunnel_id).await;
+        }
+        self.connection_manager.remove_client(&client).await;
+        let mut sessions = self.sessions.write().await;
+        if sessions
+            .get(&run_id)
+            .is_some_and(|current| Arc::ptr_eq(current, &session))
+        {
+            sessions.remove(&run_id);
+        }
+        info!("frpc {} disconnected", client_id);
+        result
+    }
+
+    /// Act on a message from frpc's control connection
+    async fn on_frame<W: AsyncWrite + Unpin>(
+        &self,
+        client: &Arc<ClientConnection>,
+        kind: u8,
+        body: &[u8],
+        proxies: &mut HashMap<Uuid, Proxy>,
+        writer: &mut W,
+        cipher: &mut Cfb,
+    ) -> NatResult<()> {
+        match kind {
+            TYPE_NEW_PROXY => {
+                let proxy = parse::<NewProxy>(body)?;
+                let response = match self.open_proxy(client, &proxy).await {
+                    Ok((tunnel_id, remote_port)) => {
+                        info!(
+                            "frpc {} opened proxy {} on port {}",
+                            client.id, proxy.proxy_name, remote_port
+                        );
+                        proxies.insert(
+                            tunnel_id,
+                            Proxy {
+                                name: proxy.proxy_name.clone(),
+                                remote_port,
+                            },
+                        );
+                        NewProxyResp {
+                            proxy_name: proxy.proxy_name,
+                            remote_addr: format!(":{}", remote_port),
+                            error: String::new(),
+                        }
+                    }
+                    Err(e) => {
+                        info!(
+                            "Refused proxy {} of frpc {}: {}",
+                            proxy.proxy_name, client.id, e
+                        );
+                        NewProxyResp {
+                            proxy_name: proxy.proxy_name,
+                            remote_addr: String::new(),
+                            error: e.to_string(),
+                        }
+                    }
+                };
+                write_message(writer, TYPE_NEW_PROXY_RESP, &response, Some(cipher)).await?;
+            }
+            TYPE_CLOSE_PROXY => {
+                let close = parse::<CloseProxy>(body)?;
+                let found = proxies
+                    .iter()
+                    .find(|(_, proxy)| proxy.name == close.proxy_name)
+                    .map(|(tunnel_id, _)| *tunnel_id);
+                if let Some(tunnel_id) = found {
+                    proxies.remove(&tunnel_id);
+                    let _ = self.tunnel_manager.close_tunnel(&tunnel_id).await;
+                    client.remove_tunnel(&tunnel_id).await;
+                    info!("frpc {} closed proxy {}", client.id, close.proxy_name);
+                }
+            }
+            TYPE_PING => {
+                let ping = parse::<Ping>(body)?;
+                if !self.authentic_scope(&ping.privilege_key, ping.timestamp) {
+                    return Err(NatError::authentication(
+                        "frp heartbeat failed authentication",
+                    ));
+                }
+                write_message(writer, TYPE_PONG, &Pong {}, Some(cipher)).await?;
+            }
+            kind => debug!(
+                "Ignoring frp message type {:?} from {}",
+                kind as char, client.id
+            ),
+        }
+        Ok(())
+    }
+
+    /// Open a proxy as a tunnel; its ID and public port
+    async fn open_proxy(
+        &self,
+        client: &Arc<ClientConnection>,
+        proxy: &NewProxy,
+    ) -> NatResult<(Uuid, u16)> {
+        if proxy.proxy_type != "tcp" {
+            return Err(NatError::tunnel(format!(
+                "Proxy type {} is not supported; only tcp is",
+                proxy.proxy_type
+            )));
+        }
+        if proxy.use_encryption || proxy.use_compression {
+            return Err(NatError::tunnel(
+                "Proxies with encryption or compression are not supported",
+            ));
+        }
+        let name = Some(proxy.proxy_name.as_str());
+        NatServer::check_maintenance(&self.connection_manager, &self.tunnel_manager, client, name)
+            .await?;
+        let remote_port = (proxy.remote_port != 0).then_some(proxy.remote_port);
+        NatServer::check_scope(client, TunnelProtocol::Tcp, remote_port).await?;
+        let tunnel = self
+            .tunnel_manager
+            .create_tunnel(
+                client.id.clone(),
+                "frpc".to_string(),
+                0,
+                remote_port,
+                1,
+                TunnelProtocol::Tcp,
+                Some(proxy.proxy_name.clone()),
+                None,
+                None,
+                None,
+                None,
+                false,
+                false,
+                None,
+                None,
+                None,
+                &client.scope.ports,
+            )
+            .await?;
+        client.add_tunnel(tunnel.clone()).await;
+        Ok((tunnel.id, tunnel.remote_port))
+    }
+
+    /// Act on what the tunnel manager sends the client. False once the
+    /// session is over.
+    async fn on_message<W: AsyncWrite + Unpin>(
+        &self,
+        session: &Session,
+        message: Message,
+        proxies: &mut HashMap<Uuid, Proxy>,
+        writer: &mut W,
+        cipher: &mut Cfb,
+    ) -> NatResult<bool> {
+        match message {
+            Message::NewConnection {
+                tunnel_id,
+                connection_id,
+                client_addr,
+                port_offset,
+            } => {
+                let Some(proxy) = proxies.get(&tunnel_id) else {
+                    self.tunnel_manager
+                        .close_connection(&tunnel_id, connection_id)
+                        .await;
+                    return Ok(true);
+                };
+                let (data_tx, data) = mpsc::unbounded_channel();
+                session
+                    .links
+                    .lock()
+                    .unwrap()
+                    .insert((tunnel_id, connection_id), data_tx);
+                session.pending.lock().unwrap().push_back(Visitor {
+                    tunnel_id,
+                    connection_id,
+                    proxy_name: proxy.name.clone(),
+                    addr: client_addr,
+                    port: proxy.remote_port + port_offset,
+                    data,
+                });
+                write_message(writer, TYPE_REQ_WORK_CONN, &ReqWorkConn {}, Some(cipher)).await?;
+            }
+            Message::Data {
+                tunnel_id,
+                data,
+                connection_id,
+            } => {
+                if let Some(link) = session
+                    .links
+                    .lock()
+                    .unwrap()
+                    .get(&(tunnel_id, connection_id))
+                {
+                    let _ = link.send(data);
+                }
+            }
+            Message::ConnectionShutdown {
+                tunnel_id,
+                connection_id,
+                ..
+            } => {
+                if let Some(link) = session
+                    .links
+                    .lock()
+                    .unwrap()
+                    .get(&(tunnel_id, connection_id))
+                {
+                    let _ = link.send(Bytes::new());
+                }
+            }
+            Message::ConnectionClosed {
+                tunnel_id,
+                connection_id,
+            } => {
+                session
+                    .links
+                    .lock()
+                    .unwrap()
+                    .remove(&(tunnel_id, connection_id));
+            }
+            Message::TunnelClosed { tunnel_id, reason } => {
+                if let Some(proxy) = proxies.remove(&tunnel_id) {
+                    session.client.remove_tunnel(&tunnel_id).await;
+                    info!(
+                        "Proxy {} of frpc {} closed: {}",
+                        proxy.name, session.client.id, reason
+                    );
+                }
+            }
+            Message::Disconnect { reason } => {
+                info!("Disconnecting frpc {}: {}", session.client.id, reason);
+                return Ok(false);
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    /// Carry the oldest waiting visitor over a work connection
+    async fn work(&self, mut stream: Box<dyn Transport>, work: NewWorkConn) -> NatResult<()> {
+        let Some(session) = self.sessions.read().await.get(&work.run_id).cloned() else {
+            return Err(NatError::protocol(format!(
+                "Work connection for unknown frpc run {}",
+                work.run_id
+            )));
+        };
+        if !self.authentic_scope(&work.privilege_key, work.timestamp) {
+            return Err(NatError::authentication(
+                "frp work connection failed authentication",
+            ));
+        }
+        let visitor = {
+            let links = session.links.lock().unwrap();
+            let mut pending = session.pending.lock().unwrap();
+            std::iter::from_fn(|| pending.pop_front())
+                .find(|visitor| links.contains_key(&(visitor.tunnel_id, visitor.connection_id)))
+        };
+        let Some(mut visitor) = visitor else {
+            debug!("No visitor waiting for frpc {}", session.client.id);
+            return Ok(());
+        };
+        let start = StartWorkConn {
+            proxy_name: visitor.proxy_name.clone(),
+            src_addr: visitor.addr.ip().to_string(),
+            dst_addr: String::new(),
+            src_port: visitor.addr.port(),
+            dst_port: visitor.port,
+        };
+        let (tunnel_id, connection_id) = (visitor.tunnel_id, visitor.connection_id);
+        let tunnel_manager = &self.tunnel_manager;
+        let result = async {
+            if write_message(&mut stream, TYPE_START_WORK_CONN, &start, None)
+                .await
+                .is_err()
+            {
+                return false;
+            }
+            let (mut reader, mut writer) = tokio::io::split(stream);
+
+            // Each half says whether it ended cleanly
+            let upstream = async {
+                let mut buffer = vec![0u8; 16 * 1024];
+                loop {
+                    match reader.read(&mut buffer).await {
+                        Ok(0) => {
+                            tunnel_manager
+                                .shutdown_connection(&tunnel_id, connection_id)
+                                .await;
+                            return true;
+                        }
+                        Ok(n) => {
+                            let data = Bytes::copy_from_slice(&buffer[..n]);
+                            if tunnel_manager
+                                .forward_data(&tunnel_id, connection_id, data)
+                                .await
+                                .is_err()
+                            {
+                                return false;
+                            }
+                        }
+                        Err(_) => return false,
+                    }
+                }
+            };
+            let downstream = async {
+                while let Some(data) = visitor.data.recv().await {
+                    if data.is_empty() {
+                        return writer.shutdown().await.is_ok();
+                    }
+                    if writer.write_all(&data).await.is_err() {
+                        return false;
+                    }
+                }
+                // The connection was closed
+                false
+            };
+            tokio::pin!(upstream, downstream);
+            let (mut upstream_done, mut downstream_done) = (false, false);
+            while !(upstream_done && downstream_done) {
+                let clean = tokio::select! {
+                    clean = &mut upstream, if !upstream_done => {
+                        upstream_done = true;
+                        clean
+                    }
+                    clean = &mut downstream, if !downstream_done => {
+                        downstream_done = true;
+                        clean
+                    }
+                };
+                if !clean {
+                    return false;
+                }
+            }
+            true
+        }
+        .await;
+        if !result {
+            tunnel_manager
+                .close_connection(&tunnel_id, connection_id)
+                .await;
+        }
+        session
+            .links
+            .lock()
+            .unwrap()
+            .remove(&(tunnel_id, connection_id));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IV: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+    const PLAIN: &[u8] = br#"{"proxy_name":"ssh","proxy_type":"tcp","remote_port":8022}"#;
+    /// `PLAIN` under the token "frp-secret" and `IV`, from another
+    /// implementation of AES-128-CFB
+    const CIPHER: &str = "957960aa8695df84bd02fa859196e8594c45dca6719a2915281f72dc84d8df46\
+                          3efa473abbd4e532018562e93876bad49444e898428f3502b193";
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn frame(kind: u8, length: i64, body: &[u8]) -> Vec<u8> {
+        let mut frame = vec![kind];
+        frame.extend_from_slice(&length.to_be_bytes());
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    #[test]
+    fn test_privilege_key() {
+        assert_eq!(
+            privilege_key("frp-secret", 1_700_000_000),
+            "58f9b7a6d3e28e496b247b639fe94713"
+        );
+        assert_ne!(
+            privilege_key("frp-secret", 1_700_000_001),
+            privilege_key("frp-secret", 1_700_000_000)
+        );
+    }
+
+    #[test]
+    fn test_recent_timestamps() {
+        let now = chrono::Utc::now().timestamp();
+        assert!(recent(now));
+        assert!(recent(now - 60));
+        assert!(!recent(now - TIMESTAMP_WINDOW - 60));
+        assert!(!recent(now + TIMESTAMP_WINDOW + 60));
+    }
+
+    #[test]
+    fn test_cfb_known_answer() {
+        // Uneven pieces carry the keystream across block boundaries
+        let mut data = PLAIN.to_vec();
+        let mut cipher = Cfb::new("frp-secret", IV);
+        let (first, rest) = data.split_at_mut(5);
+        let (second, third) = rest.split_at_mut(20);
+        cipher.encrypt(first);
+        cipher.encrypt(second);
+        cipher.encrypt(third);
+        assert_eq!(hex(&data), CIPHER);
+
+        let mut cipher = Cfb::new("frp-secret", IV);
+        let (first, rest) = data.split_at_mut(16);
+        cipher.decrypt(first);
+        cipher.decrypt(rest);
+        assert_eq!(data, PLAIN);
+
+        let mut data = PLAIN.to_vec();
+        Cfb::new("other", IV).encrypt(&mut data);
+        assert_ne!(hex(&data), CIPHER);
+    }
+
+    #[tokio::test]
+    async fn test_message_round_trip() {
+        let ping = serde_json::json!({ "privilege_key": "abc", "timestamp": 7 });
+        let mut plain = Vec::new();
+        write_message(&mut plain, TYPE_PING, &ping, None)
+            .await
+            .unwrap();
+        assert_eq!(plain[0], TYPE_PING);
+        let (kind, body) = read_message(&mut plain.as_slice(), None).await.unwrap();
+        assert_eq!(kind, TYPE_PING);
+        let parsed: Ping = parse(&body).unwrap();
+        assert_eq!(
+            (parsed.privilege_key.as_str(), parsed.timestamp),
+            ("abc", 7)
+        );
+
+        let mut encrypted = Vec::new();
+        let mut cipher = Cfb::new("frp-secret", IV);
+        write_message(&mut encrypted, TYPE_PING, &ping, Some(&mut cipher))
+            .await
+            .unwrap();
+        write_message(&mut encrypted, TYPE_PONG, &Pong {}, Some(&mut cipher))
+            .await
+            .unwrap();
+        assert_ne!(encrypted[..plain.len()], plain[..]);
+        let mut reader = encrypted.as_slice();
+        let mut cipher = Cfb::new("frp-secret", IV);
+        let first = read_message(&mut reader, Some(&mut cipher)).await.unwrap();
+        let second = read_message(&mut reader, Some(&mut cipher)).await.unwrap();
+        assert_eq!(first, (kind, body));
+        assert_eq!(second, (TYPE_PONG, b"{}".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_messages() {
+        let negative = frame(TYPE_PING, -1, b"{}");
+        assert!(read_message(&mut negative.as_slice(), None).await.is_err());
+
+        let oversized = frame(TYPE_PING, MAX_MESSAGE as i64 + 1, &[b' '; 16]);
+        assert!(read_message(&mut oversized.as_slice(), None).await.is_err());
+
+        let truncated_body = frame(TYPE_PING, 10, b"{}");
+        assert!(read_message(&mut truncated_body.as_slice(), None)
+            .await
+            .is_err());
+
+        let truncated_head = &frame(TYPE_PING, 2, b"{}")[..5];
+        assert!(read_message(&mut &truncated_head[..], None).await.is_err());
+
+        let largest = frame(
+            TYPE_PING,
+            MAX_MESSAGE as i64,
+            &vec![b' '; MAX_MESSAGE as usize],
+        );
+        let (_, body) = read_message(&mut largest.as_slice(), None).await.unwrap();
+        assert_eq!(body.len(), MAX_MESSAGE as usize);
+
+        assert!(parse::<Ping>(b"{\"timestamp\":").is_err());
+        assert!(parse::<Ping>(b"{\"timestamp\":\"soon\"}").is_err());
+    }
+}
@@ -0,0 +1,463 @@
+//! The administration API of `proto/admin.proto` over gRPC.
+//!
+//! Each call is turned into the inspect request `nat-server inspect` would
+//! send, so the API and the control socket always do the same thing;
+//! observer tokens get the same read-only requests as on the observer
+//! socket. `WatchEvents` streams the server's [`Events`](crate::events).
+
+use crate::control::{
+    ClientCounters, ClientSummary, InspectRequest, InspectResponse, Inspector, ProvisionedSummary,
+    TunnelCounters,
+};
+use crate::events::ServerEvent;
+use crate::usage::UsageSummary;
+use chrono::{DateTime, Utc};
+use nat_traversal_common::{
+    config::{GrpcConfig, TunnelConfig},
+    crypto,
+    protocol::{Gauge, TunnelInfo, TunnelProtocol},
+};
+use std::pin::Pin;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::{
+    errors::BroadcastStreamRecvError, BroadcastStream, TcpListenerStream,
+};
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+pub mod proto {
+    tonic::include_proto!("nat_traversal.admin.v1");
+}
+
+use proto::admin_server::{Admin, AdminServer};
+
+/// Answer API calls on `listener` until the process exits
+pub async fn serve(listener: TcpListener, config: GrpcConfig, inspector: Inspector) {
+    let service = AdminService {
+        inspector,
+        tokens: config.tokens,
+        observer_tokens: config.observer_tokens,
+    };
+    let result = tonic::transport::Server::builder()
+        .add_service(AdminServer::new(service))
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await;
+    if let Err(e) = result {
+        error!("gRPC API stopped: {}", e);
+    }
+}
+
+struct AdminService {
+    inspector: Inspector,
+    tokens: Vec<String>,
+    observer_tokens: Vec<String>,
+}
+
+impl AdminService {
+    /// Check the call's bearer token, which must be a full one unless the
+    /// call only looks at the server
+    fn authorize<T>(&self, request: &Request<T>, read_only: bool) -> Result<(), Status> {
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("Missing bearer token"))?;
+        // Compared as hashes so the time taken says nothing about the tokens
+        let hash = crypto::hash_token(token);
+        let known = |tokens: &[String]| tokens.iter().any(|t| crypto::verify_token(t, &hash));
+        if known(&self.tokens) {
+            Ok(())
+        } else if known(&self.observer_tokens) {
+            if read_only {
+                Ok(())
+            } else {
+                Err(Status::permission_denied(
+                    "Observers may only view the server",
+                ))
+            }
+        } else {
+            warn!("Refused a gRPC call with an unknown token");
+            Err(Status::unauthenticated("Unknown token"))
+        }
+    }
+
+    async fn inspect<T>(
+        &self,
+        request: &Request<T>,
+        inspect: InspectRequest,
+    ) -> Result<InspectResponse, Status> {
+        self.authorize(request, inspect.read_only())?;
+        match self.inspector.handle(inspect).await {
+            InspectResponse::Error(e) => Err(Status::failed_precondition(e)),
+            response => Ok(response),
+        }
+    }
+
+    /// Run a request that changes something and report what it did
+    async fn act<T>(
+        &self,
+        request: &Request<T>,
+        inspect: InspectRequest,
+    ) -> Result<Response<proto::ActionResponse>, Status> {
+        match self.inspect(request, inspect).await? {
+            InspectResponse::Done(message) => Ok(Response::new(proto::ActionResponse { message })),
+            _ => Err(unexpected()),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    type WatchEventsStream = Pin<Box<dyn Stream<Item = Result<proto::Event, Status>> + Send>>;
+
+    async fn list_clients(
+        &self,
+        request: Request<proto::ListClientsRequest>,
+    ) -> Result<Response<proto::ListClientsResponse>, Status> {
+        match self.inspect(&request, InspectRequest::Clients).await? {
+            InspectResponse::Clients(clients) => Ok(Response::new(proto::ListClientsResponse {
+                clients: clients.into_iter().map(client).collect(),
+            })),
+            _ => Err(unexpected()),
+        }
+    }
+
+    async fn list_tunnels(
+        &self,
+        request: Request<proto::ListTunnelsRequest>,
+    ) -> Result<Response<proto::ListTunnelsResponse>, Status> {
+        let client_id = request.get_ref().client_id.clone();
+        match self
+            .inspect(&request, InspectRequest::Tunnels { client_id })
+            .await?
+        {
+            InspectResponse::Tunnels(tunnels) => Ok(Response::new(proto::ListTunnelsResponse {
+                tunnels: tunnels
+                    .into_iter()
+                    .map(|summary| tunnel(summary.client_id, summary.tunnel))
+                    .collect(),
+            })),
+            _ => Err(unexpected()),
+        }
+    }
+
+    async fn list_provisioned(
+        &self,
+        request: Request<proto::ListProvisionedRequest>,
+    ) -> Result<Response<proto::ListProvisionedResponse>, Status> {
+        let client_id = request.get_ref().client_id.clone();
+        match self
+            .inspect(&request, InspectRequest::Provisioned { client_id })
+            .await?
+        {
+            InspectResponse::Provisioned(tunnels) => {
+                Ok(Response::new(proto::ListProvisionedResponse {
+                    tunnels: tunnels.into_iter().map(provisioned).collect(),
+                }))
+            }
+            _ => Err(unexpected()),
+        }
+    }
+
+    async fn create_tunnel(
+        &self,
+        request: Request<proto::CreateTunnelRequest>,
+    ) -> Result<Response<proto::ActionResponse>, Status> {
+        let client_id = request.get_ref().client_id.clone();
+        let spec = request
+            .get_ref()
+            .tunnel
+            .clone()
+            .ok_or_else(|| Status::invalid_argument("No tunnel given"))?;
+        let tunnel = Box::new(tunnel_config(spec)?);
+        self.act(&request, InspectRequest::Provision { client_id, tunnel })
+            .await
+    }
+
+    async fn delete_tunnel(
+        &self,
+        request: Request<proto::DeleteTunnelRequest>,
+    ) -> Result<Response<proto::ActionResponse>, Status> {
+        let proto::DeleteTunnelRequest { client_id, name } = request.get_ref().clone();
+        self.act(&request, InspectRequest::Unprovision { client_id, name })
+            .await
+    }
+
+    async fn close_tunnel(
+        &self,
+        request: Request<proto::CloseTunnelRequest>,
+    ) -> Result<Response<proto::ActionResponse>, Status> {
+        let tunnel_id: Uuid = request
+            .get_ref()
+            .tunnel_id
+            .parse()
+            .map_err(|e| Status::invalid_argument(format!("Invalid tunnel ID: {}", e)))?;
+        self.act(&request, InspectRequest::CloseTunnel { tunnel_id })
+            .await
+    }
+
+    async fn kick_client(
+        &self,
+        request: Request<proto::KickClientRequest>,
+    ) -> Result<Response<proto::ActionResponse>, Status> {
+        let client_id = request.get_ref().client_id.clone();
+        self.act(&request, InspectRequest::Kick { client_id }).await
+    }
+
+    async fn get_stats(
+        &self,
+        request: Request<proto::GetStatsRequest>,
+    ) -> Result<Response<proto::Stats>, Status> {
+        let InspectResponse::Top(top) = self.inspect(&request, InspectRequest::Top).await? else {
+            return Err(unexpected());
+        };
+        let InspectResponse::Capacity(capacity) =
+            self.inspect(&request, InspectRequest::Capacity).await?
+        else {
+            return Err(unexpected());
+        };
+        Ok(Response::new(proto::Stats {
+            taken_at: Some(timestamp(top.taken_at)),
+            clients: top.clients.into_iter().map(client_counters).collect(),
+            tunnels: top.tunnels.into_iter().map(tunnel_counters).collect(),
+            tunnels_in_use: Some(gauge(capacity.tunnels)),
+            connections_in_use: Some(gauge(capacity.connections)),
+            ports_in_use: Some(gauge(capacity.ports)),
+        }))
+    }
+
+    async fn get_usage(
+        &self,
+        request: Request<proto::GetUsageRequest>,
+    ) -> Result<Response<proto::GetUsageResponse>, Status> {
+        match self.inspect(&request, InspectRequest::Usage).await? {
+            InspectResponse::Usage(usage) => Ok(Response::new(proto::GetUsageResponse {
+                clients: usage.into_iter().map(usage_message).collect(),
+            })),
+            _ => Err(unexpected()),
+        }
+    }
+
+    async fn watch_events(
+        &self,
+        request: Request<proto::WatchEventsRequest>,
+    ) -> Result<Response<Self::WatchEventsStream>, Status> {
+        self.authorize(&request, true)?;
+        info!("gRPC watcher subscribed to events");
+        let events = self.inspector.connection_manager.events().subscribe();
+        // A watcher that falls behind loses its place; the stream ends so
+        // it can list the server again and watch anew
+        let stream = BroadcastStream::new(events).map(|event| match event {
+            Ok(event) => Ok(event_message(event)),
+            Err(BroadcastStreamRecvError::Lagged(missed)) => Err(Status::data_loss(format!(
+                "Fell behind by {} events",
+                missed
+            ))),
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+fn unexpected() -> Status {
+    Status::internal("Unexpected response to the request")
+}
+
+fn timestamp(at: DateTime<Utc>) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: at.timestamp(),
+        nanos: at.timestamp_subsec_nanos() as i32,
+    }
+}
+
+fn protocol(protocol: TunnelProtocol) -> i32 {
+    match protocol {
+        TunnelProtocol::Tcp => proto::Protocol::Tcp as i32,
+        TunnelProtocol::Udp => proto::Protocol::Udp as i32,
+    }
+}
+
+fn client(client: ClientSummary) -> proto::Client {
+    proto::Client {
+        id: client.id,
+        addr: client.addr.to_string(),
+        connected_at: Some(timestamp(client.connected_at)),
+        tunnels: client.tunnels as u32,
+        data_channels: client.data_channels as u32,
+        relays: client.relays as u32,
+    }
+}
+
+fn tunnel(client_id: String, tunnel: TunnelInfo) -> proto::Tunnel {
+    proto::Tunnel {
+        id: tunnel.id.to_string(),
+        client_id,
+        name: tunnel.name,
+        protocol: protocol(tunnel.protocol),
+        local_host: tunnel.local_host,
+        local_port: tunnel.local_port.into(),
+        remote_port: tunnel.remote_port.into(),
+        port_count: tunnel.port_count.into(),
+        created_at: Some(timestamp(tunnel.created_at)),
+        bytes_sent: tunnel.bytes_sent,
+        bytes_received: tunnel.bytes_received,
+        active_connections: tunnel.active_connections,
+        domain: tunnel.domain,
+        expires_at: tunnel.expires_at.map(timestamp),
+    }
+}
+
+fn provisioned(summary: ProvisionedSummary) -> proto::ProvisionedTunnel {
+    let tunnel = summary.tunnel;
+    proto::ProvisionedTunnel {
+        client_id: summary.client_id,
+        open: summary.open,
+        tunnel: Some(proto::TunnelSpec {
+            name: tunnel.name,
+            local_host: tunnel.local_host,
+            local_port: tunnel.local_port.into(),
+            remote_port: tunnel.remote_port.map(u32::from),
+            port_count: tunnel.port_count.into(),
+            protocol: protocol(tunnel.protocol),
+            ttl_secs: tunnel.ttl_secs,
+        }),
+    }
+}
+
+fn client_counters(client: ClientCounters) -> proto::ClientCounters {
+    proto::ClientCounters {
+        id: client.id,
+        addr: client.addr.to_string(),
+        connected_at: Some(timestamp(client.connected_at)),
+        tunnels: client.tunnels as u32,
+        bytes_sent: client.bytes_sent,
+        bytes_received: client.bytes_received,
+    }
+}
+
+fn tunnel_counters(counters: TunnelCounters) -> proto::TunnelCounters {
+    proto::TunnelCounters {
+        tunnel: Some(tunnel(counters.client_id, counters.tunnel)),
+        connections: counters.connections,
+    }
+}
+
+fn gauge(gauge: Gauge) -> proto::Gauge {
+    proto::Gauge {
+        used: gauge.used,
+        limit: gauge.limit,
+    }
+}
+
+fn usage_message(usage: UsageSummary) -> proto::Usage {
+    proto::Usage {
+        client_id: usage.client_id,
+        month: usage.month,
+        bytes: usage.bytes,
+        quota_bytes: usage.quota_bytes,
+    }
+}
+
+fn event_message(event: ServerEvent) -> proto::Event {
+    use proto::event::Kind;
+    let (at, kind) = match event {
+        ServerEvent::ClientConnected {
+            client_id,
+            addr,
+            at,
+        } => (
+            at,
+            Kind::ClientConnected(proto::ClientConnected {
+                client_id,
+                addr: addr.to_string(),
+            }),
+        ),
+        ServerEvent::ClientDisconnected {
+            client_id,
+            at,
+            bytes_sent,
+            bytes_received,
+        } => (
+            at,
+            Kind::ClientDisconnected(proto::ClientDisconnected {
+                client_id,
+                bytes_sent,
+                bytes_received,
+            }),
+        ),
+        ServerEvent::TunnelOpened {
+            client_id,
+            tunnel: info,
+        } => (
+            info.created_at,
+            Kind::TunnelOpened(proto::TunnelOpened {
+                tunnel: Some(tunnel(client_id, info)),
+            }),
+        ),
+        ServerEvent::TunnelClosed {
+            client_id,
+            tunnel: info,
+            connections,
+            at,
+        } => (
+            at,
+            Kind::TunnelClosed(proto::TunnelClosed {
+                tunnel: Some(tunnel(client_id, info)),
+                connections,
+            }),
+        ),
+    };
+    proto::Event {
+        at: Some(timestamp(at)),
+        kind: Some(kind),
+    }
+}
+
+/// The tunnel `spec` describes, as the server provisions it
+fn tunnel_config(spec: proto::TunnelSpec) -> Result<TunnelConfig, Status> {
+    let port = |value: u32, name: &str| {
+        u16::try_from(value)
+            .map_err(|_| Status::invalid_argument(format!("{} {} is not a port", name, value)))
+    };
+    if spec.name.is_empty() {
+        return Err(Status::invalid_argument("The tunnel needs a name"));
+    }
+    let protocol = match proto::Protocol::try_from(spec.protocol) {
+        Ok(proto::Protocol::Tcp) => TunnelProtocol::Tcp,
+        Ok(proto::Protocol::Udp) => TunnelProtocol::Udp,
+        Err(_) => return Err(Status::invalid_argument("Unknown protocol")),
+    };
+    Ok(TunnelConfig {
+        name: spec.name,
+        local_host: if spec.local_host.is_empty() {
+            "127.0.0.1".to_string()
+        } else {
+            spec.local_host
+        },
+        local_port: port(spec.local_port, "local_port")?,
+        remote_port: spec
+            .remote_port
+            .map(|value| port(value, "remote_port"))
+            .transpose()?,
+        port_count: port(spec.port_count, "port_count")?.max(1),
+        protocol,
+        auto_start: true,
+        ttl_secs: spec.ttl_secs,
+        schedule: Vec::new(),
+        domain: None,
+        http_auth: None,
+        tls_cert: None,
+        tls_key: None,
+        tls_passthrough: false,
+        https_redirect: false,
+        http_headers: None,
+        port_mapping: false,
+        e2e_peers: Vec::new(),
+        stages: Vec::new(),
+        dscp: None,
+        udp: None,
+        source: None,
+    })
+}
@@ -0,0 +1,421 @@
+use crate::config::{InspectAction, MaintenanceMode};
+use crate::control::{self, InspectRequest, InspectResponse};
+use chrono::{DateTime, Utc};
+use nat_traversal_common::config::{ServerConfig, TunnelConfig};
+
+/// Send `action` to the running server and print the answer
+pub async fn run(
+    config: &ServerConfig,
+    action: InspectAction,
+    json: bool,
+    observer_token: Option<&str>,
+) -> anyhow::Result<()> {
+    let request_body = match action {
+        InspectAction::Clients => InspectRequest::Clients,
+        InspectAction::Tunnels { client } => InspectRequest::Tunnels { client_id: client },
+        InspectAction::Connections { tunnel } => InspectRequest::Connections { tunnel_id: tunnel },
+        InspectAction::CloseTunnel { tunnel_id } => InspectRequest::CloseTunnel { tunnel_id },
+        InspectAction::Shares => InspectRequest::Shares,
+        InspectAction::CloseShare { share_id } => InspectRequest::CloseShare { share_id },
+        InspectAction::Kick { client_id } => InspectRequest::Kick { client_id },
+        InspectAction::RotateToken { client_id } => InspectRequest::RotateToken { client_id },
+        InspectAction::History {
+            kind,
+            client,
+            limit,
+        } => InspectRequest::History {
+            kind,
+            client_id: client,
+            limit,
+        },
+        InspectAction::Usage => InspectRequest::Usage,
+        InspectAction::Capture {
+            tunnel_id,
+            path,
+            connection,
+            format,
+            max_mb,
+            redact,
+        } => InspectRequest::Capture {
+            tunnel_id,
+            connection_id: connection,
+            // The server opens the file, from its own working directory
+            path: std::path::absolute(&path)?,
+            format,
+            max_bytes: max_mb.saturating_mul(1024 * 1024),
+            redact,
+        },
+        InspectAction::CaptureStop { tunnel_id } => InspectRequest::StopCapture { tunnel_id },
+        InspectAction::Provisioned { client } => InspectRequest::Provisioned { client_id: client },
+        InspectAction::Provision {
+            client_id,
+            name,
+            local_port,
+            local_host,
+            remote_port,
+            protocol,
+        } => InspectRequest::Provision {
+            client_id,
+            tunnel: Box::new(TunnelConfig {
+                name,
+                local_host,
+                local_port,
+                remote_port,
+                port_count: 1,
+                protocol,
+                auto_start: true,
+                ttl_secs: None,
+                schedule: Vec::new(),
+                domain: None,
+                http_auth: None,
+                tls_cert: None,
+                tls_key: None,
+                tls_passthrough: false,
+                https_redirect: false,
+                http_headers: None,
+                port_mapping: false,
+                e2e_peers: Vec::new(),
+                stages: Vec::new(),
+                dscp: None,
+                udp: None,
+                source: None,
+            }),
+        },
+        InspectAction::Unprovision { client_id, name } => {
+            InspectRequest::Unprovision { client_id, name }
+        }
+        InspectAction::ClientLogs {
+            client_id,
+            level,
+            secs,
+            lines,
+        } => InspectRequest::ClientLogs {
+            client_id,
+            level,
+            duration_secs: secs,
+            max_lines: lines,
+        },
+        InspectAction::Capacity => InspectRequest::Capacity,
+        InspectAction::Maintenance { mode, drain_secs } => InspectRequest::Maintenance {
+            enable: mode.map(|mode| matches!(mode, MaintenanceMode::On)),
+            drain_secs,
+        },
+    };
+    let response = control::send(config, observer_token, request_body).await?;
+
+    if let InspectResponse::Error(message) = response {
+        return Err(anyhow::anyhow!(message));
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&response)?);
+        return Ok(());
+    }
+
+    let now = Utc::now();
+    match response {
+        InspectResponse::Clients(clients) => print_table(
+            &["CLIENT", "ADDRESS", "UPTIME", "TUNNELS", "DATA", "RELAYS"],
+            clients
+                .into_iter()
+                .map(|c| {
+                    vec![
+                        c.id,
+                        c.addr.to_string(),
+                        format_age(now, c.connected_at),
+                        c.tunnels.to_string(),
+                        c.data_channels.to_string(),
+                        c.relays.to_string(),
+                    ]
+                })
+                .collect(),
+        ),
+        InspectResponse::Tunnels(tunnels) => print_table(
+            &[
+                "TUNNEL", "CLIENT", "NAME", "PORT", "LOCAL", "PROTO", "UPTIME", "CONNS",
+            ],
+            tunnels
+                .into_iter()
+                .map(|t| {
+                    vec![
+                        t.tunnel.id.to_string(),
+                        t.client_id,
+                        t.tunnel.name.clone().unwrap_or_else(|| "-".to_string()),
+                        match &t.tunnel.domain {
+                            Some(domain) => format!("{} ({})", t.tunnel.remote_port, domain),
+                            None => t.tunnel.remote_ports().to_string(),
+                        },
+                        format!("{}:{}", t.tunnel.local_host, t.tunnel.local_ports()),
+                        t.tunnel.protocol.to_string(),
+                        format_age(now, t.tunnel.created_at),
+                        t.tunnel.active_connections.to_string(),
+                    ]
+                })
+                .collect(),
+        ),
+        InspectResponse::Connections(connections) => print_table(
+            &["TUNNEL", "ID", "PEER", "AGE"],
+            connections
+                .into_iter()
+                .map(|c| {
+                    vec![
+                        c.tunnel_id.to_string(),
+                        c.connection_id.to_string(),
+                        c.peer.to_string(),
+                        format_age(now, c.opened_at),
+                    ]
+                })
+                .collect(),
+        ),
+        InspectResponse::Shares(shares) => print_table(
+            &["SHARE", "CLIENT", "TUNNEL", "PORT", "PASSWORD", "EXPIRES"],
+            shares
+                .into_iter()
+                .map(|s| {
+                    vec![
+                        s.share.id.to_string(),
+                        s.client_id,
+                        s.share.tunnel_id.to_string(),
+                        s.share.port.to_string(),
+                        if s.share.password_protected {
+                            "yes"
+                        } else {
+                            "no"
+                        }
+                        .to_string(),
+                        format_age(s.share.expires_at, now),
+                    ]
+                })
+                .collect(),
+        ),
+        InspectResponse::ClientHistory(clients) => print_table(
+            &[
+                "CLIENT",
+                "FIRST SEEN",
+                "LAST SEEN",
+                "LAST ADDRESS",
+                "SESSIONS",
+            ],
+            clients
+                .into_iter()
+                .map(|c| {
+                    vec![
+                        c.client_id,
+                        format_time(c.first_seen),
+                        format_time(c.last_seen),
+                        c.last_addr,
+                        c.sessions.to_string(),
+                    ]
+                })
+                .collect(),
+        ),
+        InspectResponse::SessionHistory(sessions) => print_table(
+            &[
+                "CLIENT",
+                "ADDRESS",
+                "CONNECTED",
+                "DURATION",
+                "SENT",
+                "RECEIVED",
+            ],
+            sessions
+                .into_iter()
+                .map(|s| {
+                    vec![
+                        s.client_id,
+                        s.addr,
+                        format_time(s.connected_at),
+                        match s.disconnected_at {
+                            Some(at) => format_age(at, s.connected_at),
+                            None => format!("{} (open)", format_age(now, s.connected_at)),
+                        },
+                        format_bytes(s.bytes_sent),
+                        format_bytes(s.bytes_received),
+                    ]
+                })
+                .collect(),
+        ),
+        InspectResponse::TunnelHistory(tunnels) => print_table(
+            &[
+                "TUNNEL", "CLIENT", "NAME", "PORT", "LOCAL", "PROTO", "CREATED", "DURATION",
+                "SENT", "RECEIVED",
+            ],
+            tunnels
+                .into_iter()
+                .map(|t| {
+                    let ports = if t.port_count > 1 {
+                        format!(
+                            "{}-{}",
+                            t.remote_port,
+                            t.remote_port.saturating_add(t.port_count - 1)
+                        )
+                    } else {
+                        t.remote_port.to_string()
+                    };
+                    vec![
+                        t.id,
+                        t.client_id,
+                        t.name.unwrap_or_else(|| "-".to_string()),
+                        match t.domain {
+                            Some(domain) => format!("{} ({})", ports, domain),
+                            None => ports,
+                        },
+                        format!("{}:{}", t.local_host, t.local_port),
+                        t.protocol,
+                        format_time(t.created_at),
+                        match t.closed_at {
+                            Some(at) => format_age(at, t.created_at),
+                            None => format!("{} (open)", format_age(now, t.created_at)),
+                        },
+                        format_bytes(t.bytes_sent),
+                        format_bytes(t.bytes_received),
+                    ]
+                })
+                .collect(),
+        ),
+        InspectResponse::Usage(usage) => print_table(
+            &["CLIENT", "MONTH", "USED", "QUOTA"],
+            usage
+                .into_iter()
+                .map(|u| {
+                    vec![
+                        u.client_id,
+                        u.month,
+                        format_bytes(u.bytes),
+                        u.quota_bytes
+                            .map(format_bytes)
+                            .unwrap_or_else(|| "-".to_string()),
+                    ]
+                })
+                .collect(),
+        ),
+        InspectResponse::Provisioned(tunnels) => print_table(
+            &["CLIENT", "NAME", "PORT", "LOCAL", "PROTO", "OPEN"],
+            tunnels
+                .into_iter()
+                .map(|p| {
+                    vec![
+                        p.client_id,
+                        p.tunnel.name,
+                        p.tunnel
+                            .remote_port
+                            .map(|port| port.to_string())
+                            .unwrap_or_else(|| "-".to_string()),
+                        format!("{}:{}", p.tunnel.local_host, p.tunnel.local_port),
+                        p.tunnel.protocol.to_string(),
+                        if p.open { "yes" } else { "no" }.to_string(),
+                    ]
+                })
+                .collect(),
+        ),
+        InspectResponse::Logs(lines) => {
+            for line in lines {
+                println!("{}", line);
+            }
+        }
+        InspectResponse::Capacity(utilization) => print_table(
+            &["RESOURCE", "IN USE", "LIMIT", "USED"],
+            [
+                ("tunnels", utilization.tunnels),
+                ("connections", utilization.connections),
+                ("ports", utilization.ports),
+            ]
+            .into_iter()
+            .map(|(resource, gauge)| {
+                vec![
+                    resource.to_string(),
+                    gauge.used.to_string(),
+                    gauge
+                        .limit
+                        .map_or_else(|| "-".to_string(), |limit| limit.to_string()),
+                    gauge
+                        .percent()
+                        .map_or_else(|| "-".to_string(), |percent| format!("{:.0}%", percent)),
+                ]
+            })
+            .collect(),
+        ),
+        InspectResponse::Maintenance(None) => println!("Not in maintenance"),
+        InspectResponse::Maintenance(Some(maintenance)) => {
+            println!(
+                "In maintenance for {}: no new clients or tunnels are accepted",
+                format_age(now, maintenance.since)
+            );
+            if let Some(drain_at) = maintenance.drain_at {
+                if drain_at > now {
+                    println!(
+                        "Remaining clients are disconnected in {}",
+                        format_age(drain_at, now)
+                    );
+                } else {
+                    println!("Remaining clients were disconnected");
+                }
+            }
+        }
+        InspectResponse::Done(message) => println!("{}", message),
+        InspectResponse::Error(_) | InspectResponse::Top(_) => unreachable!(),
+    }
+    Ok(())
+}
+
+fn print_table(header: &[&str], rows: Vec<Vec<String>>) {
+    if rows.is_empty() {
+        println!("None");
+        return;
+    }
+    for line in format_table(header, rows) {
+        println!("{}", line);
+    }
+}
+
+/// Lines of a table with columns as wide as their widest cell
+pub fn format_table(header: &[&str], rows: Vec<Vec<String>>) -> Vec<String> {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let header = header.iter().map(|h| h.to_string()).collect();
+    std::iter::once(header)
+        .chain(rows)
+        .map(|row| {
+            let line: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect();
+            line.join("  ").trim_end().to_string()
+        })
+        .collect()
+}
+
+fn format_time(at: DateTime<Utc>) -> String {
+    at.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+pub fn format_age(now: DateTime<Utc>, since: DateTime<Utc>) -> String {
+    let secs = (now - since).num_seconds().max(0);
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    if days > 0 {
+        format!("{}d{:02}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h{:02}m", hours, minutes)
+    } else {
+        format!("{}m{:02}s", minutes, secs % 60)
+    }
+}
@@ -0,0 +1,35 @@
+//! The NAT traversal server. `nat-server` is the command line around it;
+//! the library lets tests and embedders run a server in their own process.
+
+pub mod ca;
+pub mod capture;
+pub mod check;
+pub mod compression;
+pub mod config;
+pub mod connection;
+pub mod control;
+pub mod events;
+pub mod frp;
+pub mod grpc;
+pub mod inspect;
+pub mod overlay;
+pub mod plugin;
+pub mod provision;
+pub mod relay;
+pub mod report;
+pub mod reservation;
+pub mod selftest;
+pub mod server;
+pub mod speedtest;
+pub mod storage;
+#[cfg(feature = "test-util")]
+pub mod testing;
+pub mod token;
+pub mod top;
+pub mod transport;
+pub mod tunnel;
+pub mod usage;
+pub mod vhost;
+pub mod vpn;
+pub mod websocket;
+pub mod wireguard;
@@ -1,17 +1,110 @@
-mod config;
-mod connection;
-mod server;
-mod tunnel;
-
 use clap::Parser;
 use config::*;
+use nat_traversal_common::config::{set_portable, TokenScope};
+use nat_traversal_platform::{
+    notify,
+    service::{run_service_action, ServiceAction, ServiceConfig},
+};
+use nat_traversal_server::{check, config, inspect, report, selftest, server, token, top};
 use server::NatServer;
+use std::future::Future;
 use tracing::{error, info};
 
-#[tokio::main]
-async fn main() {
+fn main() {
     let args = Args::parse();
 
+    if args.portable {
+        set_portable(true);
+    }
+
+    if let Some(Command::Service { name, action }) = &args.command {
+        tracing_subscriber::fmt().with_target(false).init();
+        if let Err(e) = run_service_command(*action, name, &args) {
+            eprintln!("Service command failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Inspect {
+        json,
+        observer_token,
+        action,
+    }) = &args.command
+    {
+        if let Err(e) = run_inspect(&args, action.clone(), *json, observer_token.as_deref()) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Report {
+        from,
+        to,
+        by,
+        format,
+    }) = &args.command
+    {
+        let result = load_server_config(&args)
+            .and_then(|config| report::run(&config, *from, *to, *by, *format));
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::IssueToken {
+        client_id,
+        ttl_hours,
+        protocols,
+        ports,
+        max_tunnels,
+        max_bandwidth_mbps,
+        monthly_quota_mb,
+        over_quota_mbps,
+    }) = &args.command
+    {
+        let scope = TokenScope {
+            protocols: protocols.clone(),
+            ports: ports.clone(),
+            max_tunnels: *max_tunnels,
+            max_bandwidth_mbps: *max_bandwidth_mbps,
+            monthly_quota_mb: *monthly_quota_mb,
+            over_quota_mbps: *over_quota_mbps,
+        };
+        match issue_token(&args, client_id, *ttl_hours, &scope) {
+            Ok(token) => println!("{}", token),
+            Err(e) => {
+                eprintln!("Failed to issue token: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(Command::Top {
+        interval,
+        limit,
+        observer_token,
+    }) = &args.command
+    {
+        if let Err(e) = run_top(&args, *interval, *limit, observer_token.as_deref()) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::CheckConfig) = &args.command {
+        if let Err(e) = run_check_config(&args) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // Generate config if requested
     if args.generate_config {
         if let Err(e) = generate_default_config() {
@@ -21,6 +114,51 @@ async fn main() {
         return;
     }
 
+    if args.self_test {
+        tracing_subscriber::fmt()
+            .with_target(false)
+            .with_max_level(tracing::Level::WARN)
+            .with_writer(std::io::stderr)
+            .init();
+        if let Err(e) = run_self_test(&args) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Hand control to the service control dispatcher when started by the SCM
+    #[cfg(windows)]
+    if args.service {
+        let result =
+            nat_traversal_platform::windows::run_service_dispatcher("nat-server", move |stop| {
+                let runtime = tokio::runtime::Runtime::new()?;
+                runtime.block_on(run(args, async move {
+                    let _ = tokio::task::spawn_blocking(move || stop.recv()).await;
+                }));
+                Ok(())
+            });
+        if let Err(e) = result {
+            eprintln!("Failed to start service dispatcher: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Failed to start runtime: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    runtime.block_on(run(args, async {
+        let _ = tokio::signal::ctrl_c().await;
+    }));
+}
+
+async fn run(args: Args, shutdown: impl Future<Output = ()>) {
     // Load configuration
     let config = match load_server_config(&args) {
         Ok(config) => config,
@@ -30,11 +168,14 @@ async fn main() {
         }
     };
 
-    // Setup logging
-    if let Err(e) = setup_logging(&config) {
-        eprintln!("Failed to setup logging: {}", e);
-        std::process::exit(1);
-    }
+    // Setup logging; the guard keeps telemetry export running
+    let _telemetry = match setup_logging(&config) {
+        Ok(telemetry) => telemetry,
+        Err(e) => {
+            eprintln!("Failed to setup logging: {}", e);
+            std::process::exit(1);
+        }
+    };
 
     info!("Starting NAT Traversal Server");
 
@@ -47,8 +188,73 @@ async fn main() {
         }
     };
 
-    if let Err(e) = server.run().await {
-        error!("Server error: {}", e);
-        std::process::exit(1);
+    tokio::select! {
+        result = server.run() => {
+            if let Err(e) = result {
+                error!("Server error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        _ = shutdown => {
+            info!("Shutting down...");
+            notify::notify_stopping();
+            server.disconnect_all("Server shutting down").await;
+            server.save_usage();
+        }
     }
 }
+
+fn run_inspect(
+    args: &Args,
+    action: InspectAction,
+    json: bool,
+    observer_token: Option<&str>,
+) -> anyhow::Result<()> {
+    let config = load_server_config(args)?;
+    tokio::runtime::Runtime::new()?.block_on(inspect::run(&config, action, json, observer_token))
+}
+
+fn run_top(
+    args: &Args,
+    interval: u64,
+    limit: usize,
+    observer_token: Option<&str>,
+) -> anyhow::Result<()> {
+    let config = load_server_config(args)?;
+    let interval = std::time::Duration::from_secs(interval.max(1));
+    tokio::runtime::Runtime::new()?.block_on(top::run(&config, interval, limit, observer_token))
+}
+
+fn run_check_config(args: &Args) -> anyhow::Result<()> {
+    tokio::runtime::Runtime::new()?.block_on(check::run(args))
+}
+
+fn run_self_test(args: &Args) -> anyhow::Result<()> {
+    let config = load_server_config(args)?;
+    tokio::runtime::Runtime::new()?.block_on(selftest::run(config))
+}
+
+fn issue_token(
+    args: &Args,
+    client_id: &str,
+    ttl_hours: u64,
+    scope: &TokenScope,
+) -> anyhow::Result<String> {
+    let config = load_server_config(args)?;
+    let jwt = config
+        .auth
+        .jwt
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("auth.jwt is not configured"))?;
+    token::issue(jwt, client_id, ttl_hours, scope)
+}
+
+fn run_service_command(action: ServiceAction, name: &str, args: &Args) -> anyhow::Result<()> {
+    let config = ServiceConfig::for_current_exe(
+        name,
+        "NAT Traversal Server",
+        "NAT traversal tunnel server",
+        service_arguments(args)?,
+    )?;
+    run_service_action(action, &config)
+}
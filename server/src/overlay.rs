@@ -0,0 +1,178 @@
+//! What the layer-3 VPN and the WireGuard mesh share: overlay addresses
+//! that stay with a client ID while the server runs, and members routing
+//! the subnets they advertise.
+
+use crate::connection::ClientConnection;
+use nat_traversal_common::{
+    error::{NatError, NatResult},
+    vpn::Subnet,
+};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// A client on an overlay network
+pub struct Member {
+    pub client: Arc<ClientConnection>,
+    pub address: Ipv4Addr,
+    /// Subnets routed to the client
+    pub advertise: Vec<Subnet>,
+}
+
+impl Member {
+    /// How specific the member's claim on `addr` is, if it has one
+    pub fn claim(&self, addr: Ipv4Addr) -> Option<u8> {
+        if addr == self.address {
+            return Some(33);
+        }
+        self.advertise
+            .iter()
+            .filter(|subnet| subnet.contains(addr))
+            .map(|subnet| subnet.prefix)
+            .max()
+    }
+
+    /// Whether the member joined in `client`'s session rather than an
+    /// older or newer one with its ID
+    pub fn is_session(&self, client: &Arc<ClientConnection>) -> bool {
+        Arc::ptr_eq(&self.client, client)
+    }
+}
+
+/// Refuse `advertise` when one of its subnets overlaps the overlay
+/// `subnet` or a subnet another of `members` advertises
+pub fn check_advertise<'a>(
+    name: &str,
+    subnet: &Subnet,
+    client_id: &str,
+    advertise: &[Subnet],
+    members: impl IntoIterator<Item = &'a Member>,
+) -> NatResult<()> {
+    let others: Vec<&Member> = members
+        .into_iter()
+        .filter(|member| member.client.id != client_id)
+        .collect();
+    for advertised in advertise {
+        if advertised.overlaps(subnet) {
+            return Err(NatError::config(format!(
+                "{} overlaps the {} subnet {}",
+                advertised, name, subnet
+            )));
+        }
+        let taken = others.iter().any(|member| {
+            member
+                .advertise
+                .iter()
+                .any(|other| other.overlaps(advertised))
+        });
+        if taken {
+            return Err(NatError::config(format!(
+                "{} overlaps a subnet another client advertises",
+                advertised
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Overlay addresses handed out, each kept for its client ID
+pub struct Addresses {
+    name: &'static str,
+    subnet: Subnet,
+    /// Index of the first address given to clients
+    first: u32,
+    taken: RwLock<HashMap<String, Ipv4Addr>>,
+}
+
+impl Addresses {
+    pub fn new(name: &'static str, subnet: Subnet, first: u32) -> Self {
+        Self {
+            name,
+            subnet,
+            first,
+            taken: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// The address `client_id` had before, or a free one
+    pub async fn address_for(&self, client_id: &str) -> NatResult<Ipv4Addr> {
+        let mut taken = self.taken.write().await;
+        if let Some(address) = taken.get(client_id) {
+            return Ok(*address);
+        }
+        let address = (self.first..)
+            .map_while(|index| self.subnet.host(index))
+            .find(|address| !taken.values().any(|other| other == address))
+            .ok_or_else(|| NatError::limit_reached(format!("No free {} addresses", self.name)))?;
+        taken.insert(client_id.to_string(), address);
+        Ok(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(id: &str, address: Ipv4Addr, advertise: &[&str]) -> Member {
+        let (tx, _) = tokio::sync::mpsc::unbounded_channel();
+        Member {
+            client: Arc::new(ClientConnection::new(
+                id.to_string(),
+                "192.0.2.1:4000".parse().unwrap(),
+                tx,
+            )),
+            address,
+            advertise: advertise.iter().map(|s| s.parse().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_claims() {
+        let home = member(
+            "home",
+            Ipv4Addr::new(10, 89, 0, 2),
+            &["192.168.0.0/16", "192.168.1.0/24"],
+        );
+        assert_eq!(home.claim(Ipv4Addr::new(10, 89, 0, 2)), Some(33));
+        assert_eq!(home.claim(Ipv4Addr::new(192, 168, 1, 5)), Some(24));
+        assert_eq!(home.claim(Ipv4Addr::new(192, 168, 2, 5)), Some(16));
+        assert_eq!(home.claim(Ipv4Addr::new(10, 89, 0, 3)), None);
+    }
+
+    #[test]
+    fn test_check_advertise() {
+        let overlay: Subnet = "10.89.0.0/24".parse().unwrap();
+        let members = [member(
+            "home",
+            Ipv4Addr::new(10, 89, 0, 2),
+            &["192.168.1.0/24"],
+        )];
+        let check = |client_id: &str, advertise: &str| {
+            check_advertise(
+                "VPN",
+                &overlay,
+                client_id,
+                &[advertise.parse().unwrap()],
+                &members,
+            )
+        };
+        assert!(check("office", "172.16.0.0/12").is_ok());
+        assert!(check("office", "10.0.0.0/8").is_err());
+        assert!(check("office", "192.168.1.128/25").is_err());
+        assert!(check("office", "192.168.0.0/16").is_err());
+        // A member rejoining may advertise what it did before
+        assert!(check("home", "192.168.1.0/24").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_addresses_stay_with_client_ids() {
+        let addresses = Addresses::new("VPN", "10.89.0.0/30".parse().unwrap(), 1);
+        let first = addresses.address_for("a").await.unwrap();
+        let second = addresses.address_for("b").await.unwrap();
+        assert_eq!(first, Ipv4Addr::new(10, 89, 0, 1));
+        assert_eq!(second, Ipv4Addr::new(10, 89, 0, 2));
+        assert_eq!(addresses.address_for("a").await.unwrap(), first);
+        assert!(addresses.address_for("c").await.is_err());
+    }
+}
@@ -0,0 +1,477 @@
+//! Operator scripts run for tunnels' public connections.
+//!
+//! A plugin is a Lua script defining `on_connect(conn)`, called before a new
+//! public connection reaches the client, and `on_data(conn, direction,
+//! data)`, called with every chunk when the plugin is configured with
+//! `data = true`. `conn` holds `tunnel`, `tunnel_id`, `client_id`,
+//! `connection_id`, `peer` and `port`; `direction` is `"inbound"` towards
+//! the client or `"outbound"` back to the visitor.
+//!
+//! `on_connect` refuses the connection by returning `false` or a reason.
+//! `on_data` closes it by returning `false`, or replaces the chunk by
+//! returning a string. Scripts may call `log(message)` and
+//! `metric(name, value)`.
+//!
+//! Every tunnel gets its own Lua state of each plugin, so one tunnel's
+//! connections never wait on another's, and calls run on the blocking
+//! pool rather than the runtime's workers. Scripts only get Lua's string,
+//! table, math and utf8 libraries, a memory cap and a time limit per
+//! call. A call that fails or runs out of time refuses or closes the
+//! connection, unless the plugin is configured with `on_error = "Open"`,
+//! which lets the connection and its data through unchanged; either way
+//! the failure is logged.
+
+use bytes::Bytes;
+use mlua::{HookTriggers, Lua, LuaOptions, MultiValue, StdLib, Table, Value};
+use nat_traversal_common::{
+    config::{PluginConfig, PluginFailMode},
+    error::{NatError, NatResult},
+    telemetry::{self, Direction},
+};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// Instructions between checks of the time limit
+const HOOK_INTERVAL: u32 = 1000;
+
+/// When the running call has to be done by, kept in the Lua state
+struct Deadline(Instant);
+
+/// What a script's function returned, copied out of its state
+enum Returned {
+    False,
+    Text(Bytes),
+    /// Anything else, `nil` included
+    Other,
+}
+
+struct Plugin {
+    /// Script file name, for logs and metrics
+    name: String,
+    tunnels: Vec<String>,
+    data: bool,
+    time_limit: Duration,
+    memory_limit: usize,
+    on_error: PluginFailMode,
+    source: String,
+}
+
+impl Plugin {
+    fn load(config: &PluginConfig) -> Result<Self, String> {
+        let name = config
+            .script
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| config.script.display().to_string());
+        let source = std::fs::read_to_string(&config.script)
+            .map_err(|e| format!("Failed to read {}: {}", config.script.display(), e))?;
+        let plugin = Self {
+            name,
+            tunnels: config.tunnels.clone(),
+            data: config.data,
+            time_limit: Duration::from_millis(config.time_limit_ms.max(1)),
+            memory_limit: config.memory_limit_kb.saturating_mul(1024),
+            on_error: config.on_error,
+            source,
+        };
+        // Run the script once so a broken one stops the server starting
+        plugin.instantiate()?;
+        Ok(plugin)
+    }
+
+    /// A fresh Lua state with the script run in it
+    fn instantiate(&self) -> Result<Lua, String> {
+        let lua = Lua::new_with(
+            StdLib::STRING | StdLib::TABLE | StdLib::MATH | StdLib::UTF8,
+            LuaOptions::default(),
+        )
+        .map_err(|e| e.to_string())?;
+        lua.set_memory_limit(self.memory_limit)
+            .map_err(|e| e.to_string())?;
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(HOOK_INTERVAL),
+            |lua, _| match lua.app_data_ref::<Deadline>() {
+                Some(deadline) if Instant::now() > deadline.0 => {
+                    Err(mlua::Error::runtime("time limit exceeded"))
+                }
+                _ => Ok(()),
+            },
+        );
+
+        let globals = lua.globals();
+        let log_name = self.name.clone();
+        let log = lua
+            .create_function(move |_, message: String| {
+                info!("Plugin {}: {}", log_name, message);
+                Ok(())
+            })
+            .map_err(|e| e.to_string())?;
+        let metric_name = self.name.clone();
+        let metric = lua
+            .create_function(move |_, (metric, value): (String, f64)| {
+                telemetry::plugin_metric(&metric_name, &metric, value);
+                Ok(())
+            })
+            .map_err(|e| e.to_string())?;
+        globals.set("log", log).map_err(|e| e.to_string())?;
+        globals.set("metric", metric).map_err(|e| e.to_string())?;
+
+        lua.set_app_data(Deadline(Instant::now() + self.time_limit));
+        lua.load(&self.source)
+            .set_name(self.name.as_str())
+            .exec()
+            .map_err(|e| e.to_string())?;
+
+        let defines = |function: &str| matches!(globals.get(function), Ok(Value::Function(_)));
+        if !defines("on_connect") && !defines("on_data") {
+            return Err("The script defines neither on_connect nor on_data".to_string());
+        }
+        if self.data && !defines("on_data") {
+            return Err("data is set but the script defines no on_data".to_string());
+        }
+        drop(globals);
+        Ok(lua)
+    }
+
+    fn applies_to(&self, tunnel: Option<&str>) -> bool {
+        self.tunnels.is_empty()
+            || tunnel.is_some_and(|tunnel| self.tunnels.iter().any(|t| t == tunnel))
+    }
+}
+
+/// A plugin's Lua state for one tunnel
+struct Instance {
+    plugin: Arc<Plugin>,
+    /// None when the script failed to run for the tunnel, which then
+    /// fails every call
+    lua: Option<Mutex<Lua>>,
+}
+
+impl Instance {
+    /// Call the script's `function` with `args` built in its state;
+    /// `Ok(None)` when it defines no such function. Blocks until the
+    /// call returns or runs out of time.
+    fn call(
+        &self,
+        function: &str,
+        args: impl for<'lua> FnOnce(&'lua Lua) -> mlua::Result<MultiValue<'lua>>,
+    ) -> mlua::Result<Option<Returned>> {
+        let Some(lua) = &self.lua else {
+            return Err(mlua::Error::runtime("the script failed to load"));
+        };
+        let lua = lua.lock().unwrap();
+        let Value::Function(function) = lua.globals().get::<_, Value>(function)? else {
+            return Ok(None);
+        };
+        let args = args(&lua)?;
+        lua.set_app_data(Deadline(Instant::now() + self.plugin.time_limit));
+        let returned = match function.call::<_, Value>(args)? {
+            Value::Boolean(false) => Returned::False,
+            Value::String(text) => Returned::Text(Bytes::copy_from_slice(text.as_bytes())),
+            _ => Returned::Other,
+        };
+        Ok(Some(returned))
+    }
+}
+
+/// The plugins the server was configured with
+#[derive(Default)]
+pub struct Plugins {
+    plugins: Vec<Arc<Plugin>>,
+}
+
+impl Plugins {
+    pub fn load(configs: &[PluginConfig]) -> NatResult<Self> {
+        let mut plugins = Vec::new();
+        for config in configs {
+            let plugin = Plugin::load(config).map_err(|e| {
+                NatError::config(format!("Plugin {}: {}", config.script.display(), e))
+            })?;
+            info!("Loaded plugin {}", plugin.name);
+            plugins.push(Arc::new(plugin));
+        }
+        Ok(Self { plugins })
+    }
+
+    /// The plugins running for a tunnel, if any do, each with a state of
+    /// its own for the tunnel
+    pub async fn for_tunnel(
+        &self,
+        tunnel_id: Uuid,
+        name: Option<&str>,
+        client_id: &str,
+    ) -> Option<Arc<TunnelPlugins>> {
+        let plugins: Vec<_> = self
+            .plugins
+            .iter()
+            .filter(|plugin| plugin.applies_to(name))
+            .cloned()
+            .collect();
+        if plugins.is_empty() {
+            return None;
+        }
+        let instances = tokio::task::spawn_blocking(move || {
+            plugins
+                .into_iter()
+                .map(|plugin| {
+                    let lua = plugin
+                        .instantiate()
+                        .map_err(|e| warn!("Plugin {} failed to load: {}", plugin.name, e))
+                        .ok();
+                    Instance {
+                        plugin,
+                        lua: lua.map(Mutex::new),
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .await
+        .unwrap_or_default();
+        Some(Arc::new(TunnelPlugins {
+            tunnel_id,
+            tunnel: name.map(str::to_string),
+            client_id: client_id.to_string(),
+            data: instances.iter().any(|instance| instance.plugin.data),
+            instances,
+        }))
+    }
+}
+
+/// What a plugin made of a chunk
+pub enum Filtered {
+    Pass(Bytes),
+    /// Close the connection
+    Close,
+}
+
+/// The plugins running for one tunnel
+pub struct TunnelPlugins {
+    tunnel_id: Uuid,
+    tunnel: Option<String>,
+    client_id: String,
+    /// Whether any of them sees the tunnel's data
+    data: bool,
+    instances: Vec<Instance>,
+}
+
+impl TunnelPlugins {
+    /// The plugins' view of a public connection to the tunnel, arriving
+    /// from `peer` on `port`
+    pub fn visit(self: &Arc<Self>, connection_id: u32, peer: SocketAddr, port: u16) -> Visit {
+        Visit {
+            plugins: self.clone(),
+            connection_id,
+            peer,
+            port,
+        }
+    }
+}
+
+/// One public connection, as the plugins of its tunnel see it
+#[derive(Clone)]
+pub struct Visit {
+    plugins: Arc<TunnelPlugins>,
+    connection_id: u32,
+    peer: SocketAddr,
+    port: u16,
+}
+
+impl Visit {
+    fn connection<'lua>(&self, lua: &'lua Lua) -> mlua::Result<Table<'lua>> {
+        let conn = lua.create_table()?;
+        conn.set("tunnel", self.plugins.tunnel.clone())?;
+        conn.set("tunnel_id", self.plugins.tunnel_id.to_string())?;
+        conn.set("client_id", self.plugins.client_id.as_str())?;
+        conn.set("connection_id", self.connection_id)?;
+        conn.set("peer", self.peer.ip().to_string())?;
+        conn.set("port", self.port)?;
+        Ok(conn)
+    }
+
+    /// Ask every plugin whether the connection may in, returning the
+    /// reason of the first to refuse it
+    pub async fn admit(&self) -> Result<(), String> {
+        let visit = self.clone();
+        tokio::task::spawn_blocking(move || visit.admit_blocking())
+            .await
+            .unwrap_or_else(|e| Err(format!("refused, plugins failed: {}", e)))
+    }
+
+    fn admit_blocking(&self) -> Result<(), String> {
+        for instance in &self.plugins.instances {
+            let plugin = &instance.plugin;
+            let result = instance.call("on_connect", |lua| {
+                Ok(MultiValue::from_vec(vec![Value::Table(
+                    self.connection(lua)?,
+                )]))
+            });
+            match result {
+                Ok(Some(Returned::False)) => {
+                    return Err(format!("refused by plugin {}", plugin.name))
+                }
+                Ok(Some(Returned::Text(reason))) => {
+                    return Err(format!(
+                        "refused by plugin {}: {}",
+                        plugin.name,
+                        String::from_utf8_lossy(&reason)
+                    ))
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Plugin {} failed in on_connect: {}", plugin.name, e);
+                    if plugin.on_error == PluginFailMode::Closed {
+                        return Err(format!("refused, plugin {} failed", plugin.name));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether chunks have to go through `filter`
+    pub fn sees_data(&self) -> bool {
+        self.plugins.data
+    }
+
+    /// Pass a chunk through the plugins that see the tunnel's data
+    pub async fn filter(&self, direction: Direction, data: Bytes) -> Filtered {
+        let visit = self.clone();
+        tokio::task::spawn_blocking(move || visit.filter_blocking(direction, data))
+            .await
+            .unwrap_or_else(|e| {
+                debug!("Plugins failed over data: {}", e);
+                Filtered::Close
+            })
+    }
+
+    fn filter_blocking(&self, direction: Direction, mut data: Bytes) -> Filtered {
+        let direction = match direction {
+            Direction::Inbound => "inbound",
+            Direction::Outbound => "outbound",
+        };
+        for instance in self.plugins.instances.iter().filter(|i| i.plugin.data) {
+            let plugin = &instance.plugin;
+            let result = instance.call("on_data", |lua| {
+                Ok(MultiValue::from_vec(vec![
+                    Value::Table(self.connection(lua)?),
+                    Value::String(lua.create_string(direction)?),
+                    Value::String(lua.create_string(&data[..])?),
+                ]))
+            });
+            match result {
+                Ok(Some(Returned::False)) => return Filtered::Close,
+                Ok(Some(Returned::Text(replaced))) => data = replaced,
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Plugin {} failed in on_data: {}", plugin.name, e);
+                    if plugin.on_error == PluginFailMode::Closed {
+                        return Filtered::Close;
+                    }
+                }
+            }
+        }
+        Filtered::Pass(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn visit(source: &str, on_error: PluginFailMode) -> Visit {
+        let script = std::env::temp_dir().join(format!("nat-plugin-{}.lua", Uuid::new_v4()));
+        std::fs::write(&script, source).unwrap();
+        let config = PluginConfig {
+            script: script.clone(),
+            tunnels: Vec::new(),
+            data: true,
+            time_limit_ms: 10,
+            memory_limit_kb: 4096,
+            on_error,
+        };
+        let plugins = Plugins::load(&[config]).unwrap();
+        std::fs::remove_file(script).unwrap();
+        plugins
+            .for_tunnel(Uuid::new_v4(), Some("web"), "client")
+            .await
+            .unwrap()
+            .visit(1, "192.0.2.1:4000".parse().unwrap(), 8080)
+    }
+
+    #[tokio::test]
+    async fn test_plugin_decisions() {
+        let visit = visit(
+            r#"
+            function on_connect(conn)
+                if conn.peer == "192.0.2.1" then return "blocked" end
+            end
+            function on_data(conn, direction, data)
+                if direction == "inbound" then return data:upper() end
+            end
+            "#,
+            PluginFailMode::Closed,
+        )
+        .await;
+        assert!(matches!(visit.admit().await, Err(reason) if reason.ends_with(": blocked")));
+        assert!(matches!(
+            visit.filter(Direction::Inbound, Bytes::from_static(b"abc")).await,
+            Filtered::Pass(data) if data == "ABC"
+        ));
+        assert!(matches!(
+            visit.filter(Direction::Outbound, Bytes::from_static(b"abc")).await,
+            Filtered::Pass(data) if data == "abc"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_plugin_failures_close_by_default() {
+        let source = r#"
+            function on_connect(conn) while true do end end
+            function on_data(conn, direction, data) error("broken") end
+        "#;
+        let closed = visit(source, PluginFailMode::Closed).await;
+        assert!(closed.admit().await.is_err());
+        assert!(matches!(
+            closed
+                .filter(Direction::Inbound, Bytes::from_static(b"abc"))
+                .await,
+            Filtered::Close
+        ));
+
+        let open = visit(source, PluginFailMode::Open).await;
+        assert_eq!(open.admit().await, Ok(()));
+        assert!(matches!(
+            open.filter(Direction::Inbound, Bytes::from_static(b"abc")).await,
+            Filtered::Pass(data) if data == "abc"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_plugin_state_per_tunnel() {
+        let script = std::env::temp_dir().join(format!("nat-plugin-{}.lua", Uuid::new_v4()));
+        std::fs::write(
+            &script,
+            "seen = 0\nfunction on_connect(conn) seen = seen + 1; if seen > 1 then return false end end",
+        )
+        .unwrap();
+        let plugins = Plugins::load(&[PluginConfig {
+            script: script.clone(),
+            tunnels: Vec::new(),
+            data: false,
+            time_limit_ms: 10,
+            memory_limit_kb: 4096,
+            on_error: PluginFailMode::Closed,
+        }])
+        .unwrap();
+        std::fs::remove_file(script).unwrap();
+        let peer = "192.0.2.1:4000".parse().unwrap();
+        let first = plugins.for_tunnel(Uuid::new_v4(), None, "a").await.unwrap();
+        let second = plugins.for_tunnel(Uuid::new_v4(), None, "b").await.unwrap();
+        assert_eq!(first.visit(1, peer, 80).admit().await, Ok(()));
+        assert_eq!(second.visit(1, peer, 80).admit().await, Ok(()));
+        assert!(first.visit(2, peer, 80).admit().await.is_err());
+    }
+}
@@ -0,0 +1,72 @@
+//! Tunnels the operator defines for particular clients. The server tells a
+//! client to open them each time it logs in, so a fleet of devices can be
+//! managed from here instead of editing each one's configuration.
+
+use nat_traversal_common::config::TunnelConfig;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+pub struct Provisioning {
+    /// Tunnel definitions by client ID, in the order they were added
+    tunnels: Mutex<BTreeMap<String, Vec<TunnelConfig>>>,
+}
+
+impl Provisioning {
+    /// Start from the configured definitions, refusing a name given twice
+    /// for one client
+    pub fn new(config: &BTreeMap<String, Vec<TunnelConfig>>) -> Result<Self, String> {
+        for (client_id, tunnels) in config {
+            for (i, tunnel) in tunnels.iter().enumerate() {
+                if tunnels[..i].iter().any(|other| other.name == tunnel.name) {
+                    return Err(format!(
+                        "Tunnel {} is provisioned twice for {}",
+                        tunnel.name, client_id
+                    ));
+                }
+            }
+        }
+        Ok(Self {
+            tunnels: Mutex::new(config.clone()),
+        })
+    }
+
+    /// The tunnels to send `client_id` when it logs in
+    pub fn for_client(&self, client_id: &str) -> Vec<TunnelConfig> {
+        self.tunnels
+            .lock()
+            .unwrap()
+            .get(client_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Every client's tunnels
+    pub fn list(&self) -> BTreeMap<String, Vec<TunnelConfig>> {
+        self.tunnels.lock().unwrap().clone()
+    }
+
+    /// Provision `tunnel` for `client_id`, replacing one of the same name
+    pub fn set(&self, client_id: &str, tunnel: TunnelConfig) {
+        let mut tunnels = self.tunnels.lock().unwrap();
+        let list = tunnels.entry(client_id.to_string()).or_default();
+        match list.iter_mut().find(|other| other.name == tunnel.name) {
+            Some(existing) => *existing = tunnel,
+            None => list.push(tunnel),
+        }
+    }
+
+    /// Stop provisioning the named tunnel; false when there was none
+    pub fn remove(&self, client_id: &str, name: &str) -> bool {
+        let mut tunnels = self.tunnels.lock().unwrap();
+        let Some(list) = tunnels.get_mut(client_id) else {
+            return false;
+        };
+        let before = list.len();
+        list.retain(|tunnel| tunnel.name != name);
+        let removed = list.len() < before;
+        if list.is_empty() {
+            tunnels.remove(client_id);
+        }
+        removed
+    }
+}
@@ -0,0 +1,434 @@
+use crate::connection::ConnectionManager;
+use crate::tunnel::PortAllocator;
+use chrono::Utc;
+use nat_traversal_common::{
+    error::{NatError, NatResult},
+    protocol::{Message, RelayInfo, TunnelProtocol},
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// How long a peer has to send the relay ID after connecting
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Manages relay port pairs used as a fallback path for peer sessions
+pub struct RelayManager {
+    relays: Arc<RwLock<HashMap<Uuid, RelayHandle>>>,
+    port_allocator: Arc<RwLock<PortAllocator>>,
+    connection_manager: Arc<ConnectionManager>,
+    max_relays_per_client: u32,
+    idle_timeout: Duration,
+}
+
+struct RelayHandle {
+    info: RelayInfo,
+    client_id: String,
+    stats: Arc<RelayStats>,
+    task: JoinHandle<()>,
+}
+
+/// Traffic accounting for one relay
+struct RelayStats {
+    bytes_a_to_b: AtomicU64,
+    bytes_b_to_a: AtomicU64,
+    last_activity_ms: AtomicI64,
+}
+
+impl RelayStats {
+    fn new() -> Self {
+        Self {
+            bytes_a_to_b: AtomicU64::new(0),
+            bytes_b_to_a: AtomicU64::new(0),
+            last_activity_ms: AtomicI64::new(Utc::now().timestamp_millis()),
+        }
+    }
+
+    fn record(&self, a_to_b: bool, bytes: usize) {
+        let counter = if a_to_b {
+            &self.bytes_a_to_b
+        } else {
+            &self.bytes_b_to_a
+        };
+        counter.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_activity_ms
+            .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    fn total(&self) -> u64 {
+        self.bytes_a_to_b.load(Ordering::Relaxed) + self.bytes_b_to_a.load(Ordering::Relaxed)
+    }
+
+    fn idle_for(&self) -> Duration {
+        let idle_ms = Utc::now().timestamp_millis() - self.last_activity_ms.load(Ordering::Relaxed);
+        Duration::from_millis(idle_ms.max(0) as u64)
+    }
+}
+
+enum RelaySockets {
+    Tcp(TcpListener, TcpListener),
+    Udp(UdpSocket, UdpSocket),
+}
+
+impl RelayManager {
+    pub fn new(
+        port_allocator: Arc<RwLock<PortAllocator>>,
+        connection_manager: Arc<ConnectionManager>,
+        max_relays_per_client: u32,
+        idle_timeout: Duration,
+    ) -> Self {
+        Self {
+            relays: Arc::new(RwLock::new(HashMap::new())),
+            port_allocator,
+            connection_manager,
+            max_relays_per_client,
+            idle_timeout,
+        }
+    }
+
+    pub async fn allocate(
+        &self,
+        client_id: &str,
+        protocol: TunnelProtocol,
+    ) -> NatResult<RelayInfo> {
+        let existing = self
+            .relays
+            .read()
+            .await
+            .values()
+            .filter(|relay| relay.client_id == client_id)
+            .count();
+        if existing >= self.max_relays_per_client as usize {
+            return Err(NatError::tunnel("Relay limit reached"));
+        }
+
+        let relay_id = Uuid::new_v4();
+        let (port_a, port_b) = {
+            let mut allocator = self.port_allocator.write().await;
+            allocator.check_room(2)?;
+            let port_a = allocator
+                .reserve(relay_id)
+                .ok_or_else(|| NatError::tunnel("No available ports"))?;
+            match allocator.reserve(relay_id) {
+                Some(port_b) => (port_a, port_b),
+                None => {
+                    allocator.release_port(port_a);
+                    return Err(NatError::tunnel("No available ports"));
+                }
+            }
+        };
+
+        let sockets = match Self::bind(protocol, port_a, port_b).await {
+            Ok(sockets) => sockets,
+            Err(e) => {
+                let mut allocator = self.port_allocator.write().await;
+                allocator.release_port(port_a);
+                allocator.release_port(port_b);
+                return Err(e);
+            }
+        };
+
+        let info = RelayInfo {
+            id: relay_id,
+            protocol,
+            port_a,
+            port_b,
+            created_at: Utc::now(),
+            bytes_relayed: 0,
+        };
+
+        let stats = Arc::new(RelayStats::new());
+        let task = self.spawn_relay(relay_id, sockets, stats.clone());
+
+        self.relays.write().await.insert(
+            relay_id,
+            RelayHandle {
+                info: info.clone(),
+                client_id: client_id.to_string(),
+                stats,
+                task,
+            },
+        );
+
+        info!(
+            "Allocated {} relay {} for client {} on ports {} <-> {}",
+            protocol, relay_id, client_id, port_a, port_b
+        );
+
+        Ok(info)
+    }
+
+    /// Release a relay owned by `client_id`, returning the bytes it relayed
+    pub async fn release(&self, client_id: &str, relay_id: &Uuid) -> NatResult<u64> {
+        {
+            let relays = self.relays.read().await;
+            match relays.get(relay_id) {
+                Some(relay) if relay.client_id == client_id => {}
+                _ => return Err(NatError::tunnel("Relay not found")),
+            }
+        }
+
+        Self::remove(&self.relays, &self.port_allocator, relay_id, false)
+            .await
+            .ok_or_else(|| NatError::tunnel("Relay not found"))
+    }
+
+    /// Release every relay owned by a disconnected client
+    pub async fn release_client(&self, client_id: &str) {
+        let relay_ids: Vec<Uuid> = self
+            .relays
+            .read()
+            .await
+            .iter()
+            .filter(|(_, relay)| relay.client_id == client_id)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for relay_id in relay_ids {
+            Self::remove(&self.relays, &self.port_allocator, &relay_id, false).await;
+        }
+    }
+
+    pub async fn list_relays(&self, client_id: &str) -> Vec<RelayInfo> {
+        self.relays
+            .read()
+            .await
+            .values()
+            .filter(|relay| relay.client_id == client_id)
+            .map(|relay| RelayInfo {
+                bytes_relayed: relay.stats.total(),
+                ..relay.info.clone()
+            })
+            .collect()
+    }
+
+    async fn bind(protocol: TunnelProtocol, port_a: u16, port_b: u16) -> NatResult<RelaySockets> {
+        let addr_a = format!("0.0.0.0:{}", port_a);
+        let addr_b = format!("0.0.0.0:{}", port_b);
+        let bind_error =
+            |e: std::io::Error| NatError::network(format!("Failed to bind relay: {}", e));
+
+        Ok(match protocol {
+            TunnelProtocol::Tcp => RelaySockets::Tcp(
+                TcpListener::bind(&addr_a).await.map_err(bind_error)?,
+                TcpListener::bind(&addr_b).await.map_err(bind_error)?,
+            ),
+            TunnelProtocol::Udp => RelaySockets::Udp(
+                UdpSocket::bind(&addr_a).await.map_err(bind_error)?,
+                UdpSocket::bind(&addr_b).await.map_err(bind_error)?,
+            ),
+        })
+    }
+
+    fn spawn_relay(
+        &self,
+        relay_id: Uuid,
+        sockets: RelaySockets,
+        stats: Arc<RelayStats>,
+    ) -> JoinHandle<()> {
+        let relays = self.relays.clone();
+        let port_allocator = self.port_allocator.clone();
+        let connection_manager = self.connection_manager.clone();
+        let idle_timeout = self.idle_timeout;
+
+        tokio::spawn(async move {
+            let relay = async {
+                match sockets {
+                    RelaySockets::Tcp(a, b) => Self::run_tcp(relay_id, a, b, stats.clone()).await,
+                    RelaySockets::Udp(a, b) => Self::run_udp(relay_id, a, b, stats.clone()).await,
+                }
+            };
+
+            let reason = tokio::select! {
+                _ = relay => "Peers disconnected",
+                _ = Self::idle_monitor(&stats, idle_timeout) => "Idle timeout",
+            };
+
+            // Tell the owner unless the relay was already released explicitly
+            let client_id = relays
+                .read()
+                .await
+                .get(&relay_id)
+                .map(|relay| relay.client_id.clone());
+            if let Some(client_id) = client_id {
+                debug!("Relay {} finished: {}", relay_id, reason);
+                if let Some(bytes_relayed) =
+                    Self::remove(&relays, &port_allocator, &relay_id, true).await
+                {
+                    if let Some(client) = connection_manager.get_client(&client_id).await {
+                        let _ = client
+                            .send_message(Message::RelayClosed {
+                                relay_id,
+                                bytes_relayed,
+                                reason: reason.to_string(),
+                            })
+                            .await;
+                    }
+                }
+            }
+        })
+    }
+
+    async fn idle_monitor(stats: &RelayStats, idle_timeout: Duration) {
+        loop {
+            tokio::time::sleep(idle_timeout / 4).await;
+            if stats.idle_for() >= idle_timeout {
+                return;
+            }
+        }
+    }
+
+    async fn run_tcp(relay_id: Uuid, a: TcpListener, b: TcpListener, stats: Arc<RelayStats>) {
+        let mut side_a = None;
+        let mut side_b = None;
+
+        while side_a.is_none() || side_b.is_none() {
+            let (result, is_a) = tokio::select! {
+                result = a.accept(), if side_a.is_none() => (result, true),
+                result = b.accept(), if side_b.is_none() => (result, false),
+            };
+
+            let (stream, addr) = match result {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Relay {} accept failed: {}", relay_id, e);
+                    return;
+                }
+            };
+
+            match Self::claim_tcp(relay_id, stream).await {
+                Some(stream) => {
+                    debug!(
+                        "Relay {} side {} claimed by {}",
+                        relay_id,
+                        if is_a { "A" } else { "B" },
+                        addr
+                    );
+                    if is_a {
+                        side_a = Some(stream);
+                    } else {
+                        side_b = Some(stream);
+                    }
+                }
+                None => debug!("Relay {} rejected connection from {}", relay_id, addr),
+            }
+        }
+
+        let (Some(side_a), Some(side_b)) = (side_a, side_b) else {
+            return;
+        };
+        let (mut read_a, mut write_a) = side_a.into_split();
+        let (mut read_b, mut write_b) = side_b.into_split();
+
+        tokio::join!(
+            Self::pump(&mut read_a, &mut write_b, &stats, true),
+            Self::pump(&mut read_b, &mut write_a, &stats, false),
+        );
+    }
+
+    /// Accept a TCP peer only if it starts by sending the relay ID
+    async fn claim_tcp(relay_id: Uuid, mut stream: TcpStream) -> Option<TcpStream> {
+        let mut claim = [0u8; 16];
+        match tokio::time::timeout(CLAIM_TIMEOUT, stream.read_exact(&mut claim)).await {
+            Ok(Ok(_)) if claim == *relay_id.as_bytes() => Some(stream),
+            _ => None,
+        }
+    }
+
+    async fn pump<R, W>(reader: &mut R, writer: &mut W, stats: &RelayStats, a_to_b: bool)
+    where
+        R: AsyncReadExt + Unpin,
+        W: AsyncWriteExt + Unpin,
+    {
+        let mut buffer = [0u8; 8192];
+        loop {
+            match reader.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if writer.write_all(&buffer[..n]).await.is_err() {
+                        break;
+                    }
+                    stats.record(a_to_b, n);
+                }
+            }
+        }
+        let _ = writer.shutdown().await;
+    }
+
+    async fn run_udp(relay_id: Uuid, a: UdpSocket, b: UdpSocket, stats: Arc<RelayStats>) {
+        let mut peer_a: Option<SocketAddr> = None;
+        let mut peer_b: Option<SocketAddr> = None;
+        let mut buffer_a = [0u8; 65536];
+        let mut buffer_b = [0u8; 65536];
+
+        loop {
+            let (result, is_a) = tokio::select! {
+                result = a.recv_from(&mut buffer_a) => (result, true),
+                result = b.recv_from(&mut buffer_b) => (result, false),
+            };
+
+            let (len, from) = match result {
+                Ok(received) => received,
+                Err(e) => {
+                    debug!("Relay {} receive error: {}", relay_id, e);
+                    continue;
+                }
+            };
+
+            let (data, peer, other_peer, out) = if is_a {
+                (&buffer_a[..len], &mut peer_a, peer_b, &b)
+            } else {
+                (&buffer_b[..len], &mut peer_b, peer_a, &a)
+            };
+
+            // The relay ID claims (or re-claims after a NAT rebinding) a side
+            if data == relay_id.as_bytes() {
+                *peer = Some(from);
+                continue;
+            }
+
+            if *peer != Some(from) {
+                continue;
+            }
+
+            if let Some(other_peer) = other_peer {
+                if out.send_to(data, other_peer).await.is_ok() {
+                    stats.record(is_a, len);
+                }
+            }
+        }
+    }
+
+    /// Stop a relay and release its ports, returning the bytes it relayed.
+    ///
+    /// `from_task` is set when the relay task itself is finishing, in which
+    /// case it must not be aborted.
+    async fn remove(
+        relays: &Arc<RwLock<HashMap<Uuid, RelayHandle>>>,
+        port_allocator: &Arc<RwLock<PortAllocator>>,
+        relay_id: &Uuid,
+        from_task: bool,
+    ) -> Option<u64> {
+        let relay = relays.write().await.remove(relay_id)?;
+        if !from_task {
+            relay.task.abort();
+        }
+        Self::release_ports(port_allocator, &relay).await;
+        info!("Released relay {}", relay_id);
+        Some(relay.stats.total())
+    }
+
+    async fn release_ports(port_allocator: &Arc<RwLock<PortAllocator>>, relay: &RelayHandle) {
+        let mut allocator = port_allocator.write().await;
+        allocator.release_port(relay.info.port_a);
+        allocator.release_port(relay.info.port_b);
+    }
+}
@@ -0,0 +1,151 @@
+//! `nat-server report`: traffic per client or tunnel over a period, read
+//! from the history database for billing and capacity planning.
+
+use crate::storage::History;
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Utc};
+use nat_traversal_common::config::ServerConfig;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum ReportFormat {
+    Csv,
+    Json,
+}
+
+/// What each row of a report covers
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum ReportBy {
+    Client,
+    Tunnel,
+}
+
+/// Parse `YYYY-MM-DD` (midnight UTC) or an RFC 3339 time
+pub fn parse_time(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(at) = DateTime::parse_from_rfc3339(value) {
+        return Ok(at.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| Utc.from_utc_datetime(&date.and_time(Default::default())))
+        .map_err(|_| format!("Expected YYYY-MM-DD or an RFC 3339 time, got '{}'", value))
+}
+
+/// Print the report for `from` until before `to`; the current month so far
+/// by default
+pub fn run(
+    config: &ServerConfig,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    by: ReportBy,
+    format: ReportFormat,
+) -> anyhow::Result<()> {
+    let path = config
+        .storage
+        .database
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("No history is recorded; set storage.database"))?;
+    let now = Utc::now();
+    let from = from.unwrap_or_else(|| {
+        Utc.with_ymd_and_hms(now.year(), now.month(), 1, 0, 0, 0)
+            .unwrap()
+    });
+    let to = to.unwrap_or(now);
+    if from >= to {
+        anyhow::bail!("--from must be before --to");
+    }
+
+    let history = History::open(path)?;
+    match by {
+        ReportBy::Client => {
+            let clients = history.clients(from, to)?;
+            write(
+                format,
+                &clients,
+                &[
+                    "client_id",
+                    "sessions",
+                    "connected_secs",
+                    "tunnels",
+                    "connections",
+                    "bytes_sent",
+                    "bytes_received",
+                ],
+                |c| {
+                    vec![
+                        c.client_id.clone(),
+                        c.sessions.to_string(),
+                        c.connected_secs.to_string(),
+                        c.tunnels.to_string(),
+                        c.connections.to_string(),
+                        c.bytes_sent.to_string(),
+                        c.bytes_received.to_string(),
+                    ]
+                },
+            )
+        }
+        ReportBy::Tunnel => {
+            let tunnels = history.tunnels(from, to)?;
+            write(
+                format,
+                &tunnels,
+                &[
+                    "tunnel_id",
+                    "client_id",
+                    "name",
+                    "protocol",
+                    "remote_port",
+                    "domain",
+                    "created_at",
+                    "closed_at",
+                    "duration_secs",
+                    "connections",
+                    "bytes_sent",
+                    "bytes_received",
+                ],
+                |t| {
+                    vec![
+                        t.id.clone(),
+                        t.client_id.clone(),
+                        t.name.clone().unwrap_or_default(),
+                        t.protocol.clone(),
+                        t.remote_port.to_string(),
+                        t.domain.clone().unwrap_or_default(),
+                        t.created_at.to_rfc3339(),
+                        t.closed_at.map(|at| at.to_rfc3339()).unwrap_or_default(),
+                        t.duration_secs.to_string(),
+                        t.connections.to_string(),
+                        t.bytes_sent.to_string(),
+                        t.bytes_received.to_string(),
+                    ]
+                },
+            )
+        }
+    }
+}
+
+fn write<T: Serialize>(
+    format: ReportFormat,
+    rows: &[T],
+    header: &[&str],
+    fields: impl Fn(&T) -> Vec<String>,
+) -> anyhow::Result<()> {
+    match format {
+        ReportFormat::Json => println!("{}", serde_json::to_string_pretty(rows)?),
+        ReportFormat::Csv => {
+            println!("{}", header.join(","));
+            for row in rows {
+                let fields: Vec<String> = fields(row).iter().map(|f| csv_field(f)).collect();
+                println!("{}", fields.join(","));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Quote a field when it holds a separator, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
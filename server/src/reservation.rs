@@ -0,0 +1,83 @@
+//! Public ports and domains held for particular clients. Other clients can
+//! never take them, so a client finds its endpoints where it left them
+//! however long it was away.
+
+use nat_traversal_common::{config::Reservation, domain};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Default)]
+pub struct Reservations {
+    /// Client ID holding each reserved port
+    ports: HashMap<u16, String>,
+    /// Client ID holding each reserved domain, normalized
+    domains: HashMap<String, String>,
+}
+
+impl Reservations {
+    /// Index the configured reservations, refusing ports or domains
+    /// reserved for two clients
+    pub fn new(config: &BTreeMap<String, Reservation>) -> Result<Self, String> {
+        let mut reservations = Self::default();
+        for (client_id, reservation) in config {
+            for range in &reservation.ports {
+                for port in range.start..=range.end {
+                    if let Some(other) = reservations.ports.insert(port, client_id.clone()) {
+                        if other != *client_id {
+                            return Err(format!(
+                                "Port {} is reserved for both {} and {}",
+                                port, other, client_id
+                            ));
+                        }
+                    }
+                }
+            }
+            for requested in &reservation.domains {
+                let name = domain::normalize(requested)
+                    .ok_or_else(|| format!("'{}' is not a valid domain", requested))?;
+                if let Some(other) = reservations.domains.insert(name, client_id.clone()) {
+                    if other != *client_id {
+                        return Err(format!(
+                            "Domain {} is reserved for both {} and {}",
+                            requested, other, client_id
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(reservations)
+    }
+
+    pub fn port_owner(&self, port: u16) -> Option<&str> {
+        self.ports.get(&port).map(String::as_str)
+    }
+
+    /// The client holding `domain`, which must be normalized
+    pub fn domain_owner(&self, domain: &str) -> Option<&str> {
+        self.domains.get(domain).map(String::as_str)
+    }
+
+    /// Ports reserved for `client_id`, lowest first
+    pub fn ports_of(&self, client_id: &str) -> Vec<u16> {
+        let mut ports: Vec<u16> = self
+            .ports
+            .iter()
+            .filter(|(_, owner)| *owner == client_id)
+            .map(|(port, _)| *port)
+            .collect();
+        ports.sort_unstable();
+        ports
+    }
+
+    /// Refuse `count` ports from `start` when another client holds any
+    pub fn check_ports(&self, client_id: &str, start: u16, count: u16) -> Result<(), String> {
+        let end = start.saturating_add(count.saturating_sub(1));
+        match (start..=end).find_map(|port| {
+            self.port_owner(port)
+                .filter(|owner| *owner != client_id)
+                .map(|_| port)
+        }) {
+            Some(port) => Err(format!("Port {} is reserved for another client", port)),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,320 @@
+//! `nat-server --self-test`: start the server from its configuration, then
+//! connect to it as a client over TLS, open a tunnel, push data through the
+//! tunnel's public port and check it comes back unchanged. A quick sanity
+//! check of certificates, authentication and the tunnel port range after
+//! deploying.
+
+use crate::server::NatServer;
+use bytes::BytesMut;
+use nat_traversal_common::{
+    config::ServerConfig,
+    crypto,
+    protocol::{Message, TunnelProtocol, PROTOCOL_VERSION},
+    tls,
+};
+use rand::RngCore;
+use rustls_pemfile::certs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::{rustls, TlsConnector};
+use uuid::Uuid;
+
+/// How long each step may take
+const STEP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bytes pushed through the tunnel and expected back
+const PAYLOAD_SIZE: usize = 256 * 1024;
+
+const CLIENT_ID: &str = "self-test";
+
+/// Run the self-test and print a report; fails when any step does
+pub async fn run(mut config: ServerConfig) -> anyhow::Result<()> {
+    // A token only this process knows, so no configured client is needed
+    let token = Uuid::new_v4().simple().to_string();
+    config.auth.tokens.push(token.clone());
+    // Token authentication is what the in-process client can do
+    config.tls.verify_client = false;
+    // Leave the running server's control socket, history and HTTP
+    // listeners alone
+    config.control.enabled = false;
+    config.storage.database = None;
+    config.http.enabled = false;
+
+    let pinned = leaf_certificate(&config)?;
+    let addr = SocketAddr::new(loopback(config.network.bind_addr), config.network.port);
+    println!("Self-test against {}", addr);
+
+    let server = Arc::new(NatServer::new(config.clone()).await?);
+    let serving = {
+        let server = server.clone();
+        tokio::spawn(async move { server.run().await })
+    };
+
+    let result = check(&config, addr, pinned, &token, &serving).await;
+    serving.abort();
+    match result {
+        Ok(()) => {
+            println!("Self-test passed");
+            Ok(())
+        }
+        Err(e) => {
+            println!("  FAIL  {}", e);
+            Err(anyhow::anyhow!("Self-test failed"))
+        }
+    }
+}
+
+async fn check(
+    config: &ServerConfig,
+    addr: SocketAddr,
+    pinned: rustls::Certificate,
+    token: &str,
+    serving: &tokio::task::JoinHandle<nat_traversal_common::error::NatResult<()>>,
+) -> anyhow::Result<()> {
+    // The listener binds in the background; wait until it accepts
+    let started = Instant::now();
+    let tcp = loop {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => break stream,
+            Err(e) if serving.is_finished() || started.elapsed() > STEP_TIMEOUT => {
+                return Err(anyhow::anyhow!(
+                    "Server did not start on {}: {} (is another instance running? try --port)",
+                    addr,
+                    e
+                ));
+            }
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+    println!("  PASS  Server listening on {}", addr);
+
+    let mut tls_config = tls::restrict(rustls::ClientConfig::builder(), &config.tls.policy)?
+        .with_custom_certificate_verifier(Arc::new(PinnedCertificate(pinned)))
+        .with_no_client_auth();
+    tls_config.alpn_protocols = tls::alpn_protocols(&config.tls.policy);
+    let server_name = rustls::ServerName::try_from("localhost")?;
+    let mut stream = within(
+        "TLS handshake",
+        TlsConnector::from(Arc::new(tls_config)).connect(server_name, tcp),
+    )
+    .await?;
+    println!("  PASS  TLS handshake with the configured certificate");
+
+    write_message(&mut stream, &Message::RequestAuthChallenge).await?;
+    let nonce = match within("Authentication", read_message(&mut stream)).await? {
+        Message::AuthChallenge { nonce } => nonce,
+        other => {
+            return Err(anyhow::anyhow!(
+                "Unexpected reply to RequestAuthChallenge: {:?}",
+                other
+            ))
+        }
+    };
+    let auth = Message::Auth {
+        version: PROTOCOL_VERSION,
+        token: String::new(),
+        client_id: CLIENT_ID.to_string(),
+        proof: Some(crypto::auth_proof(token, &nonce, CLIENT_ID)),
+        heartbeat: None,
+        max_frame_size: None,
+    };
+    write_message(&mut stream, &auth).await?;
+    match within("Authentication", read_message(&mut stream)).await? {
+        Message::AuthResponse { success: true, .. } => {}
+        Message::AuthResponse { error, .. } => {
+            return Err(anyhow::anyhow!(
+                "Authentication refused: {}",
+                error.unwrap_or_default()
+            ))
+        }
+        other => return Err(anyhow::anyhow!("Unexpected reply to Auth: {:?}", other)),
+    }
+    println!("  PASS  Authenticated as {}", CLIENT_ID);
+
+    let create = Message::CreateTunnel {
+        local_host: "127.0.0.1".to_string(),
+        local_port: 7,
+        remote_port: None,
+        protocol: TunnelProtocol::Tcp,
+        name: Some(CLIENT_ID.to_string()),
+        port_count: 1,
+        ttl_secs: None,
+        domain: None,
+        http_auth: None,
+        tls_certificate: None,
+        tls_passthrough: false,
+        https_redirect: false,
+        http_headers: None,
+        dscp: None,
+        udp: None,
+    };
+    write_message(&mut stream, &create).await?;
+    let (tunnel_id, remote_port) = loop {
+        match within("Tunnel creation", read_message(&mut stream)).await? {
+            Message::TunnelCreated {
+                tunnel_id,
+                remote_port,
+                ..
+            } => break (tunnel_id, remote_port),
+            Message::Error { message, .. } => {
+                return Err(anyhow::anyhow!("Tunnel refused: {}", message))
+            }
+            _ => {}
+        }
+    };
+    println!("  PASS  Tunnel open on public port {}", remote_port);
+
+    let public = SocketAddr::new(addr.ip(), remote_port);
+    let elapsed = within("Round trip", echo(&mut stream, tunnel_id, public)).await?;
+    println!(
+        "  PASS  {} KiB through port {} and back unchanged in {} ms",
+        PAYLOAD_SIZE / 1024,
+        remote_port,
+        elapsed.as_millis()
+    );
+
+    write_message(&mut stream, &Message::CloseTunnel { tunnel_id }).await?;
+    Ok(())
+}
+
+/// Send the payload through the tunnel's public port while answering
+/// every `Data` for it with the same bytes, like an echo service behind a
+/// client, until the payload has come back
+async fn echo<S>(control: &mut S, tunnel_id: Uuid, public: SocketAddr) -> anyhow::Result<Duration>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut payload = vec![0u8; PAYLOAD_SIZE];
+    rand::thread_rng().fill_bytes(&mut payload);
+
+    let started = Instant::now();
+    let visitor = TcpStream::connect(public)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to connect to public port {}: {}", public, e))?;
+    let (mut visitor_read, mut visitor_write) = visitor.into_split();
+
+    let send = async {
+        visitor_write.write_all(&payload).await?;
+        Ok::<_, anyhow::Error>(())
+    };
+    let receive = async {
+        let mut returned = vec![0u8; PAYLOAD_SIZE];
+        visitor_read.read_exact(&mut returned).await?;
+        Ok::<_, anyhow::Error>(returned)
+    };
+
+    let returned = tokio::select! {
+        result = async { tokio::try_join!(send, receive) } => result?.1,
+        result = echo_data(control, tunnel_id) => {
+            result?;
+            return Err(anyhow::anyhow!("Control connection closed"));
+        }
+    };
+    if returned != payload {
+        return Err(anyhow::anyhow!("Data came back altered"));
+    }
+    Ok(started.elapsed())
+}
+
+/// Answer the tunnel's `Data` messages with the same bytes until the
+/// control connection fails
+async fn echo_data<S>(control: &mut S, tunnel_id: Uuid) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        if let Message::Data {
+            tunnel_id: id,
+            data,
+            connection_id,
+        } = read_message(control).await?
+        {
+            if id == tunnel_id {
+                let reply = Message::Data {
+                    tunnel_id,
+                    data,
+                    connection_id,
+                };
+                write_message(control, &reply).await?;
+            }
+        }
+    }
+}
+
+async fn within<T, E>(
+    step: &str,
+    future: impl std::future::Future<Output = Result<T, E>>,
+) -> anyhow::Result<T>
+where
+    E: Into<anyhow::Error>,
+{
+    match tokio::time::timeout(STEP_TIMEOUT, future).await {
+        Ok(result) => result.map_err(|e| anyhow::anyhow!("{}: {}", step, e.into())),
+        Err(_) => Err(anyhow::anyhow!("{} timed out", step)),
+    }
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &Message,
+) -> anyhow::Result<()> {
+    let mut frame = BytesMut::new();
+    message.encode_frame(&mut frame)?;
+    writer.write_all(&frame).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<Message> {
+    let len = reader.read_u32().await? as usize;
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data).await?;
+    Message::from_bytes(&data)
+}
+
+/// Where to reach a listener bound to `bind`
+fn loopback(bind: IpAddr) -> IpAddr {
+    match bind {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    }
+}
+
+/// The certificate the server presents, first in `tls.cert_path`
+fn leaf_certificate(config: &ServerConfig) -> anyhow::Result<rustls::Certificate> {
+    let pem = std::fs::read(&config.tls.cert_path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", config.tls.cert_path.display(), e))?;
+    certs(&mut pem.as_slice())?
+        .into_iter()
+        .next()
+        .map(rustls::Certificate)
+        .ok_or_else(|| anyhow::anyhow!("No certificate in {}", config.tls.cert_path.display()))
+}
+
+/// Accepts exactly the certificate the server was configured with, since
+/// the name it was issued for need not resolve to this machine
+struct PinnedCertificate(rustls::Certificate);
+
+impl rustls::client::ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        if *end_entity == self.0 {
+            Ok(rustls::client::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "The server presented a different certificate".to_string(),
+            ))
+        }
+    }
+}
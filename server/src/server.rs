@@ -1,49 +1,219 @@
-use crate::{connection::*, tunnel::TunnelManager};
+use crate::{
+    ca::CertificateAuthority,
+    connection::*,
+    control::{self, Inspector},
+    frp::FrpServer,
+    grpc,
+    plugin::Plugins,
+    provision::Provisioning,
+    relay::RelayManager,
+    reservation::Reservations,
+    speedtest,
+    storage::Storage,
+    token::JwtVerifier,
+    transport::TlsListener,
+    tunnel::{Capacity, TunnelManager, TUNNEL_PORTS},
+    usage::UsageLedger,
+    vhost::{self, CertStore, DomainVerifier, OfflinePage},
+    vpn::VpnHub,
+    websocket,
+    wireguard::WireGuardBroker,
+};
 use nat_traversal_common::{
-    config::ServerConfig,
+    batch::{self, FrameLimit},
+    config::{get_config_dir, FlushPolicy, PerformanceConfig, ServerConfig},
+    control::ControlListener,
+    crypto, data_channel,
     error::{NatError, NatResult},
-    protocol::{ErrorCode, Message, PROTOCOL_VERSION},
+    protocol::{
+        ErrorCode, Message, ShutdownDirection, TunnelProtocol, UsageInfo, PROTOCOL_VERSION,
+    },
+    stun, telemetry, tls,
+    transport::{self, Established, Listener, Transport},
 };
+use nat_traversal_platform::{notify, privileges::drop_privileges};
 use rustls_pemfile::{certs, pkcs8_private_keys};
 use std::fs::File;
 use std::io::BufReader;
 use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{TcpListener, UdpSocket};
 use tokio::sync::mpsc;
 use tokio_rustls::{rustls, TlsAcceptor};
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+/// How often capacity in use is checked and reported
+const CAPACITY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+/// Share of a capped resource in use that draws a warning
+const CAPACITY_WARNING: f64 = 90.0;
 
 /// Main server structure
 pub struct NatServer {
     config: ServerConfig,
     connection_manager: Arc<ConnectionManager>,
     tunnel_manager: Arc<TunnelManager>,
-    tls_acceptor: TlsAcceptor,
+    relay_manager: Arc<RelayManager>,
+    /// Missing on servers built for tests, which take their clients
+    /// through `serve`
+    tls_acceptor: Option<TlsAcceptor>,
+    /// Present when the HTTPS listener is enabled
+    https_acceptor: Option<TlsAcceptor>,
+    /// What the HTTP and HTTPS listeners answer for offline clients
+    offline_page: Arc<OfflinePage>,
 }
 
 impl NatServer {
     pub async fn new(config: ServerConfig) -> NatResult<Self> {
+        Self::build(config, true).await
+    }
+
+    /// A server without certificates, for tests that hand it clients
+    /// through `serve`; `run` refuses to start it
+    #[cfg(feature = "test-util")]
+    pub async fn without_tls(config: ServerConfig) -> NatResult<Self> {
+        Self::build(config, false).await
+    }
+
+    async fn build(config: ServerConfig, tls: bool) -> NatResult<Self> {
+        let ca = match &config.ca {
+            Some(ca) => Some(CertificateAuthority::load_or_create(ca)?),
+            None => None,
+        };
+
         // Setup TLS
-        let tls_acceptor = Self::setup_tls(&config).await?;
+        let tls_acceptor = if tls {
+            Some(Self::setup_tls(&config, ca.as_ref()).await?)
+        } else {
+            None
+        };
 
         // Create connection manager
-        let connection_manager = Arc::new(ConnectionManager::new(config.auth.tokens.clone()));
+        let tokens = config
+            .auth
+            .load_tokens()
+            .map_err(|e| NatError::config(format!("Failed to load tokens: {}", e)))?;
+        let jwt = match &config.auth.jwt {
+            Some(jwt) => Some(
+                JwtVerifier::new(jwt)
+                    .map_err(|e| NatError::config(format!("Invalid JWT settings: {}", e)))?,
+            ),
+            None => None,
+        };
+        let storage = match &config.storage.database {
+            Some(path) => {
+                let storage = Storage::open(path, config.storage.retention_days).map_err(|e| {
+                    NatError::config(format!("Failed to open {}: {}", path.display(), e))
+                })?;
+                info!("Recording history in {}", path.display());
+                Some(Arc::new(storage))
+            }
+            None => None,
+        };
+        let usage_file = match &config.usage.file {
+            Some(path) => Some(path.clone()),
+            None => get_config_dir()
+                .map(|dir| dir.join("usage.json"))
+                .map_err(|e| warn!("Usage will not be saved: {}", e))
+                .ok(),
+        };
+        let usage = Arc::new(
+            UsageLedger::open(usage_file)
+                .map_err(|e| NatError::config(format!("Failed to load usage: {}", e)))?,
+        );
+        let vpn = if config.vpn.enabled {
+            Some(VpnHub::open(&config.vpn).await?)
+        } else {
+            None
+        };
+        if config.auth.allow_plain_tokens {
+            warn!("Accepting static tokens sent in the clear by old clients (auth.allow_plain_tokens)");
+        }
+        let connection_manager = Arc::new(ConnectionManager::new(
+            tokens,
+            config.auth.allow_plain_tokens,
+            config.auth.scopes.clone(),
+            jwt,
+            ca,
+            config.tls.verify_client,
+            config.limits.max_data_connections as usize,
+            storage,
+            usage,
+            config.auth.duplicate_client_id,
+            Provisioning::new(&config.provisioned).map_err(NatError::config)?,
+            config.auth.motd.clone(),
+            vpn,
+            config
+                .wireguard
+                .enabled
+                .then(|| WireGuardBroker::new(&config.wireguard, config.network.bind_addr)),
+        ));
+
+        let domain_verifier = if config.http.enabled {
+            Some(DomainVerifier::new(&config.http).map_err(|e| {
+                NatError::config(format!("No DNS resolver for domain checks: {}", e))
+            })?)
+        } else {
+            None
+        };
+
+        let certificates = match config.http.https_port {
+            Some(_) if config.http.enabled => Some(Arc::new(
+                CertStore::new(&config.http.certificates).map_err(NatError::config)?,
+            )),
+            _ => None,
+        };
+        let https_acceptor = certificates.as_ref().map(CertStore::acceptor);
+        let offline_page = Arc::new(OfflinePage::new(&config.http).map_err(NatError::config)?);
 
         // Create tunnel manager
+        let reservations =
+            Arc::new(Reservations::new(&config.reservations).map_err(NatError::config)?);
         let tunnel_manager = Arc::new(TunnelManager::new(
             connection_manager.clone(),
-            (8000, 9000), // Port range for tunnels
+            TUNNEL_PORTS,
+            config.performance,
+            config.sockets.tunnel.clone(),
+            config.sockets.accept_workers,
+            config.limits.max_ports_per_tunnel,
+            config.limits.max_share_ttl_secs,
+            domain_verifier,
+            certificates,
+            reservations,
+            config.limits.resume_limits(),
+            config.limits.connection_buffer(),
+            Capacity {
+                max_tunnels: config.limits.max_total_tunnels,
+                max_connections: config.limits.max_total_connections,
+                max_ports: config.limits.max_ports_in_use,
+            },
+            Arc::new(Plugins::load(&config.plugins)?),
+        ));
+
+        // Relays share the public port range with tunnels
+        let relay_manager = Arc::new(RelayManager::new(
+            tunnel_manager.port_allocator(),
+            connection_manager.clone(),
+            config.limits.max_relays_per_client,
+            std::time::Duration::from_secs(config.limits.relay_idle_timeout_secs),
         ));
 
         Ok(Self {
             config,
             connection_manager,
             tunnel_manager,
+            relay_manager,
             tls_acceptor,
+            https_acceptor,
+            offline_page,
         })
     }
 
-    async fn setup_tls(config: &ServerConfig) -> NatResult<TlsAcceptor> {
+    /// TLS settings for client connections, from the configured
+    /// certificate, key and client CAs
+    pub(crate) async fn setup_tls(
+        config: &ServerConfig,
+        ca: Option<&CertificateAuthority>,
+    ) -> NatResult<TlsAcceptor> {
         // Load certificates
         let cert_file = File::open(&config.tls.cert_path)
             .map_err(|e| NatError::config(format!("Failed to open cert file: {}", e)))?;
@@ -67,17 +237,62 @@ impl NatServer {
 
         let private_key = rustls::PrivateKey(keys.remove(0));
 
+        // Client certificates are checked when offered but not demanded,
+        // so clients can still connect to enroll
+        let mut roots = rustls::RootCertStore::empty();
+        if let Some(ca) = ca {
+            roots
+                .add(&rustls::Certificate(ca.cert_der().to_vec()))
+                .map_err(|e| NatError::config(format!("Unusable CA certificate: {}", e)))?;
+        }
+        if let Some(ca_path) = &config.tls.ca_path {
+            let ca_file = File::open(ca_path)
+                .map_err(|e| NatError::config(format!("Failed to open CA file: {}", e)))?;
+            let ca_certs = certs(&mut BufReader::new(ca_file))
+                .map_err(|e| NatError::config(format!("Failed to parse CA file: {}", e)))?;
+            roots.add_parsable_certificates(&ca_certs);
+        }
+        let builder = tls::restrict(rustls::ServerConfig::builder(), &config.tls.policy)?;
+        let builder = if roots.is_empty() {
+            if config.tls.verify_client {
+                return Err(NatError::config(
+                    "tls.verify_client needs tls.ca_path or a [ca] section",
+                ));
+            }
+            builder.with_no_client_auth()
+        } else {
+            builder.with_client_cert_verifier(
+                rustls::server::AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+            )
+        };
+
         // Configure TLS
-        let tls_config = rustls::ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
+        let mut tls_config = builder
             .with_single_cert(cert_chain, private_key)
             .map_err(|e| NatError::config(format!("Failed to configure TLS: {}", e)))?;
+        tls_config.alpn_protocols = tls::alpn_protocols(&config.tls.policy);
 
         Ok(TlsAcceptor::from(Arc::new(tls_config)))
     }
 
+    /// Tell every connected client the server is going away and give them
+    /// a moment to hang up, before exiting
+    pub async fn disconnect_all(&self, reason: &str) {
+        self.connection_manager.disconnect_all(reason).await;
+    }
+
+    /// Write monthly usage to disk, before exiting
+    pub fn save_usage(&self) {
+        if let Err(e) = self.connection_manager.usage().save() {
+            warn!("Failed to save usage: {}", e);
+        }
+    }
+
     pub async fn run(&self) -> NatResult<()> {
+        let tls_acceptor = self
+            .tls_acceptor
+            .clone()
+            .ok_or_else(|| NatError::config("The server was built without TLS"))?;
         let bind_addr = format!(
             "{}:{}",
             self.config.network.bind_addr, self.config.network.port
@@ -88,26 +303,262 @@ impl NatServer {
 
         info!("NAT Traversal Server listening on {}", bind_addr);
 
+        // STUN responder on the same port lets clients learn their public address
+        if self.config.network.stun_enabled {
+            match UdpSocket::bind(&bind_addr).await {
+                Ok(socket) => {
+                    info!("STUN responder listening on udp/{}", bind_addr);
+                    // Second port for clients probing their NAT's filtering
+                    let alternate = UdpSocket::bind((self.config.network.bind_addr, 0))
+                        .await
+                        .map_err(|e| warn!("Failed to bind alternate STUN port: {}", e))
+                        .ok();
+                    tokio::spawn(async move {
+                        if let Err(e) = stun::serve(socket, alternate).await {
+                            error!("STUN responder stopped: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Failed to bind STUN responder on {}: {}", bind_addr, e),
+            }
+        }
+
+        // The HTTP port is usually privileged too
+        if self.config.http.enabled {
+            let http_addr = format!("{}:{}", self.config.http.bind_addr, self.config.http.port);
+            let http_listener = TcpListener::bind(&http_addr).await.map_err(|e| {
+                NatError::network(format!("Failed to bind HTTP listener {}: {}", http_addr, e))
+            })?;
+            info!("HTTP listener on {}", http_addr);
+            tokio::spawn(vhost::serve(
+                http_listener,
+                self.tunnel_manager.clone(),
+                self.config.sockets.tunnel.clone(),
+                self.https_acceptor
+                    .as_ref()
+                    .and(self.config.http.https_port),
+                self.offline_page.clone(),
+                self.config.http.compress,
+            ));
+        }
+        if let (Some(acceptor), Some(port)) = (&self.https_acceptor, self.config.http.https_port) {
+            let https_addr = format!("{}:{}", self.config.http.bind_addr, port);
+            let https_listener = TcpListener::bind(&https_addr).await.map_err(|e| {
+                NatError::network(format!(
+                    "Failed to bind HTTPS listener {}: {}",
+                    https_addr, e
+                ))
+            })?;
+            info!("HTTPS listener on {}", https_addr);
+            tokio::spawn(vhost::serve_tls(
+                https_listener,
+                acceptor.clone(),
+                self.tunnel_manager.clone(),
+                self.config.sockets.tunnel.clone(),
+                self.offline_page.clone(),
+                self.config.http.compress,
+            ));
+        }
+
+        if self.config.frp.enabled {
+            let frp_addr = format!("{}:{}", self.config.frp.bind_addr, self.config.frp.port);
+            let frp_listener = TcpListener::bind(&frp_addr).await.map_err(|e| {
+                NatError::network(format!("Failed to bind frp listener {}: {}", frp_addr, e))
+            })?;
+            info!("frp listener on {}", frp_addr);
+            let frp = FrpServer::new(
+                &self.config.frp,
+                Some(tls_acceptor.clone()),
+                self.connection_manager.clone(),
+                self.tunnel_manager.clone(),
+            );
+            tokio::spawn(Arc::new(frp).serve(frp_listener));
+        }
+
+        let inspector = Inspector {
+            connection_manager: self.connection_manager.clone(),
+            tunnel_manager: self.tunnel_manager.clone(),
+            relay_manager: self.relay_manager.clone(),
+        };
+        if self.config.grpc.enabled {
+            let listen = self.config.grpc.listen;
+            let grpc_listener = TcpListener::bind(listen).await.map_err(|e| {
+                NatError::network(format!("Failed to bind gRPC listener {}: {}", listen, e))
+            })?;
+            info!("gRPC API listening on {}", listen);
+            if !listen.ip().is_loopback() {
+                warn!(
+                    "The gRPC API on {} is not encrypted; its tokens cross the network in the clear",
+                    listen
+                );
+            }
+            tokio::spawn(grpc::serve(
+                grpc_listener,
+                self.config.grpc.clone(),
+                inspector.clone(),
+            ));
+        }
+
+        if self.config.events.enabled {
+            let listen = self.config.events.listen;
+            let events_listener = TcpListener::bind(listen).await.map_err(|e| {
+                NatError::network(format!("Failed to bind event stream {}: {}", listen, e))
+            })?;
+            info!("Event stream on ws://{}/events", listen);
+            tokio::spawn(websocket::serve(
+                events_listener,
+                self.config.events.clone(),
+                self.connection_manager.clone(),
+                self.tunnel_manager.clone(),
+            ));
+        }
+
+        // Bound before dropping privileges, so only the starting user can inspect
+        if self.config.control.enabled {
+            let listener = match control::socket_path(&self.config) {
+                Ok(path) => ControlListener::bind(&path)
+                    .await
+                    .map_err(|e| warn!("Control socket {} unavailable: {}", path.display(), e))
+                    .ok(),
+                Err(e) => {
+                    warn!("No control socket: {}", e);
+                    None
+                }
+            };
+            if let Some(listener) = listener {
+                tokio::spawn(inspector.clone().serve(listener));
+            }
+
+            let observer_tokens = self.config.control.observer_tokens.clone();
+            if !observer_tokens.is_empty() {
+                match control::observer_socket_path(&self.config) {
+                    Ok(path) => match ControlListener::bind_shared(&path).await {
+                        Ok(listener) => {
+                            tokio::spawn(inspector.serve_observers(listener, observer_tokens));
+                        }
+                        Err(e) => {
+                            warn!("Observer socket {} unavailable: {}", path.display(), e)
+                        }
+                    },
+                    Err(e) => warn!("No observer socket: {}", e),
+                }
+            }
+        }
+
+        // Everything privileged (listener, TLS keys, token files) is held now
+        if let Some(user) = &self.config.network.run_as_user {
+            drop_privileges(user, self.config.network.run_as_group.as_deref())
+                .map_err(|e| NatError::config(format!("Failed to drop privileges: {}", e)))?;
+        }
+
+        tokio::spawn(
+            self.connection_manager
+                .usage()
+                .clone()
+                .run(std::time::Duration::from_secs(
+                    self.config.usage.save_interval_secs.max(1),
+                )),
+        );
+
+        if self.connection_manager.storage().is_some() {
+            let connection_manager = self.connection_manager.clone();
+            let tunnel_manager = self.tunnel_manager.clone();
+            let interval =
+                std::time::Duration::from_secs(self.config.storage.checkpoint_secs.max(1));
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                ticker.tick().await;
+                loop {
+                    ticker.tick().await;
+                    connection_manager.checkpoint().await;
+                    tunnel_manager.checkpoint().await;
+                }
+            });
+        }
+
+        // Report capacity in use, and warn as a capped resource runs short
+        let tunnel_manager = self.tunnel_manager.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(CAPACITY_INTERVAL);
+            let mut short = [false; 3];
+            loop {
+                ticker.tick().await;
+                let utilization = tunnel_manager.utilization().await;
+                telemetry::utilization(&utilization);
+                let gauges = [
+                    ("tunnels", utilization.tunnels),
+                    ("connections", utilization.connections),
+                    ("ports", utilization.ports),
+                ];
+                for ((resource, gauge), short) in gauges.into_iter().zip(short.iter_mut()) {
+                    let percent = gauge.percent().unwrap_or_default();
+                    if percent >= CAPACITY_WARNING && !*short {
+                        warn!(
+                            "{:.0}% of the server's {} are in use ({} of {})",
+                            percent,
+                            resource,
+                            gauge.used,
+                            gauge.limit.unwrap_or_default()
+                        );
+                    }
+                    *short = percent >= CAPACITY_WARNING;
+                }
+            }
+        });
+
+        notify::notify_ready();
+        if let Some(interval) = notify::watchdog_interval() {
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval / 2);
+                loop {
+                    ticker.tick().await;
+                    notify::notify_watchdog();
+                }
+            });
+        }
+
+        let listener =
+            TlsListener::new(listener, tls_acceptor, self.config.sockets.control.clone());
+        self.serve(Arc::new(listener)).await
+    }
+
+    /// Serve the clients that connect through `listener`
+    pub async fn serve<L: Listener>(&self, listener: Arc<L>) -> NatResult<()> {
         loop {
             match listener.accept().await {
-                Ok((stream, addr)) => {
-                    let tls_acceptor = self.tls_acceptor.clone();
+                Ok((incoming, addr)) => {
+                    let listener = listener.clone();
                     let connection_manager = self.connection_manager.clone();
                     let tunnel_manager = self.tunnel_manager.clone();
+                    let relay_manager = self.relay_manager.clone();
+                    let performance = self.config.performance;
+                    let heartbeat_timeout = self.config.limits.heartbeat_timeout_secs;
+                    let heartbeat_timeout = (heartbeat_timeout > 0)
+                        .then(|| std::time::Duration::from_secs(heartbeat_timeout));
 
-                    tokio::spawn(async move {
-                        if let Err(e) = Self::handle_client(
-                            stream,
-                            addr,
-                            tls_acceptor,
-                            connection_manager,
-                            tunnel_manager,
-                        )
-                        .await
-                        {
-                            error!("Error handling client {}: {}", addr, e);
+                    let span = info_span!(
+                        "connection",
+                        %addr,
+                        client_id = tracing::field::Empty
+                    );
+                    tokio::spawn(
+                        async move {
+                            if let Err(e) = Self::handle_client(
+                                listener.establish(incoming),
+                                addr,
+                                connection_manager,
+                                tunnel_manager,
+                                relay_manager,
+                                performance,
+                                heartbeat_timeout,
+                            )
+                            .await
+                            {
+                                error!("Error handling client {}: {}", addr, e);
+                            }
                         }
-                    });
+                        .instrument(span),
+                    );
                 }
                 Err(e) => {
                     error!("Failed to accept connection: {}", e);
@@ -116,39 +567,81 @@ impl NatServer {
         }
     }
 
-    async fn handle_client(
-        stream: TcpStream,
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_client<S: Transport>(
+        establish: impl std::future::Future<Output = NatResult<Established<S>>>,
         addr: std::net::SocketAddr,
-        tls_acceptor: TlsAcceptor,
         connection_manager: Arc<ConnectionManager>,
         tunnel_manager: Arc<TunnelManager>,
+        relay_manager: Arc<RelayManager>,
+        performance: PerformanceConfig,
+        heartbeat_timeout: Option<std::time::Duration>,
     ) -> NatResult<()> {
         debug!("New connection from {}", addr);
 
-        // Perform TLS handshake
-        let tls_stream = tls_acceptor
-            .accept(stream)
-            .await
-            .map_err(|e| NatError::tls(format!("TLS handshake failed: {}", e)))?;
+        let Established {
+            mut stream,
+            identity: certificate,
+        } = establish.await?;
+
+        // A data channel announces itself with its first message
+        let first = tokio::select! {
+            data = Self::read_frame(&mut stream, performance.max_frame_size) => match data {
+                Some(data) => data,
+                None => return Ok(()),
+            },
+            _ = Self::silence(heartbeat_timeout) => {
+                debug!("{} sent nothing after the handshake", addr);
+                return Ok(());
+            }
+        };
+        if let Ok(Message::AttachDataChannel { client_id, key }) = Message::from_bytes(&first) {
+            return Self::handle_data_channel(
+                stream,
+                addr,
+                client_id,
+                key,
+                connection_manager,
+                tunnel_manager,
+                performance,
+            )
+            .await;
+        }
 
         // Setup message channels
-        let (tx, mut rx) = mpsc::unbounded_channel();
-        let (read_half, write_half) = tokio::io::split(tls_stream);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (read_half, write_half) = tokio::io::split(stream);
+        // Raised or lowered to what the client advertises when it logs in
+        let frame_limit = FrameLimit::default();
 
         // Handle message sending
-        let write_task = tokio::spawn(async move { Self::handle_write(write_half, rx).await });
+        let write_task = {
+            let frame_limit = frame_limit.clone();
+            tokio::spawn(async move {
+                Self::handle_write(write_half, rx, performance.flush, &frame_limit).await
+            })
+        };
 
         // Handle message receiving and processing
-        let read_task = tokio::spawn(async move {
-            Self::handle_read(
-                read_half,
-                addr,
-                tx.clone(),
-                connection_manager,
-                tunnel_manager,
-            )
-            .await
-        });
+        let read_task = tokio::spawn(
+            async move {
+                Self::handle_read(
+                    read_half,
+                    first,
+                    addr,
+                    certificate,
+                    tx.clone(),
+                    connection_manager,
+                    tunnel_manager,
+                    relay_manager,
+                    performance.max_frame_size,
+                    frame_limit,
+                    heartbeat_timeout,
+                )
+                .await
+            }
+            .in_current_span(),
+        );
 
         // Wait for either task to complete
         tokio::select! {
@@ -160,56 +653,255 @@ impl NatServer {
         Ok(())
     }
 
-    async fn handle_write(
-        mut writer: tokio::io::WriteHalf<tokio_rustls::server::TlsStream<TcpStream>>,
-        mut rx: mpsc::UnboundedReceiver<Message>,
+    /// Carry an authenticated client's tunnel traffic as binary frames
+    async fn handle_data_channel<S: Transport>(
+        mut stream: S,
+        addr: std::net::SocketAddr,
+        client_id: String,
+        key: Uuid,
+        connection_manager: Arc<ConnectionManager>,
+        tunnel_manager: Arc<TunnelManager>,
+        performance: PerformanceConfig,
     ) -> NatResult<()> {
-        use tokio::io::AsyncWriteExt;
+        tracing::Span::current().record("client_id", client_id.as_str());
+        let client = match connection_manager.get_client(&client_id).await {
+            Some(client) if client.data_key == key => client,
+            _ => {
+                warn!(
+                    "Rejected data channel from {} for client {}",
+                    addr, client_id
+                );
+                return Ok(());
+            }
+        };
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let attached = client
+            .attach_data_channel(tx.clone(), connection_manager.max_data_connections)
+            .await;
+
+        // Reply with control framing; binary frames follow an acceptance
+        let reply = if attached {
+            Message::DataChannelAttached
+        } else {
+            Message::Error {
+                code: ErrorCode::RateLimitExceeded,
+                message: format!(
+                    "At most {} data connections per client",
+                    connection_manager.max_data_connections
+                ),
+            }
+        };
+        transport::write_frame(&mut stream, &reply).await?;
+        if !attached {
+            warn!("Client {} has too many data channels", client.id);
+            return Ok(());
+        }
+
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        info!("Client {} attached a data channel from {}", client.id, addr);
 
-        while let Some(message) = rx.recv().await {
-            let data = message.to_bytes()?;
-            let len = data.len() as u32;
+        let frame_limit = client.frame_limit.clone();
+        let mut write_task = tokio::spawn(async move {
+            batch::write_batched(
+                &mut writer,
+                &mut rx,
+                performance.flush,
+                &frame_limit,
+                data_channel::encode,
+            )
+            .await
+        });
+
+        let read_task = async {
+            loop {
+                match data_channel::read_message(&mut reader, performance.max_frame_size).await {
+                    Ok(Some(Message::Data {
+                        tunnel_id,
+                        data,
+                        connection_id,
+                    })) => {
+                        if tunnel_manager.tunnel_owner(&tunnel_id).await.as_ref()
+                            != Some(&client.id)
+                        {
+                            debug!(
+                                "Client {} may not send data to tunnel {}",
+                                client.id, tunnel_id
+                            );
+                        } else if let Err(e) = tunnel_manager
+                            .forward_data(&tunnel_id, connection_id, data)
+                            .await
+                        {
+                            debug!("Dropping data for tunnel {}: {}", tunnel_id, e);
+                        }
+                    }
+                    Ok(Some(Message::ConnectionClosed {
+                        tunnel_id,
+                        connection_id,
+                    })) => {
+                        if tunnel_manager.tunnel_owner(&tunnel_id).await.as_ref()
+                            == Some(&client.id)
+                        {
+                            tunnel_manager
+                                .close_connection(&tunnel_id, connection_id)
+                                .await;
+                        } else {
+                            debug!(
+                                "Client {} may not close connections of tunnel {}",
+                                client.id, tunnel_id
+                            );
+                        }
+                    }
+                    Ok(Some(Message::ConnectionShutdown {
+                        tunnel_id,
+                        connection_id,
+                        direction: ShutdownDirection::Outbound,
+                    })) => {
+                        if tunnel_manager.tunnel_owner(&tunnel_id).await.as_ref()
+                            == Some(&client.id)
+                        {
+                            tunnel_manager
+                                .shutdown_connection(&tunnel_id, connection_id)
+                                .await;
+                        } else {
+                            debug!(
+                                "Client {} may not shut down connections of tunnel {}",
+                                client.id, tunnel_id
+                            );
+                        }
+                    }
+                    Ok(Some(Message::VpnPacket { data })) => {
+                        if let Some(vpn) = connection_manager.vpn() {
+                            vpn.from_client(&client.id, data).await;
+                        }
+                    }
+                    Ok(Some(message)) => {
+                        warn!("Unexpected data channel message: {:?}", message);
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("Data channel error for client {}: {}", client.id, e);
+                        break;
+                    }
+                }
+            }
+        };
 
-            // Write length prefix
-            writer.write_all(&len.to_be_bytes()).await?;
-            // Write message data
-            writer.write_all(&data).await?;
-            writer.flush().await?;
+        tokio::select! {
+            _ = &mut write_task => {},
+            _ = read_task => {},
         }
 
+        // Connections routed here notice the closed channel and move on
+        client.detach_data_channel(&tx).await;
+        write_task.abort();
+        debug!("Data channel for client {} closed", client.id);
+        Ok(())
+    }
+
+    async fn handle_write<S: Transport>(
+        mut writer: tokio::io::WriteHalf<S>,
+        mut rx: mpsc::UnboundedReceiver<Message>,
+        flush: FlushPolicy,
+        frame_limit: &FrameLimit,
+    ) -> NatResult<()> {
+        batch::write_batched(
+            &mut writer,
+            &mut rx,
+            flush,
+            frame_limit,
+            Message::encode_frame,
+        )
+        .await?;
         Ok(())
     }
 
-    async fn handle_read(
-        mut reader: tokio::io::ReadHalf<tokio_rustls::server::TlsStream<TcpStream>>,
+    /// Read one length-prefixed frame, `None` when the stream ends or
+    /// the frame is over `max_len`
+    async fn read_frame<R: tokio::io::AsyncRead + Unpin>(
+        reader: &mut R,
+        max_len: usize,
+    ) -> Option<Vec<u8>> {
+        match transport::read_frame(reader, max_len).await {
+            Ok(data) => data,
+            Err(e @ NatError::Protocol { .. }) => {
+                error!("{}", e);
+                None
+            }
+            Err(_) => None,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_read<S: Transport>(
+        mut reader: tokio::io::ReadHalf<S>,
+        first: Vec<u8>,
         addr: std::net::SocketAddr,
+        certificate: Option<String>,
         tx: mpsc::UnboundedSender<Message>,
         connection_manager: Arc<ConnectionManager>,
         tunnel_manager: Arc<TunnelManager>,
+        relay_manager: Arc<RelayManager>,
+        max_frame_size: usize,
+        frame_limit: FrameLimit,
+        heartbeat_timeout: Option<std::time::Duration>,
     ) -> NatResult<()> {
-        use tokio::io::AsyncReadExt;
-
         let mut client_connection: Option<Arc<ClientConnection>> = None;
+        // Nonce of the outstanding authentication challenge
+        let mut challenge: Option<String> = None;
+        let mut pending = Some(first);
+        // Whether the client may come back for its open connections
+        let mut resumable = true;
 
         loop {
-            // Read message length
-            let mut len_buf = [0u8; 4];
-            if reader.read_exact(&mut len_buf).await.is_err() {
-                break;
-            }
-            let len = u32::from_be_bytes(len_buf) as usize;
-
-            if len > 1024 * 1024 {
-                // 1MB limit
-                error!("Message too large: {} bytes", len);
-                break;
-            }
-
-            // Read message data
-            let mut data = vec![0u8; len];
-            if reader.read_exact(&mut data).await.is_err() {
-                break;
-            }
+            let data = match pending.take() {
+                Some(data) => data,
+                None => {
+                    let client = client_connection.clone();
+                    // Once authenticated, the client's own heartbeat
+                    // schedule decides how long it may stay silent
+                    let silence = heartbeat_timeout.and_then(|timeout| {
+                        client
+                            .as_ref()
+                            .and_then(|client| client.heartbeat)
+                            .and_then(|heartbeat| heartbeat.timeout())
+                            .or(Some(timeout))
+                    });
+                    tokio::select! {
+                        data = Self::read_frame(&mut reader, max_frame_size) => match data {
+                            Some(data) => data,
+                            None => break,
+                        },
+                        _ = Self::kicked(client.as_deref()) => {
+                            // A newer session may pick up the open
+                            // connections, as the same client reconnecting
+                            // from another network does
+                            if client.as_ref().is_some_and(|client| client.replaced()) {
+                                info!("Dropping client {} for a newer session with its ID", addr);
+                                let _ = tx.send(Message::Disconnect {
+                                    reason: "Another client logged in with this client ID"
+                                        .to_string(),
+                                });
+                            } else {
+                                info!("Dropping client {} on the server's request", addr);
+                                let _ = tx.send(Message::Disconnect {
+                                    reason: "Disconnected by the server".to_string(),
+                                });
+                                resumable = false;
+                            }
+                            break;
+                        }
+                        _ = Self::silence(silence) => {
+                            warn!(
+                                "No heartbeat from {} in {} seconds, dropping it",
+                                addr,
+                                silence.unwrap_or_default().as_secs()
+                            );
+                            break;
+                        }
+                    }
+                }
+            };
 
             // Parse message
             let message = match Message::from_bytes(&data) {
@@ -219,23 +911,38 @@ impl NatServer {
                     continue;
                 }
             };
+            if let Message::Disconnect { reason } = &message {
+                info!("Client {} disconnected: {}", addr, reason);
+                resumable = false;
+                break;
+            }
 
             // Handle message
             if let Err(e) = Self::handle_message(
                 message,
                 &mut client_connection,
+                &mut challenge,
                 addr,
+                certificate.as_deref(),
                 &tx,
                 &connection_manager,
                 &tunnel_manager,
+                &relay_manager,
+                max_frame_size,
+                &frame_limit,
             )
             .await
             {
                 error!("Error handling message: {}", e);
 
                 // Send error response
+                let code = match e {
+                    NatError::PermissionDenied { .. } => ErrorCode::PermissionDenied,
+                    NatError::LimitReached { .. } => ErrorCode::RateLimitExceeded,
+                    _ => ErrorCode::InternalError,
+                };
                 let error_msg = Message::Error {
-                    code: ErrorCode::InternalError,
+                    code,
                     message: e.to_string(),
                 };
                 let _ = tx.send(error_msg);
@@ -244,79 +951,325 @@ impl NatServer {
 
         // Clean up client connection
         if let Some(client) = &client_connection {
-            connection_manager.remove_client(&client.id).await;
+            if resumable {
+                tunnel_manager.hold_connections(client).await;
+            }
+            if connection_manager.remove_client(client).await {
+                relay_manager.release_client(&client.id).await;
+            }
         }
 
         Ok(())
     }
 
+    /// Refuse new tunnels during maintenance, though not a client reopening
+    /// one it had open before its connection dropped
+    pub(crate) async fn check_maintenance(
+        connection_manager: &ConnectionManager,
+        tunnel_manager: &TunnelManager,
+        client: &ClientConnection,
+        name: Option<&str>,
+    ) -> NatResult<()> {
+        if connection_manager.maintenance().is_none() {
+            return Ok(());
+        }
+        let reopening = name.is_some()
+            && tunnel_manager
+                .list_client_tunnels(&client.id)
+                .await
+                .iter()
+                .any(|tunnel| tunnel.name.as_deref() == name);
+        if reopening {
+            return Ok(());
+        }
+        Err(NatError::permission_denied(
+            "The server is under maintenance and opens no new tunnels",
+        ))
+    }
+
+    /// Refuse a tunnel the client's token does not allow
+    pub(crate) async fn check_scope(
+        client: &ClientConnection,
+        protocol: TunnelProtocol,
+        remote_port: Option<u16>,
+    ) -> NatResult<()> {
+        let scope = &client.scope;
+        if client.quota_exhausted() {
+            return Err(NatError::permission_denied("Monthly traffic quota used up"));
+        }
+        if !scope.allows_protocol(protocol) {
+            return Err(NatError::permission_denied(format!(
+                "Token may not open {} tunnels",
+                protocol
+            )));
+        }
+        if let Some(port) = remote_port.filter(|port| !scope.allows_port(*port)) {
+            return Err(NatError::permission_denied(format!(
+                "Token may not use port {}",
+                port
+            )));
+        }
+        if let Some(max) = scope.max_tunnels {
+            if client.tunnels.read().await.len() >= max as usize {
+                return Err(NatError::permission_denied(format!(
+                    "Token allows at most {} tunnels",
+                    max
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// The credential an `Auth` or `Enroll` carries. Without a token or
+    /// proof, a client certificate from the handshake stands in.
+    fn credential<'a>(
+        token: &'a str,
+        proof: Option<&'a str>,
+        nonce: Option<&'a str>,
+        certificate: Option<&'a str>,
+    ) -> Result<Credential<'a>, String> {
+        match (proof, nonce, certificate) {
+            (Some(proof), Some(nonce), _) => Ok(Credential::Proof { nonce, proof }),
+            (Some(_), None, _) => Err("No authentication challenge was issued".to_string()),
+            (None, _, Some(name)) if token.is_empty() => Ok(Credential::Certificate(name)),
+            (None, _, _) => Ok(Credential::Token(token)),
+        }
+    }
+
+    /// Resolves when the authenticated client is kicked or its token expires
+    async fn kicked(client: Option<&ClientConnection>) {
+        match client {
+            Some(client) => client.kicked().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Resolves after `timeout` without a message; restarted for each one
+    async fn silence(timeout: Option<std::time::Duration>) {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn handle_message(
         message: Message,
         client_connection: &mut Option<Arc<ClientConnection>>,
+        challenge: &mut Option<String>,
         addr: std::net::SocketAddr,
+        certificate: Option<&str>,
         tx: &mpsc::UnboundedSender<Message>,
         connection_manager: &Arc<ConnectionManager>,
         tunnel_manager: &Arc<TunnelManager>,
+        relay_manager: &Arc<RelayManager>,
+        max_frame_size: usize,
+        frame_limit: &FrameLimit,
     ) -> NatResult<()> {
         match message {
+            Message::RequestAuthChallenge => {
+                let nonce = crypto::generate_nonce();
+                *challenge = Some(nonce.clone());
+                tx.send(Message::AuthChallenge { nonce })
+                    .map_err(|_| NatError::connection("Failed to send response"))?;
+            }
+
             Message::Auth {
                 version,
                 token,
                 client_id,
+                proof,
+                heartbeat,
+                max_frame_size: advertised,
             } => {
+                // Pre-negotiation clients accept the old fixed limit
+                frame_limit.set(advertised);
                 if version != PROTOCOL_VERSION {
                     let response = Message::AuthResponse {
                         success: false,
                         error: Some("Protocol version mismatch".to_string()),
                         server_version: PROTOCOL_VERSION,
+                        data_channel: None,
+                        motd: None,
+                        max_frame_size: Some(max_frame_size as u32),
                     };
                     tx.send(response)
                         .map_err(|_| NatError::connection("Failed to send response"))?;
                     return Ok(());
                 }
 
-                let success = connection_manager.authenticate(&token, &client_id).await;
+                // A nonce answers one attempt only
+                let nonce = challenge.take();
+                let result =
+                    match Self::credential(&token, proof.as_deref(), nonce.as_deref(), certificate)
+                    {
+                        Ok(credential) => {
+                            connection_manager
+                                .authenticate(credential, &client_id)
+                                .await
+                        }
+                        Err(reason) => Err(reason),
+                    };
 
-                if success {
-                    let client =
-                        Arc::new(ClientConnection::new(client_id.clone(), addr, tx.clone()));
-                    connection_manager.add_client(client.clone()).await;
-                    *client_connection = Some(client);
-                }
+                let mut data_channel = None;
+                // During maintenance only clients with tunnels open here
+                // may come back, as after a dropped connection
+                let refused = connection_manager.maintenance().is_some()
+                    && tunnel_manager
+                        .list_client_tunnels(&client_id)
+                        .await
+                        .is_empty();
+                let result = match result {
+                    Ok(_) if refused => {
+                        info!("Refusing new client {} during maintenance", client_id);
+                        Err("The server is under maintenance; try again later".to_string())
+                    }
+                    Ok(grant) => {
+                        let mut client = ClientConnection::new(client_id.clone(), addr, tx.clone());
+                        client.set_scope(grant.scope.clone());
+                        client.set_usage(connection_manager.usage().account(&client_id));
+                        client.heartbeat = heartbeat;
+                        client.frame_limit = frame_limit.clone();
+                        let client = Arc::new(client);
+                        connection_manager
+                            .add_client(client.clone())
+                            .await
+                            .map(|()| {
+                                tracing::Span::current().record("client_id", client_id.as_str());
+                                data_channel = Some(client.data_key);
+                                if let Some(expires_at) = grant.expires_at {
+                                    client.expire_at(expires_at);
+                                }
+                                *client_connection = Some(client);
+                            })
+                    }
+                    Err(reason) => Err(reason),
+                };
+                let provisioned = match &result {
+                    Ok(()) => connection_manager.provisioning().for_client(&client_id),
+                    Err(_) => Vec::new(),
+                };
 
+                let motd = result
+                    .is_ok()
+                    .then(|| connection_manager.motd().map(str::to_string))
+                    .flatten();
                 let response = Message::AuthResponse {
-                    success,
-                    error: if success {
-                        None
-                    } else {
-                        Some("Authentication failed".to_string())
-                    },
+                    success: result.is_ok(),
+                    error: result.err(),
                     server_version: PROTOCOL_VERSION,
+                    data_channel,
+                    motd,
+                    max_frame_size: Some(max_frame_size as u32),
                 };
 
+                tx.send(response)
+                    .map_err(|_| NatError::connection("Failed to send response"))?;
+                if !provisioned.is_empty() {
+                    info!(
+                        "Sending client {} {} provisioned tunnel(s)",
+                        client_id,
+                        provisioned.len()
+                    );
+                }
+                for tunnel in provisioned {
+                    let _ = tx.send(Message::ProvisionTunnel { tunnel });
+                }
+            }
+
+            Message::Enroll {
+                client_id,
+                token,
+                proof,
+                csr,
+            } => {
+                let nonce = challenge.take();
+                let result =
+                    match Self::credential(&token, proof.as_deref(), nonce.as_deref(), certificate)
+                    {
+                        Ok(credential) => {
+                            connection_manager
+                                .enroll(credential, &client_id, &csr)
+                                .await
+                        }
+                        Err(reason) => Err(reason),
+                    };
+                let response = match result {
+                    Ok(certificate) => Message::Enrolled { certificate },
+                    Err(message) => Message::Error {
+                        code: ErrorCode::AuthenticationFailed,
+                        message,
+                    },
+                };
                 tx.send(response)
                     .map_err(|_| NatError::connection("Failed to send response"))?;
             }
 
             Message::CreateTunnel {
+                local_host,
                 local_port,
                 remote_port,
                 protocol,
                 name,
+                port_count,
+                ttl_secs,
+                domain,
+                http_auth,
+                tls_certificate,
+                tls_passthrough,
+                https_redirect,
+                http_headers,
+                dscp,
+                udp,
             } => {
                 if let Some(client) = client_connection {
+                    Self::check_maintenance(
+                        connection_manager,
+                        tunnel_manager,
+                        client,
+                        name.as_deref(),
+                    )
+                    .await?;
+                    Self::check_scope(client, protocol, remote_port).await?;
                     let tunnel_info = tunnel_manager
-                        .create_tunnel(client.id.clone(), local_port, remote_port, protocol, name)
+                        .create_tunnel(
+                            client.id.clone(),
+                            local_host,
+                            local_port,
+                            remote_port,
+                            port_count,
+                            protocol,
+                            name,
+                            ttl_secs,
+                            domain,
+                            http_auth,
+                            tls_certificate,
+                            tls_passthrough,
+                            https_redirect,
+                            http_headers,
+                            dscp,
+                            udp,
+                            &client.scope.ports,
+                        )
                         .await?;
 
                     client.add_tunnel(tunnel_info.clone()).await;
+                    if let Some(expires_at) = tunnel_info.expires_at {
+                        tunnel_manager.schedule_expiry(tunnel_info.id, expires_at);
+                    }
 
                     let response = Message::TunnelCreated {
                         tunnel_id: tunnel_info.id,
                         remote_port: tunnel_info.remote_port,
+                        local_host: tunnel_info.local_host.clone(),
                         local_port: tunnel_info.local_port,
                         protocol: tunnel_info.protocol,
                         name: tunnel_info.name.clone(),
+                        port_count: tunnel_info.port_count,
+                        expires_at: tunnel_info.expires_at,
+                        domain: tunnel_info.domain.clone(),
+                        tls_passthrough: tunnel_info.tls_passthrough,
+                        udp: tunnel_info.udp,
                     };
 
                     tx.send(response)
@@ -326,6 +1279,14 @@ impl NatServer {
                 }
             }
 
+            Message::RequestTokenRotation => {
+                if let Some(client) = client_connection {
+                    connection_manager.rotate_token(client).await?;
+                } else {
+                    return Err(NatError::authentication("Not authenticated"));
+                }
+            }
+
             Message::CloseTunnel { tunnel_id } => {
                 if let Some(client) = client_connection {
                     tunnel_manager.close_tunnel(&tunnel_id).await?;
@@ -343,6 +1304,57 @@ impl NatServer {
                 }
             }
 
+            Message::CreateShare {
+                request_id,
+                tunnel_id,
+                ttl_secs,
+                password,
+            } => {
+                if let Some(client) = client_connection {
+                    let response = match tunnel_manager
+                        .create_share(
+                            &client.id,
+                            tunnel_id,
+                            ttl_secs,
+                            password,
+                            &client.scope.ports,
+                        )
+                        .await
+                    {
+                        Ok(share) => Message::ShareCreated {
+                            request_id,
+                            share: Some(share),
+                            error: None,
+                        },
+                        Err(e) => Message::ShareCreated {
+                            request_id,
+                            share: None,
+                            error: Some(match e {
+                                NatError::Tunnel { message } => message,
+                                e => e.to_string(),
+                            }),
+                        },
+                    };
+                    tx.send(response)
+                        .map_err(|_| NatError::connection("Failed to send response"))?;
+                } else {
+                    return Err(NatError::authentication("Not authenticated"));
+                }
+            }
+
+            Message::CloseShare { share_id } => {
+                if let Some(client) = client_connection {
+                    if tunnel_manager.share_owner(&share_id).await.as_ref() != Some(&client.id) {
+                        return Err(NatError::tunnel("Share not found"));
+                    }
+                    tunnel_manager
+                        .close_share(&share_id, "Closed by client")
+                        .await;
+                } else {
+                    return Err(NatError::authentication("Not authenticated"));
+                }
+            }
+
             Message::Data {
                 tunnel_id,
                 data,
@@ -353,6 +1365,70 @@ impl NatServer {
                     .await?;
             }
 
+            Message::ConnectionClosed {
+                tunnel_id,
+                connection_id,
+            } => {
+                if let Some(client) = client_connection {
+                    if tunnel_manager.tunnel_owner(&tunnel_id).await.as_ref() == Some(&client.id) {
+                        tunnel_manager
+                            .close_connection(&tunnel_id, connection_id)
+                            .await;
+                    } else {
+                        debug!(
+                            "Client {} may not close connections of tunnel {}",
+                            client.id, tunnel_id
+                        );
+                    }
+                } else {
+                    return Err(NatError::authentication("Not authenticated"));
+                }
+            }
+
+            Message::ConnectionShutdown {
+                tunnel_id,
+                connection_id,
+                direction: ShutdownDirection::Outbound,
+            } => {
+                if let Some(client) = client_connection {
+                    if tunnel_manager.tunnel_owner(&tunnel_id).await.as_ref() == Some(&client.id) {
+                        tunnel_manager
+                            .shutdown_connection(&tunnel_id, connection_id)
+                            .await;
+                    } else {
+                        debug!(
+                            "Client {} may not shut down connections of tunnel {}",
+                            client.id, tunnel_id
+                        );
+                    }
+                } else {
+                    return Err(NatError::authentication("Not authenticated"));
+                }
+            }
+
+            Message::ResumeConnection {
+                tunnel_id,
+                connection_id,
+                received,
+            } => {
+                if let Some(client) = client_connection {
+                    if !tunnel_manager
+                        .resume_connection(client, tunnel_id, connection_id, received)
+                        .await
+                    {
+                        debug!(
+                            "Connection {} on tunnel {} is gone, not resuming it",
+                            connection_id, tunnel_id
+                        );
+                        tx.send(Message::ConnectionClosed {
+                            tunnel_id,
+                            connection_id,
+                        })
+                        .map_err(|_| NatError::connection("Failed to send response"))?;
+                    }
+                }
+            }
+
             Message::Ping { timestamp } => {
                 let response = Message::Pong { timestamp };
                 tx.send(response)
@@ -361,13 +1437,21 @@ impl NatServer {
 
             Message::StatusRequest => {
                 if let Some(client) = client_connection {
-                    let tunnels = client.list_tunnels().await;
+                    let tunnels = tunnel_manager.list_client_tunnels(&client.id).await;
+                    let connections = tunnels.iter().map(|t| t.active_connections).sum();
                     let uptime = (chrono::Utc::now() - client.connected_at).num_seconds() as u64;
 
                     let response = Message::Status {
                         tunnels,
-                        connections: 0, // TODO: count active connections
+                        connections,
                         uptime,
+                        relays: relay_manager.list_relays(&client.id).await,
+                        usage: client.usage().map(|(bytes, quota_bytes)| UsageInfo {
+                            month: connection_manager.usage().month(),
+                            bytes,
+                            quota_bytes,
+                        }),
+                        utilization: Some(tunnel_manager.utilization().await),
                     };
 
                     tx.send(response)
@@ -375,6 +1459,281 @@ impl NatServer {
                 }
             }
 
+            Message::ListTunnels { request_id } => {
+                if let Some(client) = client_connection {
+                    let response = Message::TunnelList {
+                        request_id,
+                        tunnels: tunnel_manager.list_client_tunnels(&client.id).await,
+                        shares: tunnel_manager.list_client_shares(&client.id).await,
+                        relays: relay_manager.list_relays(&client.id).await,
+                    };
+                    tx.send(response)
+                        .map_err(|_| NatError::connection("Failed to send response"))?;
+                } else {
+                    return Err(NatError::authentication("Not authenticated"));
+                }
+            }
+
+            Message::AllocateRelay { protocol } => {
+                if let Some(client) = client_connection {
+                    let relay = relay_manager.allocate(&client.id, protocol).await?;
+                    tx.send(Message::RelayAllocated { relay })
+                        .map_err(|_| NatError::connection("Failed to send response"))?;
+                } else {
+                    return Err(NatError::authentication("Not authenticated"));
+                }
+            }
+
+            Message::ReleaseRelay { relay_id } => {
+                if let Some(client) = client_connection {
+                    let bytes_relayed = relay_manager.release(&client.id, &relay_id).await?;
+                    let response = Message::RelayClosed {
+                        relay_id,
+                        bytes_relayed,
+                        reason: "Released by client".to_string(),
+                    };
+                    tx.send(response)
+                        .map_err(|_| NatError::connection("Failed to send response"))?;
+                } else {
+                    return Err(NatError::authentication("Not authenticated"));
+                }
+            }
+
+            Message::CandidateOffer {
+                session_id,
+                peer,
+                candidates,
+                service,
+                public_key,
+            } => {
+                if let Some(client) = client_connection {
+                    let offer = Message::CandidateOffer {
+                        session_id,
+                        peer: client.id.clone(),
+                        candidates,
+                        service,
+                        public_key,
+                    };
+                    Self::forward_to_peer(connection_manager, &peer, offer, tx).await?;
+                } else {
+                    return Err(NatError::authentication("Not authenticated"));
+                }
+            }
+
+            Message::CandidateAnswer {
+                session_id,
+                peer,
+                candidates,
+                public_key,
+            } => {
+                if let Some(client) = client_connection {
+                    let answer = Message::CandidateAnswer {
+                        session_id,
+                        peer: client.id.clone(),
+                        candidates,
+                        public_key,
+                    };
+                    Self::forward_to_peer(connection_manager, &peer, answer, tx).await?;
+                } else {
+                    return Err(NatError::authentication("Not authenticated"));
+                }
+            }
+
+            Message::PublishService { name } => {
+                if let Some(client) = client_connection {
+                    connection_manager
+                        .publish_service(&client.id, &name)
+                        .await?;
+                    tx.send(Message::ServicePublished { name })
+                        .map_err(|_| NatError::connection("Failed to send response"))?;
+                } else {
+                    return Err(NatError::authentication("Not authenticated"));
+                }
+            }
+
+            Message::UnpublishService { name } => {
+                if let Some(client) = client_connection {
+                    connection_manager
+                        .unpublish_service(&client.id, &name)
+                        .await;
+                } else {
+                    return Err(NatError::authentication("Not authenticated"));
+                }
+            }
+
+            Message::JoinVpn { advertise } => {
+                if let Some(client) = client_connection {
+                    let vpn = connection_manager
+                        .vpn()
+                        .ok_or_else(|| NatError::permission_denied("The server has no VPN"))?;
+                    let address = vpn.join(client, advertise).await?;
+                    tx.send(Message::VpnJoined { address })
+                        .map_err(|_| NatError::connection("Failed to send response"))?;
+                } else {
+                    return Err(NatError::authentication("Not authenticated"));
+                }
+            }
+
+            Message::VpnPacket { data } => {
+                if let (Some(client), Some(vpn)) = (client_connection, connection_manager.vpn()) {
+                    vpn.from_client(&client.id, data).await;
+                }
+            }
+
+            Message::JoinWireGuard {
+                public_key,
+                endpoint,
+                advertise,
+            } => {
+                if let Some(client) = client_connection {
+                    let wireguard = connection_manager.wireguard().ok_or_else(|| {
+                        NatError::permission_denied("The server has no WireGuard mesh")
+                    })?;
+                    let address = wireguard
+                        .join(client, public_key, endpoint, advertise)
+                        .await?;
+                    tx.send(Message::WireGuardJoined { address })
+                        .map_err(|_| NatError::connection("Failed to send response"))?;
+                    wireguard.announce().await;
+                } else {
+                    return Err(NatError::authentication("Not authenticated"));
+                }
+            }
+
+            Message::SubscribeService {
+                session_id,
+                name,
+                candidates,
+                public_key,
+            } => {
+                if let Some(client) = client_connection {
+                    match connection_manager.service_publisher(&name).await {
+                        Some(publisher) => {
+                            let offer = Message::CandidateOffer {
+                                session_id,
+                                peer: client.id.clone(),
+                                candidates,
+                                service: Some(name),
+                                public_key,
+                            };
+                            Self::forward_to_peer(connection_manager, &publisher, offer, tx)
+                                .await?;
+                        }
+                        None => tx
+                            .send(Message::Error {
+                                code: ErrorCode::PeerNotFound,
+                                message: format!("No client publishes service '{}'", name),
+                            })
+                            .map_err(|_| NatError::connection("Failed to send response"))?,
+                    }
+                } else {
+                    return Err(NatError::authentication("Not authenticated"));
+                }
+            }
+
+            Message::WakeOnLan {
+                request_id,
+                peer,
+                target,
+            } => {
+                if let Some(client) = client_connection {
+                    match connection_manager.get_client(&peer).await {
+                        Some(peer_connection) => {
+                            let request = Message::WakeOnLan {
+                                request_id,
+                                peer: client.id.clone(),
+                                target,
+                            };
+                            peer_connection.send_message(request).await?;
+                        }
+                        None => tx
+                            .send(Message::WakeOnLanResult {
+                                request_id,
+                                error: Some(format!("Client '{}' is not connected", peer)),
+                                peer,
+                            })
+                            .map_err(|_| NatError::connection("Failed to send response"))?,
+                    }
+                } else {
+                    return Err(NatError::authentication("Not authenticated"));
+                }
+            }
+
+            Message::WakeOnLanResult {
+                request_id,
+                peer,
+                error,
+            } => {
+                if let Some(client) = client_connection {
+                    let result = Message::WakeOnLanResult {
+                        request_id,
+                        peer: client.id.clone(),
+                        error,
+                    };
+                    Self::forward_to_peer(connection_manager, &peer, result, tx).await?;
+                } else {
+                    return Err(NatError::authentication("Not authenticated"));
+                }
+            }
+
+            Message::Logs {
+                request_id,
+                lines,
+                error,
+            } => {
+                if let Some(client) = client_connection {
+                    client.logs_received(
+                        request_id,
+                        match error {
+                            Some(error) => Err(error),
+                            None => Ok(lines),
+                        },
+                    );
+                } else {
+                    return Err(NatError::authentication("Not authenticated"));
+                }
+            }
+
+            Message::SpeedTest {
+                request_id,
+                tunnel_id,
+                bytes,
+            } => {
+                if let Some(client) = client_connection {
+                    if tunnel_manager.tunnel_owner(&tunnel_id).await.as_deref()
+                        != Some(client.id.as_str())
+                    {
+                        tx.send(Message::SpeedTestResult {
+                            request_id,
+                            report: None,
+                            error: Some("Tunnel not found".to_string()),
+                        })
+                        .map_err(|_| NatError::connection("Failed to send response"))?;
+                        return Ok(());
+                    }
+                    info!("Speed test of tunnel {} for {}", tunnel_id, client.id);
+                    let tunnel_manager = tunnel_manager.clone();
+                    let tx = tx.clone();
+                    tokio::spawn(
+                        async move {
+                            let result =
+                                speedtest::measure(&tunnel_manager, tunnel_id, bytes).await;
+                            if let Err(e) = &result {
+                                warn!("Speed test of tunnel {} failed: {}", tunnel_id, e);
+                            }
+                            let _ = tx.send(Message::SpeedTestResult {
+                                request_id,
+                                error: result.as_ref().err().map(|e| e.to_string()),
+                                report: result.ok(),
+                            });
+                        }
+                        .in_current_span(),
+                    );
+                } else {
+                    return Err(NatError::authentication("Not authenticated"));
+                }
+            }
+
             _ => {
                 warn!("Unhandled message type: {:?}", message);
             }
@@ -382,4 +1741,23 @@ impl NatServer {
 
         Ok(())
     }
+
+    /// Deliver a signaling message to another connected client, telling the
+    /// sender when the peer is not connected
+    async fn forward_to_peer(
+        connection_manager: &Arc<ConnectionManager>,
+        peer: &str,
+        message: Message,
+        tx: &mpsc::UnboundedSender<Message>,
+    ) -> NatResult<()> {
+        match connection_manager.get_client(peer).await {
+            Some(peer_connection) => peer_connection.send_message(message).await,
+            None => tx
+                .send(Message::Error {
+                    code: ErrorCode::PeerNotFound,
+                    message: format!("Client '{}' is not connected", peer),
+                })
+                .map_err(|_| NatError::connection("Failed to send response")),
+        }
+    }
 }
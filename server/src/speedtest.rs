@@ -0,0 +1,141 @@
+//! Speed tests for `nat-client speedtest`: the server connects through the
+//! client's tunnel the way a visitor would, without a socket, and talks to
+//! a test service the client runs behind it. Each connection starts with a
+//! mode byte:
+//!
+//! - `p`: the service echoes what it reads, timed one byte at a time
+//! - `d`: an 8 byte big-endian length, then that many bytes for the
+//!   service to read; it answers one byte once it has them all
+//! - `u`: an 8 byte big-endian length the service answers with that many
+//!   bytes
+
+use crate::tunnel::TunnelManager;
+use bytes::Bytes;
+use nat_traversal_common::{
+    error::{NatError, NatResult},
+    protocol::SpeedTestReport,
+};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tracing::debug;
+use uuid::Uuid;
+
+/// Most bytes one test moves in each direction
+pub const MAX_BYTES: u64 = 1 << 30;
+
+/// The whole test, all three connections, must finish within this
+const TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Timed round trips, after one that warms the connection up
+const ROUND_TRIPS: usize = 10;
+
+const CHUNK: usize = 64 * 1024;
+
+/// Shown to the client as the visitor's address
+const VISITOR: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Measure latency, then `bytes` down to the client and back up
+pub async fn measure(
+    tunnel_manager: &Arc<TunnelManager>,
+    tunnel_id: Uuid,
+    bytes: u64,
+) -> NatResult<SpeedTestReport> {
+    if bytes == 0 || bytes > MAX_BYTES {
+        return Err(NatError::tunnel(format!(
+            "A speed test moves 1 to {} bytes",
+            MAX_BYTES
+        )));
+    }
+    tokio::time::timeout(TIMEOUT, async {
+        let round_trips_ms = ping(open(tunnel_manager, tunnel_id, b'p').await?).await?;
+        let download_ms = download(open(tunnel_manager, tunnel_id, b'd').await?, bytes).await?;
+        let upload_ms = upload(open(tunnel_manager, tunnel_id, b'u').await?, bytes).await?;
+        Ok(SpeedTestReport {
+            round_trips_ms,
+            download_bytes: bytes,
+            download_ms,
+            upload_bytes: bytes,
+            upload_ms,
+        })
+    })
+    .await
+    .map_err(|_| NatError::timeout("The speed test did not finish in time"))?
+}
+
+/// Open a connection through the tunnel and send its mode
+async fn open(
+    tunnel_manager: &Arc<TunnelManager>,
+    tunnel_id: Uuid,
+    mode: u8,
+) -> NatResult<DuplexStream> {
+    let (mut ours, theirs) = tokio::io::duplex(CHUNK);
+    let tunnel_manager = tunnel_manager.clone();
+    tokio::spawn(async move {
+        if let Err(e) = tunnel_manager
+            .attach_connection(tunnel_id, theirs, VISITOR, Bytes::new())
+            .await
+        {
+            debug!("Speed test connection ended: {}", e);
+        }
+    });
+    ours.write_all(&[mode]).await?;
+    Ok(ours)
+}
+
+async fn ping(mut stream: DuplexStream) -> NatResult<Vec<f64>> {
+    let mut byte = [0u8];
+    let mut round_trips = Vec::with_capacity(ROUND_TRIPS);
+    for i in 0..=ROUND_TRIPS {
+        let started = Instant::now();
+        stream.write_all(&byte).await?;
+        stream.read_exact(&mut byte).await?;
+        // The first also waits for the client to reach its test service
+        if i > 0 {
+            round_trips.push(millis(started));
+        }
+    }
+    Ok(round_trips)
+}
+
+async fn download(mut stream: DuplexStream, bytes: u64) -> NatResult<f64> {
+    let data = pattern();
+    let started = Instant::now();
+    stream.write_all(&bytes.to_be_bytes()).await?;
+    let mut left = bytes;
+    while left > 0 {
+        let n = left.min(CHUNK as u64) as usize;
+        stream.write_all(&data[..n]).await?;
+        left -= n as u64;
+    }
+    stream.read_u8().await?;
+    Ok(millis(started))
+}
+
+async fn upload(mut stream: DuplexStream, bytes: u64) -> NatResult<f64> {
+    let mut buffer = vec![0u8; CHUNK];
+    let started = Instant::now();
+    stream.write_all(&bytes.to_be_bytes()).await?;
+    let mut left = bytes;
+    while left > 0 {
+        let n = left.min(CHUNK as u64) as usize;
+        let read = stream.read(&mut buffer[..n]).await?;
+        if read == 0 {
+            return Err(NatError::connection(
+                "The client closed the speed test connection early",
+            ));
+        }
+        left -= read as u64;
+    }
+    Ok(millis(started))
+}
+
+/// Filler bytes that are not all alike
+fn pattern() -> Vec<u8> {
+    (0..CHUNK).map(|i| (i % 251) as u8).collect()
+}
+
+fn millis(started: Instant) -> f64 {
+    started.elapsed().as_secs_f64() * 1000.0
+}
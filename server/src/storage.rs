@@ -0,0 +1,629 @@
+//! Optional SQLite record of the clients that connected, the tunnels they
+//! opened and their sessions, kept across restarts. Writes go through a
+//! channel to one thread so the async tasks never wait on the disk.
+
+use chrono::{DateTime, Utc};
+use nat_traversal_common::protocol::TunnelInfo;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+use uuid::Uuid;
+
+/// How often records past the retention period are deleted
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS clients (
+    client_id TEXT PRIMARY KEY,
+    first_seen TEXT NOT NULL,
+    last_seen TEXT NOT NULL,
+    last_addr TEXT NOT NULL,
+    sessions INTEGER NOT NULL DEFAULT 0
+);
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY,
+    client_id TEXT NOT NULL,
+    addr TEXT NOT NULL,
+    connected_at TEXT NOT NULL,
+    disconnected_at TEXT,
+    bytes_sent INTEGER NOT NULL DEFAULT 0,
+    bytes_received INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS sessions_by_client ON sessions (client_id, connected_at);
+CREATE TABLE IF NOT EXISTS tunnels (
+    id TEXT PRIMARY KEY,
+    client_id TEXT NOT NULL,
+    name TEXT,
+    protocol TEXT NOT NULL,
+    local_host TEXT NOT NULL,
+    local_port INTEGER NOT NULL,
+    remote_port INTEGER NOT NULL,
+    port_count INTEGER NOT NULL,
+    domain TEXT,
+    created_at TEXT NOT NULL,
+    closed_at TEXT,
+    bytes_sent INTEGER NOT NULL DEFAULT 0,
+    bytes_received INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS tunnels_by_client ON tunnels (client_id, created_at);
+";
+
+/// Changes since the first schema, applied in order once each; the
+/// database's `user_version` counts how many it has
+const MIGRATIONS: &[&str] =
+    &["ALTER TABLE tunnels ADD COLUMN connections INTEGER NOT NULL DEFAULT 0;"];
+
+/// What `nat-server inspect history` lists
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, clap::ValueEnum)]
+pub enum HistoryKind {
+    /// Every client that ever connected
+    Clients,
+    /// Client connections, newest first
+    Sessions,
+    /// Tunnels, newest first
+    Tunnels,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientRecord {
+    pub client_id: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub last_addr: String,
+    pub sessions: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub client_id: String,
+    pub addr: String,
+    pub connected_at: DateTime<Utc>,
+    /// `None` while the session lasts
+    pub disconnected_at: Option<DateTime<Utc>>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TunnelRecord {
+    pub id: String,
+    pub client_id: String,
+    pub name: Option<String>,
+    pub protocol: String,
+    pub local_host: String,
+    pub local_port: u16,
+    pub remote_port: u16,
+    pub port_count: u16,
+    pub domain: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// `None` while the tunnel is open
+    pub closed_at: Option<DateTime<Utc>>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+enum Event {
+    ClientConnected {
+        client_id: String,
+        addr: SocketAddr,
+        at: DateTime<Utc>,
+    },
+    ClientDisconnected {
+        client_id: String,
+        connected_at: DateTime<Utc>,
+        at: DateTime<Utc>,
+        bytes_sent: u64,
+        bytes_received: u64,
+    },
+    TunnelCreated {
+        client_id: String,
+        tunnel: TunnelInfo,
+    },
+    SessionProgress {
+        client_id: String,
+        connected_at: DateTime<Utc>,
+        bytes_sent: u64,
+        bytes_received: u64,
+    },
+    TunnelProgress {
+        tunnel_id: Uuid,
+        connections: u64,
+        bytes_sent: u64,
+        bytes_received: u64,
+    },
+    TunnelClosed {
+        tunnel_id: Uuid,
+        at: DateTime<Utc>,
+        connections: u64,
+        bytes_sent: u64,
+        bytes_received: u64,
+    },
+}
+
+pub struct Storage {
+    events: mpsc::Sender<Event>,
+    db: Arc<Mutex<Connection>>,
+}
+
+impl Storage {
+    /// Open or create the database at `path`. Sessions and tunnels left
+    /// open by a previous run are closed as of now, as the server does not
+    /// bring them back.
+    pub fn open(path: &Path, retention_days: u32) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let db = Connection::open(path)?;
+        db.execute_batch("PRAGMA journal_mode = WAL;")?;
+        db.execute_batch(SCHEMA)?;
+        migrate(&db)?;
+        let now = Utc::now();
+        db.execute(
+            "UPDATE sessions SET disconnected_at = ?1 WHERE disconnected_at IS NULL",
+            params![now],
+        )?;
+        db.execute(
+            "UPDATE tunnels SET closed_at = ?1 WHERE closed_at IS NULL",
+            params![now],
+        )?;
+        purge(&db, retention_days)?;
+
+        let db = Arc::new(Mutex::new(db));
+        let (events, receiver) = mpsc::channel();
+        let writer = db.clone();
+        std::thread::Builder::new()
+            .name("storage".to_string())
+            .spawn(move || write_events(writer, receiver, retention_days))?;
+        Ok(Self { events, db })
+    }
+
+    pub fn client_connected(&self, client_id: &str, addr: SocketAddr, at: DateTime<Utc>) {
+        self.send(Event::ClientConnected {
+            client_id: client_id.to_string(),
+            addr,
+            at,
+        });
+    }
+
+    pub fn client_disconnected(
+        &self,
+        client_id: &str,
+        connected_at: DateTime<Utc>,
+        (bytes_sent, bytes_received): (u64, u64),
+    ) {
+        self.send(Event::ClientDisconnected {
+            client_id: client_id.to_string(),
+            connected_at,
+            at: Utc::now(),
+            bytes_sent,
+            bytes_received,
+        });
+    }
+
+    pub fn tunnel_created(&self, client_id: &str, tunnel: &TunnelInfo) {
+        self.send(Event::TunnelCreated {
+            client_id: client_id.to_string(),
+            tunnel: tunnel.clone(),
+        });
+    }
+
+    /// Record the traffic of a session that is still open
+    pub fn session_progress(
+        &self,
+        client_id: &str,
+        connected_at: DateTime<Utc>,
+        (bytes_sent, bytes_received): (u64, u64),
+    ) {
+        self.send(Event::SessionProgress {
+            client_id: client_id.to_string(),
+            connected_at,
+            bytes_sent,
+            bytes_received,
+        });
+    }
+
+    /// Record the traffic of a tunnel that is still open
+    pub fn tunnel_progress(&self, tunnel: &TunnelInfo, connections: u64) {
+        self.send(Event::TunnelProgress {
+            tunnel_id: tunnel.id,
+            connections,
+            bytes_sent: tunnel.bytes_sent,
+            bytes_received: tunnel.bytes_received,
+        });
+    }
+
+    /// Record `tunnel` as closed after carrying `connections` connections
+    pub fn tunnel_closed(&self, tunnel: &TunnelInfo, connections: u64) {
+        self.send(Event::TunnelClosed {
+            tunnel_id: tunnel.id,
+            at: Utc::now(),
+            connections,
+            bytes_sent: tunnel.bytes_sent,
+            bytes_received: tunnel.bytes_received,
+        });
+    }
+
+    fn send(&self, event: Event) {
+        if self.events.send(event).is_err() {
+            warn!("Storage writer stopped; history is no longer recorded");
+        }
+    }
+
+    pub async fn clients(&self) -> anyhow::Result<Vec<ClientRecord>> {
+        self.query(|db| {
+            let mut statement = db.prepare(
+                "SELECT client_id, first_seen, last_seen, last_addr, sessions
+                 FROM clients ORDER BY last_seen DESC",
+            )?;
+            let rows = statement.query_map([], |row| {
+                Ok(ClientRecord {
+                    client_id: row.get(0)?,
+                    first_seen: row.get(1)?,
+                    last_seen: row.get(2)?,
+                    last_addr: row.get(3)?,
+                    sessions: row.get(4)?,
+                })
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// The latest `limit` sessions, optionally of one client
+    pub async fn sessions(
+        &self,
+        client_id: Option<String>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<SessionRecord>> {
+        self.query(move |db| {
+            let mut statement = db.prepare(
+                "SELECT client_id, addr, connected_at, disconnected_at, bytes_sent, bytes_received
+                 FROM sessions WHERE ?1 IS NULL OR client_id = ?1
+                 ORDER BY connected_at DESC LIMIT ?2",
+            )?;
+            let rows = statement.query_map(params![client_id, limit as i64], |row| {
+                Ok(SessionRecord {
+                    client_id: row.get(0)?,
+                    addr: row.get(1)?,
+                    connected_at: row.get(2)?,
+                    disconnected_at: row.get(3)?,
+                    bytes_sent: row.get(4)?,
+                    bytes_received: row.get(5)?,
+                })
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// The latest `limit` tunnels, optionally of one client
+    pub async fn tunnels(
+        &self,
+        client_id: Option<String>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<TunnelRecord>> {
+        self.query(move |db| {
+            let mut statement = db.prepare(
+                "SELECT id, client_id, name, protocol, local_host, local_port, remote_port,
+                        port_count, domain, created_at, closed_at, bytes_sent, bytes_received
+                 FROM tunnels WHERE ?1 IS NULL OR client_id = ?1
+                 ORDER BY created_at DESC LIMIT ?2",
+            )?;
+            let rows = statement.query_map(params![client_id, limit as i64], |row| {
+                Ok(TunnelRecord {
+                    id: row.get(0)?,
+                    client_id: row.get(1)?,
+                    name: row.get(2)?,
+                    protocol: row.get(3)?,
+                    local_host: row.get(4)?,
+                    local_port: row.get(5)?,
+                    remote_port: row.get(6)?,
+                    port_count: row.get(7)?,
+                    domain: row.get(8)?,
+                    created_at: row.get(9)?,
+                    closed_at: row.get(10)?,
+                    bytes_sent: row.get(11)?,
+                    bytes_received: row.get(12)?,
+                })
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn query<T, F>(&self, query: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let db = self.db.clone();
+        let result = tokio::task::spawn_blocking(move || query(&db.lock().unwrap())).await?;
+        Ok(result?)
+    }
+}
+
+/// One client's totals over a report period
+#[derive(Debug, Default, Serialize)]
+pub struct ClientUsage {
+    pub client_id: String,
+    pub sessions: u64,
+    /// Time connected, open sessions counting up to now
+    pub connected_secs: u64,
+    pub tunnels: u64,
+    /// Public connections through the client's tunnels
+    pub connections: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// One tunnel's totals for a report
+#[derive(Debug, Serialize)]
+pub struct TunnelUsage {
+    pub id: String,
+    pub client_id: String,
+    pub name: Option<String>,
+    pub protocol: String,
+    pub remote_port: u16,
+    pub domain: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub closed_at: Option<DateTime<Utc>>,
+    /// Time open, open tunnels counting up to now
+    pub duration_secs: u64,
+    pub connections: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// Direct access to the database for `nat-server report`, which runs
+/// beside the server rather than through it
+pub struct History {
+    db: Connection,
+}
+
+impl History {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            anyhow::bail!("No history database at {}", path.display());
+        }
+        let db = Connection::open(path)?;
+        db.busy_timeout(Duration::from_secs(5))?;
+        db.execute_batch(SCHEMA)?;
+        migrate(&db)?;
+        Ok(Self { db })
+    }
+
+    /// Tunnels created from `from` until before `to`. Counts of tunnels
+    /// still open are only known once they close.
+    pub fn tunnels(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> rusqlite::Result<Vec<TunnelUsage>> {
+        let now = Utc::now();
+        let mut statement = self.db.prepare(
+            "SELECT id, client_id, name, protocol, remote_port, domain, created_at, closed_at,
+                    connections, bytes_sent, bytes_received
+             FROM tunnels WHERE created_at >= ?1 AND created_at < ?2
+             ORDER BY client_id, created_at",
+        )?;
+        let rows = statement.query_map(params![from, to], |row| {
+            let created_at: DateTime<Utc> = row.get(6)?;
+            let closed_at: Option<DateTime<Utc>> = row.get(7)?;
+            Ok(TunnelUsage {
+                id: row.get(0)?,
+                client_id: row.get(1)?,
+                name: row.get(2)?,
+                protocol: row.get(3)?,
+                remote_port: row.get(4)?,
+                domain: row.get(5)?,
+                created_at,
+                closed_at,
+                duration_secs: seconds_between(created_at, closed_at.unwrap_or(now)),
+                connections: row.get(8)?,
+                bytes_sent: row.get(9)?,
+                bytes_received: row.get(10)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Sessions and tunnels started from `from` until before `to`, totalled
+    /// per client. Traffic of open sessions is only known once they end.
+    pub fn clients(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> rusqlite::Result<Vec<ClientUsage>> {
+        let now = Utc::now();
+        let mut clients: BTreeMap<String, ClientUsage> = BTreeMap::new();
+        let mut statement = self.db.prepare(
+            "SELECT client_id, connected_at, disconnected_at, bytes_sent, bytes_received
+             FROM sessions WHERE connected_at >= ?1 AND connected_at < ?2",
+        )?;
+        let mut rows = statement.query(params![from, to])?;
+        while let Some(row) = rows.next()? {
+            let client_id: String = row.get(0)?;
+            let connected_at: DateTime<Utc> = row.get(1)?;
+            let disconnected_at: Option<DateTime<Utc>> = row.get(2)?;
+            let client = clients
+                .entry(client_id.clone())
+                .or_insert_with(|| ClientUsage {
+                    client_id,
+                    ..Default::default()
+                });
+            client.sessions += 1;
+            client.connected_secs += seconds_between(connected_at, disconnected_at.unwrap_or(now));
+            client.bytes_sent += row.get::<_, u64>(3)?;
+            client.bytes_received += row.get::<_, u64>(4)?;
+        }
+        for tunnel in self.tunnels(from, to)? {
+            let client = clients
+                .entry(tunnel.client_id.clone())
+                .or_insert_with(|| ClientUsage {
+                    client_id: tunnel.client_id,
+                    ..Default::default()
+                });
+            client.tunnels += 1;
+            client.connections += tunnel.connections;
+        }
+        Ok(clients.into_values().collect())
+    }
+}
+
+fn seconds_between(start: DateTime<Utc>, end: DateTime<Utc>) -> u64 {
+    (end - start).num_seconds().max(0) as u64
+}
+
+fn write_events(db: Arc<Mutex<Connection>>, events: mpsc::Receiver<Event>, retention_days: u32) {
+    loop {
+        let result = match events.recv_timeout(PURGE_INTERVAL) {
+            Ok(event) => apply(&db.lock().unwrap(), event),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                purge(&db.lock().unwrap(), retention_days).map(|_| ())
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        };
+        if let Err(e) = result {
+            warn!("Failed to record history: {}", e);
+        }
+    }
+}
+
+fn apply(db: &Connection, event: Event) -> rusqlite::Result<()> {
+    match event {
+        Event::ClientConnected {
+            client_id,
+            addr,
+            at,
+        } => {
+            let addr = addr.to_string();
+            db.execute(
+                "INSERT INTO clients (client_id, first_seen, last_seen, last_addr, sessions)
+                 VALUES (?1, ?2, ?2, ?3, 1)
+                 ON CONFLICT (client_id) DO UPDATE SET
+                     last_seen = excluded.last_seen,
+                     last_addr = excluded.last_addr,
+                     sessions = sessions + 1",
+                params![client_id, at, addr],
+            )?;
+            db.execute(
+                "INSERT INTO sessions (client_id, addr, connected_at) VALUES (?1, ?2, ?3)",
+                params![client_id, addr, at],
+            )?;
+        }
+        Event::ClientDisconnected {
+            client_id,
+            connected_at,
+            at,
+            bytes_sent,
+            bytes_received,
+        } => {
+            db.execute(
+                "UPDATE clients SET last_seen = ?2 WHERE client_id = ?1",
+                params![client_id, at],
+            )?;
+            db.execute(
+                "UPDATE sessions SET disconnected_at = ?3, bytes_sent = ?4, bytes_received = ?5
+                 WHERE client_id = ?1 AND connected_at = ?2",
+                params![client_id, connected_at, at, bytes_sent, bytes_received],
+            )?;
+        }
+        Event::TunnelCreated { client_id, tunnel } => {
+            db.execute(
+                "INSERT OR REPLACE INTO tunnels (id, client_id, name, protocol, local_host,
+                     local_port, remote_port, port_count, domain, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    tunnel.id.to_string(),
+                    client_id,
+                    tunnel.name,
+                    tunnel.protocol.to_string(),
+                    tunnel.local_host,
+                    tunnel.local_port,
+                    tunnel.remote_port,
+                    tunnel.port_count,
+                    tunnel.domain,
+                    tunnel.created_at,
+                ],
+            )?;
+        }
+        Event::SessionProgress {
+            client_id,
+            connected_at,
+            bytes_sent,
+            bytes_received,
+        } => {
+            db.execute(
+                "UPDATE sessions SET bytes_sent = ?3, bytes_received = ?4
+                 WHERE client_id = ?1 AND connected_at = ?2 AND disconnected_at IS NULL",
+                params![client_id, connected_at, bytes_sent, bytes_received],
+            )?;
+        }
+        Event::TunnelProgress {
+            tunnel_id,
+            connections,
+            bytes_sent,
+            bytes_received,
+        } => {
+            db.execute(
+                "UPDATE tunnels SET connections = ?2, bytes_sent = ?3, bytes_received = ?4
+                 WHERE id = ?1 AND closed_at IS NULL",
+                params![
+                    tunnel_id.to_string(),
+                    connections,
+                    bytes_sent,
+                    bytes_received
+                ],
+            )?;
+        }
+        Event::TunnelClosed {
+            tunnel_id,
+            at,
+            connections,
+            bytes_sent,
+            bytes_received,
+        } => {
+            db.execute(
+                "UPDATE tunnels SET closed_at = ?2, connections = ?3, bytes_sent = ?4,
+                     bytes_received = ?5
+                 WHERE id = ?1",
+                params![
+                    tunnel_id.to_string(),
+                    at,
+                    connections,
+                    bytes_sent,
+                    bytes_received
+                ],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn migrate(db: &Connection) -> rusqlite::Result<()> {
+    let version: usize = db.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        db.execute_batch(migration)?;
+        db.execute_batch(&format!("PRAGMA user_version = {};", applied + 1))?;
+    }
+    Ok(())
+}
+
+/// Delete sessions and tunnels that ended more than `retention_days` ago
+fn purge(db: &Connection, retention_days: u32) -> rusqlite::Result<usize> {
+    if retention_days == 0 {
+        return Ok(0);
+    }
+    let cutoff = Utc::now() - chrono::Duration::days(retention_days.into());
+    let sessions = db.execute(
+        "DELETE FROM sessions WHERE disconnected_at < ?1",
+        params![cutoff],
+    )?;
+    let tunnels = db.execute("DELETE FROM tunnels WHERE closed_at < ?1", params![cutoff])?;
+    Ok(sessions + tunnels)
+}
@@ -0,0 +1,71 @@
+//! Servers running inside a test process. Clients reach them over the
+//! in-memory transport, so no certificates or control port are involved;
+//! tunnels still listen on public ports of this machine.
+
+use crate::server::NatServer;
+use nat_traversal_common::{
+    config::ServerConfig,
+    error::NatResult,
+    transport::{self, MemoryConnector},
+};
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+/// Bytes each in-memory connection buffers each way
+const BUFFER: usize = 256 * 1024;
+
+/// Settings for a test server admitting clients that present `token`.
+/// Nothing is read from or written to the configuration directory.
+pub fn config(token: &str) -> ServerConfig {
+    let mut config = ServerConfig::default();
+    config.auth.tokens = vec![token.to_string()];
+    config.auth.max_clients_per_token = None;
+    config.tls.verify_client = false;
+    config.control.enabled = false;
+    config.storage.database = None;
+    config.http.enabled = false;
+    // A file that does not exist starts the ledger empty; only `run`
+    // saves it
+    config.usage.file =
+        Some(std::env::temp_dir().join(format!("nat-test-usage-{}.json", Uuid::new_v4())));
+    config
+}
+
+/// A server serving in-memory clients until dropped
+pub struct TestServer {
+    server: Arc<NatServer>,
+    connector: MemoryConnector,
+    serving: JoinHandle<NatResult<()>>,
+}
+
+impl TestServer {
+    pub async fn start(config: ServerConfig) -> NatResult<Self> {
+        let server = Arc::new(NatServer::without_tls(config).await?);
+        let (connector, listener) = transport::memory(BUFFER);
+        let serving = {
+            let server = server.clone();
+            tokio::spawn(async move { server.serve(Arc::new(listener)).await })
+        };
+        Ok(Self {
+            server,
+            connector,
+            serving,
+        })
+    }
+
+    pub fn server(&self) -> &Arc<NatServer> {
+        &self.server
+    }
+
+    /// What clients in this process connect to the server with
+    pub fn connector(&self) -> MemoryConnector {
+        self.connector.clone()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.serving.abort();
+    }
+}
@@ -0,0 +1,353 @@
+//! Signed, expiring client tokens (JWT), accepted besides the static tokens.
+
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{
+    decode, decode_header, encode, errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header,
+    Validation,
+};
+use nat_traversal_common::config::{JwtConfig, TokenScope};
+use serde::Deserialize;
+
+/// What an accepted token allows its holder
+#[derive(Debug, Clone, Default)]
+pub struct TokenGrant {
+    pub scope: TokenScope,
+    /// When the session must end; static tokens never expire
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Claims the server reads; `iss`, `aud`, `nbf` and the rest are checked by
+/// the validation itself
+#[derive(Debug, Deserialize)]
+struct Claims {
+    #[serde(default)]
+    sub: Option<String>,
+    exp: i64,
+    #[serde(flatten)]
+    scope: TokenScope,
+}
+
+pub struct JwtVerifier {
+    hs256: Option<DecodingKey>,
+    eddsa: Option<DecodingKey>,
+    config: JwtConfig,
+}
+
+impl JwtVerifier {
+    pub fn new(config: &JwtConfig) -> anyhow::Result<Self> {
+        let hs256 = config
+            .load_secret()?
+            .map(|secret| DecodingKey::from_secret(secret.as_bytes()));
+        let eddsa = match &config.public_key {
+            Some(path) => {
+                let pem = std::fs::read(path).map_err(|e| {
+                    anyhow::anyhow!("Failed to read JWT public key {}: {}", path.display(), e)
+                })?;
+                Some(DecodingKey::from_ed_pem(&pem).map_err(|e| {
+                    anyhow::anyhow!("Invalid Ed25519 public key {}: {}", path.display(), e)
+                })?)
+            }
+            None => None,
+        };
+        if hs256.is_none() && eddsa.is_none() {
+            return Err(anyhow::anyhow!(
+                "auth.jwt needs a secret, secret_file or public_key"
+            ));
+        }
+
+        Ok(Self {
+            hs256,
+            eddsa,
+            config: config.clone(),
+        })
+    }
+
+    /// Check `token`'s signature, expiry and claims for `client_id`
+    pub fn verify(&self, token: &str, client_id: &str) -> Result<TokenGrant, String> {
+        let header = decode_header(token).map_err(|_| "Invalid token".to_string())?;
+        let key = match header.alg {
+            Algorithm::HS256 => self.hs256.as_ref(),
+            Algorithm::EdDSA => self.eddsa.as_ref(),
+            _ => None,
+        }
+        .ok_or_else(|| format!("Token algorithm {:?} is not accepted", header.alg))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = self.config.leeway_secs;
+        validation.validate_nbf = true;
+        // Configured claims have to be present, not only right when they are
+        let mut required = vec!["exp"];
+        if let Some(issuer) = &self.config.issuer {
+            validation.set_issuer(&[issuer]);
+            required.push("iss");
+        }
+        match &self.config.audience {
+            Some(audience) => {
+                validation.set_audience(&[audience]);
+                required.push("aud");
+            }
+            None => validation.validate_aud = false,
+        }
+        validation.set_required_spec_claims(&required);
+
+        let claims = decode::<Claims>(token, key, &validation)
+            .map_err(|e| {
+                match e.kind() {
+                    ErrorKind::ExpiredSignature => "Token expired",
+                    ErrorKind::ImmatureSignature => "Token not yet valid",
+                    ErrorKind::InvalidIssuer => "Token issuer not accepted",
+                    ErrorKind::InvalidAudience => "Token audience not accepted",
+                    _ => "Invalid token",
+                }
+                .to_string()
+            })?
+            .claims;
+
+        match claims.sub.as_deref() {
+            Some(sub) if sub != client_id => {
+                return Err("Token was issued to another client".to_string())
+            }
+            None if self.config.require_subject => return Err("Token names no client".to_string()),
+            _ => {}
+        }
+        Ok(TokenGrant {
+            scope: claims.scope,
+            expires_at: DateTime::from_timestamp(claims.exp, 0),
+        })
+    }
+
+    /// Sign a replacement token for `client_id` with `scope`, valid for
+    /// `rotation_ttl_hours`. Needs the HS256 secret; EdDSA tokens are only
+    /// ever verified here.
+    pub fn renew(
+        &self,
+        client_id: &str,
+        scope: &TokenScope,
+    ) -> anyhow::Result<(String, DateTime<Utc>)> {
+        let ttl_hours = self.config.rotation_ttl_hours;
+        let expires_at = Utc::now() + chrono::Duration::hours(ttl_hours as i64);
+        Ok((
+            issue(&self.config, client_id, ttl_hours, scope)?,
+            expires_at,
+        ))
+    }
+}
+
+/// Sign an HS256 token for `client_id` valid for `ttl_hours`
+pub fn issue(
+    config: &JwtConfig,
+    client_id: &str,
+    ttl_hours: u64,
+    scope: &TokenScope,
+) -> anyhow::Result<String> {
+    let secret = config
+        .load_secret()?
+        .ok_or_else(|| anyhow::anyhow!("Issuing tokens needs auth.jwt.secret or secret_file"))?;
+
+    let now = Utc::now().timestamp();
+    let mut claims = serde_json::to_value(scope)?;
+    claims["sub"] = client_id.into();
+    claims["iat"] = now.into();
+    claims["exp"] = (now + (ttl_hours * 3600) as i64).into();
+    if let Some(issuer) = &config.issuer {
+        claims["iss"] = issuer.as_str().into();
+    }
+    if let Some(audience) = &config.audience {
+        claims["aud"] = audience.as_str().into();
+    }
+
+    Ok(encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+    use serde_json::{json, Value};
+
+    const SECRET: &str = "test-secret";
+
+    /// A verifier taking HS256 tokens with `SECRET` and EdDSA tokens of a
+    /// fresh key, and that key's PKCS#8 document for signing
+    fn setup(config: JwtConfig) -> (JwtVerifier, Vec<u8>) {
+        let key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ED25519).unwrap();
+        let verifier = JwtVerifier {
+            hs256: Some(DecodingKey::from_secret(SECRET.as_bytes())),
+            eddsa: Some(DecodingKey::from_ed_der(key.public_key_raw())),
+            config,
+        };
+        (verifier, key.serialize_der())
+    }
+
+    fn claims(extra: Value) -> Value {
+        let mut claims = json!({
+            "sub": "office-pc",
+            "exp": Utc::now().timestamp() + 3600,
+        });
+        for (name, value) in extra.as_object().unwrap() {
+            claims[name] = value.clone();
+        }
+        claims
+    }
+
+    fn encode_part(text: &str) -> String {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(text)
+    }
+
+    fn hs256(claims: &Value) -> String {
+        encode(
+            &Header::new(Algorithm::HS256),
+            claims,
+            &EncodingKey::from_secret(SECRET.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_valid_tokens() {
+        let (verifier, pkcs8) = setup(JwtConfig::default());
+        let grant = verifier
+            .verify(&hs256(&claims(json!({ "max_tunnels": 2 }))), "office-pc")
+            .unwrap();
+        assert_eq!(grant.scope.max_tunnels, Some(2));
+        assert!(grant.expires_at.is_some());
+
+        let eddsa = encode(
+            &Header::new(Algorithm::EdDSA),
+            &claims(json!({})),
+            &EncodingKey::from_ed_der(&pkcs8),
+        )
+        .unwrap();
+        assert!(verifier.verify(&eddsa, "office-pc").is_ok());
+    }
+
+    #[test]
+    fn test_expired_and_immature_tokens() {
+        let (verifier, _) = setup(JwtConfig {
+            leeway_secs: 0,
+            ..JwtConfig::default()
+        });
+        let now = Utc::now().timestamp();
+        let expired = hs256(&claims(json!({ "exp": now - 60 })));
+        assert_eq!(
+            verifier.verify(&expired, "office-pc").unwrap_err(),
+            "Token expired"
+        );
+        let immature = hs256(&claims(json!({ "nbf": now + 600 })));
+        assert_eq!(
+            verifier.verify(&immature, "office-pc").unwrap_err(),
+            "Token not yet valid"
+        );
+        let no_expiry = hs256(&json!({ "sub": "office-pc" }));
+        assert!(verifier.verify(&no_expiry, "office-pc").is_err());
+    }
+
+    #[test]
+    fn test_algorithm_confusion() {
+        let (verifier, pkcs8) = setup(JwtConfig::default());
+        let public = rcgen::KeyPair::try_from(pkcs8.as_slice())
+            .unwrap()
+            .public_key_raw()
+            .to_vec();
+
+        // An HS256 token keyed by the public Ed25519 key is not EdDSA's
+        let confused = encode(
+            &Header::new(Algorithm::HS256),
+            &claims(json!({})),
+            &EncodingKey::from_secret(&public),
+        )
+        .unwrap();
+        assert_eq!(
+            verifier.verify(&confused, "office-pc").unwrap_err(),
+            "Invalid token"
+        );
+
+        // Nor is a token claiming EdDSA but signed with the HS256 secret
+        let token = hs256(&claims(json!({})));
+        let (_, rest) = token.split_once('.').unwrap();
+        let header = encode_part(&json!({ "alg": "EdDSA", "typ": "JWT" }).to_string());
+        assert!(verifier
+            .verify(&format!("{}.{}", header, rest), "office-pc")
+            .is_err());
+
+        // Algorithms without a key here are refused outright
+        let hs384 = encode(
+            &Header::new(Algorithm::HS384),
+            &claims(json!({})),
+            &EncodingKey::from_secret(SECRET.as_bytes()),
+        )
+        .unwrap();
+        assert!(verifier.verify(&hs384, "office-pc").is_err());
+        let unsigned = format!(
+            "{}.{}.",
+            encode_part(&json!({ "alg": "none" }).to_string()),
+            encode_part(&claims(json!({})).to_string())
+        );
+        assert!(verifier.verify(&unsigned, "office-pc").is_err());
+
+        let (hs256_only, _) = setup(JwtConfig::default());
+        let hs256_only = JwtVerifier {
+            eddsa: None,
+            ..hs256_only
+        };
+        let eddsa = encode(
+            &Header::new(Algorithm::EdDSA),
+            &claims(json!({})),
+            &EncodingKey::from_ed_der(&pkcs8),
+        )
+        .unwrap();
+        assert_eq!(
+            hs256_only.verify(&eddsa, "office-pc").unwrap_err(),
+            "Token algorithm EdDSA is not accepted"
+        );
+    }
+
+    #[test]
+    fn test_issuer_and_audience() {
+        let (verifier, _) = setup(JwtConfig {
+            issuer: Some("ops".to_string()),
+            audience: Some("nat".to_string()),
+            ..JwtConfig::default()
+        });
+        let good = hs256(&claims(json!({ "iss": "ops", "aud": "nat" })));
+        assert!(verifier.verify(&good, "office-pc").is_ok());
+        let issuer = hs256(&claims(json!({ "iss": "dev", "aud": "nat" })));
+        assert_eq!(
+            verifier.verify(&issuer, "office-pc").unwrap_err(),
+            "Token issuer not accepted"
+        );
+        let audience = hs256(&claims(json!({ "iss": "ops", "aud": "web" })));
+        assert_eq!(
+            verifier.verify(&audience, "office-pc").unwrap_err(),
+            "Token audience not accepted"
+        );
+        let missing = hs256(&claims(json!({})));
+        assert!(verifier.verify(&missing, "office-pc").is_err());
+    }
+
+    #[test]
+    fn test_subject() {
+        let (verifier, _) = setup(JwtConfig::default());
+        let token = hs256(&claims(json!({})));
+        assert_eq!(
+            verifier.verify(&token, "kiosk-7").unwrap_err(),
+            "Token was issued to another client"
+        );
+        let anyone = hs256(&json!({ "exp": Utc::now().timestamp() + 3600 }));
+        assert_eq!(
+            verifier.verify(&anyone, "kiosk-7").unwrap_err(),
+            "Token names no client"
+        );
+
+        let (lenient, _) = setup(JwtConfig {
+            require_subject: false,
+            ..JwtConfig::default()
+        });
+        assert!(lenient.verify(&anyone, "kiosk-7").is_ok());
+        assert!(lenient.verify(&token, "kiosk-7").is_err());
+    }
+}
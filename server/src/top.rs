@@ -0,0 +1,228 @@
+//! `nat-server top`: a live view of clients, tunnels, connection rate and
+//! bandwidth, redrawn in place from the control socket until Ctrl+C.
+
+use crate::control::{
+    self, ClientCounters, InspectRequest, InspectResponse, TopSnapshot, TunnelCounters,
+};
+use crate::inspect::{format_age, format_bytes, format_table};
+use nat_traversal_common::config::ServerConfig;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io::Write as _;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Switch to the terminal's alternate screen, and back
+const ENTER_SCREEN: &str = "\x1b[?1049h\x1b[?25l";
+const LEAVE_SCREEN: &str = "\x1b[?25h\x1b[?1049l";
+/// Cursor home and clear
+const CLEAR: &str = "\x1b[H\x1b[2J";
+
+/// Redraw every `interval` until Ctrl+C, listing at most `limit` tunnels
+pub async fn run(
+    config: &ServerConfig,
+    interval: Duration,
+    limit: usize,
+    observer_token: Option<&str>,
+) -> anyhow::Result<()> {
+    let sample = || async {
+        match control::send(config, observer_token, InspectRequest::Top).await? {
+            InspectResponse::Top(snapshot) => Ok(snapshot),
+            InspectResponse::Error(message) => Err(anyhow::anyhow!(message)),
+            _ => Err(anyhow::anyhow!("Unexpected answer from the server")),
+        }
+    };
+
+    // Fail before taking over the screen when no server answers
+    let mut previous = sample().await?;
+    let mut stdout = std::io::stdout();
+    write!(stdout, "{}{}", ENTER_SCREEN, render(&previous, None, limit))?;
+    stdout.flush()?;
+
+    let result = loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = tokio::signal::ctrl_c() => break Ok(()),
+        }
+        let current = match sample().await {
+            Ok(current) => current,
+            Err(e) => break Err(e),
+        };
+        write!(stdout, "{}", render(&current, Some(&previous), limit))?;
+        stdout.flush()?;
+        previous = current;
+    };
+    write!(stdout, "{}", LEAVE_SCREEN)?;
+    stdout.flush()?;
+    result
+}
+
+/// Per second change of counters between two snapshots
+struct Rates {
+    secs: f64,
+}
+
+impl Rates {
+    fn between(current: &TopSnapshot, previous: &TopSnapshot) -> Self {
+        let millis = (current.taken_at - previous.taken_at).num_milliseconds();
+        Self {
+            secs: (millis.max(1) as f64) / 1000.0,
+        }
+    }
+
+    fn per_sec(&self, now: u64, before: u64) -> f64 {
+        now.saturating_sub(before) as f64 / self.secs
+    }
+}
+
+fn render(current: &TopSnapshot, previous: Option<&TopSnapshot>, limit: usize) -> String {
+    let rates = previous.map(|previous| Rates::between(current, previous));
+    let bandwidth = |rate: Option<f64>| match rate {
+        Some(rate) => format!("{}/s", format_bytes(rate as u64)),
+        None => "-".to_string(),
+    };
+
+    // Earlier counters by client and tunnel; clients that reconnected
+    // start over and are treated as new
+    let clients_before: HashMap<(&str, _), _> = previous
+        .map(|p| {
+            p.clients
+                .iter()
+                .map(|c| ((c.id.as_str(), c.connected_at), c))
+                .collect()
+        })
+        .unwrap_or_default();
+    let tunnels_before: HashMap<Uuid, _> = previous
+        .map(|p| p.tunnels.iter().map(|t| (t.tunnel.id, t)).collect())
+        .unwrap_or_default();
+
+    let open: u32 = current
+        .tunnels
+        .iter()
+        .map(|t| t.tunnel.active_connections)
+        .sum();
+    let (mut new_connections, mut sent, mut received) = (0.0, 0.0, 0.0);
+    let mut tunnels: Vec<_> = current
+        .tunnels
+        .iter()
+        .map(|t| {
+            let rate = rates.as_ref().map(|rates| {
+                let before = tunnels_before.get(&t.tunnel.id);
+                let counter = |now: u64, then: fn(&TunnelCounters) -> u64| {
+                    rates.per_sec(now, before.map_or(0, |b| then(b)))
+                };
+                (
+                    counter(t.connections, |b| b.connections),
+                    counter(t.tunnel.bytes_sent, |b| b.tunnel.bytes_sent),
+                    counter(t.tunnel.bytes_received, |b| b.tunnel.bytes_received),
+                )
+            });
+            if let Some((connections, out, into)) = rate {
+                new_connections += connections;
+                sent += out;
+                received += into;
+            }
+            (t, rate)
+        })
+        .collect();
+    // Busiest first
+    tunnels.sort_by(|(a, a_rate), (b, b_rate)| {
+        let busy = |rate: &Option<(f64, f64, f64)>| rate.map_or(0.0, |(_, s, r)| s + r);
+        busy(b_rate)
+            .total_cmp(&busy(a_rate))
+            .then(
+                b.tunnel
+                    .active_connections
+                    .cmp(&a.tunnel.active_connections),
+            )
+            .then(a.tunnel.remote_port.cmp(&b.tunnel.remote_port))
+    });
+
+    let now = current.taken_at;
+    let mut screen = String::from(CLEAR);
+    let _ = writeln!(
+        screen,
+        "nat-server top - {}    Ctrl+C to quit",
+        now.format("%Y-%m-%d %H:%M:%S UTC")
+    );
+    let _ = writeln!(
+        screen,
+        "Clients: {}   Tunnels: {}   Connections: {} open, {}   Traffic: {} sent, {} received",
+        current.clients.len(),
+        current.tunnels.len(),
+        open,
+        match rates {
+            Some(_) => format!("{:.1}/s", new_connections),
+            None => "-/s".to_string(),
+        },
+        bandwidth(rates.as_ref().map(|_| sent)),
+        bandwidth(rates.as_ref().map(|_| received)),
+    );
+    screen.push('\n');
+
+    let mut clients: Vec<_> = current.clients.iter().collect();
+    clients.sort_by_key(|c| c.connected_at);
+    let rows = clients
+        .into_iter()
+        .map(|c| {
+            let before = clients_before.get(&(c.id.as_str(), c.connected_at));
+            let rate = |now: u64, then: fn(&ClientCounters) -> u64| {
+                rates
+                    .as_ref()
+                    .map(|rates| rates.per_sec(now, before.map_or(0, |b| then(b))))
+            };
+            vec![
+                c.id.clone(),
+                c.addr.to_string(),
+                format_age(now, c.connected_at),
+                c.tunnels.to_string(),
+                bandwidth(rate(c.bytes_sent, |b| b.bytes_sent)),
+                bandwidth(rate(c.bytes_received, |b| b.bytes_received)),
+            ]
+        })
+        .collect();
+    for line in format_table(
+        &["CLIENT", "ADDRESS", "UPTIME", "TUNNELS", "SENT", "RECEIVED"],
+        rows,
+    ) {
+        let _ = writeln!(screen, "{}", line);
+    }
+    screen.push('\n');
+
+    let hidden = tunnels.len().saturating_sub(limit);
+    let rows = tunnels
+        .into_iter()
+        .take(limit)
+        .map(|(t, rate)| {
+            vec![
+                t.tunnel
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| t.tunnel.id.to_string()),
+                t.client_id.clone(),
+                match &t.tunnel.domain {
+                    Some(domain) => format!("{} ({})", t.tunnel.remote_port, domain),
+                    None => t.tunnel.remote_ports().to_string(),
+                },
+                t.tunnel.protocol.to_string(),
+                t.tunnel.active_connections.to_string(),
+                rate.map_or_else(|| "-".to_string(), |(c, _, _)| format!("{:.1}", c)),
+                bandwidth(rate.map(|(_, s, _)| s)),
+                bandwidth(rate.map(|(_, _, r)| r)),
+                format_bytes(t.tunnel.bytes_sent + t.tunnel.bytes_received),
+            ]
+        })
+        .collect();
+    for line in format_table(
+        &[
+            "TUNNEL", "CLIENT", "PORT", "PROTO", "CONNS", "CONN/S", "SENT", "RECEIVED", "TOTAL",
+        ],
+        rows,
+    ) {
+        let _ = writeln!(screen, "{}", line);
+    }
+    if hidden > 0 {
+        let _ = writeln!(screen, "... {} quieter tunnel(s) not shown", hidden);
+    }
+    screen
+}
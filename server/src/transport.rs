@@ -0,0 +1,56 @@
+//! TLS over TCP, the transport clients reach the server on
+
+use crate::{ca, connection::SecureStream};
+use nat_traversal_common::{
+    config::SocketOptions,
+    error::{NatError, NatResult},
+    socket,
+    transport::{Established, Listener},
+};
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+
+pub struct TlsListener {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    /// Options for accepted control connections
+    sockets: SocketOptions,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, acceptor: TlsAcceptor, sockets: SocketOptions) -> Self {
+        Self {
+            listener,
+            acceptor,
+            sockets,
+        }
+    }
+}
+
+impl Listener for TlsListener {
+    type Incoming = TcpStream;
+    type Stream = SecureStream;
+
+    async fn accept(&self) -> NatResult<(TcpStream, SocketAddr)> {
+        let (stream, addr) = self.listener.accept().await?;
+        socket::configure(&stream, &self.sockets);
+        Ok((stream, addr))
+    }
+
+    async fn establish(&self, incoming: TcpStream) -> NatResult<Established<SecureStream>> {
+        let stream = self
+            .acceptor
+            .accept(incoming)
+            .await
+            .map_err(|e| NatError::tls(format!("TLS handshake failed: {}", e)))?;
+        // Name on the client certificate, already verified against the CA
+        let identity = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|chain| chain.first())
+            .and_then(|cert| ca::identity(&cert.0));
+        Ok(Established { stream, identity })
+    }
+}
@@ -1,23 +1,117 @@
-use crate::connection::ConnectionManager;
-use chrono::Utc;
+use crate::capture::Capture;
+use crate::connection::{ClientConnection, ConnectionManager};
+use crate::events::ServerEvent;
+use crate::plugin::{Filtered, Plugins, TunnelPlugins, Visit};
+use crate::reservation::Reservations;
+use crate::vhost::{
+    self, CertStore, DomainVerifier, HeaderRewrite, HttpGate, RequestFilter, Requests,
+};
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
 use nat_traversal_common::{
+    config::{Dscp, PerformanceConfig, PortRange, SocketOptions},
+    crypto,
+    datagram::{self, DatagramStream},
+    domain as domain_name,
     error::{NatError, NatResult},
-    protocol::{Message, TunnelInfo, TunnelProtocol},
+    protocol::{
+        Gauge, HttpAuth, HttpHeaders, Message, ShareInfo, ShutdownDirection, TlsCertificate,
+        TunnelInfo, TunnelMode, TunnelProtocol, UdpSessions, Utilization,
+    },
+    queue::{self, QueueSender, SendError},
+    resume::{Outbox, ResumeLimits},
+    socket,
+    telemetry::{self, Direction},
 };
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::{mpsc, RwLock};
-use tracing::{debug, error, info};
+use tokio::task::AbortHandle;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 use uuid::Uuid;
 
+/// Public ports tunnels and relays are given
+pub const TUNNEL_PORTS: (u16, u16) = (8000, 9000);
+
+/// Server-wide caps; what goes over one is refused
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Capacity {
+    pub max_tunnels: Option<u32>,
+    pub max_connections: Option<u32>,
+    pub max_ports: Option<u32>,
+}
+
+/// Public connections keyed by tunnel and connection ID. Sharded so the
+/// data path never waits on the tunnel table or on unrelated connections.
+type ConnectionMap = Arc<DashMap<(Uuid, u32), TunnelConnection>>;
+
 /// Manages tunnels and port forwarding
 pub struct TunnelManager {
     tunnels: Arc<RwLock<HashMap<Uuid, TunnelHandler>>>,
+    connections: ConnectionMap,
     port_allocator: Arc<RwLock<PortAllocator>>,
     connection_manager: Arc<ConnectionManager>,
+    performance: PerformanceConfig,
+    /// Options for accepted public connections
+    sockets: SocketOptions,
+    /// Accept loops per tunnel port
+    accept_workers: usize,
+    /// Largest port range one tunnel may expose
+    max_ports_per_tunnel: u16,
+    shares: Arc<RwLock<HashMap<Uuid, Share>>>,
+    /// Longest a share may last
+    max_share_ttl_secs: u64,
+    /// Present when the HTTP listener is enabled
+    domain_verifier: Option<DomainVerifier>,
+    /// Present when the HTTPS listener is enabled
+    certificates: Option<Arc<CertStore>>,
+    /// Ports and domains held for particular clients
+    reservations: Arc<Reservations>,
+    /// How long public connections wait for a dropped client to come back
+    resume: ResumeLimits,
+    /// Bytes a public connection may queue before it is closed
+    connection_buffer: usize,
+    capacity: Capacity,
+    /// Operator scripts run on public connections
+    plugins: Arc<Plugins>,
+}
+
+/// A temporary extra public port of a tunnel
+struct Share {
+    info: ShareInfo,
+    owner: String,
+    /// Stops accepting on the share's port
+    accept_task: AbortHandle,
+}
+
+/// What a visitor to a share has to pass before reaching the tunnel
+struct ShareGate {
+    id: Uuid,
+    /// Hash of the password visitors send as their first line
+    password_hash: Option<String>,
+}
+
+/// Longest password line a visitor may send
+const MAX_PASSWORD_LINE: usize = 256;
+
+/// Datagrams a UDP session holds while its connection catches up; more
+/// are dropped, as on any congested path
+const UDP_SESSION_QUEUE: usize = 256;
+
+/// A bound public port of a tunnel
+enum PortListener {
+    Tcp(TcpListener),
+    /// One socket for all of the port's UDP visitors
+    Udp(UdpSocket),
 }
 
 /// Handles a specific tunnel
@@ -25,15 +119,129 @@ pub struct TunnelHandler {
     pub info: TunnelInfo,
     pub listener: Option<TcpListener>,
     pub client_id: String,
-    pub connections: Arc<RwLock<HashMap<u32, TunnelConnection>>>,
-    pub next_connection_id: Arc<RwLock<u32>>,
+    pub next_connection_id: Arc<AtomicU32>,
+    /// Open connections and bytes carried, reported in `TunnelInfo`
+    pub traffic: Arc<TunnelTraffic>,
+    /// Credentials HTTP visitors have to present
+    pub http_gate: Option<Arc<HttpGate>>,
+    /// Plain-HTTP requests for the domain are redirected to HTTPS
+    pub https_redirect: bool,
+    /// Headers set on HTTP requests for the domain
+    pub header_rewrite: Option<Arc<HeaderRewrite>>,
+    /// Operator plugins run for the tunnel's connections
+    pub plugins: Option<Arc<TunnelPlugins>>,
+    /// Options for the tunnel's public connections, marked with the DSCP
+    /// class the client asked for
+    pub sockets: SocketOptions,
+    /// Set on UDP tunnels, whose ports carry each visitor's datagrams as a
+    /// session of its own
+    pub udp: Option<UdpSessions>,
+    /// Stop accepting on the tunnel's ports
+    pub accept_tasks: Vec<AbortHandle>,
+}
+
+/// Where the HTTP and HTTPS listeners send connections for a domain
+pub struct DomainRoute {
+    pub tunnel_id: Uuid,
+    /// Credentials HTTP visitors have to present
+    pub http_gate: Option<Arc<HttpGate>>,
+    /// HTTPS connections go to the tunnel without being decrypted
+    pub tls_passthrough: bool,
+    /// Plain-HTTP requests get a redirect to HTTPS instead
+    pub https_redirect: bool,
+    /// Headers set on HTTP requests before they reach the tunnel
+    pub header_rewrite: Option<Arc<HeaderRewrite>>,
 }
 
 /// Represents a connection through a tunnel
 pub struct TunnelConnection {
     pub id: u32,
     pub client_addr: SocketAddr,
-    pub sender: mpsc::UnboundedSender<Vec<u8>>,
+    pub sender: QueueSender<Bytes>,
+    pub opened_at: DateTime<Utc>,
+    /// Share the connection arrived through, if any
+    pub share: Option<Uuid>,
+    /// Payload bytes received from the client, reported when resuming
+    received: Arc<AtomicU64>,
+    /// Tells the connection's reader about its client's session
+    events: mpsc::UnboundedSender<SessionEvent>,
+    _active: ActiveConnection,
+}
+
+/// What happened to the session a public connection's client is on
+enum SessionEvent {
+    /// The visitor stopped reading and the client's data for it reached
+    /// the buffer cap; drop the connection
+    Overflowed,
+    /// A plugin closed the connection over data for the visitor
+    Refused,
+    /// The session ended without a goodbye; hold data for the client to
+    /// come back
+    Lost(Arc<ClientConnection>),
+    /// The client is back on a new session and picked the connection up
+    /// after receiving `received` bytes of it
+    Resumed {
+        client: Arc<ClientConnection>,
+        received: u64,
+    },
+}
+
+/// An open public connection, as listed by `nat-server inspect`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicConnection {
+    pub tunnel_id: Uuid,
+    pub connection_id: u32,
+    pub peer: SocketAddr,
+    pub opened_at: DateTime<Utc>,
+}
+
+/// Live counters of a tunnel, shared with its public connections
+#[derive(Default)]
+pub struct TunnelTraffic {
+    pub active_connections: AtomicU32,
+    /// Connections carried since the tunnel opened
+    pub connections: AtomicU64,
+    /// Bytes sent to public visitors
+    pub bytes_sent: AtomicU64,
+    /// Bytes received from public visitors
+    pub bytes_received: AtomicU64,
+    /// Payloads are written here while an operator captures the tunnel
+    pub capture: std::sync::RwLock<Option<Arc<Capture>>>,
+}
+
+impl TunnelTraffic {
+    fn capture(&self) -> Option<Arc<Capture>> {
+        self.capture.read().unwrap().clone()
+    }
+}
+
+/// Holds one place in a tunnel's active connection count, so every way a
+/// connection leaves the map keeps the count right
+struct ActiveConnection(Arc<TunnelTraffic>);
+
+impl ActiveConnection {
+    fn new(traffic: Arc<TunnelTraffic>) -> Self {
+        traffic.active_connections.fetch_add(1, Ordering::Relaxed);
+        traffic.connections.fetch_add(1, Ordering::Relaxed);
+        Self(traffic)
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl TunnelHandler {
+    /// Tunnel info with the live connection count filled in
+    fn current_info(&self) -> TunnelInfo {
+        let mut info = self.info.clone();
+        info.active_connections = self.traffic.active_connections.load(Ordering::Relaxed);
+        info.bytes_sent = self.traffic.bytes_sent.load(Ordering::Relaxed);
+        info.bytes_received = self.traffic.bytes_received.load(Ordering::Relaxed);
+        info
+    }
 }
 
 /// Manages port allocation for tunnels
@@ -41,24 +249,83 @@ pub struct PortAllocator {
     allocated_ports: HashMap<u16, Uuid>,
     next_port: u16,
     port_range: (u16, u16),
+    /// Ports only their client's tunnels may take
+    reservations: Arc<Reservations>,
+    /// Most ports held at once
+    max_in_use: Option<u32>,
 }
 
 impl PortAllocator {
-    pub fn new(port_range: (u16, u16)) -> Self {
+    pub fn new(
+        port_range: (u16, u16),
+        reservations: Arc<Reservations>,
+        max_in_use: Option<u32>,
+    ) -> Self {
         Self {
             allocated_ports: HashMap::new(),
             next_port: port_range.0,
             port_range,
+            reservations,
+            max_in_use,
+        }
+    }
+
+    /// Refuse `count` more ports when they would go over the cap
+    pub fn check_room(&self, count: u16) -> NatResult<()> {
+        match self.max_in_use {
+            Some(max) if self.allocated_ports.len() + count as usize > max as usize => Err(
+                NatError::limit_reached(format!("The server has all {} of its ports in use", max)),
+            ),
+            _ => Ok(()),
         }
     }
 
+    /// Ports held, against the cap
+    pub fn in_use(&self) -> Gauge {
+        Gauge {
+            used: self.allocated_ports.len() as u64,
+            limit: self.max_in_use.map(u64::from),
+        }
+    }
+
+    /// Whether `port` is neither taken nor reserved
+    fn is_free(&self, port: u16) -> bool {
+        !self.allocated_ports.contains_key(&port) && self.reservations.port_owner(port).is_none()
+    }
+
+    /// Allocate `count` consecutive ports reserved for `client_id` to
+    /// `owner`, starting at `preferred_port` when it is one of them, and
+    /// return the first. Reserved ports may lie outside the public range.
+    pub fn allocate_reserved(
+        &mut self,
+        client_id: &str,
+        preferred_port: Option<u16>,
+        count: u16,
+        owner: Uuid,
+    ) -> Option<u16> {
+        let fits = |start: u16| {
+            let end = start.checked_add(count.checked_sub(1)?)?;
+            let usable = (start..=end).all(|port| {
+                self.reservations.port_owner(port) == Some(client_id)
+                    && !self.allocated_ports.contains_key(&port)
+            });
+            usable.then_some(end)
+        };
+        let (start, end) = preferred_port
+            .into_iter()
+            .chain(self.reservations.ports_of(client_id))
+            .find_map(|start| fits(start).map(|end| (start, end)))?;
+
+        for port in start..=end {
+            self.allocated_ports.insert(port, owner);
+        }
+        Some(start)
+    }
+
     pub fn allocate_port(&mut self, preferred_port: Option<u16>) -> Option<u16> {
         // Try preferred port first
         if let Some(port) = preferred_port {
-            if port >= self.port_range.0
-                && port <= self.port_range.1
-                && !self.allocated_ports.contains_key(&port)
-            {
+            if port >= self.port_range.0 && port <= self.port_range.1 && self.is_free(port) {
                 self.allocated_ports.insert(port, Uuid::nil()); // Temporary placeholder
                 return Some(port);
             }
@@ -67,7 +334,7 @@ impl PortAllocator {
         // Find next available port
         let start_port = self.next_port;
         loop {
-            if !self.allocated_ports.contains_key(&self.next_port) {
+            if self.is_free(self.next_port) {
                 let port = self.next_port;
                 self.next_port += 1;
                 if self.next_port > self.port_range.1 {
@@ -90,6 +357,84 @@ impl PortAllocator {
         None
     }
 
+    /// Like `allocate_port`, but never leaves `allowed` when it is not empty
+    pub fn allocate_port_within(
+        &mut self,
+        preferred_port: Option<u16>,
+        allowed: &[PortRange],
+    ) -> Option<u16> {
+        if allowed.is_empty() {
+            return self.allocate_port(preferred_port);
+        }
+        let (low, high) = self.port_range;
+        preferred_port
+            .into_iter()
+            .chain(
+                allowed
+                    .iter()
+                    .flat_map(|range| range.start.max(low)..=range.end.min(high)),
+            )
+            .find(|port| {
+                (low..=high).contains(port)
+                    && allowed.iter().any(|range| range.contains(*port))
+                    && self.is_free(*port)
+            })
+    }
+
+    /// Allocate `count` consecutive free ports for `owner` in one step,
+    /// starting at `preferred_port` when it is free, and return the first.
+    /// The block stays within `allowed` when it is not empty.
+    pub fn allocate_block(
+        &mut self,
+        preferred_port: Option<u16>,
+        count: u16,
+        allowed: &[PortRange],
+        owner: Uuid,
+    ) -> Option<u16> {
+        let (low, high) = self.port_range;
+        let fits = |start: u16| {
+            let end = start.checked_add(count.checked_sub(1)?)?;
+            let usable = start >= low
+                && end <= high
+                && (allowed.is_empty()
+                    || allowed
+                        .iter()
+                        .any(|range| range.contains(start) && range.contains(end)))
+                && (start..=end).all(|port| self.is_free(port));
+            usable.then_some(end)
+        };
+        let (start, end) = preferred_port
+            .into_iter()
+            .chain(low..=high)
+            .find_map(|start| fits(start).map(|end| (start, end)))?;
+
+        for port in start..=end {
+            self.allocated_ports.insert(port, owner);
+        }
+        Some(start)
+    }
+
+    /// Allocate a free port picked at random, so it cannot be guessed from
+    /// earlier allocations, and record `owner` as holding it
+    pub fn reserve_random(&mut self, allowed: &[PortRange], owner: Uuid) -> Option<u16> {
+        let (low, high) = self.port_range;
+        let free: Vec<u16> = (low..=high)
+            .filter(|port| {
+                (allowed.is_empty() || allowed.iter().any(|range| range.contains(*port)))
+                    && self.is_free(*port)
+            })
+            .collect();
+        let port = *free.choose(&mut rand::thread_rng())?;
+        self.allocated_ports.insert(port, owner);
+        Some(port)
+    }
+
+    /// Allocate any free port and record `owner` as holding it
+    pub fn reserve(&mut self, owner: Uuid) -> Option<u16> {
+        let port = self.allocate_port(None)?;
+        self.allocated_ports.insert(port, owner);
+        Some(port)
+    }
 
     pub fn release_port(&mut self, port: u16) -> bool {
         self.allocated_ports.remove(&port).is_some()
@@ -97,32 +442,275 @@ impl PortAllocator {
 }
 
 impl TunnelManager {
-    pub fn new(connection_manager: Arc<ConnectionManager>, port_range: (u16, u16)) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        connection_manager: Arc<ConnectionManager>,
+        port_range: (u16, u16),
+        performance: PerformanceConfig,
+        sockets: SocketOptions,
+        accept_workers: usize,
+        max_ports_per_tunnel: u16,
+        max_share_ttl_secs: u64,
+        domain_verifier: Option<DomainVerifier>,
+        certificates: Option<Arc<CertStore>>,
+        reservations: Arc<Reservations>,
+        resume: ResumeLimits,
+        connection_buffer: usize,
+        capacity: Capacity,
+        plugins: Arc<Plugins>,
+    ) -> Self {
         Self {
             tunnels: Arc::new(RwLock::new(HashMap::new())),
-            port_allocator: Arc::new(RwLock::new(PortAllocator::new(port_range))),
+            connections: Arc::new(DashMap::new()),
+            port_allocator: Arc::new(RwLock::new(PortAllocator::new(
+                port_range,
+                reservations.clone(),
+                capacity.max_ports,
+            ))),
             connection_manager,
+            performance,
+            sockets,
+            accept_workers,
+            max_ports_per_tunnel,
+            shares: Arc::new(RwLock::new(HashMap::new())),
+            max_share_ttl_secs,
+            domain_verifier,
+            certificates,
+            reservations,
+            resume,
+            connection_buffer,
+            capacity,
+            plugins,
+        }
+    }
+
+    /// How much of the server's capacity is in use
+    pub async fn utilization(&self) -> Utilization {
+        Utilization {
+            tunnels: Gauge {
+                used: self.tunnels.read().await.len() as u64,
+                limit: self.capacity.max_tunnels.map(u64::from),
+            },
+            connections: Gauge {
+                used: self.connections.len() as u64,
+                limit: self.capacity.max_connections.map(u64::from),
+            },
+            ports: self.port_allocator.read().await.in_use(),
         }
     }
 
+    /// Port allocator shared with other users of the public port range
+    pub fn port_allocator(&self) -> Arc<RwLock<PortAllocator>> {
+        self.port_allocator.clone()
+    }
+
+    /// Create a tunnel, picking the public port from `allowed_ports` when
+    /// none was requested and the list is not empty. A tunnel with
+    /// `port_count` above one reserves that many consecutive public ports.
+    #[allow(clippy::too_many_arguments)]
     pub async fn create_tunnel(
         &self,
         client_id: String,
+        local_host: String,
         local_port: u16,
         remote_port: Option<u16>,
+        port_count: u16,
         protocol: TunnelProtocol,
         name: Option<String>,
+        ttl_secs: Option<u64>,
+        domain: Option<String>,
+        http_auth: Option<HttpAuth>,
+        tls_certificate: Option<TlsCertificate>,
+        tls_passthrough: bool,
+        https_redirect: bool,
+        http_headers: Option<HttpHeaders>,
+        dscp: Option<Dscp>,
+        udp: Option<UdpSessions>,
+        allowed_ports: &[PortRange],
     ) -> NatResult<TunnelInfo> {
+        if port_count == 0 || port_count > self.max_ports_per_tunnel {
+            return Err(NatError::tunnel(format!(
+                "A tunnel may expose 1 to {} ports",
+                self.max_ports_per_tunnel
+            )));
+        }
+        if local_port.checked_add(port_count - 1).is_none() {
+            return Err(NatError::tunnel("Local port range runs past 65535"));
+        }
+        if let Some(max) = self.capacity.max_tunnels {
+            if self.tunnels.read().await.len() >= max as usize {
+                return Err(NatError::limit_reached(format!(
+                    "The server has all {} of its tunnels open",
+                    max
+                )));
+            }
+        }
+        let expires_at = match ttl_secs {
+            Some(0) => return Err(NatError::tunnel("Tunnel TTL must be at least one second")),
+            Some(secs) => i64::try_from(secs)
+                .ok()
+                .and_then(chrono::Duration::try_seconds)
+                .and_then(|ttl| Utc::now().checked_add_signed(ttl)),
+            None => None,
+        };
+        let domain = match domain {
+            Some(domain) => {
+                let verifier = self
+                    .domain_verifier
+                    .as_ref()
+                    .ok_or_else(|| NatError::tunnel("This server has no HTTP listener"))?;
+                // A reserved domain needs no TXT record from its client and
+                // is refused to everyone else
+                let reserved = domain_name::normalize(&domain).and_then(|name| {
+                    let owner = self.reservations.domain_owner(&name)?;
+                    Some((owner == client_id, name))
+                });
+                match reserved {
+                    Some((true, name)) => Some(name),
+                    Some((false, name)) => {
+                        return Err(NatError::permission_denied(format!(
+                            "Domain {} is reserved for another client",
+                            name
+                        )))
+                    }
+                    None => Some(
+                        verifier
+                            .verify(&client_id, &domain)
+                            .await
+                            .map_err(NatError::tunnel)?,
+                    ),
+                }
+            }
+            None => None,
+        };
+        let http_gate = match &http_auth {
+            Some(_) if protocol != TunnelProtocol::Tcp => {
+                return Err(NatError::tunnel("Only TCP tunnels can require HTTP auth"))
+            }
+            Some(auth) => Some(Arc::new(HttpGate::new(auth).map_err(NatError::tunnel)?)),
+            None => None,
+        };
+        if let Some(domain) = &domain {
+            if protocol != TunnelProtocol::Tcp {
+                return Err(NatError::tunnel("Only TCP tunnels can serve a domain"));
+            }
+            if let Some(existing) = self.route_domain(domain).await {
+                // A reconnecting client takes its domain back from the
+                // tunnel it left behind
+                if self.tunnel_owner(&existing.tunnel_id).await.as_deref()
+                    != Some(client_id.as_str())
+                {
+                    return Err(NatError::tunnel(format!(
+                        "Domain {} is already served by another tunnel",
+                        domain
+                    )));
+                }
+                let _ = self.close_tunnel(&existing.tunnel_id).await;
+            }
+        }
+        if tls_passthrough {
+            if domain.is_none() {
+                return Err(NatError::tunnel("TLS passthrough needs a domain"));
+            }
+            if self.certificates.is_none() {
+                return Err(NatError::tunnel("This server has no HTTPS listener"));
+            }
+            if tls_certificate.is_some() || http_gate.is_some() {
+                return Err(NatError::tunnel(
+                    "A TLS passthrough tunnel cannot have a certificate or HTTP auth",
+                ));
+            }
+        }
+        let certified_key = match (&tls_certificate, &domain, &self.certificates) {
+            (None, _, _) => None,
+            (Some(_), None, _) => return Err(NatError::tunnel("A certificate needs a domain")),
+            (Some(_), _, None) => {
+                return Err(NatError::tunnel("This server has no HTTPS listener"))
+            }
+            (Some(certificate), Some(_), Some(_)) => Some(Arc::new(
+                vhost::certified_key(certificate).map_err(NatError::tunnel)?,
+            )),
+        };
+        let header_rewrite = match &http_headers {
+            Some(_) if domain.is_none() => {
+                return Err(NatError::tunnel("HTTP headers need a domain"))
+            }
+            Some(_) if tls_passthrough => {
+                return Err(NatError::tunnel(
+                    "A TLS passthrough tunnel cannot have HTTP headers set",
+                ))
+            }
+            Some(headers) => Some(Arc::new(
+                HeaderRewrite::new(headers).map_err(NatError::tunnel)?,
+            )),
+            None => None,
+        };
+        let udp = match udp {
+            Some(_) if protocol != TunnelProtocol::Udp => {
+                return Err(NatError::tunnel(
+                    "Only UDP tunnels have UDP session settings",
+                ))
+            }
+            Some(udp)
+                if udp.idle_timeout_secs == 0
+                    || udp.max_sessions == 0
+                    || udp.max_datagram_size == 0 =>
+            {
+                return Err(NatError::tunnel(
+                    "A UDP tunnel needs an idle timeout, a session cap and a datagram size of at least one",
+                ))
+            }
+            udp => udp.or((protocol == TunnelProtocol::Udp).then(UdpSessions::default)),
+        };
+        if https_redirect {
+            if domain.is_none() {
+                return Err(NatError::tunnel("An HTTPS redirect needs a domain"));
+            }
+            if self.certificates.is_none() {
+                return Err(NatError::tunnel("This server has no HTTPS listener"));
+            }
+        }
+        if let Some(port) = remote_port {
+            self.reservations
+                .check_ports(&client_id, port, port_count)
+                .map_err(NatError::permission_denied)?;
+        }
         let tunnel_id = Uuid::new_v4();
 
+        // A client with reserved ports gets one unless it asks for another
+        let mut preferred_port = remote_port;
+        let reserved = !self.reservations.ports_of(&client_id).is_empty()
+            && remote_port
+                .is_none_or(|port| self.reservations.port_owner(port) == Some(client_id.as_str()));
+        if reserved {
+            if let Some(port) = self
+                .reclaim_reserved(&client_id, remote_port, name.as_deref())
+                .await
+            {
+                preferred_port = Some(port);
+            }
+        }
+
         // Allocate remote port
         let mut allocator = self.port_allocator.write().await;
-        let assigned_port = allocator
-            .allocate_port(remote_port)
-            .ok_or_else(|| NatError::tunnel("No available ports"))?;
-
-        // Update the reservation with the actual tunnel ID
-        allocator.allocated_ports.insert(assigned_port, tunnel_id);
+        allocator.check_room(port_count)?;
+        let assigned_port = match reserved
+            .then(|| allocator.allocate_reserved(&client_id, preferred_port, port_count, tunnel_id))
+            .flatten()
+        {
+            Some(port) => Some(port),
+            None if remote_port.is_some() && reserved => None,
+            None if port_count == 1 => {
+                let port = allocator.allocate_port_within(remote_port, allowed_ports);
+                // Update the reservation with the actual tunnel ID
+                if let Some(port) = port {
+                    allocator.allocated_ports.insert(port, tunnel_id);
+                }
+                port
+            }
+            None => allocator.allocate_block(remote_port, port_count, allowed_ports, tunnel_id),
+        }
+        .ok_or_else(|| NatError::tunnel("No available ports"))?;
         drop(allocator);
 
         // Create tunnel info
@@ -130,12 +718,19 @@ impl TunnelManager {
             id: tunnel_id,
             name,
             protocol,
+            local_host: local_host.clone(),
             local_port,
             remote_port: assigned_port,
             created_at: Utc::now(),
             bytes_sent: 0,
             bytes_received: 0,
             active_connections: 0,
+            mode: TunnelMode::Server,
+            port_count,
+            expires_at,
+            domain,
+            tls_passthrough,
+            udp,
         };
 
         // Create tunnel handler
@@ -143,32 +738,147 @@ impl TunnelManager {
             info: tunnel_info.clone(),
             listener: None,
             client_id: client_id.clone(),
-            connections: Arc::new(RwLock::new(HashMap::new())),
-            next_connection_id: Arc::new(RwLock::new(1)),
+            next_connection_id: Arc::new(AtomicU32::new(1)),
+            traffic: Arc::new(TunnelTraffic::default()),
+            http_gate,
+            https_redirect,
+            header_rewrite,
+            plugins: self
+                .plugins
+                .for_tunnel(tunnel_id, tunnel_info.name.as_deref(), &client_id)
+                .await,
+            sockets: SocketOptions {
+                dscp: dscp.or(self.sockets.dscp),
+                ..self.sockets.clone()
+            },
+            udp,
+            accept_tasks: Vec::new(),
         };
 
         // Store tunnel
         let mut tunnels = self.tunnels.write().await;
         tunnels.insert(tunnel_id, tunnel_handler);
         drop(tunnels);
+        if let (Some(key), Some(domain), Some(certificates)) =
+            (certified_key, &tunnel_info.domain, &self.certificates)
+        {
+            certificates.upload(domain, tunnel_id, key);
+        }
 
         // Start listening for connections
         self.start_tunnel_listener(tunnel_id).await?;
+        telemetry::tunnels_changed(1);
+        if let Some(storage) = self.connection_manager.storage() {
+            storage.tunnel_created(&client_id, &tunnel_info);
+        }
+        self.connection_manager
+            .events()
+            .publish(ServerEvent::TunnelOpened {
+                client_id: client_id.clone(),
+                tunnel: tunnel_info.clone(),
+            });
 
         info!(
-            "Created tunnel {} for client {} - {}:{} -> {}:{}",
-            tunnel_id, client_id, assigned_port, protocol, local_port, protocol
+            "Created tunnel {} for client {} - {}:{} -> {}:{}:{}",
+            tunnel_id,
+            client_id,
+            tunnel_info.remote_ports(),
+            protocol,
+            local_host,
+            tunnel_info.local_ports(),
+            protocol
         );
 
         Ok(tunnel_info)
     }
 
+    /// Close a tunnel `client_id` left behind on a reserved port, by the
+    /// port asked for or else by name, and return the port so a
+    /// reconnecting client gets it back
+    async fn reclaim_reserved(
+        &self,
+        client_id: &str,
+        remote_port: Option<u16>,
+        name: Option<&str>,
+    ) -> Option<u16> {
+        let live = self.connection_manager.get_client(client_id).await;
+        let stale = {
+            let tunnels = self.tunnels.read().await;
+            let mut stale = Vec::new();
+            for tunnel in tunnels.values() {
+                let matches = match remote_port {
+                    Some(port) => tunnel.info.remote_port == port,
+                    None => name.is_some() && tunnel.info.name.as_deref() == name,
+                };
+                if tunnel.client_id == client_id
+                    && matches
+                    && self.reservations.port_owner(tunnel.info.remote_port) == Some(client_id)
+                {
+                    stale.push((tunnel.info.id, tunnel.info.remote_port));
+                }
+            }
+            stale
+        };
+        for (tunnel_id, port) in stale {
+            if let Some(client) = &live {
+                if client.get_tunnel(&tunnel_id).await.is_some() {
+                    continue;
+                }
+            }
+            let _ = self.close_tunnel(&tunnel_id).await;
+            return Some(port);
+        }
+        None
+    }
+
     pub async fn close_tunnel(&self, tunnel_id: &Uuid) -> NatResult<()> {
         let mut tunnels = self.tunnels.write().await;
         if let Some(tunnel) = tunnels.remove(tunnel_id) {
-            // Release port
+            // Release ports
             let mut allocator = self.port_allocator.write().await;
-            allocator.release_port(tunnel.info.remote_port);
+            let ports = tunnel.info.remote_ports();
+            for port in ports.start..=ports.end {
+                allocator.release_port(port);
+            }
+            drop(allocator);
+            // Free the ports for whoever is allocated them next
+            for task in &tunnel.accept_tasks {
+                task.abort();
+            }
+
+            if let (Some(domain), Some(certificates)) = (&tunnel.info.domain, &self.certificates) {
+                certificates.remove(domain, *tunnel_id);
+            }
+
+            // Dropping the senders ends the tunnel's public connections
+            self.connections.retain(|(id, _), _| id != tunnel_id);
+            telemetry::tunnels_changed(-1);
+            let info = tunnel.current_info();
+            let connections = tunnel.traffic.connections.load(Ordering::Relaxed);
+            if let Some(storage) = self.connection_manager.storage() {
+                storage.tunnel_closed(&info, connections);
+            }
+            self.connection_manager
+                .events()
+                .publish(ServerEvent::TunnelClosed {
+                    client_id: tunnel.client_id.clone(),
+                    tunnel: info,
+                    connections,
+                    at: Utc::now(),
+                });
+            drop(tunnels);
+
+            let shares: Vec<Uuid> = self
+                .shares
+                .read()
+                .await
+                .values()
+                .filter(|share| share.info.tunnel_id == *tunnel_id)
+                .map(|share| share.info.id)
+                .collect();
+            for share_id in shares {
+                self.close_share(&share_id, "Tunnel closed").await;
+            }
 
             info!("Closed tunnel {}", tunnel_id);
             Ok(())
@@ -177,204 +887,1394 @@ impl TunnelManager {
         }
     }
 
+    /// Close a tunnel at `expires_at` and tell its client, if still
+    /// connected, that it expired
+    pub fn schedule_expiry(self: &Arc<Self>, tunnel_id: Uuid, expires_at: DateTime<Utc>) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let remaining = (expires_at - Utc::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(remaining).await;
+
+            // The tunnel may have been closed in the meantime
+            let Some(owner) = manager.tunnel_owner(&tunnel_id).await else {
+                return;
+            };
+            if manager.close_tunnel(&tunnel_id).await.is_err() {
+                return;
+            }
+            info!("Tunnel {} of client {} expired", tunnel_id, owner);
+            if let Some(client) = manager.connection_manager.get_client(&owner).await {
+                client.remove_tunnel(&tunnel_id).await;
+                let _ = client
+                    .send_message(Message::TunnelClosed {
+                        tunnel_id,
+                        reason: "expired".to_string(),
+                    })
+                    .await;
+            }
+        });
+    }
+
+    /// The tunnel bound to `host`, and how to hand it connections
+    pub async fn route_domain(&self, host: &str) -> Option<DomainRoute> {
+        let tunnels = self.tunnels.read().await;
+        tunnels
+            .values()
+            .find(|tunnel| tunnel.info.domain.as_deref() == Some(host))
+            .map(|tunnel| DomainRoute {
+                tunnel_id: tunnel.info.id,
+                http_gate: tunnel.http_gate.clone(),
+                tls_passthrough: tunnel.info.tls_passthrough,
+                https_redirect: tunnel.https_redirect,
+                header_rewrite: tunnel.header_rewrite.clone(),
+            })
+    }
+
+    /// Carry a public connection accepted elsewhere, such as by the HTTP
+    /// listener, through a tunnel. `prefix` holds what was already read
+    /// from it and reaches the client first.
+    pub async fn attach_connection<S>(
+        &self,
+        tunnel_id: Uuid,
+        stream: S,
+        addr: SocketAddr,
+        prefix: Bytes,
+    ) -> NatResult<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        if let Some(max) = self.capacity.max_connections {
+            if self.connections.len() >= max as usize {
+                return Err(NatError::limit_reached(format!(
+                    "The server has all {} of its connections open",
+                    max
+                )));
+            }
+        }
+        let (client_id, connection_id, active, visit) = {
+            let tunnels = self.tunnels.read().await;
+            let tunnel = tunnels
+                .get(&tunnel_id)
+                .ok_or_else(|| NatError::tunnel("Tunnel not found"))?;
+            let connection_id = tunnel.next_connection_id.fetch_add(1, Ordering::Relaxed);
+            (
+                tunnel.client_id.clone(),
+                connection_id,
+                ActiveConnection::new(tunnel.traffic.clone()),
+                tunnel
+                    .plugins
+                    .as_ref()
+                    .map(|plugins| plugins.visit(connection_id, addr, tunnel.info.remote_port)),
+            )
+        };
+        let client = self
+            .connection_manager
+            .get_client(&client_id)
+            .await
+            .ok_or_else(|| NatError::connection(format!("Client {} gone", client_id)))?;
+        if client.quota_exhausted() {
+            return Err(NatError::permission_denied(format!(
+                "Client {} used up its monthly quota",
+                client_id
+            )));
+        }
+        let client_tx = client.tunnel_sender(&tunnel_id, connection_id).await;
+
+        Self::handle_tunnel_connection(
+            tunnel_id,
+            connection_id,
+            0,
+            stream,
+            addr,
+            self.connections.clone(),
+            client_tx,
+            client,
+            active,
+            self.performance,
+            self.resume,
+            self.connection_buffer,
+            None,
+            prefix,
+            visit,
+        )
+        .instrument(info_span!("session", connection_id, peer = %addr))
+        .await
+    }
+
+    /// Open a temporary public port for `client_id`'s tunnel that closes
+    /// after `ttl_secs`. With a password, visitors must send it as their
+    /// first line before their traffic reaches the tunnel.
+    pub async fn create_share(
+        self: &Arc<Self>,
+        client_id: &str,
+        tunnel_id: Uuid,
+        ttl_secs: u64,
+        password: Option<String>,
+        allowed_ports: &[PortRange],
+    ) -> NatResult<ShareInfo> {
+        if ttl_secs == 0 || ttl_secs > self.max_share_ttl_secs {
+            return Err(NatError::tunnel(format!(
+                "A share may last 1 to {} seconds",
+                self.max_share_ttl_secs
+            )));
+        }
+        let (next_connection_id, traffic, http_gate, plugins, sockets) = {
+            let tunnels = self.tunnels.read().await;
+            match tunnels.get(&tunnel_id) {
+                Some(tunnel) if tunnel.client_id == client_id && tunnel.info.port_count > 1 => {
+                    return Err(NatError::tunnel("Port range tunnels cannot be shared"))
+                }
+                Some(tunnel) if tunnel.client_id == client_id && tunnel.udp.is_some() => {
+                    return Err(NatError::tunnel("UDP tunnels cannot be shared"))
+                }
+                Some(tunnel) if tunnel.client_id == client_id => (
+                    tunnel.next_connection_id.clone(),
+                    tunnel.traffic.clone(),
+                    tunnel.http_gate.clone(),
+                    tunnel.plugins.clone(),
+                    tunnel.sockets.clone(),
+                ),
+                _ => return Err(NatError::tunnel("Tunnel not found")),
+            }
+        };
+
+        let share_id = Uuid::new_v4();
+        let port = {
+            let mut allocator = self.port_allocator.write().await;
+            allocator.check_room(1)?;
+            allocator
+                .reserve_random(allowed_ports, share_id)
+                .ok_or_else(|| NatError::tunnel("No available ports"))?
+        };
+        let listener = match TcpListener::bind(("0.0.0.0", port)).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                self.port_allocator.write().await.release_port(port);
+                return Err(NatError::tunnel(format!(
+                    "Failed to bind port {}: {}",
+                    port, e
+                )));
+            }
+        };
+
+        let created_at = Utc::now();
+        let info = ShareInfo {
+            id: share_id,
+            tunnel_id,
+            port,
+            created_at,
+            expires_at: created_at + chrono::Duration::seconds(ttl_secs as i64),
+            password_protected: password.is_some(),
+        };
+        let gate = ShareGate {
+            id: share_id,
+            password_hash: password.as_deref().map(crypto::hash_token),
+        };
+
+        let span = info_span!("share", %tunnel_id, port);
+        let accept_task = tokio::spawn(
+            Self::accept_connections(
+                listener,
+                0,
+                tunnel_id,
+                client_id.to_string(),
+                next_connection_id,
+                traffic,
+                self.connections.clone(),
+                self.connection_manager.clone(),
+                self.performance,
+                self.resume,
+                self.connection_buffer,
+                sockets,
+                Some(Arc::new(gate)),
+                http_gate,
+                plugins,
+                self.capacity.max_connections,
+            )
+            .instrument(span),
+        )
+        .abort_handle();
+        self.shares.write().await.insert(
+            share_id,
+            Share {
+                info: info.clone(),
+                owner: client_id.to_string(),
+                accept_task,
+            },
+        );
+
+        let manager = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(ttl_secs)).await;
+            manager.close_share(&share_id, "expired").await;
+        });
+
+        info!(
+            "Shared tunnel {} of client {} on port {} for {}s",
+            tunnel_id, client_id, port, ttl_secs
+        );
+        Ok(info)
+    }
+
+    /// Close a share and the connections made through it, telling its
+    /// client, if still connected, why. Returns whether it was open.
+    pub async fn close_share(&self, share_id: &Uuid, reason: &str) -> bool {
+        let Some(share) = self.shares.write().await.remove(share_id) else {
+            return false;
+        };
+        share.accept_task.abort();
+        self.port_allocator
+            .write()
+            .await
+            .release_port(share.info.port);
+        self.connections
+            .retain(|_, connection| connection.share != Some(*share_id));
+
+        info!(
+            "Closed share {} on port {}: {}",
+            share_id, share.info.port, reason
+        );
+        if let Some(client) = self.connection_manager.get_client(&share.owner).await {
+            let _ = client
+                .send_message(Message::ShareClosed {
+                    share_id: *share_id,
+                    reason: reason.to_string(),
+                })
+                .await;
+        }
+        true
+    }
+
+    /// ID of the client that owns a share
+    pub async fn share_owner(&self, share_id: &Uuid) -> Option<String> {
+        let shares = self.shares.read().await;
+        shares.get(share_id).map(|share| share.owner.clone())
+    }
+
+    /// Open shares with the ID of the client that owns each
+    pub async fn list_shares(&self) -> Vec<(String, ShareInfo)> {
+        let shares = self.shares.read().await;
+        shares
+            .values()
+            .map(|share| (share.owner.clone(), share.info.clone()))
+            .collect()
+    }
+
+    /// Shares of one client's tunnels
+    pub async fn list_client_shares(&self, client_id: &str) -> Vec<ShareInfo> {
+        let shares = self.shares.read().await;
+        shares
+            .values()
+            .filter(|share| share.owner == client_id)
+            .map(|share| share.info.clone())
+            .collect()
+    }
+
     async fn start_tunnel_listener(&self, tunnel_id: Uuid) -> NatResult<()> {
         let tunnels = self.tunnels.clone();
+        let connections = self.connections.clone();
         let connection_manager = self.connection_manager.clone();
+        let performance = self.performance;
+        let resume = self.resume;
+        let connection_buffer = self.connection_buffer;
+        let accept_workers = self.accept_workers;
+        let max_connections = self.capacity.max_connections;
 
-        tokio::spawn(async move {
-            let (listener, client_id, _protocol, port) = {
-                let mut tunnels_guard = tunnels.write().await;
-                let tunnel = tunnels_guard.get_mut(&tunnel_id).unwrap();
-
-                let bind_addr = format!("0.0.0.0:{}", tunnel.info.remote_port);
-                let listener = match TcpListener::bind(&bind_addr).await {
-                    Ok(l) => l,
-                    Err(e) => {
-                        error!("Failed to bind to {}: {}", bind_addr, e);
-                        return;
+        let span = info_span!("tunnel", %tunnel_id, port = tracing::field::Empty);
+        tokio::spawn(
+            async move {
+                let (
+                    listeners,
+                    client_id,
+                    next_connection_id,
+                    traffic,
+                    http_gate,
+                    plugins,
+                    sockets,
+                    udp,
+                    ports,
+                ) = {
+                    let mut tunnels_guard = tunnels.write().await;
+                    let tunnel = tunnels_guard.get_mut(&tunnel_id).unwrap();
+
+                    // A port range is served only if every port binds
+                    let ports = tunnel.info.remote_ports();
+                    let mut listeners = Vec::new();
+                    for (port_offset, port) in (0u16..).zip(ports.start..=ports.end) {
+                        let bind_addr = SocketAddr::from(([0, 0, 0, 0], port));
+                        let bound = match tunnel.udp {
+                            Some(_) => socket::bind_datagrams(bind_addr, &tunnel.sockets)
+                                .map(|socket| vec![PortListener::Udp(socket)]),
+                            None => socket::bind_listeners(bind_addr, accept_workers)
+                                .map(|bound| bound.into_iter().map(PortListener::Tcp).collect()),
+                        };
+                        match bound {
+                            Ok(bound) => {
+                                listeners.extend(bound.into_iter().map(|l| (port_offset, l)))
+                            }
+                            Err(e) => {
+                                error!("Failed to bind to {}: {}", bind_addr, e);
+                                return;
+                            }
+                        }
                     }
+
+                    // Note: tokio TcpListener doesn't have try_clone, we'll store the bind address instead
+                    (
+                        listeners,
+                        tunnel.client_id.clone(),
+                        tunnel.next_connection_id.clone(),
+                        tunnel.traffic.clone(),
+                        tunnel.http_gate.clone(),
+                        tunnel.plugins.clone(),
+                        tunnel.sockets.clone(),
+                        tunnel.udp,
+                        ports,
+                    )
                 };
 
-                // Note: tokio TcpListener doesn't have try_clone, we'll store the bind address instead
-                (
-                    listener,
-                    tunnel.client_id.clone(),
-                    tunnel.info.protocol,
-                    tunnel.info.remote_port,
-                )
-            };
+                tracing::Span::current().record("port", tracing::field::display(ports));
+                info!("Tunnel {} listening on port {}", tunnel_id, ports);
 
-            info!("Tunnel {} listening on port {}", tunnel_id, port);
+                let mut accept_tasks = Vec::new();
+                for (port_offset, listener) in listeners {
+                    let task = match listener {
+                        PortListener::Tcp(listener) => tokio::spawn(
+                            Self::accept_connections(
+                                listener,
+                                port_offset,
+                                tunnel_id,
+                                client_id.clone(),
+                                next_connection_id.clone(),
+                                traffic.clone(),
+                                connections.clone(),
+                                connection_manager.clone(),
+                                performance,
+                                resume,
+                                connection_buffer,
+                                sockets.clone(),
+                                None,
+                                http_gate.clone(),
+                                plugins.clone(),
+                                max_connections,
+                            )
+                            .in_current_span(),
+                        ),
+                        PortListener::Udp(socket) => tokio::spawn(
+                            Self::accept_datagrams(
+                                socket,
+                                port_offset,
+                                tunnel_id,
+                                client_id.clone(),
+                                next_connection_id.clone(),
+                                traffic.clone(),
+                                connections.clone(),
+                                connection_manager.clone(),
+                                performance,
+                                resume,
+                                connection_buffer,
+                                plugins.clone(),
+                                max_connections,
+                                udp.unwrap_or_default(),
+                            )
+                            .in_current_span(),
+                        ),
+                    };
+                    accept_tasks.push(task.abort_handle());
+                }
+                // The tunnel may have closed while its ports were bound
+                match tunnels.write().await.get_mut(&tunnel_id) {
+                    Some(tunnel) => tunnel.accept_tasks = accept_tasks,
+                    None => accept_tasks.iter().for_each(AbortHandle::abort),
+                }
+            }
+            .instrument(span),
+        );
 
-            // Accept connections
-            while let Ok((stream, addr)) = listener.accept().await {
-                let tunnels = tunnels.clone();
-                let connection_manager = connection_manager.clone();
-                let client_id = client_id.clone();
+        Ok(())
+    }
 
-                tokio::spawn(async move {
-                    if let Err(e) = Self::handle_tunnel_connection(
-                        tunnel_id,
+    /// Accept public connections on one of a tunnel's ports, or on a share
+    /// of it when `gate` is set
+    #[allow(clippy::too_many_arguments)]
+    async fn accept_connections(
+        listener: TcpListener,
+        port_offset: u16,
+        tunnel_id: Uuid,
+        client_id: String,
+        next_connection_id: Arc<AtomicU32>,
+        traffic: Arc<TunnelTraffic>,
+        connections: ConnectionMap,
+        connection_manager: Arc<ConnectionManager>,
+        performance: PerformanceConfig,
+        resume: ResumeLimits,
+        connection_buffer: usize,
+        sockets: SocketOptions,
+        gate: Option<Arc<ShareGate>>,
+        http_gate: Option<Arc<HttpGate>>,
+        plugins: Option<Arc<TunnelPlugins>>,
+        max_connections: Option<u32>,
+    ) {
+        let port = listener.local_addr().map_or(0, |addr| addr.port());
+        while let Ok((mut stream, addr)) = listener.accept().await {
+            if max_connections.is_some_and(|max| connections.len() >= max as usize) {
+                debug!("Server at its connection limit, refusing {}", addr);
+                continue;
+            }
+            socket::configure(&stream, &sockets);
+            let connection_id = next_connection_id.fetch_add(1, Ordering::Relaxed);
+            let connections = connections.clone();
+            let connection_manager = connection_manager.clone();
+            let client_id = client_id.clone();
+            let traffic = traffic.clone();
+            let gate = gate.clone();
+            let http_gate = http_gate.clone();
+            let visit = plugins
+                .as_ref()
+                .map(|plugins| plugins.visit(connection_id, addr, port));
+
+            let span = info_span!("session", connection_id, peer = %addr);
+            tokio::spawn(
+                async move {
+                    if let Some(hash) = gate.as_ref().and_then(|g| g.password_hash.as_ref()) {
+                        if !check_password(&mut stream, hash).await {
+                            debug!("Wrong share password from {}", addr);
+                            return;
+                        }
+                    }
+                    let (prefix, rest) = match &http_gate {
+                        Some(http_gate) => match http_gate.admit(&mut stream, None).await {
+                            Some(head) => vhost::split_head(head),
+                            None => return debug!("Refused unauthorized request from {}", addr),
+                        },
+                        None => (Bytes::new(), Bytes::new()),
+                    };
+                    // Later requests on the connection need the credentials too
+                    let filter = http_gate.map(|gate| RequestFilter {
+                        gate: Some(gate),
+                        rewrite: None,
+                        peer: addr,
+                        scheme: "http".to_string(),
+                    });
+                    let stream = Requests::new(stream, filter, &prefix, rest);
+                    Self::serve_visitor(
                         stream,
                         addr,
-                        tunnels,
-                        connection_manager,
+                        tunnel_id,
+                        connection_id,
+                        port_offset,
                         client_id,
+                        connection_manager,
+                        connections,
+                        traffic,
+                        performance,
+                        resume,
+                        connection_buffer,
+                        gate.map(|gate| gate.id),
+                        prefix,
+                        visit,
                     )
-                    .await
-                    {
-                        error!("Error handling tunnel connection: {}", e);
+                    .await;
+                }
+                .instrument(span),
+            );
+        }
+    }
+
+    /// Receive on a UDP port of a tunnel, giving each visitor address a
+    /// session of its own that reaches the client as a connection. A
+    /// session ends once it has been idle for the tunnel's timeout, and
+    /// visitors beyond its session cap are ignored until one does.
+    /// Datagrams over the tunnel's size limit are dropped either way.
+    #[allow(clippy::too_many_arguments)]
+    async fn accept_datagrams(
+        socket: UdpSocket,
+        port_offset: u16,
+        tunnel_id: Uuid,
+        client_id: String,
+        next_connection_id: Arc<AtomicU32>,
+        traffic: Arc<TunnelTraffic>,
+        connections: ConnectionMap,
+        connection_manager: Arc<ConnectionManager>,
+        performance: PerformanceConfig,
+        resume: ResumeLimits,
+        connection_buffer: usize,
+        plugins: Option<Arc<TunnelPlugins>>,
+        max_connections: Option<u32>,
+        udp: UdpSessions,
+    ) {
+        let socket = Arc::new(socket);
+        let port = socket.local_addr().map_or(0, |addr| addr.port());
+        let idle_timeout = Duration::from_secs(udp.idle_timeout_secs);
+        let mut sessions: HashMap<SocketAddr, mpsc::Sender<Bytes>> = HashMap::new();
+        let mut buffer = vec![0; datagram::MAX_DATAGRAM];
+        loop {
+            let (len, addr) = match socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                // Some platforms report an earlier reply being refused here
+                Err(e) => {
+                    debug!("Failed to receive on port {}: {}", port, e);
+                    continue;
+                }
+            };
+            if len > usize::from(udp.max_datagram_size) {
+                debug!(
+                    "Dropping {} byte datagram from {}, over the tunnel's {} byte limit",
+                    len, addr, udp.max_datagram_size
+                );
+                continue;
+            }
+            let mut datagram = Bytes::copy_from_slice(&buffer[..len]);
+            if let Some(session) = sessions.get(&addr) {
+                match session.try_send(datagram) {
+                    Ok(()) | Err(TrySendError::Full(_)) => continue,
+                    // The session went idle; the visitor starts another
+                    Err(TrySendError::Closed(unsent)) => {
+                        sessions.remove(&addr);
+                        datagram = unsent;
                     }
-                });
+                }
             }
-        });
+            if sessions.len() >= udp.max_sessions as usize {
+                sessions.retain(|_, session| !session.is_closed());
+                if sessions.len() >= udp.max_sessions as usize {
+                    debug!(
+                        "Tunnel has all {} of its UDP sessions, dropping datagram from {}",
+                        udp.max_sessions, addr
+                    );
+                    continue;
+                }
+            }
+            if max_connections.is_some_and(|max| connections.len() >= max as usize) {
+                debug!("Server at its connection limit, refusing {}", addr);
+                continue;
+            }
+            let (tx, rx) = mpsc::channel(UDP_SESSION_QUEUE);
+            let _ = tx.try_send(datagram);
+            sessions.insert(addr, tx);
+            let stream = DatagramStream::demuxed(socket.clone(), addr, rx, Some(idle_timeout))
+                .with_max_datagram(usize::from(udp.max_datagram_size));
+            let connection_id = next_connection_id.fetch_add(1, Ordering::Relaxed);
+            let visit = plugins
+                .as_ref()
+                .map(|plugins| plugins.visit(connection_id, addr, port));
 
-        Ok(())
+            let span = info_span!("session", connection_id, peer = %addr);
+            tokio::spawn(
+                Self::serve_visitor(
+                    stream,
+                    addr,
+                    tunnel_id,
+                    connection_id,
+                    port_offset,
+                    client_id.clone(),
+                    connection_manager.clone(),
+                    connections.clone(),
+                    traffic.clone(),
+                    performance,
+                    resume,
+                    connection_buffer,
+                    None,
+                    Bytes::new(),
+                    visit,
+                )
+                .instrument(span),
+            );
+        }
     }
 
-    async fn handle_tunnel_connection(
+    /// Carry a visitor's connection to the tunnel's client, unless the
+    /// client is gone or has used up its quota
+    #[allow(clippy::too_many_arguments)]
+    async fn serve_visitor<S>(
+        stream: S,
+        addr: SocketAddr,
         tunnel_id: Uuid,
-        mut stream: TcpStream,
-        client_addr: SocketAddr,
-        tunnels: Arc<RwLock<HashMap<Uuid, TunnelHandler>>>,
-        connection_manager: Arc<ConnectionManager>,
+        connection_id: u32,
+        port_offset: u16,
         client_id: String,
-    ) -> NatResult<()> {
-        // Get next connection ID
-        let connection_id = {
-            let tunnels_guard = tunnels.read().await;
-            let tunnel = tunnels_guard.get(&tunnel_id).unwrap();
-            let mut next_id = tunnel.next_connection_id.write().await;
-            let id = *next_id;
-            *next_id += 1;
-            id
+        connection_manager: Arc<ConnectionManager>,
+        connections: ConnectionMap,
+        traffic: Arc<TunnelTraffic>,
+        performance: PerformanceConfig,
+        resume: ResumeLimits,
+        connection_buffer: usize,
+        share: Option<Uuid>,
+        prefix: Bytes,
+        visit: Option<Visit>,
+    ) where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let active = ActiveConnection::new(traffic);
+
+        // Resolve the client once; the connection keeps its channel for
+        // its whole lifetime
+        let client = match connection_manager.get_client(&client_id).await {
+            Some(client) => client,
+            None => {
+                debug!(
+                    "Client {} gone, dropping connection from {}",
+                    client_id, addr
+                );
+                return;
+            }
         };
+        if client.quota_exhausted() {
+            return debug!(
+                "Client {} used up its monthly quota, refusing {}",
+                client_id, addr
+            );
+        }
+        let client_tx = client.tunnel_sender(&tunnel_id, connection_id).await;
 
+        if let Err(e) = Self::handle_tunnel_connection(
+            tunnel_id,
+            connection_id,
+            port_offset,
+            stream,
+            addr,
+            connections,
+            client_tx,
+            client,
+            active,
+            performance,
+            resume,
+            connection_buffer,
+            share,
+            prefix,
+            visit,
+        )
+        .await
+        {
+            error!("Error handling tunnel connection: {}", e);
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_tunnel_connection<S>(
+        tunnel_id: Uuid,
+        connection_id: u32,
+        port_offset: u16,
+        stream: S,
+        client_addr: SocketAddr,
+        connections: ConnectionMap,
+        client_tx: mpsc::UnboundedSender<Message>,
+        client: Arc<ClientConnection>,
+        active: ActiveConnection,
+        performance: PerformanceConfig,
+        resume: ResumeLimits,
+        connection_buffer: usize,
+        share: Option<Uuid>,
+        prefix: Bytes,
+        visit: Option<Visit>,
+    ) -> NatResult<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
         debug!(
             "New connection {} to tunnel {} from {}",
             connection_id, tunnel_id, client_addr
         );
+        if let Some(visit) = &visit {
+            if let Err(reason) = visit.admit().await {
+                info!("Connection from {} {}", client_addr, reason);
+                return Ok(());
+            }
+        }
+        let visit = visit.filter(Visit::sees_data).map(Arc::new);
 
-        // Notify client about new connection
-        if let Some(client) = connection_manager.get_client(&client_id).await {
-            let message = Message::NewConnection {
-                tunnel_id,
-                connection_id,
+        // Store connection before notifying the client so its first data
+        // frame always finds a destination
+        let key = (tunnel_id, connection_id);
+        let (tx, mut rx) = queue::queue(performance.connection_queue, connection_buffer);
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let refused_tx = events_tx.clone();
+        let received = Arc::new(AtomicU64::new(0));
+        let traffic = active.0.clone();
+        connections.insert(
+            key,
+            TunnelConnection {
+                id: connection_id,
                 client_addr,
-            };
+                sender: tx,
+                opened_at: Utc::now(),
+                share,
+                received: received.clone(),
+                events: events_tx,
+                _active: active,
+            },
+        );
+        telemetry::sessions_changed(1);
 
-            if let Err(e) = client.send_message(message).await {
-                error!("Failed to notify client about new connection: {}", e);
-                return Err(e);
+        // Notify client about new connection
+        let message = Message::NewConnection {
+            tunnel_id,
+            connection_id,
+            client_addr,
+            port_offset,
+        };
+        let mut client_tx = client_tx;
+        if let Err(unsent) = client_tx.send(message) {
+            // The data channel picked may have gone since
+            client_tx = client.tunnel_sender(&tunnel_id, connection_id).await;
+            if client_tx.send(unsent.0).is_err() {
+                connections.remove(&key);
+                telemetry::sessions_changed(-1);
+                return Err(NatError::connection(
+                    "Failed to notify client about new connection",
+                ));
             }
         }
-
-        // Handle data forwarding
-        let (tx, mut rx) = mpsc::unbounded_channel();
-
-        // Store connection
-        {
-            let tunnels_guard = tunnels.read().await;
-            let tunnel = tunnels_guard.get(&tunnel_id).unwrap();
-            let mut connections = tunnel.connections.write().await;
-            connections.insert(
+        let prefix_len = prefix.len() as u64;
+        traffic
+            .bytes_received
+            .fetch_add(prefix_len, Ordering::Relaxed);
+        client.update_bytes_sent(prefix_len);
+        let mut outbox = Outbox::new(client_tx, resume);
+        if !prefix.is_empty() {
+            outbox.send(Message::Data {
+                tunnel_id,
+                data: prefix,
                 connection_id,
-                TunnelConnection {
-                    id: connection_id,
-                    client_addr,
-                    sender: tx,
-                },
-            );
+            });
         }
 
         // Split stream for reading and writing
         let (mut reader, mut writer) = tokio::io::split(stream);
 
-        // Read from TCP connection and forward to client
-        let tunnels_read = tunnels.clone();
-        let connection_manager_read = connection_manager.clone();
-        let client_id_read = client_id.clone();
-
-        tokio::spawn(async move {
-            let mut buffer = [0u8; 8192];
-            loop {
-                match reader.read(&mut buffer).await {
-                    Ok(0) => break, // Connection closed
-                    Ok(n) => {
-                        let data = buffer[..n].to_vec();
-
-                        // Send data to client
-                        if let Some(client) =
-                            connection_manager_read.get_client(&client_id_read).await
-                        {
-                            let message = Message::Data {
-                                tunnel_id,
-                                data,
-                                connection_id,
-                            };
-
-                            if let Err(e) = client.send_message(message).await {
-                                error!("Failed to forward data to client: {}", e);
+        // Write data from client to TCP connection. An empty chunk is the
+        // client's half-close: the visitor reads end of stream while it
+        // may still send.
+        let write_client = client.clone();
+        let write_traffic = traffic.clone();
+        let write_visit = visit.clone();
+        let mut write_task = tokio::spawn(
+            async move {
+                let mut forwarded = 0u64;
+                while let Some(data) = rx.recv().await {
+                    if data.is_empty() {
+                        break;
+                    }
+                    let data = match &write_visit {
+                        Some(visit) => match visit.filter(Direction::Outbound, data).await {
+                            Filtered::Close => {
+                                debug!("A plugin closed the connection");
+                                let _ = refused_tx.send(SessionEvent::Refused);
                                 break;
                             }
-                        }
+                            Filtered::Pass(data) if data.is_empty() => continue,
+                            Filtered::Pass(data) => data,
+                        },
+                        None => data,
+                    };
+                    if let Some(throttle) = write_client.current_throttle() {
+                        throttle.consume(data.len()).await;
                     }
-                    Err(e) => {
-                        error!("Error reading from connection: {}", e);
+                    if let Err(e) = writer.write_all(&data).await {
+                        error!("Error writing to connection: {}", e);
                         break;
                     }
+                    forwarded += data.len() as u64;
+                    if let Some(capture) = write_traffic.capture() {
+                        capture.record(
+                            Direction::Outbound,
+                            connection_id,
+                            client_addr,
+                            port_offset,
+                            &data,
+                        );
+                    }
+                    write_traffic
+                        .bytes_sent
+                        .fetch_add(data.len() as u64, Ordering::Relaxed);
+                    write_client.update_bytes_received(data.len() as u64);
                 }
+                let _ = writer.shutdown().await;
+                telemetry::bytes_forwarded(Direction::Outbound, forwarded);
             }
+            .in_current_span(),
+        );
 
-            // Clean up connection
-            let tunnels_guard = tunnels_read.read().await;
-            if let Some(tunnel) = tunnels_guard.get(&tunnel_id) {
-                let mut connections = tunnel.connections.write().await;
-                connections.remove(&connection_id);
-            }
-        });
+        // Read from TCP connection and forward to client, holding the data
+        // while the client's session is away
+        tokio::spawn(
+            async move {
+                let mut client = client;
+                let mut buffer = BytesMut::with_capacity(performance.read_buffer_size);
+                let mut forwarded = prefix_len;
+                let mut reading = true;
+                let finished = loop {
+                    let expiry = outbox.expiry();
+                    tokio::select! {
+                        read = reader.read_buf(&mut buffer), if reading => match read {
+                            Ok(0) if connections.contains_key(&key) => {
+                                // The visitor is done sending; let the
+                                // service finish its answer before the
+                                // connection goes away
+                                reading = false;
+                                let shutdown = Message::ConnectionShutdown {
+                                    tunnel_id,
+                                    connection_id,
+                                    direction: ShutdownDirection::Inbound,
+                                };
+                                reroute(&mut outbox, &client, tunnel_id, connection_id).await;
+                                if !outbox.send(shutdown) {
+                                    break false;
+                                }
+                            }
+                            Ok(0) => break false,
+                            Ok(n) => {
+                                if let Some(throttle) = client.current_throttle() {
+                                    throttle.consume(n).await;
+                                }
+                                forwarded += n as u64;
+                                if let Some(capture) = traffic.capture() {
+                                    capture.record(
+                                        Direction::Inbound,
+                                        connection_id,
+                                        client_addr,
+                                        port_offset,
+                                        &buffer,
+                                    );
+                                }
+                                traffic
+                                    .bytes_received
+                                    .fetch_add(n as u64, Ordering::Relaxed);
+                                client.update_bytes_sent(n as u64);
+                                let data = buffer.split().freeze();
+                                let data = match &visit {
+                                    Some(visit) => {
+                                        match visit.filter(Direction::Inbound, data).await {
+                                            Filtered::Close => {
+                                                debug!("A plugin closed the connection");
+                                                break false;
+                                            }
+                                            Filtered::Pass(data) if data.is_empty() => continue,
+                                            Filtered::Pass(data) => data,
+                                        }
+                                    }
+                                    None => data,
+                                };
+                                let message = Message::Data {
+                                    tunnel_id,
+                                    data,
+                                    connection_id,
+                                };
+                                reroute(&mut outbox, &client, tunnel_id, connection_id).await;
+                                if !outbox.send(message) {
+                                    debug!("Client session gone, closing the connection");
+                                    break false;
+                                }
+                            }
+                            Err(e) => {
+                                error!("Error reading from connection: {}", e);
+                                break false;
+                            }
+                        },
+                        _ = &mut write_task, if !reading => break true,
+                        Some(event) = events.recv() => match event {
+                            SessionEvent::Overflowed => {
+                                // Nothing queued for the visitor is kept
+                                write_task.abort();
+                                outbox.notify(Message::ConnectionClosed {
+                                    tunnel_id,
+                                    connection_id,
+                                });
+                                break false;
+                            }
+                            SessionEvent::Refused => break false,
+                            SessionEvent::Lost(session) => {
+                                if Arc::ptr_eq(&session, &client) && outbox.hold() {
+                                    debug!("Holding the connection for the client to come back");
+                                }
+                            }
+                            SessionEvent::Resumed {
+                                client: resumed,
+                                received: peer_received,
+                            } => {
+                                let tx = resumed.tunnel_sender(&tunnel_id, connection_id).await;
+                                let _ = tx.send(Message::ResumeConnection {
+                                    tunnel_id,
+                                    connection_id,
+                                    received: received.load(Ordering::Relaxed),
+                                });
+                                client = resumed;
+                                if !outbox.resume(tx, peer_received) {
+                                    debug!("The data lost with the client's session is gone, closing");
+                                    break false;
+                                }
+                                // The end of stream may have been lost too
+                                if !reading {
+                                    outbox.send(Message::ConnectionShutdown {
+                                        tunnel_id,
+                                        connection_id,
+                                        direction: ShutdownDirection::Inbound,
+                                    });
+                                }
+                                debug!("Connection resumed");
+                            }
+                        },
+                        _ = expiry => {
+                            debug!("Client did not come back in time, closing");
+                            break false;
+                        }
+                    }
+                };
+                telemetry::bytes_forwarded(Direction::Inbound, forwarded);
 
-        // Write data from client to TCP connection
-        tokio::spawn(async move {
-            while let Some(data) = rx.recv().await {
-                if let Err(e) = writer.write_all(&data).await {
-                    error!("Error writing to connection: {}", e);
-                    break;
+                if finished {
+                    connections.remove(&key);
+                } else if connections.remove(&key).is_some() {
+                    // Clean up connection, telling the client unless it closed it first
+                    reroute(&mut outbox, &client, tunnel_id, connection_id).await;
+                    outbox.notify(Message::ConnectionClosed {
+                        tunnel_id,
+                        connection_id,
+                    });
                 }
+                telemetry::sessions_changed(-1);
             }
-        });
+            .in_current_span(),
+        );
 
         Ok(())
     }
 
+    /// Have a dropped client's public connections hold their data for it
+    /// to reconnect and resume them
+    pub async fn hold_connections(&self, client: &Arc<ClientConnection>) {
+        if !self.resume.enabled() {
+            return;
+        }
+        let tunnel_ids: Vec<Uuid> = self
+            .tunnels
+            .read()
+            .await
+            .values()
+            .filter(|tunnel| tunnel.client_id == client.id)
+            .map(|tunnel| tunnel.info.id)
+            .collect();
+        for connection in self.connections.iter() {
+            if tunnel_ids.contains(&connection.key().0) {
+                let _ = connection.events.send(SessionEvent::Lost(client.clone()));
+            }
+        }
+    }
+
+    /// Continue a public connection on `client`'s new session, which
+    /// received `received` bytes of it before. False when the connection
+    /// is gone or not the client's.
+    pub async fn resume_connection(
+        &self,
+        client: &Arc<ClientConnection>,
+        tunnel_id: Uuid,
+        connection_id: u32,
+        received: u64,
+    ) -> bool {
+        if self.tunnel_owner(&tunnel_id).await.as_deref() != Some(client.id.as_str()) {
+            return false;
+        }
+        self.connections
+            .get(&(tunnel_id, connection_id))
+            .is_some_and(|connection| {
+                connection
+                    .events
+                    .send(SessionEvent::Resumed {
+                        client: client.clone(),
+                        received,
+                    })
+                    .is_ok()
+            })
+    }
+
+    /// Close a public connection after the client's local side went away
+    pub async fn close_connection(&self, tunnel_id: &Uuid, connection_id: u32) {
+        if self
+            .connections
+            .remove(&(*tunnel_id, connection_id))
+            .is_some()
+        {
+            debug!(
+                "Connection {} on tunnel {} closed by client",
+                connection_id, tunnel_id
+            );
+        }
+    }
+
+    /// End the visitor's side of a public connection after the client's
+    /// local service stopped sending; the visitor may still send
+    pub async fn shutdown_connection(&self, tunnel_id: &Uuid, connection_id: u32) {
+        let sender = self
+            .connections
+            .get(&(*tunnel_id, connection_id))
+            .map(|connection| connection.sender.clone());
+        if let Some(sender) = sender {
+            debug!(
+                "Connection {} on tunnel {} half-closed by client",
+                connection_id, tunnel_id
+            );
+            let _ = sender.send(Bytes::new()).await;
+        }
+    }
+
     pub async fn forward_data(
         &self,
         tunnel_id: &Uuid,
         connection_id: u32,
-        data: Vec<u8>,
+        data: Bytes,
     ) -> NatResult<()> {
-        let tunnels = self.tunnels.read().await;
-        if let Some(tunnel) = tunnels.get(tunnel_id) {
-            let connections = tunnel.connections.read().await;
-            if let Some(connection) = connections.get(&connection_id) {
-                connection
-                    .sender
-                    .send(data)
-                    .map_err(|_| NatError::connection("Failed to forward data"))?;
-                return Ok(());
-            }
+        // An empty chunk would half-close the connection
+        if data.is_empty() {
+            return Ok(());
         }
+        // Release the shard before a bounded queue can make us wait
+        let (sender, received, events) = self
+            .connections
+            .get(&(*tunnel_id, connection_id))
+            .map(|connection| {
+                (
+                    connection.sender.clone(),
+                    connection.received.clone(),
+                    connection.events.clone(),
+                )
+            })
+            .ok_or_else(|| NatError::tunnel("Connection not found"))?;
 
-        Err(NatError::tunnel("Connection not found"))
+        let len = data.len() as u64;
+        match sender.send(data).await {
+            Ok(()) => {
+                received.fetch_add(len, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(SendError::Full(_)) => {
+                warn!(
+                    "Connection {} on tunnel {} has {} bytes waiting for the visitor, dropping it",
+                    connection_id,
+                    tunnel_id,
+                    sender.buffered()
+                );
+                // Frames still on their way find no connection
+                self.connections.remove(&(*tunnel_id, connection_id));
+                let _ = events.send(SessionEvent::Overflowed);
+                Ok(())
+            }
+            Err(SendError::Closed(_)) => Err(NatError::connection("Failed to forward data")),
+        }
     }
 
     pub async fn get_tunnel(&self, tunnel_id: &Uuid) -> Option<TunnelInfo> {
         let tunnels = self.tunnels.read().await;
-        tunnels.get(tunnel_id).map(|t| t.info.clone())
+        tunnels.get(tunnel_id).map(TunnelHandler::current_info)
     }
 
     pub async fn list_tunnels(&self) -> Vec<TunnelInfo> {
         let tunnels = self.tunnels.read().await;
-        tunnels.values().map(|t| t.info.clone()).collect()
+        tunnels.values().map(TunnelHandler::current_info).collect()
+    }
+
+    /// Write the traffic of every open tunnel to the history database
+    pub async fn checkpoint(&self) {
+        let Some(storage) = self.connection_manager.storage() else {
+            return;
+        };
+        let tunnels = self.tunnels.read().await;
+        for tunnel in tunnels.values() {
+            storage.tunnel_progress(
+                &tunnel.current_info(),
+                tunnel.traffic.connections.load(Ordering::Relaxed),
+            );
+        }
+    }
+
+    /// Every tunnel with its owner and the connections it carried so far
+    pub async fn list_tunnel_counters(&self) -> Vec<(String, TunnelInfo, u64)> {
+        let tunnels = self.tunnels.read().await;
+        tunnels
+            .values()
+            .map(|t| {
+                (
+                    t.client_id.clone(),
+                    t.current_info(),
+                    t.traffic.connections.load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+
+    /// Every tunnel with the ID of the client that owns it
+    pub async fn list_tunnels_with_owner(&self) -> Vec<(String, TunnelInfo)> {
+        let tunnels = self.tunnels.read().await;
+        tunnels
+            .values()
+            .map(|t| (t.client_id.clone(), t.current_info()))
+            .collect()
+    }
+
+    /// Whether the client behind a tunnel is connected, so public
+    /// connections can reach it
+    pub async fn tunnel_online(&self, tunnel_id: &Uuid) -> bool {
+        match self.tunnel_owner(tunnel_id).await {
+            Some(owner) => self.connection_manager.get_client(&owner).await.is_some(),
+            None => false,
+        }
+    }
+
+    /// ID of the client that owns a tunnel
+    pub async fn tunnel_owner(&self, tunnel_id: &Uuid) -> Option<String> {
+        let tunnels = self.tunnels.read().await;
+        tunnels.get(tunnel_id).map(|t| t.client_id.clone())
+    }
+
+    /// Open public connections, optionally only those of one tunnel
+    pub fn list_connections(&self, tunnel_id: Option<&Uuid>) -> Vec<PublicConnection> {
+        let mut connections: Vec<_> = self
+            .connections
+            .iter()
+            .filter(|entry| tunnel_id.is_none_or(|id| &entry.key().0 == id))
+            .map(|entry| PublicConnection {
+                tunnel_id: entry.key().0,
+                connection_id: entry.id,
+                peer: entry.client_addr,
+                opened_at: entry.opened_at,
+            })
+            .collect();
+        connections.sort_by_key(|c| (c.opened_at, c.connection_id));
+        connections
+    }
+
+    /// Start writing a tunnel's payloads to `capture`, replacing a capture
+    /// already running on it
+    pub async fn start_capture(&self, tunnel_id: &Uuid, capture: Arc<Capture>) -> NatResult<()> {
+        let tunnels = self.tunnels.read().await;
+        let tunnel = tunnels
+            .get(tunnel_id)
+            .ok_or_else(|| NatError::tunnel("Tunnel not found"))?;
+        *tunnel.traffic.capture.write().unwrap() = Some(capture);
+        Ok(())
+    }
+
+    /// Stop capturing a tunnel, returning the capture if one was running
+    pub async fn stop_capture(&self, tunnel_id: &Uuid) -> Option<Arc<Capture>> {
+        let tunnels = self.tunnels.read().await;
+        let tunnel = tunnels.get(tunnel_id)?;
+        let capture = tunnel.traffic.capture.write().unwrap().take();
+        capture
+    }
+
+    /// Tunnels owned by one client, with current connection counts
+    pub async fn list_client_tunnels(&self, client_id: &str) -> Vec<TunnelInfo> {
+        let tunnels = self.tunnels.read().await;
+        tunnels
+            .values()
+            .filter(|t| t.client_id == client_id)
+            .map(TunnelHandler::current_info)
+            .collect()
+    }
+}
+
+/// Move a connection whose data channel went away, with the client's
+/// session still up, to another of its channels or its control connection.
+/// The client ends its session when it loses a data channel, and
+/// resumption then makes up for what was in flight on it.
+async fn reroute(
+    outbox: &mut Outbox,
+    client: &ClientConnection,
+    tunnel_id: Uuid,
+    connection_id: u32,
+) {
+    if !outbox.is_closed() {
+        return;
+    }
+    let tx = client.tunnel_sender(&tunnel_id, connection_id).await;
+    if !tx.is_closed() {
+        debug!("Data channel gone, carrying on over another");
+        outbox.reroute(tx);
+    }
+}
+
+/// Read the first line a visitor of a password protected share sends and
+/// check it against `hash`. Reads byte by byte so nothing past the line is
+/// consumed.
+async fn check_password(stream: &mut TcpStream, hash: &str) -> bool {
+    let read_line = async {
+        let mut line = Vec::new();
+        let mut byte = [0u8; 1];
+        while line.len() <= MAX_PASSWORD_LINE {
+            stream.read_exact(&mut byte).await?;
+            if byte[0] == b'\n' {
+                return Ok(Some(line));
+            }
+            line.push(byte[0]);
+        }
+        Ok::<_, std::io::Error>(None)
+    };
+    match tokio::time::timeout(Duration::from_secs(10), read_line).await {
+        Ok(Ok(Some(line))) => {
+            let password = String::from_utf8_lossy(&line);
+            crypto::verify_token(password.trim_end_matches('\r'), hash)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::DuplexStream;
+
+    const TUNNEL: Uuid = Uuid::from_u128(1);
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A client session and what the server sends on it
+    fn session() -> (Arc<ClientConnection>, mpsc::UnboundedReceiver<Message>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 40000));
+        (
+            Arc::new(ClientConnection::new("client".to_string(), addr, tx)),
+            rx,
+        )
+    }
+
+    async fn next(rx: &mut mpsc::UnboundedReceiver<Message>) -> Message {
+        tokio::time::timeout(TIMEOUT, rx.recv())
+            .await
+            .unwrap()
+            .expect("the connection stopped sending")
+    }
+
+    fn payload(message: Message) -> Vec<u8> {
+        match message {
+            Message::Data { data, .. } => data.to_vec(),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    /// A public connection whose visitor sent `chunks`, each passed to
+    /// the client on its own, before the client's session dropped
+    async fn lost_connection(chunks: &[&[u8]]) -> (ConnectionMap, DuplexStream) {
+        let connections = ConnectionMap::default();
+        let (client, mut rx) = session();
+        let (mut visitor, stream) = tokio::io::duplex(64 * 1024);
+        TunnelManager::handle_tunnel_connection(
+            TUNNEL,
+            1,
+            0,
+            stream,
+            SocketAddr::from(([127, 0, 0, 1], 50000)),
+            connections.clone(),
+            client.tunnel_sender(&TUNNEL, 1).await,
+            client.clone(),
+            ActiveConnection::new(Arc::default()),
+            PerformanceConfig::default(),
+            ResumeLimits::new(60, 1),
+            64 * 1024,
+            None,
+            Bytes::new(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(next(&mut rx).await, Message::NewConnection { .. }));
+        for chunk in chunks {
+            visitor.write_all(chunk).await.unwrap();
+            assert_eq!(payload(next(&mut rx).await), *chunk);
+        }
+
+        drop(rx);
+        connections
+            .get(&(TUNNEL, 1))
+            .unwrap()
+            .events
+            .send(SessionEvent::Lost(client))
+            .unwrap();
+        (connections, visitor)
+    }
+
+    /// Resume the connection on a new session that received `received`
+    /// bytes of it, after the visitor sent `held` in the meantime
+    async fn resume(
+        connections: &ConnectionMap,
+        visitor: &mut DuplexStream,
+        held: &[u8],
+        received: u64,
+    ) -> mpsc::UnboundedReceiver<Message> {
+        visitor.write_all(held).await.unwrap();
+        let (client, mut rx) = session();
+        connections
+            .get(&(TUNNEL, 1))
+            .unwrap()
+            .events
+            .send(SessionEvent::Resumed { client, received })
+            .unwrap();
+        match next(&mut rx).await {
+            Message::ResumeConnection {
+                tunnel_id: TUNNEL,
+                connection_id: 1,
+                received: 0,
+            } => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+        rx
+    }
+
+    #[tokio::test]
+    async fn test_resume_replays_unacknowledged_tail() {
+        // Offsets within what was sent replay from there on; the visitor's
+        // bytes held meanwhile follow
+        for (received, replayed) in [
+            (0, &b"helloworld"[..]),
+            (5, b"world"),
+            (7, b"rld"),
+            (10, b""),
+        ] {
+            let (connections, mut visitor) = lost_connection(&[b"hello", b"world"]).await;
+            let mut rx = resume(&connections, &mut visitor, b"!!", received).await;
+            let mut got = Vec::new();
+            loop {
+                let data = payload(next(&mut rx).await);
+                if data == b"!!" {
+                    break;
+                }
+                got.extend(data);
+            }
+            assert_eq!(got, replayed, "resumed at {}", received);
+
+            // The connection carries on over the new session
+            visitor.write_all(b"more").await.unwrap();
+            assert_eq!(payload(next(&mut rx).await), b"more");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resume_out_of_range_closes() {
+        // Past what was sent, and before the oldest bytes kept: 1 KiB of
+        // the 1800 sent
+        for (chunks, received) in [
+            (&[&b"hello"[..], b"world"][..], 11),
+            (&[&[1u8; 600][..], &[2; 600], &[3; 600]][..], 0),
+            (&[&[1u8; 600][..], &[2; 600], &[3; 600]][..], 599),
+        ] {
+            let (connections, mut visitor) = lost_connection(chunks).await;
+            let mut rx = resume(&connections, &mut visitor, b"", received).await;
+            assert!(
+                matches!(
+                    next(&mut rx).await,
+                    Message::ConnectionClosed {
+                        tunnel_id: TUNNEL,
+                        connection_id: 1
+                    }
+                ),
+                "resumed at {}",
+                received
+            );
+            assert!(!connections.contains_key(&(TUNNEL, 1)));
+
+            // The visitor is let go rather than left hanging
+            let mut rest = Vec::new();
+            tokio::time::timeout(TIMEOUT, visitor.read_to_end(&mut rest))
+                .await
+                .unwrap()
+                .unwrap();
+        }
     }
 }
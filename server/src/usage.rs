@@ -0,0 +1,223 @@
+//! Tunnel traffic per client over the calendar month (UTC), counted against
+//! the monthly quotas of their tokens. Counts live in memory and are saved
+//! to a JSON file periodically and on shutdown, so restarts keep them.
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// One client's traffic this month, shared with its connection
+#[derive(Default)]
+pub struct UsageAccount {
+    bytes: AtomicU64,
+}
+
+impl UsageAccount {
+    pub fn add(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+/// A client's traffic in one month, as listed by `nat-server inspect usage`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageSummary {
+    pub client_id: String,
+    pub month: String,
+    pub bytes: u64,
+    /// Quota of the client's token, known while it is connected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota_bytes: Option<u64>,
+}
+
+/// What the usage file holds
+#[derive(Default, Serialize, Deserialize)]
+struct UsageFile {
+    month: String,
+    clients: BTreeMap<String, u64>,
+    /// Final counts of the month before, kept for billing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    previous: Option<MonthTotals>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct MonthTotals {
+    month: String,
+    clients: BTreeMap<String, u64>,
+}
+
+struct Ledger {
+    month: String,
+    accounts: HashMap<String, Arc<UsageAccount>>,
+    previous: Option<MonthTotals>,
+}
+
+pub struct UsageLedger {
+    /// Where counts are saved; kept in memory only when unset
+    path: Option<PathBuf>,
+    ledger: Mutex<Ledger>,
+}
+
+impl UsageLedger {
+    /// Load the counts saved at `path`. Counts from an earlier month become
+    /// the previous month's totals.
+    pub fn open(path: Option<PathBuf>) -> anyhow::Result<Self> {
+        let month = current_month();
+        let saved = match &path {
+            Some(path) if path.exists() => {
+                serde_json::from_str::<UsageFile>(&std::fs::read_to_string(path)?)?
+            }
+            _ => UsageFile::default(),
+        };
+        let ledger = if saved.month == month {
+            Ledger {
+                month,
+                accounts: saved
+                    .clients
+                    .into_iter()
+                    .map(|(id, bytes)| {
+                        (
+                            id,
+                            Arc::new(UsageAccount {
+                                bytes: AtomicU64::new(bytes),
+                            }),
+                        )
+                    })
+                    .collect(),
+                previous: saved.previous,
+            }
+        } else {
+            Ledger {
+                month,
+                accounts: HashMap::new(),
+                previous: (!saved.month.is_empty()).then_some(MonthTotals {
+                    month: saved.month,
+                    clients: saved.clients,
+                }),
+            }
+        };
+        Ok(Self {
+            path,
+            ledger: Mutex::new(ledger),
+        })
+    }
+
+    /// The account `client_id`'s traffic is counted on
+    pub fn account(&self, client_id: &str) -> Arc<UsageAccount> {
+        let mut ledger = self.ledger.lock().unwrap();
+        ledger
+            .accounts
+            .entry(client_id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// The month being counted, as `YYYY-MM`
+    pub fn month(&self) -> String {
+        self.ledger.lock().unwrap().month.clone()
+    }
+
+    /// Counts of this month and the one before, this month first
+    pub fn summaries(&self) -> Vec<UsageSummary> {
+        let ledger = self.ledger.lock().unwrap();
+        let mut current: Vec<_> = ledger
+            .accounts
+            .iter()
+            .map(|(id, account)| UsageSummary {
+                client_id: id.clone(),
+                month: ledger.month.clone(),
+                bytes: account.bytes(),
+                quota_bytes: None,
+            })
+            .collect();
+        current.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        let previous = ledger.previous.iter().flat_map(|totals| {
+            totals.clients.iter().map(|(id, bytes)| UsageSummary {
+                client_id: id.clone(),
+                month: totals.month.clone(),
+                bytes: *bytes,
+                quota_bytes: None,
+            })
+        });
+        current.into_iter().chain(previous).collect()
+    }
+
+    /// Start a new month when the calendar has moved on. Accounts are reset
+    /// in place so connected clients keep counting on them.
+    fn roll_over(&self) {
+        let month = current_month();
+        let mut ledger = self.ledger.lock().unwrap();
+        if ledger.month == month {
+            return;
+        }
+        let clients = ledger
+            .accounts
+            .iter()
+            .map(|(id, account)| (id.clone(), account.bytes.swap(0, Ordering::Relaxed)))
+            .collect();
+        let finished = std::mem::replace(&mut ledger.month, month);
+        info!("Usage for {} closed, counting {}", finished, ledger.month);
+        ledger.previous = Some(MonthTotals {
+            month: finished,
+            clients,
+        });
+    }
+
+    /// Write the counts to the usage file
+    pub fn save(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let file = {
+            let ledger = self.ledger.lock().unwrap();
+            UsageFile {
+                month: ledger.month.clone(),
+                clients: ledger
+                    .accounts
+                    .iter()
+                    .map(|(id, account)| (id.clone(), account.bytes()))
+                    .collect(),
+                previous: ledger.previous.clone(),
+            }
+        };
+        write_atomically(path, &serde_json::to_vec_pretty(&file)?)
+    }
+
+    /// Save every `interval` and start new months as they come
+    pub async fn run(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            self.roll_over();
+            let ledger = self.clone();
+            let result = tokio::task::spawn_blocking(move || ledger.save()).await;
+            if let Err(e) = result.map_err(anyhow::Error::from).and_then(|saved| saved) {
+                warn!("Failed to save usage: {}", e);
+            }
+        }
+    }
+}
+
+fn current_month() -> String {
+    Utc::now().format("%Y-%m").to_string()
+}
+
+/// Replace `path` without leaving a half-written file behind
+fn write_atomically(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
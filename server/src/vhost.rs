@@ -0,0 +1,1629 @@
+//! The shared HTTP and HTTPS listeners. Each connection is routed by the
+//! `Host` header of its first request to the tunnel bound to that domain,
+//! and from then on carried like any other public connection of the tunnel.
+//! HTTPS is terminated here with the certificate for the requested name,
+//! unless the tunnel asked for TLS passthrough: its connections are routed
+//! by the SNI of their ClientHello and carried still encrypted.
+
+use crate::compression::{self, Compressing};
+use crate::tunnel::TunnelManager;
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use hickory_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
+use hickory_resolver::TokioAsyncResolver;
+use nat_traversal_common::{
+    config::{DomainCertificate, HttpConfig, SocketOptions},
+    crypto, domain,
+    protocol::{HttpAuth, HttpHeaders, TlsCertificate},
+    socket,
+};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info_span, warn, Instrument};
+use uuid::Uuid;
+
+/// Largest request head read before routing
+const MAX_HEAD: usize = 16 * 1024;
+pub(crate) const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest TLS record, with its header, read before routing by SNI
+const MAX_TLS_RECORD: usize = 5 + 16 * 1024 + 2048;
+
+/// Checks that a client may bind a domain
+pub struct DomainVerifier {
+    /// `None` when verification is turned off
+    resolver: Option<TokioAsyncResolver>,
+}
+
+impl DomainVerifier {
+    pub fn new(config: &HttpConfig) -> anyhow::Result<Self> {
+        let resolver = if !config.verify_domains {
+            None
+        } else if config.dns_servers.is_empty() {
+            Some(TokioAsyncResolver::tokio_from_system_conf()?)
+        } else {
+            let servers: Vec<_> = config
+                .dns_servers
+                .iter()
+                .map(|addr| NameServerConfig::new(*addr, Protocol::Udp))
+                .collect();
+            Some(TokioAsyncResolver::tokio(
+                ResolverConfig::from_parts(None, Vec::new(), servers),
+                ResolverOpts::default(),
+            ))
+        };
+        Ok(Self { resolver })
+    }
+
+    /// Normalize `domain` and check its TXT record names `client_id`
+    pub async fn verify(&self, client_id: &str, requested: &str) -> Result<String, String> {
+        let name = domain::normalize(requested)
+            .ok_or_else(|| format!("'{}' is not a valid domain", requested))?;
+        let Some(resolver) = &self.resolver else {
+            return Ok(name);
+        };
+
+        let record = domain::challenge_name(&name);
+        let expected = domain::challenge_value(client_id, &name);
+        let found = match resolver.txt_lookup(format!("{}.", record)).await {
+            Ok(lookup) => lookup.iter().any(|txt| {
+                let value: Vec<u8> = txt.iter().flat_map(|part| part.iter().copied()).collect();
+                value == expected.as_bytes()
+            }),
+            Err(e) => {
+                debug!("TXT lookup of {} failed: {}", record, e);
+                false
+            }
+        };
+        if found {
+            Ok(name)
+        } else {
+            Err(format!(
+                "Domain {} is not verified: add a TXT record {} with the value {}",
+                name, record, expected
+            ))
+        }
+    }
+}
+
+/// Certificates of the HTTPS listener: those from the configuration, and
+/// those clients uploaded with their tunnels, which take precedence
+pub struct CertStore {
+    configured: HashMap<String, Arc<CertifiedKey>>,
+    /// By domain, with the tunnel that uploaded the certificate
+    uploaded: RwLock<HashMap<String, (Uuid, Arc<CertifiedKey>)>>,
+}
+
+impl CertStore {
+    pub fn new(certificates: &[DomainCertificate]) -> Result<Self, String> {
+        let mut configured = HashMap::new();
+        for certificate in certificates {
+            let read = |path: &std::path::Path| {
+                std::fs::read_to_string(path)
+                    .map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+            };
+            let key = certified_key(&TlsCertificate {
+                cert_pem: read(&certificate.cert_path)?,
+                key_pem: read(&certificate.key_path)?,
+            })
+            .map_err(|e| format!("Certificate for {}: {}", certificate.domain, e))?;
+            configured.insert(certificate.domain.to_ascii_lowercase(), Arc::new(key));
+        }
+        Ok(Self {
+            configured,
+            uploaded: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// Present `key` for `domain` until `tunnel_id` closes
+    pub fn upload(&self, domain: &str, tunnel_id: Uuid, key: Arc<CertifiedKey>) {
+        self.uploaded
+            .write()
+            .unwrap()
+            .insert(domain.to_string(), (tunnel_id, key));
+    }
+
+    /// Forget the certificate `tunnel_id` uploaded for `domain`, if any
+    pub fn remove(&self, domain: &str, tunnel_id: Uuid) {
+        let mut uploaded = self.uploaded.write().unwrap();
+        if uploaded.get(domain).is_some_and(|(id, _)| *id == tunnel_id) {
+            uploaded.remove(domain);
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<Arc<CertifiedKey>> {
+        if let Some((_, key)) = self.uploaded.read().unwrap().get(name) {
+            return Some(key.clone());
+        }
+        if let Some(key) = self.configured.get(name) {
+            return Some(key.clone());
+        }
+        let (_, parent) = name.split_once('.')?;
+        self.configured.get(&format!("*.{}", parent)).cloned()
+    }
+
+    /// Acceptor for the HTTPS listener
+    pub fn acceptor(self: &Arc<Self>) -> TlsAcceptor {
+        let mut config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        TlsAcceptor::from(Arc::new(config))
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let name = client_hello.server_name()?.to_ascii_lowercase();
+        let key = self.find(&name);
+        if key.is_none() {
+            debug!("No certificate for {}", name);
+        }
+        key
+    }
+}
+
+/// Check and load a certificate chain and its key
+pub fn certified_key(certificate: &TlsCertificate) -> Result<CertifiedKey, String> {
+    let certs: Vec<_> = rustls_pemfile::certs(&mut certificate.cert_pem.as_bytes())
+        .map_err(|e| format!("Invalid certificate: {}", e))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err("No certificate found".to_string());
+    }
+    let key = rustls_pemfile::read_all(&mut certificate.key_pem.as_bytes())
+        .map_err(|e| format!("Invalid private key: {}", e))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| "No private key found".to_string())?;
+    let key = rustls::sign::any_supported_type(&key)
+        .map_err(|_| "Unsupported private key type".to_string())?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Built-in page for domains whose client is disconnected
+const DEFAULT_OFFLINE_PAGE: &str = "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<meta http-equiv=\"refresh\" content=\"{{retry_after}}\">
+<title>{{tunnel}} is offline</title>
+</head>
+<body>
+<h1>{{tunnel}} is offline</h1>
+<p>The service behind this address is not connected right now. This page
+reloads in {{retry_after}} seconds.</p>
+</body>
+</html>
+";
+
+/// What HTTP visitors get while the client behind a domain is disconnected
+pub struct OfflinePage {
+    template: String,
+    retry_after_secs: u64,
+}
+
+impl OfflinePage {
+    pub fn new(config: &HttpConfig) -> Result<Self, String> {
+        let template = match &config.offline_page {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?,
+            None => DEFAULT_OFFLINE_PAGE.to_string(),
+        };
+        Ok(Self {
+            template,
+            retry_after_secs: config.offline_retry_secs,
+        })
+    }
+
+    fn render(&self, tunnel: &str) -> String {
+        let tunnel = tunnel
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;");
+        self.template
+            .replace("{{tunnel}}", &tunnel)
+            .replace("{{retry_after}}", &self.retry_after_secs.to_string())
+    }
+
+    async fn send<S: AsyncWrite + Unpin>(&self, stream: &mut S, tunnel: &str) {
+        let headers = format!("Retry-After: {}\r\n", self.retry_after_secs);
+        let body = self.render(tunnel);
+        respond_with(
+            stream,
+            503,
+            "Service Unavailable",
+            &headers,
+            "text/html; charset=utf-8",
+            &body,
+        )
+        .await;
+    }
+}
+
+/// Credentials a tunnel requires on HTTP requests, with secrets hashed.
+///
+/// The first request of a connection is checked by `admit`, the rest as
+/// they arrive through `Requests`. Each is forwarded with `Connection:
+/// close`, so the local service ends the connection after answering.
+pub struct HttpGate {
+    username: Option<String>,
+    password_hash: Option<String>,
+    bearer_hash: Option<String>,
+}
+
+impl HttpGate {
+    pub fn new(auth: &HttpAuth) -> Result<Self, String> {
+        let basic = match (&auth.username, &auth.password) {
+            (Some(username), Some(password)) if !username.is_empty() && !password.is_empty() => {
+                Some((username.clone(), crypto::hash_token(password)))
+            }
+            (None, None) => None,
+            _ => return Err("HTTP auth needs both a user name and a password".to_string()),
+        };
+        let bearer_hash = match auth.bearer_token.as_deref() {
+            Some("") => return Err("HTTP auth bearer token is empty".to_string()),
+            token => token.map(crypto::hash_token),
+        };
+        if basic.is_none() && bearer_hash.is_none() {
+            return Err("HTTP auth needs a user name and password or a bearer token".to_string());
+        }
+        let (username, password_hash) = basic.unzip();
+        Ok(Self {
+            username,
+            password_hash,
+            bearer_hash,
+        })
+    }
+
+    /// Whether the request's `Authorization` header carries the credentials
+    fn allows(&self, request: &httparse::Request) -> bool {
+        let Some(value) = request
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case("authorization"))
+            .and_then(|header| std::str::from_utf8(header.value).ok())
+        else {
+            return false;
+        };
+        let (scheme, credentials) = value.trim().split_once(' ').unwrap_or((value, ""));
+        let credentials = credentials.trim();
+        if scheme.eq_ignore_ascii_case("basic") {
+            let (Some(username), Some(hash)) = (&self.username, &self.password_hash) else {
+                return false;
+            };
+            base64::engine::general_purpose::STANDARD
+                .decode(credentials)
+                .ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+                .and_then(|decoded| {
+                    decoded
+                        .split_once(':')
+                        .map(|(u, p)| u == username && crypto::verify_token(p, hash))
+                })
+                .unwrap_or(false)
+        } else if scheme.eq_ignore_ascii_case("bearer") {
+            self.bearer_hash
+                .as_deref()
+                .is_some_and(|hash| crypto::verify_token(credentials, hash))
+        } else {
+            false
+        }
+    }
+
+    /// Check the first request of `stream`, answering 401 when it fails.
+    /// Returns the request as it should be forwarded, followed by whatever
+    /// was read past it.
+    pub async fn admit<S>(&self, stream: &mut S, head: Option<Bytes>) -> Option<Bytes>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let head = match head {
+            Some(head) => head,
+            None => match tokio::time::timeout(HEAD_TIMEOUT, read_head(stream)).await {
+                Ok(Ok(Some(head))) => head,
+                Ok(Ok(None)) => {
+                    respond(stream, 400, "Bad Request", "").await;
+                    return None;
+                }
+                Ok(Err(_)) => return None,
+                Err(_) => {
+                    respond(stream, 408, "Request Timeout", "").await;
+                    return None;
+                }
+            },
+        };
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut request = httparse::Request::new(&mut headers);
+        let head_len = match request.parse(&head) {
+            Ok(httparse::Status::Complete(len)) => len,
+            _ => {
+                respond(stream, 400, "Bad Request", "").await;
+                return None;
+            }
+        };
+        if !self.allows(&request) {
+            let challenge = if self.username.is_some() {
+                "WWW-Authenticate: Basic realm=\"nat-traversal\", charset=\"UTF-8\"\r\n"
+            } else {
+                "WWW-Authenticate: Bearer\r\n"
+            };
+            respond(stream, 401, "Unauthorized", challenge).await;
+            return None;
+        }
+        Some(close_after(&request, &head, head_len))
+    }
+}
+
+/// Headers that keep a connection open, left out of requests forwarded
+/// with `Connection: close`
+const CONNECTION_HEADERS: [&str; 3] = ["connection", "keep-alive", "proxy-connection"];
+
+/// Headers a visitor may send to claim where a request came from
+const FORWARDED_HEADERS: [&str; 5] = [
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+    "x-real-ip",
+];
+
+/// Whether the request switches the connection to another protocol, such
+/// as WebSocket, after which no more requests follow
+fn is_upgrade(request: &httparse::Request) -> bool {
+    request
+        .headers
+        .iter()
+        .any(|header| header.name.eq_ignore_ascii_case("upgrade"))
+}
+
+/// `head` with the request asking the service to close the connection
+/// after answering. Upgrades are left alone.
+fn close_after(request: &httparse::Request, head: &Bytes, head_len: usize) -> Bytes {
+    if is_upgrade(request) {
+        return head.clone();
+    }
+    rebuild(
+        request,
+        head,
+        head_len,
+        |name| CONNECTION_HEADERS.contains(&name),
+        &[("Connection", "close")],
+    )
+}
+
+/// `head` without the headers whose lowercase name `drop` matches, and
+/// with `extra` added after the rest
+fn rebuild(
+    request: &httparse::Request,
+    head: &[u8],
+    head_len: usize,
+    drop: impl Fn(&str) -> bool,
+    extra: &[(&str, &str)],
+) -> Bytes {
+    let request_line_end = head
+        .windows(2)
+        .position(|window| window == b"\r\n")
+        .unwrap_or(0);
+    let mut rewritten = BytesMut::with_capacity(head.len() + 128);
+    rewritten.extend_from_slice(&head[..request_line_end + 2]);
+    for header in request.headers.iter() {
+        if drop(&header.name.to_ascii_lowercase()) {
+            continue;
+        }
+        rewritten.extend_from_slice(header.name.as_bytes());
+        rewritten.extend_from_slice(b": ");
+        rewritten.extend_from_slice(header.value);
+        rewritten.extend_from_slice(b"\r\n");
+    }
+    for (name, value) in extra {
+        rewritten.extend_from_slice(name.as_bytes());
+        rewritten.extend_from_slice(b": ");
+        rewritten.extend_from_slice(value.as_bytes());
+        rewritten.extend_from_slice(b"\r\n");
+    }
+    rewritten.extend_from_slice(b"\r\n");
+    rewritten.extend_from_slice(&head[head_len..]);
+    rewritten.freeze()
+}
+
+/// Headers a tunnel sets on the HTTP requests routed to it, checked when
+/// the tunnel is created.
+///
+/// Every request of a connection gets them: the first when the connection
+/// is routed, the rest through `Requests`. Each is also forwarded with
+/// `Connection: close`, so the service ends the connection after answering.
+pub struct HeaderRewrite {
+    forwarded: bool,
+    host: Option<String>,
+    set: Vec<(String, String)>,
+}
+
+impl HeaderRewrite {
+    pub fn new(headers: &HttpHeaders) -> Result<Self, String> {
+        if let Some(host) = &headers.host {
+            if host.is_empty() || host.bytes().any(|b| b.is_ascii_whitespace()) {
+                return Err(format!("'{}' is not a valid Host", host));
+            }
+        }
+        for (name, value) in &headers.set {
+            if !valid_header_name(name) {
+                return Err(format!("'{}' is not a valid header name", name));
+            }
+            if !valid_header_value(value) {
+                return Err(format!("The value of header {} is not valid", name));
+            }
+            let lowercase = name.to_ascii_lowercase();
+            if ["host", "content-length", "transfer-encoding", "upgrade"]
+                .contains(&lowercase.as_str())
+                || CONNECTION_HEADERS.contains(&lowercase.as_str())
+            {
+                return Err(format!("Header {} cannot be set", name));
+            }
+        }
+        Ok(Self {
+            forwarded: headers.forwarded,
+            host: headers.host.clone(),
+            set: headers
+                .set
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+        })
+    }
+
+    /// `head`, which `peer` sent over `scheme`, with the tunnel's headers
+    /// set. A head that does not parse is passed on as it is.
+    pub fn apply(&self, head: &Bytes, peer: SocketAddr, scheme: &str) -> Bytes {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut request = httparse::Request::new(&mut headers);
+        let head_len = match request.parse(head) {
+            Ok(httparse::Status::Complete(len)) => len,
+            _ => return head.clone(),
+        };
+        let original_host = request
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case("host"))
+            .and_then(|header| std::str::from_utf8(header.value).ok())
+            .unwrap_or_default()
+            .to_string();
+        let upgrade = is_upgrade(&request);
+
+        let peer_ip = peer.ip().to_string();
+        let mut extra = Vec::new();
+        if let Some(host) = &self.host {
+            extra.push(("Host", host.as_str()));
+        }
+        if self.forwarded {
+            extra.push(("X-Forwarded-For", peer_ip.as_str()));
+            extra.push(("X-Real-IP", peer_ip.as_str()));
+            extra.push(("X-Forwarded-Proto", scheme));
+            if !original_host.is_empty() {
+                extra.push(("X-Forwarded-Host", original_host.as_str()));
+            }
+        }
+        extra.extend(
+            self.set
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        );
+        if !upgrade {
+            extra.push(("Connection", "close"));
+        }
+
+        rebuild(
+            &request,
+            head,
+            head_len,
+            |name| {
+                (name == "host" && self.host.is_some())
+                    || (self.forwarded && FORWARDED_HEADERS.contains(&name))
+                    || (!upgrade && CONNECTION_HEADERS.contains(&name))
+                    || self
+                        .set
+                        .iter()
+                        .any(|(set, _)| set.eq_ignore_ascii_case(name))
+            },
+            &extra,
+        )
+    }
+}
+
+/// Whether `name` is an HTTP header name (an RFC 9110 token)
+fn valid_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Whether `value` can go on a header line as it is
+fn valid_header_value(value: &str) -> bool {
+    !value.bytes().any(|b| b.is_ascii_control() && b != b'\t')
+}
+
+/// Accept HTTPS connections and hand them to tunnels, passed through or
+/// with TLS terminated, until the listener fails
+pub async fn serve_tls(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    tunnel_manager: Arc<TunnelManager>,
+    sockets: SocketOptions,
+    offline_page: Arc<OfflinePage>,
+    compress: bool,
+) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("HTTPS listener stopped: {}", e);
+                return;
+            }
+        };
+        socket::configure(&stream, &sockets);
+        let acceptor = acceptor.clone();
+        let tunnel_manager = tunnel_manager.clone();
+        let offline_page = offline_page.clone();
+        tokio::spawn(
+            async move {
+                let mut stream = stream;
+                let (hello, name) = match tokio::time::timeout(
+                    HEAD_TIMEOUT,
+                    read_client_hello(&mut stream),
+                )
+                .await
+                {
+                    Ok(Ok(read)) => read,
+                    Ok(Err(e)) => return debug!("Failed to read ClientHello: {}", e),
+                    Err(_) => return debug!("TLS handshake timed out"),
+                };
+                if let Some(name) = name {
+                    if let Some(route) = tunnel_manager
+                        .route_domain(&name)
+                        .await
+                        .filter(|route| route.tls_passthrough)
+                    {
+                        if let Err(e) = tunnel_manager
+                            .attach_connection(route.tunnel_id, stream, addr, hello)
+                            .await
+                        {
+                            debug!(
+                                "Failed to hand {} to tunnel {}: {}",
+                                name, route.tunnel_id, e
+                            );
+                        }
+                        return;
+                    }
+                }
+
+                let stream = Rewound {
+                    prefix: hello,
+                    inner: stream,
+                };
+                match tokio::time::timeout(HEAD_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        route(
+                            stream,
+                            addr,
+                            "https",
+                            tunnel_manager,
+                            None,
+                            offline_page,
+                            compress,
+                        )
+                        .await
+                    }
+                    Ok(Err(e)) => debug!("TLS handshake failed: {}", e),
+                    Err(_) => debug!("TLS handshake timed out"),
+                }
+            }
+            .instrument(info_span!("https", peer = %addr)),
+        );
+    }
+}
+
+/// Read the first TLS record of a connection and the server name its
+/// ClientHello asks for. A ClientHello split over several records yields
+/// no name, and the connection is terminated here as usual.
+async fn read_client_hello<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> std::io::Result<(Bytes, Option<String>)> {
+    let mut buffer = BytesMut::with_capacity(1024);
+    loop {
+        if buffer.len() >= 5 {
+            let record_len = 5 + u16::from_be_bytes([buffer[3], buffer[4]]) as usize;
+            if buffer[0] != 0x16 || record_len > MAX_TLS_RECORD {
+                return Ok((buffer.freeze(), None));
+            }
+            if buffer.len() >= record_len {
+                let name = client_hello_server_name(&buffer[5..record_len]);
+                return Ok((buffer.freeze(), name));
+            }
+        }
+        if stream.read_buf(&mut buffer).await? == 0 {
+            return Ok((buffer.freeze(), None));
+        }
+    }
+}
+
+/// The normalized host name in the SNI extension of a ClientHello
+/// handshake message
+fn client_hello_server_name(hello: &[u8]) -> Option<String> {
+    let mut hello = Reader(hello);
+    // Handshake type and length, client version and random
+    if hello.u8()? != 1 {
+        return None;
+    }
+    hello.take(3 + 2 + 32)?;
+    let session_id = hello.u8()? as usize;
+    hello.take(session_id)?;
+    let cipher_suites = hello.u16()? as usize;
+    hello.take(cipher_suites)?;
+    let compression = hello.u8()? as usize;
+    hello.take(compression)?;
+    let extensions_len = hello.u16()? as usize;
+    let mut extensions = Reader(hello.take(extensions_len)?);
+    while let Some(kind) = extensions.u16() {
+        let len = extensions.u16()? as usize;
+        let mut extension = Reader(extensions.take(len)?);
+        if kind != 0 {
+            continue;
+        }
+        let list_len = extension.u16()? as usize;
+        let mut names = Reader(extension.take(list_len)?);
+        while let Some(name_type) = names.u8() {
+            let len = names.u16()? as usize;
+            let name = names.take(len)?;
+            if name_type == 0 {
+                return domain::normalize(std::str::from_utf8(name).ok()?);
+            }
+        }
+        return None;
+    }
+    None
+}
+
+/// Big-endian fields of a TLS message, front to back
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+/// A stream that first gives back `prefix`, what was already read from it
+struct Rewound<S> {
+    prefix: Bytes,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewound<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let len = self.prefix.len().min(buf.remaining());
+            let chunk = self.prefix.split_to(len);
+            buf.put_slice(&chunk);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewound<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// What each request after the first on a visitor connection goes through
+/// before the service sees it
+pub struct RequestFilter {
+    pub gate: Option<Arc<HttpGate>>,
+    pub rewrite: Option<Arc<HeaderRewrite>>,
+    pub peer: SocketAddr,
+    pub scheme: String,
+}
+
+impl RequestFilter {
+    /// `head` as it is forwarded, `None` to stop forwarding because it
+    /// lacks the gate's credentials
+    fn forward(&self, head: &Bytes) -> Option<Bytes> {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut request = httparse::Request::new(&mut headers);
+        let head_len = match request.parse(head) {
+            Ok(httparse::Status::Complete(len)) => len,
+            _ => return None,
+        };
+        if let Some(gate) = &self.gate {
+            if !gate.allows(&request) {
+                return None;
+            }
+        }
+        Some(match &self.rewrite {
+            Some(rewrite) => rewrite.apply(head, self.peer, &self.scheme),
+            None if self.gate.is_some() => close_after(&request, head, head_len),
+            None => head.clone(),
+        })
+    }
+}
+
+/// Where a request stream is between request heads
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Framing {
+    /// At the next request head
+    Head,
+    /// In a body with this many bytes left
+    Body(u64),
+    /// In a chunked body
+    Chunked(Chunk),
+    /// Past an upgrade; what follows is not HTTP
+    Opaque,
+    /// Forwarding has stopped
+    Closed,
+}
+
+/// Where a chunked body is
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Chunk {
+    /// At a chunk size line
+    Size,
+    /// In chunk data, with this many bytes left including its CRLF
+    Data(u64),
+    /// In the trailer section after the last chunk
+    Trailers,
+}
+
+impl Framing {
+    /// How the body of `request` is delimited, `None` when that is
+    /// ambiguous, as with conflicting lengths, and the stream cannot be
+    /// followed past it
+    fn of(request: &httparse::Request) -> Option<Self> {
+        if is_upgrade(request) {
+            return Some(Framing::Opaque);
+        }
+        let mut length = None;
+        let mut chunked = false;
+        for header in request.headers.iter() {
+            let value = std::str::from_utf8(header.value).ok()?.trim();
+            if header.name.eq_ignore_ascii_case("transfer-encoding") {
+                // Only a final chunked coding says where the body ends
+                let last = value.rsplit(',').next().unwrap_or_default().trim();
+                if !last.eq_ignore_ascii_case("chunked") {
+                    return None;
+                }
+                chunked = true;
+            } else if header.name.eq_ignore_ascii_case("content-length") {
+                if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                    return None;
+                }
+                let value: u64 = value.parse().ok()?;
+                if length.is_some_and(|length| length != value) {
+                    return None;
+                }
+                length = Some(value);
+            }
+        }
+        match (chunked, length) {
+            (true, Some(_)) => None,
+            (true, None) => Some(Framing::Chunked(Chunk::Size)),
+            (false, length) => Some(Framing::Body(length.unwrap_or(0))),
+        }
+    }
+
+    /// The framing after `head`, a complete request head
+    fn after(head: &[u8]) -> Option<Self> {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(head) {
+            Ok(httparse::Status::Complete(len)) if len == head.len() => Framing::of(&request),
+            _ => None,
+        }
+    }
+}
+
+/// Where `needle` first starts in `haystack`
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// A visitor's HTTP/1 request stream after its first head, which was
+/// handled when the connection was routed. Bodies pass through as they
+/// are, delimited by their length or chunks so that nothing inside one is
+/// taken for a request, and every further head goes through `filter`.
+/// When one is refused or the stream cannot be followed, the service
+/// reads the end of the stream there; a refused visitor sees the
+/// connection close after the answers to the requests before.
+pub struct Requests<S> {
+    inner: S,
+    filter: Option<RequestFilter>,
+    framing: Framing,
+    /// Read from the visitor and not yet looked at
+    input: BytesMut,
+    /// Ready for the service
+    output: Bytes,
+    /// Whether the visitor has finished sending
+    eof: bool,
+}
+
+impl<S> Requests<S> {
+    /// Follow `inner` after the first request `head`, with `rest` what
+    /// was read past it. Without a filter everything passes unchanged.
+    pub fn new(inner: S, filter: Option<RequestFilter>, head: &[u8], rest: Bytes) -> Self {
+        let framing = match &filter {
+            Some(_) => Framing::after(head).unwrap_or(Framing::Closed),
+            None => Framing::Opaque,
+        };
+        Self {
+            inner,
+            filter,
+            framing,
+            input: BytesMut::from(&rest[..]),
+            output: Bytes::new(),
+            eof: false,
+        }
+    }
+
+    /// Stop forwarding, logging why
+    fn close(&mut self, reason: &str) {
+        debug!("Stopped forwarding requests: {}", reason);
+        self.framing = Framing::Closed;
+        self.input.clear();
+    }
+
+    /// Go through as much of the input as possible, returning what the
+    /// service gets of it
+    fn advance(&mut self) -> BytesMut {
+        let mut output = BytesMut::new();
+        loop {
+            match self.framing {
+                Framing::Closed => break,
+                Framing::Opaque => {
+                    output.extend_from_slice(&self.input.split());
+                    break;
+                }
+                Framing::Body(0) => self.framing = Framing::Head,
+                Framing::Body(left) | Framing::Chunked(Chunk::Data(left)) => {
+                    if self.input.is_empty() {
+                        break;
+                    }
+                    let len = left.min(self.input.len() as u64);
+                    output.extend_from_slice(&self.input.split_to(len as usize));
+                    self.framing = match self.framing {
+                        Framing::Body(_) => Framing::Body(left - len),
+                        _ if left == len => Framing::Chunked(Chunk::Size),
+                        _ => Framing::Chunked(Chunk::Data(left - len)),
+                    };
+                }
+                Framing::Chunked(chunk) => {
+                    let Some(end) = find(&self.input, b"\r\n") else {
+                        if self.input.len() >= MAX_HEAD {
+                            self.close("chunk line too long");
+                        }
+                        break;
+                    };
+                    let line = self.input.split_to(end + 2);
+                    self.framing = if chunk == Chunk::Trailers {
+                        if end == 0 {
+                            Framing::Head
+                        } else {
+                            Framing::Chunked(Chunk::Trailers)
+                        }
+                    } else {
+                        let size = std::str::from_utf8(&line[..end])
+                            .ok()
+                            .map(|line| line.split(';').next().unwrap_or_default().trim())
+                            .and_then(|size| u64::from_str_radix(size, 16).ok());
+                        match size {
+                            Some(0) => Framing::Chunked(Chunk::Trailers),
+                            Some(size) => Framing::Chunked(Chunk::Data(size + 2)),
+                            None => {
+                                self.close("malformed chunk size");
+                                break;
+                            }
+                        }
+                    };
+                    output.extend_from_slice(&line);
+                }
+                Framing::Head => {
+                    if self.input.is_empty() {
+                        break;
+                    }
+                    let Some(end) = find(&self.input, b"\r\n\r\n") else {
+                        if self.input.len() >= MAX_HEAD {
+                            self.close("request head too long");
+                        }
+                        break;
+                    };
+                    let head = self.input.split_to(end + 4).freeze();
+                    let Some(framing) = Framing::after(&head) else {
+                        self.close("malformed request");
+                        break;
+                    };
+                    let Some(head) = self.filter.as_ref().and_then(|f| f.forward(&head)) else {
+                        self.close("request refused");
+                        break;
+                    };
+                    output.extend_from_slice(&head);
+                    self.framing = framing;
+                }
+            }
+        }
+        output
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Requests<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        if this.framing == Framing::Opaque && this.input.is_empty() && this.output.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        loop {
+            if !this.output.is_empty() {
+                let len = this.output.len().min(buf.remaining());
+                buf.put_slice(&this.output.split_to(len));
+                return Poll::Ready(Ok(()));
+            }
+            let output = this.advance();
+            if !output.is_empty() {
+                this.output = output.freeze();
+                continue;
+            }
+            if this.eof || this.framing == Framing::Closed {
+                return Poll::Ready(Ok(()));
+            }
+            let read =
+                tokio_util::io::poll_read_buf(Pin::new(&mut this.inner), cx, &mut this.input);
+            match read {
+                Poll::Ready(Ok(0)) => this.eof = true,
+                Poll::Ready(Ok(_)) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Requests<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Accept HTTP connections and hand them to tunnels until the listener
+/// fails. `https_port` is where tunnels that ask for it redirect to.
+pub async fn serve(
+    listener: TcpListener,
+    tunnel_manager: Arc<TunnelManager>,
+    sockets: SocketOptions,
+    https_port: Option<u16>,
+    offline_page: Arc<OfflinePage>,
+    compress: bool,
+) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("HTTP listener stopped: {}", e);
+                return;
+            }
+        };
+        socket::configure(&stream, &sockets);
+        let tunnel_manager = tunnel_manager.clone();
+        tokio::spawn(
+            route(
+                stream,
+                addr,
+                "http",
+                tunnel_manager,
+                https_port,
+                offline_page.clone(),
+                compress,
+            )
+            .instrument(info_span!("http", peer = %addr)),
+        );
+    }
+}
+
+/// Hand a connection to the tunnel its first request is for. `scheme` is
+/// what the visitor connected with; `https_port` is set on plain HTTP, for
+/// redirects to the HTTPS listener. With `compress`, responses are
+/// compressed for visitors that accept it.
+async fn route<S>(
+    mut stream: S,
+    addr: SocketAddr,
+    scheme: &str,
+    tunnel_manager: Arc<TunnelManager>,
+    https_port: Option<u16>,
+    offline_page: Arc<OfflinePage>,
+    compress: bool,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let head = match tokio::time::timeout(HEAD_TIMEOUT, read_head(&mut stream)).await {
+        Ok(Ok(Some(head))) => head,
+        Ok(Ok(None)) => return respond(&mut stream, 400, "Bad Request", "").await,
+        Ok(Err(e)) => return debug!("Failed to read request: {}", e),
+        Err(_) => return respond(&mut stream, 408, "Request Timeout", "").await,
+    };
+
+    let host = match request_host(&head) {
+        Some(host) => host,
+        None => return respond(&mut stream, 400, "Bad Request", "").await,
+    };
+    let Some(route) = tunnel_manager.route_domain(&host).await else {
+        debug!("No tunnel for host {}", host);
+        return respond(&mut stream, 404, "Not Found", "").await;
+    };
+    if let Some(port) = https_port.filter(|_| route.https_redirect) {
+        let location = match port {
+            443 => format!("https://{}{}", host, request_path(&head)),
+            port => format!("https://{}:{}{}", host, port, request_path(&head)),
+        };
+        let headers = format!("Location: {}\r\n", location);
+        return respond(&mut stream, 301, "Moved Permanently", &headers).await;
+    }
+    // Tunnels with TLS passthrough only take encrypted connections
+    if route.tls_passthrough {
+        debug!("Plain HTTP request for TLS passthrough host {}", host);
+        return respond(&mut stream, 404, "Not Found", "").await;
+    }
+    let tunnel_id = route.tunnel_id;
+    // Requests pipelined after the first are filtered as they come
+    let (head, rest) = split_head(head);
+    let gate = route.http_gate;
+    let head = match &gate {
+        Some(gate) => match gate.admit(&mut stream, Some(head)).await {
+            Some(head) => head,
+            None => return debug!("Refused unauthorized request for {}", host),
+        },
+        None => head,
+    };
+    let head = match &route.header_rewrite {
+        Some(rewrite) => rewrite.apply(&head, addr, scheme),
+        None => head,
+    };
+    if !tunnel_manager.tunnel_online(&tunnel_id).await {
+        let name = tunnel_manager
+            .get_tunnel(&tunnel_id)
+            .await
+            .and_then(|info| info.name)
+            .unwrap_or(host);
+        return offline_page.send(&mut stream, &name).await;
+    }
+
+    let encoding = if compress {
+        request_encoding(&head)
+    } else {
+        None
+    };
+    let filter = (gate.is_some() || route.header_rewrite.is_some()).then(|| RequestFilter {
+        gate,
+        rewrite: route.header_rewrite,
+        peer: addr,
+        scheme: scheme.to_string(),
+    });
+    let stream = Requests::new(stream, filter, &head, rest);
+    let attached = match encoding {
+        Some(encoding) => {
+            let head = closing(&head);
+            let stream = Compressing::new(stream, encoding);
+            tunnel_manager
+                .attach_connection(tunnel_id, stream, addr, head)
+                .await
+        }
+        None => {
+            tunnel_manager
+                .attach_connection(tunnel_id, stream, addr, head)
+                .await
+        }
+    };
+    if let Err(e) = attached {
+        debug!("Failed to hand {} to tunnel {}: {}", host, tunnel_id, e);
+    }
+}
+
+/// Read up to the end of the first request head, `None` if it does not
+/// fit in `MAX_HEAD`. The returned bytes may include the start of a body.
+pub(crate) async fn read_head<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> std::io::Result<Option<Bytes>> {
+    let mut buffer = BytesMut::with_capacity(4096);
+    while buffer.len() < MAX_HEAD {
+        if stream.read_buf(&mut buffer).await? == 0 {
+            return Ok(None);
+        }
+        if buffer.windows(4).any(|window| window == b"\r\n\r\n") {
+            return Ok(Some(buffer.freeze()));
+        }
+    }
+    Ok(None)
+}
+
+/// The first request head of what `read_head` returned, and the rest
+pub(crate) fn split_head(mut head: Bytes) -> (Bytes, Bytes) {
+    match find(&head, b"\r\n\r\n") {
+        Some(end) => {
+            let rest = head.split_off(end + 4);
+            (head, rest)
+        }
+        None => (head, Bytes::new()),
+    }
+}
+
+/// The normalized host a request head is for
+fn request_host(head: &[u8]) -> Option<String> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    request.parse(head).ok()?;
+    let host = request
+        .headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("host"))?;
+    domain::host_of(std::str::from_utf8(host.value).ok()?)
+}
+
+/// The path and query a request head asks for
+fn request_path(head: &[u8]) -> String {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    let _ = request.parse(head);
+    match request.path {
+        Some(path) if path.starts_with('/') => path.to_string(),
+        _ => "/".to_string(),
+    }
+}
+
+/// The encoding a request head accepts its response in
+fn request_encoding(head: &[u8]) -> Option<compression::Encoding> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    request.parse(head).ok()?;
+    compression::negotiate(&request)
+}
+
+/// `head` asking the service to close the connection after answering
+fn closing(head: &Bytes) -> Bytes {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    match request.parse(head) {
+        Ok(httparse::Status::Complete(head_len)) => close_after(&request, head, head_len),
+        _ => head.clone(),
+    }
+}
+
+/// Answer with a plain text error; `headers` are extra CRLF-terminated
+/// header lines
+pub(crate) async fn respond<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    status: u16,
+    reason: &str,
+    headers: &str,
+) {
+    let body = format!("{} {}\n", status, reason);
+    respond_with(stream, status, reason, headers, "text/plain", &body).await;
+}
+
+async fn respond_with<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    status: u16,
+    reason: &str,
+    headers: &str,
+    content_type: &str,
+    body: &str,
+) {
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        status,
+        reason,
+        content_type,
+        body.len(),
+        headers,
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite() -> RequestFilter {
+        let headers = HttpHeaders {
+            forwarded: true,
+            ..Default::default()
+        };
+        RequestFilter {
+            gate: None,
+            rewrite: Some(Arc::new(HeaderRewrite::new(&headers).unwrap())),
+            peer: "192.0.2.7:5000".parse().unwrap(),
+            scheme: "http".to_string(),
+        }
+    }
+
+    fn gate() -> Arc<HttpGate> {
+        let auth = HttpAuth {
+            username: Some("alice".to_string()),
+            password: Some("secret".to_string()),
+            bearer_token: Some("token".to_string()),
+        };
+        Arc::new(HttpGate::new(&auth).unwrap())
+    }
+
+    /// The first request `head` with an `Authorization` header of `value`
+    fn authorized(path: &str, value: &str) -> String {
+        format!(
+            "GET {} HTTP/1.1\r\nHost: a.example\r\nAuthorization: {}\r\n\r\n",
+            path, value
+        )
+    }
+
+    /// `gate`'s answer to `head`: what it forwards, or what the visitor
+    /// reads
+    async fn admitted(head: &str) -> Result<String, String> {
+        let (mut visitor, mut server) = tokio::io::duplex(64 * 1024);
+        let admitted = gate()
+            .admit(&mut server, Some(Bytes::copy_from_slice(head.as_bytes())))
+            .await;
+        drop(server);
+        if let Some(head) = admitted {
+            return Ok(String::from_utf8(head.to_vec()).unwrap());
+        }
+        let mut response = String::new();
+        visitor.read_to_string(&mut response).await.unwrap();
+        Err(response)
+    }
+
+    /// What the service reads after the first request `head`, when the
+    /// visitor sends `rest` along with it and then `later`
+    async fn forwarded(filter: RequestFilter, head: &[u8], rest: &[u8], later: &[u8]) -> String {
+        let (mut visitor, server) = tokio::io::duplex(64 * 1024);
+        visitor.write_all(later).await.unwrap();
+        drop(visitor);
+        let mut requests = Requests::new(server, Some(filter), head, Bytes::copy_from_slice(rest));
+        let mut read = Vec::new();
+        requests.read_to_end(&mut read).await.unwrap();
+        String::from_utf8(read).unwrap()
+    }
+
+    #[test]
+    fn test_split_head() {
+        let (head, rest) = split_head(Bytes::from_static(b"GET / HTTP/1.1\r\n\r\nGET /b"));
+        assert_eq!(&head[..], b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(&rest[..], b"GET /b");
+    }
+
+    #[tokio::test]
+    async fn test_rewrites_pipelined_requests() {
+        let head = b"GET /a HTTP/1.1\r\nHost: a.example\r\n\r\n";
+        let second = b"GET /b HTTP/1.1\r\nHost: a.example\r\nX-Forwarded-For: 10.0.0.1\r\n\r\n";
+        let third = b"GET /c HTTP/1.1\r\nHost: a.example\r\nX-Real-IP: 10.0.0.1\r\n\r\n";
+        let read = forwarded(rewrite(), head, second, third).await;
+        assert_eq!(read.matches("X-Forwarded-For: 192.0.2.7\r\n").count(), 2);
+        assert_eq!(read.matches("X-Real-IP: 192.0.2.7\r\n").count(), 2);
+        assert!(!read.contains("10.0.0.1"));
+        assert!(read.starts_with("GET /b HTTP/1.1\r\n"));
+        assert!(read.contains("GET /c HTTP/1.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_passes_bodies_unchanged() {
+        let smuggled = "GET /x HTTP/1.1\r\nX-Forwarded-For: 10.0.0.1\r\n\r\n";
+        let head = format!(
+            "POST /a HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            smuggled.len()
+        );
+        let later = format!(
+            "{}POST /b HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n0\r\nX-Trailer: 1\r\n\r\nGET /c HTTP/1.1\r\n\r\n",
+            smuggled,
+            smuggled.len(),
+            smuggled
+        );
+        let read = forwarded(rewrite(), head.as_bytes(), b"", later.as_bytes()).await;
+        // Both bodies arrive as they were sent, and only real heads are
+        // rewritten
+        assert!(read.starts_with(smuggled));
+        assert_eq!(read.matches(smuggled).count(), 2);
+        assert!(read.contains("\r\n0\r\nX-Trailer: 1\r\n\r\nGET /c HTTP/1.1\r\n"));
+        assert_eq!(read.matches("X-Forwarded-For: 192.0.2.7").count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_stops_at_ambiguous_framing() {
+        let second = b"GET /b HTTP/1.1\r\n\r\n";
+        // Conflicting lengths leave the end of the body unknown
+        let head = b"POST /a HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n";
+        assert_eq!(forwarded(rewrite(), head, b"x", second).await, "");
+        let head = b"POST /a HTTP/1.1\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(forwarded(rewrite(), head, b"x", second).await, "");
+        // A later request that does not parse ends the stream there
+        let head = b"GET /a HTTP/1.1\r\n\r\n";
+        let read = forwarded(rewrite(), head, b"GARBAGE\r\n\r\n", second).await;
+        assert_eq!(read, "");
+        let read = forwarded(
+            rewrite(),
+            head,
+            b"",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+        )
+        .await;
+        assert!(read.starts_with("POST / HTTP/1.1\r\n"));
+        assert!(!read.contains("zz"));
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_passes_through() {
+        let head = b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+        let frames = b"\x01\x05hello GET / HTTP/1.1\r\n\r\n";
+        assert_eq!(
+            forwarded(rewrite(), head, b"", frames).await.as_bytes(),
+            frames
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admit() {
+        // alice:secret
+        let basic = admitted(&authorized("/", "Basic YWxpY2U6c2VjcmV0"))
+            .await
+            .unwrap();
+        assert!(basic.contains("Connection: close\r\n"));
+        assert!(admitted(&authorized("/", "bearer token")).await.is_ok());
+
+        let unauthorized = [
+            // alice:wrong, bob:secret
+            authorized("/", "Basic YWxpY2U6d3Jvbmc="),
+            authorized("/", "Basic Ym9iOnNlY3JldA=="),
+            authorized("/", "Bearer wrong"),
+            authorized("/", "Bearer"),
+            authorized("/", "Basic !!!"),
+            authorized("/", "Basic YWxpY2U="),
+            authorized("/", "Digest username=\"alice\""),
+            "GET / HTTP/1.1\r\nHost: a.example\r\n\r\n".to_string(),
+        ];
+        for head in unauthorized {
+            let response = admitted(&head).await.unwrap_err();
+            assert!(response.starts_with("HTTP/1.1 401 "), "{}", head);
+            assert!(response.contains("WWW-Authenticate: Basic"));
+        }
+        let response = admitted("GET / HTTP/1.1\r\nBad Header\r\n\r\n").await;
+        assert!(response.unwrap_err().starts_with("HTTP/1.1 400 "));
+    }
+
+    #[tokio::test]
+    async fn test_gates_every_request() {
+        let filter = || RequestFilter {
+            gate: Some(gate()),
+            rewrite: None,
+            peer: "192.0.2.7:5000".parse().unwrap(),
+            scheme: "http".to_string(),
+        };
+        let head = authorized("/a", "Bearer token");
+        let later = format!(
+            "{}{}{}",
+            authorized("/b", "Bearer token"),
+            "GET /c HTTP/1.1\r\nHost: a.example\r\n\r\n",
+            authorized("/d", "Bearer token"),
+        );
+        let read = forwarded(filter(), head.as_bytes(), b"", later.as_bytes()).await;
+        assert!(read.starts_with("GET /b HTTP/1.1\r\n"));
+        assert!(read.contains("Connection: close\r\n"));
+        assert!(!read.contains("/c"));
+        assert!(!read.contains("/d"));
+
+        let later = authorized("/b", "Bearer wrong");
+        let read = forwarded(filter(), head.as_bytes(), later.as_bytes(), b"").await;
+        assert_eq!(read, "");
+        let later = authorized("/b", "Basic !!!");
+        let read = forwarded(filter(), head.as_bytes(), b"", later.as_bytes()).await;
+        assert_eq!(read, "");
+    }
+
+    /// The first TLS record a rustls client sends to `server_name`
+    fn real_client_hello(server_name: rustls::ServerName) -> Vec<u8> {
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        let mut client = rustls::ClientConnection::new(Arc::new(config), server_name).unwrap();
+        let mut record = Vec::new();
+        client.write_tls(&mut record).unwrap();
+        record
+    }
+
+    /// A ClientHello handshake message, without its record header, with
+    /// `session_id` and `extensions` as given
+    fn client_hello(session_id: &[u8], extensions: &[u8]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0x42; 32]);
+        body.push(session_id.len() as u8);
+        body.extend_from_slice(session_id);
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(extensions);
+        let mut hello = vec![0x01, 0x00];
+        hello.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&body);
+        hello
+    }
+
+    /// A server name extension listing `name` in a list of `list_len`
+    /// bytes, with the name's length given as `name_len`
+    fn sni(name: &[u8], list_len: u16, name_len: u16) -> Vec<u8> {
+        let mut list = vec![0x00];
+        list.extend_from_slice(&name_len.to_be_bytes());
+        list.extend_from_slice(name);
+        let mut extension = vec![0x00, 0x00];
+        extension.extend_from_slice(&(list.len() as u16 + 2).to_be_bytes());
+        extension.extend_from_slice(&list_len.to_be_bytes());
+        extension.extend_from_slice(&list);
+        extension
+    }
+
+    #[tokio::test]
+    async fn test_real_client_hello() {
+        let record = real_client_hello("App.Example.COM".try_into().unwrap());
+        assert_eq!(
+            client_hello_server_name(&record[5..]).as_deref(),
+            Some("app.example.com")
+        );
+        let (read, name) = read_client_hello(&mut &record[..]).await.unwrap();
+        assert_eq!(name.as_deref(), Some("app.example.com"));
+        assert_eq!(read, record);
+
+        // Connecting by address sends no server name
+        let ip = "192.0.2.1".parse().unwrap();
+        let record = real_client_hello(rustls::ServerName::IpAddress(ip));
+        assert_eq!(client_hello_server_name(&record[5..]), None);
+        let (read, name) = read_client_hello(&mut &record[..]).await.unwrap();
+        assert_eq!(name, None);
+        assert_eq!(read, record);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_client_hello() {
+        let record = real_client_hello("app.example.com".try_into().unwrap());
+        for cut in 0..record.len() - 5 {
+            assert_eq!(client_hello_server_name(&record[5..5 + cut]), None);
+        }
+        // A visitor that stops partway hands back what it sent
+        for cut in [0, 3, 5, record.len() - 1] {
+            let (read, name) = read_client_hello(&mut &record[..cut]).await.unwrap();
+            assert_eq!(name, None);
+            assert_eq!(read, record[..cut]);
+        }
+    }
+
+    #[test]
+    fn test_client_hello_length_fields() {
+        let name = b"app.example.com";
+        let good = sni(name, name.len() as u16 + 3, name.len() as u16);
+        let hello = client_hello(&[0x07; 32], &good);
+        assert_eq!(
+            client_hello_server_name(&hello).as_deref(),
+            Some("app.example.com")
+        );
+
+        // Lengths that claim more than there is
+        let oversized = [
+            client_hello(&[], &sni(name, 0xFFFF, name.len() as u16)),
+            client_hello(&[], &sni(name, name.len() as u16 + 3, 0xFFFF)),
+            client_hello(
+                &[],
+                &sni(name, name.len() as u16 + 3, name.len() as u16 + 1),
+            ),
+        ];
+        for hello in oversized {
+            assert_eq!(client_hello_server_name(&hello), None);
+        }
+        let mut hello = client_hello(&[], &good);
+        // The session ID length
+        hello[4 + 2 + 32] = 0xFF;
+        assert_eq!(client_hello_server_name(&hello), None);
+        let mut hello = client_hello(&[], &good);
+        // The extensions length
+        let at = hello.len() - good.len() - 2;
+        hello[at..at + 2].copy_from_slice(&[0xFF, 0xFF]);
+        assert_eq!(client_hello_server_name(&hello), None);
+
+        // No extensions, another extension, not a host name, not UTF-8
+        assert_eq!(client_hello_server_name(&client_hello(&[], &[])), None);
+        let other = [0x00, 0x10, 0x00, 0x03, 0x02, b'h', b'2'];
+        assert_eq!(client_hello_server_name(&client_hello(&[], &other)), None);
+        let mut not_host = sni(name, name.len() as u16 + 3, name.len() as u16);
+        not_host[6] = 0x01;
+        assert_eq!(
+            client_hello_server_name(&client_hello(&[], &not_host)),
+            None
+        );
+        let invalid = sni(b"\xFF\xFE", 5, 2);
+        assert_eq!(client_hello_server_name(&client_hello(&[], &invalid)), None);
+        // Not a ClientHello at all
+        let mut server_hello = client_hello(&[], &good);
+        server_hello[0] = 0x02;
+        assert_eq!(client_hello_server_name(&server_hello), None);
+    }
+
+    #[tokio::test]
+    async fn test_client_hello_records() {
+        let record = real_client_hello("app.example.com".try_into().unwrap());
+        // Plain HTTP and oversized records are not read for a name
+        let http = b"GET / HTTP/1.1\r\n\r\n";
+        let (read, name) = read_client_hello(&mut &http[..]).await.unwrap();
+        assert_eq!((&read[..], name), (&http[..], None));
+        let mut oversized = record.clone();
+        oversized[3..5].copy_from_slice(&(MAX_TLS_RECORD as u16).to_be_bytes());
+        let (read, name) = read_client_hello(&mut &oversized[..]).await.unwrap();
+        assert_eq!(name, None);
+        assert!(oversized.starts_with(&read));
+    }
+}
@@ -0,0 +1,243 @@
+//! The server end of the layer-3 VPN.
+//!
+//! The server holds a TUN device with the first address of the overlay
+//! subnet and gives every client that joins another one, the same one
+//! each time it rejoins while the server runs. Packets from clients are
+//! routed by destination: to the client owning that overlay address or
+//! advertising a subnet holding it, otherwise into the device, where the
+//! server's own routing table takes over. A client may only send from its
+//! overlay address or the subnets it advertised, and may only advertise
+//! what the operator allows it to.
+//!
+//! The device and the routes for every allowed subnet are set up once
+//! when the server starts, before it gives up root, so joining and
+//! leaving never touch the routing table.
+
+use crate::connection::ClientConnection;
+use crate::overlay::{self, Addresses, Member};
+use bytes::Bytes;
+use nat_traversal_common::{
+    config::ServerVpnConfig,
+    error::{NatError, NatResult},
+    protocol::Message,
+    vpn::{self, Subnet},
+};
+use nat_traversal_platform::tun::Tun;
+use std::collections::{BTreeMap, HashMap};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+pub struct VpnHub {
+    device: Tun,
+    subnet: Subnet,
+    /// Subnets each client ID may advertise
+    allowed: BTreeMap<String, Vec<Subnet>>,
+    members: RwLock<HashMap<String, Member>>,
+    addresses: Addresses,
+}
+
+impl VpnHub {
+    /// Create the server's TUN device, route the subnets clients may
+    /// advertise through it and start routing the packets the kernel sends
+    /// through it
+    pub async fn open(config: &ServerVpnConfig) -> NatResult<Arc<Self>> {
+        let own = config.subnet.host(1).ok_or_else(|| {
+            NatError::config(format!(
+                "VPN subnet {} has no room for clients",
+                config.subnet
+            ))
+        })?;
+        let mut routes: Vec<Subnet> = Vec::new();
+        for subnet in config.advertise.values().flatten() {
+            if !routes
+                .iter()
+                .any(|route| route.network() == subnet.network() && route.prefix == subnet.prefix)
+            {
+                routes.push(*subnet);
+            }
+        }
+        // `ip` runs as a child process, which must not stall the runtime
+        let (interface, subnet, mtu) = (config.interface.clone(), config.subnet, config.mtu);
+        let device = tokio::task::spawn_blocking(move || {
+            let device = Tun::create(&interface, own, subnet.prefix, mtu)?;
+            for route in &routes {
+                device
+                    .add_route(route.network(), route.prefix)
+                    .map_err(|e| anyhow::anyhow!("Failed to route {}: {}", route, e))?;
+            }
+            anyhow::Ok(device)
+        })
+        .await
+        .map_err(|e| NatError::config(format!("Failed to set up the VPN: {}", e)))?
+        .map_err(|e| NatError::config(format!("Failed to set up the VPN: {}", e)))?;
+        let hub = Arc::new(Self {
+            device,
+            subnet: config.subnet,
+            allowed: config.advertise.clone(),
+            members: RwLock::new(HashMap::new()),
+            addresses: Addresses::new("VPN", config.subnet, 2),
+        });
+        tokio::spawn(hub.clone().pump(usize::from(config.mtu)));
+        info!(
+            "VPN on {} with address {}",
+            hub.device.name(),
+            config.subnet.with_host(own)
+        );
+        Ok(hub)
+    }
+
+    /// Add `client` to the VPN, routing `advertise` to it, and return its
+    /// overlay address
+    pub async fn join(
+        &self,
+        client: &Arc<ClientConnection>,
+        advertise: Vec<Subnet>,
+    ) -> NatResult<Subnet> {
+        let allowed = self
+            .allowed
+            .get(&client.id)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        if let Some(subnet) = advertise
+            .iter()
+            .find(|subnet| !allowed.iter().any(|allowed| allowed.covers(subnet)))
+        {
+            warn!(
+                "Client {} may not advertise {} on the VPN",
+                client.id, subnet
+            );
+            return Err(NatError::permission_denied(format!(
+                "Advertising {} on the VPN is not allowed",
+                subnet
+            )));
+        }
+        let mut members = self.members.write().await;
+        overlay::check_advertise(
+            "VPN",
+            &self.subnet,
+            &client.id,
+            &advertise,
+            members.values(),
+        )?;
+        let address = self.addresses.address_for(&client.id).await?;
+        info!(
+            "Client {} joined the VPN as {}{}",
+            client.id,
+            address,
+            if advertise.is_empty() {
+                String::new()
+            } else {
+                format!(
+                    " advertising {}",
+                    advertise
+                        .iter()
+                        .map(Subnet::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            }
+        );
+        members.insert(
+            client.id.clone(),
+            Member {
+                client: client.clone(),
+                address,
+                advertise,
+            },
+        );
+        Ok(self.subnet.with_host(address))
+    }
+
+    /// Take `client` off the VPN, unless a newer session with its ID
+    /// has joined since
+    pub async fn leave(&self, client: &Arc<ClientConnection>) {
+        let mut members = self.members.write().await;
+        if !members
+            .get(&client.id)
+            .is_some_and(|member| member.is_session(client))
+        {
+            return;
+        }
+        if members.remove(&client.id).is_some() {
+            debug!("Client {} left the VPN", client.id);
+        }
+    }
+
+    /// Route a packet `client_id` sent into the VPN
+    pub async fn from_client(&self, client_id: &str, data: Bytes) {
+        let Some((source, destination)) = vpn::addresses(&data) else {
+            return debug!("Dropping a non-IPv4 VPN packet from {}", client_id);
+        };
+        {
+            let members = self.members.read().await;
+            let Some(member) = members.get(client_id) else {
+                return debug!("Dropping a VPN packet from {}, not a member", client_id);
+            };
+            if member.claim(source).is_none() {
+                return debug!(
+                    "Dropping a VPN packet from {} with foreign source {}",
+                    client_id, source
+                );
+            }
+            member.client.update_bytes_received(data.len() as u64);
+        }
+        self.route(data, destination).await;
+    }
+
+    /// Send a packet to the member owning `destination`, or into the
+    /// device when none does
+    async fn route(&self, data: Bytes, destination: Ipv4Addr) {
+        let Some(data) = self.to_member(data, destination).await else {
+            return;
+        };
+        if let Err(e) = self.device.send(&data).await {
+            debug!("Failed to write VPN packet to {}: {}", destination, e);
+        }
+    }
+
+    /// Send a packet to the member owning `destination`, handing it back
+    /// when none does
+    async fn to_member(&self, data: Bytes, destination: Ipv4Addr) -> Option<Bytes> {
+        let client = {
+            let members = self.members.read().await;
+            members
+                .values()
+                .filter_map(|member| member.claim(destination).map(|claim| (claim, member)))
+                .max_by_key(|(claim, _)| *claim)
+                .map(|(_, member)| member.client.clone())
+        };
+        let Some(client) = client else {
+            return Some(data);
+        };
+        client.update_bytes_sent(data.len() as u64);
+        let _ = client
+            .tunnel_sender(&Uuid::nil(), 0)
+            .await
+            .send(Message::VpnPacket { data });
+        None
+    }
+
+    /// Pass the packets the kernel sends through the device on to the
+    /// members they are for
+    async fn pump(self: Arc<Self>, mtu: usize) {
+        let mut buffer = vec![0; mtu.max(576)];
+        loop {
+            let len = match self.device.recv(&mut buffer).await {
+                Ok(len) => len,
+                Err(e) => {
+                    return error!("VPN device {} failed: {}", self.device.name(), e);
+                }
+            };
+            let packet = Bytes::copy_from_slice(&buffer[..len]);
+            let Some((_, destination)) = vpn::addresses(&packet) else {
+                continue;
+            };
+            if self.to_member(packet, destination).await.is_some() {
+                debug!("No VPN member has {}, dropping packet", destination);
+            }
+        }
+    }
+}
@@ -0,0 +1,500 @@
+//! The server's [`Events`](crate::events) as JSON text messages on a
+//! WebSocket at `/events`, so dashboards can follow the server without
+//! polling.
+//!
+//! Besides the events each watcher gets a `traffic` message every sample
+//! interval with the counters of every open tunnel and their rates since
+//! the previous sample. A watcher falling too far behind gets a `lagged`
+//! message saying how many events it missed, then the stream goes on.
+//! The server only sends; of what the watcher sends, only pings and
+//! close frames are answered.
+
+use crate::connection::ConnectionManager;
+use crate::tunnel::TunnelManager;
+use crate::vhost::{self, HEAD_TIMEOUT};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use nat_traversal_common::{config::EventsConfig, crypto};
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// Appended to the client's key to prove the server speaks WebSocket
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Largest frame read from a watcher; it has nothing to say beyond pings
+const MAX_FRAME: u64 = 64 * 1024;
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// The traffic of every open tunnel at one time
+#[derive(Serialize)]
+struct TrafficSample {
+    r#type: &'static str,
+    at: DateTime<Utc>,
+    tunnels: Vec<TunnelTraffic>,
+}
+
+#[derive(Serialize)]
+struct TunnelTraffic {
+    tunnel_id: Uuid,
+    client_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    remote_port: u16,
+    bytes_sent: u64,
+    bytes_received: u64,
+    active_connections: u32,
+    /// Since the previous sample, 0 in a tunnel's first
+    bytes_sent_per_sec: u64,
+    bytes_received_per_sec: u64,
+}
+
+#[derive(Serialize)]
+struct Lagged {
+    r#type: &'static str,
+    missed: u64,
+}
+
+/// Answer watchers on `listener` until the process exits
+pub async fn serve(
+    listener: TcpListener,
+    config: EventsConfig,
+    connection_manager: Arc<ConnectionManager>,
+    tunnel_manager: Arc<TunnelManager>,
+) {
+    let config = Arc::new(config);
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Event stream listener stopped: {}", e);
+                return;
+            }
+        };
+        let config = config.clone();
+        let connection_manager = connection_manager.clone();
+        let tunnel_manager = tunnel_manager.clone();
+        tokio::spawn(async move {
+            watch(stream, addr, &config, &connection_manager, &tunnel_manager).await;
+        });
+    }
+}
+
+/// Upgrade the request to a WebSocket and stream events over it
+async fn watch(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    config: &EventsConfig,
+    connection_manager: &ConnectionManager,
+    tunnel_manager: &TunnelManager,
+) {
+    let head = match tokio::time::timeout(HEAD_TIMEOUT, vhost::read_head(&mut stream)).await {
+        Ok(Ok(Some(head))) => head,
+        _ => return vhost::respond(&mut stream, 400, "Bad Request", "").await,
+    };
+    let key = match upgrade_key(&head, config) {
+        Ok(key) => key,
+        Err((status, reason)) => return vhost::respond(&mut stream, status, reason, "").await,
+    };
+    let accept = base64::engine::general_purpose::STANDARD
+        .encode(Sha1::digest(format!("{}{}", key, ACCEPT_GUID)));
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept
+    );
+    if stream.write_all(response.as_bytes()).await.is_err() {
+        return;
+    }
+    info!("Event watcher connected from {}", addr);
+
+    // Events go out from here, replies to the watcher's frames from its reader
+    let mut events = connection_manager.events().subscribe();
+    let (mut reader, mut writer) = stream.into_split();
+    let (replies_tx, mut replies) = mpsc::unbounded_channel();
+    let read_task = tokio::spawn(async move {
+        while let Ok((opcode, payload)) = read_frame(&mut reader).await {
+            match opcode {
+                OPCODE_PING => {
+                    let _ = replies_tx.send((OPCODE_PONG, payload));
+                }
+                OPCODE_CLOSE => {
+                    let _ = replies_tx.send((OPCODE_CLOSE, payload));
+                    return;
+                }
+                _ => {}
+            }
+        }
+    });
+
+    let interval = Duration::from_secs(config.sample_interval_secs);
+    let mut samples = (!interval.is_zero()).then(|| tokio::time::interval(interval));
+    let mut previous: HashMap<Uuid, (u64, u64)> = HashMap::new();
+    let mut sampled_at = Instant::now();
+    loop {
+        let message = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => serde_json::to_string(&event),
+                Err(RecvError::Lagged(missed)) => serde_json::to_string(&Lagged {
+                    r#type: "lagged",
+                    missed,
+                }),
+                Err(RecvError::Closed) => break,
+            },
+            _ = async { samples.as_mut().unwrap().tick().await }, if samples.is_some() => {
+                let elapsed = sampled_at.elapsed().as_secs_f64();
+                sampled_at = Instant::now();
+                let sample = sample(tunnel_manager, &mut previous, elapsed).await;
+                serde_json::to_string(&sample)
+            }
+            reply = replies.recv() => match reply {
+                Some((OPCODE_CLOSE, payload)) => {
+                    let _ = write_frame(&mut writer, OPCODE_CLOSE, &payload).await;
+                    break;
+                }
+                Some((opcode, payload)) => {
+                    if write_frame(&mut writer, opcode, &payload).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                None => break,
+            },
+        };
+        let Ok(message) = message else { continue };
+        if write_frame(&mut writer, OPCODE_TEXT, message.as_bytes())
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+    read_task.abort();
+    debug!("Event watcher {} left", addr);
+}
+
+/// The request's `Sec-WebSocket-Key` once it is an authorized upgrade to
+/// `/events`, or the status to refuse it with
+fn upgrade_key(head: &[u8], config: &EventsConfig) -> Result<String, (u16, &'static str)> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    if request.parse(head).is_err() || request.method != Some("GET") {
+        return Err((400, "Bad Request"));
+    }
+    let (path, query) = request
+        .path
+        .unwrap_or_default()
+        .split_once('?')
+        .unwrap_or((request.path.unwrap_or_default(), ""));
+    if path != "/events" {
+        return Err((404, "Not Found"));
+    }
+    let header = |name: &str| {
+        request
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .and_then(|header| std::str::from_utf8(header.value).ok())
+    };
+    // Browsers cannot set headers on a WebSocket, so the query may carry it
+    let token = header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| {
+            query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("token="))
+        });
+    // Compared as hashes so the time taken says nothing about the tokens
+    let known = token.map(crypto::hash_token).is_some_and(|hash| {
+        config
+            .tokens
+            .iter()
+            .any(|known| crypto::verify_token(known, &hash))
+    });
+    if !known {
+        warn!("Refused an event watcher without a known token");
+        return Err((401, "Unauthorized"));
+    }
+    let upgrade = header("upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+    match header("sec-websocket-key") {
+        Some(key) if upgrade => Ok(key.trim().to_string()),
+        _ => Err((426, "Upgrade Required")),
+    }
+}
+
+/// Every open tunnel's counters, with rates against `previous`, which is
+/// updated
+async fn sample(
+    tunnel_manager: &TunnelManager,
+    previous: &mut HashMap<Uuid, (u64, u64)>,
+    elapsed: f64,
+) -> TrafficSample {
+    let rate = |now: u64, before: Option<u64>| match before {
+        Some(before) if elapsed > 0.0 => (now.saturating_sub(before) as f64 / elapsed) as u64,
+        _ => 0,
+    };
+    let tunnels: Vec<TunnelTraffic> = tunnel_manager
+        .list_tunnels_with_owner()
+        .await
+        .into_iter()
+        .map(|(client_id, tunnel)| {
+            let before = previous.get(&tunnel.id);
+            TunnelTraffic {
+                tunnel_id: tunnel.id,
+                client_id,
+                name: tunnel.name,
+                remote_port: tunnel.remote_port,
+                bytes_sent: tunnel.bytes_sent,
+                bytes_received: tunnel.bytes_received,
+                active_connections: tunnel.active_connections,
+                bytes_sent_per_sec: rate(tunnel.bytes_sent, before.map(|b| b.0)),
+                bytes_received_per_sec: rate(tunnel.bytes_received, before.map(|b| b.1)),
+            }
+        })
+        .collect();
+    *previous = tunnels
+        .iter()
+        .map(|t| (t.tunnel_id, (t.bytes_sent, t.bytes_received)))
+        .collect();
+    TrafficSample {
+        r#type: "traffic",
+        at: Utc::now(),
+        tunnels,
+    }
+}
+
+/// Read one frame from the watcher, unmasking its payload. Frames a client
+/// may not send (unmasked, with reserved bits, unknown opcodes, or
+/// fragmented or long control frames) are refused.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<(u8, Vec<u8>)> {
+    let invalid = |reason| Err(std::io::Error::new(std::io::ErrorKind::InvalidData, reason));
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head).await?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    if head[0] & 0x70 != 0 {
+        return invalid("WebSocket frame has reserved bits set");
+    }
+    if !matches!(opcode, 0x0..=0x2 | 0x8..=0xA) {
+        return invalid("Unknown WebSocket opcode");
+    }
+    if head[1] & 0x80 == 0 {
+        return invalid("WebSocket frame from a client is not masked");
+    }
+    let length = match head[1] & 0x7F {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        length => length as u64,
+    };
+    if length > MAX_FRAME {
+        return invalid("WebSocket frame too large");
+    }
+    if opcode & 0x8 != 0 && (!fin || length > 125) {
+        return invalid("WebSocket control frame is fragmented or too long");
+    }
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0u8; length as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok((opcode, payload))
+}
+
+/// Write one final, unmasked frame, as servers send them
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    opcode: u8,
+    payload: &[u8],
+) -> std::io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        length @ 0..=125 => frame.push(length as u8),
+        length @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASK: [u8; 4] = [0x37, 0xFA, 0x21, 0x3D];
+
+    /// A frame as a client sends it, with a payload `length` encoded in
+    /// `extended` bytes beyond the first (0, 2 or 8)
+    fn client_frame(first: u8, payload: &[u8], extended: usize) -> Vec<u8> {
+        let mut frame = vec![first];
+        match extended {
+            0 => frame.push(0x80 | payload.len() as u8),
+            2 => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+            }
+            _ => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&MASK);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ MASK[i % 4]));
+        frame
+    }
+
+    async fn read(frame: &[u8]) -> std::io::Result<(u8, Vec<u8>)> {
+        read_frame(&mut &frame[..]).await
+    }
+
+    async fn written(payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        write_frame(&mut frame, OPCODE_TEXT, payload).await.unwrap();
+        frame
+    }
+
+    #[test]
+    fn test_upgrade_tokens() {
+        let config = EventsConfig {
+            tokens: vec!["secret".to_string()],
+            ..EventsConfig::default()
+        };
+        let upgrade = |target: &str, authorization: &str| {
+            let head = format!(
+                "GET {} HTTP/1.1\r\nHost: server\r\n{}Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\nSec-WebSocket-Key: a2V5\r\n\r\n",
+                target, authorization
+            );
+            upgrade_key(head.as_bytes(), &config)
+        };
+
+        assert_eq!(
+            upgrade("/events", "Authorization: Bearer secret\r\n"),
+            Ok("a2V5".to_string())
+        );
+        assert_eq!(upgrade("/events?token=secret", ""), Ok("a2V5".to_string()));
+        for (target, authorization) in [
+            ("/events", ""),
+            ("/events", "Authorization: Bearer secre\r\n"),
+            ("/events", "Authorization: Basic secret\r\n"),
+            ("/events?token=secrets", ""),
+            ("/events?token=", ""),
+        ] {
+            assert_eq!(
+                upgrade(target, authorization),
+                Err((401, "Unauthorized")),
+                "{} {}",
+                target,
+                authorization
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unmasks_client_frames() {
+        // The example from RFC 6455 section 5.7
+        let hello = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        assert_eq!(
+            read(&hello).await.unwrap(),
+            (OPCODE_TEXT, b"Hello".to_vec())
+        );
+
+        let empty = client_frame(0x80 | OPCODE_PING, b"", 0);
+        assert_eq!(read(&empty).await.unwrap(), (OPCODE_PING, Vec::new()));
+        let close = client_frame(0x80 | OPCODE_CLOSE, &[0x03, 0xE8], 0);
+        assert_eq!(
+            read(&close).await.unwrap(),
+            (OPCODE_CLOSE, vec![0x03, 0xE8])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_length_encodings() {
+        for (length, extended) in [(125, 0), (126, 2), (0xFFFF, 2), (MAX_FRAME as usize, 8)] {
+            let payload: Vec<u8> = (0..length).map(|i| i as u8).collect();
+            let frame = client_frame(0x80 | OPCODE_TEXT, &payload, extended);
+            assert_eq!(read(&frame).await.unwrap(), (OPCODE_TEXT, payload));
+        }
+
+        assert_eq!(written(&[0u8; 125]).await[..2], [0x81, 125]);
+        assert_eq!(written(&[0u8; 126]).await[..4], [0x81, 126, 0x00, 126]);
+        assert_eq!(written(&[0u8; 0xFFFF]).await[..4], [0x81, 126, 0xFF, 0xFF]);
+        let frame = written(&[0u8; 0x10000]).await;
+        assert_eq!(frame[..10], [0x81, 127, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(frame.len(), 10 + 0x10000);
+    }
+
+    #[tokio::test]
+    async fn test_refuses_oversized_frames() {
+        let payload = vec![0u8; MAX_FRAME as usize + 1];
+        let frame = client_frame(0x80 | OPCODE_TEXT, &payload, 8);
+        let error = read(&frame).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        // The length alone is enough, nothing is allocated for it
+        let huge = [
+            0x81,
+            0x80 | 127,
+            0xFF,
+            0xFF,
+            0xFF,
+            0xFF,
+            0xFF,
+            0xFF,
+            0xFF,
+            0xFF,
+        ];
+        assert_eq!(
+            read(&huge).await.unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refuses_invalid_frames() {
+        let mut unmasked = client_frame(0x80 | OPCODE_TEXT, b"hi", 0);
+        unmasked[1] &= 0x7F;
+        let invalid = [
+            unmasked,
+            client_frame(0x80 | 0x40 | OPCODE_TEXT, b"hi", 0),
+            client_frame(0x80 | 0x3, b"hi", 0),
+            client_frame(0x80 | 0xB, b"hi", 0),
+            client_frame(OPCODE_PING, b"hi", 0),
+            client_frame(0x80 | OPCODE_CLOSE, &[0u8; 126], 2),
+        ];
+        for frame in invalid {
+            let error = read(&frame).await.unwrap_err();
+            assert_eq!(
+                error.kind(),
+                std::io::ErrorKind::InvalidData,
+                "{:02x?}",
+                &frame[..2]
+            );
+        }
+
+        let frame = client_frame(0x80 | OPCODE_TEXT, b"Hello", 0);
+        for cut in [1, 3, 7, frame.len() - 1] {
+            let error = read(&frame[..cut]).await.unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! The server as a WireGuard broker.
+//!
+//! Clients joining the mesh bring their own kernel WireGuard device and
+//! send the server their public key and the public address STUN found
+//! for their WireGuard port. The server gives each one an overlay address
+//! and sends every member the full list of the others, again whenever one
+//! joins or leaves. The server never holds a key or decrypts anything.
+//!
+//! Members that cannot reach each other directly send through the relay:
+//! every member gets a UDP port, and a datagram arriving on member B's
+//! port from member A's public address goes to B's public address from
+//! A's port, so B sees it coming from the address it has for A. Only
+//! datagrams from members' known addresses are relayed, and a member's
+//! public address has to be the one its control connection comes from,
+//! so the relay cannot be pointed at anyone else.
+
+use crate::connection::ClientConnection;
+use crate::overlay::{self, Addresses, Member};
+use nat_traversal_common::{
+    config::{PortRange, ServerWireGuardConfig},
+    error::{NatError, NatResult},
+    noise,
+    protocol::{Message, WireGuardPeer},
+    vpn::Subnet,
+};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// A client on the mesh
+struct Node {
+    member: Member,
+    public_key: String,
+    endpoint: Option<SocketAddr>,
+    relay: Arc<UdpSocket>,
+    relay_port: u16,
+    relay_task: JoinHandle<()>,
+}
+
+impl Node {
+    /// The member as another member sees it
+    fn peer(&self) -> WireGuardPeer {
+        let mut allowed_ips = vec![Subnet {
+            addr: self.member.address,
+            prefix: 32,
+        }];
+        allowed_ips.extend(self.member.advertise.iter().map(|advertised| Subnet {
+            addr: advertised.network(),
+            prefix: advertised.prefix,
+        }));
+        WireGuardPeer {
+            client_id: self.member.client.id.clone(),
+            public_key: self.public_key.clone(),
+            allowed_ips,
+            endpoint: self.endpoint,
+            relay_port: self.relay_port,
+        }
+    }
+}
+
+pub struct WireGuardBroker {
+    subnet: Subnet,
+    relay_ports: PortRange,
+    /// Address the relay ports are bound on
+    bind_addr: IpAddr,
+    members: RwLock<HashMap<String, Node>>,
+    addresses: Addresses,
+}
+
+impl WireGuardBroker {
+    pub fn new(config: &ServerWireGuardConfig, bind_addr: IpAddr) -> Arc<Self> {
+        Arc::new(Self {
+            subnet: config.subnet,
+            relay_ports: config.relay_ports,
+            bind_addr,
+            members: RwLock::new(HashMap::new()),
+            addresses: Addresses::new("WireGuard", config.subnet, 1),
+        })
+    }
+
+    /// Add `client` to the mesh, or update its key and addresses when it
+    /// rejoins, and return its overlay address. The other members learn
+    /// about it from `announce`.
+    pub async fn join(
+        self: &Arc<Self>,
+        client: &Arc<ClientConnection>,
+        public_key: String,
+        endpoint: Option<SocketAddr>,
+        advertise: Vec<Subnet>,
+    ) -> NatResult<Subnet> {
+        noise::decode_key(&public_key)
+            .map_err(|e| NatError::protocol(format!("Invalid WireGuard key: {}", e)))?;
+        // The relay sends to this address, which must not be a stranger's
+        if let Some(endpoint) = endpoint {
+            if endpoint.ip().to_canonical() != client.addr.ip().to_canonical() {
+                warn!(
+                    "Client {} from {} claimed WireGuard endpoint {}",
+                    client.id, client.addr, endpoint
+                );
+                return Err(NatError::permission_denied(format!(
+                    "WireGuard endpoint {} is not this client's address {}",
+                    endpoint,
+                    client.addr.ip()
+                )));
+            }
+        }
+        let mut members = self.members.write().await;
+        if members
+            .iter()
+            .any(|(id, member)| id != &client.id && member.public_key == public_key)
+        {
+            return Err(NatError::config(
+                "Another client on the mesh uses this WireGuard key",
+            ));
+        }
+        overlay::check_advertise(
+            "WireGuard",
+            &self.subnet,
+            &client.id,
+            &advertise,
+            members.values().map(|node| &node.member),
+        )?;
+        let address = self.addresses.address_for(&client.id).await?;
+
+        // A rejoining client keeps its relay port
+        let (relay, relay_port, relay_task) = match members.remove(&client.id) {
+            Some(previous) => (previous.relay, previous.relay_port, previous.relay_task),
+            None => {
+                let (relay, relay_port) = self.bind_relay(&members).await?;
+                let relay = Arc::new(relay);
+                let task = tokio::spawn(self.clone().relay(client.id.clone(), relay.clone()));
+                (relay, relay_port, task)
+            }
+        };
+        info!(
+            "Client {} joined the WireGuard mesh as {} ({}, relay port {})",
+            client.id,
+            address,
+            endpoint.map_or_else(|| "no public address".to_string(), |e| e.to_string()),
+            relay_port
+        );
+        members.insert(
+            client.id.clone(),
+            Node {
+                member: Member {
+                    client: client.clone(),
+                    address,
+                    advertise,
+                },
+                public_key,
+                endpoint,
+                relay,
+                relay_port,
+                relay_task,
+            },
+        );
+        Ok(self.subnet.with_host(address))
+    }
+
+    /// Take `client` off the mesh, unless a newer session with its ID has
+    /// joined since
+    pub async fn leave(&self, client: &Arc<ClientConnection>) {
+        {
+            let mut members = self.members.write().await;
+            if !members
+                .get(&client.id)
+                .is_some_and(|node| node.member.is_session(client))
+            {
+                return;
+            }
+            if let Some(node) = members.remove(&client.id) {
+                node.relay_task.abort();
+                debug!("Client {} left the WireGuard mesh", client.id);
+            }
+        }
+        self.announce().await;
+    }
+
+    /// Send every member the current list of the others
+    pub async fn announce(&self) {
+        let members = self.members.read().await;
+        for (id, node) in members.iter() {
+            let peers = members
+                .iter()
+                .filter(|(other, _)| *other != id)
+                .map(|(_, other)| other.peer())
+                .collect();
+            let _ = node
+                .member
+                .client
+                .send_message(Message::WireGuardPeers { peers })
+                .await;
+        }
+    }
+
+    /// Relay datagrams arriving on `client_id`'s port to it
+    async fn relay(self: Arc<Self>, client_id: String, socket: Arc<UdpSocket>) {
+        let mut buffer = vec![0u8; 65536];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(e) => {
+                    debug!("WireGuard relay for {} receive error: {}", client_id, e);
+                    continue;
+                }
+            };
+            let route = {
+                let members = self.members.read().await;
+                let sender = members.values().find(|node| node.endpoint == Some(from));
+                match (sender, members.get(&client_id)) {
+                    (Some(sender), Some(target)) if !Arc::ptr_eq(&sender.relay, &socket) => target
+                        .endpoint
+                        .map(|to| (sender.relay.clone(), to, target.member.client.clone())),
+                    _ => None,
+                }
+            };
+            let Some((out, to, target)) = route else {
+                continue;
+            };
+            if out.send_to(&buffer[..len], to).await.is_ok() {
+                target.update_bytes_sent(len as u64);
+            }
+        }
+    }
+
+    /// Bind the first free port of the relay range
+    async fn bind_relay(&self, members: &HashMap<String, Node>) -> NatResult<(UdpSocket, u16)> {
+        for port in self.relay_ports.start..=self.relay_ports.end {
+            if members.values().any(|node| node.relay_port == port) {
+                continue;
+            }
+            if let Ok(socket) = UdpSocket::bind((self.bind_addr, port)).await {
+                return Ok((socket, port));
+            }
+        }
+        Err(NatError::limit_reached("No free WireGuard relay ports"))
+    }
+}